@@ -0,0 +1,21 @@
+//! Criterion benchmarks over the scenes that `rendrs bench` renders, for tracking the speed of the
+//! marcher and the bvh across changes with `cargo bench`.
+
+use criterion::{criterion_group, criterion_main, Criterion};
+
+use rendrs::{bench, render::Backend};
+
+fn render_scenes(c: &mut Criterion) {
+    let threads = std::thread::available_parallelism().map_or(1, |threads| threads.get());
+    let mut group = c.benchmark_group("render");
+    group.sample_size(10);
+    for scene in bench::SCENES {
+        group.bench_function(scene.name, |b| {
+            b.iter(|| bench::run(scene, threads, Backend::Cpu).unwrap())
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, render_scenes);
+criterion_main!(benches);
@@ -0,0 +1,17 @@
+//! Feed arbitrary input to the scene parser and the expander, neither of which may panic.
+//!
+//! Run with `cargo fuzz run parse fuzz/corpus/parse scenes tests/corpus/valid tests/corpus/invalid`
+//! to start from the example scenes and the test corpus.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|input: &str| {
+    let flags = [String::from("final")];
+    let _ = rendrs::parser::parse(input, &flags);
+
+    if let Ok(expanded) = rendrs::parser::expand(input, &flags) {
+        assert_eq!(expanded, rendrs::parser::expand(&expanded, &[]).unwrap());
+    }
+});
@@ -0,0 +1,417 @@
+//! Python bindings for building scenes and rendering them into numpy arrays, built with
+//! `maturin develop` from this directory. Scenes built in Python are written out in the scene
+//! description language and parsed like any other, so everything that a scene file can do is
+//! available through [`SceneBuilder::node`] even when there's no method for it.
+
+// The code that pyo3 generates for functions returning `PyResult` trips this lint.
+#![allow(clippy::useless_conversion)]
+
+use anyhow::Error;
+use numpy::{PyArray1, PyArrayMethods};
+use pyo3::exceptions::{PyRuntimeError, PyValueError};
+use pyo3::prelude::*;
+use pyo3::types::PyDict;
+use std::fmt::Write;
+
+use rendrs::{parser, render};
+
+fn parse_error(err: Error) -> PyErr {
+    PyValueError::new_err(format!("{:#}", err))
+}
+
+fn render_error(err: Error) -> PyErr {
+    PyRuntimeError::new_err(format!("{:#}", err))
+}
+
+/// Render every target in `source`, returning the images of file targets as `uint8` arrays of
+/// shape `(height, width, 4)` keyed by the paths they would be written to, and ascii targets as
+/// strings keyed by their names.
+fn render_all<'py>(
+    py: Python<'py>,
+    source: String,
+    flags: Vec<String>,
+    threads: Option<usize>,
+) -> PyResult<Bound<'py, PyDict>> {
+    let settings = render::Settings {
+        threads: threads.unwrap_or_else(|| {
+            std::thread::available_parallelism().map_or(1, |threads| threads.get())
+        }),
+        backend: render::Backend::Cpu,
+        strict: false,
+        coordinator: None,
+        in_memory: true,
+        concurrent: false,
+        cache: None,
+    };
+
+    // The scene is parsed before anything renders, so errors from starting the renders are
+    // mistakes in the scene, and errors from the renders themselves are failed renders.
+    let outputs = py
+        .allow_threads(|| {
+            render::render_source(
+                &settings,
+                source,
+                &flags,
+                &parser::Overrides::default(),
+                None,
+            )
+            .map(|outputs| outputs.collect::<Result<Vec<_>, Error>>())
+        })
+        .map_err(parse_error)?
+        .map_err(render_error)?;
+
+    let images = PyDict::new_bound(py);
+    for output in outputs {
+        match output {
+            render::Output::Image { path, image, .. } => {
+                let image = image.into_rgba8();
+                let (width, height) = image.dimensions();
+                let pixels = PyArray1::from_vec_bound(py, image.into_raw()).reshape([
+                    height as usize,
+                    width as usize,
+                    4,
+                ])?;
+                images.set_item(path.to_string_lossy(), pixels)?;
+            }
+            render::Output::Ascii { name, chars } => images.set_item(name, chars)?,
+            render::Output::File { .. } => unreachable!("renders are kept in memory"),
+        }
+    }
+    Ok(images)
+}
+
+/// Render every target in a scene description, returning a dict from the path of each file target
+/// to its image as a `uint8` array of shape `(height, width, 4)`, and from the name of each ascii
+/// target to its text.
+/// `flags` enable the scene's `if-flag` conditionals, and `threads` defaults to one per core.
+#[pyfunction]
+#[pyo3(signature = (source, flags = Vec::new(), threads = None))]
+fn render_scene<'py>(
+    py: Python<'py>,
+    source: String,
+    flags: Vec<String>,
+    threads: Option<usize>,
+) -> PyResult<Bound<'py, PyDict>> {
+    render_all(py, source, flags, threads)
+}
+
+/// Check that a scene description parses, raising a `ValueError` that describes the first
+/// mistake when it doesn't. Returns the number of renders in the scene.
+#[pyfunction]
+#[pyo3(signature = (source, flags = Vec::new()))]
+fn parse(source: &str, flags: Vec<String>) -> PyResult<usize> {
+    let (_, renders) = parser::parse(source, &flags).map_err(parse_error)?;
+    Ok(renders.len())
+}
+
+/// A color written as `#rrggbb`, or as a tuple of red, green and blue from `0` to `1`.
+#[derive(FromPyObject)]
+enum Color {
+    Hex(String),
+    Rgb(f32, f32, f32),
+}
+
+impl Color {
+    fn hex(&self) -> PyResult<String> {
+        match self {
+            Color::Hex(hex) if hex.len() == 7 && hex.starts_with('#') => Ok(hex.clone()),
+            Color::Hex(hex) => Err(PyValueError::new_err(format!(
+                "Colors are written as `#rrggbb`, not `{}`",
+                hex
+            ))),
+            Color::Rgb(r, g, b) => {
+                let channel = |c: f32| (c.clamp(0., 1.) * 255.).round() as u8;
+                Ok(format!(
+                    "#{:02x}{:02x}{:02x}",
+                    channel(*r),
+                    channel(*g),
+                    channel(*b)
+                ))
+            }
+        }
+    }
+}
+
+type Point = (f64, f64, f64);
+
+fn point((x, y, z): Point) -> String {
+    format!("({} {} {})", x, y, z)
+}
+
+/// Builds a scene from Python, one node at a time. Nodes and materials are returned as names,
+/// which may be passed to the methods that combine them or to [`SceneBuilder::node`].
+#[pyclass]
+#[derive(Default)]
+struct SceneBuilder {
+    /// The scene description written so far.
+    source: String,
+
+    /// The names given to nodes and materials so far.
+    names: usize,
+}
+
+impl SceneBuilder {
+    fn declare(&mut self, kind: &str, form: &str) -> String {
+        let name = format!("{}-{}", kind, self.names);
+        self.names += 1;
+        writeln!(self.source, "({} {} {})", kind, name, form).unwrap();
+        name
+    }
+}
+
+#[pymethods]
+impl SceneBuilder {
+    #[new]
+    fn new() -> Self {
+        Self::default()
+    }
+
+    /// The scene description that the builder has written.
+    #[getter]
+    fn source(&self) -> String {
+        self.source.clone()
+    }
+
+    /// A node written in the scene description language, such as `"(metaballs ...)"`, which may
+    /// refer to the names of nodes already built.
+    fn node(&mut self, form: &str) -> String {
+        self.declare("node", form)
+    }
+
+    fn sphere(&mut self, radius: f64) -> String {
+        self.node(&format!("(sphere {})", radius))
+    }
+
+    /// A box with the given half width, height and depth.
+    #[pyo3(name = "box")]
+    fn rect(&mut self, width: f64, height: f64, depth: f64) -> String {
+        self.node(&format!("(box {} {} {})", width, height, depth))
+    }
+
+    fn torus(&mut self, hole: f64, radius: f64) -> String {
+        self.node(&format!("(torus {} {})", hole, radius))
+    }
+
+    #[pyo3(signature = (normal = (0., 1., 0.)))]
+    fn plane(&mut self, normal: Point) -> String {
+        self.node(&format!("(plane {})", point(normal)))
+    }
+
+    fn capsule(&mut self, a: Point, b: Point, radius: f64) -> String {
+        self.node(&format!("(capsule {} {} {})", point(a), point(b), radius))
+    }
+
+    fn group(&mut self, nodes: Vec<String>) -> String {
+        self.node(&format!("(group {})", nodes.join(" ")))
+    }
+
+    fn union(&mut self, nodes: Vec<String>) -> String {
+        self.node(&format!("(union {})", nodes.join(" ")))
+    }
+
+    fn intersect(&mut self, nodes: Vec<String>) -> String {
+        self.node(&format!("(intersect {})", nodes.join(" ")))
+    }
+
+    fn subtract(&mut self, left: &str, right: &str) -> String {
+        self.node(&format!("(subtract {} {})", left, right))
+    }
+
+    fn smooth_union(&mut self, k: f64, nodes: Vec<String>) -> String {
+        self.node(&format!("(smooth-union {} {})", k, nodes.join(" ")))
+    }
+
+    fn translate(&mut self, node: &str, offset: Point) -> String {
+        let (x, y, z) = offset;
+        self.node(&format!(
+            "(transform (translate {} {} {}) {})",
+            x, y, z, node
+        ))
+    }
+
+    /// Rotate `node` by the angle, in degrees, about `axis`.
+    fn rotate(&mut self, node: &str, axis: Point, degrees: f64) -> PyResult<String> {
+        let (x, y, z) = axis;
+        let norm = (x * x + y * y + z * z).sqrt();
+        if norm == 0. {
+            return Err(PyValueError::new_err("A rotation needs a non-zero axis"));
+        }
+        let scale = degrees.to_radians() / norm;
+        let axis = point((x * scale, y * scale, z * scale));
+        Ok(self.node(&format!("(transform (rotate {}) {})", axis, node)))
+    }
+
+    fn scale(&mut self, node: &str, amount: f64) -> String {
+        self.node(&format!("(transform (uniform-scale {}) {})", amount, node))
+    }
+
+    /// A phong material of the given color. The keyword arguments are its fields without their
+    /// leading `:`, with `_` for `-`, such as `reflective=0.5` or `gloss_samples=4`.
+    #[pyo3(signature = (color, **fields))]
+    fn phong(&mut self, color: Color, fields: Option<&Bound<'_, PyDict>>) -> PyResult<String> {
+        let mut form = format!("(phong :pattern (solid {})", color.hex()?);
+        for (field, value) in fields.into_iter().flatten() {
+            let field: String = field.extract()?;
+            let value = match value.extract::<bool>() {
+                Ok(value) => value.to_string(),
+                Err(_) => value.extract::<f64>()?.to_string(),
+            };
+            // Fields are written with dashes, except for `:refractive_index`.
+            let field = match field.as_str() {
+                "refractive_index" => field,
+                _ => field.replace('_', "-"),
+            };
+            write!(form, " :{} {}", field, value).unwrap();
+        }
+        form.push(')');
+        Ok(self.declare("material", &form))
+    }
+
+    fn paint(&mut self, material: &str, node: &str) -> String {
+        self.node(&format!("(paint {} {})", material, node))
+    }
+
+    #[pyo3(signature = (position, color = Color::Hex(String::from("#ffffff")), intensity = 1.))]
+    fn point_light(&mut self, position: Point, color: Color, intensity: f64) -> PyResult<()> {
+        writeln!(
+            self.source,
+            "(light (point {} {} :intensity {}))",
+            color.hex()?,
+            point(position),
+            intensity
+        )
+        .unwrap();
+        Ok(())
+    }
+
+    #[pyo3(signature = (color, horizon = None, intensity = 1.))]
+    fn diffuse_light(
+        &mut self,
+        color: Color,
+        horizon: Option<Color>,
+        intensity: f64,
+    ) -> PyResult<()> {
+        let horizon = match horizon {
+            Some(horizon) => format!(" :horizon {}", horizon.hex()?),
+            None => String::new(),
+        };
+        writeln!(
+            self.source,
+            "(light (diffuse {}{} :intensity {}))",
+            color.hex()?,
+            horizon,
+            intensity
+        )
+        .unwrap();
+        Ok(())
+    }
+
+    /// Render `root` with a pinhole camera at `eye` looking at `target`, returning a `uint8`
+    /// array of shape `(height, width, 4)`. The field of view is in degrees.
+    #[pyo3(signature = (
+        root,
+        width,
+        height,
+        eye = (0., 0., -5.),
+        target = (0., 0., 0.),
+        fov = 60.,
+        samples = 1,
+        threads = None,
+    ))]
+    #[allow(clippy::too_many_arguments)]
+    fn render<'py>(
+        &self,
+        py: Python<'py>,
+        root: &str,
+        width: u32,
+        height: u32,
+        eye: Point,
+        target: Point,
+        fov: f64,
+        samples: u32,
+        threads: Option<usize>,
+    ) -> PyResult<Bound<'py, PyAny>> {
+        let mut source = self.source.clone();
+        writeln!(
+            source,
+            "(render (file \"render.png\") \
+             (whitted (uniform {}) \
+             (pinhole {} {} (look-at {} {} (0 1 0)) (degrees {}))) \
+             {})",
+            samples,
+            width,
+            height,
+            point(eye),
+            point(target),
+            fov,
+            root
+        )
+        .unwrap();
+
+        let images = render_all(py, source, Vec::new(), threads)?;
+        Ok(images
+            .get_item("render.png")?
+            .expect("the scene has a single render"))
+    }
+}
+
+#[pymodule]
+#[pyo3(name = "rendrs")]
+fn rendrs_module(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add_class::<SceneBuilder>()?;
+    m.add_function(wrap_pyfunction!(parse, m)?)?;
+    m.add_function(wrap_pyfunction!(render_scene, m)?)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Shows the depth of what it sees, so that renders without lights still have something in
+    /// them.
+    const DEPTH: &str = "(debug (uniform 1) (pinhole 8 4 (translate 0 0 3) (degrees 60)) depth)";
+
+    #[test]
+    fn test_render_scene() {
+        Python::with_gil(|py| {
+            let source = format!(
+                "(render (ascii \"ball\") {} (sphere 1)) (render (ascii \"far\") {} (sphere 0.1))",
+                DEPTH, DEPTH
+            );
+            let outputs = render_scene(py, source, Vec::new(), Some(1)).unwrap();
+            assert_eq!(2, outputs.len());
+            let text = |name: &str| -> String {
+                outputs.get_item(name).unwrap().unwrap().extract().unwrap()
+            };
+            assert_eq!(4, text("ball").lines().count());
+            assert_ne!(text("ball"), text("far"));
+
+            let err =
+                render_scene(py, String::from("(node ball"), Vec::new(), Some(1)).unwrap_err();
+            assert!(err.is_instance_of::<PyValueError>(py), "{}", err);
+            let err = parse("(node ball (sphere -1))", Vec::new()).unwrap_err();
+            assert!(err.is_instance_of::<PyValueError>(py), "{}", err);
+        });
+    }
+
+    #[test]
+    fn test_scene_builder() {
+        let mut builder = SceneBuilder::new();
+        let ball = builder.sphere(1.);
+        let moved = builder.translate(&ball, (0., 1., 0.));
+        assert_eq!("node-1", moved);
+        assert_eq!(
+            "(node node-0 (sphere 1))\n(node node-1 (transform (translate 0 1 0) node-0))\n",
+            builder.source()
+        );
+
+        let source = format!(
+            "{}(render (ascii \"out\") {} {})",
+            builder.source(),
+            DEPTH,
+            moved
+        );
+        assert_eq!(1, parse(&source, Vec::new()).unwrap());
+    }
+}
@@ -0,0 +1,228 @@
+//! A fixed set of scenes for measuring the speed of the renderer. Each one leans on a different
+//! part of it, so that a change to the marcher or the bvh shows up as a change in the time taken by
+//! the scenes that exercise it. They're rendered by `rendrs bench` and by the criterion benchmarks
+//! in `benches`.
+
+use anyhow::Error;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::{
+    canvas::Histogram,
+    math::{self, Float},
+    parser::Overrides,
+    render::{self, Backend, Status},
+};
+
+type Result<T> = std::result::Result<T, Error>;
+
+/// A scene that's rendered to measure the renderer.
+pub struct BenchScene {
+    pub name: &'static str,
+
+    /// What the scene spends its time on.
+    pub description: &'static str,
+
+    source: fn() -> String,
+}
+
+pub const SCENES: &[BenchScene] = &[
+    BenchScene {
+        name: "csg",
+        description: "nested subtractions, intersections and smooth unions",
+        source: csg,
+    },
+    BenchScene {
+        name: "mesh",
+        description: "a sphere of triangles, found through the bvh",
+        source: mesh,
+    },
+    BenchScene {
+        name: "transparency",
+        description: "refraction through nested glass and water",
+        source: transparency,
+    },
+];
+
+/// The camera that all of the scenes are rendered through, which is small enough for a debug build
+/// to get through them quickly.
+const CAMERA: &str = "(pinhole 64 48 (look-at (0 2 -6) (0 0 0) (0 1 0)) (degrees 60))";
+
+/// Lights and a floor shared by the scenes.
+const STAGE: &str = "\
+(light (point #ffffff (5 10 -10)))
+(light (point #ffe0c0 (-4 3 -3) :intensity 0.5))
+(node floor (transform (translate 0 -1 0) (paint (phong :pattern (checkers (solid #ffffff) (solid #202020))) (plane (0 1 0)))))
+";
+
+impl BenchScene {
+    /// Look up a scene by its name.
+    pub fn find(name: &str) -> Option<&'static BenchScene> {
+        SCENES.iter().find(|scene| scene.name == name)
+    }
+
+    /// The scene description.
+    pub fn source(&self) -> String {
+        (self.source)()
+    }
+}
+
+fn csg() -> String {
+    let mut source = String::from(STAGE);
+    source.push_str(
+        "\
+(node piece
+  (smooth-union 0.2
+    (subtract (intersect (sphere 0.6) (box 0.45 0.45 0.45)) (sphere 0.5))
+    (transform (translate 0 0.5 0) (torus 0.3 0.08))))
+",
+    );
+
+    let mut pieces = String::new();
+    for x in -2..=2 {
+        for z in 0..3 {
+            write!(pieces, " (transform (translate {} 0 {}) piece)", x, z * 2).unwrap();
+        }
+    }
+    writeln!(
+        source,
+        "(render (ascii \"csg\") (whitted (uniform 2) {}) (group floor{}))",
+        CAMERA, pieces
+    )
+    .unwrap();
+    source
+}
+
+fn mesh() -> String {
+    // A sphere of triangles, with `rings` bands from pole to pole of `segments` quads each.
+    let (rings, segments) = (16, 32);
+    let point = |ring: usize, segment: usize| {
+        let theta = math::consts::PI * ring as Float / rings as Float;
+        let phi = 2. * math::consts::PI * segment as Float / segments as Float;
+        format!(
+            "({:.4} {:.4} {:.4})",
+            1.5 * theta.sin() * phi.cos(),
+            1.5 * theta.cos(),
+            1.5 * theta.sin() * phi.sin()
+        )
+    };
+
+    let mut triangles = String::new();
+    for ring in 0..rings {
+        for segment in 0..segments {
+            let a = point(ring, segment);
+            let b = point(ring, segment + 1);
+            let c = point(ring + 1, segment + 1);
+            let d = point(ring + 1, segment);
+            if ring > 0 {
+                write!(triangles, "\n    (triangle {} {} {})", a, b, c).unwrap();
+            }
+            if ring + 1 < rings {
+                write!(triangles, "\n    (triangle {} {} {})", a, c, d).unwrap();
+            }
+        }
+    }
+
+    let mut source = String::from(STAGE);
+    writeln!(
+        source,
+        "(node ball (transform (translate 0 0.5 1) (paint (phong :pattern (solid #c04040)) (group{}))))",
+        triangles
+    )
+    .unwrap();
+    writeln!(
+        source,
+        "(render (ascii \"mesh\") (whitted (uniform 2) {}) (group floor ball))",
+        CAMERA
+    )
+    .unwrap();
+    source
+}
+
+fn transparency() -> String {
+    let mut source = String::from(STAGE);
+    source.push_str(
+        "\
+(material glass (phong :pattern (solid #000000) :transparent 1 :reflective 0.1 :refractive_index 1.5 :absorption #80c0ff :priority 2))
+(material water (phong :pattern (solid #000000) :transparent 1 :refractive_index 1.33 :priority 1))
+(node bath
+  (group
+    (paint water (box 2.5 0.75 1.5))
+    (transform (translate -1.2 0.2 0) (paint glass (sphere 0.6)))
+    (transform (translate 1.2 0.2 0) (paint glass (smooth-union 0.2 (sphere 0.5) (box 0.4 0.4 0.4))))))
+(node lens (transform (translate 0 1.5 -1) (paint glass (intersect (sphere 1) (transform (translate 0 0 1.6) (sphere 1))))))
+",
+    );
+    writeln!(
+        source,
+        "(render (ascii \"transparency\") (whitted (uniform 2) {} :max-reflections 8) (group floor bath lens))",
+        CAMERA
+    )
+    .unwrap();
+    source
+}
+
+/// How long a scene took to render.
+#[derive(Debug, Clone)]
+pub struct Timing {
+    pub elapsed: Duration,
+
+    /// The number of camera rays traced.
+    pub rays: u64,
+}
+
+impl Timing {
+    pub fn rays_per_sec(&self) -> f64 {
+        self.rays as f64 / self.elapsed.as_secs_f64().max(0.001)
+    }
+}
+
+/// Render `scene` once with `threads` threads, including the time taken to parse it.
+pub fn run(scene: &BenchScene, threads: usize, backend: Backend) -> Result<Timing> {
+    let settings = render::Settings {
+        threads,
+        backend,
+        strict: false,
+        coordinator: None,
+        in_memory: true,
+        concurrent: false,
+        cache: None,
+    };
+
+    let rays = Arc::new(AtomicU64::new(0));
+    let progress = {
+        let rays = rays.clone();
+        Arc::new(move |_: &str, _: &Histogram, status: &Status| {
+            rays.fetch_max(status.rays, Ordering::Relaxed);
+        })
+    };
+
+    let start = Instant::now();
+    let outputs = render::render_source(
+        &settings,
+        scene.source(),
+        &[],
+        &Overrides::default(),
+        Some(progress),
+    )?;
+    for output in outputs {
+        output?;
+    }
+
+    Ok(Timing {
+        elapsed: start.elapsed(),
+        rays: rays.load(Ordering::Relaxed),
+    })
+}
+
+#[test]
+fn test_scenes_parse() {
+    for scene in SCENES {
+        let (_, renders) = crate::parser::parse_with(&scene.source(), &[], &Overrides::default())
+            .unwrap_or_else(|err| panic!("{}: {}", scene.name, err));
+        assert_eq!(1, renders.len(), "{}", scene.name);
+        assert!(BenchScene::find(scene.name).is_some());
+    }
+}
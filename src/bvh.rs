@@ -1,6 +1,7 @@
 use nalgebra::{Matrix4, Point3, Vector3};
+use smallvec::SmallVec;
 
-use crate::{ray::Ray, transform::ApplyTransform};
+use crate::{math::Float, ray::Ray, transform::ApplyTransform};
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum BoundingBox {
@@ -8,28 +9,31 @@ pub enum BoundingBox {
     Min,
 
     /// A non-empty bounding box that doesn't include everything.
-    Bounds { min: Point3<f32>, max: Point3<f32> },
+    Bounds {
+        min: Point3<Float>,
+        max: Point3<Float>,
+    },
 
     /// The bounding box that contains everything.
     Max,
 }
 
-fn min_point(a: &Point3<f32>, b: &Point3<f32>) -> Point3<f32> {
+fn min_point(a: &Point3<Float>, b: &Point3<Float>) -> Point3<Float> {
     Point3::new(a.x.min(b.x), a.y.min(b.y), a.z.min(b.z))
 }
 
-fn max_point(a: &Point3<f32>, b: &Point3<f32>) -> Point3<f32> {
+fn max_point(a: &Point3<Float>, b: &Point3<Float>) -> Point3<Float> {
     Point3::new(a.x.max(b.x), a.y.max(b.y), a.z.max(b.z))
 }
 
 impl BoundingBox {
-    pub fn new(a: Point3<f32>, b: Point3<f32>) -> Self {
+    pub fn new(a: Point3<Float>, b: Point3<Float>) -> Self {
         let min = min_point(&a, &b);
         let max = max_point(&a, &b);
         BoundingBox::Bounds { min, max }
     }
 
-    pub fn centroid(&self) -> Point3<f32> {
+    pub fn centroid(&self) -> Point3<Float> {
         match self {
             Self::Min => Point3::origin(),
             Self::Max => Point3::origin(),
@@ -38,14 +42,26 @@ impl BoundingBox {
     }
 
     #[inline]
-    pub fn extent(&self) -> Vector3<f32> {
+    pub fn extent(&self) -> Vector3<Float> {
         match self {
             Self::Min => Vector3::new(0., 0., 0.),
-            Self::Max => Vector3::new(std::f32::INFINITY, std::f32::INFINITY, std::f32::INFINITY),
+            Self::Max => Vector3::new(Float::INFINITY, Float::INFINITY, Float::INFINITY),
             Self::Bounds { min, max } => (max - min) / 2.,
         }
     }
 
+    /// The surface area of the bounding box.
+    pub fn surface_area(&self) -> Float {
+        match self {
+            Self::Min => 0.,
+            Self::Max => Float::INFINITY,
+            Self::Bounds { min, max } => {
+                let d = max - min;
+                2. * (d.x * d.y + d.y * d.z + d.z * d.x)
+            }
+        }
+    }
+
     /// True when the bound contains no volume.
     pub fn is_empty(&self) -> bool {
         match self {
@@ -82,7 +98,7 @@ impl BoundingBox {
         }
     }
 
-    pub fn union_point(&self, other: &Point3<f32>) -> Self {
+    pub fn union_point(&self, other: &Point3<Float>) -> Self {
         match self {
             Self::Min => Self::Bounds {
                 min: other.clone(),
@@ -96,6 +112,21 @@ impl BoundingBox {
         }
     }
 
+    /// The bounding box pushed out by `amount` on every side.
+    pub fn grow(&self, amount: Float) -> Self {
+        match self {
+            Self::Bounds { min, max } => {
+                let amount = Vector3::repeat(amount);
+                Self::Bounds {
+                    min: min - amount,
+                    max: max + amount,
+                }
+            }
+            _ => self.clone(),
+        }
+    }
+
+    /// The overlap of the two bounding boxes, which is empty when they're apart on any axis.
     pub fn intersect(&self, other: &Self) -> Self {
         match (self, other) {
             (Self::Min, _) => Self::Min,
@@ -103,16 +134,19 @@ impl BoundingBox {
             (_, Self::Min) => Self::Min,
             (_, Self::Max) => self.clone(),
             (Self::Bounds { min: lm, max: lx }, Self::Bounds { min: rm, max: rx }) => {
-                Self::Bounds {
-                    min: max_point(lm, rm),
-                    max: min_point(lx, rx),
+                let min = max_point(lm, rm);
+                let max = min_point(lx, rx);
+                if min.x > max.x || min.y > max.y || min.z > max.z {
+                    Self::Min
+                } else {
+                    Self::Bounds { min, max }
                 }
             }
         }
     }
 
     #[cfg(test)]
-    pub fn contains(&self, p: &Point3<f32>) -> bool {
+    pub fn contains(&self, p: &Point3<Float>) -> bool {
         match self {
             Self::Min => false,
             Self::Max => true,
@@ -122,9 +156,16 @@ impl BoundingBox {
 
     /// True when the ray would intersect this bounding box.
     pub fn intersects(&self, ray: &Ray) -> bool {
+        self.ray_interval(ray).is_some()
+    }
+
+    /// The distances along the ray at which the line it travels on enters and leaves this bounding
+    /// box, or `None` if it misses. The entry is negative when the ray starts inside the box, and
+    /// both are when the box is behind it.
+    pub fn ray_interval(&self, ray: &Ray) -> Option<(Float, Float)> {
         match self {
-            Self::Min => false,
-            Self::Max => true,
+            Self::Min => None,
+            Self::Max => Some((Float::NEG_INFINITY, Float::INFINITY)),
             Self::Bounds { min, max } => {
                 let t1 = Point3::new(
                     (min.x - ray.position.x) * ray.inv_direction.x,
@@ -143,14 +184,14 @@ impl BoundingBox {
                 let tmin = min.x.max(min.y).max(min.z);
                 let tmax = max.x.min(max.y).min(max.z);
 
-                tmax >= tmin
+                (tmax >= tmin).then_some((tmin, tmax))
             }
         }
     }
 }
 
 impl ApplyTransform for BoundingBox {
-    fn transform(&self, m: &Matrix4<f32>) -> Self {
+    fn transform(&self, m: &Matrix4<Float>) -> Self {
         match self {
             Self::Min => Self::Min,
             Self::Max => Self::Max,
@@ -185,10 +226,10 @@ enum Axis {
 #[derive(Debug, Clone)]
 struct Node {
     /// The offset to the right subtree, or the start of the values.
-    offset: u16,
+    offset: u32,
 
     /// The number of values present.
-    len: u16,
+    len: u32,
 
     /// The bounds of this node.
     bounds: BoundingBox,
@@ -205,13 +246,63 @@ impl Node {
 
     fn leaf(bounds: BoundingBox, offset: usize, len: usize) -> Self {
         Self {
-            offset: offset as u16,
-            len: len as u16,
+            offset: offset as u32,
+            len: len as u32,
             bounds,
         }
     }
 }
 
+/// The shape of a hierarchy, for judging how well it was built.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Stats {
+    /// The number of levels in the tree, which is zero when it's empty.
+    pub depth: usize,
+    pub leaves: usize,
+    pub largest_leaf: usize,
+
+    /// The number of values without bounds, which are tested by every ray.
+    pub unbounded: usize,
+}
+
+/// How to choose where to split the values of an interior node.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Split {
+    /// Split at the midpoint of the largest axis. This is fast to build, but can produce poor
+    /// trees for unevenly distributed values.
+    Midpoint,
+
+    /// Choose the split that minimizes the surface area heuristic. This is slower to build, but
+    /// produces much better trees for large meshes.
+    Sah,
+}
+
+/// Options that control the quality of the hierarchy that's built.
+#[derive(Debug, Clone)]
+pub struct BuildOptions {
+    /// The strategy used to split interior nodes.
+    pub split: Split,
+
+    /// The number of values that the sah builder will allow in a leaf before it considers
+    /// splitting it, even when splitting doesn't look profitable.
+    pub max_leaf_size: usize,
+}
+
+impl Default for BuildOptions {
+    fn default() -> Self {
+        Self {
+            split: Split::Sah,
+            max_leaf_size: 4,
+        }
+    }
+}
+
+/// The number of buckets that centroids are binned into when evaluating the sah.
+const SAH_BUCKETS: usize = 12;
+
+/// The cost of traversing an interior node, relative to the cost of testing a value.
+const SAH_TRAVERSAL_COST: Float = 0.125;
+
 #[derive(Default, Debug, Clone)]
 pub struct BVH<T> {
     // Values that have max extent
@@ -229,28 +320,32 @@ impl<T: Clone + core::fmt::Debug> BVH<T> {
         }
     }
 
-    pub fn from_nodes(mut values: Vec<(BoundingBox, T)>) -> Self {
+    pub fn from_nodes(mut values: Vec<(BoundingBox, T)>, options: &BuildOptions) -> Self {
+        let _span = tracing::debug_span!("bvh", nodes = values.len()).entered();
         let mut bvh = Self::new();
 
         // First, sort all the nodes that have max extent into the start of the vector, so that
         // it's possible to place them all in the root.
         values.sort_unstable_by_key(|(b, _)| !b.is_max());
         let max_end = values.partition_point(|(b, _)| b.is_max());
-        let values = if max_end > 0 {
-            bvh.max.extend(values.iter().map(|(_, v)| v.clone()));
-            &mut values[max_end..]
-        } else {
-            &mut values
-        };
+        bvh.max
+            .extend(values[..max_end].iter().map(|(_, v)| v.clone()));
+        let values = &mut values[max_end..];
 
         if !values.is_empty() {
-            bvh.build(values);
+            bvh.build(options, values);
         }
 
         bvh
     }
 
-    fn build(&mut self, values: &mut [(BoundingBox, T)]) {
+    fn leaf(&mut self, bounds: BoundingBox, values: &[(BoundingBox, T)]) {
+        self.nodes
+            .push(Node::leaf(bounds, self.values.len(), values.len()));
+        self.values.extend(values.iter().map(|(_, v)| v.clone()));
+    }
+
+    fn build(&mut self, options: &BuildOptions, values: &mut [(BoundingBox, T)]) {
         assert!(!values.is_empty());
 
         let (bounds, centroid) = values.iter().fold(
@@ -263,74 +358,188 @@ impl<T: Clone + core::fmt::Debug> BVH<T> {
         // If the centroids of all the values are the same, there's not point in trying to reduce
         // any further. Conveniently, this is true when the values slice is a singleton.
         if centroid.is_empty() {
-            self.nodes
-                .push(Node::leaf(bounds, self.values.len(), values.len()));
-            self.values.extend(values.iter().map(|(_, v)| v.clone()));
-            return;
+            return self.leaf(bounds, values);
         }
 
-        // Partition the values about the mid-point of the largest centroid bound axis.
-        let (mid_point, axis) = largest_axis(&centroid);
-        let compare: Box<dyn Fn(&BoundingBox) -> bool> = match axis {
-            Axis::X => Box::new(|b| b.centroid().x >= mid_point),
-            Axis::Y => Box::new(|b| b.centroid().y >= mid_point),
-            Axis::Z => Box::new(|b| b.centroid().z >= mid_point),
+        let middle = match options.split {
+            Split::Midpoint => partition_midpoint(&centroid, values),
+            Split::Sah => partition_sah(options, &bounds, &centroid, values),
+        };
+
+        // If the partition failed to separate the values, they all belong in a single leaf.
+        let Some(middle) = middle.filter(|&middle| middle > 0 && middle < values.len()) else {
+            return self.leaf(bounds, values);
         };
 
-        // there's no obvious way to partition values in a slice, so instead we sort according to
-        // the negation of compare, to ensure that values that are less than the midpoint are in
-        // the front of the slice.
-        values.sort_unstable_by_key(|(bound, _)| !compare(bound));
-        let middle = values.partition_point(|(b, _)| compare(b));
         let (left, right) = values.split_at_mut(middle);
-        assert!(
-            !left.is_empty() && !right.is_empty(),
-            "midpoint fell entirely on one side of axis {:?}",
-            axis
-        );
 
         let cur = self.nodes.len();
         self.nodes.push(Node::internal(bounds));
 
-        self.build(left);
+        self.build(options, left);
 
         // update the offset after writing the left subtree
-        self.nodes[cur].offset = self.nodes.len() as u16;
+        self.nodes[cur].offset = self.nodes.len() as u32;
 
-        self.build(right);
+        self.build(options, right);
     }
 }
 
+/// Reorder `values` so that the ones for which `left` returns true come first, returning the
+/// number of those values.
+fn partition<T>(values: &mut [(BoundingBox, T)], left: impl Fn(&BoundingBox) -> bool) -> usize {
+    // there's no obvious way to partition values in a slice, so instead we sort according to the
+    // negation of the predicate, to ensure that the left values are in the front of the slice.
+    values.sort_unstable_by_key(|(bound, _)| !left(bound));
+    values.partition_point(|(b, _)| left(b))
+}
+
+/// Partition the values about the mid-point of the largest centroid bound axis.
+fn partition_midpoint<T>(centroid: &BoundingBox, values: &mut [(BoundingBox, T)]) -> Option<usize> {
+    let (mid_point, axis) = largest_axis(centroid);
+    Some(partition(values, |b| {
+        b.centroid()[axis as usize] < mid_point
+    }))
+}
+
+/// Partition the values along the largest centroid bound axis, at the bucket boundary that
+/// minimizes the surface area heuristic. Returns `None` when a leaf would be cheaper.
+fn partition_sah<T>(
+    options: &BuildOptions,
+    bounds: &BoundingBox,
+    centroid: &BoundingBox,
+    values: &mut [(BoundingBox, T)],
+) -> Option<usize> {
+    let BoundingBox::Bounds { min, max } = centroid else {
+        return None;
+    };
+
+    let (_, axis) = largest_axis(centroid);
+    let axis = axis as usize;
+    let lo = min[axis];
+    let width = max[axis] - lo;
+
+    let bucket = |b: &BoundingBox| {
+        let ix = ((b.centroid()[axis] - lo) / width * SAH_BUCKETS as Float) as usize;
+        ix.min(SAH_BUCKETS - 1)
+    };
+
+    let mut buckets: [(usize, BoundingBox); SAH_BUCKETS] =
+        std::array::from_fn(|_| (0, BoundingBox::min()));
+    for (bound, _) in values.iter() {
+        let (count, bounds) = &mut buckets[bucket(bound)];
+        *count += 1;
+        *bounds = bounds.union(bound);
+    }
+
+    // The area of the parent is only used to normalize the costs, so a flat parent can fall back
+    // on comparing the counts.
+    let area = bounds.surface_area();
+    let normalize = if area > 0. { 1. / area } else { 0. };
+
+    let mut best = None;
+    for split in 1..SAH_BUCKETS {
+        let (left_count, left) = buckets[..split]
+            .iter()
+            .fold((0, BoundingBox::min()), |(n, acc), (count, b)| {
+                (n + count, acc.union(b))
+            });
+        let (right_count, right) = buckets[split..]
+            .iter()
+            .fold((0, BoundingBox::min()), |(n, acc), (count, b)| {
+                (n + count, acc.union(b))
+            });
+
+        if left_count == 0 || right_count == 0 {
+            continue;
+        }
+
+        let weighted = if normalize > 0. {
+            (left_count as Float * left.surface_area()
+                + right_count as Float * right.surface_area())
+                * normalize
+        } else {
+            left_count.max(right_count) as Float
+        };
+        let cost = SAH_TRAVERSAL_COST + weighted;
+
+        if best.is_none_or(|(_, best)| cost < best) {
+            best = Some((split, cost));
+        }
+    }
+
+    let (split, cost) = best?;
+
+    if values.len() <= options.max_leaf_size && cost >= values.len() as Float {
+        return None;
+    }
+
+    Some(partition(values, |b| bucket(b) < split))
+}
+
 impl<T> BVH<T> {
     pub fn fold_intersections<R, F>(&self, ray: &Ray, mut acc: R, mut fun: F) -> R
     where
         F: FnMut(R, &T) -> R,
     {
         acc = self.max.iter().fold(acc, &mut fun);
-        if !self.nodes.is_empty() {
-            self.intersections_rec(ray, 0, acc, &mut fun)
-        } else {
-            acc
+
+        if self.nodes.is_empty() {
+            return acc;
         }
-    }
 
-    fn intersections_rec<R, F>(&self, ray: &Ray, ix: usize, acc: R, fun: &mut F) -> R
-    where
-        F: FnMut(R, &T) -> R,
-    {
-        let node = &self.nodes[ix];
-        if node.bounds.intersects(ray) {
+        // Traverse the tree with an explicit stack, visiting left subtrees first.
+        let mut stack: SmallVec<[u32; 64]> = SmallVec::new();
+        stack.push(0);
+
+        while let Some(ix) = stack.pop() {
+            let node = &self.nodes[ix as usize];
+            if !node.bounds.intersects(ray) {
+                continue;
+            }
+
             if node.len > 0 {
                 let start = node.offset as usize;
                 let end = start + node.len as usize;
-                self.values[start..end].iter().fold(acc, fun)
+                acc = self.values[start..end].iter().fold(acc, &mut fun);
             } else {
-                let acc = self.intersections_rec(ray, ix + 1, acc, fun);
-                self.intersections_rec(ray, node.offset as usize, acc, fun)
+                stack.push(node.offset);
+                stack.push(ix + 1);
             }
-        } else {
-            acc
         }
+
+        acc
+    }
+
+    /// Measure the shape of the hierarchy.
+    pub fn stats(&self) -> Stats {
+        let mut stats = Stats {
+            unbounded: self.max.len(),
+            ..Stats::default()
+        };
+
+        let mut stack = vec![(0, 1)];
+        while let Some((ix, depth)) = stack.pop() {
+            let Some(node) = self.nodes.get(ix as usize) else {
+                continue;
+            };
+
+            stats.depth = stats.depth.max(depth);
+            if node.len > 0 {
+                stats.leaves += 1;
+                stats.largest_leaf = stats.largest_leaf.max(node.len as usize);
+            } else {
+                stack.push((node.offset, depth + 1));
+                stack.push((ix + 1, depth + 1));
+            }
+        }
+
+        stats
+    }
+
+    /// Iterate over all the values stored in the hierarchy.
+    pub fn iter(&self) -> impl Iterator<Item = &T> {
+        self.max.iter().chain(self.values.iter())
     }
 
     pub fn bounding_box(&self) -> BoundingBox {
@@ -343,10 +552,10 @@ impl<T> BVH<T> {
     }
 }
 
-fn largest_axis(bound: &BoundingBox) -> (f32, Axis) {
+fn largest_axis(bound: &BoundingBox) -> (Float, Axis) {
     match bound {
         BoundingBox::Min => (0., Axis::X),
-        BoundingBox::Max => (std::f32::INFINITY, Axis::X),
+        BoundingBox::Max => (Float::INFINITY, Axis::X),
         BoundingBox::Bounds { min, max } => {
             let diff = max - min;
 
@@ -360,7 +569,7 @@ fn largest_axis(bound: &BoundingBox) -> (f32, Axis) {
                 axis = Axis::Z;
             }
 
-            (min[axis as usize] + max[axis as usize] / 2., axis)
+            ((min[axis as usize] + max[axis as usize]) / 2., axis)
         }
     }
 }
@@ -375,7 +584,7 @@ mod tests {
 
         let bound = BoundingBox::new(Point3::new(-1., -1., 0.), Point3::new(1., 1., 0.));
         let other = bound.apply(&Transform::new().rotate(&Vector3::new(
-            std::f32::consts::FRAC_PI_2,
+            crate::math::consts::FRAC_PI_2,
             0.,
             0.,
         )));
@@ -385,7 +594,7 @@ mod tests {
 
         let bound = BoundingBox::max();
         let other = bound.apply(&Transform::new().rotate(&Vector3::new(
-            std::f32::consts::FRAC_PI_2,
+            crate::math::consts::FRAC_PI_2,
             0.,
             0.,
         )));
@@ -419,11 +628,101 @@ mod tests {
         let a = BoundingBox::new(Point3::new(1., 1., 1.), Point3::new(-1., -1., -1.));
         assert_eq!(a, a.intersect(&BoundingBox::max()));
         assert_eq!(BoundingBox::min(), a.intersect(&BoundingBox::min()));
+
+        let b = BoundingBox::new(Point3::new(0., 0., 0.), Point3::new(2., 2., 2.));
+        assert_eq!(
+            BoundingBox::new(Point3::new(0., 0., 0.), Point3::new(1., 1., 1.)),
+            a.intersect(&b)
+        );
+        let apart = BoundingBox::new(Point3::new(0., 3., 0.), Point3::new(2., 4., 2.));
+        assert_eq!(BoundingBox::min(), a.intersect(&apart));
+    }
+
+    #[test]
+    fn test_ray_interval() {
+        use nalgebra::Unit;
+
+        let a = BoundingBox::new(Point3::new(1., 1., 1.), Point3::new(-1., -1., -1.));
+        let z = Unit::new_normalize(Vector3::new(0., 0., 1.));
+
+        let ray = Ray::new(Point3::new(0., 0., -5.), z);
+        assert_eq!(Some((4., 6.)), a.ray_interval(&ray));
+        assert_eq!(
+            Some((-6., -4.)),
+            a.ray_interval(&Ray::new(Point3::new(0., 0., 5.), z))
+        );
+        assert_eq!(None, a.ray_interval(&Ray::new(Point3::new(2., 0., -5.), z)));
+
+        assert_eq!(None, BoundingBox::min().ray_interval(&ray));
+        assert!(BoundingBox::max().intersects(&ray));
     }
 
     #[test]
     fn test_largest_axis() {
         let bound = BoundingBox::new(Point3::new(0., 0., 0.), Point3::new(0., 0., 2.));
         assert_eq!((1.0, Axis::Z), largest_axis(&bound));
+
+        // The midpoint is halfway between the ends, wherever the bound is.
+        let bound = BoundingBox::new(Point3::new(0., 4., 0.), Point3::new(1., 8., 1.));
+        assert_eq!((6.0, Axis::Y), largest_axis(&bound));
+    }
+
+    #[test]
+    fn test_surface_area() {
+        let a = BoundingBox::new(Point3::new(0., 0., 0.), Point3::new(1., 2., 3.));
+        assert_eq!(22., a.surface_area());
+        assert_eq!(0., BoundingBox::min().surface_area());
+    }
+
+    /// Build hierarchies with more values than fit in a `u16`, and check that every value is
+    /// still reachable.
+    #[test]
+    fn test_large_hierarchy() {
+        use nalgebra::Unit;
+
+        let count = 70_000;
+        let values: Vec<_> = (0..count)
+            .map(|i| {
+                let x = i as Float * 2.;
+                let bound =
+                    BoundingBox::new(Point3::new(x, -0.5, -0.5), Point3::new(x + 1., 0.5, 0.5));
+                (bound, i)
+            })
+            .chain(std::iter::once((BoundingBox::max(), count)))
+            .collect();
+
+        for split in [Split::Midpoint, Split::Sah] {
+            let options = BuildOptions {
+                split,
+                ..BuildOptions::default()
+            };
+            let bvh = BVH::from_nodes(values.clone(), &options);
+
+            let hit = Ray::new(
+                Point3::new(-1., 0., 0.),
+                Unit::new_normalize(Vector3::new(1., 0., 0.)),
+            );
+            let mut seen = bvh.fold_intersections(&hit, Vec::new(), |mut acc, &v| {
+                acc.push(v);
+                acc
+            });
+            seen.sort_unstable();
+            assert_eq!((0..=count).collect::<Vec<_>>(), seen, "{:?}", split);
+
+            let miss = Ray::new(
+                Point3::new(-1., 10., 0.),
+                Unit::new_normalize(Vector3::new(1., 0., 0.)),
+            );
+            let seen = bvh.fold_intersections(&miss, Vec::new(), |mut acc, &v| {
+                acc.push(v);
+                acc
+            });
+            assert_eq!(vec![count], seen, "{:?}", split);
+
+            let stats = bvh.stats();
+            assert_eq!(1, stats.unbounded);
+            assert!(stats.depth > 1 && stats.depth < 64, "{:?}", stats);
+            assert!(stats.leaves * stats.largest_leaf >= count, "{:?}", stats);
+        }
     }
 }
@@ -1,8 +1,9 @@
 use std::sync::Arc;
 
-use nalgebra::{Point2, Point3, Unit, Vector3};
+use nalgebra::{Point2, Point3, Rotation3, Unit, Vector3};
 
 use crate::canvas::Canvas;
+use crate::math::Float;
 use crate::ray::Ray;
 use crate::transform::{ApplyTransform, Transform};
 
@@ -26,16 +27,16 @@ impl CanvasInfo {
     }
 
     /// Compute the aspect ratio.
-    pub fn aspect_ratio(&self) -> f32 {
-        self.width_f32() / self.height_f32()
+    pub fn aspect_ratio(&self) -> Float {
+        self.width_float() / self.height_float()
     }
 
-    pub fn width_f32(&self) -> f32 {
-        self.width as f32
+    pub fn width_float(&self) -> Float {
+        self.width as Float
     }
 
-    pub fn height_f32(&self) -> f32 {
-        self.height as f32
+    pub fn height_float(&self) -> Float {
+        self.height as Float
     }
 }
 
@@ -49,7 +50,7 @@ impl ProjectiveCamera {
     // TODO: support targeting pixels that aren't square, like ascii characters
     pub fn new(info: &CanvasInfo, camera_to_world: Transform, camera_to_screen: Transform) -> Self {
         let screen_to_raster = Transform::new()
-            .scale(&Vector3::new(info.width_f32(), info.height_f32(), 1.))
+            .scale(&Vector3::new(info.width_float(), info.height_float(), 1.))
             .scale(&Vector3::new(-0.5, 0.5, 1.))
             .translate(&Vector3::new(-1., 1., 0.));
 
@@ -69,7 +70,7 @@ pub struct PinholeCamera {
 }
 
 impl PinholeCamera {
-    pub fn new(info: &CanvasInfo, camera_to_world: Transform, fov: f32) -> Self {
+    pub fn new(info: &CanvasInfo, camera_to_world: Transform, fov: Float) -> Self {
         let camera_to_screen = Transform::perspective(info.aspect_ratio(), fov, -1., -1000.);
         Self {
             camera: ProjectiveCamera::new(info, camera_to_world, camera_to_screen),
@@ -77,43 +78,234 @@ impl PinholeCamera {
     }
 }
 
+/// A camera that sees all around it, with longitude across the canvas and latitude down it, for
+/// panoramas viewed in VR. It covers a full sphere by default, or less of one, such as the front
+/// half for VR180.
+///
+/// With a stereo separation the canvas is split into side-by-side images for the left and right
+/// eyes. They're rendered as omni-directional stereo, where each column's eyes are offset across
+/// the direction it looks in, so that every direction has the parallax of a viewer facing it.
+#[derive(Debug, Clone)]
+pub struct EquirectangularCamera {
+    camera_to_world: Transform,
+    width: Float,
+    height: Float,
+
+    /// The angles covered across and down the image of each eye, in radians.
+    horizontal: Float,
+    vertical: Float,
+
+    /// The distance between the eyes, or zero for a single image.
+    separation: Float,
+}
+
+impl EquirectangularCamera {
+    pub fn new(
+        info: &CanvasInfo,
+        camera_to_world: Transform,
+        horizontal: Float,
+        vertical: Float,
+        separation: Float,
+    ) -> Self {
+        Self {
+            camera_to_world,
+            width: info.width_float(),
+            height: info.height_float(),
+            horizontal,
+            vertical,
+            separation,
+        }
+    }
+}
+
+impl Camera for EquirectangularCamera {
+    fn generate_ray(&self, sample: &Sample) -> Option<Ray> {
+        // The position across the image of one eye, and which way that eye is offset.
+        let (u, eye) = if self.separation > 0. {
+            let half = self.width / 2.;
+            if sample.film.x < half {
+                (sample.film.x / half, -0.5)
+            } else {
+                ((sample.film.x - half) / half, 0.5)
+            }
+        } else {
+            (sample.film.x / self.width, 0.)
+        };
+
+        let longitude = (u - 0.5) * self.horizontal;
+        let latitude = (0.5 - sample.film.y / self.height) * self.vertical;
+        let direction = Vector3::new(
+            latitude.cos() * longitude.sin(),
+            latitude.sin(),
+            latitude.cos() * longitude.cos(),
+        );
+        let right = Vector3::new(longitude.cos(), 0., -longitude.sin());
+        let origin = Point3::origin() + right * (eye * self.separation);
+
+        Some(Ray::new(origin, Unit::new_normalize(direction)).invert(&self.camera_to_world))
+    }
+}
+
+/// A camera with a fisheye lens, whose image is a circle that fills the shorter side of the canvas.
+/// The angle from the center of view grows with the distance from the center of the circle, as
+/// given by the projection, up to half the field of view at its edge.
+#[derive(Debug, Clone)]
+pub struct FisheyeCamera {
+    camera_to_world: Transform,
+    center: Point2<Float>,
+
+    /// The radius of the circle, in pixels.
+    radius: Float,
+    fov: Float,
+    projection: FisheyeProjection,
+}
+
+/// How the angles seen by a [`FisheyeCamera`] are laid out across its image.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum FisheyeProjection {
+    /// The distance from the center is proportional to the angle, as in planetarium dome masters.
+    #[default]
+    Equidistant,
+
+    /// Each part of the image covers the same solid angle, so areas keep their relative sizes.
+    Equisolid,
+}
+
+impl FisheyeCamera {
+    pub fn new(
+        info: &CanvasInfo,
+        camera_to_world: Transform,
+        fov: Float,
+        projection: FisheyeProjection,
+    ) -> Self {
+        Self {
+            camera_to_world,
+            center: Point2::new(info.width_float() / 2., info.height_float() / 2.),
+            radius: info.width_float().min(info.height_float()) / 2.,
+            fov,
+            projection,
+        }
+    }
+}
+
+impl Camera for FisheyeCamera {
+    fn generate_ray(&self, sample: &Sample) -> Option<Ray> {
+        // The film's y axis points down, while the camera's points up.
+        let offset = (sample.film - self.center) / self.radius;
+        let r = offset.norm();
+        if r > 1. {
+            return None;
+        }
+
+        let theta = match self.projection {
+            FisheyeProjection::Equidistant => r * self.fov / 2.,
+            FisheyeProjection::Equisolid => 2. * (r * (self.fov / 4.).sin()).asin(),
+        };
+        let phi = (-offset.y).atan2(offset.x);
+        let direction = Vector3::new(
+            theta.sin() * phi.cos(),
+            theta.sin() * phi.sin(),
+            theta.cos(),
+        );
+
+        Some(
+            Ray::new(Point3::origin(), Unit::new_normalize(direction))
+                .invert(&self.camera_to_world),
+        )
+    }
+}
+
+/// Adjustments to the viewpoint of a camera, for inspecting a scene interactively. The camera
+/// orbits around and pans with the point that it's looking at.
+#[derive(Debug, Clone, PartialEq)]
+pub struct View {
+    /// Rotation around the camera's up axis, through the target, in radians.
+    pub yaw: Float,
+
+    /// Rotation up and over the target, in radians.
+    pub pitch: Float,
+
+    /// Movement of the target across the view, in multiples of its distance from the camera.
+    pub pan_x: Float,
+    pub pan_y: Float,
+
+    /// Scales the distance from the camera to the target.
+    pub dolly: Float,
+}
+
+impl Default for View {
+    fn default() -> Self {
+        Self {
+            yaw: 0.,
+            pitch: 0.,
+            pan_x: 0.,
+            pan_y: 0.,
+            dolly: 1.,
+        }
+    }
+}
+
+impl View {
+    /// Move the camera transform `world_to_camera`, which looks at a target `distance` in front of
+    /// it.
+    pub fn apply(&self, world_to_camera: &Transform, distance: Float) -> Transform {
+        let eye = Point3::origin().invert(world_to_camera);
+        let axis = |x, y, z| Unit::new_normalize(Vector3::new(x, y, z).invert(world_to_camera));
+        let forward = axis(0., 0., 1.);
+        let up = axis(0., 1., 0.);
+        let right = axis(1., 0., 0.);
+
+        // Panning follows the camera as it orbits, so that it's always across the view.
+        let rotation = Rotation3::from_axis_angle(&up, self.yaw)
+            * Rotation3::from_axis_angle(&right, self.pitch);
+        let target = eye
+            + forward.as_ref() * distance
+            + rotation * (right.as_ref() * self.pan_x + up.as_ref() * self.pan_y) * distance;
+        let offset = rotation * (forward.as_ref() * -distance * self.dolly);
+
+        Transform::look_at(&(target + offset), &target, &(rotation * up.as_ref()))
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct Sample {
     /// The point on the film where the ray originates.
-    pub film: Point2<f32>,
+    pub film: Point2<Float>,
 }
 
 impl Sample {
-    pub fn new(fx: f32, fy: f32) -> Self {
+    pub fn new(fx: Float, fy: Float) -> Self {
         Self {
             film: Point2::new(fx, fy),
         }
     }
 }
 
+/// Turns points on the film into rays through the scene.
 pub trait Camera: std::marker::Send + std::marker::Sync {
-    /// Given a [`Sample`], generate a ray.
-    fn generate_ray(&self, sample: &Sample) -> Ray;
+    /// Given a [`Sample`], generate a ray. There's no ray for parts of the film that the camera
+    /// doesn't see anything through, such as outside the circle of a fisheye lens.
+    fn generate_ray(&self, sample: &Sample) -> Option<Ray>;
 }
 
 impl<C> Camera for Arc<C>
 where
     C: Camera + ?Sized,
 {
-    fn generate_ray(&self, sample: &Sample) -> Ray {
+    fn generate_ray(&self, sample: &Sample) -> Option<Ray> {
         self.as_ref().generate_ray(sample)
     }
 }
 
 impl Camera for PinholeCamera {
-    fn generate_ray(&self, sample: &Sample) -> Ray {
+    fn generate_ray(&self, sample: &Sample) -> Option<Ray> {
         let canvas =
             Point3::new(sample.film.x, sample.film.y, 0.).apply(&self.camera.raster_to_camera);
         let camera = Unit::new_normalize(canvas - Point3::origin());
 
         let ray = Ray::new(Point3::origin(), camera);
 
-        ray.invert(&self.camera.camera_to_world)
+        Some(ray.invert(&self.camera.camera_to_world))
     }
 }
 
@@ -145,12 +337,102 @@ fn test_projective_camera() {
 #[test]
 fn test_pinhole_camera() {
     let t = Transform::new();
-    let fov = std::f32::consts::FRAC_PI_2;
+    let fov = crate::math::consts::FRAC_PI_2;
     let info = CanvasInfo::new(10, 10);
     let camera = PinholeCamera::new(&info, t, fov);
 
-    let ray = camera.generate_ray(&Sample::new(5., 5.));
+    let ray = camera.generate_ray(&Sample::new(5., 5.)).unwrap();
 
     assert_eq!(Point3::new(0., 0., 0.), ray.position);
     assert_eq!(Unit::new_normalize(Vector3::new(0., 0., 1.)), ray.direction);
 }
+
+#[test]
+fn test_view() {
+    let t = Transform::look_at(
+        &Point3::new(0., 0., -2.),
+        &Point3::origin(),
+        &Vector3::new(0., 1., 0.),
+    );
+    let eye = |view: &View| Point3::origin().invert(&view.apply(&t, 2.));
+    let close = |a: Point3<Float>, b: Point3<Float>| (a - b).norm() < 1e-4;
+
+    assert!(close(Point3::new(0., 0., -2.), eye(&View::default())));
+
+    let view = View {
+        yaw: crate::math::consts::PI,
+        ..View::default()
+    };
+    assert!(close(Point3::new(0., 0., 2.), eye(&view)));
+
+    let view = View {
+        dolly: 0.5,
+        pan_x: 1.,
+        ..View::default()
+    };
+    assert!(close(Point3::new(2., 0., -1.), eye(&view)));
+}
+
+#[test]
+fn test_equirectangular_camera() {
+    let info = CanvasInfo::new(40, 20);
+    let full = crate::math::consts::PI * 2.;
+    let half = crate::math::consts::PI;
+    let close = |a: Vector3<Float>, b: Vector3<Float>| (a - b).norm() < 1e-4;
+
+    // The center looks forward like a pinhole camera, the left edge behind, and the top up.
+    let camera = EquirectangularCamera::new(&info, Transform::new(), full, half, 0.);
+    let ray = |x, y| camera.generate_ray(&Sample::new(x, y)).unwrap();
+    assert!(close(Vector3::new(0., 0., 1.), *ray(20., 10.).direction));
+    assert!(close(Vector3::new(-1., 0., 0.), *ray(10., 10.).direction));
+    assert!(close(Vector3::new(0., 0., -1.), *ray(0., 10.).direction));
+    assert!(close(Vector3::new(0., 1., 0.), *ray(20., 0.).direction));
+
+    // Each eye gets half of the canvas, offset across the direction it looks.
+    let stereo = EquirectangularCamera::new(&info, Transform::new(), half, half, 0.1);
+    let left = stereo.generate_ray(&Sample::new(10., 10.)).unwrap();
+    let right = stereo.generate_ray(&Sample::new(30., 10.)).unwrap();
+    assert!(close(Vector3::new(0., 0., 1.), *left.direction));
+    assert!(close(Vector3::new(0., 0., 1.), *right.direction));
+    assert!(close(Vector3::new(-0.05, 0., 0.), left.position.coords));
+    assert!(close(Vector3::new(0.05, 0., 0.), right.position.coords));
+
+    let side = stereo.generate_ray(&Sample::new(0., 10.)).unwrap();
+    assert!(close(Vector3::new(-1., 0., 0.), *side.direction));
+    assert!(close(Vector3::new(0., 0., -0.05), side.position.coords));
+}
+
+#[test]
+fn test_fisheye_camera() {
+    let info = CanvasInfo::new(20, 10);
+    let close = |a: Vector3<Float>, b: Vector3<Float>| (a - b).norm() < 1e-4;
+    let fov = crate::math::consts::PI;
+
+    // The circle fills the height of the canvas, and its edge looks out to the side.
+    let camera = FisheyeCamera::new(&info, Transform::new(), fov, FisheyeProjection::Equidistant);
+    let ray = |x, y| camera.generate_ray(&Sample::new(x, y));
+    assert!(close(
+        Vector3::new(0., 0., 1.),
+        *ray(10., 5.).unwrap().direction
+    ));
+    assert!(close(
+        Vector3::new(-1., 0., 0.),
+        *ray(5., 5.).unwrap().direction
+    ));
+    assert!(close(
+        Vector3::new(0., 1., 0.),
+        *ray(10., 0.).unwrap().direction
+    ));
+    assert!(ray(2., 5.).is_none());
+
+    // Halfway out, an equidistant lens is halfway to the edge, while an equisolid lens has
+    // covered a quarter of the hemisphere's area.
+    let diagonal = Vector3::new(1., 0., 1.).normalize();
+    assert!(close(diagonal, *ray(12.5, 5.).unwrap().direction));
+
+    let camera = FisheyeCamera::new(&info, Transform::new(), fov, FisheyeProjection::Equisolid);
+    let ray = camera.generate_ray(&Sample::new(12.5, 5.)).unwrap();
+    let theta = ray.direction.x.atan2(ray.direction.z);
+    assert!((theta - 2. * (0.5 * (fov / 4.).sin()).asin()).abs() < 1e-4);
+    assert!(theta < fov / 4.);
+}
@@ -1,3 +1,7 @@
+use nalgebra::Vector3;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+
 use crate::math::Mix;
 
 #[derive(Debug, Default, Clone)]
@@ -13,6 +17,53 @@ pub struct Canvas {
     width: u32,
     height: u32,
     buffer: Vec<Color>,
+
+    /// How much of each pixel is covered by the scene, rather than a transparent background. The
+    /// colors are premultiplied by it.
+    alpha: Vec<f32>,
+}
+
+/// How a [`Canvas`] is drawn as text for a terminal.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum AsciiMode {
+    /// A character per pixel, chosen from a palette ordered by brightness.
+    #[default]
+    Chars,
+
+    /// A full block per pixel, colored with 24-bit ANSI escapes.
+    ColorBlocks,
+
+    /// Two pixels per character, stacked in an upper half block using the foreground and
+    /// background colors.
+    HalfBlocks,
+
+    /// Eight pixels per character, as the dots of a braille pattern that are raised for pixels
+    /// brighter than half.
+    Braille,
+}
+
+/// The channels written when a [`Canvas`] is saved as an image.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Channels {
+    #[default]
+    Rgb,
+
+    /// The color along with its coverage, for compositing renders with a transparent background.
+    Rgba,
+
+    /// A single channel, using the weights of [`Color::to_grayscale`].
+    Grayscale,
+
+    /// A single channel holding the relative luminance from [`Color::luminance`].
+    Luma,
+}
+
+/// The number of bits stored for each channel when a [`Canvas`] is saved as an image.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum BitDepth {
+    #[default]
+    Eight,
+    Sixteen,
 }
 
 /// An iterator for the rows of the resulting image, starting at the top and working down. This is
@@ -59,9 +110,357 @@ impl Color {
     pub fn to_grayscale(&self) -> f32 {
         0.3 * self.r + 0.59 * self.g + 0.11 * self.b
     }
+
+    /// The relative luminance of the [`Color`], using the Rec. 709 primaries.
+    pub fn luminance(&self) -> f32 {
+        0.2126 * self.r + 0.7152 * self.g + 0.0722 * self.b
+    }
 }
 
-impl Mix for &Color {
+/// Automatic exposure, scaling the canvas so that its log-average luminance maps to `key`.
+#[derive(Debug, Clone)]
+pub struct AutoExposure {
+    /// The target log-average luminance of the exposed image.
+    pub key: f32,
+
+    /// The smallest scale factor that will be applied.
+    pub min: f32,
+
+    /// The largest scale factor that will be applied.
+    pub max: f32,
+}
+
+impl Default for AutoExposure {
+    fn default() -> Self {
+        Self {
+            key: 0.18,
+            min: 0.1,
+            max: 10.,
+        }
+    }
+}
+
+impl AutoExposure {
+    /// Compute the scale factor that this exposure would apply to the canvas.
+    pub fn scale_factor(&self, canvas: &Canvas) -> f32 {
+        self.scale_for(canvas.log_average_luminance())
+    }
+
+    /// Compute the scale factor for an image with the given log-average luminance.
+    pub fn scale_for(&self, avg: f32) -> f32 {
+        if avg <= 0. {
+            return self.max;
+        }
+
+        (self.key / avg).clamp(self.min, self.max)
+    }
+
+    /// Expose the canvas.
+    pub fn apply(&self, canvas: &mut Canvas) {
+        let factor = self.scale_factor(canvas);
+        for pixel in canvas.pixels_mut() {
+            *pixel *= factor;
+        }
+    }
+}
+
+/// The largest blur radius and streak length of a bloom, in pixels. The work to spread each pixel
+/// grows with both.
+pub const MAX_BLOOM_SIZE: f32 = 256.;
+
+/// The largest standard deviation of the distance weight of a denoise, in pixels. Each pixel is
+/// averaged with the square of neighbors within twice this distance.
+pub const MAX_DENOISE_RADIUS: f32 = 64.;
+
+/// The most streaks of glare that a bloom can draw through each pixel.
+pub const MAX_STREAKS: u32 = 16;
+
+/// Bloom, spreading the light of pixels brighter than `threshold` over their neighbors so that
+/// bright surfaces appear to glow.
+#[derive(Debug, Clone)]
+pub struct Bloom {
+    /// The luminance above which pixels bloom. Only the light above it is spread.
+    pub threshold: f32,
+
+    /// The standard deviation of the blur, in pixels.
+    pub radius: f32,
+
+    /// How much of the spread light is added back to the canvas.
+    pub intensity: f32,
+
+    /// The number of lines of glare drawn through bright pixels, evenly spaced around them. Two
+    /// gives a four-pointed star, and zero leaves out the glare.
+    pub streaks: u32,
+
+    /// How far the glare reaches, in pixels.
+    pub streak_length: f32,
+}
+
+impl Default for Bloom {
+    fn default() -> Self {
+        Self {
+            threshold: 1.,
+            radius: 4.,
+            intensity: 0.5,
+            streaks: 0,
+            streak_length: 16.,
+        }
+    }
+}
+
+impl Bloom {
+    /// Add the bloom to the canvas.
+    pub fn apply(&self, canvas: &mut Canvas) {
+        let width = canvas.width as usize;
+        let height = canvas.height as usize;
+
+        let bright: Vec<Color> = canvas
+            .buffer
+            .iter()
+            .map(|color| {
+                let luminance = color.luminance();
+                if luminance > self.threshold {
+                    color * ((luminance - self.threshold) / luminance)
+                } else {
+                    Color::black()
+                }
+            })
+            .collect();
+
+        // The gaussian is separable, so it's applied along the rows and then the columns.
+        let kernel = gaussian_kernel(self.radius);
+        let blurred = convolve(&bright, width, height, &kernel, (1, 0));
+        let blurred = convolve(&blurred, width, height, &kernel, (0, 1));
+        for (pixel, glow) in canvas.buffer.iter_mut().zip(&blurred) {
+            *pixel += glow * self.intensity;
+        }
+
+        if self.streaks == 0 {
+            return;
+        }
+
+        // Each streak is a line through the pixel, whose weights fall off exponentially along it.
+        let length = self.streak_length.max(1.).ceil() as i64;
+        let weights: Vec<f32> = (-length..=length)
+            .map(|t| f32::exp(-4. * t.abs() as f32 / length as f32))
+            .collect();
+        let total = weights.iter().sum::<f32>() * self.streaks as f32;
+
+        for streak in 0..self.streaks {
+            let angle = std::f32::consts::PI * streak as f32 / self.streaks as f32;
+            let (dy, dx) = angle.sin_cos();
+            for (i, pixel) in canvas.buffer.iter_mut().enumerate() {
+                let (x, y) = ((i % width) as f32, (i / width) as f32);
+                let mut glare = Color::black();
+                for (t, weight) in (-length..=length).zip(&weights) {
+                    let sx = (x + dx * t as f32).round();
+                    let sy = (y + dy * t as f32).round();
+                    if sx >= 0. && sy >= 0. && (sx as usize) < width && (sy as usize) < height {
+                        glare += &bright[sy as usize * width + sx as usize] * *weight;
+                    }
+                }
+                *pixel += glare * (self.intensity / total);
+            }
+        }
+    }
+}
+
+/// The imperfections of a real lens, which give renders a photographic finish. Each is measured at
+/// the corners of the image, and grows with the square of the distance from its center.
+#[derive(Debug, Default, Clone)]
+pub struct LensEffects {
+    /// How much darker the corners are, from `0` for no change to `1` for black.
+    pub vignette: f32,
+
+    /// How far the red and blue channels are pulled apart at the corners, in pixels. Red moves
+    /// outwards and blue inwards.
+    pub aberration: f32,
+
+    /// Barrel distortion when positive, bowing straight lines outwards, and pincushion distortion
+    /// when negative. The corners show what would be this fraction further out without it.
+    pub distortion: f32,
+}
+
+impl LensEffects {
+    /// Apply the effects to the canvas. Parts of the image that are distorted in from beyond its
+    /// edges are left black.
+    pub fn apply(&self, canvas: &mut Canvas) {
+        let width = canvas.width as usize;
+        let (cx, cy) = (canvas.width as f32 / 2., canvas.height as f32 / 2.);
+        let corner = cx.hypot(cy);
+
+        let pixels = canvas.buffer.clone();
+        let alpha = canvas.alpha.clone();
+        for (i, (pixel, coverage)) in canvas.buffer.iter_mut().zip(&mut canvas.alpha).enumerate() {
+            // The offset of the pixel from the center, as a fraction of the distance to a corner.
+            let dx = ((i % width) as f32 + 0.5 - cx) / corner;
+            let dy = ((i / width) as f32 + 0.5 - cy) / corner;
+            let r2 = dx * dx + dy * dy;
+
+            let scale = 1. + self.distortion * r2;
+            let shift = self.aberration * r2 / corner;
+            let at = |scale: f32| {
+                (
+                    cx + dx * scale * corner - 0.5,
+                    cy + dy * scale * corner - 0.5,
+                )
+            };
+            let (rx, ry) = at(scale + shift);
+            let (gx, gy) = at(scale);
+            let (bx, by) = at(scale - shift);
+
+            let sample = |x, y, channel: &dyn Fn(usize) -> f32| {
+                bilinear(x, y, canvas.width, canvas.height, channel)
+            };
+            let vignette = (1. - self.vignette * r2).max(0.);
+            *pixel = Color::new(
+                sample(rx, ry, &|j| pixels[j].r),
+                sample(gx, gy, &|j| pixels[j].g),
+                sample(bx, by, &|j| pixels[j].b),
+            ) * vignette;
+            *coverage = sample(gx, gy, &|j| alpha[j]);
+        }
+    }
+}
+
+/// Interpolate between the four pixels around `(x, y)`, where pixel centers fall on whole numbers,
+/// reading each pixel's value by its index with `channel`. Pixels off the edge are zero.
+fn bilinear(x: f32, y: f32, width: u32, height: u32, channel: &dyn Fn(usize) -> f32) -> f32 {
+    let (x0, y0) = (x.floor(), y.floor());
+    let (tx, ty) = (x - x0, y - y0);
+    let value = |x: f32, y: f32| {
+        if x < 0. || y < 0. || x >= width as f32 || y >= height as f32 {
+            0.
+        } else {
+            channel(y as usize * width as usize + x as usize)
+        }
+    };
+
+    f32::mix(
+        f32::mix(value(x0, y0), value(x0 + 1., y0), tx),
+        f32::mix(value(x0, y0 + 1.), value(x0 + 1., y0 + 1.), tx),
+        ty,
+    )
+}
+
+/// What the surface seen through a pixel looks like, which guides a [`Denoise`] so that it doesn't
+/// blur across the edges of objects or their patterns.
+#[derive(Debug, Default, Clone)]
+pub struct Guide {
+    /// The color of the surface, before it's lit.
+    pub albedo: Color,
+
+    /// The normal of the surface, or zero where nothing was hit.
+    pub normal: Vector3<f32>,
+}
+
+/// Denoising with a joint bilateral filter, which averages each pixel with its neighbors, weighting
+/// them by how close they are, and how similar their colors and [`Guide`]s are.
+#[derive(Debug, Clone)]
+pub struct Denoise {
+    /// The standard deviation of the weights by distance, in pixels.
+    pub radius: f32,
+
+    /// The standard deviation of the weights by difference in color.
+    pub color: f32,
+
+    /// The standard deviation of the weights by difference in normal.
+    pub normal: f32,
+
+    /// The standard deviation of the weights by difference in albedo.
+    pub albedo: f32,
+}
+
+impl Default for Denoise {
+    fn default() -> Self {
+        Self {
+            radius: 2.,
+            color: 0.5,
+            normal: 0.3,
+            albedo: 0.1,
+        }
+    }
+}
+
+impl Denoise {
+    /// Denoise the canvas, using `guide` for each of its pixels.
+    pub fn apply(&self, canvas: &mut Canvas, guide: &[Guide]) {
+        let width = canvas.width as i64;
+        let height = canvas.height as i64;
+        let reach = ((2. * self.radius).ceil() as i64).min(width.max(height));
+
+        let falloff = |sigma: f32| -0.5 / (sigma * sigma).max(f32::EPSILON);
+        let (spatial, color, normal, albedo) = (
+            falloff(self.radius),
+            falloff(self.color),
+            falloff(self.normal),
+            falloff(self.albedo),
+        );
+        let distance =
+            |a: &Color, b: &Color| (a.r - b.r).powi(2) + (a.g - b.g).powi(2) + (a.b - b.b).powi(2);
+
+        let pixels = canvas.buffer.clone();
+        for y in 0..height {
+            for x in 0..width {
+                let i = (y * width + x) as usize;
+                let mut total = Color::black();
+                let mut weights = 0.;
+                for sy in (y - reach).max(0)..(y + reach + 1).min(height) {
+                    for sx in (x - reach).max(0)..(x + reach + 1).min(width) {
+                        let j = (sy * width + sx) as usize;
+                        let exponent = spatial * ((sx - x).pow(2) + (sy - y).pow(2)) as f32
+                            + color * distance(&pixels[i], &pixels[j])
+                            + normal * (guide[i].normal - guide[j].normal).norm_squared()
+                            + albedo * distance(&guide[i].albedo, &guide[j].albedo);
+                        let weight = exponent.exp();
+                        total += &pixels[j] * weight;
+                        weights += weight;
+                    }
+                }
+                canvas.buffer[i] = total * (1. / weights);
+            }
+        }
+    }
+}
+
+/// The weights of a normalized gaussian with standard deviation `sigma`, out to three deviations
+/// either side of the center.
+fn gaussian_kernel(sigma: f32) -> Vec<f32> {
+    let sigma = sigma.max(0.1);
+    let reach = (3. * sigma).ceil() as i64;
+    let kernel: Vec<f32> = (-reach..=reach)
+        .map(|x| f32::exp(-((x * x) as f32) / (2. * sigma * sigma)))
+        .collect();
+    let total: f32 = kernel.iter().sum();
+    kernel.into_iter().map(|w| w / total).collect()
+}
+
+/// Convolve `pixels` with `kernel` along `step`, treating pixels off the edge as black.
+fn convolve(
+    pixels: &[Color],
+    width: usize,
+    height: usize,
+    kernel: &[f32],
+    step: (usize, usize),
+) -> Vec<Color> {
+    let reach = (kernel.len() / 2) as i64;
+    let mut result = vec![Color::black(); pixels.len()];
+    for y in 0..height {
+        for x in 0..width {
+            let out = &mut result[y * width + x];
+            for (offset, weight) in (-reach..=reach).zip(kernel) {
+                let sx = x as i64 + offset * step.0 as i64;
+                let sy = y as i64 + offset * step.1 as i64;
+                if sx >= 0 && sy >= 0 && (sx as usize) < width && (sy as usize) < height {
+                    *out += &pixels[sy as usize * width + sx as usize] * *weight;
+                }
+            }
+        }
+    }
+    result
+}
+
+impl Mix<f32> for &Color {
     type Output = Color;
 
     fn mix(self, b: Self, t: f32) -> Self::Output {
@@ -224,7 +623,41 @@ impl Canvas {
             width,
             height,
             buffer,
+            alpha: vec![1.; size],
+        }
+    }
+
+    /// A smaller copy of the canvas, where each pixel is the average of the pixels it covers.
+    pub fn downsample(&self, width: u32, height: u32) -> Canvas {
+        let mut small = Canvas::new(width, height);
+        let span = |i: u32, size: u32, from: u32| {
+            let start = (i as u64 * from as u64 / size as u64) as usize;
+            let end = ((i as u64 + 1) * from as u64).div_ceil(size as u64) as usize;
+            start..end.max(start + 1)
+        };
+
+        for y in 0..height {
+            let rows = span(y, height, self.height);
+            for x in 0..width {
+                let cols = span(x, width, self.width);
+                let mut color = Color::black();
+                let mut alpha = 0.;
+                for sy in rows.clone() {
+                    let row = sy * self.width as usize;
+                    for sx in cols.clone() {
+                        color += &self.buffer[row + sx];
+                        alpha += self.alpha[row + sx];
+                    }
+                }
+
+                let scale = 1. / (rows.len() * cols.len()) as f32;
+                let i = (y * width + x) as usize;
+                small.buffer[i] = color * scale;
+                small.alpha[i] = alpha * scale;
+            }
         }
+
+        small
     }
 
     pub fn blit(&mut self, off_x: u32, off_y: u32, other: &Canvas) {
@@ -233,6 +666,11 @@ impl Canvas {
         for (y, src) in other.rows() {
             let dst = self.row_mut(y + off_y as usize);
             dst[start..end].clone_from_slice(src);
+
+            let dst = (y + off_y as usize) * self.width as usize;
+            let src = y * other.width as usize;
+            self.alpha[dst + start..dst + end]
+                .copy_from_slice(&other.alpha[src..src + other.width as usize]);
         }
     }
 
@@ -250,6 +688,12 @@ impl Canvas {
         &self.buffer[start..start + self.width as usize]
     }
 
+    /// Fetch the coverage of a row of the canvas.
+    pub fn alpha_row(&self, y: usize) -> &[f32] {
+        let start = y * self.width as usize;
+        &self.alpha[start..start + self.width as usize]
+    }
+
     /// Fetch a mutable row of the canvas.
     pub fn row_mut(&mut self, y: usize) -> &mut [Color] {
         let start = y * self.width as usize;
@@ -274,6 +718,23 @@ impl Canvas {
         &mut self.buffer
     }
 
+    /// The pixels of the image along with their coverage, which the colors are premultiplied by.
+    pub fn pixels_and_alpha_mut(&mut self) -> (&mut [Color], &mut [f32]) {
+        (&mut self.buffer, &mut self.alpha)
+    }
+
+    /// The log-average luminance of the canvas. A small delta is added to each pixel's luminance
+    /// so that black pixels don't force the average to zero.
+    pub fn log_average_luminance(&self) -> f32 {
+        if self.buffer.is_empty() {
+            return 0.;
+        }
+
+        let sum: f32 = self.buffer.iter().map(log_luminance).sum();
+
+        (sum / self.buffer.len() as f32).exp()
+    }
+
     /// Return raw image RGB8 data for the image.
     pub fn data(&self) -> Vec<u8> {
         let size = (self.width * self.height) as usize;
@@ -288,8 +749,70 @@ impl Canvas {
         data
     }
 
-    /// Return an ascii version of the [`Canvas`].
-    pub fn to_ascii(&self) -> String {
+    /// Convert the [`Canvas`] to an image with the given channels and bit depth.
+    pub fn to_image(&self, channels: Channels, depth: BitDepth) -> image::DynamicImage {
+        let (width, height) = (self.width, self.height);
+        match (channels, depth) {
+            (Channels::Rgb, BitDepth::Eight) => image::DynamicImage::ImageRgb8(
+                image::ImageBuffer::from_raw(width, height, self.data()).unwrap(),
+            ),
+            (Channels::Rgb, BitDepth::Sixteen) => image::DynamicImage::ImageRgb16(
+                image::ImageBuffer::from_raw(width, height, self.samples(channels, to_u16))
+                    .unwrap(),
+            ),
+            (Channels::Rgba, BitDepth::Eight) => image::DynamicImage::ImageRgba8(
+                image::ImageBuffer::from_raw(width, height, self.samples(channels, to_u8)).unwrap(),
+            ),
+            (Channels::Rgba, BitDepth::Sixteen) => image::DynamicImage::ImageRgba16(
+                image::ImageBuffer::from_raw(width, height, self.samples(channels, to_u16))
+                    .unwrap(),
+            ),
+            (_, BitDepth::Eight) => image::DynamicImage::ImageLuma8(
+                image::ImageBuffer::from_raw(width, height, self.samples(channels, to_u8)).unwrap(),
+            ),
+            (_, BitDepth::Sixteen) => image::DynamicImage::ImageLuma16(
+                image::ImageBuffer::from_raw(width, height, self.samples(channels, to_u16))
+                    .unwrap(),
+            ),
+        }
+    }
+
+    /// The channels of every pixel, from the top row down, converted with `convert`.
+    fn samples<T>(&self, channels: Channels, convert: impl Fn(f32) -> T) -> Vec<T> {
+        let mut samples = Vec::with_capacity(self.buffer.len() * 4);
+        for (y, row) in self.rows() {
+            let alpha = &self.alpha[y * self.width as usize..];
+            for (color, &alpha) in row.iter().zip(alpha) {
+                match channels {
+                    Channels::Rgb => samples.extend([color.r, color.g, color.b].map(&convert)),
+
+                    // Images store colors that aren't premultiplied, and pixels that aren't
+                    // covered at all are left black.
+                    Channels::Rgba => {
+                        let scale = if alpha > 0. { 1. / alpha } else { 0. };
+                        let color = color * scale;
+                        samples.extend([color.r, color.g, color.b, alpha].map(&convert))
+                    }
+
+                    Channels::Grayscale => samples.push(convert(color.to_grayscale())),
+                    Channels::Luma => samples.push(convert(color.luminance())),
+                }
+            }
+        }
+        samples
+    }
+
+    /// Return a version of the [`Canvas`] drawn as text, for printing to a terminal.
+    pub fn to_ascii(&self, mode: AsciiMode) -> String {
+        match mode {
+            AsciiMode::Chars => self.to_chars(),
+            AsciiMode::ColorBlocks => self.to_color_blocks(),
+            AsciiMode::HalfBlocks => self.to_half_blocks(),
+            AsciiMode::Braille => self.to_braille(),
+        }
+    }
+
+    fn to_chars(&self) -> String {
         let mut buf = String::new();
         let palette = r#"$@B%8&WM#*oahkbdpqwmZO0QLCJUYXzcvunxrjft/\|()1{}[]?-_+~<>i!lI;:,"^`'. "#;
         let bytes = palette.as_bytes();
@@ -306,6 +829,73 @@ impl Canvas {
 
         buf
     }
+
+    fn to_color_blocks(&self) -> String {
+        let mut buf = String::new();
+
+        for (_, row) in self.rows() {
+            for col in row {
+                let [r, g, b] = col.to_u8();
+                write!(buf, "\x1b[38;2;{};{};{}m\u{2588}", r, g, b).unwrap();
+            }
+            buf.push_str("\x1b[0m\n");
+        }
+
+        buf
+    }
+
+    fn to_half_blocks(&self) -> String {
+        let mut buf = String::new();
+
+        for y in (0..self.height as usize).step_by(2) {
+            let bottom = (y + 1 < self.height as usize).then(|| self.row(y + 1));
+            for (x, top) in self.row(y).iter().enumerate() {
+                let [r, g, b] = top.to_u8();
+                write!(buf, "\x1b[38;2;{};{};{}m", r, g, b).unwrap();
+
+                // The last row of an image with an odd height has nothing below it.
+                match bottom {
+                    Some(bottom) => {
+                        let [r, g, b] = bottom[x].to_u8();
+                        write!(buf, "\x1b[48;2;{};{};{}m", r, g, b).unwrap();
+                    }
+                    None => buf.push_str("\x1b[49m"),
+                }
+
+                buf.push('\u{2580}');
+            }
+            buf.push_str("\x1b[0m\n");
+        }
+
+        buf
+    }
+
+    fn to_braille(&self) -> String {
+        // The bit for the dot at each position in a 2x4 cell, indexed by row and then column.
+        const DOTS: [[u32; 2]; 4] = [[0x01, 0x08], [0x02, 0x10], [0x04, 0x20], [0x40, 0x80]];
+
+        let width = self.width as usize;
+        let height = self.height as usize;
+        let mut buf = String::new();
+
+        for y in (0..height).step_by(4) {
+            for x in (0..width).step_by(2) {
+                let mut bits = 0;
+                for (dy, dots) in DOTS.iter().enumerate().take(height - y) {
+                    let row = self.row(y + dy);
+                    for (dx, dot) in dots.iter().enumerate().take(width - x) {
+                        if row[x + dx].to_grayscale() > 0.5 {
+                            bits |= dot;
+                        }
+                    }
+                }
+                buf.push(char::from_u32(0x2800 + bits).unwrap());
+            }
+            buf.push('\n');
+        }
+
+        buf
+    }
 }
 
 impl<'a> Iterator for Rows<'a> {
@@ -324,3 +914,379 @@ impl<'a> Iterator for Rows<'a> {
         Some((ix, row))
     }
 }
+
+fn to_u8(x: f32) -> u8 {
+    (x * 255.0).clamp(0.0, 255.0) as u8
+}
+
+fn to_u16(x: f32) -> u16 {
+    (x * 65535.0).clamp(0.0, 65535.0) as u16
+}
+
+/// The log luminance of a color, offset by a small delta so that black doesn't produce `-inf`.
+fn log_luminance(color: &Color) -> f32 {
+    (0.0001 + color.luminance().max(0.)).ln()
+}
+
+/// The number of bins in a [`Histogram`].
+pub const HISTOGRAM_BINS: usize = 32;
+
+/// The smallest luminance tracked by a [`Histogram`], in stops. Darker pixels are counted in the
+/// first bin.
+pub const HISTOGRAM_MIN_STOP: f32 = -10.;
+
+/// The largest luminance tracked by a [`Histogram`], in stops. Brighter pixels are counted in the
+/// last bin.
+pub const HISTOGRAM_MAX_STOP: f32 = 6.;
+
+/// A luminance histogram that can be accumulated from many threads as the tiles of a canvas are
+/// completed. The bins are spaced evenly in stops (`log2` of luminance).
+#[derive(Debug)]
+pub struct Histogram {
+    bins: [AtomicU64; HISTOGRAM_BINS],
+
+    /// The sum of the log luminance of all pixels seen, stored as the bits of an `f64`.
+    log_sum: AtomicU64,
+
+    /// The number of pixels seen.
+    pixels: AtomicU64,
+
+    /// The number of pixels in the full canvas.
+    total: u64,
+}
+
+/// The state of a [`Histogram`] at a point in time.
+#[derive(Debug, Clone)]
+pub struct HistogramSnapshot {
+    pub bins: Vec<u64>,
+    pub pixels: u64,
+    pub total: u64,
+    pub log_average: f32,
+}
+
+impl Histogram {
+    /// Construct an empty histogram for a canvas with `total` pixels.
+    pub fn new(total: u64) -> Self {
+        Self {
+            bins: std::array::from_fn(|_| AtomicU64::new(0)),
+            log_sum: AtomicU64::new(0f64.to_bits()),
+            pixels: AtomicU64::new(0),
+            total,
+        }
+    }
+
+    fn bin(color: &Color) -> usize {
+        let stop = color.luminance().max(0.).log2();
+        let t = (stop - HISTOGRAM_MIN_STOP) / (HISTOGRAM_MAX_STOP - HISTOGRAM_MIN_STOP);
+        ((t * HISTOGRAM_BINS as f32).max(0.) as usize).min(HISTOGRAM_BINS - 1)
+    }
+
+    /// Accumulate the pixels of a completed tile.
+    pub fn add(&self, tile: &Canvas) {
+        let mut bins = [0; HISTOGRAM_BINS];
+        let mut log_sum = 0.;
+        for color in tile.buffer.iter() {
+            bins[Self::bin(color)] += 1;
+            log_sum += log_luminance(color) as f64;
+        }
+
+        for (bin, count) in self.bins.iter().zip(bins) {
+            if count > 0 {
+                bin.fetch_add(count, Ordering::Relaxed);
+            }
+        }
+
+        let _ = self
+            .log_sum
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |bits| {
+                Some((f64::from_bits(bits) + log_sum).to_bits())
+            });
+
+        self.pixels
+            .fetch_add(tile.buffer.len() as u64, Ordering::Relaxed);
+    }
+
+    /// Read the current state of the histogram.
+    pub fn snapshot(&self) -> HistogramSnapshot {
+        let pixels = self.pixels.load(Ordering::Relaxed);
+        let log_sum = f64::from_bits(self.log_sum.load(Ordering::Relaxed));
+        let log_average = if pixels > 0 {
+            (log_sum / pixels as f64).exp() as f32
+        } else {
+            0.
+        };
+
+        HistogramSnapshot {
+            bins: self
+                .bins
+                .iter()
+                .map(|bin| bin.load(Ordering::Relaxed))
+                .collect(),
+            pixels,
+            total: self.total,
+            log_average,
+        }
+    }
+}
+
+#[test]
+fn test_histogram() {
+    let mut tile = Canvas::new(2, 2);
+    for pixel in tile.pixels_mut() {
+        *pixel = Color::new(0.5, 0.5, 0.5);
+    }
+
+    let histogram = Histogram::new(8);
+    std::thread::scope(|s| {
+        s.spawn(|| histogram.add(&tile));
+        s.spawn(|| histogram.add(&Canvas::new(2, 2)));
+    });
+
+    let snapshot = histogram.snapshot();
+    assert_eq!(8, snapshot.pixels);
+    assert_eq!(8, snapshot.total);
+    assert_eq!(4, snapshot.bins[0]);
+    assert_eq!(4, snapshot.bins[Histogram::bin(&Color::new(0.5, 0.5, 0.5))]);
+
+    let mut canvas = Canvas::new(4, 2);
+    canvas.blit(0, 0, &tile);
+    let avg = canvas.log_average_luminance();
+    assert!((snapshot.log_average - avg).abs() < 0.001);
+}
+
+#[test]
+fn test_auto_exposure() {
+    let mut canvas = Canvas::new(2, 2);
+    for pixel in canvas.pixels_mut() {
+        *pixel = Color::new(0.5, 0.5, 0.5);
+    }
+
+    let avg = canvas.log_average_luminance();
+    assert!((avg - 0.5).abs() < 0.001);
+
+    let exposure = AutoExposure::default();
+    exposure.apply(&mut canvas);
+    assert!((canvas.row(0)[0].luminance() - exposure.key).abs() < 0.001);
+
+    // A black canvas is scaled by the maximum factor, rather than dividing by zero.
+    let canvas = Canvas::new(2, 2);
+    assert_eq!(exposure.max, exposure.scale_factor(&canvas));
+}
+
+#[test]
+fn test_ascii_modes() {
+    let mut canvas = Canvas::new(2, 3);
+    for (pixel, level) in canvas.pixels_mut().iter_mut().zip([1., 0., 0., 1., 1., 1.]) {
+        *pixel = Color::new(level, level, level);
+    }
+
+    assert_eq!(
+        "\x1b[38;2;255;255;255m\u{2588}\x1b[38;2;0;0;0m\u{2588}\x1b[0m",
+        canvas
+            .to_ascii(AsciiMode::ColorBlocks)
+            .lines()
+            .next()
+            .unwrap()
+    );
+
+    let half = canvas.to_ascii(AsciiMode::HalfBlocks);
+    let lines: Vec<_> = half.lines().collect();
+    assert_eq!(2, lines.len());
+    assert!(lines[0].starts_with("\x1b[38;2;255;255;255m\x1b[48;2;0;0;0m\u{2580}"));
+    assert!(lines[1].starts_with("\x1b[38;2;255;255;255m\x1b[49m\u{2580}"));
+
+    // The dots for (0,0), (1,1), (0,2) and (1,2) are raised.
+    assert_eq!("\u{2835}\n", canvas.to_ascii(AsciiMode::Braille));
+}
+
+#[test]
+fn test_image_formats() {
+    let mut canvas = Canvas::new(2, 1);
+    canvas.pixels_mut()[0] = Color::new(1., 0., 0.);
+    canvas.pixels_mut()[1] = Color::new(0.5, 0.5, 2.);
+
+    let image = canvas
+        .to_image(Channels::Rgb, BitDepth::Sixteen)
+        .into_rgb16();
+    assert_eq!([65535, 0, 0], image.get_pixel(0, 0).0);
+    assert_eq!([32767, 32767, 65535], image.get_pixel(1, 0).0);
+
+    let image = canvas.to_image(Channels::Grayscale, BitDepth::Eight);
+    assert_eq!(image::ColorType::L8, image.color());
+    assert_eq!([76], image.into_luma8().get_pixel(0, 0).0);
+
+    let image = canvas.to_image(Channels::Luma, BitDepth::Sixteen);
+    assert_eq!(image::ColorType::L16, image.color());
+    assert_eq!([13932], image.into_luma16().get_pixel(0, 0).0);
+}
+
+#[test]
+fn test_bloom() {
+    let mut canvas = Canvas::new(15, 15);
+    canvas.pixels_mut()[7 * 15 + 7] = Color::new(3., 3., 3.);
+    canvas.pixels_mut()[0] = Color::new(0.5, 0.5, 0.5);
+
+    let bloom = Bloom {
+        threshold: 1.,
+        radius: 1.,
+        intensity: 1.,
+        ..Bloom::default()
+    };
+    let mut bloomed = canvas.clone();
+    bloom.apply(&mut bloomed);
+
+    // Only the light above the threshold is spread, and all of it lands on the canvas.
+    let added = bloomed
+        .pixels_mut()
+        .iter()
+        .zip(canvas.pixels_mut().iter())
+        .map(|(after, before)| after.r - before.r)
+        .sum::<f32>();
+    assert!((added - 2.).abs() < 0.001, "{}", added);
+    assert_eq!(0.5, bloomed.row(0)[0].r);
+    assert_eq!(bloomed.row(6)[7].r, bloomed.row(7)[8].r);
+    assert!(bloomed.row(7)[9].r > 0. && bloomed.row(7)[9].r < bloomed.row(7)[8].r);
+
+    // Glare reaches past the blur, along the streaks.
+    let glare = Bloom {
+        streaks: 2,
+        streak_length: 6.,
+        ..bloom
+    };
+    let mut glared = canvas.clone();
+    glare.apply(&mut glared);
+    assert!(glared.row(7)[13].r > 0.);
+    assert_eq!(0., glared.row(3)[11].r);
+}
+
+#[test]
+fn test_denoise() {
+    // Two halves of a canvas, which differ only in their guides, with the same noise on both.
+    let mut canvas = Canvas::new(8, 8);
+    let mut guide = Vec::new();
+    for (i, pixel) in canvas.pixels_mut().iter_mut().enumerate() {
+        let noise = if (i + i / 8) % 2 == 0 { 0.1 } else { -0.1 };
+        let left = i % 8 < 4;
+        let base = if left { 0.8 } else { 0.2 };
+        *pixel = Color::new(base + noise, base + noise, base + noise);
+        guide.push(Guide {
+            albedo: Color::white(),
+            normal: if left {
+                Vector3::new(0., 0., 1.)
+            } else {
+                Vector3::new(1., 0., 0.)
+            },
+        });
+    }
+
+    let denoise = Denoise::default();
+    denoise.apply(&mut canvas, &guide);
+
+    // The noise is smoothed out, without the halves bleeding into each other.
+    for (x, expected) in [(0, 0.8), (3, 0.8), (4, 0.2), (7, 0.2)] {
+        let found = canvas.row(4)[x].r;
+        assert!((found - expected).abs() < 0.02, "{}: {}", x, found);
+    }
+}
+
+#[test]
+fn test_lens_effects() {
+    let mut canvas = Canvas::new(9, 9);
+    for (i, pixel) in canvas.pixels_mut().iter_mut().enumerate() {
+        *pixel = if i % 9 == 4 {
+            Color::white()
+        } else {
+            Color::new(0.5, 0.5, 0.5)
+        };
+    }
+
+    // Doing nothing leaves the canvas as it was.
+    let mut plain = canvas.clone();
+    LensEffects::default().apply(&mut plain);
+    assert_eq!(canvas.row(2)[4].r, plain.row(2)[4].r);
+    assert_eq!(canvas.row(0)[0].r, plain.row(0)[0].r);
+
+    let mut vignetted = canvas.clone();
+    LensEffects {
+        vignette: 0.5,
+        ..LensEffects::default()
+    }
+    .apply(&mut vignetted);
+    assert_eq!(1., vignetted.row(4)[4].r);
+    assert!(vignetted.row(0)[0].r < 0.35);
+
+    // The center doesn't move, while the rest of the image is pulled in from further out, so that
+    // the white column bows outwards away from the middle row.
+    let mut barrel = canvas.clone();
+    LensEffects {
+        distortion: 0.3,
+        ..LensEffects::default()
+    }
+    .apply(&mut barrel);
+    assert_eq!(1., barrel.row(4)[4].r);
+    assert!(barrel.row(0)[4].r < 1.);
+    assert!(barrel.row(0)[0].r < 0.1, "{:?}", barrel.row(0)[0]);
+
+    // The channels separate away from the center, so blue is drawn in from the white column.
+    let mut fringed = canvas.clone();
+    LensEffects {
+        aberration: 2.,
+        ..LensEffects::default()
+    }
+    .apply(&mut fringed);
+    let pixel = &fringed.row(1)[3];
+    assert!(
+        pixel.b > 0.53 && (pixel.r - 0.5).abs() < 0.001 && (pixel.g - 0.5).abs() < 0.001,
+        "{:?}",
+        pixel
+    );
+}
+
+#[test]
+fn test_downsample() {
+    let mut canvas = Canvas::new(4, 3);
+    for (i, pixel) in canvas.pixels_mut().iter_mut().enumerate() {
+        *pixel = Color::new(i as f32, 0., 0.);
+    }
+
+    // Each pixel covers a two by two block, except those on the bottom row, which share the
+    // middle row with the top.
+    let small = canvas.downsample(2, 2);
+    assert_eq!(
+        [2.5, 4.5],
+        small.row(0).iter().map(|c| c.r).collect::<Vec<_>>()[..]
+    );
+    assert_eq!(
+        [6.5, 8.5],
+        small.row(1).iter().map(|c| c.r).collect::<Vec<_>>()[..]
+    );
+
+    let same = canvas.downsample(4, 3);
+    assert_eq!(canvas.row(2)[3].r, same.row(2)[3].r);
+}
+
+#[test]
+fn test_image_alpha() {
+    let mut canvas = Canvas::new(2, 1);
+    let (pixels, alpha) = canvas.pixels_and_alpha_mut();
+    pixels[0] = Color::new(1., 0., 0.);
+    alpha[0] = 0.;
+    pixels[1] = Color::new(0.25, 0.25, 0.5);
+    alpha[1] = 0.5;
+
+    // Colors are stored without the coverage they're premultiplied by.
+    let image = canvas.to_image(Channels::Rgba, BitDepth::Eight);
+    assert_eq!(image::ColorType::Rgba8, image.color());
+    let image = image.into_rgba8();
+    assert_eq!([0, 0, 0, 0], image.get_pixel(0, 0).0);
+    assert_eq!([127, 127, 255, 127], image.get_pixel(1, 0).0);
+
+    // Blitting a tile carries its coverage along.
+    let mut target = Canvas::new(3, 1);
+    target.blit(1, 0, &canvas);
+    let image = target
+        .to_image(Channels::Rgba, BitDepth::Eight)
+        .into_rgba8();
+    assert_eq!(255, image.get_pixel(0, 0).0[3]);
+    assert_eq!([0, 0, 0, 0], image.get_pixel(1, 0).0);
+}
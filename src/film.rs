@@ -0,0 +1,245 @@
+//! Images that are written out a tile at a time as the tiles finish, so that renders far larger
+//! than the memory available never hold the whole image. Tiles are stored in a tiled EXR with no
+//! particular order, and the table that finds them is written once the last tile is in.
+
+use anyhow::{anyhow, Error};
+use crossbeam::channel::{self, Sender};
+use exr::{
+    block::{chunk::Chunk, writer::ChunksWriter, BlockIndex, UncompressedBlock},
+    math::{RoundingMode, Vec2},
+    meta::{
+        attribute::{ChannelDescription, LevelMode, LineOrder, SampleType, TileDescription},
+        header::Header,
+        BlockDescription, Headers,
+    },
+    prelude::Compression,
+};
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::Path;
+use std::sync::Mutex;
+use std::thread::JoinHandle;
+
+use crate::{
+    canvas::{Canvas, Channels, Color},
+    integrator::TILE_SIZE,
+};
+
+type Result<T> = std::result::Result<T, Error>;
+
+/// The most compressed tiles that may wait to be written, before the threads that finish them
+/// wait for the file to catch up.
+const QUEUE_LENGTH: usize = 64;
+
+/// An EXR file that's written as the tiles of a render finish. The colors are stored as 32-bit
+/// floats in linear space, premultiplied by their coverage as EXR expects.
+pub struct ExrFilm {
+    width: u32,
+    height: u32,
+    channels: Channels,
+    headers: Headers,
+
+    /// Sends compressed tiles to the thread that writes the file.
+    send: Option<Sender<(usize, Chunk)>>,
+    writer: Option<JoinHandle<exr::error::UnitResult>>,
+
+    /// The first tile that couldn't be compressed.
+    error: Mutex<Option<Error>>,
+}
+
+impl ExrFilm {
+    /// Create the file at `path`, for an image that's `width` by `height` pixels.
+    pub fn create(path: &Path, width: u32, height: u32, channels: Channels) -> Result<Self> {
+        // Channels are stored in alphabetical order.
+        let names: &[&str] = match channels {
+            Channels::Rgb => &["B", "G", "R"],
+            Channels::Rgba => &["A", "B", "G", "R"],
+            Channels::Grayscale | Channels::Luma => &["Y"],
+        };
+        let header = Header::new(
+            "rendrs".into(),
+            (width as usize, height as usize),
+            names
+                .iter()
+                .map(|name| ChannelDescription::new(*name, SampleType::F32, true))
+                .collect(),
+        )
+        .with_encoding(
+            Compression::ZIP16,
+            // The file is split into the same tiles as the render, so each is written once.
+            BlockDescription::Tiles(TileDescription {
+                tile_size: Vec2(TILE_SIZE as usize, TILE_SIZE as usize),
+                level_mode: LevelMode::Singular,
+                rounding_mode: RoundingMode::Down,
+            }),
+            LineOrder::Unspecified,
+        );
+        let headers: Headers = std::iter::once(header).collect();
+
+        let file = BufWriter::new(
+            File::create(path)
+                .map_err(|err| anyhow!("Failed to create `{}`: {}", path.display(), err))?,
+        );
+        let (send, receive) = channel::bounded::<(usize, Chunk)>(QUEUE_LENGTH);
+        let writer = {
+            let headers = headers.clone();
+            std::thread::spawn(move || {
+                exr::block::write(file, headers, true, |_, chunks| {
+                    for (index, chunk) in receive {
+                        chunks.write_chunk(index, chunk)?;
+                    }
+                    Ok(())
+                })
+            })
+        };
+
+        Ok(Self {
+            width,
+            height,
+            channels,
+            headers,
+            send: Some(send),
+            writer: Some(writer),
+            error: Mutex::new(None),
+        })
+    }
+
+    /// Write the tile whose top left pixel is at `x` and `y`. Tiles must line up with the tiles of
+    /// the file, and this is called from the rendering threads.
+    pub fn write_tile(&self, x: u32, y: u32, tile: &Canvas) {
+        self.write_region(tile, 0, 0, x, y);
+    }
+
+    /// Write the whole of `canvas`, for images that were rendered all at once.
+    pub fn write_canvas(&self, canvas: &Canvas) {
+        for y in (0..self.height).step_by(TILE_SIZE as usize) {
+            for x in (0..self.width).step_by(TILE_SIZE as usize) {
+                self.write_region(canvas, x, y, x, y);
+            }
+        }
+    }
+
+    /// Write the tile of the file at `x` and `y`, from the pixels of `canvas` starting at
+    /// `from_x` and `from_y`.
+    fn write_region(&self, canvas: &Canvas, from_x: u32, from_y: u32, x: u32, y: u32) {
+        let width = (self.width - x).min(TILE_SIZE) as usize;
+        let height = (self.height - y).min(TILE_SIZE) as usize;
+        let columns = from_x as usize..from_x as usize + width;
+
+        // Each row holds all of the samples for one channel, then all of the samples for the next.
+        let mut data =
+            Vec::with_capacity(width * height * self.headers[0].channels.bytes_per_pixel);
+        for row in from_y as usize..from_y as usize + height {
+            let colors = &canvas.row(row)[columns.clone()];
+            let alpha = &canvas.alpha_row(row)[columns.clone()];
+            let mut channel = |samples: &mut dyn Iterator<Item = f32>| {
+                for sample in samples {
+                    data.extend_from_slice(&sample.to_le_bytes());
+                }
+            };
+            if self.channels == Channels::Rgba {
+                channel(&mut alpha.iter().copied());
+            }
+            match self.channels {
+                Channels::Rgb | Channels::Rgba => {
+                    channel(&mut colors.iter().map(|color| color.b));
+                    channel(&mut colors.iter().map(|color| color.g));
+                    channel(&mut colors.iter().map(|color| color.r));
+                }
+                Channels::Grayscale => channel(&mut colors.iter().map(Color::to_grayscale)),
+                Channels::Luma => channel(&mut colors.iter().map(Color::luminance)),
+            }
+        }
+
+        let block = UncompressedBlock {
+            index: BlockIndex {
+                layer: 0,
+                pixel_position: Vec2(x as usize, y as usize),
+                pixel_size: Vec2(width, height),
+                level: Vec2(0, 0),
+            },
+            data,
+        };
+
+        // Tiles are numbered along each row, from the top row down.
+        let columns = self.width.div_ceil(TILE_SIZE);
+        let index = ((y / TILE_SIZE) * columns + x / TILE_SIZE) as usize;
+        match block.compress_to_chunk(&self.headers) {
+            Ok(chunk) => {
+                // The writer only stops early when it fails, which `finish` reports.
+                if let Some(send) = &self.send {
+                    let _ = send.send((index, chunk));
+                }
+            }
+            Err(err) => {
+                self.error.lock().unwrap().get_or_insert(anyhow!("{}", err));
+            }
+        }
+    }
+
+    /// Wait for the tiles to be written, and finish the file. Every tile must have been written.
+    pub fn finish(mut self) -> Result<()> {
+        drop(self.send.take());
+        let written = self.writer.take().unwrap().join();
+        if let Some(err) = self.error.lock().unwrap().take() {
+            return Err(err);
+        }
+        match written {
+            Ok(result) => result.map_err(|err| anyhow!("Failed to write the image: {}", err)),
+            Err(panic) => std::panic::resume_unwind(panic),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_exr_film() {
+        let dir = std::env::temp_dir().join(format!("rendrs-film-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("film.exr");
+
+        // Tiles may arrive in any order, and the last row and column are cut short.
+        let (width, height) = (40, 20);
+        let film = ExrFilm::create(&path, width, height, Channels::Rgba).unwrap();
+        for y in (0..height).step_by(TILE_SIZE as usize).rev() {
+            for x in (0..width).step_by(TILE_SIZE as usize) {
+                let mut tile = Canvas::new((width - x).min(TILE_SIZE), (height - y).min(TILE_SIZE));
+                let (pixels, alpha) = tile.pixels_and_alpha_mut();
+                for (pixel, alpha) in pixels.iter_mut().zip(alpha) {
+                    *pixel = Color::new(x as f32 / 40., y as f32 / 20., 0.25);
+                    *alpha = 0.5;
+                }
+                film.write_tile(x, y, &tile);
+            }
+        }
+        film.finish().unwrap();
+
+        let image = image::open(&path).unwrap().into_rgba32f();
+        assert_eq!((width, height), image.dimensions());
+
+        // Colors are stored premultiplied, and come back out as they went in.
+        let [r, g, b, a] = image.get_pixel(35, 18).0;
+        assert!(
+            (r - 0.8).abs() < 1e-6 && (g - 0.8).abs() < 1e-6,
+            "{} {}",
+            r,
+            g
+        );
+        assert!(
+            (b - 0.25).abs() < 1e-6 && (a - 0.5).abs() < 1e-6,
+            "{} {}",
+            b,
+            a
+        );
+
+        // A film missing some of its tiles can't be finished.
+        let film = ExrFilm::create(&path, width, height, Channels::Rgb).unwrap();
+        film.write_tile(0, 0, &Canvas::new(16, 16));
+        assert!(film.finish().is_err());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,216 @@
+//! Fractals whose distances are estimated from how quickly their orbits escape. The estimates
+//! are only bounds near the surface, so outside of the sphere that contains the fractal the
+//! distance to that sphere is used instead.
+
+use nalgebra::{Point2, Point3, Quaternion, Vector3};
+
+use crate::math::Float;
+
+#[derive(Debug, Clone)]
+pub enum FractalKind {
+    /// The power `power` mandelbulb, which folds space by raising points to that power in
+    /// spherical coordinates.
+    Mandelbulb { power: Float },
+
+    /// The menger sponge, which fills the cube from `-1` to `1` and removes the middle of each
+    /// face at every iteration.
+    Menger,
+
+    /// The quaternion julia set for the constant `c`, sliced where the last component is zero.
+    Julia { c: Quaternion<Float> },
+}
+
+#[derive(Debug, Clone)]
+pub struct Fractal {
+    pub kind: FractalKind,
+
+    /// The number of times that points are folded.
+    pub iterations: u32,
+
+    /// The distance from the origin past which an orbit has escaped.
+    pub bailout: Float,
+}
+
+/// The result of following the orbit of a point.
+struct Orbit {
+    distance: Float,
+
+    /// The closest that the orbit came to the origin.
+    trap: Float,
+
+    /// The number of iterations before the orbit escaped.
+    steps: u32,
+}
+
+impl Fractal {
+    /// The radius of a sphere around the origin that contains the fractal.
+    pub fn radius(&self) -> Float {
+        match self.kind {
+            // Past this radius, raising a point to the power moves it further out than adding the
+            // original point can bring it back.
+            FractalKind::Mandelbulb { power } => Float::powf(2., 1. / (power - 1.)),
+            FractalKind::Menger => Float::sqrt(3.),
+            FractalKind::Julia { c } => 0.5 + Float::sqrt(0.25 + c.norm()),
+        }
+    }
+
+    pub fn sdf(&self, p: &Point3<Float>) -> Float {
+        self.orbit(p).distance
+    }
+
+    /// Texture coordinates from the orbit of `p`, which are its orbit trap and the fraction of the
+    /// iterations that it took to escape.
+    pub fn uv(&self, p: &Point3<Float>) -> Point2<Float> {
+        let orbit = self.orbit(p);
+        Point2::new(
+            orbit.trap.clamp(0., 1.),
+            orbit.steps as Float / self.iterations.max(1) as Float,
+        )
+    }
+
+    fn orbit(&self, p: &Point3<Float>) -> Orbit {
+        let outside = p.coords.norm() - self.radius();
+        if outside > 0. {
+            return Orbit {
+                distance: outside,
+                trap: p.coords.norm(),
+                steps: 0,
+            };
+        }
+
+        match self.kind {
+            FractalKind::Mandelbulb { power } => self.mandelbulb(p, power),
+            FractalKind::Menger => self.menger(p),
+            FractalKind::Julia { c } => self.julia(p, c),
+        }
+    }
+
+    fn mandelbulb(&self, p: &Point3<Float>, power: Float) -> Orbit {
+        let mut z = p.coords;
+        let mut dr = 1.;
+        let mut r = z.norm();
+        let mut trap = r;
+        let mut steps = 0;
+        while steps < self.iterations && r <= self.bailout {
+            let theta = if r > 0. {
+                (z.z / r).clamp(-1., 1.).acos()
+            } else {
+                0.
+            };
+            let phi = Float::atan2(z.y, z.x);
+            dr = r.powf(power - 1.) * power * dr + 1.;
+
+            let (theta, phi) = (theta * power, phi * power);
+            z = Vector3::new(
+                theta.sin() * phi.cos(),
+                theta.sin() * phi.sin(),
+                theta.cos(),
+            ) * r.powf(power)
+                + p.coords;
+
+            r = z.norm();
+            trap = trap.min(r);
+            steps += 1;
+        }
+
+        // Points that never escape are inside, though the estimate can't say by how much.
+        let distance = if r > 0. { 0.5 * r.ln() * r / dr } else { 0. };
+        Orbit {
+            distance,
+            trap,
+            steps,
+        }
+    }
+
+    fn menger(&self, p: &Point3<Float>) -> Orbit {
+        let q = p.coords.abs() - Vector3::repeat(1.);
+        let mut distance = q.max().min(0.) + q.map(|c| c.max(0.)).norm();
+        let mut trap = Float::INFINITY;
+        let mut steps = 0;
+
+        // Each iteration carves a cross of square holes out of every remaining cube, at a third of
+        // the size of the last.
+        let mut scale = 1.;
+        for _ in 0..self.iterations {
+            let a = (p.coords * scale).map(|c| c.rem_euclid(2.) - 1.);
+            scale *= 3.;
+            let r = (Vector3::repeat(1.) - a.abs() * 3.).abs();
+            let cross = r.x.max(r.y).min(r.y.max(r.z)).min(r.z.max(r.x));
+            let hole = (cross - 1.) / scale;
+            if hole > distance {
+                distance = hole;
+                steps += 1;
+            }
+            trap = trap.min(a.norm());
+        }
+
+        Orbit {
+            distance,
+            trap,
+            steps,
+        }
+    }
+
+    fn julia(&self, p: &Point3<Float>, c: Quaternion<Float>) -> Orbit {
+        let mut z = Quaternion::new(p.x, p.y, p.z, 0.);
+        let mut dz2 = 1.;
+        let mut m2 = z.norm_squared();
+        let mut trap = m2;
+        let mut steps = 0;
+        while steps < self.iterations && m2 <= self.bailout * self.bailout {
+            dz2 *= 4. * m2;
+            z = z * z + c;
+            m2 = z.norm_squared();
+            trap = trap.min(m2);
+            steps += 1;
+        }
+
+        let distance = if m2 > 0. && dz2 > 0. {
+            0.25 * m2.ln() * Float::sqrt(m2 / dz2)
+        } else {
+            0.
+        };
+        Orbit {
+            distance,
+            trap: trap.sqrt(),
+            steps,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fractals() {
+        let fractal = |kind| Fractal {
+            kind,
+            iterations: 8,
+            bailout: 2.,
+        };
+
+        let bulb = fractal(FractalKind::Mandelbulb { power: 8. });
+        assert!(bulb.sdf(&Point3::new(0., 0., 0.)) <= 0.);
+        assert!(bulb.sdf(&Point3::new(0., 0., 0.5)) < 0.01);
+        let far = bulb.sdf(&Point3::new(0., 3., 0.));
+        assert!(far > 1. && far < 3., "{}", far);
+
+        // The sponge has holes through the middle of each face, down to the center.
+        let sponge = Fractal {
+            iterations: 2,
+            ..fractal(FractalKind::Menger)
+        };
+        assert!(sponge.sdf(&Point3::new(0.9, 0.9, 0.9)) < 0.);
+        assert!(sponge.sdf(&Point3::new(0., 0., 0.9)) > 0.);
+        assert!(sponge.sdf(&Point3::new(0., 0., 0.)) > 0.);
+        assert!(sponge.sdf(&Point3::new(0., 0., 3.)) > 1.);
+
+        let julia = fractal(FractalKind::Julia {
+            c: Quaternion::new(-0.2, 0.6, 0.2, 0.),
+        });
+        assert!(julia.sdf(&Point3::new(0., 3., 0.)) > 1.);
+        let uv = julia.uv(&Point3::new(0.1, 0.1, 0.));
+        assert!((0. ..=1.).contains(&uv.x) && (0. ..=1.).contains(&uv.y));
+    }
+}
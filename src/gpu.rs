@@ -0,0 +1,362 @@
+//! A rendering backend that compiles the scene to WGSL and marches rays in a wgpu compute shader.
+//! Camera rays are generated on the cpu, so any [`Camera`] may be used, while marching and shading
+//! happen on the gpu. The cpu integrators remain the reference implementation.
+
+use anyhow::{bail, Error};
+use std::sync::Arc;
+
+use crate::{
+    camera::CanvasInfo,
+    canvas::Canvas,
+    integrator::IntegratorBuilder,
+    sampler::Sampler,
+    scene::{NodeId, Scene},
+};
+
+#[cfg(feature = "gpu")]
+mod wgsl;
+
+type Result<T> = std::result::Result<T, Error>;
+
+#[cfg(feature = "gpu")]
+pub use enabled::{Context, Pipeline, Settings};
+
+#[cfg(not(feature = "gpu"))]
+pub use disabled::{Context, Pipeline};
+
+#[cfg(feature = "gpu")]
+mod enabled {
+    use nalgebra::{Point2, Point3, Vector3};
+    use wgpu::util::DeviceExt;
+
+    use super::*;
+    use crate::{
+        camera::{Camera, Sample},
+        canvas::Color,
+        integrator::Background,
+        math::Float,
+        ray::Ray,
+        scene::MarchConfig,
+    };
+
+    /// The maximum number of rays submitted to the gpu in a single dispatch.
+    const BATCH_SIZE: usize = 1 << 20;
+
+    /// The integrator parameters needed to render with the gpu backend.
+    pub struct Settings {
+        pub camera: Arc<dyn Camera>,
+        pub config: MarchConfig,
+        pub max_reflections: u32,
+        pub background: Background,
+    }
+
+    /// A connection to the gpu.
+    pub struct Context {
+        device: wgpu::Device,
+        queue: wgpu::Queue,
+    }
+
+    impl Context {
+        pub fn new() -> Result<Self> {
+            let instance = wgpu::Instance::new(&wgpu::InstanceDescriptor::default());
+            let adapter =
+                pollster::block_on(instance.request_adapter(&wgpu::RequestAdapterOptions {
+                    power_preference: wgpu::PowerPreference::HighPerformance,
+                    ..Default::default()
+                }))?;
+            let (device, queue) =
+                pollster::block_on(adapter.request_device(&wgpu::DeviceDescriptor::default()))?;
+            Ok(Self { device, queue })
+        }
+
+        /// Compile the scene for a single render.
+        pub fn prepare(
+            self: &Arc<Self>,
+            scene: &Scene,
+            root: NodeId,
+            builder: &dyn IntegratorBuilder,
+        ) -> Result<Pipeline> {
+            let Some(settings) = builder.gpu_settings() else {
+                bail!("The integrator isn't supported by the gpu backend");
+            };
+
+            if !matches!(settings.background, Background::Sky) {
+                bail!("Backgrounds other than the sky aren't supported by the gpu backend");
+            }
+
+            let source = wgsl::compile(
+                scene,
+                root,
+                &settings.config.scaled(scene.bounding_box(root)),
+                settings.max_reflections,
+            )?;
+
+            self.device.push_error_scope(wgpu::ErrorFilter::Validation);
+            let module = self
+                .device
+                .create_shader_module(wgpu::ShaderModuleDescriptor {
+                    label: Some("rendrs"),
+                    source: wgpu::ShaderSource::Wgsl(source.into()),
+                });
+            let pipeline = self
+                .device
+                .create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+                    label: Some("rendrs"),
+                    layout: None,
+                    module: &module,
+                    entry_point: Some("main"),
+                    compilation_options: Default::default(),
+                    cache: None,
+                });
+            if let Some(err) = pollster::block_on(self.device.pop_error_scope()) {
+                bail!("Failed to compile the scene for the gpu: {}", err);
+            }
+
+            Ok(Pipeline {
+                context: self.clone(),
+                pipeline,
+                camera: settings.camera,
+            })
+        }
+    }
+
+    /// A scene compiled for the gpu.
+    pub struct Pipeline {
+        context: Arc<Context>,
+        pipeline: wgpu::ComputePipeline,
+        camera: Arc<dyn Camera>,
+    }
+
+    impl Pipeline {
+        pub fn render(&self, info: &CanvasInfo, mut sampler: impl Sampler) -> Result<Canvas> {
+            let mut canvas = info.new_canvas();
+
+            // Generate all of the camera rays up front, packed as pairs of vec4s.
+            let mut rays = Vec::new();
+            let mut counts = Vec::with_capacity((info.width * info.height) as usize);
+            let mut seen = Vec::new();
+            let mut samples = Vec::with_capacity(sampler.samples_per_pixel());
+            for (col, row) in canvas.coords() {
+                samples.clear();
+                sampler.pixel_samples(&mut samples, &Point2::new(col as Float, row as Float));
+                for sample in &samples {
+                    // Samples without a ray still take a slot in the batch, but aren't counted.
+                    let ray = self.camera.generate_ray(&Sample::new(sample.x, sample.y));
+                    seen.push(ray.is_some());
+                    let ray = ray.unwrap_or_else(|| Ray::new(Point3::origin(), Vector3::z_axis()));
+                    rays.extend_from_slice(&[
+                        ray.position.x as f32,
+                        ray.position.y as f32,
+                        ray.position.z as f32,
+                        1.,
+                        ray.direction.x as f32,
+                        ray.direction.y as f32,
+                        ray.direction.z as f32,
+                        0.,
+                    ]);
+                }
+                counts.push(samples.len());
+            }
+
+            let mut colors: Vec<f32> = Vec::with_capacity(rays.len() / 2);
+            for batch in rays.chunks(BATCH_SIZE * 8) {
+                colors.extend(self.dispatch(batch)?);
+            }
+
+            let mut colors = colors.chunks(4).zip(seen);
+            let (pixels, alpha) = canvas.pixels_and_alpha_mut();
+            for ((pixel, alpha), count) in pixels.iter_mut().zip(alpha).zip(counts) {
+                let mut covered = 0;
+                for (color, seen) in colors.by_ref().take(count) {
+                    if seen {
+                        *pixel += Color::new(color[0], color[1], color[2]);
+                        covered += 1;
+                    }
+                }
+                *pixel *= 1. / count.max(1) as f32;
+                *alpha = covered as f32 / count.max(1) as f32;
+            }
+
+            Ok(canvas)
+        }
+
+        /// Run the shader for a batch of rays, returning a vec4 color for each.
+        fn dispatch(&self, rays: &[f32]) -> Result<Vec<f32>> {
+            let device = &self.context.device;
+            let count = rays.len() / 8;
+            let size = (count * 4 * std::mem::size_of::<f32>()) as wgpu::BufferAddress;
+
+            let input = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                label: Some("rays"),
+                contents: bytemuck::cast_slice(rays),
+                usage: wgpu::BufferUsages::STORAGE,
+            });
+            let output = device.create_buffer(&wgpu::BufferDescriptor {
+                label: Some("colors"),
+                size,
+                usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_SRC,
+                mapped_at_creation: false,
+            });
+            let readback = device.create_buffer(&wgpu::BufferDescriptor {
+                label: Some("readback"),
+                size,
+                usage: wgpu::BufferUsages::MAP_READ | wgpu::BufferUsages::COPY_DST,
+                mapped_at_creation: false,
+            });
+
+            let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+                label: Some("rendrs"),
+                layout: &self.pipeline.get_bind_group_layout(0),
+                entries: &[
+                    wgpu::BindGroupEntry {
+                        binding: 0,
+                        resource: input.as_entire_binding(),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 1,
+                        resource: output.as_entire_binding(),
+                    },
+                ],
+            });
+
+            let mut encoder = device.create_command_encoder(&Default::default());
+            {
+                let mut pass = encoder.begin_compute_pass(&Default::default());
+                pass.set_pipeline(&self.pipeline);
+                pass.set_bind_group(0, &bind_group, &[]);
+                pass.dispatch_workgroups((count as u32).div_ceil(wgsl::WORKGROUP_SIZE), 1, 1);
+            }
+            encoder.copy_buffer_to_buffer(&output, 0, &readback, 0, size);
+            self.context.queue.submit([encoder.finish()]);
+
+            let (send, recv) = crossbeam::channel::bounded(1);
+            let slice = readback.slice(..);
+            slice.map_async(wgpu::MapMode::Read, move |res| send.send(res).unwrap());
+            device.poll(wgpu::PollType::Wait)?;
+            recv.recv()??;
+
+            let colors = bytemuck::cast_slice(&slice.get_mapped_range()).to_vec();
+            readback.unmap();
+
+            Ok(colors)
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use nalgebra::{Point3, Vector3};
+
+        use super::*;
+        use crate::{
+            camera::PinholeCamera, integrator, integrator::WhittedBuilder, sampler::UniformSampler,
+            scene::Falloff, transform::Transform,
+        };
+
+        /// Render a small scene with both backends, and check that they agree. The test is skipped
+        /// when no gpu adapter is available.
+        #[test]
+        fn test_compare_cpu() {
+            let context = match Context::new() {
+                Ok(context) => Arc::new(context),
+                Err(err) => {
+                    eprintln!("skipping gpu comparison: {}", err);
+                    return;
+                }
+            };
+
+            let mut scene = Scene::default();
+            let red = scene.solid(Color::new(1., 0., 0.));
+            let white = scene.solid(Color::white());
+            let floor_pat = scene.checkers(red, white);
+            let shiny = scene.phong(red, 0.1, 0.9, 0.9, 200.0, 0.3, 0.0, 1.0);
+            let matte = scene.phong(floor_pat, 0.1, 0.9, 0.0, 200.0, 0.0, 0.0, 1.0);
+            let sphere = scene.sphere(1.).unwrap();
+            let sphere = scene.paint(shiny, sphere);
+            let floor = scene.plane(nalgebra::Unit::new_normalize(Vector3::new(0., 1., 0.)));
+            let floor = scene.transform(
+                Transform::new().translate(&Vector3::new(0., -1., 0.)),
+                floor,
+            );
+            let floor = scene.paint(matte, floor);
+            let root = scene.group(vec![sphere, floor]).unwrap();
+            scene.point_light(
+                Point3::new(5., 10., -10.),
+                Color::white(),
+                1.,
+                Falloff::None,
+            );
+
+            let info = CanvasInfo::new(32, 32);
+            let camera = PinholeCamera::new(
+                &info,
+                Transform::look_at(
+                    &Point3::new(0., 1., -5.),
+                    &Point3::origin(),
+                    &Vector3::new(0., 1., 0.),
+                ),
+                crate::math::consts::FRAC_PI_2,
+            );
+            let builder = WhittedBuilder::new(camera, MarchConfig::default(), 4);
+
+            let pool = rayon::ThreadPoolBuilder::new().build().unwrap();
+            let cpu = integrator::render(
+                info.clone(),
+                &scene,
+                root,
+                UniformSampler::new(1, 1),
+                &builder,
+                integrator::Workers::local(&pool),
+                &|_| (),
+            )
+            .unwrap();
+            let gpu = context
+                .prepare(&scene, root, &builder)
+                .unwrap()
+                .render(&info, UniformSampler::new(1, 1))
+                .unwrap();
+
+            let cpu = cpu.data();
+            let gpu = gpu.data();
+            let differing = cpu
+                .iter()
+                .zip(gpu.iter())
+                .filter(|(a, b)| a.abs_diff(**b) > 8)
+                .count();
+
+            // Allow for a small number of pixels along silhouettes to disagree.
+            assert!(differing * 50 < cpu.len(), "{} channels differ", differing);
+        }
+    }
+}
+
+#[cfg(not(feature = "gpu"))]
+mod disabled {
+    use super::*;
+
+    /// A stand-in for the gpu connection, when the `gpu` feature is disabled.
+    pub struct Context;
+
+    impl Context {
+        pub fn new() -> Result<Self> {
+            bail!("rendrs was built without gpu support, rebuild with `--features gpu`")
+        }
+
+        pub fn prepare(
+            self: &Arc<Self>,
+            _scene: &Scene,
+            _root: NodeId,
+            _builder: &dyn IntegratorBuilder,
+        ) -> Result<Pipeline> {
+            unreachable!()
+        }
+    }
+
+    /// Pipelines can't be constructed without the `gpu` feature.
+    pub enum Pipeline {}
+
+    impl Pipeline {
+        pub fn render(&self, _info: &CanvasInfo, _sampler: impl Sampler) -> Result<Canvas> {
+            match *self {}
+        }
+    }
+}
@@ -0,0 +1,623 @@
+use anyhow::{bail, Error};
+use std::fmt::Write;
+
+use crate::{
+    canvas::Color,
+    scene::{Backface, Bias, Falloff, Light, MarchConfig, Material, MaterialId, NodeId, Scene},
+    shader::{self, float, Language},
+};
+
+type Result<T> = std::result::Result<T, Error>;
+
+/// The number of invocations in a single workgroup of the generated shader.
+pub const WORKGROUP_SIZE: u32 = 64;
+
+/// Compile the scene reachable from `root` into a WGSL compute shader. The shader reads rays from
+/// binding `0`, and writes one color per ray to binding `1`.
+pub fn compile(
+    scene: &Scene,
+    root: NodeId,
+    config: &MarchConfig,
+    max_reflections: u32,
+) -> Result<String> {
+    let mut compiler = Compiler {
+        scene,
+        shader: shader::Compiler::new(
+            scene,
+            Language::Wgsl,
+            "can't be rendered by the gpu backend",
+        ),
+    };
+
+    if scene.lights.iter().any(|light| !light.links().is_empty()) {
+        bail!("Linking lights to nodes isn't supported by the gpu backend");
+    }
+
+    if !scene.emitters(root).is_empty() {
+        bail!("Lighting from emissive surfaces isn't supported by the gpu backend");
+    }
+
+    compiler.prelude(config, max_reflections);
+    compiler.shader.node(root)?;
+    for id in compiler.shader.materials.clone() {
+        compiler.material(id)?;
+    }
+    compiler.surface()?;
+    compiler.lighting();
+    compiler.shader.sdf(root);
+    compiler.main();
+
+    Ok(compiler.shader.out)
+}
+
+/// Compiles the shading and marching of the gpu backend, around the distance functions and
+/// patterns that the shader compiler emits.
+struct Compiler<'a> {
+    scene: &'a Scene,
+    shader: shader::Compiler<'a>,
+}
+
+/// Format a color as a WGSL literal.
+fn color(c: &Color) -> String {
+    Language::Wgsl.color(c)
+}
+
+impl<'a> Compiler<'a> {
+    fn prelude(&mut self, config: &MarchConfig, max_reflections: u32) {
+        // A density of zero disables the fog.
+        let (fog_color, fog_density) = match &self.scene.fog {
+            Some(fog) => (fog.color.clone(), fog.density),
+            None => (Color::black(), 0.),
+        };
+
+        write!(
+            self.shader.out,
+            "const MAX_STEPS: u32 = {}u;
+const MIN_DIST: f32 = {};
+const MAX_DIST: f32 = {};
+const MAX_REFLECTIONS: u32 = {}u;
+const FOG_COLOR: vec3<f32> = {};
+const FOG_DENSITY: f32 = {};
+
+struct Ray {{
+    origin: vec4<f32>,
+    direction: vec4<f32>,
+}}
+
+@group(0) @binding(0) var<storage, read> rays: array<Ray>;
+@group(0) @binding(1) var<storage, read_write> colors: array<vec4<f32>>;
+
+struct Hit {{
+    found: bool,
+    position: vec3<f32>,
+    object: vec3<f32>,
+    material: i32,
+}}
+
+struct Surface {{
+    color: vec3<f32>,
+    ambient: f32,
+    diffuse: f32,
+    specular: f32,
+    shininess: f32,
+    reflective: f32,
+    fresnel: bool,
+    lit: bool,
+}}
+
+",
+            config.max_steps,
+            float(config.min_dist),
+            float(config.max_dist),
+            max_reflections,
+            color(&fog_color),
+            float(fog_density),
+        )
+        .unwrap();
+
+        self.shader.prelude();
+    }
+
+    /// Check that the material `id` can be rendered, and compile its pattern.
+    fn material(&mut self, id: MaterialId) -> Result<()> {
+        let material = self.scene.material(id);
+        if material.backface() != Backface::default() {
+            bail!("Shading the far side of surfaces isn't supported by the gpu backend");
+        }
+        if material.bias() != Bias::default() {
+            bail!("Offsetting rays from surfaces isn't supported by the gpu backend");
+        }
+
+        match *material {
+            Material::Phong {
+                pattern,
+                transparent,
+                roughness,
+                ref scatter,
+                film_thickness,
+                ..
+            } => {
+                if transparent > 0. {
+                    bail!("Transparent materials aren't supported by the gpu backend");
+                }
+                if roughness > 0. {
+                    bail!("Rough reflections aren't supported by the gpu backend");
+                }
+                if !scatter.is_black() {
+                    bail!("Subsurface scattering isn't supported by the gpu backend");
+                }
+                if film_thickness > 0. {
+                    bail!("Thin films aren't supported by the gpu backend");
+                }
+                self.shader.pattern(pattern)?;
+            }
+
+            Material::Toon { .. } => {
+                bail!("Toon materials aren't supported by the gpu backend")
+            }
+
+            Material::Matcap { .. } => {
+                bail!("Matcap materials aren't supported by the gpu backend")
+            }
+
+            Material::Emissive { pattern, .. } => self.shader.pattern(pattern)?,
+        }
+
+        Ok(())
+    }
+
+    /// Emit the function that looks up the surface properties of a material.
+    fn surface(&mut self) -> Result<()> {
+        writeln!(
+            self.shader.out,
+            "fn surface(material: i32, object: vec3<f32>) -> Surface {{
+    // unlit magenta for objects with no material
+    var s = Surface(vec3<f32>(1.0, 0.0, 1.0), 0.0, 0.0, 0.0, 0.0, 0.0, false, false);
+    switch material {{"
+        )
+        .unwrap();
+
+        for &id in self.shader.materials.iter() {
+            let surface = match self.scene.material(id) {
+                &Material::Phong {
+                    pattern,
+                    ambient,
+                    diffuse,
+                    specular,
+                    shininess,
+                    reflective,
+                    fresnel,
+                    ..
+                } => format!(
+                    "Surface(scene_pattern_{}(object), {}, {}, {}, {}, {}, {}, true)",
+                    pattern.index(),
+                    float(ambient),
+                    float(diffuse),
+                    float(specular),
+                    float(shininess),
+                    float(reflective),
+                    fresnel
+                ),
+
+                Material::Emissive { pattern, .. } => format!(
+                    "Surface(scene_pattern_{}(object), 0.0, 0.0, 0.0, 0.0, 0.0, false, false)",
+                    pattern.index()
+                ),
+
+                Material::Toon { .. } | Material::Matcap { .. } => {
+                    unreachable!("toon and matcap materials are rejected up front")
+                }
+            };
+
+            writeln!(
+                self.shader.out,
+                "        case {}: {{
+            s = {};
+        }}",
+                id.index(),
+                surface
+            )
+            .unwrap();
+        }
+
+        write!(
+            self.shader.out,
+            "        default: {{}}
+    }}
+    return s;
+}}
+
+"
+        )
+        .unwrap();
+
+        Ok(())
+    }
+
+    /// Emit the lighting computation and the light seen by escaping rays, unrolled for each light
+    /// in the scene.
+    fn lighting(&mut self) {
+        writeln!(
+            self.shader.out,
+            "fn lighting(s: Surface, position: vec3<f32>, normal: vec3<f32>, eye: vec3<f32>) -> vec3<f32> {{
+    var result = vec3<f32>(0.0);"
+        )
+        .unwrap();
+
+        for light in self.scene.lights.iter() {
+            match light {
+                Light::Diffuse {
+                    color: c,
+                    intensity,
+                    ..
+                } => writeln!(
+                    self.shader.out,
+                    "    result += s.ambient * s.color * {};",
+                    color(&(c * *intensity))
+                )
+                .unwrap(),
+
+                Light::Point {
+                    position,
+                    color: c,
+                    intensity,
+                    falloff,
+                    ..
+                } => write!(
+                    self.shader.out,
+                    "    {{
+        let light = {};
+        let intensity = {}{};
+        let effective = s.color * intensity;
+        result += s.ambient * s.color * {};
+        if !in_shadow(position, normal, light) {{
+            let lightv = normalize(light - position);
+            let light_dot_normal = dot(lightv, normal);
+            if light_dot_normal >= 0.0 {{
+                result += effective * s.diffuse * light_dot_normal;
+                if s.specular > 0.0 {{
+                    let reflect_dot_eye = dot(reflect(-lightv, normal), eye);
+                    if reflect_dot_eye > 0.0 {{
+                        result += intensity * s.specular * pow(reflect_dot_eye, s.shininess);
+                    }}
+                }}
+            }}
+        }}
+    }}
+",
+                    Language::Wgsl.vec3_literal(position.x, position.y, position.z),
+                    color(&(c * *intensity)),
+                    match falloff {
+                        Falloff::None => String::new(),
+                        Falloff::InverseSquare => {
+                            " / dot(light - position, light - position)".to_string()
+                        }
+                        Falloff::Exponent(exponent) =>
+                            format!(" * pow(length(light - position), {})", float(-exponent)),
+                    },
+                    color(&light.ambient()),
+                )
+                .unwrap(),
+            }
+        }
+
+        write!(self.shader.out, "    return result;\n}}\n\n").unwrap();
+
+        writeln!(
+            self.shader.out,
+            "fn escape(direction: vec3<f32>) -> vec3<f32> {{
+    var result = vec3<f32>(0.0);"
+        )
+        .unwrap();
+
+        for light in self.scene.lights.iter() {
+            if let Light::Diffuse {
+                color: c,
+                horizon,
+                intensity,
+                ..
+            } = light
+            {
+                writeln!(
+                    self.shader.out,
+                    "    result += mix({}, {}, max(direction.y, 0.0));",
+                    color(&(horizon * *intensity)),
+                    color(&(c * *intensity))
+                )
+                .unwrap();
+            }
+        }
+
+        write!(self.shader.out, "    return result;\n}}\n\n").unwrap();
+    }
+
+    /// Emit the marching functions and the entry point.
+    fn main(&mut self) {
+        write!(
+            self.shader.out,
+            "fn march(origin: vec3<f32>, direction: vec3<f32>) -> Hit {{
+    var position = origin;
+    var total = 0.0;
+    for (var i = 0u; i < MAX_STEPS; i++) {{
+        let r = scene_sdf(position);
+        if r.dist < MIN_DIST {{
+            return Hit(true, position, r.object, r.material);
+        }}
+        total += r.dist;
+        if total > MAX_DIST {{
+            break;
+        }}
+        position += direction * r.dist;
+    }}
+    return Hit(false, position, position, -1);
+}}
+
+fn march_dist(origin: vec3<f32>, direction: vec3<f32>) -> f32 {{
+    var position = origin;
+    var total = 0.0;
+    for (var i = 0u; i < MAX_STEPS; i++) {{
+        let d = scene_sdf(position).dist;
+        if d < MIN_DIST {{
+            return total;
+        }}
+        total += d;
+        if total > MAX_DIST {{
+            break;
+        }}
+        position += direction * d;
+    }}
+    return -1.0;
+}}
+
+fn in_shadow(position: vec3<f32>, normal: vec3<f32>, light: vec3<f32>) -> bool {{
+    let start = position + MIN_DIST * normal;
+    let dir = light - start;
+    let dist_to_light = length(dir);
+    let dist = march_dist(start, dir / dist_to_light);
+    return dist >= 0.0 && dist < dist_to_light;
+}}
+
+fn normal_at(p: vec3<f32>) -> vec3<f32> {{
+    let e = vec2<f32>(1.0, -1.0) * 0.0001 * max(max(abs(p.x), max(abs(p.y), abs(p.z))), 1.0);
+    let n = e.xyy * scene_sdf(p + e.xyy).dist
+        + e.yyx * scene_sdf(p + e.yyx).dist
+        + e.yxy * scene_sdf(p + e.yxy).dist
+        + e.xxx * scene_sdf(p + e.xxx).dist;
+    if length(n) == 0.0 {{
+        return vec3<f32>(0.0, 1.0, 0.0);
+    }}
+    return normalize(n);
+}}
+
+@compute @workgroup_size({WORKGROUP_SIZE})
+fn main(@builtin(global_invocation_id) id: vec3<u32>) {{
+    let ix = id.x;
+    if ix >= arrayLength(&rays) {{
+        return;
+    }}
+
+    var origin = rays[ix].origin.xyz;
+    var direction = rays[ix].direction.xyz;
+    var color = vec3<f32>(0.0);
+    var weight = 1.0;
+
+    for (var reflection = 0u; reflection < MAX_REFLECTIONS; reflection++) {{
+        let hit = march(origin, direction);
+        if !hit.found {{
+            let fog = exp(-FOG_DENSITY * MAX_DIST);
+            color += weight * (fog * escape(direction) + (1.0 - fog) * FOG_COLOR);
+            break;
+        }}
+
+        // The light from the hit is attenuated by the fog, and replaced by the fog color.
+        let fog = exp(-FOG_DENSITY * distance(origin, hit.position));
+        color += weight * (1.0 - fog) * FOG_COLOR;
+        weight *= fog;
+
+        let s = surface(hit.material, hit.object);
+        if !s.lit {{
+            color += weight * s.color;
+            break;
+        }}
+
+        let normal = normal_at(hit.position);
+        color += weight * lighting(s, hit.position, normal, -direction);
+
+        if s.reflective <= 0.0 {{
+            break;
+        }}
+
+        // Surfaces reflect more at grazing angles, by Schlick's approximation.
+        var reflectance = s.reflective;
+        if s.fresnel {{
+            let cosine = max(dot(-direction, normal), 0.0);
+            reflectance += (1.0 - s.reflective) * pow(1.0 - cosine, 5.0);
+        }}
+
+        weight *= reflectance;
+        direction = reflect(direction, normal);
+        origin = hit.position + direction * MIN_DIST;
+    }}
+
+    colors[ix] = vec4<f32>(color, 1.0);
+}}
+",
+        )
+        .unwrap();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nalgebra::Vector3;
+
+    use crate::transform::Transform;
+
+    #[test]
+    fn test_compile_reachable() {
+        let mut scene = Scene::default();
+
+        let white = scene.solid(Color::white());
+        let material = scene.phong(white, 0.1, 0.9, 0.9, 200.0, 0.0, 0.0, 1.0);
+        let sphere = scene.sphere(1.0).unwrap();
+        let unused = scene.rect(1.0, 1.0, 1.0).unwrap();
+        let moved = scene.transform(
+            Transform::new().translate(&Vector3::new(1., 0., 0.)),
+            sphere,
+        );
+        let root = scene.paint(material, moved);
+
+        let source = compile(&scene, root, &MarchConfig::default(), 10).unwrap();
+
+        assert!(source.contains(&format!("fn scene_node_{}(", sphere.index())));
+        assert!(source.contains(&format!("fn scene_node_{}(", root.index())));
+        assert!(!source.contains(&format!("fn scene_node_{}(", unused.index())));
+        assert!(source.contains(&format!("fn scene_pattern_{}(", white.index())));
+    }
+
+    /// Build a scene that exercises all of the node and pattern types.
+    fn kitchen_sink() -> (Scene, NodeId) {
+        let mut scene = Scene::default();
+
+        let white = scene.solid(Color::white());
+        let black = scene.solid(Color::black());
+        let gradiant = scene.gradiant(white, black);
+        let stripes = scene.stripes(gradiant, black);
+        let ramp = scene.ramp(
+            Vector3::new(0., 1., 0.),
+            vec![
+                (0., Color::white()),
+                (0.5, Color::black()),
+                (1., Color::white()),
+            ],
+        );
+        let checkers = scene.checkers(stripes, ramp);
+        let shells = scene.shells(checkers, black);
+        let pattern = scene.transform_pat(Transform::new().uniform_scale(2.), shells);
+
+        let shiny = scene.phong(pattern, 0.1, 0.9, 0.9, 200.0, 0.5, 0.0, 1.0);
+        let glow = scene.emissive(white);
+
+        let plane = scene.plane(nalgebra::Unit::new_normalize(Vector3::new(0., 1., 0.)));
+        let sphere = scene.sphere(1.).unwrap();
+        let rect = scene.rect(1., 2., 3.).unwrap();
+        let torus = scene.torus(1., 0.25).unwrap();
+        let triangle = scene.triangle(
+            nalgebra::Point3::new(0., 0., 0.),
+            nalgebra::Point3::new(1., 0., 0.),
+            nalgebra::Point3::new(0., 1., 0.),
+            nalgebra::Unit::new_normalize(Vector3::new(0., 0., 1.)),
+        );
+        let inverted = scene.invert(rect);
+        let smooth = scene.smooth_union(0.5, &[sphere, torus]).unwrap();
+        let subtracted = scene.subtract(smooth, triangle);
+        let intersected = scene.intersect(vec![subtracted, inverted]).unwrap();
+        let union = scene.union(vec![intersected, sphere]).unwrap();
+        let painted = scene.paint(shiny, union);
+        let glowing = scene.paint(glow, plane);
+        let root = scene.group(vec![painted, glowing]).unwrap();
+
+        scene.diffuse_light(Color::new(0.1, 0.1, 0.1), Color::new(0.3, 0.3, 0.4), 1.);
+        scene.point_light(
+            nalgebra::Point3::new(1., 2., 3.),
+            Color::white(),
+            1.,
+            Falloff::None,
+        );
+        scene.point_light(
+            nalgebra::Point3::new(-1., 2., 3.),
+            Color::white(),
+            10.,
+            Falloff::InverseSquare,
+        );
+        scene.point_light(
+            nalgebra::Point3::new(0., 2., -3.),
+            Color::white(),
+            2.,
+            Falloff::Exponent(1.),
+        );
+        scene.fog = Some(crate::scene::Fog {
+            color: Color::new(0.5, 0.5, 0.6),
+            density: 0.02,
+        });
+
+        (scene, root)
+    }
+
+    #[test]
+    fn test_compile_validates() {
+        use wgpu::naga;
+
+        let (scene, root) = kitchen_sink();
+        let source = compile(&scene, root, &MarchConfig::default(), 10).unwrap();
+
+        let module = naga::front::wgsl::parse_str(&source).unwrap();
+        naga::valid::Validator::new(
+            naga::valid::ValidationFlags::all(),
+            naga::valid::Capabilities::empty(),
+        )
+        .validate(&module)
+        .unwrap();
+    }
+
+    #[test]
+    fn test_compile_rejects_transparent() {
+        let mut scene = Scene::default();
+
+        let white = scene.solid(Color::white());
+        let glass = scene.phong(white, 0.1, 0.9, 0.9, 200.0, 0.0, 1.0, 1.5);
+        let sphere = scene.sphere(1.0).unwrap();
+        let root = scene.paint(glass, sphere);
+
+        assert!(compile(&scene, root, &MarchConfig::default(), 10).is_err());
+    }
+
+    #[test]
+    fn test_compile_rejects_emitters() {
+        let mut scene = Scene::default();
+
+        // Emissive planes only show their color, but bounded surfaces light the scene.
+        let white = scene.solid(Color::white());
+        let glow = scene.emissive(white);
+        let plane = scene.plane(nalgebra::Unit::new_normalize(Vector3::new(0., 1., 0.)));
+        let root = scene.paint(glow, plane);
+        assert!(compile(&scene, root, &MarchConfig::default(), 10).is_ok());
+
+        let sphere = scene.sphere(1.0).unwrap();
+        let root = scene.paint(glow, sphere);
+        assert!(compile(&scene, root, &MarchConfig::default(), 10).is_err());
+    }
+
+    #[test]
+    fn test_compile_rejects_backface() {
+        let mut scene = Scene::default();
+
+        let white = scene.solid(Color::white());
+        let glow = scene.emissive(white);
+        let plane = scene.plane(nalgebra::Unit::new_normalize(Vector3::new(0., 1., 0.)));
+        let root = scene.paint(glow, plane);
+        assert!(compile(&scene, root, &MarchConfig::default(), 10).is_ok());
+
+        scene.set_backface(glow, Backface::TwoSided);
+        assert!(compile(&scene, root, &MarchConfig::default(), 10).is_err());
+    }
+
+    #[test]
+    fn test_compile_rejects_bias() {
+        let mut scene = Scene::default();
+
+        let white = scene.solid(Color::white());
+        let glow = scene.emissive(white);
+        let plane = scene.plane(nalgebra::Unit::new_normalize(Vector3::new(0., 1., 0.)));
+        let root = scene.paint(glow, plane);
+        scene.set_bias(
+            glow,
+            Bias {
+                normal: Some(0.01),
+                ..Bias::default()
+            },
+        );
+        assert!(compile(&scene, root, &MarchConfig::default(), 10).is_err());
+    }
+}
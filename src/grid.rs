@@ -0,0 +1,431 @@
+//! Signed distances sampled on a regular grid. Baking an expensive node, like a large mesh or deep
+//! csg, into a grid replaces its distance function with a few lookups and a trilinear blend, at
+//! the cost of rounding off details smaller than the grid's cells. Grids can also be loaded from
+//! files written by other tools, such as scans and simulations.
+
+use anyhow::{bail, Context, Error};
+use nalgebra::{Point3, Unit, Vector3};
+use rayon::prelude::*;
+use std::path::Path;
+
+use crate::{
+    bvh::BoundingBox,
+    math::Float,
+    ray::Ray,
+    scene::{NodeId, Scene},
+};
+
+type Result<T> = std::result::Result<T, Error>;
+
+/// The most cells along a side of a grid, which keeps its samples within a few gigabytes.
+pub const MAX_RESOLUTION: usize = 1024;
+
+/// Distances sampled at the corners of a regular grid of cubes.
+#[derive(Debug, Clone)]
+pub struct DistanceGrid {
+    origin: Point3<Float>,
+    step: Float,
+    dims: [usize; 3],
+
+    /// The samples, with x varying fastest. They're stored at single precision, which is plenty
+    /// for the error that interpolating them introduces.
+    values: Vec<f32>,
+}
+
+impl DistanceGrid {
+    /// Sample the distance to `root` over its bounding box, with `resolution` cells along the
+    /// longest side. The grid extends a cell past the bounding box on each side, so that every
+    /// surface of the node is surrounded by samples.
+    pub fn bake(scene: &Scene, root: NodeId, resolution: usize) -> Result<Self> {
+        if !(2..=MAX_RESOLUTION).contains(&resolution) {
+            bail!("The resolution must be between 2 and {}", MAX_RESOLUTION);
+        }
+
+        let (min, max) = match scene.bounding_box(root) {
+            BoundingBox::Bounds { min, max } => (min, max),
+            BoundingBox::Min => bail!("The node is empty, so there's nothing to sample"),
+            BoundingBox::Max => bail!("The node has no bounds, as it contains a plane"),
+        };
+
+        let size = max - min;
+        let step = size.max() / resolution as Float;
+        if step <= 0. || !step.is_finite() {
+            bail!("The node is too thin to sample");
+        }
+        let origin = min - Vector3::repeat(step);
+        let dims = size.map(|side| (side / step).ceil() as usize + 3);
+        let dims = [dims.x, dims.y, dims.z];
+
+        // Each slice of constant z is sampled on its own thread.
+        let mut values = vec![0.; dims[0] * dims[1] * dims[2]];
+        values
+            .par_chunks_mut(dims[0] * dims[1])
+            .enumerate()
+            .for_each(|(k, values)| {
+                for (ix, value) in values.iter_mut().enumerate() {
+                    let (i, j) = (ix % dims[0], ix / dims[0]);
+                    let p = origin + Vector3::new(i as Float, j as Float, k as Float) * step;
+                    let ray = Ray::new(p, Vector3::y_axis());
+                    let distance = scene.node(root).fast_sdf(scene, &ray, Float::NEG_INFINITY);
+                    *value = distance.distance.0 as f32;
+                }
+            });
+
+        Self::new(origin, step, dims, values)
+    }
+
+    /// A grid with the samples in `values`, which vary fastest along x and slowest along z. The
+    /// grid starts at `origin`, and its samples are `step` apart.
+    pub fn new(
+        origin: Point3<Float>,
+        step: Float,
+        dims: [usize; 3],
+        values: Vec<f32>,
+    ) -> Result<Self> {
+        if dims.iter().any(|&dim| dim < 2) {
+            bail!("A grid needs at least two samples along each axis");
+        }
+        let Some(samples) = dims
+            .iter()
+            .try_fold(1usize, |acc, &dim| acc.checked_mul(dim))
+        else {
+            bail!("A {}x{}x{} grid is too large", dims[0], dims[1], dims[2]);
+        };
+        if values.len() != samples {
+            bail!(
+                "A {}x{}x{} grid needs {} samples, but there are {}",
+                dims[0],
+                dims[1],
+                dims[2],
+                samples,
+                values.len()
+            );
+        }
+        if step <= 0. || !step.is_finite() {
+            bail!("The distance between samples must be positive");
+        }
+        if values.iter().any(|value| !value.is_finite()) {
+            bail!("The grid has samples that aren't finite");
+        }
+
+        Ok(Self {
+            origin,
+            step,
+            dims,
+            values,
+        })
+    }
+
+    /// Load a grid from a file written by another tool. Files with the `npy` extension are read as
+    /// numpy arrays of three dimensions, indexed by x, y and z. Any other file is read as raw
+    /// little-endian `f32` samples, varying fastest along x, and `dims` must be given.
+    pub fn load(
+        path: &Path,
+        dims: Option<[usize; 3]>,
+        origin: Point3<Float>,
+        step: Float,
+    ) -> Result<Self> {
+        let bytes = std::fs::read(path)
+            .with_context(|| format!("Failed to read the grid `{}`", path.display()))?;
+        if path.extension().is_some_and(|ext| ext == "npy") {
+            let (dims, values) = read_npy(&bytes)?;
+            Self::new(origin, step, dims, values)
+        } else {
+            let Some(dims) = dims else {
+                bail!(
+                    "The size of the raw grid `{}` must be given",
+                    path.display()
+                );
+            };
+            if bytes.len() % 4 != 0 {
+                bail!(
+                    "The raw grid `{}` isn't made of f32 samples",
+                    path.display()
+                );
+            }
+            let values = bytes
+                .chunks_exact(4)
+                .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+                .collect();
+            Self::new(origin, step, dims, values)
+        }
+    }
+
+    /// The width of the grid's cells.
+    pub fn step(&self) -> Float {
+        self.step
+    }
+
+    /// The number of samples along each axis.
+    pub fn dims(&self) -> [usize; 3] {
+        self.dims
+    }
+
+    pub fn index(&self, [i, j, k]: [usize; 3]) -> usize {
+        i + self.dims[0] * (j + self.dims[1] * k)
+    }
+
+    /// The sample at a corner of the grid.
+    pub fn value(&self, corner: [usize; 3]) -> Float {
+        self.values[self.index(corner)] as Float
+    }
+
+    /// The position of a corner of the grid.
+    pub fn point(&self, [i, j, k]: [usize; 3]) -> Point3<Float> {
+        self.origin + Vector3::new(i as Float, j as Float, k as Float) * self.step
+    }
+
+    pub fn bounding_box(&self) -> BoundingBox {
+        let [i, j, k] = self.dims;
+        BoundingBox::Bounds {
+            min: self.origin,
+            max: self.point([i - 1, j - 1, k - 1]),
+        }
+    }
+
+    /// The distance at `p`, blended from the samples at the corners of the cell that it falls in.
+    /// Outside of the grid the distance is to the grid's bounds, plus the cell of padding that
+    /// separates them from the node.
+    pub fn sdf(&self, p: &Point3<Float>) -> Float {
+        let BoundingBox::Bounds { min, max } = self.bounding_box() else {
+            unreachable!()
+        };
+        let clamped = p
+            .coords
+            .zip_zip_map(&min.coords, &max.coords, |x, lo, hi| x.clamp(lo, hi));
+        let outside = (p.coords - clamped).norm();
+        if outside > 0. {
+            return outside + self.step;
+        }
+
+        let g = (clamped - self.origin.coords) / self.step;
+        let mut corner = [0; 3];
+        let mut t = Vector3::zeros();
+        for axis in 0..3 {
+            let cell = (g[axis].floor() as usize).min(self.dims[axis] - 2);
+            corner[axis] = cell;
+            t[axis] = g[axis] - cell as Float;
+        }
+
+        let mut distance = 0.;
+        for n in 0..8 {
+            let mut c = corner;
+            let mut weight = 1.;
+            for axis in 0..3 {
+                if n & (1 << axis) != 0 {
+                    c[axis] += 1;
+                    weight *= t[axis];
+                } else {
+                    weight *= 1. - t[axis];
+                }
+            }
+            distance += weight * self.value(c);
+        }
+        distance
+    }
+
+    /// The normal at `p`, from central differences half a cell apart so that it blends smoothly
+    /// between neighbouring cells.
+    pub fn normal(&self, p: &Point3<Float>) -> Unit<Vector3<Float>> {
+        let h = self.step / 2.;
+        let axis = |v: Vector3<Float>| self.sdf(&(p + v * h)) - self.sdf(&(p - v * h));
+        let n = Vector3::new(axis(Vector3::x()), axis(Vector3::y()), axis(Vector3::z()));
+        Unit::try_new(n, 0.).unwrap_or_else(Vector3::y_axis)
+    }
+}
+
+/// Read an array from the contents of a `.npy` file, returning its shape and its values in the
+/// order that the grid stores them. Only arrays of three dimensions of little-endian floats are
+/// accepted.
+fn read_npy(bytes: &[u8]) -> Result<([usize; 3], Vec<f32>)> {
+    if !bytes.starts_with(b"\x93NUMPY") || bytes.len() < 10 {
+        bail!("The grid isn't a numpy array");
+    }
+    let (len, start) = match bytes[6] {
+        1 => (u16::from_le_bytes([bytes[8], bytes[9]]) as usize, 10),
+        _ if bytes.len() >= 12 => (
+            u32::from_le_bytes([bytes[8], bytes[9], bytes[10], bytes[11]]) as usize,
+            12,
+        ),
+        _ => bail!("The grid's numpy header is truncated"),
+    };
+    let Some(header) = bytes.get(start..start + len) else {
+        bail!("The grid's numpy header is truncated");
+    };
+    let header = std::str::from_utf8(header)?;
+    let data = &bytes[start + len..];
+
+    // The header is a python dictionary literal, with the fields always in the same form.
+    let field = |name: &str| -> Result<&str> {
+        let key = format!("'{}':", name);
+        let Some(at) = header.find(&key) else {
+            bail!("The grid's numpy header has no `{}`", name);
+        };
+        Ok(header[at + key.len()..].trim_start())
+    };
+
+    let descr = field("descr")?;
+    let size = if descr.starts_with("'<f4'") {
+        4
+    } else if descr.starts_with("'<f8'") {
+        8
+    } else {
+        bail!("The grid must hold little-endian floats");
+    };
+
+    let fortran = field("fortran_order")?.starts_with("True");
+
+    let shape = field("shape")?;
+    let Some((shape, _)) = shape
+        .strip_prefix('(')
+        .and_then(|shape| shape.split_once(')'))
+    else {
+        bail!("The grid's numpy shape is malformed");
+    };
+    let shape = shape
+        .split(',')
+        .map(str::trim)
+        .filter(|dim| !dim.is_empty())
+        .map(|dim| dim.parse::<usize>())
+        .collect::<std::result::Result<Vec<_>, _>>()?;
+    let [nx, ny, nz] = shape[..] else {
+        bail!(
+            "The grid must have three dimensions, but it has {}",
+            shape.len()
+        );
+    };
+
+    let data_len = [ny, nz, size]
+        .into_iter()
+        .try_fold(nx, |acc, dim| acc.checked_mul(dim));
+    if data_len != Some(data.len()) {
+        bail!("The grid's numpy data doesn't match its shape");
+    }
+    let values: Vec<f32> = if size == 4 {
+        data.chunks_exact(4)
+            .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+            .collect()
+    } else {
+        data.chunks_exact(8)
+            .map(|b| f64::from_le_bytes(b.try_into().unwrap()) as f32)
+            .collect()
+    };
+
+    // Fortran order already varies fastest along x. C order varies fastest along z, and is
+    // transposed.
+    if fortran {
+        return Ok(([nx, ny, nz], values));
+    }
+    let mut transposed = vec![0.; values.len()];
+    for x in 0..nx {
+        for y in 0..ny {
+            for z in 0..nz {
+                transposed[x + nx * (y + ny * z)] = values[(x * ny + y) * nz + z];
+            }
+        }
+    }
+    Ok(([nx, ny, nz], transposed))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bake() {
+        let mut scene = Scene::default();
+        let sphere = scene.sphere(1.).unwrap();
+        let grid = DistanceGrid::bake(&scene, sphere, 16).unwrap();
+        assert_eq!([19, 19, 19], grid.dims());
+
+        // The samples are exact, and the points between them are close.
+        for p in [
+            Point3::new(0., 0., 0.),
+            Point3::new(1., 0., 0.),
+            Point3::new(0.3, -0.7, 0.2),
+            Point3::new(0.9, 0.9, 0.1),
+        ] {
+            let expected = p.coords.norm() - 1.;
+            assert!((grid.sdf(&p) - expected).abs() < 0.05, "{}", p);
+        }
+
+        // Outside of the grid the distance stays below the true distance.
+        let far = Point3::new(4., 0., 0.);
+        assert!(grid.sdf(&far) <= 3.);
+        assert!(grid.sdf(&far) > 2.);
+
+        let normal = grid.normal(&Point3::new(0., 1., 0.));
+        assert!((normal.y - 1.).abs() < 0.01, "{}", normal.into_inner());
+
+        let plane = scene.plane(Vector3::y_axis());
+        assert!(DistanceGrid::bake(&scene, plane, 16).is_err());
+        assert!(DistanceGrid::bake(&scene, sphere, 1).is_err());
+    }
+
+    /// The contents of a `.npy` file holding `values`, with the given shape and order.
+    fn npy(shape: [usize; 3], fortran: bool, values: &[f32]) -> Vec<u8> {
+        let header = format!(
+            "{{'descr': '<f4', 'fortran_order': {}, 'shape': ({}, {}, {}), }}",
+            if fortran { "True" } else { "False" },
+            shape[0],
+            shape[1],
+            shape[2]
+        );
+        npy_with_header(header, values)
+    }
+
+    /// The contents of a `.npy` file with the given header dictionary, holding `values`.
+    fn npy_with_header(mut header: String, values: &[f32]) -> Vec<u8> {
+        while !(10 + header.len() + 1).is_multiple_of(64) {
+            header.push(' ');
+        }
+        header.push('\n');
+
+        let mut bytes = b"\x93NUMPY\x01\x00".to_vec();
+        bytes.extend((header.len() as u16).to_le_bytes());
+        bytes.extend(header.as_bytes());
+        for value in values {
+            bytes.extend(value.to_le_bytes());
+        }
+        bytes
+    }
+
+    #[test]
+    fn test_read_npy() {
+        // A 2x3x4 array whose values are 100x + 10y + z.
+        let mut c_order = Vec::new();
+        for x in 0..2 {
+            for y in 0..3 {
+                for z in 0..4 {
+                    c_order.push((100 * x + 10 * y + z) as f32);
+                }
+            }
+        }
+        let (dims, values) = read_npy(&npy([2, 3, 4], false, &c_order)).unwrap();
+        assert_eq!([2, 3, 4], dims);
+        let grid = DistanceGrid::new(Point3::origin(), 1., dims, values).unwrap();
+        assert_eq!(123., grid.value([1, 2, 3]));
+        assert_eq!(12., grid.value([0, 1, 2]));
+
+        let f_order: Vec<f32> = (0..24).map(|i| i as f32).collect();
+        let (dims, values) = read_npy(&npy([2, 3, 4], true, &f_order)).unwrap();
+        assert_eq!([2, 3, 4], dims);
+        assert_eq!(f_order, values);
+
+        assert!(read_npy(&npy([2, 3, 4], false, &f_order[1..])).is_err());
+        assert!(read_npy(b"not an array").is_err());
+
+        // Malformed shapes and shapes too large to hold are errors rather than panics.
+        for shape in [")", "3, 3, 3)", "(3, 3, 3"] {
+            let header = format!(
+                "{{'descr': '<f4', 'fortran_order': False, 'shape': {}, }}",
+                shape
+            );
+            assert!(read_npy(&npy_with_header(header, &[])).is_err());
+        }
+        assert!(read_npy(&npy([usize::MAX / 2, 2, 2], false, &[])).is_err());
+        assert!(DistanceGrid::new(Point3::origin(), 1., [usize::MAX / 2, 2, 2], vec![]).is_err());
+        assert!(DistanceGrid::new(Point3::origin(), 1., [1, 2, 2], vec![0.; 4]).is_err());
+        assert!(DistanceGrid::new(Point3::origin(), 1., [2, 2, 2], vec![0.; 7]).is_err());
+    }
+}
@@ -0,0 +1,166 @@
+//! Terrain displaced from a plane by the brightness of a grayscale image. The heights are blended
+//! between neighbouring pixels, and the distance to the surface is scaled down by the steepest
+//! slope in the image, so that marching never steps through a ridge.
+
+use anyhow::{bail, Error};
+use nalgebra::{Point2, Point3, Vector2, Vector3};
+use std::path::Path;
+
+use crate::{bvh::BoundingBox, math::Float};
+
+type Result<T> = std::result::Result<T, Error>;
+
+/// Heights sampled on a grid in the xz plane. The samples cover `scale.x` along x and `scale.z`
+/// along z, centered on the origin, and the solid reaches from the plane up to `scale.y` where
+/// the samples are brightest.
+#[derive(Debug, Clone)]
+pub struct Heightfield {
+    scale: Vector3<Float>,
+    dims: [usize; 2],
+
+    /// The heights between `0` and `1`, with x varying fastest.
+    values: Vec<f32>,
+
+    /// How much faster the distance to the surface can change than the height above it.
+    stretch: Float,
+}
+
+impl Heightfield {
+    /// A heightfield with the samples in `values`, which vary fastest along x and lie between `0`
+    /// and `1`.
+    pub fn new(scale: Vector3<Float>, dims: [usize; 2], values: Vec<f32>) -> Result<Self> {
+        if dims.iter().any(|&dim| dim < 2) {
+            bail!("A heightfield needs at least two samples along each axis");
+        }
+        if values.len() != dims[0] * dims[1] {
+            bail!(
+                "A {}x{} heightfield needs {} samples, but there are {}",
+                dims[0],
+                dims[1],
+                dims[0] * dims[1],
+                values.len()
+            );
+        }
+        if scale.iter().any(|&s| s <= 0. || !s.is_finite()) {
+            bail!("A heightfield's scale must be positive");
+        }
+
+        // The steepest slope along each axis bounds the gradient of the blended heights.
+        let cell = Vector2::new(
+            scale.x / (dims[0] - 1) as Float,
+            scale.z / (dims[1] - 1) as Float,
+        );
+        let mut slope = Vector2::<Float>::zeros();
+        for j in 0..dims[1] {
+            for i in 0..dims[0] {
+                let h = values[i + dims[0] * j];
+                if i + 1 < dims[0] {
+                    let dh = (values[i + 1 + dims[0] * j] - h).abs() as Float;
+                    slope.x = slope.x.max(dh * scale.y / cell.x);
+                }
+                if j + 1 < dims[1] {
+                    let dh = (values[i + dims[0] * (j + 1)] - h).abs() as Float;
+                    slope.y = slope.y.max(dh * scale.y / cell.y);
+                }
+            }
+        }
+
+        Ok(Self {
+            scale,
+            dims,
+            values,
+            stretch: Float::sqrt(1. + slope.norm_squared()),
+        })
+    }
+
+    /// Load the heights from the brightness of the image at `path`, with its first row at the
+    /// back of the heightfield, towards negative z.
+    pub fn load(path: &Path, scale: Vector3<Float>) -> Result<Self> {
+        let image = image::open(path)
+            .map_err(|err| anyhow::anyhow!("Failed to load `{}`: {}", path.display(), err))?
+            .into_luma16();
+        let dims = [image.width() as usize, image.height() as usize];
+        let values = image
+            .into_raw()
+            .into_iter()
+            .map(|value| value as f32 / u16::MAX as f32)
+            .collect();
+        Self::new(scale, dims, values)
+    }
+
+    pub fn bounding_box(&self) -> BoundingBox {
+        let half = Vector2::new(self.scale.x, self.scale.z) / 2.;
+        BoundingBox::new(
+            Point3::new(-half.x, 0., -half.y),
+            Point3::new(half.x, self.scale.y, half.y),
+        )
+    }
+
+    /// The position of `p` across the heightfield, from `(0, 0)` at its first sample to `(1, 1)`
+    /// at its last.
+    pub fn uv(&self, p: &Point3<Float>) -> Point2<Float> {
+        Point2::new(p.x / self.scale.x + 0.5, p.z / self.scale.z + 0.5)
+    }
+
+    /// The height of the surface above the point of the plane under `p`, blended from the four
+    /// samples around it. Points past the edges take the height of the closest edge.
+    pub fn height(&self, p: &Point3<Float>) -> Float {
+        let uv = self.uv(p);
+        let g = Vector2::new(
+            uv.x.clamp(0., 1.) * (self.dims[0] - 1) as Float,
+            uv.y.clamp(0., 1.) * (self.dims[1] - 1) as Float,
+        );
+        let i = (g.x.floor() as usize).min(self.dims[0] - 2);
+        let j = (g.y.floor() as usize).min(self.dims[1] - 2);
+        let (tx, tz) = (g.x - i as Float, g.y - j as Float);
+
+        let value = |i: usize, j: usize| self.values[i + self.dims[0] * j] as Float;
+        let back = value(i, j) * (1. - tx) + value(i + 1, j) * tx;
+        let front = value(i, j + 1) * (1. - tx) + value(i + 1, j + 1) * tx;
+        (back * (1. - tz) + front * tz) * self.scale.y
+    }
+
+    /// A bound on the distance from `p` to the solid under the surface, which is cut off at the
+    /// plane and at the edges of the heightfield.
+    pub fn sdf(&self, p: &Point3<Float>) -> Float {
+        let surface = (p.y - self.height(p)) / self.stretch;
+
+        let half = Vector3::new(self.scale.x, self.scale.y, self.scale.z) / 2.;
+        let q = (p.coords - Vector3::new(0., half.y, 0.)).abs() - half;
+        let block = q.max().min(0.) + q.map(|c| c.max(0.)).norm();
+
+        surface.max(block)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_heightfield() {
+        // A ramp that climbs one unit across four, from the back to the front.
+        let field = Heightfield::new(
+            Vector3::new(4., 1., 4.),
+            [2, 3],
+            vec![0., 0., 0.5, 0.5, 1., 1.],
+        )
+        .unwrap();
+        assert_eq!(0., field.height(&Point3::new(0., 0., -2.)));
+        assert_eq!(0.25, field.height(&Point3::new(1., 0., -1.)));
+        assert_eq!(1., field.height(&Point3::new(0., 0., 5.)));
+
+        // Distances above the ramp are scaled by its slope, so they never overshoot.
+        let above = field.sdf(&Point3::new(0., 1., 0.));
+        assert!(
+            above > 0. && above <= 0.5 / Float::sqrt(1.0625) + 1e-6,
+            "{}",
+            above
+        );
+        assert!(field.sdf(&Point3::new(0., 0.25, 0.)) < 0.);
+        assert!(field.sdf(&Point3::new(3., 0.25, 0.)) > 0.);
+
+        assert!(Heightfield::new(Vector3::repeat(1.), [1, 2], vec![0.; 2]).is_err());
+        assert!(Heightfield::new(Vector3::new(1., 0., 1.), [2, 2], vec![0.; 4]).is_err());
+    }
+}
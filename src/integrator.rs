@@ -1,26 +1,47 @@
-use crossbeam::{channel, thread};
 use nalgebra::{Point2, Point3, Unit, Vector3};
+use rayon::{prelude::*, ThreadPool};
 use smallvec::SmallVec;
+use std::panic::AssertUnwindSafe;
+use std::sync::Mutex;
 
 use crate::{
     camera::{CanvasInfo, Sample},
-    canvas::{Canvas, Color},
+    canvas::{Canvas, Color, Guide},
+    math::Float,
     ray::Ray,
     sampler::Sampler,
-    scene::{Distance, MarchConfig, MaterialId, NodeId, Scene},
+    scene::{
+        Bias, Distance, MarchConfig, Material, MaterialId, NodeId, PatternId, Scene, SurfacePoint,
+    },
 };
 
+mod background;
+mod debug;
+mod occlusion;
+mod outline;
 mod whitted;
 
+pub use background::{Background, Mapping};
+pub use debug::{DebugBuilder, DebugMode};
+pub use occlusion::OcclusionCache;
+pub use outline::Outline;
 pub use whitted::WhittedBuilder;
 
+/// The width and height of the tiles that renders are split into, except where they're cut short
+/// by the edges of the image.
+pub const TILE_SIZE: u32 = 16;
+
+/// The most transparent surfaces that a shadow ray crosses before the light is taken to be
+/// blocked.
+const MAX_CROSSINGS: usize = 16;
+
 /// An individual tile in the rendering target.
 #[derive(Debug)]
-struct Tile {
-    offset_x: f32,
-    offset_y: f32,
-    width: u32,
-    height: u32,
+pub struct Tile {
+    pub offset_x: Float,
+    pub offset_y: Float,
+    pub width: u32,
+    pub height: u32,
 }
 
 /// An iterator for tiles in a rendering target.
@@ -36,8 +57,8 @@ struct Tiles {
 
 impl Tiles {
     fn new(width: u32, height: u32) -> Self {
-        let chunks_x = (width + 15) / 16;
-        let chunks_y = (height + 15) / 16;
+        let chunks_x = width.div_ceil(TILE_SIZE);
+        let chunks_y = height.div_ceil(TILE_SIZE);
 
         Self {
             width,
@@ -48,10 +69,6 @@ impl Tiles {
             y: 0,
         }
     }
-
-    fn total(&self) -> u32 {
-        self.chunks_x * self.chunks_y
-    }
 }
 
 impl Iterator for Tiles {
@@ -67,112 +84,462 @@ impl Iterator for Tiles {
             return None;
         }
 
-        let offset_x = self.x * 16;
-        let offset_y = self.y * 16;
-        let width = (self.width - offset_x).min(16);
-        let height = (self.height - offset_y).min(16);
+        let offset_x = self.x * TILE_SIZE;
+        let offset_y = self.y * TILE_SIZE;
+        let width = (self.width - offset_x).min(TILE_SIZE);
+        let height = (self.height - offset_y).min(TILE_SIZE);
 
         self.x += 1;
 
         Some(Tile {
-            offset_x: offset_x as f32,
-            offset_y: offset_y as f32,
+            offset_x: offset_x as Float,
+            offset_y: offset_y as Float,
             width,
             height,
         })
     }
 }
 
+/// The number of tiles that a render of `info` is split into.
+pub fn tile_count(info: &CanvasInfo) -> u64 {
+    let tiles = Tiles::new(info.width, info.height);
+    tiles.chunks_x as u64 * tiles.chunks_y as u64
+}
+
+/// Renders tiles somewhere other than the local thread pool, such as on another machine.
+pub trait TileWorker: Send {
+    /// Render `tile`, returning `None` if the worker has failed. The tile is then rendered locally
+    /// instead, and the worker isn't given any more tiles.
+    fn render_tile(&mut self, tile: &Tile) -> Option<Canvas>;
+}
+
+/// Where the tiles of a render are run: the threads of a local pool, and any remote workers.
+pub struct Workers<'a> {
+    pub pool: &'a ThreadPool,
+    pub remote: Vec<Box<dyn TileWorker + 'a>>,
+}
+
+impl<'a> Workers<'a> {
+    /// Render only on the threads of `pool`.
+    pub fn local(pool: &'a ThreadPool) -> Self {
+        Self {
+            pool,
+            remote: Vec::new(),
+        }
+    }
+}
+
+/// A tile that panicked while it was rendered.
+#[derive(Debug, Clone)]
+pub struct TileError {
+    pub x: u32,
+    pub y: u32,
+    pub message: String,
+}
+
+impl std::fmt::Display for TileError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "the tile at ({}, {}) panicked: {}",
+            self.x, self.y, self.message
+        )
+    }
+}
+
+impl std::error::Error for TileError {}
+
+/// Why a render failed.
+#[derive(Debug, Clone)]
+pub enum RenderError {
+    /// The nodes under the root can't be marched, as [`Scene::check_graph`] found, so nothing was
+    /// rendered.
+    Graph(String),
+
+    /// The tiles of the render that failed. The others are still rendered, and the failed ones
+    /// are handed to `on_tile` filled with magenta, so that they stand out in progress views.
+    Tiles(Vec<TileError>),
+}
+
+impl std::fmt::Display for RenderError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RenderError::Graph(message) => write!(f, "the scene can't be marched: {}", message),
+            RenderError::Tiles(tiles) => {
+                write!(f, "{} of the tiles failed", tiles.len())?;
+                if let Some(first) = tiles.first() {
+                    write!(f, ", and {}", first)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl std::error::Error for RenderError {}
+
+/// Render the scene on `workers`. Tiles are pulled from a shared queue, so workers that finish
+/// cheap tiles early will pick up work from those rendering expensive parts of the image.
+/// `on_tile` is called from the rendering threads with each tile as it's completed. Tiles that
+/// start after the scene has been [aborted](Scene::aborted) are skipped.
+///
+/// The graph under `root` is [checked](Scene::check_graph) first. Marching graphs deeper than the
+/// default [`max_depth`](Scene::max_depth) needs threads with a stack of
+/// [`STACK_SIZE`](crate::scene::STACK_SIZE).
 pub fn render(
     info: CanvasInfo,
     scene: &Scene,
     root: NodeId,
     sampler: impl Sampler,
     builder: impl IntegratorBuilder,
-    num_threads: usize,
-) -> Canvas {
-    let mut canvas = info.new_canvas();
+    workers: Workers,
+    on_tile: &(dyn Fn(&Canvas) + Sync),
+) -> Result<Canvas, RenderError> {
+    let canvas = Mutex::new(info.new_canvas());
+    render_tiles(
+        info,
+        scene,
+        root,
+        sampler,
+        builder,
+        workers,
+        &|tile, chunk| {
+            on_tile(chunk);
+            let mut canvas = canvas.lock().unwrap();
+            canvas.blit(tile.offset_x as u32, tile.offset_y as u32, chunk);
+        },
+    )?;
+    Ok(canvas.into_inner().unwrap())
+}
 
-    let (input, tiles): (_, channel::Receiver<Tile>) = channel::unbounded();
-    let (results, chunks) = channel::unbounded();
-
-    thread::scope(|s| {
-        for _ in 0..num_threads {
-            let mut sampler = sampler.clone_sampler();
-            let results = results.clone();
-            let mut integrator = builder.build();
-            let tiles = tiles.clone();
-            s.spawn(move |_| {
-                let mut samples = Vec::with_capacity(sampler.samples_per_pixel());
-                let inv_num_samples = 1. / (sampler.samples_per_pixel() as f32);
-                for tile in tiles.clone() {
-                    let mut chunk = Canvas::new(tile.width, tile.height);
-
-                    for ((col, row), pixel) in chunk.coords().zip(chunk.pixels_mut()) {
-                        samples.clear();
-                        sampler.pixel_samples(
-                            &mut samples,
-                            &Point2::new(col as f32 + tile.offset_x, row as f32 + tile.offset_y),
-                        );
-                        for sample in &samples {
-                            let sample = Sample::new(sample.x, sample.y);
-                            *pixel += integrator.luminance(scene, root, &sample);
-                        }
-
-                        *pixel *= inv_num_samples;
+/// Render the scene on `workers` as [`render`] does, but hand each tile to `on_tile` without
+/// keeping it, so that the whole image is never held in memory.
+pub fn render_tiles(
+    info: CanvasInfo,
+    scene: &Scene,
+    root: NodeId,
+    sampler: impl Sampler,
+    builder: impl IntegratorBuilder,
+    workers: Workers,
+    on_tile: &(dyn Fn(&Tile, &Canvas) + Sync),
+) -> Result<(), RenderError> {
+    scene
+        .check_graph(root)
+        .map_err(|err| RenderError::Graph(err.to_string()))?;
+
+    let (send, queue) = crossbeam::channel::unbounded();
+    for tile in Tiles::new(info.width, info.height) {
+        send.send(tile).unwrap();
+    }
+    drop(send);
+
+    // Tiles are rendered on other threads, so their spans are attached to the caller's by hand.
+    let span = tracing::Span::current();
+
+    // Tiles that panicked, which are reported once the rest of the image is done.
+    let errors = Mutex::new(Vec::new());
+
+    let render_local = |tiles: Box<dyn Iterator<Item = Tile> + Send + '_>| {
+        workers.pool.install(|| {
+            tiles.par_bridge().for_each_init(
+                || (sampler.clone_sampler(), builder.build(), Vec::new()),
+                |(sampler, integrator, samples), tile| {
+                    let _span = tile_span(&span, &tile, "local").entered();
+                    let chunk = try_render_tile(scene, root, sampler, integrator, samples, &tile)
+                        .unwrap_or_else(|err| {
+                            tracing::error!("{}", err);
+                            errors.lock().unwrap().push(err);
+                            error_tile(&tile)
+                        });
+                    on_tile(&tile, &chunk);
+                },
+            )
+        })
+    };
+
+    // Tiles that a remote worker failed to render.
+    let failed = Mutex::new(Vec::new());
+
+    std::thread::scope(|s| {
+        for mut worker in workers.remote {
+            let queue = &queue;
+            let failed = &failed;
+            let span = &span;
+            s.spawn(move || {
+                for tile in queue.iter() {
+                    if scene.aborted() {
+                        continue;
                     }
 
-                    results
-                        .send((tile.offset_x as u32, tile.offset_y as u32, chunk))
-                        .unwrap();
+                    let _span = tile_span(span, &tile, "remote").entered();
+                    let Some(chunk) = worker.render_tile(&tile) else {
+                        failed.lock().unwrap().push(tile);
+                        break;
+                    };
+                    on_tile(&tile, &chunk);
                 }
             });
         }
 
-        let tiles = Tiles::new(info.width, info.height);
-        let expecting = tiles.total() as usize;
+        // The local threads pull from the same queue as the remote workers.
+        render_local(Box::new(queue.iter()));
+    });
 
-        s.spawn(move |_| {
-            for tile in tiles {
-                input.send(tile).unwrap();
-            }
-        });
+    render_local(Box::new(failed.into_inner().unwrap().into_iter()));
 
-        for (offset_x, offset_y, chunk) in chunks.into_iter().take(expecting) {
-            canvas.blit(offset_x, offset_y, &chunk)
-        }
-    })
-    .unwrap();
+    let mut tiles = errors.into_inner().unwrap();
+    if tiles.is_empty() {
+        return Ok(());
+    }
+    tiles.sort_by_key(|err| (err.y, err.x));
+    Err(RenderError::Tiles(tiles))
+}
+
+fn tile_span(parent: &tracing::Span, tile: &Tile, worker: &str) -> tracing::Span {
+    tracing::trace_span!(parent: parent, "tile", x = tile.offset_x, y = tile.offset_y, worker)
+}
 
+/// Render the scene one tile at a time on the calling thread, for targets without threads such as
+/// the browser. `on_tile` is called with each tile as it's completed.
+#[cfg(any(feature = "wasm", test))]
+pub fn render_serial(
+    info: CanvasInfo,
+    scene: &Scene,
+    root: NodeId,
+    mut sampler: impl Sampler,
+    builder: impl IntegratorBuilder,
+    on_tile: &mut dyn FnMut(&Tile, &Canvas),
+) -> Canvas {
+    let mut canvas = info.new_canvas();
+    let mut integrator = builder.build();
+    let mut samples = Vec::new();
+    for tile in Tiles::new(info.width, info.height) {
+        let _span = tile_span(&tracing::Span::current(), &tile, "serial").entered();
+        let chunk = render_tile(
+            scene,
+            root,
+            &mut sampler,
+            integrator.as_mut(),
+            &mut samples,
+            &tile,
+        );
+        on_tile(&tile, &chunk);
+        canvas.blit(tile.offset_x as u32, tile.offset_y as u32, &chunk);
+    }
     canvas
 }
 
-pub trait IntegratorBuilder {
+/// Render a single tile as [`render_tile`] does, catching a panic while rendering it so that one
+/// bad tile doesn't take the rest of the render down with it.
+pub fn try_render_tile(
+    scene: &Scene,
+    root: NodeId,
+    sampler: &mut dyn Sampler,
+    integrator: &mut dyn Integrator,
+    samples: &mut Vec<Point2<Float>>,
+    tile: &Tile,
+) -> Result<Canvas, TileError> {
+    std::panic::catch_unwind(AssertUnwindSafe(|| {
+        render_tile(scene, root, sampler, integrator, samples, tile)
+    }))
+    .map_err(|panic| TileError {
+        x: tile.offset_x as u32,
+        y: tile.offset_y as u32,
+        message: panic_message(panic.as_ref()),
+    })
+}
+
+/// The message that a panic was raised with.
+pub fn panic_message(panic: &(dyn std::any::Any + Send)) -> String {
+    if let Some(message) = panic.downcast_ref::<&str>() {
+        String::from(*message)
+    } else if let Some(message) = panic.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        String::from("unknown error")
+    }
+}
+
+/// A tile filled with magenta, which stands in for one that failed to render.
+fn error_tile(tile: &Tile) -> Canvas {
+    let mut chunk = Canvas::new(tile.width, tile.height);
+    for pixel in chunk.pixels_mut() {
+        *pixel = Color::magenta();
+    }
+    chunk
+}
+
+/// Render a single tile. The tile is left black if the scene has been [aborted](Scene::aborted).
+pub fn render_tile(
+    scene: &Scene,
+    root: NodeId,
+    sampler: &mut dyn Sampler,
+    integrator: &mut dyn Integrator,
+    samples: &mut Vec<Point2<Float>>,
+    tile: &Tile,
+) -> Canvas {
+    let inv_num_samples = 1. / (sampler.samples_per_pixel() as f32);
+    let mut chunk = Canvas::new(tile.width, tile.height);
+
+    if scene.aborted() {
+        return chunk;
+    }
+
+    let coords = chunk.coords();
+    let (pixels, alpha) = chunk.pixels_and_alpha_mut();
+    for (((col, row), pixel), alpha) in coords.zip(pixels).zip(alpha) {
+        samples.clear();
+        sampler.pixel_samples(
+            samples,
+            &Point2::new(col as Float + tile.offset_x, row as Float + tile.offset_y),
+        );
+        let mut coverage = 0.;
+        for sample in samples.iter() {
+            let sample = Sample::new(sample.x, sample.y);
+            let radiance = integrator.luminance(scene, root, &sample);
+            *pixel += radiance.color;
+            coverage += radiance.alpha;
+        }
+
+        *pixel *= inv_num_samples;
+        *alpha = coverage * inv_num_samples;
+    }
+
+    chunk
+}
+
+/// The [`Guide`] for each pixel of a render, seen through its center.
+pub fn guides(
+    info: &CanvasInfo,
+    scene: &Scene,
+    root: NodeId,
+    builder: impl IntegratorBuilder,
+    pool: &ThreadPool,
+) -> Vec<Guide> {
+    let width = info.width as usize;
+    pool.install(|| {
+        (0..width * info.height as usize)
+            .into_par_iter()
+            .map_init(
+                || builder.build(),
+                |integrator, i| {
+                    let sample =
+                        Sample::new((i % width) as Float + 0.5, (i / width) as Float + 0.5);
+                    integrator.guide(scene, root, &sample)
+                },
+            )
+            .collect()
+    })
+}
+
+/// Makes the integrators for a render. A render builds one integrator for each thread, so that
+/// integrators may keep state between samples without locking.
+pub trait IntegratorBuilder: Send + Sync {
+    /// A new integrator, configured as this builder was.
     fn build(&self) -> Box<dyn Integrator>;
+
+    /// The parameters needed to run this integrator with the gpu backend, when it's supported.
+    #[cfg(feature = "gpu")]
+    fn gpu_settings(&self) -> Option<crate::gpu::Settings> {
+        None
+    }
 }
 
 impl<C: IntegratorBuilder + ?Sized> IntegratorBuilder for Box<C> {
     fn build(&self) -> Box<dyn Integrator> {
         self.as_ref().build()
     }
+
+    #[cfg(feature = "gpu")]
+    fn gpu_settings(&self) -> Option<crate::gpu::Settings> {
+        self.as_ref().gpu_settings()
+    }
 }
 
+impl<C: IntegratorBuilder + ?Sized> IntegratorBuilder for &C {
+    fn build(&self) -> Box<dyn Integrator> {
+        (*self).build()
+    }
+
+    #[cfg(feature = "gpu")]
+    fn gpu_settings(&self) -> Option<crate::gpu::Settings> {
+        (*self).gpu_settings()
+    }
+}
+
+/// Finds the light that reaches the camera, one sample at a time. [`Hit::march`] finds the
+/// surfaces that rays meet, and [`Scene`] gives their materials and the lights.
 pub trait Integrator: Send {
-    fn luminance(&mut self, scene: &Scene, root: NodeId, sample: &Sample) -> Color;
+    /// The light arriving through `sample`, from the scene below `root`.
+    fn luminance(&mut self, scene: &Scene, root: NodeId, sample: &Sample) -> Radiance;
+
+    /// The surface seen through a sample, which guides denoising. Integrators that can't tell leave
+    /// the guide empty, so that only the colors of pixels are compared.
+    fn guide(&mut self, _scene: &Scene, _root: NodeId, _sample: &Sample) -> Guide {
+        Guide::default()
+    }
+}
+
+/// The light arriving at the camera through a sample.
+pub struct Radiance {
+    /// The light from the scene, premultiplied by `alpha`.
+    pub color: Color,
+
+    /// How much of the sample is covered by the scene, rather than a transparent background.
+    pub alpha: f32,
+}
+
+impl Radiance {
+    /// Light that fully covers the sample.
+    pub fn opaque(color: Color) -> Self {
+        Self { color, alpha: 1. }
+    }
 }
 
 impl<C> Integrator for Box<C>
 where
     C: Integrator + ?Sized,
 {
-    fn luminance(&mut self, scene: &Scene, root: NodeId, sample: &Sample) -> Color {
+    fn luminance(&mut self, scene: &Scene, root: NodeId, sample: &Sample) -> Radiance {
         self.as_mut().luminance(scene, root, sample)
     }
+
+    fn guide(&mut self, scene: &Scene, root: NodeId, sample: &Sample) -> Guide {
+        self.as_mut().guide(scene, root, sample)
+    }
+}
+
+/// A transparent object that a ray can travel through.
+#[derive(Clone, Debug)]
+pub struct Medium {
+    pub node: NodeId,
+    pub refractive_index: Float,
+
+    /// The spread of the refractive index between blue and red light.
+    pub dispersion: Float,
+
+    /// How much of each channel is absorbed per unit of distance traveled.
+    pub absorption: Color,
+
+    /// When media overlap, the one with the highest priority is the one the ray travels through.
+    pub priority: u32,
+}
+
+impl Medium {
+    /// The refractive index for light in one color channel, or for white light when `band` is
+    /// `None`. Red bends the least and blue the most, either side of green.
+    pub fn index_for(&self, band: Option<usize>) -> Float {
+        match band {
+            Some(0) => self.refractive_index - self.dispersion / 2.,
+            Some(2) => self.refractive_index + self.dispersion / 2.,
+            _ => self.refractive_index,
+        }
+    }
 }
 
 /// A record of transparent objects that a ray is traversing.
 #[derive(Clone, Debug, Default)]
-pub struct Containers(SmallVec<[(NodeId, f32); 4]>);
+pub struct Containers(SmallVec<[Medium; 4]>);
 
 impl Containers {
     fn is_empty(&self) -> bool {
@@ -180,28 +547,54 @@ impl Containers {
     }
 
     fn contains(&self, node: NodeId) -> bool {
-        self.0.iter().any(|(n, _)| *n == node)
+        self.0.iter().any(|medium| medium.node == node)
     }
 
-    /// For an intersection with object `node` with `refractive_index`, return the indices of
-    /// refraction on either side of the intersection.
-    fn refractive_indices(&mut self, node: NodeId, refractive_index: f32) -> (f32, f32) {
-        let n1 = self.0.last().map(|(_, ri)| *ri).unwrap_or(1.0);
+    /// The medium the ray is currently traveling through: the one with the highest priority, or
+    /// the most recently entered when priorities are equal.
+    fn current(&self) -> Option<&Medium> {
+        self.0.iter().max_by_key(|medium| medium.priority)
+    }
 
-        // Determine if we're entering or leaving `node`
-        if let Some(idx) = self
-            .0
+    /// True when crossing the boundary of `node` doesn't change the medium, because the ray is
+    /// inside another medium with a higher priority. Such boundaries are invisible.
+    fn is_false_boundary(&self, node: NodeId, priority: u32) -> bool {
+        self.0
             .iter()
-            .enumerate()
-            .find(|(_, (n, _))| *n == node)
-            .map(|(idx, _)| idx)
-        {
+            .any(|medium| medium.node != node && medium.priority > priority)
+    }
+
+    /// True when the ray is inside a medium that disperses light.
+    fn is_dispersive(&self) -> bool {
+        self.current().is_some_and(|medium| medium.dispersion > 0.)
+    }
+
+    /// The fraction of light that survives traveling `distance` through the current medium.
+    fn transmittance(&self, distance: Float) -> Color {
+        let distance = distance as f32;
+        match self.current() {
+            Some(Medium { absorption, .. }) if !absorption.is_black() => Color::new(
+                f32::exp(-absorption.r * distance),
+                f32::exp(-absorption.g * distance),
+                f32::exp(-absorption.b * distance),
+            ),
+            _ => Color::white(),
+        }
+    }
+
+    /// For an intersection with the boundary of `medium`, return the indices of refraction on
+    /// either side of the intersection, for light in `band`.
+    fn refractive_indices(&mut self, medium: Medium, band: Option<usize>) -> (Float, Float) {
+        let n1 = self.current().map_or(1.0, |m| m.index_for(band));
+
+        // Determine if we're entering or leaving `node`
+        if let Some(idx) = self.0.iter().position(|m| m.node == medium.node) {
             self.0.remove(idx);
         } else {
-            self.0.push((node, refractive_index));
+            self.0.push(medium);
         }
 
-        let n2 = self.0.last().map(|(_, ri)| *ri).unwrap_or(1.0);
+        let n2 = self.current().map_or(1.0, |m| m.index_for(band));
         (n1, n2)
     }
 }
@@ -212,14 +605,29 @@ pub struct Hit {
     pub node: NodeId,
 
     /// The intersection point in object space.
-    pub object: Point3<f32>,
+    pub object: Point3<Float>,
+
+    /// The texture coordinates of the surface at the intersection.
+    pub uv: Point2<Float>,
+
+    /// The normal to shade the hit with, in world space.
+    pub normal: Unit<Vector3<Float>>,
 
-    /// The normal of the object at the hit, in world space.
-    pub normal: Unit<Vector3<f32>>,
+    /// The normal of the surface itself, in world space, which the shading normal may lean away
+    /// from. Rays that leave the surface start from just off of it along this normal, as the
+    /// shading normal can point back into the object.
+    pub geometric: Unit<Vector3<Float>>,
+
+    /// The geometric normal in object space.
+    pub object_normal: Unit<Vector3<Float>>,
 
     /// The material for the object.
     pub material: Option<MaterialId>,
 
+    /// The nodes named by light links that the object was reached through, as in
+    /// [`crate::scene::SDFResult::links`].
+    pub links: u64,
+
     /// The ray that caused the intersection.
     pub ray: Ray,
 
@@ -228,6 +636,9 @@ pub struct Hit {
 
     /// The number of steps taken.
     pub steps: u32,
+
+    /// Whether the hit is on the far side of a surface, seen from inside the object.
+    pub inside: bool,
 }
 
 impl Hit {
@@ -239,31 +650,44 @@ impl Hit {
         mut ray: Ray,
         inside: bool,
     ) -> Option<Self> {
-        let mut total_dist = Distance::default();
+        let (mut total_dist, max_dist) =
+            Self::march_bounds(config, scene, root, &mut ray, config.max_dist)?;
 
         let node = scene.node(root);
 
-        let sign = if inside { -1.0 } else { 1.0 };
-
         for i in 0..config.max_steps {
-            let result = node.sdf(scene, root, &ray);
-            let radius = result.distance.0 * sign;
-
-            if radius < config.min_dist {
+            // Inside a transparent object the ray may cross the boundary of any object, in either
+            // direction.
+            let (result, radius) = if inside {
+                let result = node.boundary_sdf(scene, root, &ray);
+                let radius = result.distance.0.abs();
+                (result, radius)
+            } else {
+                let result = node.sdf(scene, root, &ray);
+                let radius = result.distance.0;
+                (result, radius)
+            };
+
+            if radius < config.hit_distance(total_dist.0) {
                 return Some(Self {
                     node: result.id,
                     object: result.object,
-                    normal: result.normal,
+                    uv: result.uv(scene),
+                    normal: result.shading.unwrap_or(result.normal),
+                    geometric: result.normal,
+                    object_normal: result.object_normal,
                     material: result.material,
+                    links: result.links,
                     ray,
                     distance: total_dist,
                     steps: i,
+                    inside: false,
                 });
             }
 
             total_dist.0 += radius;
 
-            if total_dist.0 > config.max_dist {
+            if total_dist.0 > max_dist {
                 break;
             }
 
@@ -273,28 +697,61 @@ impl Hit {
         None
     }
 
-    /// March the ray until it hits something, but return only the distance.
+    /// Clip the march of `ray` to the bounding box of `root`, as nothing can be hit outside of it.
+    /// The ray is moved up to where it enters the box, and the distance it was moved is returned
+    /// along with the distance at which to give up, which is at most `max_dist`. Rays that miss
+    /// the box within that distance return `None`.
+    fn march_bounds(
+        config: &MarchConfig,
+        scene: &Scene,
+        root: NodeId,
+        ray: &mut Ray,
+        max_dist: Float,
+    ) -> Option<(Distance, Float)> {
+        let (entry, exit) = scene.bounding_box(root).ray_interval(ray)?;
+
+        // Surfaces are hit from up to `min_dist` away, so leave that much room on either side.
+        let exit = exit + config.min_dist;
+        if exit < 0. {
+            return None;
+        }
+
+        let entry = (entry - config.min_dist).max(0.);
+        if entry > max_dist {
+            return None;
+        }
+
+        ray.step(entry);
+        Some((Distance(entry), exit.min(max_dist)))
+    }
+
+    /// March the ray until it hits something, but return only the distance. Marching stops
+    /// without a hit after `max_dist`, such as the distance to a light that the ray checks the
+    /// view of.
     pub fn march_dist(
         config: &MarchConfig,
         scene: &Scene,
         root: NodeId,
         mut ray: Ray,
+        max_dist: Float,
     ) -> Option<Distance> {
-        let mut total_dist = Distance::default();
+        let (mut total_dist, max_dist) =
+            Self::march_bounds(config, scene, root, &mut ray, max_dist.min(config.max_dist))?;
 
         let node = scene.node(root);
 
         for _ in 0..config.max_steps {
-            let result = node.fast_sdf(scene, &ray);
+            let min_dist = config.hit_distance(total_dist.0);
+            let result = node.fast_sdf(scene, &ray, min_dist);
             let radius = result.distance.0;
 
-            if radius < config.min_dist {
+            if radius < min_dist {
                 return Some(total_dist);
             }
 
             total_dist.0 += radius;
 
-            if total_dist.0 > config.max_dist {
+            if total_dist.0 > max_dist {
                 break;
             }
 
@@ -304,29 +761,353 @@ impl Hit {
         None
     }
 
-    /// Returns `true` when there is an object between the hit and the light at the point provided.
-    pub fn in_shadow(
+    /// The color of `pattern` at the hit, which is on the surface of everything under `root`.
+    pub fn pattern_color(&self, scene: &Scene, root: NodeId, pattern: PatternId) -> Color {
+        let node = scene.node(root);
+        let sdf = |point: &Point3<Float>| {
+            let ray = Ray::new(*point, self.ray.direction);
+            node.fast_sdf(scene, &ray, Float::NEG_INFINITY).distance.0
+        };
+        scene.pattern(pattern).color_at(
+            scene,
+            &SurfacePoint {
+                object: self.object,
+                world: self.ray.position,
+                uv: self.uv,
+                normal: self.normal,
+                object_normal: self.object_normal,
+                eye: -self.ray.direction,
+                sdf: &sdf,
+            },
+        )
+    }
+
+    /// How thick the object is behind the hit, measured along the inward normal to the far side of
+    /// everything under `root`, or `None` when it's thicker than `max`. Rather than marching, the
+    /// distance is sampled at `samples` points inward. A point is as deep as it is far from the
+    /// surface until it's past the middle of the object, after which it's nearer to the far side,
+    /// and its depth and distance from the hit add up to the thickness.
+    pub fn thickness(
+        &self,
+        scene: &Scene,
+        root: NodeId,
+        max: Float,
+        samples: u32,
+    ) -> Option<Float> {
+        let node = scene.node(root);
+        (1..=samples).find_map(|i| {
+            let dist = max * i as Float / samples as Float;
+            let point = self.ray.position - self.geometric.scale(dist);
+            let ray = Ray::new(point, self.ray.direction);
+            let depth = -node.fast_sdf(scene, &ray, Float::NEG_INFINITY).distance.0;
+
+            // Distances are only bounds for some nodes, so a point must be well past the middle
+            // before it counts.
+            (depth < dist * 0.5).then(|| (dist + depth).max(0.))
+        })
+    }
+
+    /// How far rays leaving the hit start from it, as set by the material of its surface.
+    pub fn bias(&self, scene: &Scene) -> Bias {
+        self.material
+            .map_or(Bias::default(), |material| scene.material(material).bias())
+    }
+
+    /// The distance along the normal that rays leaving the hit start from. Hits on the far side of
+    /// a surface are found by marching to the boundary from inside, which may stop on either side
+    /// of it, so rays leave them from twice as far to be sure that they don't find it again.
+    pub fn normal_offset(&self, config: &MarchConfig, scene: &Scene) -> Float {
+        let offset = self.bias(scene).normal.unwrap_or(config.min_dist);
+        if self.inside {
+            offset * 2.0
+        } else {
+            offset
+        }
+    }
+
+    /// How much of each channel of the light at the point provided reaches the hit, from black
+    /// when it's in shadow to white when nothing is in the way.
+    pub fn light_visibility(
+        &self,
+        config: &MarchConfig,
+        scene: &Scene,
+        root: NodeId,
+        light: &Point3<Float>,
+    ) -> Color {
+        self.light_visibility_from(config, scene, root, &self.ray.position, light)
+    }
+
+    /// As [`Hit::light_visibility`], but from `point` on the surface near the hit, which is
+    /// shaded as the hit is.
+    pub fn light_visibility_from(
         &self,
         config: &MarchConfig,
         scene: &Scene,
         root: NodeId,
-        light: &Point3<f32>,
-    ) -> bool {
-        // Move the point away from the hit by min_dist so that we ensure that there won't be an
-        // immediate intersection with the object.
-        let start = &self.ray.position + config.min_dist * self.normal.as_ref();
+        point: &Point3<Float>,
+        light: &Point3<Float>,
+    ) -> Color {
+        // Move the point away from the hit so that we ensure that there won't be an immediate
+        // intersection with the object.
+        let start = point + self.normal_offset(config, scene) * self.geometric.as_ref();
 
         let dir = light - start;
-        let dist_to_light = dir.norm();
-        let ray = Ray::new(start, Unit::new_normalize(dir));
-        Hit::march_dist(config, scene, root, ray)
-            .map_or(false, |hit_dist| hit_dist.0 < dist_to_light)
+        let bias = self.bias(scene).shadow;
+        let dist_to_light = dir.norm() - bias;
+        if dist_to_light <= 0. {
+            return Color::white();
+        }
+        let mut ray = Ray::new(start, Unit::new_normalize(dir));
+        ray.step(bias);
+
+        // From inside an object, the light is hidden by any surface that the ray crosses.
+        if self.inside {
+            let blocked = Hit::march(config, scene, root, ray, true)
+                .is_some_and(|hit| hit.distance.0 < dist_to_light);
+            return if blocked {
+                Color::black()
+            } else {
+                Color::white()
+            };
+        }
+
+        Self::visibility(config, scene, root, ray, dist_to_light)
+    }
+
+    /// How much of each channel of light travels `max_dist` along `ray`. Transparent surfaces let
+    /// through as much of the light as they're transparent each time that it crosses them, and
+    /// their media absorb it as they do rays from the camera, though it isn't bent as it would be
+    /// by refraction. Any other surface blocks it.
+    pub fn visibility(
+        config: &MarchConfig,
+        scene: &Scene,
+        root: NodeId,
+        mut ray: Ray,
+        max_dist: Float,
+    ) -> Color {
+        // Most shadow rays reach no surface at all, which is quicker to find without materials.
+        if Self::march_dist(config, scene, root, ray.clone(), max_dist)
+            .is_none_or(|hit| hit.0 >= max_dist)
+        {
+            return Color::white();
+        }
+
+        let mut visibility = Color::white();
+        let mut containers = Containers::default();
+        let mut remaining = max_dist;
+        for _ in 0..MAX_CROSSINGS {
+            let Some(hit) = Self::march(config, scene, root, ray, true)
+                .filter(|hit| hit.distance.0 < remaining)
+            else {
+                return visibility * containers.transmittance(remaining);
+            };
+            visibility *= &containers.transmittance(hit.distance.0);
+
+            match hit.material.map(|material| scene.material(material)) {
+                Some(&Material::Phong {
+                    transparent,
+                    refractive_index,
+                    dispersion,
+                    ref absorption,
+                    priority,
+                    ..
+                }) if transparent > 0. => {
+                    // As with rays from the camera, the boundary of a medium inside another with
+                    // a higher priority is invisible.
+                    if !containers.is_false_boundary(hit.node, priority) {
+                        visibility *= transparent;
+                    }
+                    containers.refractive_indices(
+                        Medium {
+                            node: hit.node,
+                            refractive_index,
+                            dispersion,
+                            absorption: absorption.clone(),
+                            priority,
+                        },
+                        None,
+                    );
+                }
+                _ => return Color::black(),
+            }
+
+            if visibility.is_black() {
+                return visibility;
+            }
+
+            remaining -= hit.distance.0 + config.min_dist * 2.;
+            ray = hit.ray;
+            ray.step(config.min_dist * 2.);
+        }
+
+        Color::black()
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::sampler::UniformSampler;
+    use crate::transform::Transform;
+
+    struct White;
+
+    impl Integrator for White {
+        fn luminance(&mut self, _scene: &Scene, _root: NodeId, _sample: &Sample) -> Radiance {
+            Radiance::opaque(Color::white())
+        }
+    }
+
+    impl IntegratorBuilder for White {
+        fn build(&self) -> Box<dyn Integrator> {
+            Box::new(White)
+        }
+    }
+
+    /// Panics on the pixels to the right of `x`.
+    #[derive(Clone)]
+    struct PanicsAfter {
+        x: Float,
+    }
+
+    impl Integrator for PanicsAfter {
+        fn luminance(&mut self, _scene: &Scene, _root: NodeId, sample: &Sample) -> Radiance {
+            assert!(sample.film.x < self.x, "the integrator hit a bad pixel");
+            Radiance::opaque(Color::white())
+        }
+    }
+
+    impl IntegratorBuilder for PanicsAfter {
+        fn build(&self) -> Box<dyn Integrator> {
+            Box::new(self.clone())
+        }
+    }
+
+    /// A remote worker that renders its tiles magenta, and fails once it's rendered `tiles`.
+    struct Magenta {
+        tiles: usize,
+    }
+
+    impl TileWorker for Magenta {
+        fn render_tile(&mut self, tile: &Tile) -> Option<Canvas> {
+            self.tiles = self.tiles.checked_sub(1)?;
+            let mut chunk = Canvas::new(tile.width, tile.height);
+            for pixel in chunk.pixels_mut() {
+                *pixel = Color::magenta();
+            }
+            Some(chunk)
+        }
+    }
+
+    #[test]
+    fn test_remote_workers() {
+        let mut scene = Scene::default();
+        let root = scene.sphere(1.).unwrap();
+        let pool = rayon::ThreadPoolBuilder::new().build().unwrap();
+
+        let workers = Workers {
+            pool: &pool,
+            remote: vec![
+                Box::new(Magenta { tiles: 0 }),
+                Box::new(Magenta { tiles: 2 }),
+            ],
+        };
+        let info = CanvasInfo::new(40, 40);
+        let canvas = render(
+            info,
+            &scene,
+            root,
+            UniformSampler::new(1, 1),
+            White,
+            workers,
+            &|_| (),
+        )
+        .unwrap();
+
+        // The tile that the first worker failed on is rendered locally, rather than left black.
+        let data = canvas.data();
+        let pixels: Vec<_> = data.chunks(3).collect();
+        assert!(pixels
+            .iter()
+            .all(|pixel| *pixel == [255, 255, 255] || *pixel == [255, 0, 255]));
+        let magenta = pixels
+            .iter()
+            .filter(|pixel| **pixel == [255, 0, 255])
+            .count();
+        assert!(magenta <= 2 * 16 * 16);
+
+        let canvas = render(
+            CanvasInfo::new(40, 40),
+            &scene,
+            root,
+            UniformSampler::new(1, 1),
+            White,
+            Workers::local(&pool),
+            &|_| (),
+        )
+        .unwrap();
+        assert!(canvas.data().iter().all(|channel| *channel == 255));
+
+        // Rendering serially covers the same tiles, and reports each of them.
+        let mut tiles = 0;
+        let canvas = render_serial(
+            CanvasInfo::new(40, 20),
+            &scene,
+            root,
+            UniformSampler::new(1, 1),
+            White,
+            &mut |_, _| tiles += 1,
+        );
+        assert_eq!(6, tiles);
+        assert!(canvas.data().iter().all(|channel| *channel == 255));
+    }
+
+    #[test]
+    fn test_tile_errors() {
+        let mut scene = Scene::default();
+        let root = scene.sphere(1.).unwrap();
+        let pool = rayon::ThreadPoolBuilder::new().build().unwrap();
+
+        // The tiles that panic are reported, and the rest are still rendered.
+        let errors = std::sync::atomic::AtomicUsize::new(0);
+        let err = render(
+            CanvasInfo::new(48, 16),
+            &scene,
+            root,
+            UniformSampler::new(1, 1),
+            PanicsAfter { x: 32. },
+            Workers::local(&pool),
+            &|chunk| {
+                if chunk.data().chunks(3).all(|pixel| pixel == [255, 0, 255]) {
+                    errors.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+                }
+            },
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("the tile at (32, 0) panicked"));
+        let RenderError::Tiles(tiles) = err else {
+            panic!("the render failed without rendering: {}", err);
+        };
+        assert_eq!(1, tiles.len());
+        assert_eq!((32, 0), (tiles[0].x, tiles[0].y));
+        assert!(tiles[0].message.contains("bad pixel"));
+        assert_eq!(1, errors.into_inner());
+
+        // Graphs that can't be marched fail before any tiles are rendered.
+        let root = scene.invert(root);
+        scene.max_depth = 1;
+        let err = render(
+            CanvasInfo::new(48, 16),
+            &scene,
+            root,
+            UniformSampler::new(1, 1),
+            PanicsAfter { x: 32. },
+            Workers::local(&pool),
+            &|_| panic!("a tile was rendered"),
+        )
+        .unwrap_err();
+        assert!(matches!(err, RenderError::Graph(_)), "{}", err);
+    }
 
     #[test]
     fn test_refraction_sphere_direct() {
@@ -334,7 +1115,7 @@ mod tests {
 
         let white = scene.solid(Color::white());
         let vacuum = scene.phong(white, 0.1, 0.9, 0.9, 200.0, 0.0, 1.0, 1.0);
-        let sphere = scene.sphere(1.0);
+        let sphere = scene.sphere(1.0).unwrap();
         let root = scene.paint(vacuum, sphere);
 
         // Check normal construction from an external hit
@@ -373,6 +1154,177 @@ mod tests {
         assert_eq!(res.normal.z, 1.);
     }
 
+    #[test]
+    fn test_march_dist_limit() {
+        let mut scene = Scene::default();
+        let spheres = [-4., 4.].map(|x| {
+            let sphere = scene.sphere(1.).unwrap();
+            scene.transform(Transform::new().translate(&Vector3::new(x, 0., 5.)), sphere)
+        });
+        let root = scene.group(spheres.to_vec()).unwrap();
+
+        let config = MarchConfig::default();
+        let ray = |x| {
+            Ray::new(
+                Point3::new(x, 0., 0.),
+                Unit::new_unchecked(Vector3::new(0., 0., 1.)),
+            )
+        };
+
+        // The sphere is four away, so a light in front of it isn't hidden.
+        assert!(Hit::march_dist(&config, &scene, root, ray(4.), 3.).is_none());
+        let hit = Hit::march_dist(&config, &scene, root, ray(4.), 10.).expect("intersection");
+        assert!((hit.0 - 4.).abs() < config.min_dist);
+
+        // Inside both of two spheres, either one proves the hit without checking the other.
+        let outer = scene.sphere(2.).unwrap();
+        let both = scene.group(vec![spheres[1], outer]).unwrap();
+        let inside = Ray::new(
+            Point3::new(4., 0., 5.),
+            Unit::new_unchecked(Vector3::new(0., 0., 1.)),
+        );
+        let exact = scene
+            .node(both)
+            .fast_sdf(&scene, &inside, Float::NEG_INFINITY);
+        let early = scene.node(both).fast_sdf(&scene, &inside, config.min_dist);
+        assert!(early.distance.0 < config.min_dist);
+        assert!(early.distance >= exact.distance);
+    }
+
+    #[test]
+    fn test_march_scale() {
+        let mut scene = Scene::default();
+        let tiny = scene.sphere(1e-4).unwrap();
+        let huge = scene.sphere(1e4).unwrap();
+        let ray = |z: Float| Ray::new(Point3::new(0., 0., z), Vector3::z_axis());
+
+        // A scene smaller than the default distance is hit from too far away, unless the march is
+        // scaled to it.
+        let config = MarchConfig::default();
+        let close = Hit::march_dist(&config, &scene, tiny, ray(-5e-4), 1.).expect("intersection");
+        assert!(close.0 < 1e-4);
+        let scaled = config.scaled(scene.bounding_box(tiny));
+        let hit = Hit::march_dist(&scaled, &scene, tiny, ray(-5e-4), 1.).expect("intersection");
+        assert!((hit.0 - 4e-4).abs() < 1e-6, "{}", hit.0);
+
+        // A huge scene is hit from further away, and far along a ray the hit distance grows too.
+        let scaled = config.scaled(scene.bounding_box(huge));
+        assert!(scaled.min_dist > 1.);
+        assert_eq!(config.min_dist, config.hit_distance(10.));
+        assert!(config.hit_distance(1e4) > config.min_dist);
+
+        // Opting out keeps the distances as they're given.
+        let fixed = MarchConfig {
+            auto_scale: false,
+            ..MarchConfig::default()
+        };
+        assert_eq!(
+            fixed.min_dist,
+            fixed.scaled(scene.bounding_box(huge)).min_dist
+        );
+        assert_eq!(fixed.min_dist, fixed.hit_distance(1e4));
+    }
+
+    fn medium(node: NodeId, refractive_index: Float, priority: u32) -> Medium {
+        Medium {
+            node,
+            refractive_index,
+            dispersion: 0.,
+            absorption: Color::black(),
+            priority,
+        }
+    }
+
+    #[test]
+    fn test_visibility() {
+        let mut scene = Scene::default();
+        let white = scene.solid(Color::white());
+        let tinted = scene.phong(white, 0.1, 0.9, 0.9, 200., 0., 0.5, 1.5);
+        let stained = scene.phong(white, 0.1, 0.9, 0.9, 200., 0., 1., 1.5);
+        scene.set_absorption(stained, Color::new(0.5, 0., 0.));
+        let opaque = scene.phong(white, 0.1, 0.9, 0.9, 200., 0., 0., 1.);
+        let sphere = scene.sphere(1.).unwrap();
+        let glass = scene.paint(tinted, sphere);
+        let red = scene.paint(stained, sphere);
+        let stone = scene.paint(opaque, sphere);
+        let behind = scene.transform(Transform::new().translate(&Vector3::new(0., 0., 3.)), stone);
+        let both = scene.group(vec![glass, behind]).unwrap();
+
+        let config = MarchConfig::default();
+        let visibility = |root, max_dist| {
+            let ray = Ray::new(Point3::new(0., 0., -3.), Vector3::z_axis());
+            let color = Hit::visibility(&config, &scene, root, ray, max_dist);
+            [color.r, color.g, color.b]
+        };
+        let assert_close = |expected: [f32; 3], actual: [f32; 3]| {
+            assert!(
+                expected
+                    .iter()
+                    .zip(&actual)
+                    .all(|(e, a)| (e - a).abs() < 1e-3),
+                "expected {:?}, found {:?}",
+                expected,
+                actual
+            );
+        };
+
+        // The light is dimmed each time it crosses the surface of the glass, and blocked by the
+        // stone behind it.
+        assert_close([1.; 3], visibility(glass, 1.));
+        assert_close([0.25; 3], visibility(glass, 10.));
+        assert_close([0.25; 3], visibility(both, 4.5));
+        assert_close([0.; 3], visibility(both, 10.));
+        assert_close([0.; 3], visibility(stone, 10.));
+
+        // Light is absorbed by the medium it travels through, including when it ends inside it.
+        assert_close([f32::exp(-1.), 1., 1.], visibility(red, 10.));
+        assert_close([f32::exp(-0.5), 1., 1.], visibility(red, 3.));
+    }
+
+    #[test]
+    fn test_bias() {
+        let mut scene = Scene::default();
+        let white = scene.solid(Color::white());
+        let floor = scene.phong(white, 0.1, 0.9, 0.9, 200., 0., 0., 1.);
+        let ground = scene.plane(Vector3::y_axis());
+        let ground = scene.paint(floor, ground);
+        let pebble = scene.sphere(0.1).unwrap();
+        let pebble = scene.transform(
+            Transform::new().translate(&Vector3::new(0., 0.2, 0.)),
+            pebble,
+        );
+        let root = scene.group(vec![ground, pebble]).unwrap();
+
+        let config = MarchConfig::default();
+        let light = Point3::new(0., 5., 0.);
+        let mut visibility = |bias| {
+            scene.set_bias(floor, bias);
+            let ray = Ray::new(
+                Point3::new(0., 1., -1.),
+                Unit::new_normalize(Vector3::new(0., -1., 1.)),
+            );
+            let hit = Hit::march(&config, &scene, root, ray, false).expect("intersection");
+            hit.light_visibility(&config, &scene, root, &light).r
+        };
+
+        // The pebble shadows the floor under it, unless shadow rays start beyond it.
+        assert_eq!(0., visibility(Bias::default()));
+        assert_eq!(
+            1.,
+            visibility(Bias {
+                shadow: 0.5,
+                ..Bias::default()
+            })
+        );
+        assert_eq!(
+            1.,
+            visibility(Bias {
+                normal: Some(0.5),
+                ..Bias::default()
+            })
+        );
+    }
+
     #[test]
     fn test_refraction_indices() {
         let mut containers = Containers::default();
@@ -381,21 +1333,71 @@ mod tests {
 
         // These aren't used for actual intersections, as we're mocking the intersection order in
         // the asserts below.
-        let a = scene.sphere(1.);
-        let b = scene.sphere(1.);
-        let c = scene.sphere(1.);
-
-        assert_eq!((1.0, 1.5), containers.refractive_indices(a, 1.5));
+        let a = scene.sphere(1.).unwrap();
+        let b = scene.sphere(1.).unwrap();
+        let c = scene.sphere(1.).unwrap();
+
+        assert_eq!(
+            (1.0, 1.5),
+            containers.refractive_indices(medium(a, 1.5, 0), None)
+        );
         assert!(containers.contains(a));
-        assert_eq!((1.5, 2.0), containers.refractive_indices(b, 2.0));
+        assert_eq!(
+            (1.5, 2.0),
+            containers.refractive_indices(medium(b, 2.0, 0), None)
+        );
         assert!(containers.contains(b));
-        assert_eq!((2.0, 2.5), containers.refractive_indices(c, 2.5));
+        assert_eq!(
+            (2.0, 2.5),
+            containers.refractive_indices(medium(c, 2.5, 0), None)
+        );
         assert!(containers.contains(c));
-        assert_eq!((2.5, 2.5), containers.refractive_indices(b, 2.0));
+        assert_eq!(
+            (2.5, 2.5),
+            containers.refractive_indices(medium(b, 2.0, 0), None)
+        );
         assert!(!containers.contains(b));
-        assert_eq!((2.5, 1.5), containers.refractive_indices(c, 2.5));
+        assert_eq!(
+            (2.5, 1.5),
+            containers.refractive_indices(medium(c, 2.5, 0), None)
+        );
         assert!(!containers.contains(c));
-        assert_eq!((1.5, 1.0), containers.refractive_indices(a, 1.5));
+        assert_eq!(
+            (1.5, 1.0),
+            containers.refractive_indices(medium(a, 1.5, 0), None)
+        );
         assert!(!containers.contains(a));
     }
+
+    #[test]
+    fn test_refraction_priority() {
+        let mut containers = Containers::default();
+
+        let mut scene = Scene::default();
+        let ice = scene.sphere(1.).unwrap();
+        let water = scene.sphere(1.).unwrap();
+
+        // Entering the water, then the ice that floats in it.
+        assert_eq!(
+            (1.0, 1.33),
+            containers.refractive_indices(medium(water, 1.33, 0), None)
+        );
+        assert!(!containers.is_false_boundary(ice, 1));
+        assert_eq!(
+            (1.33, 1.31),
+            containers.refractive_indices(medium(ice, 1.31, 1), None)
+        );
+
+        // The surface of the water is invisible inside the ice, regardless of the order the
+        // boundaries are crossed in.
+        assert!(containers.is_false_boundary(water, 0));
+        assert_eq!(
+            (1.31, 1.31),
+            containers.refractive_indices(medium(water, 1.33, 0), None)
+        );
+        assert_eq!(
+            (1.31, 1.0),
+            containers.refractive_indices(medium(ice, 1.31, 1), None)
+        );
+    }
 }
@@ -0,0 +1,159 @@
+use anyhow::Error;
+use image::Rgb32FImage;
+use nalgebra::{Point2, Vector3};
+use std::path::Path;
+use std::sync::Arc;
+
+use crate::{
+    canvas::Color,
+    math::{self, Float, Mix},
+    scene::Scene,
+};
+
+type Result<T> = std::result::Result<T, Error>;
+
+/// What a render shows where rays escape the scene.
+#[derive(Debug, Clone, Default)]
+pub enum Background {
+    /// The light that the diffuse lights give in the direction of the ray.
+    #[default]
+    Sky,
+
+    /// Nothing, leaving the pixels that primary rays escape through uncovered. Reflected and
+    /// refracted rays still see the sky.
+    Transparent,
+
+    /// A single color in every direction.
+    Solid(Color),
+
+    /// Colors blended from `bottom` straight down to `top` straight up.
+    Gradient { top: Color, bottom: Color },
+
+    /// An image, wrapped around the scene or stretched over the film.
+    Image {
+        image: Arc<Rgb32FImage>,
+        mapping: Mapping,
+    },
+}
+
+/// How a [`Background::Image`] is looked up.
+#[derive(Debug, Clone)]
+pub enum Mapping {
+    /// Longitude across the width of the image and latitude down its height, with the center of
+    /// the image looking down the positive z axis.
+    LatLong,
+
+    /// The image covers the film, which is `width` by `height` pixels. Rays that bounce off the
+    /// scene see the same part of the image as the pixel they started from.
+    Screen { width: Float, height: Float },
+}
+
+impl Background {
+    /// Load an image background from `path`. Its values are used as they're stored, just as
+    /// renders are written out without any gamma.
+    pub fn load(path: impl AsRef<Path>, mapping: Mapping) -> Result<Self> {
+        let path = path.as_ref();
+        let image = image::open(path)
+            .map_err(|err| anyhow::anyhow!("Failed to load `{}`: {}", path.display(), err))?
+            .into_rgb32f();
+        Ok(Background::Image {
+            image: Arc::new(image),
+            mapping,
+        })
+    }
+
+    /// The color seen by a ray that escapes the scene traveling in `direction`, and that started
+    /// from the point `film` on the camera's film.
+    pub fn color(&self, scene: &Scene, direction: &Vector3<Float>, film: &Point2<Float>) -> Color {
+        match self {
+            Background::Sky | Background::Transparent => {
+                let mut color = Color::black();
+                for light in scene.lights.iter() {
+                    color += light.light_escape(direction);
+                }
+                color
+            }
+
+            Background::Solid(color) => color.clone(),
+
+            Background::Gradient { top, bottom } => {
+                let t = direction.normalize().y * 0.5 + 0.5;
+                bottom.mix(top, t as f32)
+            }
+
+            Background::Image { image, mapping } => {
+                let (u, v) = match mapping {
+                    Mapping::LatLong => {
+                        let direction = direction.normalize();
+                        let longitude = direction.x.atan2(direction.z);
+                        let latitude = direction.y.clamp(-1., 1.).acos();
+                        (
+                            0.5 + longitude / (2. * math::consts::PI),
+                            latitude / math::consts::PI,
+                        )
+                    }
+                    Mapping::Screen { width, height } => (film.x / width, film.y / height),
+                };
+
+                let texel = |t: Float, size: u32| ((t * size as Float) as u32).min(size - 1);
+                let [r, g, b] = image
+                    .get_pixel(texel(u, image.width()), texel(v, image.height()))
+                    .0;
+                Color::new(r, g, b)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_background() {
+        let scene = Scene::default();
+        let film = Point2::new(0., 0.);
+        let up = Vector3::new(0., 2., 0.);
+        let level = Vector3::new(1., 0., 0.);
+
+        let assert_color = |expected: Color, actual: Color| {
+            assert_eq!(expected.to_u8(), actual.to_u8(), "{:?}", actual);
+        };
+
+        let gradient = Background::Gradient {
+            top: Color::new(0., 0., 1.),
+            bottom: Color::white(),
+        };
+        assert_color(Color::new(0., 0., 1.), gradient.color(&scene, &up, &film));
+        assert_color(
+            Color::new(0.5, 0.5, 1.),
+            gradient.color(&scene, &level, &film),
+        );
+
+        // The left half of the image is red and the right half green.
+        let mut image = Rgb32FImage::new(4, 2);
+        for (x, _, pixel) in image.enumerate_pixels_mut() {
+            pixel.0 = if x < 2 { [1., 0., 0.] } else { [0., 1., 0.] };
+        }
+        let image = Arc::new(image);
+
+        let latlong = Background::Image {
+            image: image.clone(),
+            mapping: Mapping::LatLong,
+        };
+        let red = Color::new(1., 0., 0.);
+        let green = Color::new(0., 1., 0.);
+        assert_color(red.clone(), latlong.color(&scene, &-level, &film));
+        assert_color(green.clone(), latlong.color(&scene, &level, &film));
+
+        let screen = Background::Image {
+            image,
+            mapping: Mapping::Screen {
+                width: 8.,
+                height: 8.,
+            },
+        };
+        assert_color(red, screen.color(&scene, &up, &Point2::new(3.5, 7.5)));
+        assert_color(green, screen.color(&scene, &up, &Point2::new(4.5, 0.5)));
+    }
+}
@@ -0,0 +1,167 @@
+use crate::{
+    camera::{Camera, Sample},
+    canvas::{Color, Guide},
+    integrator::{Hit, Integrator, IntegratorBuilder, Radiance},
+    math::Float,
+    scene::{MarchConfig, NodeId, Scene},
+};
+
+/// What a debug render shows of the surfaces that the camera sees.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DebugMode {
+    /// The shading normal, with each axis mapped from `-1..1` to a channel from dark to bright.
+    Normals,
+
+    /// The distance from the camera, white up close and fading to black at `far`.
+    Depth { far: Float },
+
+    /// The steps taken to reach the surface, as a fraction of the steps allowed, which shows where
+    /// marching is expensive.
+    Steps,
+
+    /// The texture coordinates, in the red and green channels.
+    Uv,
+}
+
+/// Renders a view of the surfaces themselves rather than their lighting, for checking geometry
+/// alongside a beauty pass. Everything that rays miss is black.
+pub struct DebugBuilder<C> {
+    camera: C,
+    config: MarchConfig,
+    mode: DebugMode,
+}
+
+impl<C> DebugBuilder<C> {
+    pub fn new(camera: C, config: MarchConfig, mode: DebugMode) -> Self {
+        Self {
+            camera,
+            config,
+            mode,
+        }
+    }
+}
+
+impl<C: Camera + Clone + 'static> IntegratorBuilder for DebugBuilder<C> {
+    fn build(&self) -> Box<dyn Integrator> {
+        Box::new(DebugView {
+            camera: self.camera.clone(),
+            march: self.config.clone(),
+            config: self.config.clone(),
+            mode: self.mode,
+            root: None,
+        })
+    }
+}
+
+struct DebugView<C> {
+    camera: C,
+
+    /// The march as it was configured, and as it's scaled to the size of the last root.
+    march: MarchConfig,
+    config: MarchConfig,
+
+    mode: DebugMode,
+    root: Option<NodeId>,
+}
+
+impl<C: Camera> DebugView<C> {
+    fn hit(&mut self, scene: &Scene, root: NodeId, sample: &Sample) -> Option<Hit> {
+        if self.root != Some(root) {
+            self.config = self.march.scaled(scene.bounding_box(root));
+            self.root = Some(root);
+        }
+
+        let ray = self.camera.generate_ray(sample)?;
+        Hit::march(&self.config, scene, root, ray, false)
+    }
+}
+
+impl<C: Camera> Integrator for DebugView<C> {
+    fn luminance(&mut self, scene: &Scene, root: NodeId, sample: &Sample) -> Radiance {
+        let Some(hit) = self.hit(scene, root, sample) else {
+            return Radiance::opaque(Color::black());
+        };
+
+        let color = match self.mode {
+            DebugMode::Normals => {
+                let n = hit.normal.map(|c| 0.5 + 0.5 * c as f32);
+                Color::new(n.x, n.y, n.z)
+            }
+            DebugMode::Depth { far } => {
+                let depth = (1. - hit.distance.0 / far).clamp(0., 1.) as f32;
+                Color::new(depth, depth, depth)
+            }
+            DebugMode::Steps => {
+                let steps = hit.steps as f32 / self.config.max_steps as f32;
+                Color::new(steps, steps, steps)
+            }
+            DebugMode::Uv => Color::new(
+                hit.uv.x.clamp(0., 1.) as f32,
+                hit.uv.y.clamp(0., 1.) as f32,
+                0.,
+            ),
+        };
+
+        Radiance::opaque(color)
+    }
+
+    fn guide(&mut self, scene: &Scene, root: NodeId, sample: &Sample) -> Guide {
+        let Some(hit) = self.hit(scene, root, sample) else {
+            return Guide::default();
+        };
+
+        Guide {
+            albedo: Color::white(),
+            normal: hit.normal.into_inner().cast(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use nalgebra::Point3;
+
+    use super::*;
+    use crate::camera::{CanvasInfo, PinholeCamera};
+    use crate::transform::Transform;
+
+    #[test]
+    fn test_debug_modes() {
+        let mut scene = Scene::default();
+        let root = scene.sphere(1.).unwrap();
+        let info = CanvasInfo::new(8, 8);
+        let camera = PinholeCamera::new(
+            &info,
+            Transform::look_at(
+                &Point3::new(0., 0., -5.),
+                &Point3::origin(),
+                &nalgebra::Vector3::y(),
+            ),
+            1.,
+        );
+
+        let render = |mode, x: Float| {
+            let builder = DebugBuilder::new(camera.clone(), MarchConfig::default(), mode);
+            let mut integrator = builder.build();
+            integrator
+                .luminance(&scene, root, &Sample::new(x, 4.))
+                .color
+        };
+
+        // The middle of the sphere faces the camera, down the negative z axis.
+        let normal = render(DebugMode::Normals, 4.);
+        assert!(
+            normal.b < 0.1 && (normal.r - 0.5).abs() < 0.1,
+            "{:?}",
+            normal
+        );
+
+        let depth = render(DebugMode::Depth { far: 8. }, 4.);
+        assert!((depth.r - 0.5).abs() < 0.01, "{:?}", depth);
+
+        // Rays that graze the sphere take more steps than those that hit it head on.
+        let steps = |x| render(DebugMode::Steps, x).r;
+        assert!(steps(5.3) > steps(4.), "{} {}", steps(5.3), steps(4.));
+        assert!(render(DebugMode::Normals, 0.).is_black());
+    }
+}
@@ -0,0 +1,102 @@
+use nalgebra::{Point3, Vector3};
+use std::collections::HashMap;
+
+use crate::{canvas::Color, math::Float};
+
+/// The most answers kept at once. The cache starts over once it's full, which keeps its memory
+/// bounded on large scenes while still helping the neighbouring pixels of a tile.
+const MAX_ENTRIES: usize = 1 << 16;
+
+/// Remembers how visible lights are from the cells of a grid over the scene, so that shadow
+/// rays from points close to each other are only marched once. Every point in a cell shares the
+/// answer for the cell's center, so shadow edges become as coarse as the cells, but don't depend
+/// on which point in a cell happens to be looked up first.
+#[derive(Debug, Clone)]
+pub struct OcclusionCache {
+    /// The width of each cell of the grid.
+    cell: Float,
+    entries: HashMap<(usize, [i64; 3]), Color>,
+}
+
+impl OcclusionCache {
+    pub fn new(cell: Float) -> Self {
+        Self {
+            cell,
+            entries: HashMap::new(),
+        }
+    }
+
+    /// How much of the light at index `light` reaches `point`, calling `visibility` with the
+    /// center of the cell that the point falls in to find out, when it hasn't been looked up yet.
+    pub fn visibility(
+        &mut self,
+        light: usize,
+        point: &Point3<Float>,
+        visibility: impl FnOnce(&Point3<Float>) -> Color,
+    ) -> Color {
+        let cell = point.coords.map(|x| (x / self.cell).floor());
+        let key = (light, cell.map(|x| x as i64).into());
+        if let Some(visibility) = self.entries.get(&key) {
+            return visibility.clone();
+        }
+
+        if self.entries.len() >= MAX_ENTRIES {
+            self.entries.clear();
+        }
+
+        let center = Point3::from((cell + Vector3::repeat(0.5)) * self.cell);
+        let visibility = visibility(&center);
+        self.entries.insert(key, visibility.clone());
+        visibility
+    }
+
+    /// Forget everything, for when the scene being rendered changes.
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_occlusion_cache() {
+        let mut cache = OcclusionCache::new(0.5);
+        let mut marched = Vec::new();
+        let mut visibility = |light, point: Point3<Float>, answer: Color| {
+            cache
+                .visibility(light, &point, |center| {
+                    marched.push(*center);
+                    answer
+                })
+                .to_u8()
+        };
+
+        // Points in the same cell share the first answer, but each light has its own.
+        let red = Color::new(1., 0., 0.);
+        assert_eq!(
+            [0; 3],
+            visibility(0, Point3::new(0.1, 0.1, 0.1), Color::black())
+        );
+        assert_eq!(
+            [0; 3],
+            visibility(0, Point3::new(0.4, 0.2, 0.3), Color::white())
+        );
+        assert_eq!([255, 0, 0], visibility(1, Point3::new(0.4, 0.2, 0.3), red));
+        assert_eq!(
+            [255; 3],
+            visibility(0, Point3::new(-0.1, 0.1, 0.1), Color::white())
+        );
+
+        // Shadows are marched from the centers of the cells.
+        assert_eq!(
+            vec![
+                Point3::new(0.25, 0.25, 0.25),
+                Point3::new(0.25, 0.25, 0.25),
+                Point3::new(-0.25, 0.25, 0.25)
+            ],
+            marched
+        );
+    }
+}
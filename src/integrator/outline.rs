@@ -0,0 +1,127 @@
+use nalgebra::{Point3, Unit, Vector3};
+
+use crate::{
+    canvas::Color,
+    integrator::Hit,
+    math::{self, consts, Float},
+    ray::Ray,
+    scene::{MarchConfig, NodeId, Scene},
+};
+
+/// The number of rays marched around each hit to look for edges.
+const SAMPLES: usize = 8;
+
+/// Lines drawn over the edges of the surfaces that the camera sees, for blueprint and cartoon
+/// styles. Edges are found by marching a ring of rays around each ray from the camera, and
+/// comparing what they hit: creases turn the normal sharply, while silhouettes and tightly curved
+/// surfaces leave the plane that the first ray hit.
+#[derive(Debug, Clone)]
+pub struct Outline {
+    pub color: Color,
+
+    /// The angle between each ray and the ring around it, which sets the width of the lines.
+    pub width: Float,
+
+    /// The smallest turn of the surface that counts as an edge, in radians.
+    pub angle: Float,
+}
+
+impl Default for Outline {
+    fn default() -> Self {
+        Self {
+            color: Color::black(),
+            width: 0.002,
+            angle: math::deg_to_rad(30.),
+        }
+    }
+}
+
+impl Outline {
+    /// How much of `hit` is covered by a line, from `0` to `1`, when it was found by a ray
+    /// starting at `origin`.
+    pub fn coverage(
+        &self,
+        config: &MarchConfig,
+        scene: &Scene,
+        root: NodeId,
+        origin: &Point3<Float>,
+        hit: &Hit,
+    ) -> f32 {
+        let dir = hit.ray.direction;
+        let up = if dir.x.abs() < 0.9 {
+            Vector3::x()
+        } else {
+            Vector3::y()
+        };
+        let u = Unit::new_normalize(dir.cross(&up));
+        let v = dir.cross(&u);
+
+        let point = hit.ray.position;
+        let cos = self.angle.cos();
+        let tan = self.angle.tan();
+
+        let mut edges = 0;
+        for i in 0..SAMPLES {
+            let theta = 2. * consts::PI * i as Float / SAMPLES as Float;
+            let offset = (u.as_ref() * theta.cos() + v * theta.sin()) * self.width;
+            let ray = Ray::new(*origin, Unit::new_normalize(dir.as_ref() + offset));
+
+            // Where the neighbour would meet the plane of the hit, if the surface were flat.
+            let facing = ray.direction.dot(&hit.geometric);
+            let Some(expected) = (facing < 0.)
+                .then(|| (point - origin).dot(&hit.geometric) / facing)
+                .filter(|t| t.is_finite() && *t > 0.)
+            else {
+                edges += 1;
+                continue;
+            };
+
+            let Some(neighbour) = Hit::march(config, scene, root, ray, false) else {
+                edges += 1;
+                continue;
+            };
+
+            // The surface may leave the plane by as much as it's allowed to turn across the gap
+            // between the rays.
+            let gap = (neighbour.ray.position - point).norm();
+            let bent = (neighbour.distance.0 - expected).abs() * -facing > gap * tan;
+            let creased = neighbour.normal.dot(&hit.normal) < cos;
+            if bent || creased {
+                edges += 1;
+            }
+        }
+
+        // A point right on an edge only sees it from half of the ring.
+        (2. * edges as f32 / SAMPLES as f32).min(1.)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_outline() {
+        let mut scene = Scene::default();
+        let root = scene.rect(1., 1., 1.).unwrap();
+        let config = MarchConfig::default();
+        let outline = Outline {
+            width: 0.01,
+            ..Outline::default()
+        };
+
+        let coverage = |x: Float, y: Float| {
+            let origin = Point3::new(0., 0., -5.);
+            let ray = Ray::new(origin, Unit::new_normalize(Vector3::new(x, y, 4.)));
+            let hit = Hit::march(&config, &scene, root, ray, false).expect("intersection");
+            outline.coverage(&config, &scene, root, &origin, &hit)
+        };
+
+        // The middle of a face is flat, while the box's silhouette and the edges between its faces
+        // are drawn.
+        assert_eq!(0., coverage(0., 0.));
+        assert_eq!(0., coverage(0.5, 0.5));
+        assert!(coverage(0.995, 0.) > 0.);
+        assert!(coverage(0.995, 0.995) > 0.);
+    }
+}
@@ -1,19 +1,56 @@
-use nalgebra::Unit;
+use nalgebra::{Point2, Point3, Unit, Vector3};
+use rand::{rngs::StdRng, Rng, SeedableRng};
 use std::borrow::Cow;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::sync::{Arc, Mutex};
 
 use crate::{
     camera::{Camera, Sample},
-    canvas::Color,
-    integrator::{Containers, Hit, Integrator, IntegratorBuilder},
-    math,
+    canvas::{Color, Guide},
+    integrator::{
+        Background, Containers, Hit, Integrator, IntegratorBuilder, Medium, OcclusionCache,
+        Outline, Radiance,
+    },
+    math::{self, Float, Mix},
     ray::Ray,
-    scene::{Light, MarchConfig, Material, NodeId, Scene},
+    scene::{Backface, Emitter, Light, MarchConfig, Material, NodeId, Scene},
 };
 
+/// Marching through volumes stops once this little of the background would remain visible.
+const MIN_TRANSMITTANCE: f32 = 0.001;
+
+/// The number of points picked on each emissive surface, each time a point is lit by it.
+const EMITTER_SAMPLES: usize = 4;
+
+/// The number of points inside an object that its thickness is sampled at, for the light that
+/// scatters through it.
+const SCATTER_SAMPLES: u32 = 8;
+
+/// Objects thicker than this many times the scatter depth of their material let no scattered
+/// light through.
+const SCATTER_RANGE: Float = 4.0;
+
+/// The wavelengths in nanometers that stand for the red, green and blue channels, when light is
+/// reflected by a thin film.
+const FILM_WAVELENGTHS: [Float; 3] = [650.0, 510.0, 475.0];
+
+/// The ambient light from a light, and the direction towards it with the part of its light that
+/// reaches a hit without being shadowed.
+type LightAt = (Color, Option<(Unit<Vector3<Float>>, Color)>);
+
+/// The emissive surfaces under the root that was last rendered, shared by the integrators that a
+/// builder makes so that they're only found once for each render.
+type SharedEmitters = Arc<Mutex<Option<(NodeId, Arc<Vec<Emitter>>)>>>;
+
 pub struct WhittedBuilder<C> {
     camera: C,
     config: MarchConfig,
     max_reflections: u32,
+    background: Background,
+    shadow_cache: Option<Float>,
+    outline: Option<Outline>,
+    emitters: SharedEmitters,
 }
 
 impl<C> WhittedBuilder<C> {
@@ -22,17 +59,60 @@ impl<C> WhittedBuilder<C> {
             camera,
             config,
             max_reflections,
+            background: Background::default(),
+            shadow_cache: None,
+            outline: None,
+            emitters: SharedEmitters::default(),
         }
     }
+
+    /// Show `background` where rays escape the scene, rather than the sky.
+    pub fn with_background(mut self, background: Background) -> Self {
+        self.background = background;
+        self
+    }
+
+    /// Share the shadows cast by point lights between points in the same `cell`-wide cube of the
+    /// scene, trading the sharpness of shadow edges for fewer shadow rays.
+    pub fn with_shadow_cache(mut self, cell: Float) -> Self {
+        self.shadow_cache = Some(cell);
+        self
+    }
+
+    /// Draw lines over the edges of the surfaces that the camera sees.
+    pub fn with_outline(mut self, outline: Outline) -> Self {
+        self.outline = Some(outline);
+        self
+    }
 }
 
 impl<C: Camera + Clone + 'static> IntegratorBuilder for WhittedBuilder<C> {
     fn build(&self) -> Box<dyn Integrator> {
-        Box::new(Whitted::new(
+        let mut whitted = Whitted::new(
             self.camera.clone(),
             self.config.clone(),
             self.max_reflections,
-        ))
+        );
+        whitted.background = self.background.clone();
+        whitted.occlusion = self.shadow_cache.map(OcclusionCache::new);
+        whitted.outline = self.outline.clone();
+        whitted.shared_emitters = self.emitters.clone();
+        Box::new(whitted)
+    }
+
+    #[cfg(feature = "gpu")]
+    fn gpu_settings(&self) -> Option<crate::gpu::Settings> {
+        // The shader doesn't find the edges that outlines are drawn over.
+        if self.outline.is_some() {
+            return None;
+        }
+
+        Some(crate::gpu::Settings {
+            camera: std::sync::Arc::new(self.camera.clone()),
+            config: self.config.clone(),
+            max_reflections: self.max_reflections,
+            background: self.background.clone(),
+        })
     }
 }
 
@@ -40,15 +120,267 @@ pub struct Whitted<C> {
     camera: C,
     config: MarchConfig,
     max_reflections: u32,
+
+    /// What rays that escape the scene see.
+    background: Background,
+
+    /// The point on the film that the current sample's rays started from.
+    film: Point2<Float>,
+
+    /// The march as it was configured, before it's scaled to the size of the root.
+    march: MarchConfig,
+
+    /// The emissive surfaces under the root that was last rendered, which light the scene.
+    emitters: Option<(NodeId, Arc<Vec<Emitter>>)>,
+
+    /// Where the emitters are found, when they're shared with other integrators.
+    shared_emitters: SharedEmitters,
+
+    /// Picks the points on emissive surfaces that light each hit, and the rays that rough
+    /// surfaces reflect. It's seeded from each sample's point on the film, so that the image
+    /// doesn't depend on which thread or worker renders each pixel.
+    rng: StdRng,
+
+    /// The shadows already found, when they're shared between nearby points.
+    occlusion: Option<OcclusionCache>,
+
+    /// The lines drawn over edges, if any.
+    outline: Option<Outline>,
+
+    /// The color channel that the current ray carries, once white light has been split by a
+    /// dispersive medium.
+    band: Option<usize>,
+
+    /// True while tracing one of the rays reflected by a rough surface, which only sample any
+    /// other rough surfaces once so that the number of rays doesn't multiply at each bounce.
+    glossy: bool,
+}
+
+/// How much of the light from the mirrored direction a surface reflects, and how rough it is.
+struct Gloss {
+    reflective: f32,
+    roughness: f32,
+
+    /// The number of rays averaged when the surface is rough.
+    samples: u32,
+}
+
+/// How much light passes through a surface, and the medium on the other side of it.
+#[derive(Clone)]
+struct Transmission {
+    transparent: f32,
+    medium: Medium,
+
+    /// True when the surface also reflects, so the light it passes is reduced by the light it
+    /// reflects.
+    reflective: bool,
 }
 
 impl<C> Whitted<C> {
     pub fn new(camera: C, config: MarchConfig, max_reflections: u32) -> Self {
         Self {
             camera,
-            config,
+            config: config.clone(),
             max_reflections,
+            background: Background::default(),
+            film: Point2::origin(),
+            march: config,
+            emitters: None,
+            shared_emitters: SharedEmitters::default(),
+            rng: StdRng::seed_from_u64(0),
+            occlusion: None,
+            outline: None,
+            band: None,
+            glossy: false,
+        }
+    }
+
+    /// Find the emissive surfaces under `root` and fit the march to its size, unless that's
+    /// already done.
+    fn prepare(&mut self, scene: &Scene, root: NodeId) {
+        if self.emitters.as_ref().map(|(id, _)| *id) != Some(root) {
+            self.config = self.march.scaled(scene.bounding_box(root));
+
+            // Finding the emitters samples their surfaces, so it's done once for all the threads.
+            let mut shared = self.shared_emitters.lock().unwrap();
+            let emitters = match &*shared {
+                Some((id, emitters)) if *id == root => emitters.clone(),
+                _ => {
+                    let emitters = Arc::new(scene.emitters(root));
+                    *shared = Some((root, emitters.clone()));
+                    emitters
+                }
+            };
+            drop(shared);
+            self.emitters = Some((root, emitters));
+            if let Some(occlusion) = &mut self.occlusion {
+                occlusion.clear();
+            }
+        }
+    }
+
+    /// How much of the light at index `light` in the scene reaches `point`, as decided by
+    /// `visibility`, unless the shadow cache already knows. With a shadow cache, `visibility` is
+    /// given the center of the cell that the point is in, to find the visibility from instead.
+    fn light_visibility(
+        &mut self,
+        light: usize,
+        point: &Point3<Float>,
+        visibility: impl FnOnce(&MarchConfig, Option<&Point3<Float>>) -> Color,
+    ) -> Color {
+        match &mut self.occlusion {
+            Some(occlusion) => occlusion.visibility(light, point, |center| {
+                visibility(&self.config, Some(center))
+            }),
+            None => visibility(&self.config, None),
+        }
+    }
+
+    /// The light arriving at `point` from the emissive surfaces, as point lights in the direction
+    /// of the light and their intensity. Each picked point on a surface stands in for the area
+    /// around it, and the light it gives falls off with the square of its distance. This divides
+    /// by pi so that a surface filling the sky is as bright as a point light of the same color.
+    /// When the point is on the surface of `hit`, shadow rays start off of it as its material
+    /// asks.
+    fn emitter_lights(
+        &mut self,
+        scene: &Scene,
+        root: NodeId,
+        point: &Point3<Float>,
+        hit: Option<&Hit>,
+    ) -> Vec<(Unit<Vector3<Float>>, Color)> {
+        let mut lights = Vec::new();
+        let Some((_, emitters)) = &self.emitters else {
+            return lights;
+        };
+
+        // Start from just off the surface, so that the march doesn't immediately hit it.
+        let (start, bias) = hit.map_or((*point, 0.0), |hit| {
+            let offset = hit.normal_offset(&self.config, scene);
+            (point + hit.geometric.scale(offset), hit.bias(scene).shadow)
+        });
+
+        for emitter in emitters.iter() {
+            for _ in 0..EMITTER_SAMPLES {
+                let (sample, area) = emitter.sample(&mut self.rng);
+
+                // Aim just above the emitter, so that reaching it doesn't count as a hit.
+                let position = sample.position + sample.normal.scale(self.config.min_dist * 2.0);
+                let dir = position - start;
+                let dist = dir.norm();
+                let lightv = Unit::new_normalize(dir);
+                let cos = -lightv.dot(&sample.normal);
+                if cos <= 0.0 {
+                    continue;
+                }
+
+                if dist <= bias {
+                    continue;
+                }
+
+                let mut ray = Ray::new(start, lightv);
+                ray.step(bias);
+                let visibility = Hit::visibility(&self.config, scene, root, ray, dist - bias);
+                if visibility.is_black() {
+                    continue;
+                }
+
+                let scale =
+                    cos * area / (dist * dist * EMITTER_SAMPLES as Float * math::consts::PI);
+                lights.push((lightv, &sample.radiance * visibility * scale as f32));
+            }
+        }
+
+        lights
+    }
+
+    /// The lights that affect `hit`. Lights that the hit is in the shadow of, and diffuse lights,
+    /// have no direction, and only give ambient light.
+    fn lights_at(&mut self, scene: &Scene, root: NodeId, hit: &Hit) -> Vec<LightAt> {
+        let mut lights = Vec::new();
+        for (index, light) in scene.lights.iter().enumerate() {
+            if !light.links().affects(hit.links) {
+                continue;
+            }
+
+            let intensity = light.intensity_at(&hit.ray.position);
+            let direct = match light {
+                Light::Diffuse { .. } => None,
+                Light::Point { position, .. } => {
+                    let visibility =
+                        self.light_visibility(index, &hit.ray.position, |config, center| {
+                            let Some(center) = center else {
+                                return hit.light_visibility(config, scene, root, position);
+                            };
+
+                            // The center of the cell is moved to where a march towards the
+                            // surface closest to it would stop. Groups only look at the members
+                            // that the ray's direction leads to, so it heads into the surface.
+                            let res = scene.node(root).sdf(
+                                scene,
+                                root,
+                                &Ray::new(*center, -hit.geometric),
+                            );
+                            let point = center - res.normal.scale(res.distance.0 - config.min_dist);
+                            hit.light_visibility_from(config, scene, root, &point, position)
+                        });
+                    (!visibility.is_black()).then(|| {
+                        let lightv = Unit::new_normalize(position - hit.ray.position);
+                        (lightv, &intensity * visibility)
+                    })
+                }
+            };
+            lights.push((light.ambient(), direct));
+        }
+        lights
+    }
+
+    /// The light that scatters through the object from behind the hit, tinted by `scatter`. The
+    /// light reaching the far side of the object, straight behind the hit, is dimmed
+    /// exponentially with the thickness of the object in units of `depth`, and most strongly
+    /// lights the hit when it comes from straight behind it.
+    fn scattered_light(
+        &mut self,
+        scene: &Scene,
+        root: NodeId,
+        hit: &Hit,
+        scatter: &Color,
+        depth: Float,
+    ) -> Color {
+        let Some(thickness) = hit.thickness(scene, root, depth * SCATTER_RANGE, SCATTER_SAMPLES)
+        else {
+            return Color::black();
+        };
+        let transmission = (-thickness / depth).exp() as f32;
+
+        // Shadows are found from off of the far side, as the object would hide the light. The hit
+        // may stop short of the surface by as much as the offset, so the far side is left by twice
+        // the offset.
+        let offset = hit.normal_offset(&self.config, scene);
+        let back = hit.ray.position - hit.geometric.scale(thickness + offset * 2.0);
+
+        let mut lights = self.emitter_lights(scene, root, &back, None);
+        for light in scene.lights.iter() {
+            if !light.links().affects(hit.links) {
+                continue;
+            }
+            if let Light::Point { position, .. } = light {
+                let dir = position - back;
+                let lightv = Unit::new_normalize(dir);
+                let ray = Ray::new(back, lightv);
+                let visibility = Hit::visibility(&self.config, scene, root, ray, dir.norm());
+                if !visibility.is_black() {
+                    lights.push((lightv, light.intensity_at(&back) * visibility));
+                }
+            }
+        }
+
+        let mut color = Color::black();
+        for (lightv, light) in lights {
+            let behind = (1. - lightv.dot(&hit.normal) as f32) * 0.5;
+            color += scatter * &light * (transmission * behind);
         }
+        color
     }
 
     /// Determine the color that would result from a ray intersection with the scene.
@@ -60,18 +392,61 @@ impl<C> Whitted<C> {
         ray: Ray,
         reflection: u32,
     ) -> Color {
-        let mut color = Color::black();
-
         if reflection >= self.max_reflections {
-            return color;
+            return Color::black();
         }
 
-        let Some(mut hit) = Hit::march(&self.config, scene, root, ray, !containers.is_empty())
-        else {
-            for light in scene.lights.iter() {
-                color += light.light_escape();
+        let hit = self.march_ray(scene, root, &containers, ray.clone());
+        self.shade_ray(scene, root, containers, ray, hit, reflection)
+    }
+
+    /// March `ray` to the surface that it sees, when it doesn't escape the scene.
+    fn march_ray(
+        &self,
+        scene: &Scene,
+        root: NodeId,
+        containers: &Containers,
+        ray: Ray,
+    ) -> Option<Hit> {
+        let start = ray.clone();
+        let hit = Hit::march(&self.config, scene, root, ray, !containers.is_empty());
+
+        // A ray that starts inside a solid sees the far side of the surface around it, rather
+        // than the point that it started from. Rays that leave a surface start up to `min_dist`
+        // from it, and may be just inside when it's curved, so only rays deeper than that count.
+        match hit {
+            Some(hit)
+                if containers.is_empty()
+                    && hit.steps == 0
+                    && scene.node(root).sdf(scene, root, &hit.ray).distance.0
+                        < -self.config.min_dist =>
+            {
+                Hit::march(&self.config, scene, root, start, true)
             }
-            return color;
+            hit => hit,
+        }
+    }
+
+    /// Determine the color that `start` sees, given the `hit` that it was marched to.
+    fn shade_ray<'a>(
+        &mut self,
+        scene: &Scene,
+        root: NodeId,
+        containers: Cow<'a, Containers>,
+        start: Ray,
+        hit: Option<Hit>,
+        reflection: u32,
+    ) -> Color {
+        // Fog fills the space between objects, but not the inside of transparent objects.
+        let fog = scene.fog.as_ref().filter(|_| containers.is_empty());
+
+        let Some(mut hit) = hit else {
+            let color = self.background.color(scene, &start.direction, &self.film);
+            let color = match fog {
+                Some(fog) => fog.apply(color, self.config.max_dist),
+                None => color,
+            };
+            return self.through_volumes(scene, root, start, self.config.max_dist, color);
         };
 
         // return unlit magenta if there's no material for this object
@@ -79,7 +454,69 @@ impl<C> Whitted<C> {
             return Color::hex(0xff00ff);
         };
 
-        match scene.material(material) {
+        // Rays only reach the far side of a surface from inside the object, and transparent
+        // objects handle that themselves.
+        if containers.is_empty() {
+            let backfacing = hit.geometric.dot(&hit.ray.direction) > 0.0;
+            hit.inside = backfacing;
+            match (scene.material(material).backface(), backfacing) {
+                (Backface::TwoSided, true) | (Backface::Flip, _) => {
+                    hit.normal = -hit.normal;
+                    hit.geometric = -hit.geometric;
+                }
+                (Backface::Cull, true) => {
+                    let mut ray = hit.ray;
+                    ray.step(self.config.min_dist * 2.0);
+                    let color = self.color_for_ray(scene, root, containers, ray, reflection);
+                    let color = match fog {
+                        Some(fog) => fog.apply(color, hit.distance.0),
+                        None => color,
+                    };
+                    return self.through_volumes(scene, root, start, hit.distance.0, color);
+                }
+                _ => {}
+            }
+        }
+
+        // Outlines are only drawn where the camera sees a surface directly.
+        let primary = reflection == 0 && containers.is_empty();
+
+        // The light reaching the origin of the ray is attenuated by the medium it traveled through.
+        let transmittance = containers.transmittance(hit.distance.0);
+
+        // The boundary of a medium inside another with a higher priority is invisible, so the ray
+        // continues through it unchanged.
+        if let &Material::Phong {
+            transparent,
+            refractive_index,
+            dispersion,
+            ref absorption,
+            priority,
+            ..
+        } = scene.material(material)
+        {
+            if transparent > 0.0 && containers.is_false_boundary(hit.node, priority) {
+                let mut containers = containers;
+                containers.to_mut().refractive_indices(
+                    Medium {
+                        node: hit.node,
+                        refractive_index,
+                        dispersion,
+                        absorption: absorption.clone(),
+                        priority,
+                    },
+                    self.band,
+                );
+
+                let mut ray = hit.ray;
+                ray.step(self.config.min_dist * 2.0);
+                let color =
+                    self.color_for_ray(scene, root, containers, ray, reflection) * transmittance;
+                return self.through_volumes(scene, root, start, hit.distance.0, color);
+            }
+        }
+
+        let color = match scene.material(material) {
             &Material::Phong {
                 pattern,
                 ambient,
@@ -87,75 +524,119 @@ impl<C> Whitted<C> {
                 specular,
                 shininess,
                 reflective,
+                roughness,
+                gloss_samples,
+                fresnel,
                 transparent,
                 refractive_index,
+                dispersion,
+                ref absorption,
+                priority,
+                ref scatter,
+                scatter_depth,
+                film_thickness,
+                film_index,
+                ..
             } => {
                 let eyev = -hit.ray.direction;
 
-                let base_color = scene
-                    .pattern(pattern)
-                    .color_at(scene, &hit.object, &hit.normal);
+                let base_color = hit.pattern_color(scene, root, pattern);
 
-                let mut surface = Color::black();
+                // The diffuse and specular light reflected towards the eye, from a light in the
+                // direction of `lightv`.
+                let shade = |lightv: Unit<Vector3<Float>>, color: &Color| {
+                    let light_dot_normal = lightv.dot(&hit.normal) as f32;
+                    if light_dot_normal < 0. {
+                        return Color::black();
+                    }
 
-                for light in scene.lights.iter() {
-                    let effective_color = &base_color * light.intensity();
-                    surface += ambient * &effective_color;
+                    let diffuse = &base_color * color * diffuse * light_dot_normal;
 
-                    // When the point is out of view of this light, we only integrate the ambient component of the
-                    // light.
-                    if light.position().map_or(false, |light| {
-                        hit.in_shadow(&self.config, scene, root, &light)
-                    }) {
-                        continue;
+                    // direction to the eye
+                    if specular > 0. {
+                        let reflectv = math::reflect(&(-lightv), &hit.normal);
+                        let reflect_dot_eye = reflectv.dot(&eyev) as f32;
+                        let specular = if reflect_dot_eye <= 0. {
+                            Color::black()
+                        } else {
+                            let factor = reflect_dot_eye.powf(shininess);
+                            color * specular * factor
+                        };
+                        diffuse + specular
+                    } else {
+                        diffuse
                     }
+                };
 
-                    let diffuse_specular = match light {
-                        Light::Diffuse { .. } => Color::black(),
-                        Light::Point { position, color } => {
-                            // direction to the light
-                            let lightv = Unit::new_normalize(position - &hit.ray.position);
-
-                            let light_dot_normal = lightv.dot(&hit.normal);
-
-                            if light_dot_normal < 0. {
-                                Color::black()
-                            } else {
-                                let diffuse = effective_color * diffuse * light_dot_normal;
-
-                                // direction to the eye
-                                if specular > 0. {
-                                    let reflectv = math::reflect(&(-lightv), &hit.normal);
-                                    let reflect_dot_eye = reflectv.dot(&eyev);
-                                    let specular = if reflect_dot_eye <= 0. {
-                                        Color::black()
-                                    } else {
-                                        let factor = reflect_dot_eye.powf(shininess);
-                                        color * specular * factor
-                                    };
-                                    diffuse + specular
-                                } else {
-                                    diffuse
-                                }
-                            }
-                        }
-                    };
+                let mut surface = Color::black();
+
+                for (light, direct) in self.lights_at(scene, root, &hit) {
+                    surface += ambient * (&base_color * &light);
+                    if let Some((lightv, color)) = direct {
+                        surface += shade(lightv, &color);
+                    }
+                }
 
-                    surface += diffuse_specular;
+                let lights = self.emitter_lights(scene, root, &hit.ray.position, Some(&hit));
+                for (lightv, color) in lights {
+                    surface += shade(lightv, &color);
+                }
+
+                if !scatter.is_black() {
+                    surface += self.scattered_light(scene, root, &hit, scatter, scatter_depth);
                 }
 
                 // If we're exiting a transparent object on this hit, we need to invert the normal.
-                if containers.contains(hit.node) {
+                let exiting = containers.contains(hit.node);
+                if exiting {
                     hit.normal = -hit.normal;
+                    hit.geometric = -hit.geometric;
                 }
 
+                // A thin film reflects each color as much as the light reflected by its two sides
+                // interferes, in place of the reflectivity of the surface beneath it.
+                let film = (film_thickness > 0.0).then(|| {
+                    let (outside, inside) = if exiting {
+                        (refractive_index, 1.0)
+                    } else {
+                        (1.0, refractive_index)
+                    };
+                    let cos = eyev.dot(&hit.normal).max(0.0);
+                    let [r, g, b] = FILM_WAVELENGTHS.map(|wavelength| {
+                        math::thin_film(
+                            cos,
+                            outside,
+                            film_index,
+                            inside,
+                            film_thickness,
+                            wavelength,
+                        ) as f32
+                    });
+                    Color::new(r, g, b)
+                });
+
+                // Opaque surfaces reflect more at grazing angles, by Schlick's approximation with
+                // the reflectivity as the reflectance seen head on.
+                let reflective = if film.is_some() {
+                    1.0
+                } else if fresnel && reflective > 0.0 && transparent <= 0.0 {
+                    let cos = eyev.dot(&hit.normal).max(0.0) as f32;
+                    reflective + (1.0 - reflective) * (1.0 - cos).powi(5)
+                } else {
+                    reflective
+                };
+
                 let reflected = self.reflected_color(
                     scene,
                     root,
                     containers.clone(),
                     reflection,
                     &hit,
-                    reflective,
+                    Gloss {
+                        reflective,
+                        roughness,
+                        samples: gloss_samples,
+                    },
                 );
 
                 let (refracted, reflectance) = self.refracted_color(
@@ -164,25 +645,195 @@ impl<C> Whitted<C> {
                     containers,
                     reflection,
                     &hit,
-                    reflective > 0.0,
-                    transparent,
-                    refractive_index,
+                    Transmission {
+                        transparent,
+                        medium: Medium {
+                            node: hit.node,
+                            refractive_index,
+                            dispersion,
+                            absorption: absorption.clone(),
+                            priority,
+                        },
+                        reflective: reflective > 0.0,
+                    },
                 );
 
                 surface
-                    + if reflective > 0.0 && transparent > 0.0 {
-                        reflected * reflectance + refracted * (1.0 - reflectance)
-                    } else {
-                        reflected + refracted
+                    + match film {
+                        Some(film) => {
+                            let transmitted = Color::new(1.0 - film.r, 1.0 - film.g, 1.0 - film.b);
+                            reflected * &film + refracted * &transmitted
+                        }
+                        None if reflective > 0.0 && transparent > 0.0 => {
+                            let transmitted = Color::new(
+                                1.0 - reflectance.r,
+                                1.0 - reflectance.g,
+                                1.0 - reflectance.b,
+                            );
+                            reflected * &reflectance + refracted * &transmitted
+                        }
+                        None => reflected + refracted,
                     }
             }
 
-            Material::Emissive { pattern } => {
-                scene
-                    .pattern(*pattern)
-                    .color_at(scene, &hit.object, &hit.normal)
+            &Material::Toon {
+                pattern,
+                ambient,
+                bands,
+                ref rim,
+                rim_width,
+                ref outline,
+                outline_width,
+                ..
+            } => {
+                let eyev = -hit.ray.direction;
+                let facing = hit.normal.dot(&eyev) as f32;
+
+                match outline {
+                    Some(outline) if facing < outline_width => outline.clone(),
+                    _ => {
+                        let base_color = hit.pattern_color(scene, root, pattern);
+
+                        // The diffuse light is rounded up to the next band, so that every lit
+                        // point is lit at least as brightly as the first band.
+                        let bands = bands.max(1) as f32;
+                        let shade = |lightv: Unit<Vector3<Float>>, color: &Color| {
+                            let light_dot_normal = lightv.dot(&hit.normal) as f32;
+                            if light_dot_normal <= 0. {
+                                return Color::black();
+                            }
+                            &base_color * color * ((light_dot_normal * bands).ceil() / bands)
+                        };
+
+                        let mut surface = Color::black();
+                        for (light, direct) in self.lights_at(scene, root, &hit) {
+                            surface += ambient * (&base_color * &light);
+                            if let Some((lightv, color)) = direct {
+                                surface += shade(lightv, &color);
+                            }
+                        }
+
+                        let lights =
+                            self.emitter_lights(scene, root, &hit.ray.position, Some(&hit));
+                        for (lightv, color) in lights {
+                            surface += shade(lightv, &color);
+                        }
+
+                        if facing < rim_width {
+                            surface += rim.clone();
+                        }
+                        surface
+                    }
+                }
+            }
+
+            Material::Matcap { matcap, .. } => matcap.color(&hit.ray.direction, &hit.normal),
+
+            Material::Emissive { pattern, .. } => hit.pattern_color(scene, root, *pattern),
+        };
+
+        let color = match &self.outline {
+            Some(outline) if primary => {
+                let edge = outline.coverage(&self.config, scene, root, &start.position, &hit);
+                (&color).mix(&outline.color, edge)
+            }
+            _ => color,
+        };
+
+        let color = color * transmittance;
+        let color = match fog {
+            Some(fog) => fog.apply(color, hit.distance.0),
+            None => color,
+        };
+        self.through_volumes(scene, root, start, hit.distance.0, color)
+    }
+
+    /// Integrate the volumes along the first `distance` units of `ray`, which are in front of
+    /// `background`.
+    fn through_volumes(
+        &mut self,
+        scene: &Scene,
+        root: NodeId,
+        ray: Ray,
+        distance: Float,
+        background: Color,
+    ) -> Color {
+        let (light, transmittance) = self.integrate_volumes(scene, root, ray, distance);
+        light + background * transmittance
+    }
+
+    /// Integrate the volumes along the first `distance` units of `ray`, returning the light they
+    /// send towards the viewer and the fraction of whatever is behind them that remains visible.
+    /// The ray is sphere traced between volumes, and takes fixed steps inside them, accumulating
+    /// the light that's emitted and scattered while attenuating the background.
+    fn integrate_volumes(
+        &mut self,
+        scene: &Scene,
+        root: NodeId,
+        mut ray: Ray,
+        distance: Float,
+    ) -> (Color, f32) {
+        let node = scene.node(root);
+        let mut light = Color::black();
+        let mut transmittance = 1.0;
+        let mut traveled = 0.0;
+        let mut steps = 0;
+
+        while traveled < distance && transmittance > MIN_TRANSMITTANCE {
+            let result = node.volume_sdf(scene, &ray);
+            let Some(volume) = result.volume else {
+                break;
+            };
+
+            let step = if result.distance.0 > 0.0 {
+                steps += 1;
+                if steps > self.config.max_steps {
+                    break;
+                }
+                result.distance.0.max(self.config.min_dist)
+            } else {
+                let step = volume.step.min(distance - traveled);
+                let length = step as f32;
+                let density = -result.distance.0 as f32 * volume.density_scale;
+                let scattered = &volume.color
+                    * self.incident_light(scene, root, &ray.position)
+                    * volume.scattering;
+                light += (&volume.emission + scattered) * (density * transmittance * length);
+                transmittance *= f32::exp(-density * length);
+                step
+            };
+
+            traveled += step;
+            ray.step(step);
+        }
+
+        (light, transmittance)
+    }
+
+    /// The light arriving at a point inside a volume. Volumes don't shadow themselves, but surfaces
+    /// between the point and a light do.
+    fn incident_light(&mut self, scene: &Scene, root: NodeId, point: &Point3<Float>) -> Color {
+        let mut incident = Color::black();
+        for (index, light) in scene.lights.iter().enumerate() {
+            let visibility = light.position().map_or(Color::white(), |position| {
+                self.light_visibility(index, point, |config, center| {
+                    let point = center.unwrap_or(point);
+                    let dir = position - point;
+                    let dist = dir.norm();
+                    let ray = Ray::new(*point, Unit::new_normalize(dir));
+                    Hit::visibility(config, scene, root, ray, dist)
+                })
+            });
+
+            if !visibility.is_black() {
+                incident += light.intensity_at(point) * visibility;
             }
         }
+
+        for (_, color) in self.emitter_lights(scene, root, point, None) {
+            incident += color;
+        }
+        incident
     }
 
     fn reflected_color<'a>(
@@ -192,15 +843,52 @@ impl<C> Whitted<C> {
         containers: Cow<'a, Containers>,
         reflection: u32,
         hit: &Hit,
-        reflective: f32,
+        gloss: Gloss,
     ) -> Color {
+        let Gloss {
+            reflective,
+            roughness,
+            samples,
+        } = gloss;
         if reflective <= 0.0 {
             return Color::black();
         }
 
+        let offset = hit.normal_offset(&self.config, scene);
         let mut reflect_ray = hit.ray.reflect(&hit.normal);
-        reflect_ray.step(self.config.min_dist);
-        reflective * self.color_for_ray(scene, root, containers, reflect_ray, reflection + 1)
+        if roughness <= 0.0 {
+            reflect_ray.step(offset);
+            return reflective
+                * self.color_for_ray(scene, root, containers, reflect_ray, reflection + 1);
+        }
+
+        // A rough surface averages rays spread in a cone around the mirrored direction.
+        let samples = if self.glossy { 1 } else { samples.max(1) };
+        let outer = std::mem::replace(&mut self.glossy, true);
+        let angle = roughness as Float * math::consts::FRAC_PI_2;
+        let mut color = Color::black();
+        for _ in 0..samples {
+            let mut direction = math::cone_sample(
+                &reflect_ray.direction,
+                angle,
+                self.rng.gen(),
+                self.rng.gen(),
+            );
+
+            // Directions that point into the surface are turned back out of it.
+            let below = direction.dot(&hit.geometric);
+            if below < 0.0 {
+                direction =
+                    Unit::new_normalize(direction.as_ref() - hit.geometric.scale(2. * below));
+            }
+
+            let mut ray = Ray::new(reflect_ray.position, direction);
+            ray.step(offset);
+            color += self.color_for_ray(scene, root, containers.clone(), ray, reflection + 1);
+        }
+        self.glossy = outer;
+
+        color * (reflective / samples as f32)
     }
 
     fn refracted_color<'a>(
@@ -210,17 +898,42 @@ impl<C> Whitted<C> {
         mut containers: Cow<'a, Containers>,
         reflection: u32,
         hit: &Hit,
-        reflective: bool,
-        transparent: f32,
-        refractive_index: f32,
-    ) -> (Color, f32) {
-        if transparent <= 0.0 {
-            return (Color::black(), 1.0);
+        transmission: Transmission,
+    ) -> (Color, Color) {
+        if transmission.transparent <= 0.0 {
+            return (Color::black(), Color::white());
+        }
+
+        // White light crossing into or out of a dispersive medium bends by a different amount in
+        // each channel, so each channel is traced along its own path.
+        if self.band.is_none()
+            && (transmission.medium.dispersion > 0.0 || containers.is_dispersive())
+        {
+            let [(r, r_reflectance), (g, g_reflectance), (b, b_reflectance)] =
+                std::array::from_fn(|band| {
+                    self.band = Some(band);
+                    self.refracted_color(
+                        scene,
+                        root,
+                        containers.clone(),
+                        reflection,
+                        hit,
+                        transmission.clone(),
+                    )
+                });
+            self.band = None;
+            return (
+                Color::new(r.r, g.g, b.b),
+                Color::new(r_reflectance.r, g_reflectance.g, b_reflectance.b),
+            );
         }
 
-        let (n1, n2) = containers
-            .to_mut()
-            .refractive_indices(hit.node, refractive_index);
+        let Transmission {
+            transparent,
+            medium,
+            reflective,
+        } = transmission;
+        let (n1, n2) = containers.to_mut().refractive_indices(medium, self.band);
 
         let n_ratio = n1 / n2;
         let cos_i = hit.ray.direction.dot(&hit.normal);
@@ -228,17 +941,19 @@ impl<C> Whitted<C> {
 
         // Check for total internal reflection
         if sin2_t > 1.0 {
-            return (Color::black(), 1.0);
+            return (Color::black(), Color::white());
         }
 
-        let cos_t = f32::sqrt(1.0 - sin2_t);
+        let cos_t = Float::sqrt(1.0 - sin2_t);
 
-        // Step 2x min distance along the negated normal to ensure that we step into the object,
+        // Step twice the offset along the negated normal to ensure that we step into the object,
         // and are far enough away to not trigger a hit immediately.
-        let start = hit.ray.position - hit.normal.scale(self.config.min_dist * 2.0);
+        let offset = hit.normal_offset(&self.config, scene);
+        let start = hit.ray.position - hit.geometric.scale(offset * 2.0);
 
+        // The normal faces the ray, so `cos_i` is negative.
         let direction = Unit::new_unchecked(
-            hit.normal.scale(n_ratio * cos_i - cos_t) - hit.ray.direction.scale(n_ratio),
+            hit.ray.direction.scale(n_ratio) - hit.normal.scale(n_ratio * cos_i + cos_t),
         );
 
         let refract_ray = Ray::new(start, direction);
@@ -253,18 +968,637 @@ impl<C> Whitted<C> {
             0.0
         };
 
-        (color, schlick)
+        let schlick = schlick as f32;
+        (color, Color::new(schlick, schlick, schlick))
     }
 }
 
+/// A seed for the random choices made for the sample at `film`, which differs between the
+/// samples of a pixel and between pixels.
+fn film_seed(film: &Point2<Float>) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    film.x.to_bits().hash(&mut hasher);
+    film.y.to_bits().hash(&mut hasher);
+    hasher.finish()
+}
+
 impl<C: Camera> Integrator for Whitted<C> {
-    fn luminance(&mut self, scene: &Scene, root: NodeId, sample: &Sample) -> Color {
-        self.color_for_ray(
-            scene,
-            root,
-            Cow::Owned(Containers::default()),
-            self.camera.generate_ray(sample),
-            0,
-        )
+    fn luminance(&mut self, scene: &Scene, root: NodeId, sample: &Sample) -> Radiance {
+        self.prepare(scene, root);
+        let Some(ray) = self.camera.generate_ray(sample) else {
+            return Radiance {
+                color: Color::black(),
+                alpha: 0.0,
+            };
+        };
+        self.film = sample.film;
+        self.rng = StdRng::seed_from_u64(film_seed(&sample.film));
+
+        if !matches!(self.background, Background::Transparent) || self.max_reflections == 0 {
+            let containers = Cow::Owned(Containers::default());
+            return Radiance::opaque(self.color_for_ray(scene, root, containers, ray, 0));
+        }
+
+        // With a transparent background, a ray that escapes the scene is only covered by the
+        // volumes along it. Fog and the sky are part of the background, so they're left out.
+        let containers = Containers::default();
+        let Some(hit) = self.march_ray(scene, root, &containers, ray.clone()) else {
+            let (light, transmittance) =
+                self.integrate_volumes(scene, root, ray, self.config.max_dist);
+            return Radiance {
+                color: light,
+                alpha: 1.0 - transmittance,
+            };
+        };
+
+        let color = self.shade_ray(scene, root, Cow::Owned(containers), ray, Some(hit), 0);
+        Radiance::opaque(color)
+    }
+
+    fn guide(&mut self, scene: &Scene, root: NodeId, sample: &Sample) -> Guide {
+        self.prepare(scene, root);
+        let Some(ray) = self.camera.generate_ray(sample) else {
+            return Guide::default();
+        };
+        let Some(hit) = Hit::march(&self.config, scene, root, ray, false) else {
+            return Guide::default();
+        };
+
+        let albedo = match hit.material.map(|material| scene.material(material)) {
+            Some(
+                Material::Phong { pattern, .. }
+                | Material::Toon { pattern, .. }
+                | Material::Emissive { pattern, .. },
+            ) => hit.pattern_color(scene, root, *pattern),
+            Some(Material::Matcap { matcap, .. }) => matcap.color(&hit.ray.direction, &hit.normal),
+            None => Color::black(),
+        };
+
+        Guide {
+            albedo,
+            normal: hit.normal.into_inner().cast(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use nalgebra::{Point3, Vector3};
+
+    use super::*;
+    use crate::{
+        camera::{CanvasInfo, PinholeCamera},
+        scene::{Falloff, Fog, PatternId, Volume},
+        transform::Transform,
+    };
+
+    /// Render a ray through the center of concentric glass spheres, given as their radius,
+    /// absorption and priority, with an emissive backdrop behind them.
+    fn through_spheres(spheres: &[(Float, Color, u32)]) -> Color {
+        through_spheres_with(Scene::default(), spheres)
+    }
+
+    fn through_spheres_with(mut scene: Scene, spheres: &[(Float, Color, u32)]) -> Color {
+        let white = scene.solid(Color::white());
+        let mut nodes = Vec::new();
+        for (radius, absorption, priority) in spheres {
+            let glass = scene.phong(white, 0.0, 0.0, 0.0, 200.0, 0.0, 1.0, 1.0);
+            scene.set_absorption(glass, absorption.clone());
+            scene.set_priority(glass, *priority);
+            let sphere = scene.sphere(*radius).unwrap();
+            nodes.push(scene.paint(glass, sphere));
+        }
+
+        through_center(scene, nodes)
+    }
+
+    /// Render a ray through the center of `nodes`, with an emissive backdrop behind them.
+    fn through_center(mut scene: Scene, mut nodes: Vec<NodeId>) -> Color {
+        let white = scene.solid(Color::white());
+        nodes.push(glowing_wall(&mut scene, white, 3.));
+        let root = scene.group(nodes).unwrap();
+
+        let ray = Ray::new(Point3::new(0., 0., -3.), Vector3::z_axis());
+        color_along(&scene, root, ray)
+    }
+
+    /// An emissive wall of `pattern` across the z axis at `z`, facing the origin.
+    fn glowing_wall(scene: &mut Scene, pattern: PatternId, z: Float) -> NodeId {
+        let glow = scene.emissive(pattern);
+        let normal = if z < 0. {
+            Vector3::z_axis()
+        } else {
+            -Vector3::z_axis()
+        };
+        let wall = scene.plane(normal);
+        let wall = scene.transform(Transform::new().translate(&Vector3::new(0., 0., z)), wall);
+        scene.paint(glow, wall)
+    }
+
+    /// The color that a ray sees of `root`.
+    fn color_along(scene: &Scene, root: NodeId, ray: Ray) -> Color {
+        let info = CanvasInfo::new(1, 1);
+        let camera = PinholeCamera::new(&info, Transform::new(), math::consts::FRAC_PI_2);
+        let mut whitted = Whitted::new(camera, MarchConfig::default(), 10);
+        whitted.color_for_ray(scene, root, Cow::Owned(Containers::default()), ray, 0)
+    }
+
+    /// Render a ray through the side of a glass ball with the given dispersion, which bends it
+    /// across to the far side of a backdrop that brightens from left to right.
+    fn through_lens(dispersion: Float) -> Color {
+        let mut scene = Scene::default();
+        let white = scene.solid(Color::white());
+        let glass = scene.phong(white, 0.0, 0.0, 0.0, 200.0, 0.0, 1.0, 1.5);
+        scene.set_dispersion(glass, dispersion);
+        let sphere = scene.sphere(1.).unwrap();
+        let ball = scene.paint(glass, sphere);
+
+        let ramp = scene.ramp(
+            Vector3::x(),
+            vec![(-2., Color::black()), (2., Color::white())],
+        );
+        let wall = glowing_wall(&mut scene, ramp, 3.);
+        let root = scene.group(vec![ball, wall]).unwrap();
+
+        let ray = Ray::new(Point3::new(0.5, 0., -3.), Vector3::z_axis());
+        color_along(&scene, root, ray)
+    }
+
+    fn assert_color(expected: Color, actual: Color) {
+        assert!(
+            (expected.r - actual.r).abs() < 0.01
+                && (expected.g - actual.g).abs() < 0.01
+                && (expected.b - actual.b).abs() < 0.01,
+            "expected {:?}, found {:?}",
+            expected,
+            actual
+        );
+    }
+
+    #[test]
+    fn test_absorption() {
+        let clear = through_spheres(&[(1., Color::black(), 0)]);
+        assert_color(Color::white(), clear);
+
+        // The ray travels through two units of the medium.
+        let tinted = through_spheres(&[(1., Color::new(0.5, 0., 0.), 0)]);
+        assert_color(Color::new(f32::exp(-1.), 1., 1.), tinted);
+    }
+
+    #[test]
+    fn test_priority() {
+        let red = Color::new(0.5, 0., 0.);
+
+        // A clear sphere with a higher priority displaces the absorbing medium around it, so the
+        // ray only travels through one unit of it.
+        let bubble = through_spheres(&[(1., red.clone(), 1), (0.5, Color::black(), 2)]);
+        assert_color(Color::new(f32::exp(-0.5), 1., 1.), bubble);
+
+        // With a lower priority the inner sphere is invisible.
+        let hidden = through_spheres(&[(1., red, 1), (0.5, Color::black(), 0)]);
+        assert_color(Color::new(f32::exp(-1.), 1., 1.), hidden);
+    }
+
+    #[test]
+    fn test_refraction() {
+        // Through the ball the ray reaches the backdrop just left of the axis, where it's darker
+        // than halfway. Bending it the wrong way sends it back out of the ball, missing the
+        // backdrop.
+        let color = through_lens(0.);
+        assert!(color.r > 0.2 && color.r < 0.45, "{:?}", color);
+    }
+
+    #[test]
+    fn test_dispersion() {
+        // Without dispersion every channel follows the same path.
+        let clear = through_lens(0.);
+        assert!(
+            (clear.r - clear.g).abs() < 1e-4 && (clear.g - clear.b).abs() < 1e-4,
+            "{:?}",
+            clear
+        );
+
+        // Blue bends the most, so it lands furthest to the left on the darker part of the backdrop.
+        let prism = through_lens(0.1);
+        assert!(prism.r > prism.g && prism.g > prism.b, "{:?}", prism);
+        assert!((prism.g - clear.g).abs() < 1e-4, "{:?} {:?}", prism, clear);
+    }
+
+    #[test]
+    fn test_roughness() {
+        // A ray is reflected by a mirror back towards a wall that's black on the left and white
+        // on the right, landing just right of the edge.
+        let render = |roughness: f32| {
+            let mut scene = Scene::default();
+            let black = scene.solid(Color::black());
+            let mirror = scene.phong(black, 0.0, 0.0, 0.0, 200.0, 1.0, 0.0, 1.0);
+            scene.set_gloss(mirror, roughness, 64);
+            let plane = scene.plane(-Vector3::z_axis());
+            let mirror = scene.paint(mirror, plane);
+
+            let edge = scene.ramp(
+                Vector3::x(),
+                vec![(-0.001, Color::black()), (0.001, Color::white())],
+            );
+            let wall = glowing_wall(&mut scene, edge, -5.);
+            let root = scene.group(vec![mirror, wall]).unwrap();
+
+            let ray = Ray::new(Point3::new(0.05, 0., -3.), Vector3::z_axis());
+            color_along(&scene, root, ray)
+        };
+
+        // A mirror shows the wall as it is, while a rough surface blurs the edge.
+        assert_color(Color::white(), render(0.));
+        let blurred = render(0.2).r;
+        assert!(blurred > 0.2 && blurred < 0.8, "{}", blurred);
+    }
+
+    #[test]
+    fn test_fresnel() {
+        // Rays are reflected by a slightly reflective surface back towards a glowing wall.
+        let render = |fresnel: bool, direction: Vector3<Float>| {
+            let mut scene = Scene::default();
+            let black = scene.solid(Color::black());
+            let floor = scene.phong(black, 0.0, 0.0, 0.0, 200.0, 0.2, 0.0, 1.0);
+            scene.set_fresnel(floor, fresnel);
+            let plane = scene.plane(-Vector3::z_axis());
+            let floor = scene.paint(floor, plane);
+
+            let white = scene.solid(Color::white());
+            let wall = glowing_wall(&mut scene, white, -5.);
+            let root = scene.group(vec![floor, wall]).unwrap();
+
+            let ray = Ray::new(Point3::new(0., 0., -1.), Unit::new_normalize(direction));
+            color_along(&scene, root, ray).r
+        };
+
+        // Seen head on the surface reflects as much as it's reflective, and more at a glancing
+        // angle, unless Fresnel is turned off.
+        let head_on = Vector3::new(0., 0., 1.);
+        let grazing = Vector3::new(1., 0., 0.2);
+        assert!((render(true, head_on) - 0.2).abs() < 1e-3);
+        let cos = grazing.normalize().z as f32;
+        let expected = 0.2 + 0.8 * (1. - cos).powi(5);
+        assert!((render(true, grazing) - expected).abs() < 1e-3);
+        assert!((render(false, grazing) - 0.2).abs() < 1e-3);
+    }
+
+    #[test]
+    fn test_film() {
+        // Rays are reflected by a black surface coated in soap back towards a glowing wall.
+        let render = |direction: Vector3<Float>| {
+            let mut scene = Scene::default();
+            let black = scene.solid(Color::black());
+            let floor = scene.phong(black, 0., 0., 0., 200., 0., 0., 1.);
+            scene.set_film(floor, 400., 1.33);
+            let plane = scene.plane(-Vector3::z_axis());
+            let floor = scene.paint(floor, plane);
+
+            let white = scene.solid(Color::white());
+            let wall = glowing_wall(&mut scene, white, -5.);
+            let root = scene.group(vec![floor, wall]).unwrap();
+
+            let ray = Ray::new(Point3::new(0., 0., -1.), Unit::new_normalize(direction));
+            color_along(&scene, root, ray)
+        };
+
+        // Each channel is reflected as much as the film reflects its wavelength, which changes
+        // with the angle that it's seen from.
+        for direction in [Vector3::new(0., 0., 1.), Vector3::new(1., 0., 0.5)] {
+            let cos = direction.normalize().z;
+            let color = render(direction);
+            for (channel, wavelength) in [color.r, color.g, color.b]
+                .into_iter()
+                .zip(FILM_WAVELENGTHS)
+            {
+                let expected = math::thin_film(cos, 1., 1.33, 1., 400., wavelength) as f32;
+                assert!(
+                    (channel - expected).abs() < 1e-3,
+                    "{} {}",
+                    channel,
+                    expected
+                );
+            }
+        }
+        let head_on = render(Vector3::new(0., 0., 1.));
+        assert!(head_on.r > 5. * head_on.g);
+        let grazing = render(Vector3::new(1., 0., 0.5));
+        assert!(grazing.g > grazing.r);
+    }
+
+    #[test]
+    fn test_fog() {
+        let fog = Fog {
+            color: Color::new(0.2, 0.4, 0.6),
+            density: 0.1,
+        };
+
+        let foggy = || Scene {
+            fog: Some(fog.clone()),
+            ..Scene::default()
+        };
+
+        // The backdrop is six units away from the ray origin.
+        let expected = fog.apply(Color::white(), 6.);
+        assert_color(expected, through_spheres_with(foggy(), &[]));
+
+        // The inside of the sphere isn't filled with fog.
+        let expected = fog.apply(Color::white(), 4.);
+        assert_color(
+            expected,
+            through_spheres_with(foggy(), &[(1., Color::black(), 0)]),
+        );
+    }
+
+    #[test]
+    fn test_emitter() {
+        let mut scene = Scene::default();
+        let white = scene.solid(Color::white());
+        let matte = scene.phong(white, 0., 1., 0., 200., 0., 0., 1.);
+        let glow = scene.emissive(white);
+
+        let plane = scene.plane(Unit::new_normalize(Vector3::new(0., 1., 0.)));
+        let floor = scene.paint(matte, plane);
+        let sphere = scene.sphere(0.5).unwrap();
+        let lamp = scene.paint(glow, sphere);
+        let lamp = scene.transform(Transform::new().translate(&Vector3::new(0., 2., 0.)), lamp);
+        let root = scene.group(vec![floor, lamp]).unwrap();
+
+        let info = CanvasInfo::new(1, 1);
+        let camera = PinholeCamera::new(&info, Transform::new(), math::consts::FRAC_PI_2);
+        let mut whitted = Whitted::new(camera, MarchConfig::default(), 10);
+        whitted.prepare(&scene, root);
+        whitted.rng = StdRng::seed_from_u64(0);
+
+        // Average enough rays at the point under the lamp for the noise to settle.
+        let rays = 4000;
+        let mut total = Color::black();
+        for _ in 0..rays {
+            let ray = Ray::new(
+                Point3::new(0., 1., -1.),
+                Unit::new_normalize(Vector3::new(0., -1., 1.)),
+            );
+            total += whitted.color_for_ray(&scene, root, Cow::Owned(Containers::default()), ray, 0);
+        }
+
+        // A sphere lights a point facing it like a point light at its center, dimmed by the square
+        // of its radius over the distance to its center.
+        let expected = 0.5 * 0.5 / (2. * 2.);
+        let lit = total.r / rays as f32;
+        assert!((lit / expected - 1.).abs() < 0.1, "{}", lit);
+
+        // The points picked on the lamp only depend on the sample, so that every thread and
+        // worker renders a pixel the same way.
+        let info = CanvasInfo::new(8, 8);
+        let eye = Transform::look_at(
+            &Point3::new(0., 1., -2.),
+            &Point3::new(0., 0., 0.),
+            &Vector3::y(),
+        );
+        let camera = PinholeCamera::new(&info, eye, math::consts::FRAC_PI_2);
+        let luminance = |samples: &[(Float, Float)]| {
+            let mut whitted = Whitted::new(camera.clone(), MarchConfig::default(), 10);
+            let mut color = Color::black();
+            for &(x, y) in samples {
+                color = whitted.luminance(&scene, root, &Sample::new(x, y)).color;
+            }
+            [color.r, color.g, color.b]
+        };
+        let lit = luminance(&[(4.25, 6.75)]);
+        assert!(lit[0] > 0., "{:?}", lit);
+        assert_eq!(lit, luminance(&[(3.25, 6.75), (4.25, 6.75)]));
+
+        // The integrators built for a render share the emitters, rather than each finding them.
+        let builder = WhittedBuilder::new(camera, MarchConfig::default(), 10);
+        let mut integrators = [builder.build(), builder.build()];
+        for integrator in integrators.iter_mut() {
+            integrator.luminance(&scene, root, &Sample::new(4.25, 6.75));
+        }
+        let shared = builder.emitters.lock().unwrap();
+        assert_eq!(Arc::strong_count(&shared.as_ref().unwrap().1), 3);
+    }
+
+    #[test]
+    fn test_toon() {
+        let render = |rim: Color, outline: Option<Color>, outline_width: f32| {
+            let mut scene = Scene::default();
+            let white = scene.solid(Color::white());
+            let toon = scene.toon(white, 0., 2);
+            scene.set_rim(toon, rim, 0.3);
+            scene.set_outline(toon, outline, outline_width);
+            let sphere = scene.sphere(1.).unwrap();
+            let root = scene.paint(toon, sphere);
+
+            // The light meets the front of the sphere at an angle whose cosine is 0.6.
+            scene.point_light(
+                Point3::new(80., 0., -61.),
+                Color::white(),
+                1.,
+                Falloff::None,
+            );
+
+            let ray = Ray::new(Point3::new(0., 0., -3.), Vector3::z_axis());
+            color_along(&scene, root, ray)
+        };
+
+        // With two bands, the light is rounded up to full brightness.
+        assert_color(Color::white(), render(Color::black(), None, 0.2));
+
+        // The front of the sphere faces the eye, so only outlines wider than that cover it.
+        let red = Color::new(1., 0., 0.);
+        assert_color(Color::white(), render(red.clone(), Some(red.clone()), 0.2));
+        assert_color(red.clone(), render(Color::black(), Some(red), 1.1));
+    }
+
+    #[test]
+    fn test_backface() {
+        let render = |backface: Backface| {
+            let mut scene = Scene::default();
+            let white = scene.solid(Color::white());
+            let matte = scene.phong(white, 0., 1., 0., 200., 0., 0., 1.);
+            scene.set_backface(matte, backface);
+            let sphere = scene.sphere(2.).unwrap();
+            let shell = scene.paint(matte, sphere);
+
+            let red = scene.solid(Color::new(1., 0., 0.));
+            let wall = glowing_wall(&mut scene, red, 3.);
+            let root = scene.group(vec![shell, wall]).unwrap();
+
+            scene.point_light(Point3::new(0., 0., -1.), Color::white(), 1., Falloff::None);
+
+            // The camera and the light are both inside the sphere.
+            let ray = Ray::new(Point3::origin(), Vector3::z_axis());
+            color_along(&scene, root, ray)
+        };
+
+        // The far side of the sphere faces away from the light unless it's turned around, and
+        // culling it shows the wall behind.
+        assert_color(Color::black(), render(Backface::OneSided));
+        assert_color(Color::white(), render(Backface::TwoSided));
+        assert_color(Color::white(), render(Backface::Flip));
+        assert_color(Color::new(1., 0., 0.), render(Backface::Cull));
+    }
+
+    #[test]
+    fn test_shadow_cache() {
+        // A ball casts a shadow across a floor from a light off to the side.
+        let mut scene = Scene::default();
+        let white = scene.solid(Color::white());
+        let matte = scene.phong(white, 0., 1., 0., 200., 0., 0., 1.);
+        let plane = scene.plane(Unit::new_normalize(Vector3::new(0., 1., 0.)));
+        let sphere = scene.sphere(1.).unwrap();
+        let ball = scene.transform(
+            Transform::new().translate(&Vector3::new(0., 1., 0.)),
+            sphere,
+        );
+        let root = scene.group(vec![plane, ball]).unwrap();
+        let root = scene.paint(matte, root);
+        scene.point_light(Point3::new(-4., 2., 0.), Color::white(), 1., Falloff::None);
+
+        let info = CanvasInfo::new(1, 1);
+        let camera = PinholeCamera::new(&info, Transform::new(), math::consts::FRAC_PI_2);
+        let shade = |cache: Option<Float>, points: &[(Float, Float)]| {
+            let mut whitted = Whitted::new(camera.clone(), MarchConfig::default(), 10);
+            whitted.occlusion = cache.map(OcclusionCache::new);
+            whitted.prepare(&scene, root);
+            points
+                .iter()
+                .map(|&(x, z)| {
+                    let ray = Ray::new(Point3::new(x, 5., z), -Vector3::y_axis());
+                    let color = whitted.color_for_ray(
+                        &scene,
+                        root,
+                        Cow::Owned(Containers::default()),
+                        ray,
+                        0,
+                    );
+                    color.r
+                })
+                .collect::<Vec<_>>()
+        };
+
+        // One point is lit and the other is in the shadow, without a cache.
+        let (lit, shadowed) = ((2.9, 1.9), (2.1, 1.1));
+        let colors = shade(None, &[lit, shadowed]);
+        assert!(colors[0] > 0. && colors[1] == 0., "{:?}", colors);
+
+        // Both are in the same cell of the cache, whose center is in the shadow, so both are
+        // shadowed whichever is shaded first.
+        assert_eq!(vec![0., 0.], shade(Some(1.), &[lit, shadowed]));
+        assert_eq!(vec![0., 0.], shade(Some(1.), &[shadowed, lit]));
+    }
+
+    #[test]
+    fn test_subsurface() {
+        // A black slab of the given thickness, lit only from behind.
+        let render = |thickness: Float, depth: Float| {
+            let mut scene = Scene::default();
+            let black = scene.solid(Color::black());
+            let wax = scene.phong(black, 0., 1., 0., 200., 0., 0., 1.);
+            scene.set_scatter(wax, Color::new(1., 0.5, 0.), depth);
+            let slab = scene.rect(2., 2., thickness / 2.).unwrap();
+            let root = scene.paint(wax, slab);
+
+            scene.point_light(Point3::new(0., 0., 5.), Color::white(), 1., Falloff::None);
+
+            let ray = Ray::new(Point3::new(0., 0., -3.), Vector3::z_axis());
+            color_along(&scene, root, ray)
+        };
+
+        // Light from straight behind comes through dimmed exponentially by the thickness, and
+        // tinted by the scatter color.
+        let thin = render(0.1, 0.5);
+        let expected = f32::exp(-0.2);
+        assert!((thin.r - expected).abs() < 0.01, "{}", thin.r);
+        assert!((thin.g - expected * 0.5).abs() < 0.01, "{}", thin.g);
+        assert_eq!(0., thin.b);
+
+        // Thick slabs hide the light.
+        assert_color(Color::black(), render(4., 0.5));
+    }
+
+    #[test]
+    fn test_volume() {
+        let through_volume = |volume: Volume| {
+            let mut scene = Scene::default();
+            let sphere = scene.sphere(1.).unwrap();
+            let cloud = scene.volume(volume, sphere);
+            through_center(scene, vec![cloud])
+        };
+
+        // Density grows linearly to the center of the sphere, giving the ray an optical depth
+        // equal to the density scale.
+        let absorbing = through_volume(Volume {
+            scattering: 0.,
+            step: 0.01,
+            ..Volume::default()
+        });
+        let transmittance = f32::exp(-1.);
+        assert_color(
+            Color::new(transmittance, transmittance, transmittance),
+            absorbing,
+        );
+
+        // Emission replaces exactly the light that's absorbed.
+        let glowing = through_volume(Volume {
+            density_scale: 2.,
+            scattering: 0.,
+            emission: Color::new(1., 0., 0.),
+            step: 0.01,
+            ..Volume::default()
+        });
+        let transmittance = f32::exp(-2.);
+        assert_color(Color::new(1., transmittance, transmittance), glowing);
+    }
+
+    #[test]
+    fn test_transparent_background() {
+        let render = |scene: &Scene, root: NodeId| {
+            let info = CanvasInfo::new(1, 1);
+            let camera = PinholeCamera::new(&info, Transform::new(), math::consts::FRAC_PI_2);
+            let builder = WhittedBuilder::new(camera, MarchConfig::default(), 10)
+                .with_background(Background::Transparent);
+            builder
+                .build()
+                .luminance(scene, root, &Sample::new(0.5, 0.5))
+        };
+
+        let mut scene = Scene::default();
+        let white = scene.solid(Color::white());
+        let glow = scene.emissive(white);
+        let sphere = scene.sphere(1.).unwrap();
+        let ball = scene.paint(glow, sphere);
+        let ball = scene.transform(Transform::new().translate(&Vector3::new(0., 0., 3.)), ball);
+        let cloud = scene.volume(
+            Volume {
+                scattering: 0.,
+                step: 0.01,
+                ..Volume::default()
+            },
+            sphere,
+        );
+        let cloud = scene.transform(Transform::new().translate(&Vector3::new(0., 0., 3.)), cloud);
+        let behind = scene.transform(
+            Transform::new().translate(&Vector3::new(0., 0., -3.)),
+            sphere,
+        );
+
+        // Nothing covers a ray that escapes the scene, however bright the sky is.
+        scene.diffuse_light(Color::white(), Color::white(), 1.);
+        let escaped = render(&scene, behind);
+        assert_eq!(0., escaped.alpha);
+        assert_color(Color::black(), escaped.color);
+
+        // Volumes only partly cover the background.
+        let hazy = render(&scene, cloud);
+        assert!(
+            (hazy.alpha - (1. - f32::exp(-1.))).abs() < 0.01,
+            "{}",
+            hazy.alpha
+        );
+
+        let solid = render(&scene, ball);
+        assert_eq!(1., solid.alpha);
+        assert_color(Color::white(), solid.color);
     }
 }
@@ -0,0 +1,54 @@
+//! A renderer for scenes built from signed distance functions, described with a small
+//! s-expression language. The `rendrs` binary is a thin command line wrapper around this crate.
+
+// Geometry is converted to `f32` where it meets color, which is a no-op unless the `f64` feature
+// is enabled.
+#![cfg_attr(not(feature = "f64"), allow(clippy::unnecessary_cast))]
+
+pub mod bench;
+mod bvh;
+mod camera;
+mod canvas;
+mod film;
+mod fractal;
+mod gpu;
+mod grid;
+mod heightfield;
+mod integrator;
+mod lsystem;
+mod matcap;
+mod math;
+pub mod mesh;
+mod metaballs;
+mod obj;
+pub mod parser;
+mod polygon;
+mod ray;
+pub mod remote;
+pub mod render;
+mod sampler;
+mod scene;
+mod sdf;
+pub mod shader;
+mod text;
+mod transform;
+#[cfg(feature = "wasm")]
+pub mod wasm;
+#[cfg(feature = "native")]
+pub mod watch;
+#[cfg(feature = "native")]
+pub mod web;
+
+// The pieces that a custom integrator is built from, for registering with
+// [`parser::Plugins::with_integrator`].
+pub use camera::{Camera, CanvasInfo, Sample};
+pub use canvas::{Color, Guide};
+pub use integrator::{Hit, Integrator, IntegratorBuilder, Radiance};
+pub use math::Float;
+pub use ray::Ray;
+pub use sampler::Sampler;
+pub use scene::{Distance, MarchConfig, NodeId, Scene};
+
+// Custom primitives, for [`Scene::custom`] and [`parser::Plugins::with_primitive`].
+pub use bvh::BoundingBox;
+pub use sdf::Sdf;
@@ -0,0 +1,217 @@
+//! Lindenmayer systems, which grow a string by rewriting each of its symbols at once, and a
+//! turtle that draws the result as capsules and spheres. This is enough for plants and other
+//! branching structures.
+//!
+//! The turtle understands these symbols, and ignores any others:
+//!
+//! * `F` draws a capsule forward, and `f` moves forward without drawing
+//! * `+` and `-` turn left and right, `&` and `^` pitch down and up, and `\` and `/` roll left
+//!   and right, all by the angle
+//! * `|` turns around
+//! * `[` and `]` save and restore the turtle, to start and end a branch
+//! * `!` shrinks the capsules drawn after it
+//! * `@` draws a sphere, for leaves and buds
+
+use anyhow::{bail, Error};
+use nalgebra::{Point3, Rotation3, Unit, Vector3};
+use std::collections::HashMap;
+
+use crate::{
+    math::{self, consts, Float},
+    scene::{NodeId, Scene},
+    transform::Transform,
+};
+
+type Result<T> = std::result::Result<T, Error>;
+
+/// The longest that a string may grow, which keeps a few rules from using all of the memory.
+pub const MAX_SYMBOLS: usize = 1 << 20;
+
+/// The most times that the string may be rewritten.
+pub const MAX_ITERATIONS: u32 = 32;
+
+#[derive(Debug, Clone, Default)]
+pub struct LSystem {
+    pub axiom: String,
+    pub rules: HashMap<char, String>,
+    pub iterations: u32,
+}
+
+impl LSystem {
+    /// Rewrite the axiom `iterations` times. Symbols without a rule are copied as they are.
+    pub fn expand(&self) -> Result<String> {
+        if self.iterations > MAX_ITERATIONS {
+            bail!(
+                "An L-system can't be rewritten more than {} times",
+                MAX_ITERATIONS
+            );
+        }
+
+        let mut current = self.axiom.clone();
+        for _ in 0..self.iterations {
+            let mut next = String::new();
+            for c in current.chars() {
+                match self.rules.get(&c) {
+                    Some(rule) => next.push_str(rule),
+                    None => next.push(c),
+                }
+                if next.len() > MAX_SYMBOLS {
+                    bail!("The L-system grows past {} symbols", MAX_SYMBOLS);
+                }
+            }
+            current = next;
+        }
+        Ok(current)
+    }
+}
+
+/// How the turtle draws.
+#[derive(Debug, Clone)]
+pub struct Turtle {
+    /// The angle of each turn, in radians.
+    pub angle: Float,
+
+    /// The length of each step forward.
+    pub length: Float,
+
+    /// The radius of the capsules.
+    pub radius: Float,
+
+    /// The factor that `!` scales the radius by.
+    pub shrink: Float,
+
+    /// The radius of the spheres.
+    pub leaf: Float,
+}
+
+impl Default for Turtle {
+    fn default() -> Self {
+        Self {
+            angle: math::deg_to_rad(25.),
+            length: 1.,
+            radius: 0.1,
+            shrink: 0.7,
+            leaf: 0.2,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct State {
+    position: Point3<Float>,
+
+    /// The turtle's heading, left and up directions, as the columns of a rotation.
+    orientation: Rotation3<Float>,
+
+    radius: Float,
+}
+
+impl Turtle {
+    /// Follow the symbols in `path`, adding the shapes drawn to `scene`. The turtle starts at the
+    /// origin heading up the y axis, with its left towards negative x, so that turns with `+` and
+    /// `-` stay in the xy plane.
+    pub fn draw(&self, scene: &mut Scene, path: &str) -> Result<Vec<NodeId>> {
+        let mut state = State {
+            position: Point3::origin(),
+            orientation: Rotation3::from_basis_unchecked(&[
+                Vector3::y(),
+                -Vector3::x(),
+                Vector3::z(),
+            ]),
+            radius: self.radius,
+        };
+        let mut stack = Vec::new();
+        let mut nodes = Vec::new();
+
+        let turn = |state: &mut State, axis: Unit<Vector3<Float>>, angle: Float| {
+            state.orientation *= Rotation3::from_axis_angle(&axis, angle);
+        };
+
+        for c in path.chars() {
+            match c {
+                'F' | 'f' => {
+                    let next = state.position + state.orientation * Vector3::x() * self.length;
+                    if c == 'F' {
+                        nodes.push(scene.capsule(state.position, next, state.radius)?);
+                    }
+                    state.position = next;
+                }
+
+                // Turns are about the up direction, pitches about the left direction, and rolls
+                // about the heading.
+                '+' => turn(&mut state, Vector3::z_axis(), self.angle),
+                '-' => turn(&mut state, Vector3::z_axis(), -self.angle),
+                '&' => turn(&mut state, Vector3::y_axis(), self.angle),
+                '^' => turn(&mut state, Vector3::y_axis(), -self.angle),
+                '\\' => turn(&mut state, Vector3::x_axis(), self.angle),
+                '/' => turn(&mut state, Vector3::x_axis(), -self.angle),
+                '|' => turn(&mut state, Vector3::z_axis(), consts::PI),
+
+                '[' => stack.push(state.clone()),
+                ']' => {
+                    let Some(saved) = stack.pop() else {
+                        bail!("The L-system closes a branch with `]` that it never opened");
+                    };
+                    state = saved;
+                }
+
+                '!' => state.radius *= self.shrink,
+
+                '@' => {
+                    let sphere = scene.sphere(self.leaf)?;
+                    let offset = state.position.coords;
+                    nodes.push(scene.transform(Transform::new().translate(&offset), sphere));
+                }
+
+                _ => {}
+            }
+        }
+
+        Ok(nodes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lsystem() {
+        let system = LSystem {
+            axiom: String::from("F"),
+            rules: HashMap::from([('F', String::from("F[+F]F"))]),
+            iterations: 2,
+        };
+        let path = system.expand().unwrap();
+        assert_eq!("F[+F]F[+F[+F]F]F[+F]F", path);
+
+        let turtle = Turtle {
+            angle: math::deg_to_rad(90.),
+            ..Turtle::default()
+        };
+        let mut scene = Scene::default();
+        let nodes = turtle.draw(&mut scene, "FF[+F@]-F").unwrap();
+        assert_eq!(5, nodes.len());
+
+        // The branch turns left towards negative x, and the sphere sits at its tip.
+        let root = scene.group(nodes).unwrap();
+        let bounds = scene.bounding_box(root);
+        assert!(bounds.contains(&Point3::new(-1.2, 2., 0.)));
+        assert!(bounds.contains(&Point3::new(1., 2., 0.)));
+        assert!(!bounds.contains(&Point3::new(0., 3.2, 0.)));
+
+        assert!(turtle.draw(&mut scene, "F]").is_err());
+
+        let explosive = LSystem {
+            iterations: MAX_ITERATIONS,
+            ..system
+        };
+        assert!(explosive.expand().is_err());
+        assert!(LSystem {
+            iterations: MAX_ITERATIONS + 1,
+            ..LSystem::default()
+        }
+        .expand()
+        .is_err());
+    }
+}
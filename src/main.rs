@@ -1,27 +1,111 @@
 use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
 
 use anyhow::Error;
 use clap::{Parser, Subcommand};
+use tracing_subscriber::{fmt::format::FmtSpan, EnvFilter};
 
-mod bvh;
-mod camera;
-mod canvas;
-mod integrator;
-mod math;
-mod obj;
-mod parser;
-mod ray;
-mod render;
-mod sampler;
-mod scene;
-mod transform;
-mod web;
+use rendrs::{bench, mesh, parser, remote, render, shader, watch, web};
 
 #[derive(Parser, Debug)]
 #[clap(author = "Trevor Elliott", version = "0.2")]
 struct Options {
     #[clap(subcommand)]
     command: Command,
+
+    #[clap(
+        long,
+        global = true,
+        help = "The messages to log, as a level like `debug` or a filter like `rendrs::render=trace`, instead of RUST_LOG"
+    )]
+    log_level: Option<String>,
+
+    #[clap(long, global = true, help = "Log messages as lines of json")]
+    log_json: bool,
+}
+
+/// The arguments of the commands that render the targets in a scene.
+#[derive(clap::Args, Debug)]
+struct RenderArgs {
+    #[clap(short,
+       long,
+       help = "The number of threads to spawn",
+       default_value_t = num_cpus::get() as u64,
+       value_parser = clap::value_parser!(u64).range(1..=num_cpus::get() as u64),
+    )]
+    threads: u64,
+
+    #[clap(
+        short,
+        long,
+        help = "The backend to render with",
+        value_enum,
+        default_value_t = render::Backend::Cpu
+    )]
+    backend: render::Backend,
+
+    #[clap(
+        short,
+        long = "flag",
+        help = "Enable the `if-flag` forms in the scene that test this name"
+    )]
+    flags: Vec<String>,
+
+    #[clap(flatten)]
+    overrides: parser::Overrides,
+
+    #[clap(
+        long,
+        help = "Fail the render when a node produces a NaN or infinite distance"
+    )]
+    strict: bool,
+
+    #[clap(
+        long,
+        help = "Listen on this address for workers to share cpu renders with"
+    )]
+    listen: Option<String>,
+
+    #[clap(
+        long,
+        requires = "listen",
+        help = "Wait for this many workers to connect before rendering"
+    )]
+    workers: Option<usize>,
+
+    #[clap(
+        long,
+        help = "Run the cpu renders in the scene at once, sharing the threads between them"
+    )]
+    concurrent: bool,
+}
+
+impl RenderArgs {
+    /// The settings to render with, after waiting for the workers to connect when listening for
+    /// them.
+    fn settings(&self) -> Result<render::Settings, Error> {
+        let coordinator = match &self.listen {
+            Some(addr) => {
+                let coordinator = remote::Coordinator::listen(addr.as_str())?;
+                if let Some(workers) = self.workers {
+                    coordinator.wait_for(workers);
+                }
+                Some(Arc::new(coordinator))
+            }
+            None => None,
+        };
+
+        Ok(render::Settings {
+            threads: self.threads as usize,
+            backend: self.backend,
+            strict: self.strict,
+            coordinator,
+            in_memory: false,
+            concurrent: self.concurrent,
+            cache: None,
+        })
+    }
 }
 
 #[derive(Subcommand, Debug)]
@@ -43,11 +127,65 @@ enum Command {
         )]
         threads: u64,
 
-        #[clap(help = "The scene file to render")]
+        #[clap(
+            short,
+            long = "flag",
+            help = "Enable the `if-flag` forms in the scene that test this name"
+        )]
+        flags: Vec<String>,
+
+        #[clap(
+            long,
+            help = "Fail the render when a node produces a NaN or infinite distance"
+        )]
+        strict: bool,
+
+        #[clap(
+            long,
+            help = "The number of renders kept for comparison in the ui",
+            default_value_t = 8,
+            value_parser = clap::value_parser!(u64).range(1..),
+        )]
+        history: u64,
+
+        #[clap(help = "The scene file to render, or a directory of scenes to pick from")]
         scene: String,
     },
 
+    /// Render the targets in the scene.
     Render {
+        #[clap(flatten)]
+        args: RenderArgs,
+
+        #[clap(help = "The scene file to render")]
+        scene: String,
+    },
+
+    /// Render the scene, and render it again each time it's edited.
+    Watch {
+        #[clap(flatten)]
+        args: RenderArgs,
+
+        #[clap(help = "The scene file to render")]
+        scene: String,
+    },
+
+    /// Render tiles for a coordinator started with `render --listen` or `watch --listen`.
+    Worker {
+        #[clap(short,
+           long,
+           help = "The number of tiles to render at once",
+           default_value_t = num_cpus::get() as u64,
+           value_parser = clap::value_parser!(u64).range(1..),
+        )]
+        threads: u64,
+
+        #[clap(long, help = "The address of the coordinator")]
+        connect: String,
+    },
+
+    /// Time renders of the built-in benchmark scenes.
+    Bench {
         #[clap(short,
            long,
            help = "The number of threads to spawn",
@@ -56,33 +194,350 @@ enum Command {
         )]
         threads: u64,
 
-        #[clap(help = "The scene file to render")]
+        #[clap(
+            short,
+            long,
+            help = "The backend to render with",
+            value_enum,
+            default_value_t = render::Backend::Cpu
+        )]
+        backend: render::Backend,
+
+        #[clap(
+            short,
+            long,
+            help = "The number of times to render each scene",
+            default_value_t = 3,
+            value_parser = clap::value_parser!(u64).range(1..),
+        )]
+        iterations: u64,
+
+        #[clap(help = "The scenes to render, or all of them if none are given")]
+        scenes: Vec<String>,
+    },
+
+    /// Report problems in the scene description without rendering it.
+    Check {
+        #[clap(
+            short,
+            long = "flag",
+            help = "Enable the `if-flag` forms in the scene that test this name"
+        )]
+        flags: Vec<String>,
+
+        #[clap(help = "The scene file to check")]
+        scene: String,
+    },
+
+    /// Describe the contents of the scene: its node graph, what it contains, and its bounds.
+    Info {
+        #[clap(
+            short,
+            long = "flag",
+            help = "Enable the `if-flag` forms in the scene that test this name"
+        )]
+        flags: Vec<String>,
+
+        #[clap(
+            long,
+            help = "Also write the node graph to this file in Graphviz dot format"
+        )]
+        dot: Option<PathBuf>,
+
+        #[clap(help = "The scene file to describe")]
+        scene: String,
+    },
+
+    /// Print the signed distance function of a render as GLSL or WGSL.
+    Export {
+        #[clap(
+            short,
+            long = "flag",
+            help = "Enable the `if-flag` forms in the scene that test this name"
+        )]
+        flags: Vec<String>,
+
+        #[clap(
+            short,
+            long,
+            value_enum,
+            default_value_t = shader::Language::Glsl,
+            help = "The shading language to export to"
+        )]
+        language: shader::Language,
+
+        #[clap(long, help = "Export the render with this name, instead of the first")]
+        render: Option<String>,
+
+        #[clap(help = "The scene file to export")]
+        scene: String,
+    },
+
+    /// Convert a node of the scene into a triangle mesh, written as STL or OBJ.
+    Mesh {
+        #[clap(
+            short,
+            long = "flag",
+            help = "Enable the `if-flag` forms in the scene that test this name"
+        )]
+        flags: Vec<String>,
+
+        #[clap(
+            long,
+            help = "The name of the node to convert, instead of the root of the first render"
+        )]
+        node: Option<String>,
+
+        #[clap(
+            short,
+            long,
+            default_value_t = 128,
+            help = "The number of cells along the longest side of the node's bounding box"
+        )]
+        resolution: usize,
+
+        #[clap(
+            short,
+            long,
+            help = "The file to write, as binary STL or OBJ depending on its extension"
+        )]
+        output: PathBuf,
+
+        #[clap(help = "The scene file to read")]
         scene: String,
     },
+
+    /// Print the scene description with all conditionals resolved.
+    Expand {
+        #[clap(
+            short,
+            long = "flag",
+            help = "Enable the `if-flag` forms in the scene that test this name"
+        )]
+        flags: Vec<String>,
+
+        #[clap(help = "The scene file to expand")]
+        scene: String,
+    },
+}
+
+fn print_output(output: render::Output) {
+    match output {
+        render::Output::File { path, preview } => {
+            println!("Wrote file {}", path.to_str().unwrap());
+            if let Some(preview) = preview {
+                println!("Wrote preview {}", preview.to_str().unwrap());
+            }
+        }
+        render::Output::Image { .. } => unreachable!(),
+        render::Output::Ascii { chars, .. } => println!("{}", chars),
+    }
+}
+
+/// Log to stderr, keeping the messages that pass `level`, or `RUST_LOG` when it's not given, or
+/// else those at `default` and above. Spans are logged when they close, with the time spent in
+/// them, so that a more detailed level also shows where the time went.
+fn init_logging(level: Option<&str>, json: bool, default: &str) -> Result<(), Error> {
+    let filter = match level {
+        Some(level) => EnvFilter::try_new(level)?,
+        None => EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new(default)),
+    };
+    let subscriber = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_span_events(FmtSpan::CLOSE)
+        .with_writer(std::io::stderr);
+    if json {
+        subscriber.json().init();
+    } else {
+        subscriber.init();
+    }
+    Ok(())
 }
 
 fn main() -> Result<(), Error> {
     let opts = Options::parse();
 
+    // Long running commands report what they're doing, and the others only report problems.
+    let default = match opts.command {
+        Command::Serve { .. } | Command::Watch { .. } | Command::Worker { .. } => "info",
+        _ => "warn",
+    };
+    init_logging(opts.log_level.as_deref(), opts.log_json, default)?;
+
     match opts.command {
         Command::Serve {
             port,
             threads,
+            flags,
+            strict,
+            history,
             scene,
         } => {
-            web::serve(port, threads as usize, scene)?;
+            web::serve(
+                port,
+                threads as usize,
+                flags,
+                strict,
+                history as usize,
+                scene,
+            )?;
+        }
+
+        Command::Render { args, scene } => {
+            let settings = args.settings()?;
+            let path = PathBuf::from(&scene);
+            let outputs =
+                render::render_scene(&settings, &args.flags, &args.overrides, &path, None)?;
+            for output in outputs {
+                print_output(output?);
+            }
         }
 
-        Command::Render { threads, scene } => {
+        Command::Watch { args, scene } => {
+            // Renders that an edit doesn't touch are reused, rather than rendered again.
+            let settings = render::Settings {
+                cache: Some(Arc::new(render::RenderCache::default())),
+                ..args.settings()?
+            };
             let path = PathBuf::from(&scene);
-            for output in render::render_scene(threads as usize, &path)? {
-                match output {
-                    render::Output::File { path } => {
-                        println!("Wrote file {}", path.to_str().unwrap())
+            let watcher = watch::SceneWatcher::new(std::slice::from_ref(&path))?;
+            loop {
+                tracing::info!(path = %path.display(), "rendering");
+
+                // Errors are reported, and fixed by editing the scene.
+                match render::render_scene(&settings, &args.flags, &args.overrides, &path, None) {
+                    Ok(outputs) => {
+                        // Fonts, heightfields and the like are watched along with the scene.
+                        if let Err(err) = watcher.watch(&outputs.files) {
+                            tracing::warn!("failed to watch the files of the scene: {}", err);
+                        }
+                        for output in outputs {
+                            match output {
+                                Ok(output) => print_output(output),
+                                Err(err) => tracing::error!("{:#}", err),
+                            }
+                        }
                     }
-                    render::Output::Ascii { chars, .. } => println!("{}", chars),
+                    Err(err) => tracing::error!("{:#}", err),
                 }
+
+                if !watcher.wait() {
+                    break;
+                }
+            }
+        }
+
+        Command::Worker { threads, connect } => {
+            remote::work(&connect, threads as usize)?;
+        }
+
+        Command::Bench {
+            threads,
+            backend,
+            iterations,
+            scenes,
+        } => {
+            let scenes = if scenes.is_empty() {
+                bench::SCENES.iter().collect()
+            } else {
+                scenes
+                    .iter()
+                    .map(|name| {
+                        bench::BenchScene::find(name)
+                            .ok_or_else(|| anyhow::anyhow!("Unknown benchmark scene: `{}`", name))
+                    })
+                    .collect::<Result<Vec<_>, Error>>()?
+            };
+
+            println!(
+                "{:<14} {:>10} {:>10} {:>12}",
+                "scene", "best", "mean", "rays/sec"
+            );
+            for scene in scenes {
+                let timings = (0..iterations)
+                    .map(|_| bench::run(scene, threads as usize, backend))
+                    .collect::<Result<Vec<_>, Error>>()?;
+                let best = timings.iter().min_by_key(|timing| timing.elapsed).unwrap();
+                let mean = timings
+                    .iter()
+                    .map(|timing| timing.elapsed)
+                    .sum::<Duration>()
+                    / iterations as u32;
+                println!(
+                    "{:<14} {:>9.3}s {:>9.3}s {:>12.0}",
+                    scene.name,
+                    best.elapsed.as_secs_f64(),
+                    mean.as_secs_f64(),
+                    best.rays_per_sec()
+                );
+            }
+        }
+
+        Command::Check { flags, scene } => {
+            let input = std::fs::read_to_string(&scene)?;
+            let problems = parser::check(&input, &flags)?;
+            for problem in problems.iter() {
+                println!("warning: {}", problem);
+            }
+            if !problems.is_empty() {
+                std::process::exit(1);
+            }
+        }
+
+        Command::Info { flags, dot, scene } => {
+            let input = std::fs::read_to_string(&scene)?;
+            let info = parser::Info::parse(&input, &flags)?;
+            print!("{}\n{}", info.tree(), info.summary());
+            if let Some(dot) = dot {
+                std::fs::write(dot, info.dot())?;
+            }
+        }
+
+        Command::Export {
+            flags,
+            language,
+            render,
+            scene,
+        } => {
+            let input = std::fs::read_to_string(scene)?;
+            print!(
+                "{}",
+                shader::export(&input, &flags, render.as_deref(), language)?
+            );
+        }
+
+        Command::Mesh {
+            flags,
+            node,
+            resolution,
+            output,
+            scene,
+        } => {
+            let stl = match output.extension().and_then(|ext| ext.to_str()) {
+                Some("stl") => true,
+                Some("obj") => false,
+                _ => anyhow::bail!("The output must be a `.stl` or `.obj` file"),
+            };
+            let input = std::fs::read_to_string(scene)?;
+            let (scene, root) = parser::parse_node(&input, &flags, node.as_deref())?;
+            let mesh = mesh::polygonize(&scene, root, resolution)?;
+            let mut out = std::io::BufWriter::new(std::fs::File::create(&output)?);
+            if stl {
+                mesh.write_stl(&mut out)?;
+            } else {
+                mesh.write_obj(&mut out)?;
             }
+            std::io::Write::flush(&mut out)?;
+            println!(
+                "Wrote {} triangles to {}",
+                mesh.triangles.len(),
+                output.display()
+            );
+        }
+
+        Command::Expand { flags, scene } => {
+            let input = std::fs::read_to_string(scene)?;
+            print!("{}", parser::expand(&input, &flags)?);
         }
     }
 
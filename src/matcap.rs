@@ -0,0 +1,102 @@
+//! Material captures, which are images of a lit sphere. A surface takes the color of the point of
+//! the sphere that faces the same way relative to the viewer, so the lighting painted into the
+//! image follows the model around without any lights in the scene.
+
+use anyhow::Error;
+use image::Rgb32FImage;
+use nalgebra::{Unit, Vector3};
+use std::path::Path;
+use std::sync::Arc;
+
+use crate::{canvas::Color, math::Float};
+
+type Result<T> = std::result::Result<T, Error>;
+
+#[derive(Debug, Clone)]
+pub struct Matcap {
+    image: Arc<Rgb32FImage>,
+}
+
+impl Matcap {
+    pub fn new(image: Rgb32FImage) -> Self {
+        Self {
+            image: Arc::new(image),
+        }
+    }
+
+    /// Load the sphere from the image at `path`. Its values are used as they're stored, as with
+    /// image backgrounds.
+    pub fn load(path: &Path) -> Result<Self> {
+        let image = image::open(path)
+            .map_err(|err| anyhow::anyhow!("Failed to load `{}`: {}", path.display(), err))?
+            .into_rgb32f();
+        Ok(Self::new(image))
+    }
+
+    /// The color of a surface with `normal`, seen along `direction`. The normal is taken into a
+    /// frame that looks down the direction with the y axis as up, so that the top of the image
+    /// lights surfaces that face up.
+    pub fn color(&self, direction: &Unit<Vector3<Float>>, normal: &Unit<Vector3<Float>>) -> Color {
+        let up = if direction.y.abs() < 0.999 {
+            Vector3::y()
+        } else {
+            Vector3::z()
+        };
+        let right = up.cross(direction).normalize();
+        let up = direction.cross(&right);
+
+        let u = 0.5 + 0.5 * normal.dot(&right);
+        let v = 0.5 - 0.5 * normal.dot(&up);
+
+        let texel = |t: Float, size: u32| ((t.max(0.) * size as Float) as u32).min(size - 1);
+        let [r, g, b] = self
+            .image
+            .get_pixel(texel(u, self.image.width()), texel(v, self.image.height()))
+            .0;
+        Color::new(r, g, b)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_matcap() {
+        // Red on the left, green on the right, and blue along the bottom row.
+        let mut image = Rgb32FImage::new(4, 4);
+        for (x, y, pixel) in image.enumerate_pixels_mut() {
+            pixel.0 = if y == 3 {
+                [0., 0., 1.]
+            } else if x < 2 {
+                [1., 0., 0.]
+            } else {
+                [0., 1., 0.]
+            };
+        }
+        let matcap = Matcap::new(image);
+
+        let forward = Vector3::z_axis();
+        let color =
+            |normal: Vector3<Float>| matcap.color(&forward, &Unit::new_normalize(normal)).to_u8();
+        assert_eq!(
+            Color::new(1., 0., 0.).to_u8(),
+            color(Vector3::new(-1., 0., -1.))
+        );
+        assert_eq!(
+            Color::new(0., 1., 0.).to_u8(),
+            color(Vector3::new(1., 0., -1.))
+        );
+        assert_eq!(
+            Color::new(0., 0., 1.).to_u8(),
+            color(Vector3::new(0., -1., 0.))
+        );
+
+        // Looking straight down, the top of the image is towards positive z.
+        let down = -Vector3::y_axis();
+        assert_eq!(
+            Color::new(0., 0., 1.).to_u8(),
+            matcap.color(&down, &-Vector3::z_axis()).to_u8()
+        );
+    }
+}
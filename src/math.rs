@@ -1,17 +1,100 @@
 use nalgebra::{Unit, Vector3};
 
+/// The scalar type used for positions, directions and distances. Building with the `f64` feature
+/// trades speed for the precision needed by scenes at very large scales. Colors are `f32` either
+/// way.
+#[cfg(not(feature = "f64"))]
+pub type Float = f32;
+
+#[cfg(feature = "f64")]
+pub type Float = f64;
+
+#[cfg(not(feature = "f64"))]
+pub use std::f32::consts;
+
+#[cfg(feature = "f64")]
+pub use std::f64::consts;
+
 /// Reflect `vec` through `normal`.
-pub fn reflect(vec: &Unit<Vector3<f32>>, normal: &Unit<Vector3<f32>>) -> Unit<Vector3<f32>> {
+pub fn reflect(vec: &Unit<Vector3<Float>>, normal: &Unit<Vector3<Float>>) -> Unit<Vector3<Float>> {
     Unit::new_unchecked(vec.as_ref() - normal.as_ref() * 2. * vec.dot(normal))
 }
 
-pub trait Mix {
+/// A direction within `angle` of `axis`, spread evenly over the cap of the cone, picked by `u` and
+/// `v` from `0..1`.
+pub fn cone_sample(
+    axis: &Unit<Vector3<Float>>,
+    angle: Float,
+    u: Float,
+    v: Float,
+) -> Unit<Vector3<Float>> {
+    let cos = 1. - u * (1. - angle.cos());
+    let sin = (1. - cos * cos).max(0.).sqrt();
+    let phi = 2. * consts::PI * v;
+
+    let other = if axis.x.abs() < 0.9 {
+        Vector3::x()
+    } else {
+        Vector3::y()
+    };
+    let tangent = axis.cross(&other).normalize();
+    let bitangent = axis.cross(&tangent);
+    Unit::new_normalize(axis.as_ref() * cos + (tangent * phi.cos() + bitangent * phi.sin()) * sin)
+}
+
+/// The fraction of light of `wavelength` reflected by a thin film of index `film` and
+/// `thickness`, between media of index `outside` and `inside`, when it arrives from outside at an
+/// angle whose cosine is `cos`. The film's thickness and the wavelength are in the same units.
+/// Light reflected by either side of the film interferes, strengthening some colors and
+/// cancelling others depending on how much further the light reflected by the far side travels.
+/// Unpolarized light is reflected as the average of its two polarizations.
+pub fn thin_film(
+    cos: Float,
+    outside: Float,
+    film: Float,
+    inside: Float,
+    thickness: Float,
+    wavelength: Float,
+) -> Float {
+    // The cosine of the angle that light crossing into a medium of index `n` travels at.
+    let refract = |n: Float| {
+        let sin2 = (outside / n).powi(2) * (1. - cos * cos);
+        (sin2 <= 1.).then(|| (1. - sin2).sqrt())
+    };
+    let Some(cos_film) = refract(film) else {
+        return 1.;
+    };
+
+    // Light that can't leave the film for the inside is all reflected by its far side.
+    let cos_inside = refract(inside);
+    let phase = 4. * consts::PI * film * thickness * cos_film / wavelength;
+    let airy = |near: Float, far: Float| {
+        let interference = 2. * near * far * phase.cos();
+        (near * near + far * far + interference) / (1. + near * near * far * far + interference)
+    };
+
+    let s = airy(
+        (outside * cos - film * cos_film) / (outside * cos + film * cos_film),
+        cos_inside.map_or(1., |cos_inside| {
+            (film * cos_film - inside * cos_inside) / (film * cos_film + inside * cos_inside)
+        }),
+    );
+    let p = airy(
+        (film * cos - outside * cos_film) / (film * cos + outside * cos_film),
+        cos_inside.map_or(1., |cos_inside| {
+            (inside * cos_film - film * cos_inside) / (inside * cos_film + film * cos_inside)
+        }),
+    );
+    (s + p) / 2.
+}
+
+pub trait Mix<T = Float> {
     type Output;
 
-    fn mix(self, b: Self, t: f32) -> Self::Output;
+    fn mix(self, b: Self, t: T) -> Self::Output;
 }
 
-impl Mix for f32 {
+impl Mix<f32> for f32 {
     type Output = f32;
 
     #[inline]
@@ -20,11 +103,21 @@ impl Mix for f32 {
     }
 }
 
-impl Mix for &Vector3<f32> {
-    type Output = Vector3<f32>;
+#[cfg(feature = "f64")]
+impl Mix<f64> for f64 {
+    type Output = f64;
+
+    #[inline]
+    fn mix(self, y: f64, t: f64) -> f64 {
+        self * (1.0 - t) + y * t
+    }
+}
+
+impl Mix for &Vector3<Float> {
+    type Output = Vector3<Float>;
 
     #[inline]
-    fn mix(self, other: Self, t: f32) -> Self::Output {
+    fn mix(self, other: Self, t: Float) -> Self::Output {
         Vector3::new(
             self.x.mix(other.x, t),
             self.y.mix(other.y, t),
@@ -34,11 +127,47 @@ impl Mix for &Vector3<f32> {
 }
 
 #[inline]
-pub fn deg_to_rad(deg: f32) -> f32 {
-    (deg / 180.) * std::f32::consts::PI
+pub fn deg_to_rad(deg: Float) -> Float {
+    (deg / 180.) * consts::PI
 }
 
 #[test]
 fn test_deg_to_rad() {
-    assert_eq!(std::f32::consts::PI, deg_to_rad(180.));
+    assert_eq!(consts::PI, deg_to_rad(180.));
+}
+
+#[test]
+fn test_cone_sample() {
+    let axis = Unit::new_normalize(Vector3::new(1., 2., 3.));
+    let angle = deg_to_rad(10.);
+    for (u, v) in [(0., 0.), (1., 0.), (1., 0.5), (0.5, 0.25), (0.99, 0.9)] {
+        let direction = cone_sample(&axis, angle, u, v);
+        assert!(direction.dot(&axis) >= angle.cos() - 1e-5);
+    }
+
+    // The edge of the cap is at the angle.
+    let edge = cone_sample(&axis, angle, 1., 0.3);
+    assert!((edge.dot(&axis) - angle.cos()).abs() < 1e-5);
+}
+
+#[test]
+fn test_thin_film() {
+    // Without a film, light is reflected as it is by the surface between the media.
+    let bare = thin_film(1., 1., 1.33, 1.5, 0., 500.);
+    assert!((bare - (0.5f32 / 2.5).powi(2) as Float).abs() < 1e-5);
+    assert!(thin_film(1., 1., 1., 1., 300., 500.).abs() < 1e-5);
+
+    // A film a quarter of a wavelength thick, whose index is the square root of the inside's,
+    // reflects nothing head on, as an anti-reflective coating does.
+    let coating = Float::sqrt(1.5);
+    assert!(thin_film(1., 1., coating, 1.5, 500. / (4. * coating), 500.) < 1e-5);
+
+    // A soap film reflects different amounts of different colors, and they shift with the angle.
+    let red = thin_film(1., 1., 1.33, 1., 400., 650.);
+    let green = thin_film(1., 1., 1.33, 1., 400., 510.);
+    assert!(red > 0.05 && green < 0.01, "{} {}", red, green);
+    assert!(thin_film(0.5, 1., 1.33, 1., 400., 510.) > 0.15);
+
+    // Light that can't cross into the inside is all reflected.
+    assert!((thin_film(0.2, 1.5, 1.6, 1., 300., 500.) - 1.).abs() < 1e-5);
 }
@@ -0,0 +1,269 @@
+//! Convert the signed distance function of a node into a triangle mesh, so that models built with
+//! rendrs can be 3D printed or used in other tools. The distance is sampled on a grid over the
+//! node's bounding box, and the surface is extracted with surface nets: every grid cell that the
+//! surface passes through gets one vertex, which is then moved onto the surface along the gradient,
+//! and every grid edge that crosses the surface becomes a quad joining the four cells around it.
+//! Unlike marching cubes, moving the vertices keeps the sharp edges of boxes and subtractions.
+
+use anyhow::Error;
+use nalgebra::{Point3, Unit, Vector3};
+use std::io::Write;
+
+use crate::{
+    grid::DistanceGrid,
+    math::Float,
+    ray::Ray,
+    scene::{NodeId, Scene},
+};
+
+type Result<T> = std::result::Result<T, Error>;
+
+/// A triangle mesh, with the triangles wound counter-clockwise when seen from outside.
+#[derive(Debug, Default)]
+pub struct Mesh {
+    pub vertices: Vec<Point3<Float>>,
+    pub triangles: Vec<[u32; 3]>,
+}
+
+/// Polygonize the node `root`, with `resolution` cells along the longest side of its bounding box.
+pub fn polygonize(scene: &Scene, root: NodeId, resolution: usize) -> Result<Mesh> {
+    let grid = DistanceGrid::bake(scene, root, resolution)?;
+    Ok(Surface { grid, scene, root }.mesh())
+}
+
+/// The surface of a node, found from its distances sampled on a grid.
+struct Surface<'a> {
+    grid: DistanceGrid,
+    scene: &'a Scene,
+    root: NodeId,
+}
+
+impl<'a> Surface<'a> {
+    fn mesh(&self) -> Mesh {
+        let grid = &self.grid;
+        let [nx, ny, nz] = grid.dims();
+        let mut mesh = Mesh::default();
+
+        // The vertex of each cell that the surface passes through, indexed by the cell's lowest
+        // corner.
+        let mut cells = vec![u32::MAX; nx * ny * nz];
+        for k in 0..nz - 1 {
+            for j in 0..ny - 1 {
+                for i in 0..nx - 1 {
+                    if let Some(vertex) = self.cell_vertex([i, j, k]) {
+                        cells[grid.index([i, j, k])] = mesh.vertices.len() as u32;
+                        mesh.vertices.push(vertex);
+                    }
+                }
+            }
+        }
+
+        // Every edge that crosses the surface is shared by four cells, whose vertices make a quad
+        // facing from the inside end of the edge to the outside end.
+        for k in 0..nz {
+            for j in 0..ny {
+                for i in 0..nx {
+                    let corner = [i, j, k];
+                    let inside = grid.value(corner) < 0.;
+                    for axis in 0..3 {
+                        let mut end = corner;
+                        end[axis] += 1;
+                        if end[axis] >= grid.dims()[axis] || (grid.value(end) < 0.) == inside {
+                            continue;
+                        }
+
+                        let (u, v) = ((axis + 1) % 3, (axis + 2) % 3);
+                        if corner[u] == 0 || corner[v] == 0 {
+                            continue;
+                        }
+
+                        let cell = |du: usize, dv: usize| {
+                            let mut cell = corner;
+                            cell[u] -= 1 - du;
+                            cell[v] -= 1 - dv;
+                            cells[grid.index(cell)]
+                        };
+                        let mut quad = [cell(0, 0), cell(1, 0), cell(1, 1), cell(0, 1)];
+                        if !inside {
+                            quad.reverse();
+                        }
+                        mesh.triangles.push([quad[0], quad[1], quad[2]]);
+                        mesh.triangles.push([quad[0], quad[2], quad[3]]);
+                    }
+                }
+            }
+        }
+
+        mesh
+    }
+
+    /// The vertex for the cell with its lowest corner at `corner`, if the surface passes through
+    /// it. It starts at the mean of the points where the surface crosses the cell's edges, and is
+    /// then moved onto the surface, without leaving the cell.
+    fn cell_vertex(&self, corner: [usize; 3]) -> Option<Point3<Float>> {
+        let (grid, scene, root) = (&self.grid, self.scene, self.root);
+        let corners: [[usize; 3]; 8] = std::array::from_fn(|n| {
+            [
+                corner[0] + (n & 1),
+                corner[1] + ((n >> 1) & 1),
+                corner[2] + (n >> 2),
+            ]
+        });
+        let values = corners.map(|c| grid.value(c));
+
+        let mut sum = Vector3::zeros();
+        let mut crossings = 0;
+        for a in 0..8 {
+            for bit in [1, 2, 4] {
+                let b = a | bit;
+                if a == b || (values[a] < 0.) == (values[b] < 0.) {
+                    continue;
+                }
+                let t = values[a] / (values[a] - values[b]);
+                let (pa, pb) = (grid.point(corners[a]), grid.point(corners[b]));
+                sum += pa.coords + (pb - pa) * t;
+                crossings += 1;
+            }
+        }
+        if crossings == 0 {
+            return None;
+        }
+
+        let low = grid.point(corner);
+        let high = low + Vector3::repeat(grid.step());
+        let mut vertex = Point3::from(sum / crossings as Float);
+        for _ in 0..4 {
+            let d = distance(scene, root, vertex);
+            let Some(normal) = gradient(scene, root, vertex, grid.step() * 0.01) else {
+                break;
+            };
+            vertex -= normal.into_inner() * d;
+            vertex = vertex
+                .coords
+                .zip_zip_map(&low.coords, &high.coords, |x, lo, hi| x.clamp(lo, hi))
+                .into();
+        }
+        Some(vertex)
+    }
+}
+
+fn distance(scene: &Scene, root: NodeId, p: Point3<Float>) -> Float {
+    let ray = Ray::new(p, Vector3::y_axis());
+    scene
+        .node(root)
+        .fast_sdf(scene, &ray, Float::NEG_INFINITY)
+        .distance
+        .0
+}
+
+/// The direction that the distance increases fastest in around `p`, by central differences.
+fn gradient(
+    scene: &Scene,
+    root: NodeId,
+    p: Point3<Float>,
+    h: Float,
+) -> Option<Unit<Vector3<Float>>> {
+    let axis =
+        |v: Vector3<Float>| distance(scene, root, p + v * h) - distance(scene, root, p - v * h);
+    let g = Vector3::new(axis(Vector3::x()), axis(Vector3::y()), axis(Vector3::z()));
+    Unit::try_new(g, 0.)
+}
+
+impl Mesh {
+    /// Write the mesh as binary STL.
+    pub fn write_stl(&self, out: &mut impl Write) -> Result<()> {
+        let mut header = [0u8; 80];
+        header[..6].copy_from_slice(b"rendrs");
+        out.write_all(&header)?;
+        out.write_all(&(self.triangles.len() as u32).to_le_bytes())?;
+        for &[a, b, c] in self.triangles.iter() {
+            let [a, b, c] = [a, b, c].map(|ix| self.vertices[ix as usize]);
+            let normal = Unit::try_new((b - a).cross(&(c - a)), 0.)
+                .map_or_else(Vector3::zeros, Unit::into_inner);
+            for v in [normal, a.coords, b.coords, c.coords] {
+                for x in v.iter() {
+                    out.write_all(&(*x as f32).to_le_bytes())?;
+                }
+            }
+            out.write_all(&[0, 0])?;
+        }
+        Ok(())
+    }
+
+    /// Write the mesh as Wavefront OBJ.
+    pub fn write_obj(&self, out: &mut impl Write) -> Result<()> {
+        for v in self.vertices.iter() {
+            writeln!(out, "v {} {} {}", v.x, v.y, v.z)?;
+        }
+        for [a, b, c] in self.triangles.iter() {
+            writeln!(out, "f {} {} {}", a + 1, b + 1, c + 1)?;
+        }
+        Ok(())
+    }
+
+    /// The volume enclosed by the mesh, which is negative if its triangles face inwards.
+    pub fn volume(&self) -> Float {
+        self.triangles
+            .iter()
+            .map(|&[a, b, c]| {
+                let [a, b, c] = [a, b, c].map(|ix| self.vertices[ix as usize].coords);
+                a.dot(&b.cross(&c)) / 6.
+            })
+            .sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::math;
+
+    #[test]
+    fn test_polygonize_sphere() {
+        let mut scene = Scene::default();
+        let sphere = scene.sphere(1.).unwrap();
+        let mesh = polygonize(&scene, sphere, 32).unwrap();
+
+        assert!(!mesh.triangles.is_empty());
+        for v in mesh.vertices.iter() {
+            assert!((v.coords.norm() - 1.).abs() < 0.01, "{:?}", v);
+        }
+
+        let expected = 4. / 3. * math::consts::PI;
+        assert!(
+            (mesh.volume() - expected).abs() < 0.05 * expected,
+            "{}",
+            mesh.volume()
+        );
+
+        // Every edge is shared by exactly two triangles, which use it in opposite directions.
+        let mut edges = std::collections::HashMap::new();
+        for &[a, b, c] in mesh.triangles.iter() {
+            for edge in [(a, b), (b, c), (c, a)] {
+                *edges.entry(edge).or_insert(0) += 1;
+            }
+        }
+        for (&(a, b), &count) in edges.iter() {
+            assert_eq!(1, count);
+            assert_eq!(Some(&1), edges.get(&(b, a)));
+        }
+    }
+
+    #[test]
+    fn test_polygonize_box() {
+        let mut scene = Scene::default();
+        let cube = scene.rect(1., 1., 1.).unwrap();
+        let mesh = polygonize(&scene, cube, 16).unwrap();
+
+        // Vertices are moved onto the surface, so the corners stay sharp and the volume is close.
+        assert!((mesh.volume() - 8.).abs() < 0.1, "{}", mesh.volume());
+
+        let mut stl = Vec::new();
+        mesh.write_stl(&mut stl).unwrap();
+        assert_eq!(84 + 50 * mesh.triangles.len(), stl.len());
+
+        let plane = scene.plane(Vector3::y_axis());
+        assert!(polygonize(&scene, plane, 16).is_err());
+        assert!(polygonize(&scene, cube, 1).is_err());
+    }
+}
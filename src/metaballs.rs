@@ -0,0 +1,141 @@
+//! Blobby shapes made of balls whose fields add together, so that nearby balls melt into each
+//! other. The field of each ball falls smoothly to zero at its radius, and the surface is where
+//! the total field reaches a threshold.
+
+use nalgebra::{Point3, Unit, Vector3};
+
+use crate::{bvh::BoundingBox, math::Float};
+
+/// The steepest slope of [`falloff`], at `1 / sqrt(5)`.
+const MAX_SLOPE: Float = 1.7173;
+
+/// A ball's contribution to the field at `x` radii from its center.
+fn falloff(x: Float) -> Float {
+    if x >= 1. {
+        0.
+    } else {
+        (1. - x * x).powi(3)
+    }
+}
+
+/// The slope of [`falloff`] at `x`.
+fn falloff_slope(x: Float) -> Float {
+    if x >= 1. {
+        0.
+    } else {
+        -6. * x * (1. - x * x).powi(2)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Metaball {
+    pub center: Point3<Float>,
+
+    /// The distance at which the ball stops contributing to the field.
+    pub radius: Float,
+
+    /// The field at the center of the ball. Negative strengths carve into other balls.
+    pub strength: Float,
+}
+
+#[derive(Debug, Clone)]
+pub struct Metaballs {
+    balls: Vec<Metaball>,
+    threshold: Float,
+
+    /// The steepest that the field can change, which converts the field into a distance.
+    slope: Float,
+}
+
+impl Metaballs {
+    pub fn new(balls: Vec<Metaball>, threshold: Float) -> Self {
+        let slope = balls
+            .iter()
+            .map(|ball| ball.strength.abs() * MAX_SLOPE / ball.radius)
+            .sum();
+        Self {
+            balls,
+            threshold,
+            slope,
+        }
+    }
+
+    /// The balls that add to the field bound the shape, as the field is zero outside of them.
+    pub fn bounding_box(&self) -> BoundingBox {
+        self.balls
+            .iter()
+            .filter(|ball| ball.strength > 0.)
+            .fold(BoundingBox::min(), |acc, ball| {
+                let r = Vector3::repeat(ball.radius);
+                acc.union(&BoundingBox::new(ball.center - r, ball.center + r))
+            })
+    }
+
+    pub fn field(&self, p: &Point3<Float>) -> Float {
+        self.balls
+            .iter()
+            .map(|ball| ball.strength * falloff((p - ball.center).norm() / ball.radius))
+            .sum()
+    }
+
+    /// A bound on the distance to the surface. The field can't reach the threshold any faster
+    /// than its steepest slope allows, and it can't reach it at all outside of the balls.
+    pub fn sdf(&self, p: &Point3<Float>) -> Float {
+        let outside = self
+            .balls
+            .iter()
+            .filter(|ball| ball.strength > 0.)
+            .map(|ball| (p - ball.center).norm() - ball.radius)
+            .fold(Float::INFINITY, Float::min);
+        outside.max((self.threshold - self.field(p)) / self.slope)
+    }
+
+    /// The normal points down the gradient of the field, which is undefined where the field is
+    /// flat.
+    pub fn normal(&self, p: &Point3<Float>) -> Option<Unit<Vector3<Float>>> {
+        let gradient: Vector3<Float> = self
+            .balls
+            .iter()
+            .filter_map(|ball| {
+                let v = p - ball.center;
+                let dist = v.norm();
+                let dir = v.try_normalize(0.)?;
+                Some(dir * ball.strength * falloff_slope(dist / ball.radius) / ball.radius)
+            })
+            .sum();
+        Unit::try_new(-gradient, 0.)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_metaballs() {
+        let ball = |x: Float, strength: Float| Metaball {
+            center: Point3::new(x, 0., 0.),
+            radius: 1.,
+            strength,
+        };
+
+        // A lone ball is a sphere, where its falloff reaches the threshold.
+        let single = Metaballs::new(vec![ball(0., 1.)], 0.125);
+        let surface = Float::sqrt(0.5);
+        assert!(single.sdf(&Point3::new(surface - 0.01, 0., 0.)) < 0.);
+        assert!(single.sdf(&Point3::new(surface + 0.01, 0., 0.)) > 0.);
+        assert!(single.sdf(&Point3::new(3., 0., 0.)) <= 3. - surface);
+        let normal = single.normal(&Point3::new(0., surface, 0.)).unwrap();
+        assert!((normal.y - 1.).abs() < 1e-5);
+
+        // Two balls too far apart to reach the threshold alone meet in the middle.
+        let pair = Metaballs::new(vec![ball(-0.6, 0.6), ball(0.6, 0.6)], 0.3);
+        assert!(pair.sdf(&Point3::origin()) < 0.);
+        assert!(Metaballs::new(vec![ball(-0.6, 0.6)], 0.3).sdf(&Point3::origin()) > 0.);
+
+        // Negative balls carve, and don't grow the bounds.
+        let carved = Metaballs::new(vec![ball(0., 1.), ball(0.5, -1.)], 0.125);
+        assert!(carved.sdf(&Point3::new(0.5, 0., 0.)) > 0.);
+        assert!(!carved.bounding_box().contains(&Point3::new(1.2, 0., 0.)));
+    }
+}
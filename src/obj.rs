@@ -1,11 +1,13 @@
 use anyhow::{bail, Error};
 use nalgebra::{Point3, Unit, Vector3};
 
+use crate::math::Float;
+
 type Result<T> = std::result::Result<T, Error>;
 
 #[derive(Default, Debug)]
 pub struct Face {
-    pub vertices: Vec<Point3<f32>>,
+    pub vertices: Vec<Point3<Float>>,
 }
 
 #[derive(Debug)]
@@ -56,7 +58,7 @@ struct Parser<'a> {
     buf: &'a str,
     chars: std::iter::Peekable<std::str::CharIndices<'a>>,
     offset: usize,
-    vertices: Vec<Point3<f32>>,
+    vertices: Vec<Point3<Float>>,
 }
 
 impl<'a> Parser<'a> {
@@ -116,7 +118,6 @@ impl<'a> Parser<'a> {
             }
 
             if c == '\n' {
-                println!("found eol");
                 self.consume();
                 return false;
             }
@@ -145,13 +146,13 @@ impl<'a> Parser<'a> {
         }
     }
 
-    fn f32(&mut self) -> Result<f32> {
+    fn float(&mut self) -> Result<Float> {
         let tok = self.token()?;
         let num = tok.parse()?;
         Ok(num)
     }
 
-    fn vertex(&mut self) -> Result<Point3<f32>> {
+    fn vertex(&mut self) -> Result<Point3<Float>> {
         let tok = self.token()?;
         let idx = tok.parse::<usize>()?;
         Ok(self.vertices[idx - 1])
@@ -168,7 +169,7 @@ impl<'a> Parser<'a> {
                 }
 
                 "v" => {
-                    let point = Point3::new(self.f32()?, self.f32()?, self.f32()?);
+                    let point = Point3::new(self.float()?, self.float()?, self.float()?);
                     self.vertices.push(point);
                 }
 
@@ -177,10 +178,8 @@ impl<'a> Parser<'a> {
                 "f" => {
                     let mut face = Face::default();
                     while self.skip_space() {
-                        println!("vertex!");
                         face.vertices.push(self.vertex()?);
                     }
-                    println!("done!");
                     return Ok(Command::Face { face });
                 }
 
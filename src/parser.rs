@@ -1,4 +1,16 @@
+mod check;
+mod expand;
+mod info;
 mod lexer;
 mod parser;
+mod plugins;
+mod template;
 
-pub use parser::{parse, Target};
+pub use check::check;
+pub use expand::expand;
+pub use info::Info;
+pub use parser::{parse, parse_node, parse_with, parse_with_plugins, Overrides, Render, Target};
+pub use plugins::{
+    IntegratorArgs, IntegratorFactory, Plugins, PrimitiveArgs, PrimitiveFactory, Value,
+};
+pub use template::{today, PathTemplate, PathVars};
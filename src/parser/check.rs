@@ -0,0 +1,171 @@
+use std::collections::{BTreeMap, HashMap};
+
+use crate::{
+    math::Float,
+    scene::{Node, NodeId, Prim, Scene},
+};
+
+use super::parser::{parse_all, Overrides, Target};
+use super::plugins::Plugins;
+use super::template::{today, PathVars};
+
+type Result<T> = std::result::Result<T, anyhow::Error>;
+
+/// Parse a scene description and look for problems that don't stop it from rendering, but
+/// probably aren't what was meant: names that are never used, renders that are overwritten or
+/// show nothing, and shapes whose parameters produce NaN or infinite distances. Errors that stop
+/// the scene from parsing are returned as errors.
+pub fn check(input: &str, flags: &[String]) -> Result<Vec<String>> {
+    let parsed = parse_all(input, flags, &Overrides::default(), &Plugins::default())?;
+    let scene = &parsed.scene;
+    let mut problems = Vec::new();
+
+    for decl in parsed.declarations.iter().filter(|decl| !decl.used) {
+        problems.push(format!("The {} `{}` is never used", decl.kind, decl.name));
+    }
+
+    if parsed.renders.is_empty() {
+        problems.push(String::from("The scene has no renders"));
+    }
+
+    // Where each node is first found, for pointing at the problems in it. Every node is found
+    // under a declaration or a render.
+    let mut owners = BTreeMap::new();
+    for decl in parsed.declarations.iter() {
+        if let Some(node) = decl.node {
+            find_owners(scene, node, &format!("node `{}`", decl.name), &mut owners);
+        }
+    }
+
+    let date = today();
+    let mut paths = HashMap::new();
+    for (index, render) in parsed.renders.iter().enumerate() {
+        let label = match &render.name {
+            Some(name) => format!("render `{}`", name),
+            None => format!("render {}", index + 1),
+        };
+        find_owners(scene, render.root, &label, &mut owners);
+
+        if let Target::File { path, .. } = &render.target {
+            let path = path.expand(&PathVars {
+                name: render.name.as_deref().unwrap_or_default(),
+                frame: 0,
+                width: render.canvas_info.width,
+                height: render.canvas_info.height,
+                date: &date,
+            });
+            if let Some(earlier) = paths.insert(path.clone(), label.clone()) {
+                problems.push(format!(
+                    "The {} writes `{}`, replacing the output of the {}",
+                    label,
+                    path.display(),
+                    earlier
+                ));
+            }
+        }
+
+        if scene.bounding_box(render.root).is_empty() {
+            problems.push(format!("The {} has nothing to show", label));
+        }
+    }
+
+    for (&id, owner) in owners.iter() {
+        if let Some(problem) = node_problem(scene.node(id)) {
+            problems.push(format!("In {}, {}", owner, problem));
+        }
+    }
+
+    Ok(problems)
+}
+
+/// Note `owner` as the owner of the nodes under `id` that don't already have one. Named nodes can
+/// be nested arbitrarily deep, so this keeps a stack of its own.
+fn find_owners(scene: &Scene, id: NodeId, owner: &str, owners: &mut BTreeMap<NodeId, String>) {
+    let mut stack = vec![id];
+    while let Some(id) = stack.pop() {
+        if owners.contains_key(&id) {
+            continue;
+        }
+        owners.insert(id, owner.to_string());
+        stack.extend(scene.node(id).children());
+    }
+}
+
+/// Describe what's wrong with the parameters of `node`, when they lead to distances that aren't
+/// finite or shapes that can't be seen.
+fn node_problem(node: &Node) -> Option<String> {
+    let finite = |values: &[Float]| values.iter().all(|value| value.is_finite());
+    let problem = match node {
+        Node::Prim { prim } => match prim {
+            Prim::Triangle { n, .. } if !finite(n.as_slice()) => {
+                "a triangle has its corners in a line, so it has no normal"
+            }
+            Prim::Triangle {
+                normals: Some(normals),
+                ..
+            } if normals.iter().any(|n| !finite(n.as_slice())) => {
+                "a triangle has a normal that isn't finite"
+            }
+            _ => return None,
+        },
+
+        Node::Transform { transform, .. } if !transform.is_invertible() => {
+            "a transform can't be inverted, as it scales something to nothing"
+        }
+
+        _ => return None,
+    };
+    Some(problem.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check() {
+        let problems = check(
+            r#"
+            (material unused (phong :pattern (solid #ffffff)))
+            (node ball (sphere 1))
+            (node flat (transform (scale (1 0 1)) (sphere 1)))
+            (node crease (smooth-union 0.5 (sphere 1) (box 1 1 1)))
+            (render (file "out.png") (whitted (uniform 1) (pinhole 8 8 (translate 0 0 -4) (degrees 60))) ball)
+            (render (file "out.png") (whitted (uniform 1) (pinhole 8 8 (translate 0 0 -4) (degrees 60)))
+              (group flat (triangle (0 0 0) (1 0 0) (2 0 0))))
+            "#,
+            &[],
+        )
+        .unwrap();
+
+        let expected = [
+            "The material `unused` is never used",
+            "The node `crease` is never used",
+            "The render 2 writes `out.png`, replacing the output of the render 1",
+            "In render 2, a triangle has its corners in a line, so it has no normal",
+            "In node `flat`, a transform can't be inverted, as it scales something to nothing",
+        ];
+        for problem in expected {
+            assert!(problems.iter().any(|p| p == problem), "{:#?}", problems);
+        }
+        assert_eq!(expected.len(), problems.len(), "{:#?}", problems);
+
+        let clean = check(
+            r#"(render (ascii "out") (whitted (uniform 1) (pinhole 8 8 (translate 0 0 -4) (degrees 60))) (sphere 1))"#,
+            &[],
+        )
+        .unwrap();
+        assert!(clean.is_empty(), "{:#?}", clean);
+
+        assert!(check("(node ball", &[]).is_err());
+
+        // Shapes that can't be marched at all are errors rather than problems.
+        let err = check("(node ball (sphere -1))", &[]).unwrap_err();
+        assert_eq!(
+            "The radius of a sphere must be positive, not -1",
+            err.to_string()
+        );
+        let err = check("(node flat (plane (0 0 0)))", &[]).unwrap_err();
+        assert_eq!("The normal of a plane must not be zero", err.to_string());
+    }
+}
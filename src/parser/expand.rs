@@ -0,0 +1,298 @@
+use anyhow::bail;
+use std::collections::HashSet;
+use std::fmt::Write;
+
+use super::lexer::{Lexeme, Lexer, Token};
+use super::parser::{condition_enabled, MAX_DEPTH};
+
+type Result<T> = std::result::Result<T, anyhow::Error>;
+
+/// The width that the expanded output tries to stay within.
+const WIDTH: usize = 80;
+
+/// Produce the scene description that's actually rendered, with all conditionals resolved
+/// according to `flags` and the environment, and the result consistently formatted.
+pub fn expand(input: &str, flags: &[String]) -> Result<String> {
+    let flags: HashSet<String> = flags.iter().cloned().collect();
+    let mut lexer = Lexer::new(input).peekable();
+
+    let mut decls = Vec::new();
+    while lexer.peek().is_some() {
+        let sexp = Sexp::parse(&mut lexer, 0)?;
+        expand_decl(&flags, sexp, &mut decls)?;
+    }
+
+    let mut out = String::new();
+    for (i, decl) in decls.iter().enumerate() {
+        if i > 0 {
+            out.push('\n');
+        }
+        decl.pretty(&mut out, 0);
+        out.push('\n');
+    }
+
+    Ok(out)
+}
+
+/// Expand a top-level declaration, pushing the declarations it produces onto `decls`.
+fn expand_decl(flags: &HashSet<String>, sexp: Sexp, decls: &mut Vec<Sexp>) -> Result<()> {
+    let Sexp::List(items) = &sexp else {
+        bail!("Expected a declaration, but found `{}`", sexp.flat());
+    };
+
+    let cond = match items.first() {
+        Some(Sexp::Atom(Lexeme {
+            token: Token::Ident,
+            text,
+        })) if text == "if-flag" || text == "if-env" => text.clone(),
+        _ => {
+            decls.push(sexp);
+            return Ok(());
+        }
+    };
+
+    let mut items = match sexp {
+        Sexp::List(items) => items.into_iter().skip(1),
+        Sexp::Atom(_) => unreachable!(),
+    };
+
+    let name = match items.next() {
+        Some(Sexp::Atom(Lexeme {
+            token: Token::String,
+            text,
+        })) => String::from(&text[1..text.len() - 1]),
+        _ => bail!("Expected a string after `{}`", cond),
+    };
+
+    let Some(then) = items.next() else {
+        bail!("Missing declaration for `{}`", cond);
+    };
+    let otherwise = items.next();
+
+    if items.next().is_some() {
+        bail!("Too many declarations for `{}`", cond);
+    }
+
+    if condition_enabled(&cond, &name, flags) {
+        expand_decl(flags, then, decls)
+    } else if let Some(otherwise) = otherwise {
+        expand_decl(flags, otherwise, decls)
+    } else {
+        Ok(())
+    }
+}
+
+/// An uninterpreted s-expression.
+enum Sexp {
+    Atom(Lexeme),
+    List(Vec<Sexp>),
+}
+
+impl Sexp {
+    fn parse(lexer: &mut std::iter::Peekable<Lexer>, depth: usize) -> Result<Self> {
+        let Some(lexeme) = lexer.next() else {
+            bail!("Unexpected EOF");
+        };
+
+        match lexeme.token {
+            Token::LParen if depth == MAX_DEPTH => {
+                bail!("Expressions are nested more than {} deep", MAX_DEPTH)
+            }
+            Token::LParen => {
+                let mut items = Vec::new();
+                loop {
+                    match lexer.peek() {
+                        None => bail!("Unexpected EOF"),
+                        Some(tok) if tok.token == Token::RParen => {
+                            lexer.next();
+                            return Ok(Sexp::List(items));
+                        }
+                        Some(_) => items.push(Self::parse(lexer, depth + 1)?),
+                    }
+                }
+            }
+            Token::RParen => bail!("Unexpected `)`"),
+            Token::Error => bail!("Failed to parse token `{}`", lexeme.text),
+            _ => Ok(Sexp::Atom(lexeme)),
+        }
+    }
+
+    /// Render the expression on a single line.
+    fn flat(&self) -> String {
+        match self {
+            Sexp::Atom(lexeme) => lexeme.text.clone(),
+            Sexp::List(items) => {
+                let items: Vec<_> = items.iter().map(Sexp::flat).collect();
+                format!("({})", items.join(" "))
+            }
+        }
+    }
+
+    /// Render the expression, breaking lists that don't fit across multiple lines. Leading atoms
+    /// stay on the line with the head, and keyword arguments stay on the line with their value.
+    fn pretty(&self, out: &mut String, indent: usize) {
+        let flat = self.flat();
+        let items = match self {
+            Sexp::List(items) if indent + flat.len() > WIDTH && items.len() > 1 => items,
+            _ => {
+                out.push_str(&flat);
+                return;
+            }
+        };
+
+        out.push('(');
+        items[0].pretty(out, indent + 1);
+
+        let mut rest = items[1..].iter().peekable();
+        while let Some(item) = rest.next_if(|item| item.is_atom() && !item.is_symbol()) {
+            write!(out, " {}", item.flat()).unwrap();
+        }
+
+        while let Some(item) = rest.next() {
+            write!(out, "\n{:width$}", "", width = indent + 2).unwrap();
+            item.pretty(out, indent + 2);
+
+            if item.is_symbol() {
+                if let Some(value) = rest.next() {
+                    out.push(' ');
+                    value.pretty(out, indent + 3 + item.flat().len());
+                }
+            }
+        }
+
+        out.push(')');
+    }
+
+    fn is_atom(&self) -> bool {
+        matches!(self, Sexp::Atom(_))
+    }
+
+    fn is_symbol(&self) -> bool {
+        matches!(self, Sexp::Atom(lexeme) if lexeme.token == Token::Symbol)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+
+    use super::*;
+    use crate::parser::{lexer::token_stream, parse};
+
+    /// A summary of everything a scene description produced, for comparing two descriptions.
+    fn describe(input: &str, flags: &[String]) -> Option<String> {
+        let (scene, renders) = parse(input, flags).ok()?;
+        let renders: Vec<_> = renders
+            .iter()
+            .map(|render| format!("{:?} {:?}", render.root, render.canvas_info))
+            .collect();
+        Some(format!("{:?} {:?}", scene, renders))
+    }
+
+    fn number() -> impl Strategy<Value = String> {
+        "[0-9]{1,2}(\\.[0-9]{1,3})?"
+    }
+
+    /// A number that's greater than zero, for the sizes of shapes.
+    fn size() -> impl Strategy<Value = String> {
+        "[1-9][0-9]?(\\.[0-9]{1,3})?"
+    }
+
+    fn node() -> impl Strategy<Value = String> {
+        let leaf = prop_oneof![
+            size().prop_map(|r| format!("(sphere {})", r)),
+            (size(), size(), size()).prop_map(|(w, h, d)| format!("(box {} {} {})", w, h, d)),
+            (number(), size()).prop_map(|(h, r)| format!("(torus {} {})", h, r)),
+        ];
+
+        leaf.prop_recursive(4, 32, 4, |inner| {
+            let nodes = prop::collection::vec(inner.clone(), 1..4).prop_map(|n| n.join(" "));
+            prop_oneof![
+                nodes.clone().prop_map(|n| format!("(group {})", n)),
+                nodes.clone().prop_map(|n| format!("(union {})", n)),
+                (size(), nodes).prop_map(|(k, n)| format!("(smooth-union {} {})", k, n)),
+                (number(), inner.clone())
+                    .prop_map(|(x, n)| format!("(transform (translate {} 0 -{}) {})", x, x, n)),
+                (1..6u32, inner).prop_map(|(c, n)| format!("(ring :count {} {})", c, n)),
+            ]
+        })
+    }
+
+    /// Valid scene descriptions, with some declarations guarded by the `"alt"` flag.
+    fn scene() -> impl Strategy<Value = String> {
+        let decl = prop_oneof![
+            node().prop_map(|n| format!("(node shape {})", n)),
+            (node(), node()).prop_map(|(a, b)| format!(
+                "(if-flag \"alt\" (node shape {}) (node shape {}))",
+                a, b
+            )),
+            ("[0-9a-f]{6}", number())
+                .prop_map(|(c, d)| format!("(fog :color #{} :density {})", c, d)),
+        ];
+
+        (node(), prop::collection::vec(decl, 0..4)).prop_map(|(first, decls)| {
+            format!(
+                "(node shape {})\n{}\n(render (ascii \"out\") \
+                 (whitted (uniform 1) (pinhole 8 8 (look-at (0 0 -2) (0 0 0) (0 1 0)) (degrees 90))) \
+                 shape)",
+                first,
+                decls.join("\n")
+            )
+        })
+    }
+
+    #[test]
+    fn test_expand() {
+        let input = r#"
+            ; a comment that is dropped
+            (node ball (sphere   1))
+            (if-flag "final"
+              (if-flag "big" (node size (sphere 2)))
+              (node preview (box 1 1 1)))
+        "#;
+
+        let flags = vec![String::from("final"), String::from("big")];
+        assert_eq!(
+            "(node ball (sphere 1))\n\n(node size (sphere 2))\n",
+            expand(input, &flags).unwrap()
+        );
+        assert_eq!(
+            "(node ball (sphere 1))\n\n(node preview (box 1 1 1))\n",
+            expand(input, &[]).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_expand_breaks_long_lines() {
+        let input = "(render (file \"out.png\") (whitted (uniform 1) (pinhole 512 512 \
+                     (look-at (0 0 -2) (0 0 0) (0 1 0)) (degrees 90))) scene)";
+        let expected = "\
+(render
+  (file \"out.png\")
+  (whitted
+    (uniform 1)
+    (pinhole 512 512 (look-at (0 0 -2) (0 0 0) (0 1 0)) (degrees 90)))
+  scene)
+";
+        assert_eq!(expected, expand(input, &[]).unwrap());
+    }
+
+    proptest! {
+        #[test]
+        fn test_expand_preserves_scene(input in scene(), alt in any::<bool>()) {
+            let flags = if alt { vec![String::from("alt")] } else { Vec::new() };
+            let expanded = expand(&input, &flags).unwrap();
+            prop_assert!(describe(&input, &flags).is_some());
+            prop_assert_eq!(describe(&input, &flags), describe(&expanded, &[]));
+            prop_assert_eq!(&expanded, &expand(&expanded, &[]).unwrap());
+        }
+
+        #[test]
+        fn test_expand_token_stream(input in token_stream()) {
+            if let Ok(expanded) = expand(&input, &[]) {
+                prop_assert_eq!(&expanded, &expand(&expanded, &[]).unwrap());
+                prop_assert_eq!(describe(&input, &[]), describe(&expanded, &[]));
+            }
+        }
+    }
+}
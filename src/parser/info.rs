@@ -0,0 +1,272 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Write;
+
+use crate::{
+    bvh::BoundingBox,
+    scene::{Light, Node, NodeId},
+};
+
+use super::parser::{parse_all, Overrides, Parsed, Target};
+use super::plugins::Plugins;
+
+type Result<T> = std::result::Result<T, anyhow::Error>;
+
+/// The most children of a node that are listed in the tree, so that meshes and other large
+/// groups don't bury the rest of the scene.
+const MAX_CHILDREN: usize = 8;
+
+/// A parsed scene, described for people rather than rendered.
+pub struct Info {
+    parsed: Parsed,
+
+    /// The names that nodes were declared with.
+    names: BTreeMap<NodeId, String>,
+}
+
+impl Info {
+    pub fn parse(input: &str, flags: &[String]) -> Result<Self> {
+        let parsed = parse_all(input, flags, &Overrides::default(), &Plugins::default())?;
+        let names = parsed
+            .declarations
+            .iter()
+            .filter_map(|decl| Some((decl.node?, decl.name.clone())))
+            .collect();
+        Ok(Self { parsed, names })
+    }
+
+    /// The nodes reachable from the renders, in the order they're first found.
+    fn reachable(&self) -> Vec<NodeId> {
+        let scene = &self.parsed.scene;
+        let mut seen = BTreeSet::new();
+        let mut order = Vec::new();
+        let mut stack: Vec<_> = self.parsed.renders.iter().rev().map(|r| r.root).collect();
+        while let Some(id) = stack.pop() {
+            if seen.insert(id) {
+                order.push(id);
+                stack.extend(scene.node(id).children().into_iter().rev());
+            }
+        }
+        order
+    }
+
+    /// Counts of what's in the scene, its bounds, and the shape of its hierarchies.
+    pub fn summary(&self) -> String {
+        let scene = &self.parsed.scene;
+        let reachable = self.reachable();
+        let mut out = String::new();
+
+        let mut kinds: BTreeMap<&str, usize> = BTreeMap::new();
+        for &id in reachable.iter() {
+            *kinds.entry(scene.node(id).name()).or_default() += 1;
+        }
+        let prims: usize = reachable
+            .iter()
+            .filter(|&&id| matches!(scene.node(id), Node::Prim { .. }))
+            .count();
+        writeln!(
+            out,
+            "nodes: {} rendered, {} in total",
+            reachable.len(),
+            scene.nodes.len()
+        )
+        .unwrap();
+        writeln!(out, "primitives: {}", prims).unwrap();
+        for (kind, count) in kinds {
+            writeln!(out, "  {}: {}", kind, count).unwrap();
+        }
+
+        let points = scene
+            .lights
+            .iter()
+            .filter(|light| matches!(light, Light::Point { .. }))
+            .count();
+        writeln!(out, "patterns: {}", scene.patterns.len()).unwrap();
+        writeln!(out, "materials: {}", scene.materials.len()).unwrap();
+        writeln!(
+            out,
+            "lights: {} ({} point, {} diffuse)",
+            scene.lights.len(),
+            points,
+            scene.lights.len() - points
+        )
+        .unwrap();
+        writeln!(out, "renders: {}", self.parsed.renders.len()).unwrap();
+
+        let bounds = self
+            .parsed
+            .renders
+            .iter()
+            .fold(BoundingBox::min(), |acc, render| {
+                acc.union(scene.bounding_box(render.root))
+            });
+        match bounds {
+            BoundingBox::Min => writeln!(out, "bounds: empty").unwrap(),
+            BoundingBox::Max => writeln!(out, "bounds: unbounded").unwrap(),
+            BoundingBox::Bounds { min, max } => {
+                let size = max - min;
+                writeln!(
+                    out,
+                    "bounds: ({} {} {}) to ({} {} {}), {} x {} x {}",
+                    min.x, min.y, min.z, max.x, max.y, max.z, size.x, size.y, size.z
+                )
+                .unwrap()
+            }
+        }
+
+        let groups: Vec<_> = reachable
+            .iter()
+            .filter_map(|&id| match scene.node(id) {
+                Node::Group { nodes, .. } => Some(nodes.stats()),
+                _ => None,
+            })
+            .collect();
+        if !groups.is_empty() {
+            writeln!(
+                out,
+                "bvh: {} groups, {} leaves, deepest {}, largest leaf {}, {} unbounded",
+                groups.len(),
+                groups.iter().map(|stats| stats.leaves).sum::<usize>(),
+                groups.iter().map(|stats| stats.depth).max().unwrap_or(0),
+                groups.iter().map(|s| s.largest_leaf).max().unwrap_or(0),
+                groups.iter().map(|stats| stats.unbounded).sum::<usize>(),
+            )
+            .unwrap();
+        }
+
+        out
+    }
+
+    /// The node graph under each render, indented by depth. Named nodes are expanded the first
+    /// time they appear, and only named after that.
+    pub fn tree(&self) -> String {
+        let mut out = String::new();
+        let mut expanded = BTreeSet::new();
+        for (index, render) in self.parsed.renders.iter().enumerate() {
+            writeln!(out, "{}", render_label(index, render)).unwrap();
+            self.write_tree(&mut out, render.root, 1, &mut expanded);
+        }
+        out
+    }
+
+    fn write_tree(
+        &self,
+        out: &mut String,
+        id: NodeId,
+        depth: usize,
+        expanded: &mut BTreeSet<NodeId>,
+    ) {
+        let node = self.parsed.scene.node(id);
+        let indent = "  ".repeat(depth);
+        let Some(name) = self.names.get(&id) else {
+            writeln!(out, "{}{}", indent, node.name()).unwrap();
+            self.write_children(out, node, depth, expanded);
+            return;
+        };
+
+        if !expanded.insert(id) {
+            writeln!(out, "{}{} (above)", indent, name).unwrap();
+            return;
+        }
+
+        writeln!(out, "{}{}: {}", indent, name, node.name()).unwrap();
+        self.write_children(out, node, depth, expanded);
+    }
+
+    fn write_children(
+        &self,
+        out: &mut String,
+        node: &Node,
+        depth: usize,
+        expanded: &mut BTreeSet<NodeId>,
+    ) {
+        let children = node.children();
+        for &child in children.iter().take(MAX_CHILDREN) {
+            self.write_tree(out, child, depth + 1, expanded);
+        }
+        if children.len() > MAX_CHILDREN {
+            let indent = "  ".repeat(depth + 1);
+            writeln!(out, "{}... {} more", indent, children.len() - MAX_CHILDREN).unwrap();
+        }
+    }
+
+    /// The node graph as a Graphviz digraph, with the renders as boxes pointing at their roots.
+    /// Shared nodes appear once, with an edge from each of their parents.
+    pub fn dot(&self) -> String {
+        let scene = &self.parsed.scene;
+        let mut out = String::from("digraph scene {\n");
+        for (index, render) in self.parsed.renders.iter().enumerate() {
+            let label = render_label(index, render).replace('"', "\\\"");
+            writeln!(out, "  render{} [shape=box, label=\"{}\"];", index, label).unwrap();
+            writeln!(out, "  render{} -> node{};", index, render.root.index()).unwrap();
+        }
+
+        for id in self.reachable() {
+            let node = scene.node(id);
+            let label = match self.names.get(&id) {
+                Some(name) => format!("{}\\n{}", name, node.name()),
+                None => node.name().to_string(),
+            };
+            writeln!(out, "  node{} [label=\"{}\"];", id.index(), label).unwrap();
+            for child in node.children() {
+                writeln!(out, "  node{} -> node{};", id.index(), child.index()).unwrap();
+            }
+        }
+
+        out.push_str("}\n");
+        out
+    }
+}
+
+fn render_label(index: usize, render: &super::parser::Render) -> String {
+    let target = match &render.target {
+        Target::File { .. } => "file",
+        Target::Ascii { name, .. } => name,
+    };
+    match &render.name {
+        Some(name) => format!("render `{}` ({})", name, target),
+        None => format!("render {} ({})", index + 1, target),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_info() {
+        let info = Info::parse(
+            r#"
+            (node ball (sphere 1))
+            (node pair (group ball (transform (translate 2 0 0) ball)))
+            (light (point #ffffff (0 4 -4)))
+            (render (ascii "out") (whitted (uniform 1) (pinhole 8 8 (translate 0 0 -4) (degrees 60))) pair)
+            "#,
+            &[],
+        )
+        .unwrap();
+
+        let summary = info.summary();
+        assert!(summary.contains("primitives: 1\n"), "{}", summary);
+        assert!(
+            summary.contains("lights: 1 (1 point, 0 diffuse)\n"),
+            "{}",
+            summary
+        );
+        assert!(
+            summary.contains("bounds: (-1 -1 -1) to (3 1 1), 4 x 2 x 2\n"),
+            "{}",
+            summary
+        );
+        assert!(summary.contains("bvh: 1 groups"), "{}", summary);
+
+        assert_eq!(
+            "render 1 (out)\n  pair: group\n    ball: sphere\n    transform\n      ball (above)\n",
+            info.tree()
+        );
+
+        let dot = info.dot();
+        assert!(dot.starts_with("digraph scene {\n"), "{}", dot);
+        assert!(dot.contains("[label=\"ball\\nsphere\"]"), "{}", dot);
+        assert_eq!(4, dot.matches(" -> ").count(), "{}", dot);
+    }
+}
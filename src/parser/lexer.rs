@@ -1,6 +1,6 @@
 type Pos = u32;
 
-#[derive(Debug, Eq, PartialEq)]
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
 pub enum Token {
     LParen,
     RParen,
@@ -12,13 +12,13 @@ pub enum Token {
     Error,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Lexeme {
     pub token: Token,
     pub text: String,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Lexer<'a> {
     input: &'a str,
     chars: std::iter::Peekable<std::str::CharIndices<'a>>,
@@ -102,20 +102,30 @@ impl<'a> Lexer<'a> {
         }) > 0
     }
 
-    fn consume_number(&mut self) {
+    /// Consume the rest of a number that starts with `first`.
+    fn consume_number(&mut self, first: char) {
         let mut dot = false;
+        let mut exponent = false;
+        let mut prev = first;
 
         self.consume_while(|_, c| {
+            let prev = std::mem::replace(&mut prev, c);
             if c.is_ascii_digit() {
                 return true;
             }
 
-            if !dot && c == '.' {
+            if !dot && !exponent && c == '.' {
                 dot = true;
                 return true;
             }
 
-            c.is_ascii_digit()
+            // An exponent, like the `e-4` of `1e-4`.
+            if !exponent && (c == 'e' || c == 'E') && prev.is_ascii_digit() {
+                exponent = true;
+                return true;
+            }
+
+            exponent && (prev == 'e' || prev == 'E') && (c == '-' || c == '+')
         });
     }
 
@@ -134,17 +144,20 @@ impl<'a> Lexer<'a> {
             done = c == '"' && (consumed && prev != '\\');
             prev = c;
             true
-        }) > 0
+        });
+
+        done
     }
 
     fn text(&self, start: Pos, end: Pos) -> String {
-        let slice = self.input.get(start as usize..=end as usize).unwrap();
-        String::from(slice)
+        String::from(&self.input[start as usize..end as usize])
     }
 
-    /// Construct a lexeme.
+    /// Construct a lexeme that ends with the most recently consumed character. Offsets are byte
+    /// indices, so the end is found by stepping over that whole character.
     fn lexeme(&self, start: Pos, token: Token) -> Lexeme {
-        let end = self.offset;
+        let last = self.input[self.offset as usize..].chars().next();
+        let end = self.offset + last.map_or(0, char::len_utf8) as Pos;
         Lexeme {
             token,
             text: self.text(start, end),
@@ -186,21 +199,18 @@ impl<'a> Iterator for Lexer<'a> {
                 }
 
                 '-' => {
-                    self.consume_number();
+                    self.consume_number(c);
                     Token::Number
                 }
 
                 _ if c.is_ascii_digit() => {
-                    self.consume_number();
+                    self.consume_number(c);
                     Token::Number
                 }
 
                 _ if c.is_ascii_alphabetic() => {
-                    if self.consume_ident(true) {
-                        Token::Ident
-                    } else {
-                        Token::Error
-                    }
+                    self.consume_ident(true);
+                    Token::Ident
                 }
 
                 _ => Token::Error,
@@ -238,6 +248,17 @@ fn test_lex_basic() {
     assert!(lexer.next().is_none());
 }
 
+#[test]
+fn test_lex_exponent() {
+    let input = "1e-4 2.5E3 -1e+2 3e";
+    let mut lexer = Lexer::new(input);
+    lexer_next!(lexer, Token::Number, "1e-4");
+    lexer_next!(lexer, Token::Number, "2.5E3");
+    lexer_next!(lexer, Token::Number, "-1e+2");
+    lexer_next!(lexer, Token::Number, "3e");
+    assert!(lexer.next().is_none());
+}
+
 #[test]
 fn test_lex_leading_space() {
     let input = "         :symbol1 :symbol-2";
@@ -266,3 +287,66 @@ fn test_lex_leading_comment() {
     let mut lexer = Lexer::new(input);
     lexer_next!(lexer, Token::Symbol, ":symbol");
 }
+
+#[test]
+fn test_lex_short_ident() {
+    let input = "(a b)";
+    let mut lexer = Lexer::new(input);
+    lexer_next!(lexer, Token::LParen, "(");
+    lexer_next!(lexer, Token::Ident, "a");
+    lexer_next!(lexer, Token::Ident, "b");
+    lexer_next!(lexer, Token::RParen, ")");
+}
+
+#[test]
+fn test_lex_malformed() {
+    let input = "é \"open";
+    let mut lexer = Lexer::new(input);
+    lexer_next!(lexer, Token::Error, "é");
+    lexer_next!(lexer, Token::Error, "\"open");
+    assert!(lexer.next().is_none());
+}
+
+/// Random streams of mostly well-formed tokens, drawn from the scene language's vocabulary so that
+/// generated inputs get past the first token of a command reasonably often.
+#[cfg(test)]
+pub(super) fn token_stream() -> impl proptest::strategy::Strategy<Value = String> {
+    use proptest::prelude::*;
+
+    let token = prop_oneof![
+        4 => Just(String::from("(")),
+        4 => Just(String::from(")")),
+        3 => prop::sample::select(vec![
+            "node", "pattern", "material", "light", "camera", "sampler", "render", "fog", "bvh",
+            "if-flag", "sphere", "box", "group", "union", "smooth-union", "transform", "ring",
+            "phyllotaxis", "translate", "look-at", "pinhole", "whitted", "uniform", "file",
+            "ascii", "phong", "solid", "paint", "volume", "degrees", "diffuse", "point", "a", "b",
+        ])
+        .prop_map(String::from),
+        2 => prop::sample::select(vec![
+            ":count", ":radius", ":color", ":density", ":horizon", ":split", ":exposure", ":x",
+        ])
+        .prop_map(String::from),
+        3 => "-?[0-9]{1,3}(\\.[0-9]{0,2})?",
+        1 => "#[0-9a-f]{0,6}",
+        1 => "\"[a-z.]{0,4}\"?",
+        1 => "[;\\\\'@é\"-]",
+    ];
+
+    prop::collection::vec(token, 0..64).prop_map(|tokens| tokens.join(" "))
+}
+
+#[cfg(test)]
+proptest::proptest! {
+    #[test]
+    fn test_lex_never_panics(input in "\\PC*") {
+        for lexeme in Lexer::new(&input) {
+            proptest::prop_assert!(input.contains(&lexeme.text));
+        }
+    }
+
+    #[test]
+    fn test_lex_token_stream(input in token_stream()) {
+        Lexer::new(&input).count();
+    }
+}
@@ -1,57 +1,336 @@
-use anyhow::bail;
-use nalgebra::{Point3, Unit, Vector3};
-use std::collections::HashMap;
+use anyhow::{bail, Context};
+use nalgebra::{Point2, Point3, Quaternion, Unit, UnitQuaternion, Vector3};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::hash::{Hash, Hasher};
 use std::iter::Peekable;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
 use std::sync::Arc;
 
 use crate::sampler::{Sampler, UniformSampler};
 use crate::scene::{MarchConfig, PatternId};
 use crate::{
-    camera::{Camera, CanvasInfo, PinholeCamera},
-    canvas::Color,
-    integrator::{IntegratorBuilder, WhittedBuilder},
-    math,
-    scene::{MaterialId, NodeId, Scene},
-    transform::Transform,
+    bvh::Split,
+    camera::{
+        Camera, CanvasInfo, EquirectangularCamera, FisheyeCamera, FisheyeProjection, PinholeCamera,
+        View,
+    },
+    canvas::{
+        AsciiMode, AutoExposure, BitDepth, Bloom, Canvas, Channels, Color, Denoise, LensEffects,
+        MAX_BLOOM_SIZE, MAX_DENOISE_RADIUS, MAX_STREAKS,
+    },
+    fractal::{Fractal, FractalKind},
+    grid::DistanceGrid,
+    heightfield::Heightfield,
+    integrator::{
+        Background, DebugBuilder, DebugMode, IntegratorBuilder, Mapping, Outline, WhittedBuilder,
+    },
+    lsystem::{LSystem, Turtle},
+    matcap::Matcap,
+    math::{self, Float},
+    metaballs::{Metaball, Metaballs},
+    polygon::{Polygon, Profile},
+    scene::{
+        non_negative, positive, Backface, Bias, Displacement, Falloff, Fog, MaterialId, NodeId,
+        Scene, Volume, MAX_COPIES, MAX_DEPTH_LIMIT,
+    },
+    text,
+    transform::{ApplyTransform, Transform},
 };
 
 use super::lexer::{Lexeme, Lexer, Token};
+use super::plugins::{IntegratorArgs, Plugins, PrimitiveArgs, Value};
+use super::template::PathTemplate;
 
 type Result<T> = std::result::Result<T, anyhow::Error>;
 
-pub fn parse(input: &str) -> Result<(Scene, Vec<Render>)> {
+/// The deepest that parenthesized expressions may be nested, which keeps malformed input from
+/// exhausting the stack.
+pub(super) const MAX_DEPTH: usize = 128;
+
+/// Parse a scene description. Conditional commands guarded by `if-flag` are enabled by the
+/// names present in `flags`.
+pub fn parse(input: &str, flags: &[String]) -> Result<(Scene, Vec<Render>)> {
+    parse_with(input, flags, &Overrides::default())
+}
+
+/// Parse a scene description, replacing some of the values that it gives for its renders.
+pub fn parse_with(
+    input: &str,
+    flags: &[String],
+    overrides: &Overrides,
+) -> Result<(Scene, Vec<Render>)> {
+    parse_with_plugins(input, flags, overrides, &Plugins::default())
+}
+
+/// Parse a scene description that may use the forms added by `plugins`.
+pub fn parse_with_plugins(
+    input: &str,
+    flags: &[String],
+    overrides: &Overrides,
+    plugins: &Plugins,
+) -> Result<(Scene, Vec<Render>)> {
+    if overrides
+        .scale
+        .is_some_and(|scale| scale <= 0. || !scale.is_finite())
+    {
+        bail!("The resolution scale must be a positive number");
+    }
+
+    let parser = parse_all(input, flags, overrides, plugins)?;
+    let mut renders = parser.renders;
+
+    for name in overrides.only.iter().chain(overrides.skip.iter()) {
+        if !renders.iter().any(|render| render.is_named(name)) {
+            bail!("There is no render named `{}`", name);
+        }
+    }
+
+    renders.retain(|render| {
+        (overrides.only.is_empty() || overrides.only.iter().any(|name| render.is_named(name)))
+            && !overrides.skip.iter().any(|name| render.is_named(name))
+    });
+
+    Ok((parser.scene, renders))
+}
+
+/// Parse a scene description, and find the node declared as `name`, or the root of the first
+/// render if no name is given. When a name is declared more than once, the last declaration is
+/// used.
+pub fn parse_node(input: &str, flags: &[String], name: Option<&str>) -> Result<(Scene, NodeId)> {
+    let parsed = parse_all(input, flags, &Overrides::default(), &Plugins::default())?;
+    let node = match name {
+        Some(name) => parsed
+            .declarations
+            .iter()
+            .rev()
+            .find(|decl| decl.name == name && decl.node.is_some())
+            .and_then(|decl| decl.node),
+        None => parsed.renders.first().map(|render| render.root),
+    };
+    match (node, name) {
+        (Some(node), _) => Ok((parsed.scene, node)),
+        (None, Some(name)) => bail!("There is no node named `{}`", name),
+        (None, None) => bail!("The scene has no renders"),
+    }
+}
+
+/// A scene description after parsing, along with the names that it declared.
+pub(super) struct Parsed {
+    pub scene: Scene,
+    pub renders: Vec<Render>,
+    pub declarations: Vec<Declaration>,
+}
+
+/// A name declared at the top-level of a scene description.
+pub(super) struct Declaration {
+    /// What the name refers to, such as `node` or `material`.
+    pub kind: &'static str,
+    pub name: String,
+
+    /// The node that the name refers to, for `node` declarations.
+    pub node: Option<NodeId>,
+
+    /// The index of the top-level command that made the declaration.
+    pub command: usize,
+
+    /// True when the name was referred to after it was declared. A name that's declared again
+    /// is a new declaration, which hides the old one.
+    pub used: bool,
+}
+
+/// Parse the whole of a scene description, keeping every render.
+pub(super) fn parse_all(
+    input: &str,
+    flags: &[String],
+    overrides: &Overrides,
+    plugins: &Plugins,
+) -> Result<Parsed> {
+    let _span = tracing::debug_span!("parse").entered();
     let mut parser = Parser::new(Lexer::new(input));
+    parser.flags.extend(flags.iter().cloned());
+    parser.overrides = overrides.clone();
+    parser.plugins = plugins.clone();
     parser.parse()?;
-    Ok((parser.scene, parser.renders))
+
+    // Marching recurses through the nodes, so graphs that are too deep are caught here instead.
+    for (index, render) in parser.renders.iter().enumerate() {
+        parser
+            .scene
+            .check_graph(render.root)
+            .with_context(|| format!("Render {} can't be marched", index + 1))?;
+    }
+    Ok(Parsed {
+        scene: parser.scene,
+        renders: parser.renders,
+        declarations: parser.declarations,
+    })
+}
+
+/// Values that replace the ones given by the renders in a scene description, and the renders to
+/// run, for making quick test renders of a scene without editing it.
+#[derive(Debug, Default, Clone, clap::Args)]
+pub struct Overrides {
+    #[clap(long, help = "Scale the resolution of every camera")]
+    pub scale: Option<Float>,
+
+    #[clap(
+        long,
+        help = "Take this many samples for each pixel",
+        value_parser = clap::value_parser!(u32).range(1..)
+    )]
+    pub samples: Option<u32>,
+
+    #[clap(long, help = "Write file targets to this directory")]
+    pub output_dir: Option<PathBuf>,
+
+    #[clap(long, help = "Only run the render with this name")]
+    pub only: Vec<String>,
+
+    #[clap(long, help = "Don't run the render with this name")]
+    pub skip: Vec<String>,
+
+    /// Moves every camera, from the camera controls of the web ui.
+    #[clap(skip)]
+    pub view: Option<View>,
+}
+
+/// True when the conditional form `cond` should use its first declaration.
+pub(super) fn condition_enabled(cond: &str, name: &str, flags: &HashSet<String>) -> bool {
+    if cond == "if-flag" {
+        flags.contains(name)
+    } else {
+        std::env::var_os(name).is_some_and(|val| !val.is_empty())
+    }
+}
+
+/// Reject march parameters that would never hit anything, or never stop.
+fn check_march(config: &MarchConfig) -> Result<()> {
+    if config.max_steps == 0 {
+        bail!("The :max-steps of a march must be positive");
+    }
+
+    if config.min_dist.is_nan() || config.min_dist <= 0. {
+        bail!("The :min-dist of a march must be positive");
+    }
+
+    if config.max_dist.is_nan() || config.max_dist <= config.min_dist {
+        bail!("The :max-dist of a march must be larger than its :min-dist");
+    }
+
+    Ok(())
 }
 
 /// How to handle the result of rendering.
 pub enum Target {
-    /// Write the output to this file.
-    File { path: PathBuf },
+    /// Write the output to the file that this path expands to.
+    File {
+        path: PathTemplate,
+        channels: Channels,
+        depth: BitDepth,
+
+        /// Write the tiles of the image to the file as they finish, rather than keeping the whole
+        /// image in memory.
+        stream: bool,
+    },
 
     /// Output the image to the console.
-    Ascii { name: String },
+    Ascii { name: String, mode: AsciiMode },
 }
 
 pub struct Render {
+    /// The name used to select the render from the command line.
+    pub name: Option<String>,
     pub target: Target,
     pub canvas_info: CanvasInfo,
     pub root: NodeId,
     pub sampler: Box<dyn Sampler>,
     pub builder: Box<dyn IntegratorBuilder>,
+    pub exposure: Option<AutoExposure>,
+    pub bloom: Option<Bloom>,
+    pub denoise: Option<Denoise>,
+    pub lens: Option<LensEffects>,
+
+    /// The size of a smaller copy of the image to write alongside it.
+    pub preview: Option<(u32, u32)>,
+
+    /// A hash of the commands that the render depends on, which stays the same when the scene
+    /// description is edited in ways that can't change the image.
+    pub fingerprint: u64,
+
+    /// The files that the commands the render depends on read, such as fonts and heightfields.
+    pub files: Vec<PathBuf>,
+}
+
+impl Render {
+    fn is_named(&self, name: &str) -> bool {
+        self.name.as_deref() == Some(name)
+    }
+
+    /// Apply the lens effects, bloom and exposure of the render to its finished canvas, in that
+    /// order. Denoising comes before them, as it needs the guides of the render.
+    pub fn apply_effects(&self, canvas: &mut Canvas) {
+        if let Some(lens) = &self.lens {
+            lens.apply(canvas);
+        }
+
+        if let Some(bloom) = &self.bloom {
+            bloom.apply(canvas);
+        }
+
+        if let Some(exposure) = &self.exposure {
+            exposure.apply(canvas);
+        }
+    }
+}
+
+/// What went into a top-level command, for working out which commands each render depends on.
+#[derive(Default)]
+struct Command {
+    /// Hashes the tokens of the command, so whitespace and comments are ignored.
+    hasher: DefaultHasher,
+
+    /// The earlier commands that declared the names that this one uses.
+    uses: BTreeSet<usize>,
+
+    /// True for commands that change every render, like lights and fog.
+    global: bool,
+
+    /// The files that the command read.
+    files: Vec<PathBuf>,
 }
 
 struct Parser<'a> {
     lexer: Peekable<Lexer<'a>>,
     scene: Scene,
     nodes: HashMap<String, NodeId>,
+
+    /// The nodes given each tag, in the order that they were tagged.
+    tags: HashMap<String, Vec<NodeId>>,
+
     patterns: HashMap<String, PatternId>,
     materials: HashMap<String, MaterialId>,
     cameras: Vec<(String, CanvasInfo, Arc<dyn Camera>)>,
+    samplers: HashMap<String, Box<dyn Sampler>>,
     renders: Vec<Render>,
+    flags: HashSet<String>,
+    overrides: Overrides,
+    plugins: Plugins,
+    depth: usize,
+
+    /// The target of the last `look-at` transform, which a [`View`] moves the camera around.
+    look_at: Option<Point3<Float>>,
+
+    /// Every name declared so far, in order.
+    declarations: Vec<Declaration>,
+
+    /// The march parameters that integrators start from, as set by `(defaults ...)`.
+    march: MarchConfig,
+
+    /// The top-level commands parsed so far, and the command that each render came from.
+    commands: Vec<Command>,
+    render_commands: Vec<usize>,
 }
 
 impl<'a> Parser<'a> {
@@ -60,15 +339,113 @@ impl<'a> Parser<'a> {
             lexer: lexer.peekable(),
             scene: Scene::default(),
             nodes: HashMap::new(),
+            tags: HashMap::new(),
             patterns: HashMap::new(),
             materials: HashMap::new(),
             cameras: Vec::new(),
+            samplers: HashMap::new(),
             renders: Vec::new(),
+            flags: HashSet::new(),
+            overrides: Overrides::default(),
+            plugins: Plugins::default(),
+            depth: 0,
+            look_at: None,
+            declarations: Vec::new(),
+            march: MarchConfig::default(),
+            commands: Vec::new(),
+            render_commands: Vec::new(),
+        }
+    }
+
+    fn declare(&mut self, kind: &'static str, name: &str, node: Option<NodeId>) {
+        self.declarations.push(Declaration {
+            kind,
+            name: name.to_string(),
+            node,
+            command: self.commands.len().saturating_sub(1),
+            used: false,
+        });
+    }
+
+    /// Note that the latest declaration of `name` is used.
+    fn use_name(&mut self, kind: &'static str, name: &str) {
+        if let Some(decl) = self
+            .declarations
+            .iter_mut()
+            .rev()
+            .find(|decl| decl.kind == kind && decl.name == name)
+        {
+            decl.used = true;
+            let command = decl.command;
+            if let Some(current) = self.commands.last_mut() {
+                current.uses.insert(command);
+            }
+        }
+    }
+
+    /// Note that the current command changes every render.
+    fn use_globally(&mut self) {
+        if let Some(current) = self.commands.last_mut() {
+            current.global = true;
+        }
+    }
+
+    /// Note that the current command reads the file at `path`, so that the renders depending on
+    /// it change along with the file.
+    fn use_file(&mut self, path: &Path) {
+        let modified = std::fs::metadata(path)
+            .and_then(|meta| meta.modified())
+            .ok();
+        if let Some(current) = self.commands.last_mut() {
+            modified.hash(&mut current.hasher);
+            current.files.push(path.to_path_buf());
+        }
+    }
+
+    /// Fill in the fingerprints and files of the renders, from their own commands, the commands
+    /// that change every render, and the commands that declared the names they use.
+    fn fingerprint_renders(&mut self) {
+        let global = self
+            .commands
+            .iter()
+            .enumerate()
+            .filter(|(_, command)| command.global)
+            .map(|(ix, _)| ix);
+        let global: Vec<_> = global.collect();
+
+        for (render, &command) in self.renders.iter_mut().zip(&self.render_commands) {
+            let mut deps = BTreeSet::new();
+            let mut pending = global.clone();
+            pending.push(command);
+            while let Some(ix) = pending.pop() {
+                if deps.insert(ix) {
+                    pending.extend(self.commands[ix].uses.iter().copied());
+                }
+            }
+
+            // The commands are hashed in order, but not their positions, so adding or removing
+            // commands that the render doesn't depend on leaves it alone.
+            let mut hasher = DefaultHasher::new();
+            let mut files = Vec::new();
+            for ix in deps {
+                self.commands[ix].hasher.finish().hash(&mut hasher);
+                for file in &self.commands[ix].files {
+                    if !files.contains(file) {
+                        files.push(file.clone());
+                    }
+                }
+            }
+            render.fingerprint = hasher.finish();
+            render.files = files;
         }
     }
 
     fn token(&mut self) -> Result<Lexeme> {
         if let Some(lexeme) = self.lexer.next() {
+            if let Some(current) = self.commands.last_mut() {
+                lexeme.token.hash(&mut current.hasher);
+                lexeme.text.hash(&mut current.hasher);
+            }
             Ok(lexeme)
         } else {
             bail!("Unexpected EOF")
@@ -108,11 +485,26 @@ impl<'a> Parser<'a> {
         Body: FnOnce(&mut Self) -> Result<T>,
     {
         self.lparen()?;
-        let ret = body(self)?;
+        if self.depth == MAX_DEPTH {
+            bail!("Expressions are nested more than {} deep", MAX_DEPTH);
+        }
+
+        self.depth += 1;
+        let ret = body(self);
+        self.depth -= 1;
+
+        let ret = ret?;
         self.rparen()?;
         Ok(ret)
     }
 
+    /// True when the next tokens open the form `head`.
+    fn peek_form(&mut self, head: &str) -> bool {
+        let mut ahead = self.lexer.clone();
+        matches!(ahead.next(), Some(tok) if tok.token == Token::LParen)
+            && matches!(ahead.next(), Some(tok) if tok.token == Token::Ident && tok.text == head)
+    }
+
     fn peek_rparen(&mut self) -> bool {
         if let Some(tok) = self.lexer.peek() {
             tok.token == Token::RParen
@@ -121,6 +513,24 @@ impl<'a> Parser<'a> {
         }
     }
 
+    /// Consume a single expression without interpreting it.
+    fn skip(&mut self) -> Result<()> {
+        let mut depth = 0;
+        loop {
+            match self.token()?.token {
+                Token::LParen => depth += 1,
+                Token::RParen if depth == 0 => bail!("Unexpected `)`"),
+                Token::RParen => depth -= 1,
+                Token::Error => bail!("Failed to parse token"),
+                _ => (),
+            }
+
+            if depth == 0 {
+                return Ok(());
+            }
+        }
+    }
+
     fn ident(&mut self) -> Result<String> {
         let tok = self.guard(Token::Ident)?;
         Ok(tok.text)
@@ -144,17 +554,56 @@ impl<'a> Parser<'a> {
         }
     }
 
-    fn number(&mut self) -> Result<f32> {
+    fn peek_symbol(&mut self) -> bool {
+        if let Some(tok) = self.lexer.peek() {
+            tok.token == Token::Symbol
+        } else {
+            false
+        }
+    }
+
+    /// True when the next token could start a node.
+    fn peek_node(&mut self) -> bool {
+        self.peek_lparen() || self.peek_ident()
+    }
+
+    fn number(&mut self) -> Result<Float> {
         if self.peek_lparen() {
             return self.angle();
         }
 
         let tok = self.guard(Token::Number)?;
-        let num = f32::from_str(&tok.text)?;
+        let num = Float::from_str(&tok.text)?;
+        if !num.is_finite() {
+            bail!("The number `{}` is too large", tok.text);
+        }
         Ok(num)
     }
 
-    fn angle(&mut self) -> Result<f32> {
+    fn boolean(&mut self) -> Result<bool> {
+        match self.ident()?.as_ref() {
+            "true" => Ok(true),
+            "false" => Ok(false),
+            other => bail!("Expected `true` or `false`, but found `{}`", other),
+        }
+    }
+
+    /// A number that describes light rather than geometry, which doesn't need the precision of
+    /// [`Float`].
+    fn scalar(&mut self) -> Result<f32> {
+        Ok(self.number()? as f32)
+    }
+
+    /// An angle in degrees, or in either unit when written as with [`Self::angle`]. The result is
+    /// in radians.
+    fn degrees(&mut self) -> Result<Float> {
+        if self.peek_lparen() {
+            return self.angle();
+        }
+        Ok(math::deg_to_rad(self.number()?))
+    }
+
+    fn angle(&mut self) -> Result<Float> {
         self.parens(|me| match me.ident()?.as_ref() {
             "degrees" => {
                 let deg = me.number()?;
@@ -177,7 +626,7 @@ impl<'a> Parser<'a> {
         Ok(Color::hex(val))
     }
 
-    fn point(&mut self) -> Result<Point3<f32>> {
+    fn point(&mut self) -> Result<Point3<Float>> {
         self.parens(|me| {
             let x = me.number()?;
             let y = me.number()?;
@@ -186,7 +635,7 @@ impl<'a> Parser<'a> {
         })
     }
 
-    fn vector(&mut self) -> Result<Vector3<f32>> {
+    fn vector(&mut self) -> Result<Vector3<Float>> {
         self.parens(|me| {
             let x = me.number()?;
             let y = me.number()?;
@@ -198,8 +647,9 @@ impl<'a> Parser<'a> {
     fn parse_transforms(&mut self) -> Result<Transform> {
         let mut res = Transform::new();
 
+        // Each transform applies to the node before the ones written to its left.
         while !self.peek_rparen() {
-            res = res * &self.parse_transform()?;
+            res = self.parse_transform()?.then(&res);
         }
 
         Ok(res)
@@ -221,6 +671,50 @@ impl<'a> Parser<'a> {
                 Ok(Transform::new().rotate(&axisangle))
             }
 
+            "rotate-x" => {
+                let angle = me.degrees()?;
+                Ok(Transform::new().then_rotate(&(Vector3::x() * angle)))
+            }
+
+            "rotate-y" => {
+                let angle = me.degrees()?;
+                Ok(Transform::new().then_rotate(&(Vector3::y() * angle)))
+            }
+
+            "rotate-z" => {
+                let angle = me.degrees()?;
+                Ok(Transform::new().then_rotate(&(Vector3::z() * angle)))
+            }
+
+            "rotate-euler" => {
+                let x = me.degrees()?;
+                let y = me.degrees()?;
+                let z = me.degrees()?;
+                let rotation = UnitQuaternion::from_euler_angles(x, y, z);
+                Ok(Transform::new().then_rotation(&rotation))
+            }
+
+            "rotate-quat" => {
+                let w = me.number()?;
+                let i = me.number()?;
+                let j = me.number()?;
+                let k = me.number()?;
+                let Some(rotation) = UnitQuaternion::try_new(Quaternion::new(w, i, j, k), 0.)
+                else {
+                    bail!("A rotate-quat transform needs a quaternion that isn't zero");
+                };
+                Ok(Transform::new().then_rotation(&rotation))
+            }
+
+            "about" => {
+                let pivot = me.point()?;
+                let inner = me.parse_transform()?;
+                Ok(Transform::new()
+                    .then_translate(&-pivot.coords)
+                    .then(&inner)
+                    .then_translate(&pivot.coords))
+            }
+
             "uniform-scale" => {
                 let amount = me.number()?;
                 Ok(Transform::new().uniform_scale(amount))
@@ -235,6 +729,13 @@ impl<'a> Parser<'a> {
                 let eye = me.point()?;
                 let target = me.point()?;
                 let up = me.vector()?;
+                if (target - eye).cross(&up).norm() == 0. {
+                    bail!(
+                        "A look-at transform needs its eye apart from its target, and an up \
+                         vector that isn't along the line between them"
+                    );
+                }
+                me.look_at = Some(target);
                 Ok(Transform::look_at(&eye, &target, &up))
             }
 
@@ -245,8 +746,9 @@ impl<'a> Parser<'a> {
     fn parse_pattern(&mut self) -> Result<PatternId> {
         if self.peek_ident() {
             let name = self.ident()?;
-            if let Some(id) = self.patterns.get(&name) {
-                return Ok(*id);
+            if let Some(&id) = self.patterns.get(&name) {
+                self.use_name("pattern", &name);
+                return Ok(id);
             } else {
                 bail!("Unknown pattern: {}", name)
             }
@@ -262,6 +764,34 @@ impl<'a> Parser<'a> {
                 let second = me.parse_pattern()?;
                 Ok(me.scene.gradiant(first, second))
             }
+            "ramp" => {
+                let mut axis = Vector3::x();
+                let mut stops: Vec<(Float, Color)> = Vec::new();
+                while !me.peek_rparen() {
+                    me.parens(|me| match me.ident()?.as_ref() {
+                        "axis" => {
+                            axis = me.vector()?;
+                            Ok(())
+                        }
+                        "stop" => {
+                            let at = me.number()?;
+                            let color = me.color()?;
+                            if stops.last().is_some_and(|(last, _)| at < *last) {
+                                bail!("The stops of a ramp must be in increasing order");
+                            }
+                            stops.push((at, color));
+                            Ok(())
+                        }
+                        field => bail!("Unknown ramp field `{}`", field),
+                    })?;
+                }
+
+                if stops.is_empty() {
+                    bail!("A ramp needs at least one stop");
+                }
+
+                Ok(me.scene.ramp(axis, stops))
+            }
             "stripes" => {
                 let first = me.parse_pattern()?;
                 let second = me.parse_pattern()?;
@@ -282,6 +812,71 @@ impl<'a> Parser<'a> {
                 let pattern = me.parse_pattern()?;
                 Ok(me.scene.transform_pat(transform, pattern))
             }
+            "facing" => {
+                let facing = me.parse_pattern()?;
+                let edge = me.parse_pattern()?;
+                let mut power = 1.;
+                while !me.peek_rparen() {
+                    match me.symbol()?.as_ref() {
+                        ":power" => power = me.number()?,
+                        sym => bail!("Unknown facing field `{}`", sym),
+                    }
+                }
+
+                if power <= 0. {
+                    bail!("The power of a facing pattern must be positive");
+                }
+
+                Ok(me.scene.facing(facing, edge, power))
+            }
+            "triplanar" => {
+                let scale = me.number()?;
+                let pattern = me.parse_pattern()?;
+                Ok(me.scene.triplanar(scale, pattern))
+            }
+            "uv" => {
+                let pattern = me.parse_pattern()?;
+                Ok(me.scene.uv(pattern))
+            }
+            "curvature" => {
+                let pattern = me.parse_pattern()?;
+                let mut radius = 0.01;
+                while !me.peek_rparen() {
+                    match me.symbol()?.as_ref() {
+                        ":radius" => radius = me.number()?,
+                        sym => bail!("Unknown curvature field `{}`", sym),
+                    }
+                }
+
+                if radius <= 0. {
+                    bail!("The radius of a curvature pattern must be positive");
+                }
+
+                Ok(me.scene.curvature(radius, pattern))
+            }
+            "contours" => {
+                let node = me.parse_node()?;
+                let first = me.parse_pattern()?;
+                let line = me.parse_pattern()?;
+                let mut spacing = 0.1;
+                let mut width = 0.01;
+                while !me.peek_rparen() {
+                    match me.symbol()?.as_ref() {
+                        ":spacing" => spacing = me.number()?,
+                        ":width" => width = me.number()?,
+                        sym => bail!("Unknown contours field `{}`", sym),
+                    }
+                }
+
+                if spacing <= 0. {
+                    bail!("The spacing of contours must be positive");
+                }
+                if width < 0. {
+                    bail!("The width of contour lines can't be negative");
+                }
+
+                Ok(me.scene.contours(node, spacing, width, first, line))
+            }
             pat => bail!("Unknown pattern type: {}", pat),
         })
     }
@@ -289,8 +884,9 @@ impl<'a> Parser<'a> {
     fn parse_material(&mut self) -> Result<MaterialId> {
         if self.peek_ident() {
             let name = self.ident()?;
-            if let Some(id) = self.materials.get(&name) {
-                return Ok(*id);
+            if let Some(&id) = self.materials.get(&name) {
+                self.use_name("material", &name);
+                return Ok(id);
             } else {
                 bail!("Unknown material: {}", name);
             }
@@ -305,23 +901,103 @@ impl<'a> Parser<'a> {
                 let mut shininess = 200.0;
                 let mut reflective = 0.0;
 
+                // mirror reflections by default
+                let mut roughness = 0.0;
+                let mut gloss_samples = 8;
+                let mut fresnel = true;
+
                 // opaque, by  default
                 let mut transparent = 0.0;
 
                 // vacuum by default
                 let mut refractive_index = 1.0;
 
+                // no dispersion by default
+                let mut abbe = None;
+
+                // no absorption by default
+                let mut absorption = Color::white();
+                let mut density = 1.0;
+
+                // no subsurface scattering by default
+                let mut scatter = Color::black();
+                let mut scatter_depth = 1.0;
+
+                // no thin film by default
+                let mut film = 0.0;
+                let mut film_index = 1.33;
+
+                // overlapping media are resolved by the order they're entered in by default
+                let mut priority = 0;
+                let mut backface = Backface::default();
+                let mut bias = Bias::default();
+
                 while !me.peek_rparen() {
                     match me.symbol()?.as_ref() {
                         ":pattern" => pattern = Some(me.parse_pattern()?),
-                        ":ambient" => ambient = me.number()?,
-                        ":diffuse" => diffuse = me.number()?,
-                        ":specular" => specular = me.number()?,
-                        ":shininess" => shininess = me.number()?,
-                        ":reflective" => reflective = me.number()?,
-                        ":transparent" => transparent = me.number()?,
+                        ":ambient" => ambient = me.scalar()?,
+                        ":diffuse" => diffuse = me.scalar()?,
+                        ":specular" => specular = me.scalar()?,
+                        ":shininess" => shininess = me.scalar()?,
+                        ":reflective" => reflective = me.scalar()?,
+                        ":roughness" => roughness = me.scalar()?.clamp(0., 1.),
+                        ":gloss-samples" => {
+                            let count = me.number()?;
+                            if count < 1. {
+                                bail!("A rough material needs at least one :gloss-samples");
+                            }
+                            gloss_samples = count as u32;
+                        }
+                        ":fresnel" => fresnel = me.boolean()?,
+                        ":transparent" => transparent = me.scalar()?,
                         ":refractive_index" => refractive_index = me.number()?,
-                        sym => bail!("Unknown material field `{}`", sym),
+                        ":abbe" => {
+                            let number = me.number()?;
+                            if number <= 0. {
+                                bail!("A material's :abbe number must be positive");
+                            }
+                            abbe = Some(number);
+                        }
+                        ":absorption" => absorption = me.color()?,
+                        ":density" => {
+                            density = me.scalar()?;
+                            if density < 0. {
+                                bail!("A material's :density can't be negative");
+                            }
+                        }
+                        ":scatter" => scatter = me.color()?,
+                        ":scatter-depth" => {
+                            scatter_depth = me.number()?;
+                            if scatter_depth <= 0. {
+                                bail!("A material's :scatter-depth must be positive");
+                            }
+                        }
+                        ":film" => {
+                            film = me.number()?;
+                            if film < 0. {
+                                bail!("The :film on a material can't be negative");
+                            }
+                        }
+                        ":film-index" => {
+                            film_index = me.number()?;
+                            if film_index <= 0. {
+                                bail!("A material's :film-index must be positive");
+                            }
+                        }
+                        ":priority" => {
+                            let number = me.number()?;
+                            if !(0. ..=u32::MAX as Float).contains(&number) || number.fract() != 0.
+                            {
+                                bail!("A material's :priority must be a non-negative integer");
+                            }
+                            priority = number as u32;
+                        }
+                        ":backface" => backface = me.parse_backface()?,
+                        sym => {
+                            if !me.parse_bias_field(&mut bias, sym)? {
+                                bail!("Unknown material field `{}`", sym);
+                            }
+                        }
                     }
                 }
 
@@ -330,7 +1006,7 @@ impl<'a> Parser<'a> {
                     None => bail!("Material is missing a :pattern"),
                 };
 
-                Ok(me.scene.phong(
+                let material = me.scene.phong(
                     pattern,
                     ambient,
                     diffuse,
@@ -339,22 +1015,179 @@ impl<'a> Parser<'a> {
                     reflective,
                     transparent,
                     refractive_index,
-                ))
+                );
+                me.scene.set_backface(material, backface);
+                me.scene.set_bias(material, bias);
+                me.scene.set_gloss(material, roughness, gloss_samples);
+                me.scene.set_fresnel(material, fresnel);
+                me.scene.set_scatter(material, scatter, scatter_depth);
+                me.scene.set_film(material, film, film_index);
+
+                // Light passing through the object tends towards the absorption color.
+                me.scene.set_absorption(
+                    material,
+                    Color::new(
+                        density * (1. - absorption.r),
+                        density * (1. - absorption.g),
+                        density * (1. - absorption.b),
+                    ),
+                );
+                me.scene.set_priority(material, priority);
+
+                // The Abbe number relates the spread of the index between blue and red light to
+                // how far the index is from a vacuum's, so lower numbers disperse more.
+                if let Some(abbe) = abbe {
+                    me.scene
+                        .set_dispersion(material, (refractive_index - 1.).max(0.) / abbe);
+                }
+                Ok(material)
+            }
+
+            "toon" => {
+                let mut pattern = None;
+                let mut ambient = 0.1;
+                let mut bands = 3;
+                let mut rim = Color::black();
+                let mut rim_width = 0.3;
+                let mut outline = None;
+                let mut outline_width = 0.2;
+                let mut backface = Backface::default();
+                let mut bias = Bias::default();
+
+                while !me.peek_rparen() {
+                    match me.symbol()?.as_ref() {
+                        ":pattern" => pattern = Some(me.parse_pattern()?),
+                        ":ambient" => ambient = me.scalar()?,
+                        ":bands" => {
+                            let count = me.number()?;
+                            if count < 1. {
+                                bail!("A toon material needs at least one band");
+                            }
+                            bands = count as u32;
+                        }
+                        ":rim" => rim = me.color()?,
+                        ":rim-width" => rim_width = me.scalar()?,
+                        ":outline" => outline = Some(me.color()?),
+                        ":outline-width" => outline_width = me.scalar()?,
+                        ":backface" => backface = me.parse_backface()?,
+                        sym => {
+                            if !me.parse_bias_field(&mut bias, sym)? {
+                                bail!("Unknown material field `{}`", sym);
+                            }
+                        }
+                    }
+                }
+
+                let pattern = match pattern {
+                    Some(pat) => pat,
+                    None => bail!("Material is missing a :pattern"),
+                };
+
+                let material = me.scene.toon(pattern, ambient, bands);
+                me.scene.set_rim(material, rim, rim_width);
+                me.scene.set_outline(material, outline, outline_width);
+                me.scene.set_backface(material, backface);
+                me.scene.set_bias(material, bias);
+                Ok(material)
+            }
+
+            "matcap" => {
+                let path = PathBuf::from(me.string()?);
+                me.use_file(&path);
+                let material = me.scene.matcap(Matcap::load(&path)?);
+                me.parse_surface_fields(material)?;
+                Ok(material)
             }
 
             "emissive" => {
                 let pattern = me.parse_pattern()?;
-                Ok(me.scene.emissive(pattern))
+                let material = me.scene.emissive(pattern);
+                me.parse_surface_fields(material)?;
+                Ok(material)
             }
 
             name => bail!("Unknown material type: {}", name),
         })
     }
 
+    fn parse_backface(&mut self) -> Result<Backface> {
+        match self.ident()?.as_ref() {
+            "one-sided" => Ok(Backface::OneSided),
+            "two-sided" => Ok(Backface::TwoSided),
+            "flip" => Ok(Backface::Flip),
+            "cull" => Ok(Backface::Cull),
+            backface => bail!("Unknown backface: `{}`", backface),
+        }
+    }
+
+    /// Parse the value of `sym` into `config` when it's one of the march fields, returning
+    /// whether it was.
+    fn parse_march_field(&mut self, config: &mut MarchConfig, sym: &str) -> Result<bool> {
+        match sym {
+            ":max-steps" => config.max_steps = self.number()? as u32,
+            // A distance that's given is used as it is.
+            ":min-dist" => {
+                config.min_dist = self.number()?;
+                config.auto_scale = false;
+            }
+            ":max-dist" => config.max_dist = self.number()?,
+            ":auto-scale" => config.auto_scale = self.boolean()?,
+            _ => return Ok(false),
+        }
+        Ok(true)
+    }
+
+    /// Parse the value of `sym` into `bias` when it's one of the fields that move rays off of a
+    /// surface, returning whether it was.
+    fn parse_bias_field(&mut self, bias: &mut Bias, sym: &str) -> Result<bool> {
+        match sym {
+            ":normal-offset" => {
+                let offset = self.number()?;
+                if offset < 0. {
+                    bail!("A material's :normal-offset can't be negative");
+                }
+                bias.normal = Some(offset);
+            }
+            ":shadow-bias" => {
+                let shadow = self.number()?;
+                if shadow < 0. {
+                    bail!("A material's :shadow-bias can't be negative");
+                }
+                bias.shadow = shadow;
+            }
+            _ => return Ok(false),
+        }
+        Ok(true)
+    }
+
+    /// Parse the `:backface` and bias fields of materials that have no other fields.
+    fn parse_surface_fields(&mut self, material: MaterialId) -> Result<()> {
+        let mut bias = Bias::default();
+        while !self.peek_rparen() {
+            match self.symbol()?.as_ref() {
+                ":backface" => {
+                    let backface = self.parse_backface()?;
+                    self.scene.set_backface(material, backface);
+                }
+                sym => {
+                    if !self.parse_bias_field(&mut bias, sym)? {
+                        bail!("Unknown material field `{}`", sym);
+                    }
+                }
+            }
+        }
+        self.scene.set_bias(material, bias);
+        Ok(())
+    }
+
     fn parse_nodes(&mut self) -> Result<Vec<NodeId>> {
         let mut nodes = Vec::new();
         while !self.peek_rparen() {
-            nodes.push(self.parse_node()?);
+            if self.peek_form("select") {
+                nodes.extend(self.parse_selection()?);
+            } else {
+                nodes.push(self.parse_node()?);
+            }
         }
 
         if nodes.is_empty() {
@@ -367,50 +1200,15 @@ impl<'a> Parser<'a> {
     fn parse_node(&mut self) -> Result<NodeId> {
         if self.peek_ident() {
             let name = self.ident()?;
-            if let Some(id) = self.nodes.get(&name) {
-                return Ok(*id);
+            if let Some(&id) = self.nodes.get(&name) {
+                self.use_name("node", &name);
+                return Ok(id);
             } else {
                 bail!("Unknown node: {}", name)
             }
         }
 
         self.parens(|me| match me.ident()?.as_ref() {
-            "plane" => {
-                let normal = me.vector()?;
-                Ok(me.scene.plane(Unit::new_normalize(normal)))
-            }
-
-            "sphere" => {
-                let radius = me.number()?;
-                Ok(me.scene.sphere(radius))
-            }
-
-            "box" => {
-                let width = me.number()?;
-                let height = me.number()?;
-                let depth = me.number()?;
-                Ok(me.scene.rect(width, height, depth))
-            }
-
-            "torus" => {
-                let hole = me.number()?;
-                let radius = me.number()?;
-                Ok(me.scene.torus(hole, radius))
-            }
-
-            "triangle" => {
-                let a = me.point()?;
-                let b = me.point()?;
-                let c = me.point()?;
-
-                // TODO: allow the normal to be specified, and default to computing it
-                let ba = b - a;
-                let ac = a - c;
-                let n = Unit::new_normalize(ba.cross(&ac));
-
-                Ok(me.scene.triangle(a, b, c, n))
-            }
-
             "invert" => {
                 let node = me.parse_node()?;
                 Ok(me.scene.invert(node))
@@ -418,12 +1216,12 @@ impl<'a> Parser<'a> {
 
             "group" => {
                 let nodes = me.parse_nodes()?;
-                Ok(me.scene.group(nodes))
+                me.scene.group(nodes)
             }
 
             "union" => {
                 let nodes = me.parse_nodes()?;
-                Ok(me.scene.union(nodes))
+                me.scene.union(nodes)
             }
 
             "subtract" => {
@@ -434,13 +1232,13 @@ impl<'a> Parser<'a> {
 
             "intersect" => {
                 let nodes = me.parse_nodes()?;
-                Ok(me.scene.intersect(nodes))
+                me.scene.intersect(nodes)
             }
 
             "smooth-union" => {
                 let k = me.number()?;
                 let nodes = me.parse_nodes()?;
-                Ok(me.scene.smooth_union(k, &nodes))
+                me.scene.smooth_union(k, &nodes)
             }
 
             "transform" => {
@@ -455,22 +1253,642 @@ impl<'a> Parser<'a> {
                 Ok(me.scene.paint(mat, node))
             }
 
-            node => bail!("Unknown node type: {}", node),
+            form @ ("ring" | "phyllotaxis" | "override-material" | "tag" | "volume"
+            | "displace" | "bake") => me.parse_node_form(form),
+
+            form => me.parse_primitive(form),
         })
     }
 
-    fn parse_light(&mut self) -> Result<()> {
-        self.parens(|me| {
-            match me.ident()?.as_ref() {
-                "diffuse" => {
-                    let color = me.color()?;
-                    me.scene.diffuse_light(color);
+    /// The forms that place or modify the nodes inside them, after their name. These are kept out
+    /// of [`Self::parse_node`], which recurses through every level of nesting, to keep its frame on
+    /// the stack small.
+    fn parse_node_form(&mut self, form: &str) -> Result<NodeId> {
+        match form {
+            "ring" => {
+                let mut count = 8.;
+                let mut radius = 1.0;
+
+                while !self.peek_node() {
+                    match self.symbol()?.as_ref() {
+                        ":count" => count = self.number()?,
+                        ":radius" => radius = self.number()?,
+                        sym => bail!("Unknown ring field `{}`", sym),
+                    }
+                }
+
+                if !(1. ..=MAX_COPIES as Float).contains(&count) {
+                    bail!("A ring's :count must be between 1 and {}", MAX_COPIES);
+                }
+
+                let node = self.parse_node()?;
+                self.scene.ring(count as u32, radius, node)
+            }
+
+            "phyllotaxis" => {
+                let mut count = 100.;
+                let mut spacing = 0.1;
+
+                while !self.peek_node() {
+                    match self.symbol()?.as_ref() {
+                        ":count" => count = self.number()?,
+                        ":spacing" => spacing = self.number()?,
+                        sym => bail!("Unknown phyllotaxis field `{}`", sym),
+                    }
+                }
+
+                if !(1. ..=MAX_COPIES as Float).contains(&count) {
+                    bail!(
+                        "A phyllotaxis's :count must be between 1 and {}",
+                        MAX_COPIES
+                    );
+                }
+
+                let node = self.parse_node()?;
+                self.scene.phyllotaxis(count as u32, spacing, node)
+            }
+
+            "override-material" => {
+                let targets = if self.peek_form("select") {
+                    self.parse_selection()?
+                } else {
+                    let name = self.ident()?;
+                    let Some(&target) = self.nodes.get(&name) else {
+                        bail!("Unknown node: {}", name)
+                    };
+                    self.use_name("node", &name);
+                    vec![target]
+                };
+                let mat = self.parse_material()?;
+                let node = self.parse_node()?;
+                match self.scene.override_material(node, &targets, mat) {
+                    Some(node) => Ok(node),
+                    None => bail!("The overridden nodes aren't part of the node"),
+                }
+            }
+
+            "tag" => {
+                let mut tags = Vec::new();
+                while !self.peek_node() {
+                    tags.push(self.string()?);
+                }
+                if tags.is_empty() {
+                    bail!("A tag form needs at least one tag");
+                }
+                let node = self.parse_node()?;
+                for tag in tags {
+                    let nodes = self.tags.entry(tag).or_default();
+                    if !nodes.contains(&node) {
+                        nodes.push(node);
+                    }
+                }
+                Ok(node)
+            }
+
+            "volume" => {
+                let node = self.parse_node()?;
+                let mut volume = Volume::default();
+
+                while !self.peek_rparen() {
+                    match self.symbol()?.as_ref() {
+                        ":density-scale" => volume.density_scale = self.scalar()?,
+                        ":scattering" => volume.scattering = self.scalar()?.clamp(0., 1.),
+                        ":color" => volume.color = self.color()?,
+                        ":emission" => volume.emission = self.color()?,
+                        ":step" => volume.step = self.number()?,
+                        sym => bail!("Unknown volume field `{}`", sym),
+                    }
+                }
+
+                non_negative("density scale of a volume", volume.density_scale as Float)?;
+                positive("step of a volume", volume.step)?;
+
+                Ok(self.scene.volume(volume, node))
+            }
+
+            "displace" => {
+                let node = self.parse_node()?;
+                let pattern = self.parse_pattern()?;
+                let mut displacement = Displacement {
+                    pattern,
+                    amount: 0.1,
+                    slope: 1.,
+                };
+
+                while !self.peek_rparen() {
+                    match self.symbol()?.as_ref() {
+                        ":amount" => displacement.amount = self.number()?,
+                        ":slope" => displacement.slope = self.number()?,
+                        sym => bail!("Unknown displace field `{}`", sym),
+                    }
+                }
+
+                if !displacement.amount.is_finite() {
+                    bail!("A displacement's :amount must be a finite number");
+                }
+
+                if displacement.slope.is_nan() || displacement.slope < 0. {
+                    bail!("A displacement's :slope must not be negative");
+                }
+
+                Ok(self.scene.displace(displacement, node))
+            }
+
+            "bake" => {
+                let mut resolution = 64;
+
+                while !self.peek_node() {
+                    match self.symbol()?.as_ref() {
+                        ":resolution" => resolution = self.number()? as usize,
+                        sym => bail!("Unknown bake field `{}`", sym),
+                    }
+                }
+
+                let node = self.parse_node()?;
+                self.scene.bake(node, resolution)
+            }
+
+            form => unreachable!("`{}` isn't a node form", form),
+        }
+    }
+
+    /// The nodes that don't contain other nodes, after their name.
+    fn parse_primitive(&mut self, form: &str) -> Result<NodeId> {
+        match form {
+            "plane" => {
+                let Some(normal) = Unit::try_new(self.vector()?, 1e-9) else {
+                    bail!("The normal of a plane must not be zero");
+                };
+                Ok(self.scene.plane(normal))
+            }
+
+            "sphere" => {
+                let radius = self.number()?;
+                self.scene.sphere(radius)
+            }
+
+            "box" => {
+                let width = self.number()?;
+                let height = self.number()?;
+                let depth = self.number()?;
+                self.scene.rect(width, height, depth)
+            }
+
+            "torus" => {
+                let hole = self.number()?;
+                let radius = self.number()?;
+                self.scene.torus(hole, radius)
+            }
+
+            "triangle" => {
+                let a = self.point()?;
+                let b = self.point()?;
+                let c = self.point()?;
+
+                let ba = b - a;
+                let ac = a - c;
+                let n = Unit::new_normalize(ba.cross(&ac));
+
+                let mut normals = None;
+                while !self.peek_rparen() {
+                    match self.symbol()?.as_ref() {
+                        ":normals" => {
+                            let mut corner = || {
+                                let Some(normal) = Unit::try_new(self.vector()?, 1e-9) else {
+                                    bail!("The :normals of a triangle must not be zero");
+                                };
+                                Ok(normal)
+                            };
+                            normals = Some([corner()?, corner()?, corner()?]);
+                        }
+                        sym => bail!("Unknown triangle field `{}`", sym),
+                    }
+                }
+
+                match normals {
+                    Some(normals) => Ok(self.scene.smooth_triangle(a, b, c, n, normals)),
+                    None => Ok(self.scene.triangle(a, b, c, n)),
+                }
+            }
+
+            "select" => {
+                bail!("A select gives a list of nodes, so it can't be used in place of one node")
+            }
+
+            "grid" => {
+                let path = PathBuf::from(self.string()?);
+                let mut dims = None;
+                let mut origin = Point3::origin();
+                let mut step = 1.;
+
+                while !self.peek_rparen() {
+                    match self.symbol()?.as_ref() {
+                        ":dims" => {
+                            let size = self.vector()?;
+                            dims = Some([size.x, size.y, size.z].map(|dim| dim as usize));
+                        }
+                        ":origin" => origin = self.point()?,
+                        ":step" => step = self.number()?,
+                        sym => bail!("Unknown grid field `{}`", sym),
+                    }
+                }
+
+                self.use_file(&path);
+                let grid = DistanceGrid::load(&path, dims, origin, step)?;
+                Ok(self.scene.grid(grid))
+            }
+
+            "heightfield" => {
+                let path = PathBuf::from(self.string()?);
+                let mut scale = Vector3::repeat(1.);
+
+                while !self.peek_rparen() {
+                    match self.symbol()?.as_ref() {
+                        ":scale" => scale = self.vector()?,
+                        sym => bail!("Unknown heightfield field `{}`", sym),
+                    }
+                }
+
+                self.use_file(&path);
+                let field = Heightfield::load(&path, scale)?;
+                Ok(self.scene.heightfield(field))
+            }
+
+            "metaballs" => {
+                let mut threshold = 0.5;
+                let mut balls = Vec::new();
+
+                while !self.peek_rparen() {
+                    if self.peek_lparen() {
+                        balls.push(self.parse_metaball()?);
+                        continue;
+                    }
+                    match self.symbol()?.as_ref() {
+                        ":threshold" => threshold = self.number()?,
+                        sym => bail!("Unknown metaballs field `{}`", sym),
+                    }
+                }
+
+                if !balls.iter().any(|ball| ball.strength > 0.) {
+                    bail!("Metaballs need at least one ball with a positive :strength");
+                }
+                if threshold <= 0. || !threshold.is_finite() {
+                    bail!("The metaballs' :threshold must be positive");
+                }
+
+                Ok(self.scene.metaballs(Metaballs::new(balls, threshold)))
+            }
+
+            "mandelbulb" => {
+                let mut power = 8.;
+                let fractal = self.parse_fractal("mandelbulb", 8, 2., |me, sym| match sym {
+                    ":power" => {
+                        power = me.number()?;
+                        Ok(())
+                    }
+                    sym => bail!("Unknown mandelbulb field `{}`", sym),
+                })?;
+                if power < 2. || !power.is_finite() {
+                    bail!("A mandelbulb's :power must be at least 2");
+                }
+                self.add_fractal(FractalKind::Mandelbulb { power }, fractal)
+            }
+
+            "menger" => {
+                let fractal = self.parse_fractal("menger", 4, 2., |_, sym| {
+                    bail!("Unknown menger field `{}`", sym)
+                })?;
+                self.add_fractal(FractalKind::Menger, fractal)
+            }
+
+            "julia" => {
+                let c = self.parens(|me| {
+                    let w = me.number()?;
+                    let i = me.number()?;
+                    let j = me.number()?;
+                    let k = me.number()?;
+                    Ok(Quaternion::new(w, i, j, k))
+                })?;
+                let fractal = self.parse_fractal("julia", 11, 4., |_, sym| {
+                    bail!("Unknown julia field `{}`", sym)
+                })?;
+                self.add_fractal(FractalKind::Julia { c }, fractal)
+            }
+
+            "capsule" => {
+                let a = self.point()?;
+                let b = self.point()?;
+                let radius = self.number()?;
+                self.scene.capsule(a, b, radius)
+            }
+
+            "lsystem" => {
+                let mut system = LSystem {
+                    axiom: self.string()?,
+                    iterations: 3,
+                    ..LSystem::default()
+                };
+                let mut turtle = Turtle::default();
+
+                while !self.peek_rparen() {
+                    if self.peek_lparen() {
+                        let (from, to) = self.parens(|me| match me.ident()?.as_ref() {
+                            "rule" => Ok((me.string()?, me.string()?)),
+                            form => bail!("Unknown lsystem form: {}", form),
+                        })?;
+                        let mut chars = from.chars();
+                        let (Some(from), None) = (chars.next(), chars.next()) else {
+                            bail!("A rule must rewrite a single symbol, not `{}`", from);
+                        };
+                        system.rules.insert(from, to);
+                        continue;
+                    }
+
+                    match self.symbol()?.as_ref() {
+                        ":iterations" => system.iterations = self.number()? as u32,
+                        ":angle" => turtle.angle = self.number()?,
+                        ":length" => turtle.length = self.number()?,
+                        ":radius" => turtle.radius = self.number()?,
+                        ":shrink" => turtle.shrink = self.number()?,
+                        ":leaf" => turtle.leaf = self.number()?,
+                        sym => bail!("Unknown lsystem field `{}`", sym),
+                    }
+                }
+
+                let path = system.expand()?;
+                let nodes = turtle.draw(&mut self.scene, &path)?;
+                if nodes.is_empty() {
+                    bail!("The L-system doesn't draw anything");
+                }
+                self.scene.group(nodes)
+            }
+
+            "text" => {
+                let text = self.string()?;
+                let mut font = None;
+                let mut size = 1.;
+                let mut depth = 0.2;
+
+                while !self.peek_rparen() {
+                    match self.symbol()?.as_ref() {
+                        ":font" => font = Some(PathBuf::from(self.string()?)),
+                        ":size" => size = self.number()?,
+                        ":depth" => depth = self.number()?,
+                        sym => bail!("Unknown text field `{}`", sym),
+                    }
+                }
+
+                let Some(font) = font else {
+                    bail!("Text needs a :font");
+                };
+                if size <= 0. || !size.is_finite() {
+                    bail!("A text's :size must be positive");
+                }
+                if depth <= 0. || !depth.is_finite() {
+                    bail!("A text's :depth must be positive");
+                }
+
+                self.use_file(&font);
+                let bytes = std::fs::read(&font)
+                    .with_context(|| format!("Failed to read the font `{}`", font.display()))?;
+                let glyphs = text::layout(&bytes, &text, size)?;
+                if glyphs.is_empty() {
+                    bail!("The text `{}` has no visible glyphs", text);
+                }
+
+                let nodes = glyphs
+                    .into_iter()
+                    .map(|polygon| self.scene.extrude(Profile::Polygon(polygon), depth, 0.))
+                    .collect();
+                self.scene.group(nodes)
+            }
+
+            "extrude" => {
+                let profile = self.parse_profile()?;
+                let mut depth = 1.;
+                let mut twist = 0.;
+
+                while !self.peek_rparen() {
+                    match self.symbol()?.as_ref() {
+                        ":depth" => depth = self.number()?,
+                        ":twist" => twist = self.number()?,
+                        sym => bail!("Unknown extrude field `{}`", sym),
+                    }
+                }
+
+                if depth <= 0. || !depth.is_finite() {
+                    bail!("An extrude's :depth must be positive");
+                }
+                if !twist.is_finite() {
+                    bail!("An extrude's :twist must be finite");
+                }
+
+                Ok(self.scene.extrude(profile, depth, twist))
+            }
+
+            "revolve" => {
+                let profile = self.parse_profile()?;
+                let mut offset = 0.;
+
+                while !self.peek_rparen() {
+                    match self.symbol()?.as_ref() {
+                        ":offset" => offset = self.number()?,
+                        sym => bail!("Unknown revolve field `{}`", sym),
+                    }
+                }
+
+                if !offset.is_finite() {
+                    bail!("A revolve's :offset must be finite");
+                }
+
+                Ok(self.scene.revolve(profile, offset))
+            }
+
+            node => self.parse_custom_node(node),
+        }
+    }
+
+    /// A primitive added by a plugin, after its name.
+    fn parse_custom_node(&mut self, name: &str) -> Result<NodeId> {
+        let Some(factory) = self.plugins.primitive(name) else {
+            bail!("Unknown node type: {}", name);
+        };
+
+        let mut args = PrimitiveArgs {
+            values: Vec::new(),
+            fields: Vec::new(),
+        };
+        while !self.peek_rparen() && !self.peek_symbol() {
+            args.values.push(self.parse_value()?);
+        }
+        while !self.peek_rparen() {
+            let sym = self.symbol()?;
+            args.fields
+                .push((String::from(&sym[1..]), self.parse_value()?));
+        }
+
+        let sdf =
+            factory(args).with_context(|| format!("Failed to build the `{}` primitive", name))?;
+        Ok(self.scene.custom(name, sdf))
+    }
+
+    /// The `:iterations` and `:bailout` fields that all fractals share, passing any others to
+    /// `field`. The kind of the fractal is filled in by [`Parser::add_fractal`].
+    fn parse_fractal<Field>(
+        &mut self,
+        name: &str,
+        iterations: u32,
+        bailout: Float,
+        mut field: Field,
+    ) -> Result<Fractal>
+    where
+        Field: FnMut(&mut Self, &str) -> Result<()>,
+    {
+        let mut fractal = Fractal {
+            kind: FractalKind::Menger,
+            iterations,
+            bailout,
+        };
+
+        while !self.peek_rparen() {
+            match self.symbol()?.as_ref() {
+                ":iterations" => fractal.iterations = self.number()? as u32,
+                ":bailout" => fractal.bailout = self.number()?,
+                sym => field(self, sym)?,
+            }
+        }
+
+        if fractal.iterations == 0 || fractal.iterations > 64 {
+            bail!("A {}'s :iterations must be between 1 and 64", name);
+        }
+        Ok(fractal)
+    }
+
+    fn add_fractal(&mut self, kind: FractalKind, mut fractal: Fractal) -> Result<NodeId> {
+        fractal.kind = kind;
+        let radius = fractal.radius();
+        if fractal.bailout < radius || !fractal.bailout.is_finite() {
+            bail!("A fractal's :bailout must be at least {}", radius);
+        }
+        Ok(self.scene.fractal(fractal))
+    }
+
+    fn parse_metaball(&mut self) -> Result<Metaball> {
+        self.parens(|me| match me.ident()?.as_ref() {
+            "ball" => {
+                let center = me.point()?;
+                let radius = me.number()?;
+                let mut strength = 1.;
+
+                while !me.peek_rparen() {
+                    match me.symbol()?.as_ref() {
+                        ":strength" => strength = me.number()?,
+                        sym => bail!("Unknown ball field `{}`", sym),
+                    }
+                }
+
+                if radius <= 0. || !radius.is_finite() {
+                    bail!("A ball's radius must be positive");
+                }
+                if !strength.is_finite() {
+                    bail!("A ball's :strength must be finite");
+                }
+
+                Ok(Metaball {
+                    center,
+                    radius,
+                    strength,
+                })
+            }
+
+            ball => bail!("Unknown metaball type: {}", ball),
+        })
+    }
+
+    fn parse_profile(&mut self) -> Result<Profile> {
+        self.parens(|me| match me.ident()?.as_ref() {
+            "polygon" => {
+                let mut points = Vec::new();
+                while !me.peek_rparen() {
+                    points.push(me.parens(|me| {
+                        let x = me.number()?;
+                        let y = me.number()?;
+                        Ok(Point2::new(x, y))
+                    })?);
+                }
+
+                let polygon = Polygon::new(vec![points]);
+                if polygon.is_empty() {
+                    bail!("A polygon needs at least three points");
+                }
+                Ok(Profile::Polygon(polygon))
+            }
+
+            "circle" => {
+                let radius = me.number()?;
+                Ok(Profile::Circle { radius })
+            }
+
+            "rect" => {
+                let width = me.number()?;
+                let height = me.number()?;
+                Ok(Profile::Rect { width, height })
+            }
+
+            profile => bail!("Unknown profile type: {}", profile),
+        })
+    }
+
+    fn parse_light(&mut self) -> Result<()> {
+        self.parens(|me| {
+            match me.ident()?.as_ref() {
+                "diffuse" => {
+                    let color = me.color()?;
+                    let mut horizon = color.clone();
+                    let mut intensity = 1.;
+                    let mut include = Vec::new();
+                    let mut exclude = Vec::new();
+
+                    while !me.peek_rparen() {
+                        match me.symbol()?.as_ref() {
+                            ":horizon" => horizon = me.color()?,
+                            ":intensity" => intensity = me.scalar()?,
+                            ":include" => include = me.parse_node_names()?,
+                            ":exclude" => exclude = me.parse_node_names()?,
+                            sym => bail!("Unknown diffuse light field `{}`", sym),
+                        }
+                    }
+                    if intensity < 0. {
+                        bail!("A light's :intensity can't be negative");
+                    }
+
+                    let light = me.scene.diffuse_light(color, horizon, intensity);
+                    me.scene.link_light(light, &include, &exclude)?;
                 }
 
                 "point" => {
                     let color = me.color()?;
                     let point = me.point()?;
-                    me.scene.point_light(point, color);
+                    let mut intensity = 1.;
+                    let mut falloff = Falloff::None;
+                    let mut include = Vec::new();
+                    let mut exclude = Vec::new();
+
+                    while !me.peek_rparen() {
+                        match me.symbol()?.as_ref() {
+                            ":intensity" => intensity = me.scalar()?,
+                            ":falloff" => falloff = me.parse_falloff()?,
+                            ":include" => include = me.parse_node_names()?,
+                            ":exclude" => exclude = me.parse_node_names()?,
+                            sym => bail!("Unknown point light field `{}`", sym),
+                        }
+                    }
+                    if intensity < 0. {
+                        bail!("A light's :intensity can't be negative");
+                    }
+
+                    let light = me.scene.point_light(point, color, intensity, falloff);
+                    me.scene.link_light(light, &include, &exclude)?;
                 }
 
                 _ => bail!("Failed to parse light"),
@@ -479,6 +1897,105 @@ impl<'a> Parser<'a> {
         })
     }
 
+    /// Parse a `(select ...)` form, giving the nodes with all of its tags.
+    fn parse_selection(&mut self) -> Result<Vec<NodeId>> {
+        // Tags can be given anywhere, so a selection depends on every command before it.
+        if let Some((current, before)) = self.commands.split_last_mut() {
+            current.uses.extend(0..before.len());
+        }
+
+        self.parens(|me| {
+            me.ident()?;
+            let mut tags = Vec::new();
+            while !me.peek_rparen() {
+                match me.symbol()?.as_ref() {
+                    ":tag" => tags.push(me.string()?),
+                    sym => bail!("Unknown select field `{}`", sym),
+                }
+            }
+
+            let Some((first, rest)) = tags.split_first() else {
+                bail!("A select needs at least one :tag");
+            };
+            let nodes: Vec<_> = me
+                .tags
+                .get(first)
+                .into_iter()
+                .flatten()
+                .copied()
+                .filter(|id| {
+                    rest.iter()
+                        .all(|tag| me.tags.get(tag).is_some_and(|nodes| nodes.contains(id)))
+                })
+                .collect();
+            if nodes.is_empty() {
+                bail!("No nodes are tagged with all of {:?}", tags);
+            }
+            Ok(nodes)
+        })
+    }
+
+    /// Parse a list of the names of nodes, like `(ball floor)`.
+    fn parse_node_names(&mut self) -> Result<Vec<NodeId>> {
+        self.parens(|me| {
+            let mut nodes = Vec::new();
+            while !me.peek_rparen() {
+                if me.peek_form("select") {
+                    nodes.extend(me.parse_selection()?);
+                    continue;
+                }
+                let name = me.ident()?;
+                match me.nodes.get(&name) {
+                    Some(&id) => {
+                        me.use_name("node", &name);
+                        nodes.push(id)
+                    }
+                    None => bail!("Unknown node: {}", name),
+                }
+            }
+            Ok(nodes)
+        })
+    }
+
+    /// Parse the falloff of a point light, which is either named or an exponent.
+    fn parse_falloff(&mut self) -> Result<Falloff> {
+        if !self.peek_ident() {
+            let exponent = self.number()?;
+            if exponent < 0. {
+                bail!("A light's :falloff can't be negative");
+            }
+            return Ok(Falloff::Exponent(exponent));
+        }
+
+        match self.ident()?.as_ref() {
+            "none" => Ok(Falloff::None),
+            "inverse-square" => Ok(Falloff::InverseSquare),
+            falloff => bail!("Unknown light falloff: {}", falloff),
+        }
+    }
+
+    /// Parse the world-to-camera transform of a camera, moved by the view of the web ui if there is
+    /// one. The view orbits a point straight ahead of the camera, as far away as the target it was
+    /// placed with `look-at`, or as the origin when it wasn't.
+    fn parse_camera_transform(&mut self) -> Result<Transform> {
+        self.look_at = None;
+        let mut t = self.parse_transform()?;
+        if let Some(view) = &self.overrides.view {
+            let eye = Point3::origin().invert(&t);
+            let distance = (self.look_at.unwrap_or_else(Point3::origin) - eye).norm();
+            t = view.apply(&t, distance.max(1e-3));
+        }
+        Ok(t)
+    }
+
+    /// The canvas for a camera of the given size, scaled by the overrides.
+    fn scale_canvas(&self, width: u32, height: u32) -> CanvasInfo {
+        let scale = self.overrides.scale.unwrap_or(1.);
+        let width = (width as Float * scale).round().max(1.) as u32;
+        let height = (height as Float * scale).round().max(1.) as u32;
+        CanvasInfo::new(width, height)
+    }
+
     fn parse_camera(&mut self) -> Result<(CanvasInfo, Arc<dyn Camera>)> {
         if self.peek_ident() {
             let camera_name = self.ident()?;
@@ -488,7 +2005,9 @@ impl<'a> Parser<'a> {
                 .rev()
                 .find(|(name, _, _)| *name == camera_name);
             if let Some((_, info, camera)) = res {
-                return Ok((info.clone(), camera.clone()));
+                let camera = (info.clone(), camera.clone());
+                self.use_name("camera", &camera_name);
+                return Ok(camera);
             } else {
                 bail!("Unknown camera: {}", camera_name);
             };
@@ -498,13 +2017,99 @@ impl<'a> Parser<'a> {
             "pinhole" => {
                 let width = me.number()? as u32;
                 let height = me.number()? as u32;
-                let t = me.parse_transform()?;
+                let t = me.parse_camera_transform()?;
                 let fov = me.number()?;
-                let info = CanvasInfo::new(width, height);
+
+                if width == 0 || height == 0 {
+                    bail!("A pinhole camera must have a positive width and height");
+                }
+
+                if !(fov > 0. && fov < math::consts::PI) {
+                    bail!("A pinhole camera's field of view must be between 0 and 180 degrees");
+                }
+
+                let info = me.scale_canvas(width, height);
                 let camera = Arc::new(PinholeCamera::new(&info, t, fov)) as Arc<dyn Camera>;
                 Ok((info, camera))
             }
 
+            "equirectangular" => {
+                let width = me.number()? as u32;
+                let height = me.number()? as u32;
+                let t = me.parse_camera_transform()?;
+
+                let mut horizontal = 2. * math::consts::PI;
+                let mut vertical = math::consts::PI;
+                let mut separation = 0.;
+                while !me.peek_rparen() {
+                    match me.symbol()?.as_ref() {
+                        ":horizontal" => horizontal = me.number()?,
+                        ":vertical" => vertical = me.number()?,
+                        ":stereo" => separation = me.number()?,
+                        sym => bail!("Unknown equirectangular field `{}`", sym),
+                    }
+                }
+
+                if width == 0 || height == 0 {
+                    bail!("An equirectangular camera must have a positive width and height");
+                }
+
+                let covered = horizontal > 0.
+                    && horizontal <= 2. * math::consts::PI
+                    && vertical > 0.
+                    && vertical <= math::consts::PI;
+                if !covered {
+                    bail!(
+                        "An equirectangular camera must cover up to 360 degrees across and 180 \
+                         degrees down"
+                    );
+                }
+
+                if separation < 0. {
+                    bail!("The separation of a stereo camera must not be negative");
+                }
+
+                let info = me.scale_canvas(width, height);
+                let camera = Arc::new(EquirectangularCamera::new(
+                    &info, t, horizontal, vertical, separation,
+                )) as Arc<dyn Camera>;
+                Ok((info, camera))
+            }
+
+            "fisheye" => {
+                let width = me.number()? as u32;
+                let height = me.number()? as u32;
+                let t = me.parse_camera_transform()?;
+                let fov = me.number()?;
+
+                let mut projection = FisheyeProjection::default();
+                while !me.peek_rparen() {
+                    match me.symbol()?.as_ref() {
+                        ":projection" => {
+                            projection = match me.ident()?.as_ref() {
+                                "equidistant" => FisheyeProjection::Equidistant,
+                                "equisolid" => FisheyeProjection::Equisolid,
+                                projection => bail!("Unknown fisheye projection: {}", projection),
+                            }
+                        }
+                        sym => bail!("Unknown fisheye field `{}`", sym),
+                    }
+                }
+
+                if width == 0 || height == 0 {
+                    bail!("A fisheye camera must have a positive width and height");
+                }
+
+                if !(fov > 0. && fov <= 2. * math::consts::PI) {
+                    bail!("A fisheye camera's field of view must be between 0 and 360 degrees");
+                }
+
+                let info = me.scale_canvas(width, height);
+                let camera =
+                    Arc::new(FisheyeCamera::new(&info, t, fov, projection)) as Arc<dyn Camera>;
+                Ok((info, camera))
+            }
+
             camera => bail!("Unknown camera type: {}", camera),
         })
     }
@@ -512,15 +2117,73 @@ impl<'a> Parser<'a> {
     fn parse_target(&mut self) -> Result<Target> {
         self.parens(|me| match me.ident()?.as_ref() {
             "file" => {
-                let string = me.string()?;
+                let file = me.string()?;
+                let mut path = PathTemplate::parse(&file)?;
+                if let Some(dir) = &me.overrides.output_dir {
+                    path = path.with_root(dir);
+                }
+
+                let mut channels = Channels::default();
+                let mut depth = None;
+                let mut stream = false;
+                while !me.peek_rparen() {
+                    match me.symbol()?.as_ref() {
+                        ":channels" => {
+                            channels = match me.ident()?.as_ref() {
+                                "rgb" => Channels::Rgb,
+                                "rgba" => Channels::Rgba,
+                                "grayscale" => Channels::Grayscale,
+                                "luma" => Channels::Luma,
+                                channels => bail!("Unknown channels: `{}`", channels),
+                            }
+                        }
+                        ":depth" => {
+                            depth = Some(match me.number()? {
+                                8. => BitDepth::Eight,
+                                16. => BitDepth::Sixteen,
+                                bits => bail!("Unsupported bit depth: `{}`", bits),
+                            })
+                        }
+                        ":stream" => stream = me.boolean()?,
+                        sym => bail!("Unknown file field `{}`", sym),
+                    }
+                }
+
+                if stream {
+                    if !file.ends_with(".exr") {
+                        bail!("Only `.exr` files can be streamed");
+                    }
+                    if depth.is_some() {
+                        bail!("A streamed file is always written as floats, so it has no :depth");
+                    }
+                }
+
                 Ok(Target::File {
-                    path: PathBuf::from(string),
+                    path,
+                    channels,
+                    depth: depth.unwrap_or_default(),
+                    stream,
                 })
             }
 
             "ascii" => {
                 let name = me.string()?;
-                Ok(Target::Ascii { name })
+                let mut mode = AsciiMode::default();
+                while !me.peek_rparen() {
+                    match me.symbol()?.as_ref() {
+                        ":mode" => {
+                            mode = match me.ident()?.as_ref() {
+                                "chars" => AsciiMode::Chars,
+                                "color-blocks" => AsciiMode::ColorBlocks,
+                                "half-blocks" => AsciiMode::HalfBlocks,
+                                "braille" => AsciiMode::Braille,
+                                mode => bail!("Unknown ascii mode: `{}`", mode),
+                            }
+                        }
+                        sym => bail!("Unknown ascii field `{}`", sym),
+                    }
+                }
+                Ok(Target::Ascii { name, mode })
             }
 
             target => bail!("Unknown target type: {}", target),
@@ -528,6 +2191,17 @@ impl<'a> Parser<'a> {
     }
 
     fn parse_sampler(&mut self) -> Result<Box<dyn Sampler>> {
+        if self.peek_ident() {
+            let name = self.ident()?;
+            if let Some(sampler) = self.samplers.get(&name) {
+                let sampler = sampler.clone_sampler();
+                self.use_name("sampler", &name);
+                return Ok(sampler);
+            } else {
+                bail!("Unknown sampler: {}", name);
+            }
+        }
+
         self.parens(|me| match me.ident()?.as_ref() {
             "uniform" => {
                 let width = me.number()?;
@@ -536,6 +2210,11 @@ impl<'a> Parser<'a> {
                 } else {
                     me.number()?
                 };
+
+                if width < 1. || height < 1. {
+                    bail!("A uniform sampler must take at least one sample");
+                }
+
                 Ok(Box::new(UniformSampler::new(width as u32, height as u32)) as Box<dyn Sampler>)
             }
 
@@ -548,31 +2227,325 @@ impl<'a> Parser<'a> {
     ) -> Result<(CanvasInfo, Box<dyn Sampler>, Box<dyn IntegratorBuilder>)> {
         self.parens(|me| match me.ident()?.as_ref() {
             "whitted" => {
-                let sampler = me.parse_sampler()?;
+                let mut sampler = me.parse_sampler()?;
+                if let Some(samples) = me.overrides.samples {
+                    sampler = Box::new(UniformSampler::with_count(samples));
+                }
+
                 let (info, camera) = me.parse_camera()?;
 
                 let mut num_reflections = 10;
-                let mut config = MarchConfig::default();
+                let mut config = me.march.clone();
+                let mut background = Background::default();
+                let mut shadow_cache = None;
+                let mut outline: Option<Outline> = None;
 
                 while !me.peek_rparen() {
                     match me.symbol()?.as_ref() {
                         ":max-reflections" => num_reflections = me.number()? as u32,
-                        ":max-steps" => config.max_steps = me.number()? as u32,
-                        ":min-dist" => config.min_dist = me.number()?,
-                        ":max-dist" => config.max_dist = me.number()?,
-                        sym => bail!("Unknown field `{}`", sym),
+                        ":background" => background = me.parse_background(&info)?,
+                        ":shadow-cache" => {
+                            let cell = me.number()?;
+                            if cell <= 0. {
+                                bail!("The cells of a shadow cache must have a positive size");
+                            }
+                            shadow_cache = Some(cell);
+                        }
+                        ":outline" => {
+                            outline.get_or_insert_with(Outline::default).color = me.color()?
+                        }
+                        ":outline-width" => {
+                            let width = me.number()?;
+                            if width <= 0. {
+                                bail!("The width of an outline must be positive");
+                            }
+                            outline.get_or_insert_with(Outline::default).width = width;
+                        }
+                        ":outline-angle" => {
+                            let angle = me.number()?;
+                            if angle <= 0. || angle >= 180. {
+                                bail!("The angle of an outline must be between 0 and 180 degrees");
+                            }
+                            outline.get_or_insert_with(Outline::default).angle =
+                                math::deg_to_rad(angle);
+                        }
+                        sym => {
+                            if !me.parse_march_field(&mut config, sym)? {
+                                bail!("Unknown field `{}`", sym);
+                            }
+                        }
                     }
                 }
 
+                check_march(&config)?;
+
+                let mut builder = WhittedBuilder::new(camera, config, num_reflections)
+                    .with_background(background);
+                if let Some(cell) = shadow_cache {
+                    builder = builder.with_shadow_cache(cell);
+                }
+                if let Some(outline) = outline {
+                    builder = builder.with_outline(outline);
+                }
+
                 Ok((
                     info,
                     sampler,
-                    Box::new(WhittedBuilder::new(camera, config, num_reflections))
-                        as Box<dyn IntegratorBuilder>,
+                    Box::new(builder) as Box<dyn IntegratorBuilder>,
                 ))
             }
 
-            integrator => bail!("Unknown integrator: `{}`", integrator),
+            "debug" => {
+                let mut sampler = me.parse_sampler()?;
+                if let Some(samples) = me.overrides.samples {
+                    sampler = Box::new(UniformSampler::with_count(samples));
+                }
+
+                let (info, camera) = me.parse_camera()?;
+
+                let mode = me.ident()?;
+                let mut far = 10.;
+                let mut config = me.march.clone();
+                while !me.peek_rparen() {
+                    match me.symbol()?.as_ref() {
+                        ":far" => {
+                            far = me.number()?;
+                            if far.is_nan() || far <= 0. {
+                                bail!("The :far distance of a depth view must be positive");
+                            }
+                        }
+                        sym => {
+                            if !me.parse_march_field(&mut config, sym)? {
+                                bail!("Unknown field `{}`", sym);
+                            }
+                        }
+                    }
+                }
+
+                check_march(&config)?;
+
+                let mode = match mode.as_ref() {
+                    "normals" => DebugMode::Normals,
+                    "depth" => DebugMode::Depth { far },
+                    "steps" => DebugMode::Steps,
+                    "uv" => DebugMode::Uv,
+                    mode => bail!("Unknown debug mode: `{}`", mode),
+                };
+
+                Ok((
+                    info,
+                    sampler,
+                    Box::new(DebugBuilder::new(camera, config, mode)) as Box<dyn IntegratorBuilder>,
+                ))
+            }
+
+            integrator => {
+                let Some(factory) = me.plugins.integrator(integrator) else {
+                    bail!("Unknown integrator: `{}`", integrator);
+                };
+
+                let mut sampler = me.parse_sampler()?;
+                if let Some(samples) = me.overrides.samples {
+                    sampler = Box::new(UniformSampler::with_count(samples));
+                }
+
+                let (info, camera) = me.parse_camera()?;
+
+                let mut config = me.march.clone();
+                let mut fields = Vec::new();
+                while !me.peek_rparen() {
+                    let sym = me.symbol()?;
+                    if !me.parse_march_field(&mut config, &sym)? {
+                        fields.push((String::from(&sym[1..]), me.parse_value()?));
+                    }
+                }
+
+                check_march(&config)?;
+
+                let builder = factory(IntegratorArgs {
+                    info: info.clone(),
+                    camera,
+                    config,
+                    fields,
+                })
+                .with_context(|| format!("Failed to build the `{}` integrator", integrator))?;
+
+                Ok((info, sampler, builder))
+            }
+        })
+    }
+
+    /// A field of a form added by a plugin, which the parser doesn't know the meaning of.
+    fn parse_value(&mut self) -> Result<Value> {
+        let Some(tok) = self.lexer.peek() else {
+            bail!("Expected a value, but the input ended");
+        };
+
+        match tok.token {
+            Token::Number => Ok(Value::Number(self.number()?)),
+            Token::String => Ok(Value::String(self.string()?)),
+            Token::Ident => Ok(Value::Ident(self.ident()?)),
+            Token::Color => Ok(Value::Color(self.color()?)),
+            Token::LParen => self.parens(|me| {
+                let mut values = Vec::new();
+                while !me.peek_rparen() {
+                    values.push(me.parse_value()?);
+                }
+                Ok(Value::List(values))
+            }),
+            _ => bail!("Expected a value, but found `{}`", tok.text),
+        }
+    }
+
+    fn parse_background(&mut self, info: &CanvasInfo) -> Result<Background> {
+        if self.peek_ident() {
+            return match self.ident()?.as_ref() {
+                // `opaque` is what `sky` was called before there were other backgrounds.
+                "sky" | "opaque" => Ok(Background::Sky),
+                "transparent" => Ok(Background::Transparent),
+                background => bail!("Unknown background: `{}`", background),
+            };
+        }
+
+        if !self.peek_lparen() {
+            return Ok(Background::Solid(self.color()?));
+        }
+
+        self.parens(|me| match me.ident()?.as_ref() {
+            "gradient" => {
+                let top = me.color()?;
+                let bottom = me.color()?;
+                Ok(Background::Gradient { top, bottom })
+            }
+
+            "image" => {
+                let path = me.string()?;
+                let mut mapping = Mapping::LatLong;
+                while !me.peek_rparen() {
+                    match me.symbol()?.as_ref() {
+                        ":mapping" => {
+                            mapping = match me.ident()?.as_ref() {
+                                "lat-long" => Mapping::LatLong,
+                                "screen" => Mapping::Screen {
+                                    width: info.width_float(),
+                                    height: info.height_float(),
+                                },
+                                mapping => bail!("Unknown image mapping: `{}`", mapping),
+                            }
+                        }
+                        sym => bail!("Unknown image field `{}`", sym),
+                    }
+                }
+                me.use_file(Path::new(&path));
+                Background::load(path, mapping)
+            }
+
+            background => bail!("Unknown background: `{}`", background),
+        })
+    }
+
+    fn parse_exposure(&mut self) -> Result<AutoExposure> {
+        self.parens(|me| match me.ident()?.as_ref() {
+            "auto" => {
+                let mut exposure = AutoExposure::default();
+
+                while !me.peek_rparen() {
+                    match me.symbol()?.as_ref() {
+                        ":key" => exposure.key = me.scalar()?,
+                        ":min" => exposure.min = me.scalar()?,
+                        ":max" => exposure.max = me.scalar()?,
+                        sym => bail!("Unknown exposure field `{}`", sym),
+                    }
+                }
+
+                if exposure.min <= 0. {
+                    bail!("Exposure :min must be positive");
+                }
+                if exposure.min > exposure.max {
+                    bail!("Exposure :min must not be larger than :max");
+                }
+
+                Ok(exposure)
+            }
+
+            exposure => bail!("Unknown exposure type: `{}`", exposure),
+        })
+    }
+
+    fn parse_bloom(&mut self) -> Result<Bloom> {
+        self.parens(|me| match me.ident()?.as_ref() {
+            "gaussian" => {
+                let mut bloom = Bloom::default();
+
+                while !me.peek_rparen() {
+                    match me.symbol()?.as_ref() {
+                        ":threshold" => bloom.threshold = me.scalar()?,
+                        ":radius" => bloom.radius = me.scalar()?,
+                        ":intensity" => bloom.intensity = me.scalar()?,
+                        ":streaks" => {
+                            let streaks = me.number()?;
+                            if !(0. ..=MAX_STREAKS as Float).contains(&streaks) {
+                                bail!("Bloom :streaks must be between 0 and {}", MAX_STREAKS);
+                            }
+                            bloom.streaks = streaks as u32;
+                        }
+                        ":streak-length" => bloom.streak_length = me.scalar()?,
+                        sym => bail!("Unknown bloom field `{}`", sym),
+                    }
+                }
+
+                if [bloom.radius, bloom.streak_length]
+                    .iter()
+                    .any(|length| !(*length > 0. && *length <= MAX_BLOOM_SIZE))
+                {
+                    bail!(
+                        "Bloom :radius and :streak-length must be positive, and at most {}",
+                        MAX_BLOOM_SIZE
+                    );
+                }
+
+                Ok(bloom)
+            }
+
+            bloom => bail!("Unknown bloom type: `{}`", bloom),
+        })
+    }
+
+    fn parse_denoise(&mut self) -> Result<Denoise> {
+        self.parens(|me| match me.ident()?.as_ref() {
+            "bilateral" => {
+                let mut denoise = Denoise::default();
+
+                while !me.peek_rparen() {
+                    match me.symbol()?.as_ref() {
+                        ":radius" => denoise.radius = me.scalar()?,
+                        ":color" => denoise.color = me.scalar()?,
+                        ":normal" => denoise.normal = me.scalar()?,
+                        ":albedo" => denoise.albedo = me.scalar()?,
+                        sym => bail!("Unknown denoise field `{}`", sym),
+                    }
+                }
+
+                if [
+                    denoise.radius,
+                    denoise.color,
+                    denoise.normal,
+                    denoise.albedo,
+                ]
+                .iter()
+                .any(|sigma| !(*sigma > 0. && sigma.is_finite()))
+                {
+                    bail!("Denoise fields must be positive and finite");
+                }
+
+                if denoise.radius > MAX_DENOISE_RADIUS {
+                    bail!("Denoise :radius must be at most {}", MAX_DENOISE_RADIUS);
+                }
+
+                Ok(denoise)
+            }
+
+            denoise => bail!("Unknown denoise type: `{}`", denoise),
         })
     }
 
@@ -582,45 +2555,250 @@ impl<'a> Parser<'a> {
                 "pattern" => {
                     let name = me.ident()?;
                     let id = me.parse_pattern()?;
+                    me.declare("pattern", &name, None);
                     me.patterns.insert(name, id);
                 }
 
                 "material" => {
                     let name = me.ident()?;
                     let id = me.parse_material()?;
+                    me.declare("material", &name, None);
                     me.materials.insert(name, id);
                 }
 
                 "node" => {
                     let name = me.ident()?;
                     let id = me.parse_node()?;
+                    me.declare("node", &name, Some(id));
                     me.nodes.insert(name, id);
                 }
 
                 "light" => {
+                    me.use_globally();
                     me.parse_light()?;
                 }
 
+                "fog" => {
+                    me.use_globally();
+                    let mut fog = Fog {
+                        color: Color::white(),
+                        density: 0.02,
+                    };
+
+                    while !me.peek_rparen() {
+                        match me.symbol()?.as_ref() {
+                            ":color" => fog.color = me.color()?,
+                            ":density" => fog.density = me.scalar()?,
+                            sym => bail!("Unknown fog field `{}`", sym),
+                        }
+                    }
+                    non_negative("density of fog", fog.density as Float)?;
+
+                    me.scene.fog = Some(fog);
+                }
+
+                "defaults" => {
+                    me.use_globally();
+                    let mut march = me.march.clone();
+                    while !me.peek_rparen() {
+                        let sym = me.symbol()?;
+                        if sym == ":max-depth" {
+                            let depth = me.number()?;
+                            if !(1. ..=MAX_DEPTH_LIMIT as Float).contains(&depth) {
+                                bail!(
+                                    "The :max-depth of the defaults must be between 1 and {}",
+                                    MAX_DEPTH_LIMIT
+                                );
+                            }
+                            me.scene.max_depth = depth as usize;
+                        } else if !me.parse_march_field(&mut march, &sym)? {
+                            bail!("Unknown defaults field `{}`", sym);
+                        }
+                    }
+
+                    check_march(&march)?;
+                    me.march = march;
+                }
+
+                "bvh" => {
+                    me.use_globally();
+                    while !me.peek_rparen() {
+                        match me.symbol()?.as_ref() {
+                            ":split" => {
+                                me.scene.bvh.split = match me.ident()?.as_ref() {
+                                    "sah" => Split::Sah,
+                                    "midpoint" => Split::Midpoint,
+                                    split => bail!("Unknown bvh split: `{}`", split),
+                                }
+                            }
+                            ":max-leaf-size" => {
+                                me.scene.bvh.max_leaf_size = me.number()?.max(1.) as usize
+                            }
+                            sym => bail!("Unknown bvh field `{}`", sym),
+                        }
+                    }
+                }
+
+                cond @ ("if-flag" | "if-env") => {
+                    let name = me.string()?;
+                    let enabled = condition_enabled(cond, &name, &me.flags);
+
+                    if enabled {
+                        me.parse_command()?;
+                    } else {
+                        me.skip()?;
+                    }
+
+                    if !me.peek_rparen() {
+                        if enabled {
+                            me.skip()?;
+                        } else {
+                            me.parse_command()?;
+                        }
+                    }
+                }
+
+                "sampler" => {
+                    let name = me.ident()?;
+                    let sampler = me.parse_sampler()?;
+                    me.declare("sampler", &name, None);
+                    me.samplers.insert(name, sampler);
+                }
+
                 "camera" => {
                     let name = me.ident()?;
                     let (info, camera) = me.parse_camera()?;
+                    me.declare("camera", &name, None);
                     me.cameras.push((name, info, camera));
                 }
 
                 "render" => {
+                    let name = if me.peek_ident() {
+                        let name = me.ident()?;
+                        if me.renders.iter().any(|render| render.is_named(&name)) {
+                            bail!("There is already a render named `{}`", name);
+                        }
+                        Some(name)
+                    } else {
+                        None
+                    };
+
                     let target = me.parse_target()?;
+                    if let Target::File { path, .. } = &target {
+                        if path.uses_name() && name.is_none() {
+                            bail!("A render must be named to use `{{name}}` in its path");
+                        }
+                    }
 
                     let (canvas_info, sampler, builder) = me.parse_integrator()?;
 
                     let root = me.parse_node()?;
 
+                    let mut exposure = None;
+                    let mut bloom = None;
+                    let mut denoise = None;
+                    let mut lens: Option<LensEffects> = None;
+                    let mut preview = None;
+                    let mut clips = Vec::new();
+                    let mut cap = None;
+
+                    while !me.peek_rparen() {
+                        match me.symbol()?.as_ref() {
+                            ":clip" => clips.push(me.parens(|me| match me.ident()?.as_ref() {
+                                "plane" => {
+                                    let point = me.point()?;
+                                    let Some(normal) = Unit::try_new(me.vector()?, 0.) else {
+                                        bail!("A clipping plane needs a nonzero normal");
+                                    };
+                                    Ok((point, normal))
+                                }
+                                clip => bail!("Unknown clip type: {}", clip),
+                            })?),
+                            ":cap" => cap = Some(me.parse_material()?),
+                            ":exposure" => exposure = Some(me.parse_exposure()?),
+                            ":bloom" => bloom = Some(me.parse_bloom()?),
+                            ":denoise" => denoise = Some(me.parse_denoise()?),
+                            ":preview" => {
+                                let (width, height) =
+                                    me.parens(|me| Ok((me.number()?, me.number()?)))?;
+                                if width < 1. || height < 1. {
+                                    bail!("A preview must be at least one pixel across");
+                                }
+                                if !matches!(target, Target::File { .. }) {
+                                    bail!("Only file targets can have a preview");
+                                }
+
+                                // The preview is scaled with the image, so that it stays smaller.
+                                let size = me.scale_canvas(width as u32, height as u32);
+                                if size.width > canvas_info.width
+                                    || size.height > canvas_info.height
+                                {
+                                    bail!(
+                                        "A preview can't be larger than the {}x{} image",
+                                        canvas_info.width,
+                                        canvas_info.height
+                                    );
+                                }
+                                preview = Some((size.width, size.height));
+                            }
+                            ":vignette" => {
+                                let vignette = me.scalar()?;
+                                if !(0. ..=1.).contains(&vignette) {
+                                    bail!("A vignette must be between 0 and 1");
+                                }
+                                lens.get_or_insert_with(LensEffects::default).vignette = vignette;
+                            }
+                            ":aberration" => {
+                                lens.get_or_insert_with(LensEffects::default).aberration =
+                                    me.scalar()?
+                            }
+                            ":distortion" => {
+                                lens.get_or_insert_with(LensEffects::default).distortion =
+                                    me.scalar()?
+                            }
+                            sym => bail!("Unknown render field `{}`", sym),
+                        }
+                    }
+
+                    // The effects need the whole image, which a streamed file never holds.
+                    if matches!(target, Target::File { stream: true, .. })
+                        && (exposure.is_some()
+                            || bloom.is_some()
+                            || denoise.is_some()
+                            || lens.is_some()
+                            || preview.is_some())
+                    {
+                        bail!(
+                            "A streamed render can't have an :exposure, :bloom, :denoise, \
+                             lens effects or a :preview"
+                        );
+                    }
+
+                    let root = if clips.is_empty() {
+                        if cap.is_some() {
+                            bail!("A render needs a :clip for its :cap to cover");
+                        }
+                        root
+                    } else {
+                        me.scene.clip(root, &clips, cap)?
+                    };
+
                     me.renders.push(Render {
+                        name,
                         target,
                         canvas_info,
                         root,
                         sampler,
                         builder,
-                    })
+                        exposure,
+                        bloom,
+                        denoise,
+                        lens,
+                        preview,
+                        fingerprint: 0,
+                        files: Vec::new(),
+                    });
+                    me.render_commands.push(me.commands.len().saturating_sub(1));
                 }
 
                 command => bail!("Failed to parse command: {}", command),
@@ -631,9 +2809,528 @@ impl<'a> Parser<'a> {
 
     fn parse(&mut self) -> Result<()> {
         while self.lexer.peek().is_some() {
+            self.commands.push(Command::default());
             self.parse_command()?;
         }
 
+        self.fingerprint_renders();
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::lexer::token_stream;
+    use crate::parser::template::PathVars;
+
+    const VARS: PathVars = PathVars {
+        name: "shot",
+        frame: 0,
+        width: 8,
+        height: 8,
+        date: "2024-01-01",
+    };
+
+    fn targets(input: &str, flags: &[&str]) -> Vec<String> {
+        let flags: Vec<_> = flags.iter().map(|f| f.to_string()).collect();
+        let (_, renders) = parse(input, &flags).unwrap();
+        renders
+            .into_iter()
+            .map(|render| match render.target {
+                Target::Ascii { name, .. } => name,
+                Target::File { path, .. } => path.expand(&VARS).to_str().unwrap().to_string(),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_named_sampler() {
+        let input = r#"
+            (sampler preview (uniform 2 3))
+            (camera cam (pinhole 8 8 (look-at (0 0 -2) (0 0 0) (0 1 0)) (degrees 90)))
+            (render (ascii "a") (whitted preview cam) (sphere 1))
+            (render (ascii "b") (whitted preview cam) (sphere 1))
+        "#;
+
+        let (_, renders) = parse(input, &[]).unwrap();
+        for render in renders {
+            assert_eq!(6, render.sampler.samples_per_pixel());
+        }
+
+        let missing = r#"(render (ascii "a") (whitted missing cam) (sphere 1))"#;
+        assert!(parse(missing, &[]).is_err());
+    }
+
+    #[test]
+    fn test_if_flag() {
+        let input = r#"
+            (camera cam (pinhole 8 8 (look-at (0 0 -2) (0 0 0) (0 1 0)) (degrees 90)))
+            (if-flag "final"
+              (render (ascii "final") (whitted (uniform 4) cam) (sphere 1))
+              (render (ascii "preview") (whitted (uniform 1) cam) (sphere 1)))
+            (if-flag "extra"
+              (render (ascii "extra") (whitted (uniform 1) cam) (sphere 1)))
+        "#;
+
+        assert_eq!(vec!["preview"], targets(input, &[]));
+        assert_eq!(vec!["final"], targets(input, &["final"]));
+        assert_eq!(vec!["final", "extra"], targets(input, &["extra", "final"]));
+    }
+
+    #[test]
+    fn test_overrides() {
+        let input = r#"
+            (sampler final (uniform 4))
+            (camera cam (pinhole 64 32 (look-at (0 0 -2) (0 0 0) (0 1 0)) (degrees 90)))
+            (render (file "renders/shot.png") (whitted final cam) (sphere 1))
+            (render (ascii "shot") (whitted (uniform 2) cam) (sphere 1))
+        "#;
+
+        let overrides = Overrides {
+            scale: Some(0.25),
+            samples: Some(1),
+            output_dir: Some(PathBuf::from("out")),
+            ..Overrides::default()
+        };
+        let (_, renders) = parse_with(input, &[], &overrides).unwrap();
+        for render in renders.iter() {
+            assert_eq!(
+                (16, 8),
+                (render.canvas_info.width, render.canvas_info.height)
+            );
+            assert_eq!(1, render.sampler.samples_per_pixel());
+        }
+        assert!(
+            matches!(&renders[0].target, Target::File { path, .. } if path.expand(&VARS) == std::path::Path::new("out/renders/shot.png"))
+        );
+
+        let overrides = Overrides {
+            scale: Some(0.),
+            ..Overrides::default()
+        };
+        assert!(parse_with(input, &[], &overrides).is_err());
+    }
+
+    #[test]
+    fn test_select_renders() {
+        let input = r#"
+            (camera cam (pinhole 8 8 (look-at (0 0 -2) (0 0 0) (0 1 0)) (degrees 90)))
+            (render wide (ascii "a") (whitted (uniform 1) cam) (sphere 1))
+            (render close (ascii "b") (whitted (uniform 1) cam) (sphere 1))
+            (render (ascii "c") (whitted (uniform 1) cam) (sphere 1))
+        "#;
+
+        let select = |only: &[&str], skip: &[&str]| {
+            let overrides = Overrides {
+                only: only.iter().map(|name| name.to_string()).collect(),
+                skip: skip.iter().map(|name| name.to_string()).collect(),
+                ..Overrides::default()
+            };
+            let (_, renders) = parse_with(input, &[], &overrides)?;
+            Ok::<_, anyhow::Error>(renders.len())
+        };
+
+        assert_eq!(3, select(&[], &[]).unwrap());
+        assert_eq!(1, select(&["close"], &[]).unwrap());
+        assert_eq!(2, select(&[], &["close"]).unwrap());
+        assert_eq!(1, select(&["wide", "close"], &["wide"]).unwrap());
+        assert!(select(&["missing"], &[]).is_err());
+
+        let duplicate = r#"
+            (render a (ascii "a") (whitted (uniform 1) (pinhole 8 8 (translate 0 0 0) (degrees 90))) (sphere 1))
+            (render a (ascii "b") (whitted (uniform 1) (pinhole 8 8 (translate 0 0 0) (degrees 90))) (sphere 1))
+        "#;
+        assert!(parse(duplicate, &[]).is_err());
+    }
+
+    #[test]
+    fn test_file_template() {
+        let input = r#"
+            (camera cam (pinhole 8 8 (look-at (0 0 -2) (0 0 0) (0 1 0)) (degrees 90)))
+            (render shot (file "{date}/{name}-{width}x{height}.png") (whitted (uniform 1) cam) (sphere 1))
+        "#;
+        assert_eq!(vec!["2024-01-01/shot-8x8.png"], targets(input, &[]));
+
+        let input = r#"
+            (camera cam (pinhole 8 8 (look-at (0 0 -2) (0 0 0) (0 1 0)) (degrees 90)))
+            (render (file "depth.png" :channels luma :depth 16) (whitted (uniform 1) cam) (sphere 1))
+        "#;
+        let (_, renders) = parse(input, &[]).unwrap();
+        assert!(matches!(
+            renders[0].target,
+            Target::File {
+                channels: Channels::Luma,
+                depth: BitDepth::Sixteen,
+                ..
+            }
+        ));
+
+        let unnamed = r#"
+            (camera cam (pinhole 8 8 (look-at (0 0 -2) (0 0 0) (0 1 0)) (degrees 90)))
+            (render (file "{name}.png") (whitted (uniform 1) cam) (sphere 1))
+        "#;
+        assert!(parse(unnamed, &[]).is_err());
+    }
+
+    #[test]
+    fn test_rotation_forms() {
+        let transform = |input: &str| Parser::new(Lexer::new(input)).parse_transform();
+        let moves = |input: &str, from: Point3<Float>, to: Point3<Float>| {
+            let p = from.apply(&transform(input).unwrap());
+            assert!((p - to).norm() < 1e-5, "{}: {}", input, p);
+        };
+
+        moves(
+            "(rotate-x 90)",
+            Point3::new(0., 1., 0.),
+            Point3::new(0., 0., 1.),
+        );
+        moves(
+            "(rotate-y 90)",
+            Point3::new(0., 0., 1.),
+            Point3::new(1., 0., 0.),
+        );
+        moves(
+            "(rotate-z (radians 1.5707964))",
+            Point3::new(1., 0., 0.),
+            Point3::new(0., 1., 0.),
+        );
+
+        // Euler angles turn about x, then y, then z.
+        moves(
+            "(rotate-euler 90 0 90)",
+            Point3::new(0., 1., 0.),
+            Point3::new(0., 0., 1.),
+        );
+        moves(
+            "(rotate-euler 90 90 0)",
+            Point3::new(0., 1., 0.),
+            Point3::new(1., 0., 0.),
+        );
+
+        moves(
+            "(rotate-quat 0.7071068 0 0 0.7071068)",
+            Point3::new(1., 0., 0.),
+            Point3::new(0., 1., 0.),
+        );
+        moves(
+            "(rotate-quat 2 0 0 0)",
+            Point3::new(1., 2., 3.),
+            Point3::new(1., 2., 3.),
+        );
+        assert!(transform("(rotate-quat 0 0 0 0)").is_err());
+
+        // The pivot stays where it is.
+        moves(
+            "(about (1 0 0) (rotate-z 180))",
+            Point3::new(0., 0., 0.),
+            Point3::new(2., 0., 0.),
+        );
+        moves(
+            "(about (1 1 1) (uniform-scale 2))",
+            Point3::new(1., 1., 1.),
+            Point3::new(1., 1., 1.),
+        );
+        moves(
+            "(about (0 1 0) (scale (2 1 1)))",
+            Point3::new(1., 0., 0.),
+            Point3::new(2., 0., 0.),
+        );
+    }
+
+    #[test]
+    fn test_select_tags() {
+        let input = r#"
+            (node pane (tag "glass" "window" (box 1 1 0.1)))
+            (node bottle (tag "glass" (sphere 0.5)))
+            (node crate (tag "wood" (box 1 1 1)))
+            (node all-glass (group (select :tag "glass")))
+            (node windows (group crate (select :tag "glass" :tag "window")))
+        "#;
+        let mut parser = Parser::new(Lexer::new(input));
+        parser.parse().unwrap();
+
+        let pane = parser.nodes["pane"];
+        let bottle = parser.nodes["bottle"];
+        let crate_ = parser.nodes["crate"];
+        let members = |name: &str| {
+            let crate::scene::Node::Group { nodes, .. } = parser.scene.node(parser.nodes[name])
+            else {
+                panic!("{} isn't a group", name);
+            };
+            let mut members: Vec<_> = nodes.iter().copied().collect();
+            members.sort();
+            members
+        };
+        assert_eq!(vec![pane, bottle], members("all-glass"));
+        assert_eq!(vec![pane, crate_], members("windows"));
+
+        let fails = |input: &str| {
+            let mut parser = Parser::new(Lexer::new(input));
+            assert!(parser.parse().is_err(), "{}", input);
+        };
+        fails(r#"(node a (group (select :tag "missing")))"#);
+        fails(r#"(node a (tag "x" (sphere 1))) (node b (select :tag "x"))"#);
+        fails(r#"(node a (group (select)))"#);
+    }
+
+    #[test]
+    fn test_fingerprints() {
+        let base = r#"
+            (camera cam (pinhole 8 8 (look-at (0 0 -2) (0 0 0) (0 1 0)) (degrees 90)))
+            (material red (phong :pattern (solid #ff0000)))
+            (node ball (paint red (sphere 1)))
+            (render (ascii "ball") (whitted (uniform 1) cam) ball)
+            (render (ascii "box") (whitted (uniform 1) cam) (box 1 1 1))
+        "#;
+        let fingerprints = |input: &str| {
+            let (_, renders) = parse(input, &[]).unwrap();
+            renders
+                .iter()
+                .map(|render| render.fingerprint)
+                .collect::<Vec<_>>()
+        };
+        let before = fingerprints(base);
+        assert_ne!(before[0], before[1]);
+
+        // Comments and the layout of the description don't matter.
+        let edit = |from, to| fingerprints(&base.replacen(from, to, 1));
+        assert_eq!(before, edit("(node ball", "; the ball\n(node   ball"));
+
+        // Changing the material only changes the render that uses it, and changing a render only
+        // changes that render.
+        let after = edit("#ff0000", "#00ff00");
+        assert_ne!(before[0], after[0]);
+        assert_eq!(before[1], after[1]);
+        let after = edit("(box 1 1 1)", "(box 1 2 1)");
+        assert_eq!(before[0], after[0]);
+        assert_ne!(before[1], after[1]);
+
+        // Lights change every render, and so do the cameras they share.
+        let after = edit("(render", "(light (point #ffffff (0 2 0))) (render");
+        assert!(before.iter().zip(&after).all(|(a, b)| a != b));
+        let after = edit("(degrees 90)", "(degrees 60)");
+        assert!(before.iter().zip(&after).all(|(a, b)| a != b));
+    }
+
+    #[test]
+    fn test_render_files() {
+        let input = r#"
+            (camera cam (pinhole 8 8 (look-at (0 0 -2) (0 0 0) (0 1 0)) (degrees 90)))
+            (node hills (heightfield "tests/corpus/valid/hills.png" :scale (1 1 1)))
+            (node clay (paint (matcap "tests/corpus/valid/hills.png") (sphere 1)))
+            (render (ascii "hills") (whitted (uniform 1) cam) (group hills clay))
+            (render (ascii "box") (whitted (uniform 1) cam) (box 1 1 1))
+        "#;
+        let (_, renders) = parse(input, &[]).unwrap();
+
+        // Each file is given once, and only to the renders that depend on it.
+        assert_eq!(
+            renders[0].files,
+            vec![PathBuf::from("tests/corpus/valid/hills.png")]
+        );
+        assert!(renders[1].files.is_empty());
+    }
+
+    #[test]
+    fn test_plugin_integrator() {
+        use crate::camera::Sample;
+        use crate::integrator::{Integrator, Radiance};
+
+        struct Flat(Color);
+
+        impl IntegratorBuilder for Flat {
+            fn build(&self) -> Box<dyn Integrator> {
+                Box::new(Flat(self.0.clone()))
+            }
+        }
+
+        impl Integrator for Flat {
+            fn luminance(&mut self, _: &Scene, _: NodeId, _: &Sample) -> Radiance {
+                Radiance::opaque(self.0.clone())
+            }
+        }
+
+        let plugins = Plugins::default().with_integrator("flat", |args| {
+            assert_eq!(8, args.info.width);
+            assert_eq!(32, args.config.max_steps);
+            assert_eq!(Some(2.), args.field("scale").and_then(Value::as_number));
+            let Some(color) = args.field("color").and_then(Value::as_color) else {
+                bail!("A flat integrator needs a :color");
+            };
+            Ok(Box::new(Flat(color.clone() * 2.)))
+        });
+
+        let input = |fields| {
+            format!(
+                r#"(render (ascii "flat")
+                     (flat (uniform 1) (pinhole 8 8 (translate 0 0 -2) (degrees 90)) {})
+                     (sphere 1))"#,
+                fields
+            )
+        };
+        let parse =
+            |fields| parse_with_plugins(&input(fields), &[], &Overrides::default(), &plugins);
+
+        let (scene, renders) = parse(":max-steps 32 :color #ff0000 :scale 2").unwrap();
+        let mut integrator = renders[0].builder.build();
+        let color = integrator
+            .luminance(&scene, renders[0].root, &Sample::new(4., 4.))
+            .color;
+        assert_eq!(2., color.r);
+
+        // The factory's errors are reported, and integrators that aren't registered are unknown.
+        assert!(parse(":max-steps 32 :scale 2").is_err());
+        assert!(parse(":max-steps 0 :color #ff0000 :scale 2").is_err());
+        assert!(super::parse(&input(":color #ff0000"), &[]).is_err());
+    }
+
+    #[test]
+    fn test_plugin_primitive() {
+        use crate::bvh::BoundingBox;
+        use crate::integrator::Hit;
+        use crate::ray::Ray;
+        use crate::sdf::Sdf;
+
+        #[derive(Debug)]
+        struct Octahedron(Float);
+
+        impl Sdf for Octahedron {
+            fn sdf(&self, p: &Point3<Float>) -> Float {
+                (p.x.abs() + p.y.abs() + p.z.abs() - self.0) * 0.577
+            }
+
+            fn bounding_box(&self) -> BoundingBox {
+                let r = Vector3::repeat(self.0);
+                BoundingBox::new(Point3::from(-r), Point3::from(r))
+            }
+        }
+
+        let plugins = Plugins::default().with_primitive("octahedron", |args| {
+            let size = match args.values.as_slice() {
+                [Value::Number(size)] if *size > 0. => *size,
+                _ => bail!("An octahedron needs a positive size"),
+            };
+            let scale = args.field("scale").and_then(Value::as_number).unwrap_or(1.);
+            Ok(Box::new(Octahedron(size * scale)))
+        });
+        let parse = |node| {
+            let input = format!("(node shape {})", node);
+            parse_node_with(&input, &plugins)
+        };
+
+        let (scene, root) = parse("(transform (translate 0 1 0) (octahedron 1 :scale 2))").unwrap();
+        assert!(scene.bounding_box(root).contains(&Point3::new(0., 2.9, 0.)));
+        let ray = Ray::new(Point3::new(0., 1., -5.), Vector3::z_axis());
+        let hit = Hit::march(&MarchConfig::default(), &scene, root, ray, false).unwrap();
+        assert!((hit.distance.0 - 3.).abs() < 0.01, "{}", hit.distance.0);
+
+        assert!(parse("(octahedron -1)").is_err());
+        assert!(parse("(octahedron 1 :scale)").is_err());
+        assert!(parse_node_with("(node shape (octahedron 1))", &Plugins::default()).is_err());
+    }
+
+    fn parse_node_with(input: &str, plugins: &Plugins) -> Result<(Scene, NodeId)> {
+        let parsed = parse_all(input, &[], &Overrides::default(), plugins)?;
+        Ok((parsed.scene, parsed.declarations[0].node.unwrap()))
+    }
+
+    #[test]
+    fn test_malformed_input() {
+        let nested = format!("(node a {}", "(group ".repeat(100_000));
+        assert!(parse(&nested, &[]).is_err());
+        assert!(parse("(node é (sphere 1))", &[]).is_err());
+        assert!(parse("(if-flag \"open (node a (sphere 1)))", &[]).is_err());
+        assert!(parse("(node a (smooth-union 1))", &[]).is_err());
+        assert!(parse("(sampler s (uniform 0))", &[]).is_err());
+        let priority = |priority: &str| {
+            let input = format!(
+                "(material m (phong :pattern (solid #ffffff) :priority {}))",
+                priority
+            );
+            parse(&input, &[])
+        };
+        assert!(priority("2").is_ok());
+        assert!(priority("-1").is_err());
+        assert!(priority("1.5").is_err());
+        assert!(parse(
+            "(camera c (pinhole 0 8 (translate 0 0 0) (degrees 90)))",
+            &[]
+        )
+        .is_err());
+        assert!(parse(
+            "(camera c (pinhole 8 8 (translate 0 0 0) (degrees 0)))",
+            &[]
+        )
+        .is_err());
+
+        // Nesting up to the limit is still accepted.
+        let depth = MAX_DEPTH - 2;
+        let nested = format!(
+            "(node a {}(sphere 1){})",
+            "(group ".repeat(depth),
+            ")".repeat(depth)
+        );
+        assert!(parse(&nested, &[]).is_ok());
+    }
+
+    #[test]
+    fn test_denoise_bounds() {
+        let render = |denoise: &str| {
+            format!(
+                "(node a (sphere 1))\n(render (ascii \"out\") (whitted (uniform 1) \
+                 (pinhole 8 8 (translate 0 0 -4) (degrees 60))) a :denoise (bilateral {}))",
+                denoise
+            )
+        };
+
+        assert!(parse(&render(":radius 64"), &[]).is_ok());
+        assert!(parse(&render(":radius 65"), &[]).is_err());
+        assert!(parse(&render(":radius 1e30"), &[]).is_err());
+        assert!(parse(&render(":radius 0"), &[]).is_err());
+        assert!(parse(&render(":color 1e39"), &[]).is_err());
+    }
+
+    #[test]
+    fn test_max_depth() {
+        // Named nodes nest without nesting the expressions, so they're only caught by the depth
+        // of the graph under a render.
+        let chain = |defaults: &str| {
+            let mut input = format!("{}\n(node n0 (sphere 1))\n", defaults);
+            for i in 1..300 {
+                input += &format!("(node n{} (invert n{}))\n", i, i - 1);
+            }
+            input
+                + "(render (ascii \"out\") (whitted (uniform 1) \
+                     (pinhole 8 8 (translate 0 0 -4) (degrees 60))) n299)"
+        };
+
+        let Err(err) = parse(&chain(""), &[]) else {
+            panic!("the chain of nodes parsed");
+        };
+        let err = format!("{:#}", err);
+        assert!(err.starts_with("Render 1 can't be marched: "), "{}", err);
+        assert!(
+            err.contains("(invert) has nodes nested 257 deep"),
+            "{}",
+            err
+        );
+        let (scene, _) = parse(&chain("(defaults :max-depth 300)"), &[]).unwrap();
+        assert_eq!(300, scene.max_depth);
+        assert!(parse(&chain("(defaults :max-depth 0)"), &[]).is_err());
+        assert!(parse(&chain("(defaults :max-depth 1025)"), &[]).is_err());
+    }
+
+    proptest::proptest! {
+        #[test]
+        fn test_parse_never_panics(input in "\\PC*") {
+            let _ = parse(&input, &[]);
+        }
+
+        #[test]
+        fn test_parse_token_stream(input in token_stream()) {
+            let _ = parse(&input, &[]);
+        }
+    }
+}
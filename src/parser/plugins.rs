@@ -0,0 +1,142 @@
+//! Forms that programs using the library add to the scene description, so that they can render
+//! with integrators and primitives of their own without changing the parser.
+
+use anyhow::Error;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use crate::{
+    camera::{Camera, CanvasInfo},
+    canvas::Color,
+    integrator::IntegratorBuilder,
+    math::Float,
+    scene::MarchConfig,
+    sdf::Sdf,
+};
+
+type Result<T> = std::result::Result<T, Error>;
+
+/// The value of a field in a custom form.
+#[derive(Debug, Clone)]
+pub enum Value {
+    Number(Float),
+    String(String),
+    Ident(String),
+    Color(Color),
+
+    /// A parenthesized list of values, such as a point or a form like `(degrees 30)`.
+    List(Vec<Value>),
+}
+
+impl Value {
+    pub fn as_number(&self) -> Option<Float> {
+        match self {
+            Value::Number(num) => Some(*num),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Value::String(text) | Value::Ident(text) => Some(text),
+            _ => None,
+        }
+    }
+
+    pub fn as_color(&self) -> Option<&Color> {
+        match self {
+            Value::Color(color) => Some(color),
+            _ => None,
+        }
+    }
+}
+
+/// A custom integrator form, `(name <sampler> <camera> :field value ...)`, with the parts that the
+/// parser understands already parsed. The sampler is kept by the render.
+pub struct IntegratorArgs {
+    pub info: CanvasInfo,
+    pub camera: Arc<dyn Camera>,
+
+    /// The march from the scene's defaults, with the march fields of the form applied.
+    pub config: MarchConfig,
+
+    /// The other fields, in the order that they're given, without their leading `:`.
+    pub fields: Vec<(String, Value)>,
+}
+
+impl IntegratorArgs {
+    /// The last value given for the field `name`.
+    pub fn field(&self, name: &str) -> Option<&Value> {
+        find_field(&self.fields, name)
+    }
+}
+
+/// A custom primitive form, `(name <value>... :field value ...)`.
+pub struct PrimitiveArgs {
+    /// The values given before the first field.
+    pub values: Vec<Value>,
+
+    /// The fields, in the order that they're given, without their leading `:`.
+    pub fields: Vec<(String, Value)>,
+}
+
+impl PrimitiveArgs {
+    /// The last value given for the field `name`.
+    pub fn field(&self, name: &str) -> Option<&Value> {
+        find_field(&self.fields, name)
+    }
+}
+
+fn find_field<'a>(fields: &'a [(String, Value)], name: &str) -> Option<&'a Value> {
+    fields
+        .iter()
+        .rev()
+        .find(|(field, _)| field == name)
+        .map(|(_, value)| value)
+}
+
+/// Builds an integrator from its form, failing when the fields don't make sense.
+pub type IntegratorFactory =
+    dyn Fn(IntegratorArgs) -> Result<Box<dyn IntegratorBuilder>> + Send + Sync;
+
+/// Builds a primitive from its form, failing when the values don't make sense.
+pub type PrimitiveFactory = dyn Fn(PrimitiveArgs) -> Result<Box<dyn Sdf>> + Send + Sync;
+
+/// The forms that a scene description may use beyond the built in ones.
+#[derive(Clone, Default)]
+pub struct Plugins {
+    integrators: HashMap<String, Arc<IntegratorFactory>>,
+    primitives: HashMap<String, Arc<PrimitiveFactory>>,
+}
+
+impl Plugins {
+    /// Accept `(name ...)` wherever an integrator is expected, built by `factory`. The built in
+    /// integrators take precedence over one with the same name.
+    pub fn with_integrator(
+        mut self,
+        name: impl Into<String>,
+        factory: impl Fn(IntegratorArgs) -> Result<Box<dyn IntegratorBuilder>> + Send + Sync + 'static,
+    ) -> Self {
+        self.integrators.insert(name.into(), Arc::new(factory));
+        self
+    }
+
+    /// Accept `(name ...)` as a node, built by `factory`. The built in nodes take precedence over
+    /// one with the same name.
+    pub fn with_primitive(
+        mut self,
+        name: impl Into<String>,
+        factory: impl Fn(PrimitiveArgs) -> Result<Box<dyn Sdf>> + Send + Sync + 'static,
+    ) -> Self {
+        self.primitives.insert(name.into(), Arc::new(factory));
+        self
+    }
+
+    pub(super) fn integrator(&self, name: &str) -> Option<Arc<IntegratorFactory>> {
+        self.integrators.get(name).cloned()
+    }
+
+    pub(super) fn primitive(&self, name: &str) -> Option<Arc<PrimitiveFactory>> {
+        self.primitives.get(name).cloned()
+    }
+}
@@ -0,0 +1,189 @@
+use anyhow::bail;
+use std::fmt::Write;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+type Result<T> = std::result::Result<T, anyhow::Error>;
+
+/// The path of a `file` target, with `{variable}` placeholders that are filled in when the render
+/// runs. Literal braces are written as `{{` and `}}`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PathTemplate {
+    root: PathBuf,
+    parts: Vec<Part>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Part {
+    Text(String),
+    Name,
+    Frame,
+    Width,
+    Height,
+    Date,
+}
+
+/// The values substituted into a [`PathTemplate`].
+pub struct PathVars<'a> {
+    pub name: &'a str,
+    pub frame: u32,
+    pub width: u32,
+    pub height: u32,
+    pub date: &'a str,
+}
+
+impl PathTemplate {
+    pub fn parse(template: &str) -> Result<Self> {
+        let mut parts = Vec::new();
+        let mut text = String::new();
+        let mut chars = template.chars();
+
+        while let Some(c) = chars.next() {
+            match c {
+                '{' if chars.as_str().starts_with('{') => {
+                    chars.next();
+                    text.push('{');
+                }
+
+                '}' if chars.as_str().starts_with('}') => {
+                    chars.next();
+                    text.push('}');
+                }
+
+                '{' => {
+                    let Some((var, rest)) = chars.as_str().split_once('}') else {
+                        bail!("Unclosed `{{` in path `{}`", template);
+                    };
+
+                    let part = match var {
+                        "name" => Part::Name,
+                        "frame" => Part::Frame,
+                        "width" => Part::Width,
+                        "height" => Part::Height,
+                        "date" => Part::Date,
+                        var => bail!("Unknown path variable: `{{{}}}`", var),
+                    };
+
+                    if !text.is_empty() {
+                        parts.push(Part::Text(std::mem::take(&mut text)));
+                    }
+                    parts.push(part);
+                    chars = rest.chars();
+                }
+
+                '}' => bail!("Unmatched `}}` in path `{}`", template),
+
+                c => text.push(c),
+            }
+        }
+
+        if !text.is_empty() {
+            parts.push(Part::Text(text));
+        }
+
+        Ok(Self {
+            root: PathBuf::new(),
+            parts,
+        })
+    }
+
+    /// Place the output under `root`. Relative paths keep their directories, while absolute paths
+    /// only keep their file name.
+    pub fn with_root(mut self, root: &Path) -> Self {
+        self.root = root.to_path_buf();
+        self
+    }
+
+    /// True when the template refers to the name of the render.
+    pub fn uses_name(&self) -> bool {
+        self.parts.contains(&Part::Name)
+    }
+
+    pub fn expand(&self, vars: &PathVars) -> PathBuf {
+        let mut path = String::new();
+        for part in &self.parts {
+            match part {
+                Part::Text(text) => path.push_str(text),
+                Part::Name => path.push_str(vars.name),
+                Part::Frame => write!(path, "{:04}", vars.frame).unwrap(),
+                Part::Width => write!(path, "{}", vars.width).unwrap(),
+                Part::Height => write!(path, "{}", vars.height).unwrap(),
+                Part::Date => path.push_str(vars.date),
+            }
+        }
+
+        let path = PathBuf::from(path);
+        if self.root.as_os_str().is_empty() {
+            path
+        } else if path.is_absolute() {
+            self.root.join(path.file_name().unwrap_or_default())
+        } else {
+            self.root.join(path)
+        }
+    }
+}
+
+/// Today's date in UTC, formatted as `YYYY-MM-DD`.
+pub fn today() -> String {
+    let secs = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_secs());
+    format_date((secs / 86400) as i64)
+}
+
+/// Format the date that's `days` after 1970-01-01, using Howard Hinnant's `civil_from_days`.
+fn format_date(days: i64) -> String {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!("{:04}-{:02}-{:02}", year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_expand() {
+        let vars = PathVars {
+            name: "hero",
+            frame: 7,
+            width: 640,
+            height: 480,
+            date: "2024-02-29",
+        };
+        let expand = |template: &str| PathTemplate::parse(template).unwrap().expand(&vars);
+
+        assert_eq!(PathBuf::from("out.png"), expand("out.png"));
+        assert_eq!(
+            PathBuf::from("2024-02-29/hero-0007-640x480.png"),
+            expand("{date}/{name}-{frame}-{width}x{height}.png")
+        );
+        assert_eq!(PathBuf::from("{hero}.png"), expand("{{{name}}}.png"));
+
+        let template = PathTemplate::parse("shots/{name}.png").unwrap();
+        assert!(template.uses_name());
+        assert_eq!(
+            PathBuf::from("out/shots/hero.png"),
+            template.with_root(Path::new("out")).expand(&vars)
+        );
+
+        for template in ["{name", "name}.png", "{scene}.png", "{}.png"] {
+            assert!(PathTemplate::parse(template).is_err(), "{}", template);
+        }
+    }
+
+    #[test]
+    fn test_format_date() {
+        assert_eq!("1970-01-01", format_date(0));
+        assert_eq!("1969-12-31", format_date(-1));
+        assert_eq!("2000-03-01", format_date(11017));
+        assert_eq!("2024-02-29", format_date(19782));
+    }
+}
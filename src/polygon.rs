@@ -0,0 +1,190 @@
+use nalgebra::{Point2, Vector2};
+
+use crate::math::Float;
+
+/// A shape in the xy plane, bounded by closed contours of straight edges. A point is inside when
+/// the contours wind around it a nonzero number of times, so holes are contours that run the
+/// other way around from the ones that surround them, as in TrueType fonts.
+#[derive(Debug, Clone)]
+pub struct Polygon {
+    contours: Vec<Vec<Point2<Float>>>,
+    min: Point2<Float>,
+    max: Point2<Float>,
+}
+
+impl Polygon {
+    /// A polygon from its contours, each of which is closed by an edge from its last point back to
+    /// its first. Contours of fewer than three points enclose nothing, and are dropped.
+    pub fn new(contours: Vec<Vec<Point2<Float>>>) -> Self {
+        let contours: Vec<_> = contours
+            .into_iter()
+            .filter(|contour| contour.len() >= 3)
+            .collect();
+
+        let mut min = Point2::new(Float::INFINITY, Float::INFINITY);
+        let mut max = Point2::new(Float::NEG_INFINITY, Float::NEG_INFINITY);
+        for p in contours.iter().flatten() {
+            min = min.inf(p);
+            max = max.sup(p);
+        }
+
+        Self { contours, min, max }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.contours.is_empty()
+    }
+
+    /// The corners of the smallest rectangle containing the polygon.
+    pub fn bounds(&self) -> (Point2<Float>, Point2<Float>) {
+        (self.min, self.max)
+    }
+
+    fn edges(&self) -> impl Iterator<Item = (&Point2<Float>, &Point2<Float>)> {
+        self.contours.iter().flat_map(|contour| {
+            contour
+                .iter()
+                .zip(contour.iter().skip(1).chain(contour.first()))
+        })
+    }
+
+    /// The distance from `p` to the closest edge, which is negative inside the polygon.
+    pub fn sdf(&self, p: &Point2<Float>) -> Float {
+        let mut closest = Float::INFINITY;
+        let mut winding = 0;
+        for (a, b) in self.edges() {
+            let ab = b - a;
+            let ap = p - a;
+            let t = (ap.dot(&ab) / ab.norm_squared()).clamp(0., 1.);
+            let t = if t.is_nan() { 0. } else { t };
+            closest = closest.min((ap - ab * t).norm_squared());
+
+            // Count the edges crossing a ray from `p` along +x, by the direction they cross it.
+            let side = perp(&ab, &ap);
+            if a.y <= p.y && b.y > p.y && side > 0. {
+                winding += 1;
+            } else if b.y <= p.y && a.y > p.y && side < 0. {
+                winding -= 1;
+            }
+        }
+
+        let distance = closest.sqrt();
+        if winding != 0 {
+            -distance
+        } else {
+            distance
+        }
+    }
+}
+
+/// A two dimensional shape that's turned into a solid by extruding or revolving it.
+#[derive(Debug, Clone)]
+pub enum Profile {
+    Polygon(Polygon),
+
+    /// A circle centered on the origin.
+    Circle {
+        radius: Float,
+    },
+
+    /// A rectangle centered on the origin, with the given half width and height.
+    Rect {
+        width: Float,
+        height: Float,
+    },
+}
+
+impl Profile {
+    /// The corners of the smallest rectangle containing the profile.
+    pub fn bounds(&self) -> (Point2<Float>, Point2<Float>) {
+        match self {
+            Profile::Polygon(polygon) => polygon.bounds(),
+            &Profile::Circle { radius } => {
+                (Point2::new(-radius, -radius), Point2::new(radius, radius))
+            }
+            &Profile::Rect { width, height } => {
+                (Point2::new(-width, -height), Point2::new(width, height))
+            }
+        }
+    }
+
+    /// The distance from the origin to the furthest point of the profile.
+    pub fn radius(&self) -> Float {
+        match self {
+            Profile::Polygon(polygon) => {
+                let (min, max) = polygon.bounds();
+                min.coords.abs().sup(&max.coords.abs()).norm()
+            }
+            &Profile::Circle { radius } => radius,
+            &Profile::Rect { width, height } => Float::hypot(width, height),
+        }
+    }
+
+    /// The distance from `p` to the edge of the profile, which is negative inside it.
+    pub fn sdf(&self, p: &Point2<Float>) -> Float {
+        match self {
+            Profile::Polygon(polygon) => polygon.sdf(p),
+            &Profile::Circle { radius } => p.coords.norm() - radius,
+            &Profile::Rect { width, height } => {
+                let d = p.coords.abs() - Vector2::new(width, height);
+                d.max().min(0.) + d.map(|c| c.max(0.)).norm()
+            }
+        }
+    }
+}
+
+/// The z component of the cross product of two vectors in the plane.
+fn perp(a: &Vector2<Float>, b: &Vector2<Float>) -> Float {
+    a.x * b.y - a.y * b.x
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn square(half: Float, clockwise: bool) -> Vec<Point2<Float>> {
+        let mut points = vec![
+            Point2::new(-half, -half),
+            Point2::new(half, -half),
+            Point2::new(half, half),
+            Point2::new(-half, half),
+        ];
+        if clockwise {
+            points.reverse();
+        }
+        points
+    }
+
+    #[test]
+    fn test_polygon_sdf() {
+        // Both orientations of a single contour have the same inside.
+        for clockwise in [false, true] {
+            let polygon = Polygon::new(vec![square(1., clockwise)]);
+            assert_eq!(-1., polygon.sdf(&Point2::new(0., 0.)));
+            assert_eq!(-0.5, polygon.sdf(&Point2::new(0.5, 0.)));
+            assert_eq!(1., polygon.sdf(&Point2::new(2., 0.)));
+            assert_eq!(Float::sqrt(2.), polygon.sdf(&Point2::new(2., 2.)));
+        }
+
+        // A contour running the other way cuts a hole.
+        let ring = Polygon::new(vec![square(2., false), square(1., true)]);
+        assert_eq!(1., ring.sdf(&Point2::new(0., 0.)));
+        assert_eq!(-0.5, ring.sdf(&Point2::new(1.5, 0.)));
+        assert_eq!((Point2::new(-2., -2.), Point2::new(2., 2.)), ring.bounds());
+
+        let rect = Profile::Rect {
+            width: 2.,
+            height: 1.,
+        };
+        assert_eq!(-1., rect.sdf(&Point2::new(0., 0.)));
+        assert_eq!(1., rect.sdf(&Point2::new(3., 0.)));
+        assert_eq!(Float::sqrt(2.), rect.sdf(&Point2::new(3., 2.)));
+        assert_eq!(
+            0.5,
+            Profile::Circle { radius: 1. }.sdf(&Point2::new(0., 1.5))
+        );
+
+        let degenerate = Polygon::new(vec![vec![Point2::new(0., 0.), Point2::new(1., 0.)]]);
+        assert!(degenerate.is_empty());
+    }
+}
@@ -0,0 +1,437 @@
+//! Rendering across machines. A coordinator listens for workers, which connect over TCP and render
+//! tiles of its cpu renders alongside its own threads. Workers are sent the scene description, with
+//! its `if-flag` and `if-env` conditionals already resolved, and parse it themselves, so after that
+//! only the bounds of tiles and their pixels are exchanged.
+
+use anyhow::{bail, Error};
+use std::collections::HashSet;
+use std::io::{ErrorKind, Read, Write};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::Duration;
+
+use crate::{
+    canvas::{Canvas, Color},
+    integrator::{self, Integrator, Tile, TileWorker},
+    math::Float,
+    parser::{self, Overrides},
+    sampler::Sampler,
+    scene::{NodeId, Scene, STACK_SIZE},
+};
+
+type Result<T> = std::result::Result<T, Error>;
+
+/// Starts a render on a worker, by sending it a [`Job`].
+const JOB: u8 = b'J';
+
+/// Asks a worker for the pixels of a tile of the current job.
+const TILE: u8 = b'T';
+
+/// How long a worker waits before trying to connect to the coordinator again.
+const RECONNECT: Duration = Duration::from_secs(1);
+
+/// How long the coordinator waits for a new connection to say which worker it's from.
+const HANDSHAKE: Duration = Duration::from_secs(5);
+
+/// How long the coordinator waits on a worker to accept a message or send a tile back, before
+/// dropping it and rendering the tile itself.
+const TIMEOUT: Duration = Duration::from_secs(120);
+
+/// The longest scene description that a worker accepts in a job, in bytes.
+const MAX_SOURCE: usize = 8 << 20;
+
+/// The longest render name that a worker accepts in a job, in bytes.
+const MAX_NAME: usize = 1024;
+
+/// The most render names that a worker accepts in the `only` or `skip` lists of a job.
+const MAX_NAMES: u32 = 1024;
+
+/// A single render from a scene description, which a worker can reproduce by parsing the same
+/// description. The description shouldn't depend on the flags or environment it's parsed with.
+pub struct Job<'a> {
+    pub source: &'a str,
+    pub overrides: &'a Overrides,
+
+    /// The index of the render, in those that the description produces.
+    pub index: usize,
+}
+
+/// Accepts connections from workers in the background, and hands out the workers that are
+/// connected to each render.
+pub struct Coordinator {
+    idle: Arc<(Mutex<Vec<Connection>>, Condvar)>,
+}
+
+/// A connection from a worker, which may be one of several from the same worker process.
+struct Connection {
+    /// Identifies the worker process that opened the connection.
+    worker: u64,
+    stream: TcpStream,
+}
+
+impl Connection {
+    /// Read which worker a new connection is from, and limit how long it may block the
+    /// coordinator from then on.
+    fn accept(mut stream: TcpStream) -> Result<Self> {
+        stream.set_nodelay(true)?;
+        stream.set_read_timeout(Some(HANDSHAKE))?;
+        let worker = read_u64(&mut stream)?;
+        stream.set_read_timeout(Some(TIMEOUT))?;
+        stream.set_write_timeout(Some(TIMEOUT))?;
+        tracing::debug!("worker connected from {}", stream.peer_addr()?);
+        Ok(Self { worker, stream })
+    }
+}
+
+impl Coordinator {
+    pub fn listen(addr: impl ToSocketAddrs) -> Result<Self> {
+        let listener = TcpListener::bind(addr)?;
+        tracing::info!("listening for workers on {}", listener.local_addr()?);
+
+        let idle = Arc::new((Mutex::new(Vec::new()), Condvar::new()));
+        {
+            let idle = idle.clone();
+            std::thread::spawn(move || {
+                for stream in listener.incoming() {
+                    let stream = match stream {
+                        Ok(stream) => stream,
+                        Err(err) => {
+                            tracing::warn!("failed to accept a worker: {}", err);
+                            continue;
+                        }
+                    };
+
+                    // A connection that's slow to identify itself doesn't hold up the others.
+                    let idle = idle.clone();
+                    std::thread::spawn(move || match Connection::accept(stream) {
+                        Ok(conn) => {
+                            idle.0.lock().unwrap().push(conn);
+                            idle.1.notify_all();
+                        }
+                        Err(err) => tracing::warn!("failed to accept a worker: {}", err),
+                    });
+                }
+            });
+        }
+
+        Ok(Self { idle })
+    }
+
+    /// Block until at least `workers` worker processes are connected.
+    pub fn wait_for(&self, workers: usize) {
+        tracing::info!("waiting for {} workers", workers);
+        let (idle, connected) = &*self.idle;
+        let mut idle = idle.lock().unwrap();
+        loop {
+            let count = idle
+                .iter()
+                .map(|conn| conn.worker)
+                .collect::<HashSet<_>>()
+                .len();
+            if count >= workers {
+                break;
+            }
+            idle = connected.wait(idle).unwrap();
+        }
+    }
+
+    /// Start `job` on all of the connected workers. Workers are returned to the coordinator once
+    /// the render is done with them, unless they fail. Scenes too large for the workers to accept
+    /// are rendered locally.
+    pub fn workers(&self, job: &Job) -> Vec<Box<dyn TileWorker + '_>> {
+        if job.source.len() > MAX_SOURCE {
+            tracing::warn!(
+                "not sharing render {}, as its scene is larger than {} bytes",
+                job.index,
+                MAX_SOURCE
+            );
+            return Vec::new();
+        }
+
+        let mut msg = vec![JOB];
+        write_str(&mut msg, job.source);
+        write_f64(&mut msg, job.overrides.scale.map_or(0., f64::from));
+        write_u32(&mut msg, job.overrides.samples.unwrap_or(0));
+        for names in [&job.overrides.only, &job.overrides.skip] {
+            write_u32(&mut msg, names.len() as u32);
+            for name in names {
+                write_str(&mut msg, name);
+            }
+        }
+        write_u32(&mut msg, job.index as u32);
+
+        let conns = std::mem::take(&mut *self.idle.0.lock().unwrap());
+        conns
+            .into_iter()
+            .filter_map(|mut conn| match conn.stream.write_all(&msg) {
+                Ok(()) => Some(Box::new(RemoteWorker {
+                    coordinator: self,
+                    conn: Some(conn),
+                }) as Box<dyn TileWorker>),
+                Err(err) => {
+                    tracing::warn!("dropping worker: {}", err);
+                    None
+                }
+            })
+            .collect()
+    }
+}
+
+/// A worker connected to the [`Coordinator`], that's been sent a job.
+struct RemoteWorker<'a> {
+    coordinator: &'a Coordinator,
+
+    /// The connection to the worker, which is dropped if it fails.
+    conn: Option<Connection>,
+}
+
+impl RemoteWorker<'_> {
+    fn request(stream: &mut TcpStream, tile: &Tile) -> Result<Canvas> {
+        let mut msg = vec![TILE];
+        for val in [
+            tile.offset_x as u32,
+            tile.offset_y as u32,
+            tile.width,
+            tile.height,
+        ] {
+            write_u32(&mut msg, val);
+        }
+        stream.write_all(&msg)?;
+
+        // Each pixel is sent as its color followed by its coverage.
+        let mut chunk = Canvas::new(tile.width, tile.height);
+        let (pixels, alpha) = chunk.pixels_and_alpha_mut();
+        let mut buf = vec![0; pixels.len() * 16];
+        stream.read_exact(&mut buf)?;
+        for ((pixel, alpha), bytes) in pixels.iter_mut().zip(alpha).zip(buf.chunks(16)) {
+            let channel = |i: usize| f32::from_le_bytes(bytes[i..i + 4].try_into().unwrap());
+            *pixel = Color::new(channel(0), channel(4), channel(8));
+            *alpha = channel(12);
+        }
+
+        Ok(chunk)
+    }
+}
+
+impl TileWorker for RemoteWorker<'_> {
+    fn render_tile(&mut self, tile: &Tile) -> Option<Canvas> {
+        let conn = self.conn.as_mut()?;
+        match Self::request(&mut conn.stream, tile) {
+            Ok(chunk) => Some(chunk),
+            Err(err) => {
+                tracing::warn!("dropping worker: {}", err);
+                self.conn = None;
+                None
+            }
+        }
+    }
+}
+
+impl Drop for RemoteWorker<'_> {
+    fn drop(&mut self) {
+        if let Some(conn) = self.conn.take() {
+            self.coordinator.idle.0.lock().unwrap().push(conn);
+        }
+    }
+}
+
+/// Render tiles for the coordinator at `addr`, over `threads` connections that each render one
+/// tile at a time. Connections are re-opened when they close, so workers may be started before
+/// the coordinator and keep running across its renders. This only returns if `addr` is invalid.
+pub fn work(addr: &str, threads: usize) -> Result<()> {
+    if addr.to_socket_addrs()?.next().is_none() {
+        bail!("`{}` doesn't resolve to an address", addr);
+    }
+
+    // Sent on each connection, so that the coordinator can tell which come from the same process.
+    let worker: u64 = rand::random();
+
+    std::thread::scope(|s| -> Result<()> {
+        for _ in 0..threads {
+            let connection = std::thread::Builder::new().stack_size(STACK_SIZE);
+            connection.spawn_scoped(s, || loop {
+                match TcpStream::connect(addr) {
+                    Ok(stream) => {
+                        tracing::info!("connected to {}", addr);
+                        match serve(stream, worker) {
+                            Ok(()) => tracing::info!("disconnected from {}", addr),
+                            Err(err) => tracing::warn!("error: {}", err),
+                        }
+                    }
+                    Err(err) => tracing::debug!("failed to connect to {}: {}", addr, err),
+                }
+                std::thread::sleep(RECONNECT);
+            })?;
+        }
+        Ok(())
+    })
+}
+
+/// The render that a worker is currently producing tiles for.
+struct Prepared {
+    scene: Scene,
+    root: NodeId,
+    sampler: Box<dyn Sampler>,
+    integrator: Box<dyn Integrator>,
+}
+
+/// Answer the coordinator's messages until it closes the connection.
+fn serve(mut stream: TcpStream, worker: u64) -> Result<()> {
+    stream.set_nodelay(true)?;
+    stream.write_all(&worker.to_le_bytes())?;
+
+    let mut job: Option<Prepared> = None;
+    let mut samples = Vec::new();
+    loop {
+        let mut tag = [0];
+        match stream.read_exact(&mut tag) {
+            Err(err) if err.kind() == ErrorKind::UnexpectedEof => return Ok(()),
+            res => res?,
+        }
+
+        match tag[0] {
+            JOB => job = Some(prepare(&mut stream)?),
+
+            TILE => {
+                let Some(job) = job.as_mut() else {
+                    bail!("The coordinator asked for a tile before sending a job");
+                };
+
+                let tile = Tile {
+                    offset_x: read_u32(&mut stream)? as Float,
+                    offset_y: read_u32(&mut stream)? as Float,
+                    width: read_u32(&mut stream)?,
+                    height: read_u32(&mut stream)?,
+                };
+                // Closing the connection on a panic leaves the tile to the coordinator, which
+                // renders it itself and reports the failure.
+                let mut chunk = integrator::try_render_tile(
+                    &job.scene,
+                    job.root,
+                    &mut job.sampler,
+                    &mut job.integrator,
+                    &mut samples,
+                    &tile,
+                )?;
+
+                let (pixels, alpha) = chunk.pixels_and_alpha_mut();
+                let mut msg = Vec::with_capacity(pixels.len() * 16);
+                for (pixel, alpha) in pixels.iter().zip(alpha.iter()) {
+                    for channel in [pixel.r, pixel.g, pixel.b, *alpha] {
+                        msg.extend_from_slice(&channel.to_le_bytes());
+                    }
+                }
+                stream.write_all(&msg)?;
+            }
+
+            tag => bail!("Unknown message from the coordinator: {}", tag),
+        }
+    }
+}
+
+/// Read a [`Job`] from the coordinator, and parse the scene description it names.
+fn prepare(stream: &mut TcpStream) -> Result<Prepared> {
+    let source = read_str(stream, MAX_SOURCE)?;
+    let scale = read_f64(stream)?;
+    let samples = read_u32(stream)?;
+    let mut names = [Vec::new(), Vec::new()];
+    for names in names.iter_mut() {
+        let count = read_u32(stream)?;
+        if count > MAX_NAMES {
+            bail!(
+                "The coordinator sent {} render names, more than the {} allowed",
+                count,
+                MAX_NAMES
+            );
+        }
+        for _ in 0..count {
+            names.push(read_str(stream, MAX_NAME)?);
+        }
+    }
+    let [only, skip] = names;
+    let index = read_u32(stream)? as usize;
+
+    let overrides = Overrides {
+        scale: (scale > 0.).then_some(scale as Float),
+        samples: (samples > 0).then_some(samples),
+        only,
+        skip,
+        ..Overrides::default()
+    };
+    let (scene, mut renders) = parser::parse_with(&source, &[], &overrides)?;
+    if index >= renders.len() {
+        bail!("The scene has no render {}", index);
+    }
+
+    let render = renders.swap_remove(index);
+    tracing::info!("rendering tiles of render {}", index);
+    Ok(Prepared {
+        scene,
+        root: render.root,
+        sampler: render.sampler,
+        integrator: render.builder.build(),
+    })
+}
+
+fn write_u32(buf: &mut Vec<u8>, val: u32) {
+    buf.extend_from_slice(&val.to_le_bytes());
+}
+
+fn write_f64(buf: &mut Vec<u8>, val: f64) {
+    buf.extend_from_slice(&val.to_le_bytes());
+}
+
+fn write_str(buf: &mut Vec<u8>, val: &str) {
+    write_u32(buf, val.len() as u32);
+    buf.extend_from_slice(val.as_bytes());
+}
+
+fn read_u32(stream: &mut impl Read) -> Result<u32> {
+    let mut buf = [0; 4];
+    stream.read_exact(&mut buf)?;
+    Ok(u32::from_le_bytes(buf))
+}
+
+fn read_u64(stream: &mut impl Read) -> Result<u64> {
+    let mut buf = [0; 8];
+    stream.read_exact(&mut buf)?;
+    Ok(u64::from_le_bytes(buf))
+}
+
+fn read_f64(stream: &mut impl Read) -> Result<f64> {
+    let mut buf = [0; 8];
+    stream.read_exact(&mut buf)?;
+    Ok(f64::from_le_bytes(buf))
+}
+
+/// Read a string of at most `max` bytes, so that a bad length can't exhaust memory.
+fn read_str(stream: &mut impl Read, max: usize) -> Result<String> {
+    let len = read_u32(stream)? as usize;
+    if len > max {
+        bail!(
+            "The coordinator sent a string of {} bytes, more than the {} allowed",
+            len,
+            max
+        );
+    }
+    let mut buf = vec![0; len];
+    stream.read_exact(&mut buf)?;
+    Ok(String::from_utf8(buf)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_read_str() {
+        let mut buf = Vec::new();
+        write_str(&mut buf, "scene");
+        assert_eq!("scene", read_str(&mut buf.as_slice(), 5).unwrap());
+        assert!(read_str(&mut buf.as_slice(), 4).is_err());
+
+        // The length is checked before anything is allocated for it.
+        let huge = u32::MAX.to_le_bytes();
+        assert!(read_str(&mut huge.as_slice(), MAX_SOURCE).is_err());
+    }
+}
@@ -1,40 +1,745 @@
-use anyhow::Error;
+use anyhow::{anyhow, Context, Error};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::VecDeque;
+use std::hash::{Hash, Hasher};
+use std::panic::AssertUnwindSafe;
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
-use crate::{integrator, parser};
+use crate::{
+    canvas::{Canvas, Histogram},
+    film::ExrFilm,
+    gpu, integrator, parser, remote,
+    scene::{FiniteCheck, Scene, STACK_SIZE},
+};
 
+#[derive(Clone)]
 pub enum Output {
-    File { path: PathBuf },
-    Ascii { name: String, chars: String },
+    File {
+        path: PathBuf,
+
+        /// Where the preview was written, when the render asks for one.
+        preview: Option<PathBuf>,
+    },
+
+    /// The image for a file target, when [`Settings::in_memory`] is set.
+    Image {
+        path: PathBuf,
+        image: image::DynamicImage,
+        preview: Option<(PathBuf, image::DynamicImage)>,
+    },
+
+    Ascii {
+        name: String,
+        chars: String,
+    },
+}
+
+impl Output {
+    /// True when the files that the output names are still there.
+    fn is_available(&self) -> bool {
+        match self {
+            Output::File { path, preview } => {
+                path.exists() && preview.as_ref().is_none_or(|preview| preview.exists())
+            }
+            Output::Image { .. } | Output::Ascii { .. } => true,
+        }
+    }
+}
+
+/// Receives the luminance histogram and status of a render target, identified by its output name,
+/// each time some of its pixels are completed. This is called from the rendering threads.
+pub type Progress = dyn Fn(&str, &Histogram, &Status) + Send + Sync;
+
+/// How far a render target has got.
+#[derive(Debug, Clone)]
+pub struct Status {
+    /// The number of tiles completed, out of `total`.
+    pub tiles: u64,
+    pub total: u64,
+
+    /// The number of camera rays traced so far.
+    pub rays: u64,
+
+    /// The time since the render started.
+    pub elapsed: Duration,
+}
+
+impl Status {
+    pub fn rays_per_sec(&self) -> f64 {
+        self.rays as f64 / self.elapsed.as_secs_f64().max(0.001)
+    }
+
+    /// Estimate the time remaining, assuming the remaining tiles take as long as those completed.
+    pub fn eta(&self) -> Option<Duration> {
+        if self.tiles == 0 {
+            return None;
+        }
+
+        let remaining = self.total.saturating_sub(self.tiles) as f64;
+        Some(self.elapsed.mul_f64(remaining / self.tiles as f64))
+    }
+}
+
+/// The number of outputs that a [`RenderCache`] keeps by default.
+const CACHE_CAPACITY: usize = 32;
+
+/// The outputs of finished renders, so that a scene description that's rendered again after an
+/// edit only repeats the renders that the edit could have changed. Renders are matched by their
+/// [`parser::Render::fingerprint`] along with the flags and overrides they were rendered with.
+pub struct RenderCache {
+    capacity: usize,
+
+    /// The least recently used outputs come first.
+    entries: Mutex<VecDeque<(u64, Arc<Cached>)>>,
+}
+
+/// A finished render, with the last progress reported for it.
+struct Cached {
+    output: Output,
+    histogram: Histogram,
+    status: Status,
+}
+
+impl Default for RenderCache {
+    fn default() -> Self {
+        Self::new(CACHE_CAPACITY)
+    }
+}
+
+impl RenderCache {
+    /// Construct a cache that keeps the outputs of the last `capacity` renders.
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            entries: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    fn get(&self, key: u64) -> Option<Arc<Cached>> {
+        let mut entries = self.entries.lock().unwrap();
+        let ix = entries.iter().position(|(k, _)| *k == key)?;
+        let entry = entries.remove(ix)?;
+        let cached = entry.1.clone();
+        entries.push_back(entry);
+        Some(cached)
+    }
+
+    fn insert(&self, key: u64, cached: Cached) {
+        let mut entries = self.entries.lock().unwrap();
+        entries.retain(|(k, _)| *k != key);
+        entries.push_back((key, Arc::new(cached)));
+        while entries.len() > self.capacity {
+            entries.pop_front();
+        }
+    }
+}
+
+/// Where rays are marched.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Backend {
+    /// Render with the integrator on the cpu, using a pool of threads.
+    Cpu,
+
+    /// Compile the scene to a compute shader, and render on the gpu.
+    Gpu,
+}
+
+/// How the targets of a scene are rendered.
+#[derive(Clone)]
+pub struct Settings {
+    pub threads: usize,
+    pub backend: Backend,
+
+    /// Fail a render that produces a non-finite distance, instead of reporting it as a warning.
+    pub strict: bool,
+
+    /// Remote workers that share the tiles of cpu renders with the local threads.
+    pub coordinator: Option<Arc<remote::Coordinator>>,
+
+    /// Return the images of file targets as [`Output::Image`], rather than writing them out.
+    pub in_memory: bool,
+
+    /// Run all of the cpu renders in a scene at once on the shared threads, rather than one after
+    /// another, which keeps the threads busy when the renders are small. Renders shared with
+    /// remote workers still run one at a time. Warnings about non-finite distances may be reported
+    /// against any of the renders running at the time.
+    pub concurrent: bool,
+
+    /// Reuse the outputs of renders that haven't changed since they were last rendered with this
+    /// cache. Files that have since been removed are rendered again.
+    pub cache: Option<Arc<RenderCache>>,
 }
 
-pub fn render_scene(threads: usize, scene: &Path) -> Result<impl Iterator<Item = Output>, Error> {
+/// Render all of the targets in a scene file, with `overrides` replacing the values it gives.
+pub fn render_scene(
+    settings: &Settings,
+    flags: &[String],
+    overrides: &parser::Overrides,
+    scene: &Path,
+    progress: Option<Arc<Progress>>,
+) -> Result<Outputs, Error> {
     let input = std::fs::read_to_string(scene)?;
-    let (scene, renders) = parser::parse(&input)?;
-
-    Ok(renders.into_iter().map(move |render| {
-        let canvas = integrator::render(
-            render.canvas_info.clone(),
-            &scene,
-            render.root,
-            render.sampler,
-            render.builder,
-            threads as usize,
+    render_source(settings, input, flags, overrides, progress)
+}
+
+/// Render all of the targets in a scene description.
+pub fn render_source(
+    settings: &Settings,
+    input: String,
+    flags: &[String],
+    overrides: &parser::Overrides,
+    progress: Option<Arc<Progress>>,
+) -> Result<Outputs, Error> {
+    let (mut scene, renders) = parser::parse_with(&input, flags, overrides)?;
+    let mut files = Vec::new();
+    for file in renders.iter().flat_map(|render| &render.files) {
+        if !files.contains(file) {
+            files.push(file.clone());
+        }
+    }
+    if settings.strict {
+        scene.finite_check = FiniteCheck::Strict;
+    }
+
+    // Compile all the renders for the gpu up front, so that unsupported scenes are reported before
+    // any rendering happens.
+    let mut pipelines = Vec::new();
+    if settings.backend == Backend::Gpu {
+        let context = Arc::new(gpu::Context::new()?);
+        for render in renders.iter() {
+            pipelines.push(context.prepare(&scene, render.root, &render.builder)?);
+        }
+    }
+
+    // All the renders in the scene share the same pool of threads.
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(settings.threads)
+        .stack_size(STACK_SIZE)
+        .build()?;
+
+    let input = match &settings.coordinator {
+        Some(_) => parser::expand(&input, flags)?,
+        None => input,
+    };
+    let shared = Arc::new(Shared {
+        scene,
+        input,
+        flags: flags.to_vec(),
+        overrides: overrides.clone(),
+        coordinator: settings.coordinator.clone(),
+        in_memory: settings.in_memory,
+        cache: settings.cache.clone(),
+        date: parser::today(),
+        pool,
+        progress,
+    });
+
+    // The gpu and remote workers take one render at a time, so only cpu renders run together.
+    if !settings.concurrent || !pipelines.is_empty() || shared.coordinator.is_some() {
+        let mut pipelines = pipelines.into_iter();
+        let outputs = renders
+            .into_iter()
+            .enumerate()
+            .map(move |(index, render)| render_target(&shared, index, render, pipelines.next()));
+        return Ok(Outputs {
+            files,
+            outputs: Box::new(outputs),
+        });
+    }
+
+    // Every render is started on the pool at once, and their tiles compete for its threads.
+    // Outputs are still given in the order of the renders.
+    let receivers: Vec<_> = renders
+        .into_iter()
+        .enumerate()
+        .map(|(index, render)| {
+            let label = match &render.name {
+                Some(name) => format!("Render `{}`", name),
+                None => format!("Render {}", index + 1),
+            };
+            let (send, receive) = crossbeam::channel::bounded(1);
+            let task = shared.clone();
+            shared.pool.spawn(move || {
+                let output = std::panic::catch_unwind(AssertUnwindSafe(|| {
+                    render_target(&task, index, render, None)
+                }));
+                let _ = send.send(output);
+            });
+            (label, receive)
+        })
+        .collect();
+
+    // A render that panics outside of its tiles is reported as an error, so that the others can
+    // still finish.
+    let outputs = receivers
+        .into_iter()
+        .map(|(label, receive)| match receive.recv() {
+            Ok(Ok(output)) => output,
+            Ok(Err(panic)) => Err(anyhow!(
+                "{} panicked: {}",
+                label,
+                integrator::panic_message(panic.as_ref())
+            )),
+            Err(_) => Err(anyhow!("{} stopped without an output", label)),
+        });
+    Ok(Outputs {
+        files,
+        outputs: Box::new(outputs),
+    })
+}
+
+/// The outputs of the renders in a scene description, in the order of the renders.
+pub struct Outputs {
+    /// The files that the renders read, other than the scene description itself.
+    pub files: Vec<PathBuf>,
+
+    outputs: Box<dyn Iterator<Item = Result<Output, Error>> + Send>,
+}
+
+impl Iterator for Outputs {
+    type Item = Result<Output, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.outputs.next()
+    }
+}
+
+/// Everything that the renders of a scene description share.
+struct Shared {
+    scene: Scene,
+
+    /// Workers parse the scene themselves, from the description with its conditionals resolved
+    /// here, so that the flags and environment of the workers don't matter.
+    input: String,
+    flags: Vec<String>,
+    overrides: parser::Overrides,
+    coordinator: Option<Arc<remote::Coordinator>>,
+
+    in_memory: bool,
+    cache: Option<Arc<RenderCache>>,
+
+    /// All the renders in the scene share the same date, even if they finish on different days.
+    date: String,
+
+    pool: rayon::ThreadPool,
+    progress: Option<Arc<Progress>>,
+}
+
+/// Render the target at `index` in the scene description, on the gpu when it has a `pipeline`.
+fn render_target(
+    shared: &Shared,
+    index: usize,
+    render: parser::Render,
+    pipeline: Option<gpu::Pipeline>,
+) -> Result<Output, Error> {
+    let scene = &shared.scene;
+    let info = &render.canvas_info;
+
+    // File targets are identified by the name of the file that their path expands to.
+    let (name, path) = match &render.target {
+        parser::Target::File { path, .. } => {
+            let path = path.expand(&parser::PathVars {
+                name: render.name.as_deref().unwrap_or_default(),
+                frame: 0,
+                width: info.width,
+                height: info.height,
+                date: &shared.date,
+            });
+            let name = path
+                .file_name()
+                .and_then(|os| os.to_str())
+                .unwrap_or_default()
+                .to_string();
+            (name, Some(path))
+        }
+        parser::Target::Ascii { name, .. } => (name.clone(), None),
+    };
+    let _span = tracing::info_span!("render", output = %name).entered();
+
+    // Everything else that the output depends on is covered by the render's fingerprint.
+    let key = {
+        let mut hasher = DefaultHasher::new();
+        render.fingerprint.hash(&mut hasher);
+        shared.flags.hash(&mut hasher);
+        format!("{:?}", shared.overrides).hash(&mut hasher);
+        (shared.in_memory, pipeline.is_some(), &shared.date).hash(&mut hasher);
+        hasher.finish()
+    };
+    let cached = shared.cache.as_ref().and_then(|cache| cache.get(key));
+    if let Some(cached) = cached.filter(|cached| cached.output.is_available()) {
+        tracing::info!("`{}` is unchanged, so its last render is reused", name);
+        if let Some(progress) = &shared.progress {
+            progress(&name, &cached.histogram, &cached.status);
+        }
+        return Ok(cached.output.clone());
+    }
+
+    let histogram = Histogram::new(info.width as u64 * info.height as u64);
+    let start = Instant::now();
+    let total = integrator::tile_count(info);
+    let samples_per_pixel = render.sampler.samples_per_pixel() as u64;
+    let (tiles_done, rays_done) = (AtomicU64::new(0), AtomicU64::new(0));
+    let on_tiles = |chunk: &Canvas, tiles: u64| {
+        if let Some(progress) = &shared.progress {
+            histogram.add(chunk);
+            let pixels = chunk.width() as u64 * chunk.height() as u64;
+            let status = Status {
+                tiles: tiles_done.fetch_add(tiles, Ordering::Relaxed) + tiles,
+                total,
+                rays: rays_done.fetch_add(pixels * samples_per_pixel, Ordering::Relaxed)
+                    + pixels * samples_per_pixel,
+                elapsed: start.elapsed(),
+            };
+            progress(&name, &histogram, &status);
+        }
+    };
+    let on_tile = |tile: &Canvas| on_tiles(tile, 1);
+
+    // Workers resolve relative paths against their own directory, so renders that read files
+    // through them stay on the local threads.
+    let relative = render.files.iter().any(|file| file.is_relative());
+    if shared.coordinator.is_some() && relative {
+        tracing::warn!(
+            "`{}` reads files by relative paths, so it isn't shared with workers",
+            name
         );
-        let width = canvas.width();
-        let height = canvas.height();
-
-        match render.target {
-            parser::Target::File { path } => {
-                image::save_buffer(&path, &canvas.data(), width, height, image::ColorType::Rgb8)
-                    .unwrap();
-                Output::File { path }
+    }
+    let workers = || match &shared.coordinator {
+        Some(coordinator) if !relative => integrator::Workers {
+            pool: &shared.pool,
+            remote: coordinator.workers(&remote::Job {
+                source: &shared.input,
+                overrides: &shared.overrides,
+                index,
+            }),
+        },
+        _ => integrator::Workers::local(&shared.pool),
+    };
+
+    // Streamed files are written a tile at a time, unless the image is wanted in memory anyway.
+    let film = match (&render.target, &path) {
+        (
+            parser::Target::File {
+                channels,
+                stream: true,
+                ..
+            },
+            Some(path),
+        ) if !shared.in_memory => {
+            if let Some(dir) = path.parent() {
+                std::fs::create_dir_all(dir)?;
             }
+            Some(ExrFilm::create(path, info.width, info.height, *channels)?)
+        }
+        _ => None,
+    };
+
+    let canvas = match (pipeline, &film) {
+        (Some(pipeline), _) => {
+            let canvas = pipeline
+                .render(&render.canvas_info, render.sampler.clone_sampler())
+                .with_context(|| format!("Failed to render `{}` on the gpu", name))?;
+            on_tiles(&canvas, total);
+            Some(canvas)
+        }
+
+        (None, Some(film)) => {
+            integrator::render_tiles(
+                render.canvas_info.clone(),
+                scene,
+                render.root,
+                render.sampler.clone_sampler(),
+                &render.builder,
+                workers(),
+                &|tile, chunk| {
+                    on_tile(chunk);
+                    film.write_tile(tile.offset_x as u32, tile.offset_y as u32, chunk);
+                },
+            )
+            .with_context(|| format!("Failed to render `{}`", name))?;
+            None
+        }
+
+        (None, None) => Some(
+            integrator::render(
+                render.canvas_info.clone(),
+                scene,
+                render.root,
+                render.sampler.clone_sampler(),
+                &render.builder,
+                workers(),
+                &on_tile,
+            )
+            .with_context(|| format!("Failed to render `{}`", name))?,
+        ),
+    };
+
+    if let Some(report) = scene.non_finite.take(scene) {
+        if scene.finite_check == FiniteCheck::Strict {
+            return Err(anyhow!("Rendering `{}` failed: {}", name, report));
+        }
+        tracing::warn!("While rendering `{}`, {}", name, report);
+    }
+
+    // Finished renders are remembered, so that they can be reused if they don't change.
+    let remember = |output: Output| {
+        if let Some(cache) = &shared.cache {
+            let status = Status {
+                tiles: total,
+                total,
+                rays: rays_done.load(Ordering::Relaxed),
+                elapsed: start.elapsed(),
+            };
+            cache.insert(
+                key,
+                Cached {
+                    output: output.clone(),
+                    histogram,
+                    status,
+                },
+            );
+        }
+        output
+    };
 
-            parser::Target::Ascii { name } => Output::Ascii {
-                name,
-                chars: canvas.to_ascii(),
+    // Streamed renders have no effects, as the parser makes sure.
+    let mut canvas = match (film, canvas) {
+        (Some(film), canvas) => {
+            let path = path.expect("streamed renders write files");
+            let _write = tracing::debug_span!("write", path = %path.display()).entered();
+            if let Some(canvas) = &canvas {
+                film.write_canvas(canvas);
+            }
+            film.finish()?;
+            return Ok(remember(Output::File {
+                path,
+                preview: None,
+            }));
+        }
+        (None, canvas) => canvas.expect("only streamed renders skip the canvas"),
+    };
+
+    if let Some(denoise) = &render.denoise {
+        let guides = integrator::guides(info, scene, render.root, &render.builder, &shared.pool);
+        denoise.apply(&mut canvas, &guides);
+    }
+
+    render.apply_effects(&mut canvas);
+
+    let output = match (render.target, path) {
+        (
+            parser::Target::File {
+                channels, depth, ..
             },
+            Some(path),
+        ) => {
+            let image = canvas.to_image(channels, depth);
+            let preview = render.preview.map(|(width, height)| {
+                let image = canvas.downsample(width, height).to_image(channels, depth);
+                (preview_path(&path), image)
+            });
+            if shared.in_memory {
+                Output::Image {
+                    path,
+                    image,
+                    preview,
+                }
+            } else {
+                let _write = tracing::debug_span!("write", path = %path.display()).entered();
+                if let Some(dir) = path.parent() {
+                    std::fs::create_dir_all(dir)?;
+                }
+                image.save(&path)?;
+                let preview = match preview {
+                    Some((preview, image)) => {
+                        image.save(&preview)?;
+                        Some(preview)
+                    }
+                    None => None,
+                };
+                Output::File { path, preview }
+            }
         }
-    }))
+
+        (parser::Target::File { .. }, None) => unreachable!(),
+
+        (parser::Target::Ascii { name, mode }, _) => Output::Ascii {
+            name,
+            chars: canvas.to_ascii(mode),
+        },
+    };
+
+    Ok(remember(output))
+}
+
+/// Where the preview of the image at `path` is written, which is next to it with `preview` added
+/// before its extension.
+pub fn preview_path(path: &Path) -> PathBuf {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    match path.extension() {
+        Some(ext) => path.with_file_name(format!("{}.preview.{}", stem, ext.to_string_lossy())),
+        None => path.with_file_name(format!("{}.preview", stem)),
+    }
+}
+
+#[test]
+fn test_preview_path() {
+    assert_eq!(
+        PathBuf::from("out/shot.preview.png"),
+        preview_path(Path::new("out/shot.png"))
+    );
+    assert_eq!(
+        PathBuf::from("shot.preview"),
+        preview_path(Path::new("shot"))
+    );
+}
+
+#[test]
+fn test_status() {
+    let status = Status {
+        tiles: 0,
+        total: 4,
+        rays: 0,
+        elapsed: Duration::from_secs(2),
+    };
+    assert_eq!(None, status.eta());
+
+    let status = Status {
+        tiles: 1,
+        rays: 512,
+        ..status
+    };
+    assert_eq!(Some(Duration::from_secs(6)), status.eta());
+    assert_eq!(256., status.rays_per_sec());
+}
+
+#[test]
+fn test_concurrent() {
+    let input = r#"
+        (camera cam (pinhole 8 8 (look-at (0 0 -3) (0 0 0) (0 1 0)) (degrees 60)))
+        (node ball (sphere 1))
+        (render (ascii "beauty") (whitted (uniform 1) cam) ball)
+        (render (ascii "normals") (debug (uniform 1) cam normals) ball)
+        (render (ascii "depth") (debug (uniform 1) cam depth :far 4) ball)
+    "#;
+    let render = |concurrent| {
+        let settings = Settings {
+            threads: 2,
+            backend: Backend::Cpu,
+            strict: false,
+            coordinator: None,
+            in_memory: true,
+            concurrent,
+            cache: None,
+        };
+        render_source(
+            &settings,
+            input.to_string(),
+            &[],
+            &parser::Overrides::default(),
+            None,
+        )
+        .unwrap()
+        .map(|output| match output.unwrap() {
+            Output::Ascii { name, chars } => (name, chars),
+            _ => unreachable!(),
+        })
+        .collect::<Vec<_>>()
+    };
+
+    // The renders come out in order, and the same as when they run one at a time.
+    let outputs = render(true);
+    let names: Vec<_> = outputs.iter().map(|(name, _)| name.as_str()).collect();
+    assert_eq!(vec!["beauty", "normals", "depth"], names);
+    assert_eq!(render(false), outputs);
+    assert_ne!(outputs[0].1, outputs[2].1);
+}
+
+#[test]
+fn test_cache() {
+    let input = r#"
+        (camera cam (pinhole 8 8 (look-at (0 0 -3) (0 0 0) (0 1 0)) (degrees 60)))
+        (node ball (sphere 1))
+        (render (ascii "ball") (whitted (uniform 1) cam) ball)
+        (render (ascii "box") (whitted (uniform 1) cam) (box 1 1 1))
+    "#;
+    let cache = Arc::new(RenderCache::default());
+    let render = |input: &str| {
+        let settings = Settings {
+            threads: 2,
+            backend: Backend::Cpu,
+            strict: false,
+            coordinator: None,
+            in_memory: true,
+            concurrent: false,
+            cache: Some(cache.clone()),
+        };
+        render_source(
+            &settings,
+            input.to_string(),
+            &[],
+            &parser::Overrides::default(),
+            None,
+        )
+        .unwrap()
+        .map(|output| match output.unwrap() {
+            Output::Ascii { chars, .. } => chars,
+            _ => unreachable!(),
+        })
+        .collect::<Vec<_>>()
+    };
+    let cached = || cache.entries.lock().unwrap().len();
+
+    let first = render(input);
+    assert_eq!(2, cached());
+    assert_eq!(first, render(input));
+    assert_eq!(2, cached());
+
+    // Only the render that was edited is run again.
+    let edited = render(&input.replace("(box 1 1 1)", "(box 2 2 2)"));
+    assert_eq!(3, cached());
+    assert_eq!(first[0], edited[0]);
+    assert_ne!(first[1], edited[1]);
+}
+
+#[test]
+fn test_stream() {
+    let dir = std::env::temp_dir().join(format!("rendrs-stream-{}", std::process::id()));
+    let input = r#"
+        (camera cam (pinhole 40 24 (look-at (0 0 -3) (0 0 0) (0 1 0)) (degrees 60)))
+        (render (file "ball.exr" :stream true) (whitted (uniform 1) cam) (sphere 1))
+    "#;
+    let settings = Settings {
+        threads: 2,
+        backend: Backend::Cpu,
+        strict: false,
+        coordinator: None,
+        in_memory: false,
+        concurrent: false,
+        cache: None,
+    };
+    let overrides = parser::Overrides {
+        output_dir: Some(dir.clone()),
+        ..parser::Overrides::default()
+    };
+    let outputs: Vec<_> = render_source(&settings, input.to_string(), &[], &overrides, None)
+        .unwrap()
+        .collect::<Result<_, _>>()
+        .unwrap();
+    let Output::File { path, preview } = &outputs[0] else {
+        unreachable!()
+    };
+    assert_eq!(&dir.join("ball.exr"), path);
+    assert!(preview.is_none());
+
+    // The ball fills the middle of the image, and misses the corners.
+    let image = image::open(path).unwrap().into_rgb32f();
+    assert_eq!((40, 24), image.dimensions());
+    assert!(image.get_pixel(20, 12).0.iter().any(|c| *c > 0.));
+    assert_eq!([0.; 3], image.get_pixel(0, 0).0);
+
+    std::fs::remove_dir_all(&dir).unwrap();
 }
@@ -1,17 +1,21 @@
 use nalgebra::{Point2, Vector2};
 
+use crate::math::Float;
+
+/// Chooses the points on the film that each pixel is sampled at.
 pub trait Sampler: std::marker::Send + std::marker::Sync {
     /// Produce an iterator that will traverse the samples for a single pixel.
-    fn pixel_samples(&mut self, samples: &mut Vec<Point2<f32>>, pixel: &Point2<f32>);
+    fn pixel_samples(&mut self, samples: &mut Vec<Point2<Float>>, pixel: &Point2<Float>);
 
     /// A size-hint for the number of samples computed for each pixel.
     fn samples_per_pixel(&self) -> usize;
 
+    /// A copy of this sampler, for another thread.
     fn clone_sampler(&self) -> Box<dyn Sampler>;
 }
 
 impl<S: Sampler + ?Sized> Sampler for Box<S> {
-    fn pixel_samples(&mut self, samples: &mut Vec<Point2<f32>>, pixel: &Point2<f32>) {
+    fn pixel_samples(&mut self, samples: &mut Vec<Point2<Float>>, pixel: &Point2<Float>) {
         self.as_mut().pixel_samples(samples, pixel)
     }
 
@@ -26,7 +30,7 @@ impl<S: Sampler + ?Sized> Sampler for Box<S> {
 
 #[derive(Debug, Clone)]
 pub struct UniformSampler {
-    step: Point2<f32>,
+    step: Point2<Float>,
     size: usize,
 }
 
@@ -34,17 +38,28 @@ impl UniformSampler {
     /// Construct a new uniform sampler that will sample the center of each cell of the
     /// width x height sub-pixel grid.
     pub fn new(width: u32, height: u32) -> Self {
-        let x = (1. / (width as f32)).min(1.);
-        let y = (1. / (height as f32)).min(1.);
+        let x = (1. / (width as Float)).min(1.);
+        let y = (1. / (height as Float)).min(1.);
         Self {
             step: Point2::new(x, y),
-            size: (width * height) as usize,
+            size: width as usize * height as usize,
         }
     }
+
+    /// Construct a uniform sampler that takes `count` samples, using the grid closest to square
+    /// that has exactly that many cells.
+    pub fn with_count(count: u32) -> Self {
+        let width = (1..=count)
+            .take_while(|width| *width <= count / width)
+            .filter(|width| count.is_multiple_of(*width))
+            .last()
+            .unwrap_or(1);
+        Self::new(count / width, width)
+    }
 }
 
 impl Sampler for UniformSampler {
-    fn pixel_samples(&mut self, samples: &mut Vec<Point2<f32>>, pixel: &Point2<f32>) {
+    fn pixel_samples(&mut self, samples: &mut Vec<Point2<Float>>, pixel: &Point2<Float>) {
         let mut pos = Vector2::new(self.step.x / 2., self.step.y / 2.);
 
         while pos.y < 1. {
@@ -87,3 +102,18 @@ fn test_uniform_sampler() {
     assert_eq!(Point2::new(0.25, 0.25), samples[0]);
     assert_eq!(Point2::new(0.75, 0.75), samples[3]);
 }
+
+#[test]
+fn test_uniform_sampler_with_count() {
+    for (count, size) in [
+        (1, (1, 1)),
+        (4, (2, 2)),
+        (6, (3, 2)),
+        (7, (7, 1)),
+        (16, (4, 4)),
+    ] {
+        let sampler = UniformSampler::with_count(count);
+        assert_eq!(count as usize, sampler.samples_per_pixel());
+        assert_eq!(UniformSampler::new(size.0, size.1).step, sampler.step);
+    }
+}
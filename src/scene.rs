@@ -1,20 +1,183 @@
+use anyhow::{bail, Error};
 use approx::AbsDiffEq;
-use nalgebra::{Point3, Unit, Vector2, Vector3};
+use nalgebra::{Point2, Point3, Unit, Vector2, Vector3};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Mutex,
+};
 
 use crate::{
-    bvh::{BoundingBox, BVH},
+    bvh::{BoundingBox, BuildOptions, BVH},
     canvas::Color,
-    math::Mix,
+    fractal::Fractal,
+    grid::DistanceGrid,
+    heightfield::Heightfield,
+    matcap::Matcap,
+    math::{consts, Float, Mix},
+    metaballs::Metaballs,
+    polygon::Profile,
     ray::Ray,
+    sdf::Sdf,
     transform::{ApplyTransform, Transform},
 };
 
-#[derive(Debug, Default)]
+type Result<T> = std::result::Result<T, Error>;
+
+/// The size of the tetrahedron used to estimate normals from the SDF, near the origin.
+const NORMAL_EPSILON: Float = 0.0001;
+
+/// The size of the scenes that the default distances suit, which is the width of a unit sphere.
+const REFERENCE_SIZE: Float = 2.;
+
+/// The fraction of the distance along a ray that a surface may be hit from, when the march scales
+/// itself to the scene. Far along a ray the position is too imprecise for `min_dist` alone.
+const RAY_EPSILON: Float = 0.00001;
+
+/// How many times larger `bounds` are than the scenes that the default distances suit, or `None`
+/// when they're empty or unbounded.
+pub fn scene_scale(bounds: &BoundingBox) -> Option<Float> {
+    let BoundingBox::Bounds { min, max } = bounds else {
+        return None;
+    };
+    let size = (max - min).max();
+    (size > 0. && size.is_finite()).then(|| size / REFERENCE_SIZE)
+}
+
+/// The most points on the surface of each emissive node that stand in for it as a light.
+const EMITTER_SAMPLES: usize = 4096;
+
+/// The number of cells along each side of the grid that the bounds of an emissive node are split
+/// into. A point is drawn from each cell, to find points on its surface and estimate its area.
+const EMITTER_GRID: usize = 26;
+
+/// How deeply nodes may be nested under a render by default. Marching recurses through the nodes,
+/// so this keeps it well within the stack of a rendering thread.
+pub const DEFAULT_MAX_DEPTH: usize = 256;
+
+/// The most that [`Scene::max_depth`] may be raised to, which marching fits in [`STACK_SIZE`].
+pub const MAX_DEPTH_LIMIT: usize = 1024;
+
+/// The most copies of a node that a `ring` or `phyllotaxis` places. Each copy is a node of its own.
+pub const MAX_COPIES: u32 = 1 << 16;
+
+/// The most nodes that lights can be linked to, which each mark the hits under them with a bit of
+/// their own.
+pub const MAX_LINKED_NODES: usize = 64;
+
+/// The stack size of the threads that march rays, which fits graphs nested [`MAX_DEPTH_LIMIT`]
+/// deep. Only the part of the stack that's used is backed by memory.
+pub const STACK_SIZE: usize = 8 << 20;
+
+#[derive(Debug)]
 pub struct Scene {
+    /// The nodes of the scene, which only refer to nodes before them. Nodes edited in place are
+    /// checked by [`Scene::check_graph`] before they're rendered.
     pub nodes: Vec<(BoundingBox, Node)>,
     pub patterns: Vec<Pattern>,
     pub materials: Vec<Material>,
     pub lights: Vec<Light>,
+
+    /// The options used when building the hierarchy for groups.
+    pub bvh: BuildOptions,
+
+    /// The medium that fills the space between objects.
+    pub fog: Option<Fog>,
+
+    /// How non-finite distances produced by nodes are handled.
+    pub finite_check: FiniteCheck,
+
+    /// The non-finite distances produced while marching.
+    pub non_finite: NonFinite,
+
+    /// How deeply nodes may be nested under a render, up to [`MAX_DEPTH_LIMIT`].
+    pub max_depth: usize,
+
+    /// The bit that each node named by a light's links sets in [`SDFResult::links`], indexed by
+    /// node. Nodes that aren't linked have no bit.
+    pub(crate) linked: Vec<u64>,
+}
+
+impl Default for Scene {
+    fn default() -> Self {
+        Self {
+            nodes: Vec::new(),
+            patterns: Vec::new(),
+            materials: Vec::new(),
+            lights: Vec::new(),
+            bvh: BuildOptions::default(),
+            fog: None,
+            finite_check: FiniteCheck::default(),
+            non_finite: NonFinite::default(),
+            max_depth: DEFAULT_MAX_DEPTH,
+            linked: Vec::new(),
+        }
+    }
+}
+
+/// How nodes that produce non-finite distances while marching are handled. Such distances
+/// otherwise silently break the ordering of distances, and show up as missing or speckled
+/// geometry.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FiniteCheck {
+    /// Don't check distances, which is the default for release builds.
+    Off,
+
+    /// Record the offending nodes, and report them once the render is done. This is the default
+    /// for debug builds.
+    Report,
+
+    /// Abort the render with an error.
+    Strict,
+}
+
+impl Default for FiniteCheck {
+    fn default() -> Self {
+        if cfg!(debug_assertions) {
+            FiniteCheck::Report
+        } else {
+            FiniteCheck::Off
+        }
+    }
+}
+
+/// A record of the non-finite distances produced by nodes, shared by all of the rendering threads.
+#[derive(Debug, Default)]
+pub struct NonFinite {
+    count: AtomicU64,
+
+    /// The first node that produced a non-finite distance, and the point it was evaluated at in
+    /// that node's space.
+    first: Mutex<Option<(NodeId, Point3<Float>)>>,
+}
+
+impl NonFinite {
+    fn record(&self, id: NodeId, point: &Point3<Float>) {
+        if self.count.fetch_add(1, Ordering::Relaxed) == 0 {
+            *self.first.lock().unwrap() = Some((id, *point));
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.count.load(Ordering::Relaxed) == 0
+    }
+
+    /// Describe the non-finite distances recorded so far, and clear the record.
+    pub fn take(&self, scene: &Scene) -> Option<String> {
+        let count = self.count.swap(0, Ordering::Relaxed);
+        let (NodeId(id), point) = self.first.lock().unwrap().take()?;
+        Some(format!(
+            "node {} ({}) produced a non-finite distance at ({}, {}, {}), \
+             with {} non-finite distances in total",
+            id,
+            scene.node(NodeId(id)).name(),
+            point.x,
+            point.y,
+            point.z,
+            count
+        ))
+    }
 }
 
 // TODO: make a macro for deriving the id/vector pairs
@@ -31,28 +194,84 @@ pub struct MaterialId(u32);
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 pub struct LightId(u32);
 
+impl NodeId {
+    /// The index of this node in the scene.
+    pub fn index(self) -> usize {
+        self.0 as usize
+    }
+}
+
+impl PatternId {
+    /// The index of this pattern in the scene.
+    pub fn index(self) -> usize {
+        self.0 as usize
+    }
+}
+
+impl MaterialId {
+    /// The index of this material in the scene.
+    pub fn index(self) -> usize {
+        self.0 as usize
+    }
+}
+
 /// Primitive shapes, centered at the origin.
 #[derive(Debug)]
 pub enum Prim {
     /// A plane with the given normal.
-    Plane { normal: Unit<Vector3<f32>> },
+    Plane { normal: Unit<Vector3<Float>> },
 
     /// A sphere with the given radius.
-    Sphere { radius: f32 },
+    Sphere { radius: Float },
 
     /// A box with the given dimensions.
-    Box { width: f32, height: f32, depth: f32 },
+    Box {
+        width: Float,
+        height: Float,
+        depth: Float,
+    },
 
     /// A torus with the given hole radius and ring radius.
-    Torus { hole: f32, radius: f32 },
+    Torus { hole: Float, radius: Float },
 
     /// A triangle with no depth.
     Triangle {
-        a: Point3<f32>,
-        b: Point3<f32>,
-        c: Point3<f32>,
-        n: Unit<Vector3<f32>>,
+        a: Point3<Float>,
+        b: Point3<Float>,
+        c: Point3<Float>,
+        n: Unit<Vector3<Float>>,
+
+        /// The normals at `a`, `b` and `c`, which are blended across the triangle for shading
+        /// when they're given.
+        normals: Option<[Unit<Vector3<Float>>; 3]>,
+    },
+
+    /// A line segment from `a` to `b`, rounded out to `radius`.
+    Capsule {
+        a: Point3<Float>,
+        b: Point3<Float>,
+        radius: Float,
+    },
+
+    /// A profile in the xy plane, extruded along z by `depth` centered on the plane. The profile
+    /// turns by `twist` radians about the z axis from the back of the solid to the front.
+    Extrude {
+        profile: Profile,
+        depth: Float,
+        twist: Float,
     },
+
+    /// A profile in the xy plane, moved `offset` along x and revolved around the y axis.
+    Revolve { profile: Profile, offset: Float },
+
+    /// Balls whose fields blend together into a single blobby surface.
+    Metaballs { metaballs: Metaballs },
+
+    /// A fractal, whose distance is estimated.
+    Fractal { fractal: Fractal },
+
+    /// A shape defined outside of the crate, and the name of its form.
+    Custom { name: String, sdf: Box<dyn Sdf> },
 }
 
 /// Nodes in the scene graph.
@@ -71,7 +290,11 @@ pub enum Node {
     Subtract { left: NodeId, right: NodeId },
 
     /// A smooth union of two nodes.
-    SmoothUnion { k: f32, left: NodeId, right: NodeId },
+    SmoothUnion {
+        k: Float,
+        left: NodeId,
+        right: NodeId,
+    },
 
     /// The intersection of nodes.
     Intersect { nodes: Vec<NodeId> },
@@ -79,18 +302,130 @@ pub enum Node {
     /// Apply this Transform the node.
     Transform { transform: Transform, node: NodeId },
 
-    /// Apply this material to the node.
+    /// Apply this material to the parts of the node that aren't painted with one of their own.
     Material { material: MaterialId, node: NodeId },
+
+    /// Render the node as a density field rather than a surface.
+    Volume { volume: Volume, node: NodeId },
+
+    /// Push the surface of the node out along its normal by the brightness of a pattern.
+    Displace {
+        displacement: Displacement,
+        node: NodeId,
+    },
+
+    /// Distances baked into a grid from another node, which it stands in for.
+    Grid { grid: DistanceGrid },
+
+    /// Terrain whose heights are sampled from an image.
+    Heightfield { field: Heightfield },
+}
+
+/// A participating medium whose density at a point grows with how far inside its node the point
+/// is. Volumes are invisible to surface marching, and are instead integrated along each ray with
+/// fixed steps.
+#[derive(Debug, Clone)]
+pub struct Volume {
+    /// The density gained per unit of depth inside the node.
+    pub density_scale: f32,
+
+    /// The fraction of the light removed by the medium that's scattered towards the viewer,
+    /// rather than absorbed.
+    pub scattering: f32,
+
+    /// The color of scattered light.
+    pub color: Color,
+
+    /// The light emitted per unit of density.
+    pub emission: Color,
+
+    /// The length of the steps taken through the medium.
+    pub step: Float,
+}
+
+impl Default for Volume {
+    fn default() -> Self {
+        Self {
+            density_scale: 1.0,
+            scattering: 0.5,
+            color: Color::white(),
+            emission: Color::black(),
+            step: 0.05,
+        }
+    }
+}
+
+/// An offset of a surface along its normal, for detail that would be tedious to model. The
+/// pattern is looked up on the undisplaced surface, and its brightness from black to white moves
+/// the surface by up to `amount`.
+#[derive(Debug, Clone)]
+pub struct Displacement {
+    pub pattern: PatternId,
+
+    /// The furthest that the surface is moved, which is inwards when it's negative.
+    pub amount: Float,
+
+    /// A bound on how quickly the offset changes along the surface. Distances near the surface
+    /// are shrunk to match, so that marching doesn't step through the peaks of the pattern.
+    pub slope: Float,
+}
+
+impl Displacement {
+    /// A bound on the distance to the displaced surface from a point `distance` away from the
+    /// undisplaced one, when that's too far for the pattern to matter.
+    fn far(&self, distance: Float) -> Option<Float> {
+        let reach = 2. * self.amount.abs();
+        if distance > reach {
+            Some(distance - self.amount.max(0.))
+        } else if distance < -reach {
+            Some(distance - self.amount.min(0.))
+        } else {
+            None
+        }
+    }
+
+    /// The distance to the displaced surface, from the result `res` for the undisplaced `node`.
+    fn near(&self, scene: &Scene, node: NodeId, ray: &Ray, res: &SDFResult) -> Float {
+        let sdf = |point: &Point3<Float>| {
+            let ray = Ray::new(*point, ray.direction);
+            scene
+                .node(node)
+                .fast_sdf(scene, &ray, Float::NEG_INFINITY)
+                .distance
+                .0
+        };
+        let height = scene
+            .pattern(self.pattern)
+            .color_at(
+                scene,
+                &SurfacePoint {
+                    object: res.object,
+                    world: ray.position,
+                    uv: res.uv(scene),
+                    normal: res.shading.unwrap_or(res.normal),
+                    object_normal: res.object_normal,
+                    eye: -ray.direction,
+                    sdf: &sdf,
+                },
+            )
+            .luminance()
+            .clamp(0., 1.);
+        (res.distance.0 - self.amount * height as Float) / (1. + self.slope)
+    }
 }
 
 #[derive(Debug, Default, Clone, Copy)]
-pub struct Distance(pub f32);
+pub struct Distance(pub Float);
 
 #[derive(Debug, Clone)]
 pub struct MarchConfig {
     pub max_steps: u32,
-    pub min_dist: f32,
-    pub max_dist: f32,
+    pub min_dist: Float,
+    pub max_dist: Float,
+
+    /// Whether `min_dist` is scaled to the size of the scene, and grows with the distance along
+    /// each ray, so that very large and very small scenes render like ones near unit size.
+    pub auto_scale: bool,
 }
 
 impl Default for MarchConfig {
@@ -99,6 +434,28 @@ impl Default for MarchConfig {
             max_steps: 200,
             min_dist: 0.001,
             max_dist: 1000.,
+            auto_scale: true,
+        }
+    }
+}
+
+impl MarchConfig {
+    /// The config for marching a scene within `bounds`. Unbounded scenes, such as ones with a
+    /// plane in them, keep `min_dist` as it is.
+    pub fn scaled(&self, bounds: &BoundingBox) -> Self {
+        let mut config = self.clone();
+        if let Some(scale) = scene_scale(bounds).filter(|_| self.auto_scale) {
+            config.min_dist *= scale;
+        }
+        config
+    }
+
+    /// How close a ray that has traveled `distance` must come to a surface to hit it.
+    pub fn hit_distance(&self, distance: Float) -> Float {
+        if self.auto_scale {
+            self.min_dist.max(distance * RAY_EPSILON)
+        } else {
+            self.min_dist
         }
     }
 }
@@ -109,26 +466,57 @@ pub struct SDFResult {
     pub id: NodeId,
 
     /// The point in object space.
-    pub object: Point3<f32>,
+    pub object: Point3<Float>,
+
+    /// The geometric normal in world space, which is the gradient of the distance.
+    pub normal: Unit<Vector3<Float>>,
 
-    /// The normal in world space.
-    pub normal: Unit<Vector3<f32>>,
+    /// The same normal in object space, for orienting things that are measured from the object
+    /// space point.
+    pub object_normal: Unit<Vector3<Float>>,
+
+    /// The normal in world space to shade the surface with, when it differs from the geometric
+    /// normal, such as on a triangle with normals at its corners.
+    pub shading: Option<Unit<Vector3<Float>>>,
 
     /// The distance between the world-space ray and this object.
     pub distance: Distance,
 
     /// The maierial for the object.
     pub material: Option<MaterialId>,
+
+    /// The bits of the nodes named by light links that the closest object was reached through.
+    /// Nodes can be shared, so this depends on the path to the object rather than on the object.
+    pub links: u64,
+
+    /// The primitive that the closest surface belongs to, and the point in its space, for
+    /// computing texture coordinates once something is hit.
+    pub prim: NodeId,
+    pub local: Point3<Float>,
 }
 
 impl SDFResult {
-    fn new(id: NodeId, object: Point3<f32>) -> Self {
+    fn new(id: NodeId, object: Point3<Float>) -> Self {
         Self {
             id,
             object,
             normal: Unit::new_unchecked(Vector3::new(0., 0., 1.)),
-            distance: Distance(std::f32::INFINITY),
+            object_normal: Unit::new_unchecked(Vector3::new(0., 0., 1.)),
+            shading: None,
+            distance: Distance(Float::INFINITY),
             material: None,
+            links: 0,
+            prim: id,
+            local: object,
+        }
+    }
+
+    /// The texture coordinates of the closest surface, or the origin when it's not a primitive.
+    pub fn uv(&self, scene: &Scene) -> Point2<Float> {
+        match scene.node(self.prim) {
+            Node::Prim { prim } => prim.uv(&self.local),
+            Node::Heightfield { field } => field.uv(&self.local),
+            _ => Point2::origin(),
         }
     }
 }
@@ -145,21 +533,153 @@ pub struct FastSDFResult {
 impl FastSDFResult {
     fn new() -> Self {
         Self {
-            distance: Distance(std::f32::INFINITY),
+            distance: Distance(Float::INFINITY),
             material: None,
         }
     }
 }
 
+#[derive(Debug)]
+pub struct VolumeSDFResult<'a> {
+    /// The distance to the closest volume, which is negative inside of it.
+    pub distance: Distance,
+
+    /// The closest volume.
+    pub volume: Option<&'a Volume>,
+}
+
+impl VolumeSDFResult<'_> {
+    fn new() -> Self {
+        Self {
+            distance: Distance(Float::INFINITY),
+            volume: None,
+        }
+    }
+}
+
+/// Check that `value`, the `what` of a node or setting, is a finite number greater than zero.
+pub(crate) fn positive(what: &str, value: Float) -> Result<()> {
+    if !(value.is_finite() && value > 0.) {
+        bail!("The {} must be positive, not {}", what, value);
+    }
+    Ok(())
+}
+
+/// Check that `value`, the `what` of a node or setting, is a finite number that's not negative.
+pub(crate) fn non_negative(what: &str, value: Float) -> Result<()> {
+    if !(value.is_finite() && value >= 0.) {
+        bail!("The {} must not be negative, not {}", what, value);
+    }
+    Ok(())
+}
+
+fn finite(what: &str, value: Float) -> Result<()> {
+    if !value.is_finite() {
+        bail!("The {} must be a finite number, not {}", what, value);
+    }
+    Ok(())
+}
+
+fn finite_point(what: &str, point: &Point3<Float>) -> Result<()> {
+    if !point.iter().all(|x| x.is_finite()) {
+        bail!(
+            "The {} must be a finite point, not ({}, {}, {})",
+            what,
+            point.x,
+            point.y,
+            point.z
+        );
+    }
+    Ok(())
+}
+
 impl Scene {
+    /// True when a strict check has found a non-finite distance, and rendering should stop.
+    pub fn aborted(&self) -> bool {
+        self.finite_check == FiniteCheck::Strict && !self.non_finite.is_empty()
+    }
+
     #[inline]
     fn add_node(&mut self, node: Node) -> NodeId {
         let id = NodeId(self.nodes.len() as u32);
+
+        // Nodes can only refer to the nodes before them, so none can contain itself.
+        assert!(
+            node.children().into_iter().all(|child| child < id),
+            "A node must only refer to nodes already in the scene"
+        );
+
         let bounds = node.bounding_box(self);
         self.nodes.push((bounds, node));
         id
     }
 
+    /// Check that the nodes under `root` can be marched: that none of them contains itself, which
+    /// can only happen when [`Scene::nodes`] is edited directly, and that they're nested no more
+    /// than [`Scene::max_depth`] deep. The nodes are walked with a stack of their own, so that this
+    /// is safe on graphs that would overflow the stack while marching.
+    pub fn check_graph(&self, root: NodeId) -> Result<()> {
+        if self.max_depth > MAX_DEPTH_LIMIT {
+            bail!(
+                "Nodes may be nested at most {} deep, not {}",
+                MAX_DEPTH_LIMIT,
+                self.max_depth
+            );
+        }
+
+        enum Visit {
+            Enter(NodeId),
+            Exit(NodeId),
+        }
+
+        // The depth of each node that's been walked, and the nodes on the path to the current one.
+        let mut depths: Vec<Option<usize>> = vec![None; self.nodes.len()];
+        let mut on_path = vec![false; self.nodes.len()];
+
+        let mut stack = vec![Visit::Enter(root)];
+        while let Some(visit) = stack.pop() {
+            match visit {
+                Visit::Enter(id) => {
+                    if id.index() >= self.nodes.len() {
+                        bail!("Node {} isn't in the scene", id.0);
+                    }
+                    if depths[id.index()].is_some() {
+                        continue;
+                    }
+                    if on_path[id.index()] {
+                        bail!("Node {} ({}) contains itself", id.0, self.node(id).name());
+                    }
+                    on_path[id.index()] = true;
+                    stack.push(Visit::Exit(id));
+                    stack.extend(self.node(id).children().into_iter().map(Visit::Enter));
+                }
+
+                Visit::Exit(id) => {
+                    on_path[id.index()] = false;
+                    let depth = 1 + self
+                        .node(id)
+                        .children()
+                        .into_iter()
+                        .filter_map(|child| depths[child.index()])
+                        .max()
+                        .unwrap_or(0);
+                    if depth > self.max_depth {
+                        bail!(
+                            "Node {} ({}) has nodes nested {} deep under it, more than the limit of {}",
+                            id.0,
+                            self.node(id).name(),
+                            depth,
+                            self.max_depth
+                        );
+                    }
+                    depths[id.index()] = Some(depth);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
     /// Fetch a node from the scene.
     #[inline]
     pub fn node(&self, NodeId(id): NodeId) -> &Node {
@@ -172,47 +692,134 @@ impl Scene {
     }
 
     /// Construct a plane with the given normal in the scene.
-    pub fn plane(&mut self, normal: Unit<Vector3<f32>>) -> NodeId {
+    pub fn plane(&mut self, normal: Unit<Vector3<Float>>) -> NodeId {
         self.add_node(Node::Prim {
             prim: Prim::Plane { normal },
         })
     }
 
     /// Construct a sphere with the given radius in the scene.
-    pub fn sphere(&mut self, radius: f32) -> NodeId {
-        self.add_node(Node::Prim {
+    pub fn sphere(&mut self, radius: Float) -> Result<NodeId> {
+        positive("radius of a sphere", radius)?;
+        Ok(self.add_node(Node::Prim {
             prim: Prim::Sphere { radius },
-        })
+        }))
     }
 
-    /// Construct a box with the given dimensions in the scene.
-    pub fn rect(&mut self, width: f32, height: f32, depth: f32) -> NodeId {
-        self.add_node(Node::Prim {
+    /// Construct a box with the given dimensions in the scene. One of them may be zero, for a flat
+    /// box, but not all of them.
+    pub fn rect(&mut self, width: Float, height: Float, depth: Float) -> Result<NodeId> {
+        non_negative("width of a box", width)?;
+        non_negative("height of a box", height)?;
+        non_negative("depth of a box", depth)?;
+        if width == 0. && height == 0. && depth == 0. {
+            bail!("A box must have a size, but its width, height and depth are all 0");
+        }
+        Ok(self.add_node(Node::Prim {
             prim: Prim::Box {
                 width,
                 height,
                 depth,
             },
-        })
+        }))
     }
 
     /// Construct a torus with the given inner and outer radii.
-    pub fn torus(&mut self, hole: f32, radius: f32) -> NodeId {
-        self.add_node(Node::Prim {
+    pub fn torus(&mut self, hole: Float, radius: Float) -> Result<NodeId> {
+        non_negative("hole of a torus", hole)?;
+        positive("radius of a torus", radius)?;
+        Ok(self.add_node(Node::Prim {
             prim: Prim::Torus { hole, radius },
-        })
+        }))
     }
 
     /// Render a triangle in the scene, with no depth.
     pub fn triangle(
         &mut self,
-        a: Point3<f32>,
-        b: Point3<f32>,
-        c: Point3<f32>,
-        n: Unit<Vector3<f32>>,
+        a: Point3<Float>,
+        b: Point3<Float>,
+        c: Point3<Float>,
+        n: Unit<Vector3<Float>>,
+    ) -> NodeId {
+        self.add_node(Node::Prim {
+            prim: Prim::Triangle {
+                a,
+                b,
+                c,
+                n,
+                normals: None,
+            },
+        })
+    }
+
+    /// Construct a triangle that's shaded as though it were curved, by blending the normals at
+    /// its corners.
+    pub fn smooth_triangle(
+        &mut self,
+        a: Point3<Float>,
+        b: Point3<Float>,
+        c: Point3<Float>,
+        n: Unit<Vector3<Float>>,
+        normals: [Unit<Vector3<Float>>; 3],
     ) -> NodeId {
         self.add_node(Node::Prim {
-            prim: Prim::Triangle { a, b, c, n },
+            prim: Prim::Triangle {
+                a,
+                b,
+                c,
+                n,
+                normals: Some(normals),
+            },
+        })
+    }
+
+    /// Construct a capsule around the segment from `a` to `b`.
+    pub fn capsule(&mut self, a: Point3<Float>, b: Point3<Float>, radius: Float) -> Result<NodeId> {
+        finite_point("end of a capsule", &a)?;
+        finite_point("end of a capsule", &b)?;
+        positive("radius of a capsule", radius)?;
+        Ok(self.add_node(Node::Prim {
+            prim: Prim::Capsule { a, b, radius },
+        }))
+    }
+
+    /// Extrude `profile` along z, `depth` thick, turning it by `twist` radians along the way.
+    pub fn extrude(&mut self, profile: Profile, depth: Float, twist: Float) -> NodeId {
+        self.add_node(Node::Prim {
+            prim: Prim::Extrude {
+                profile,
+                depth,
+                twist,
+            },
+        })
+    }
+
+    /// Revolve `profile` around the y axis, `offset` from it.
+    pub fn revolve(&mut self, profile: Profile, offset: Float) -> NodeId {
+        self.add_node(Node::Prim {
+            prim: Prim::Revolve { profile, offset },
+        })
+    }
+
+    pub fn metaballs(&mut self, metaballs: Metaballs) -> NodeId {
+        self.add_node(Node::Prim {
+            prim: Prim::Metaballs { metaballs },
+        })
+    }
+
+    pub fn fractal(&mut self, fractal: Fractal) -> NodeId {
+        self.add_node(Node::Prim {
+            prim: Prim::Fractal { fractal },
+        })
+    }
+
+    /// A custom primitive, which is described as `name`.
+    pub fn custom(&mut self, name: impl Into<String>, sdf: Box<dyn Sdf>) -> NodeId {
+        self.add_node(Node::Prim {
+            prim: Prim::Custom {
+                name: name.into(),
+                sdf,
+            },
         })
     }
 
@@ -221,21 +828,31 @@ impl Scene {
         self.add_node(Node::Invert { node })
     }
 
-    fn add_group(&mut self, union: bool, nodes: Vec<NodeId>) -> NodeId {
-        assert!(!nodes.is_empty());
+    fn add_group(&mut self, union: bool, nodes: Vec<NodeId>) -> Result<NodeId> {
+        if nodes.is_empty() {
+            bail!(
+                "A {} needs at least one node",
+                if union { "union" } else { "group" }
+            );
+        }
+        Ok(self.add_bvh(union, nodes))
+    }
+
+    /// Add a group of `nodes`, which mustn't be empty.
+    fn add_bvh(&mut self, union: bool, nodes: Vec<NodeId>) -> NodeId {
         let nodes = nodes
             .into_iter()
             .map(|id| (self.bounding_box(id).clone(), id))
             .collect();
-        let nodes = BVH::from_nodes(nodes);
+        let nodes = BVH::from_nodes(nodes, &self.bvh);
         self.add_node(Node::Group { union, nodes })
     }
 
-    pub fn group(&mut self, nodes: Vec<NodeId>) -> NodeId {
+    pub fn group(&mut self, nodes: Vec<NodeId>) -> Result<NodeId> {
         self.add_group(false, nodes)
     }
 
-    pub fn union(&mut self, nodes: Vec<NodeId>) -> NodeId {
+    pub fn union(&mut self, nodes: Vec<NodeId>) -> Result<NodeId> {
         self.add_group(true, nodes)
     }
 
@@ -243,21 +860,31 @@ impl Scene {
         self.add_node(Node::Subtract { left, right })
     }
 
-    pub fn smooth_union(&mut self, k: f32, nodes: &[NodeId]) -> NodeId {
+    pub fn smooth_union(&mut self, k: Float, nodes: &[NodeId]) -> Result<NodeId> {
+        positive("blend of a smooth union", k)?;
+        if nodes.is_empty() {
+            bail!("A smooth union needs at least one node");
+        }
+        Ok(self.add_smooth_union(k, nodes))
+    }
+
+    fn add_smooth_union(&mut self, k: Float, nodes: &[NodeId]) -> NodeId {
         match nodes.len() {
-            0 => panic!("no nodes given to `smooth_union`"),
             1 => nodes[0],
             len => {
                 let (left, right) = nodes.split_at(len / 2);
-                let left = self.smooth_union(k, left);
-                let right = self.smooth_union(k, right);
+                let left = self.add_smooth_union(k, left);
+                let right = self.add_smooth_union(k, right);
                 self.add_node(Node::SmoothUnion { k, left, right })
             }
         }
     }
 
-    pub fn intersect(&mut self, nodes: Vec<NodeId>) -> NodeId {
-        self.add_node(Node::Intersect { nodes })
+    pub fn intersect(&mut self, nodes: Vec<NodeId>) -> Result<NodeId> {
+        if nodes.is_empty() {
+            bail!("An intersection needs at least one node");
+        }
+        Ok(self.add_node(Node::Intersect { nodes }))
     }
 
     pub fn transform(&mut self, transform: Transform, node: NodeId) -> NodeId {
@@ -272,10 +899,191 @@ impl Scene {
         }
     }
 
+    /// Place `count` instances of `node` evenly around a circle of `radius` in the xz plane.
+    pub fn ring(&mut self, count: u32, radius: Float, node: NodeId) -> Result<NodeId> {
+        finite("radius of a ring", radius)?;
+        let step = consts::TAU / count as Float;
+        let nodes = (0..count)
+            .map(|i| {
+                let angle = step * i as Float;
+                let offset = Vector3::new(radius * angle.cos(), 0., radius * angle.sin());
+                self.transform(Transform::new().translate(&offset), node)
+            })
+            .collect();
+        self.group(nodes)
+    }
+
+    /// Place `count` instances of `node` along a golden-angle spiral in the xz plane, like the seeds
+    /// in a sunflower head. The `spacing` controls the distance between successive instances.
+    pub fn phyllotaxis(&mut self, count: u32, spacing: Float, node: NodeId) -> Result<NodeId> {
+        finite("spacing of a phyllotaxis", spacing)?;
+        let golden_angle = consts::PI * (3. - Float::sqrt(5.));
+        let nodes = (0..count)
+            .map(|i| {
+                let angle = golden_angle * i as Float;
+                let radius = spacing * (i as Float).sqrt();
+                let offset = Vector3::new(radius * angle.cos(), 0., radius * angle.sin());
+                self.transform(Transform::new().translate(&offset), node)
+            })
+            .collect();
+        self.group(nodes)
+    }
+
+    /// Cut away the parts of `node` on the side of each plane that its normal points to. The cut
+    /// faces are painted with `cap` when it's given. This adds new nodes over `node`, leaving
+    /// `node` itself as it was for anything else that uses it.
+    pub fn clip(
+        &mut self,
+        node: NodeId,
+        planes: &[(Point3<Float>, Unit<Vector3<Float>>)],
+        cap: Option<MaterialId>,
+    ) -> Result<NodeId> {
+        let mut nodes = vec![node];
+        for (point, normal) in planes {
+            let mut plane = self.plane(*normal);
+            if let Some(cap) = cap {
+                plane = self.paint(cap, plane);
+            }
+            nodes.push(self.transform(Transform::new().translate(&point.coords), plane));
+        }
+        self.intersect(nodes)
+    }
+
     pub fn paint(&mut self, material: MaterialId, node: NodeId) -> NodeId {
         self.add_node(Node::Material { material, node })
     }
 
+    /// Paint every instance of the `targets` under `root` with `material`, replacing the
+    /// materials inside them rather than falling back to them. This returns a new root, sharing
+    /// the parts of the old one that don't lead to a target, or `None` when none of the `targets`
+    /// are under `root`.
+    pub fn override_material(
+        &mut self,
+        root: NodeId,
+        targets: &[NodeId],
+        material: MaterialId,
+    ) -> Option<NodeId> {
+        let under = self.descendants(&[root]);
+        if !targets.iter().any(|target| under.contains(target)) {
+            return None;
+        }
+
+        Some(self.rewrite(root, &mut |scene, id| {
+            targets.contains(&id).then(|| {
+                let bare = scene.strip_materials(id);
+                scene.paint(material, bare)
+            })
+        }))
+    }
+
+    /// The node without any of the materials under it.
+    fn strip_materials(&mut self, root: NodeId) -> NodeId {
+        self.rewrite(root, &mut |scene, id| match *scene.node(id) {
+            Node::Material { node, .. } => Some(scene.strip_materials(node)),
+            _ => None,
+        })
+    }
+
+    /// Rebuild `root` with nodes replaced by `f`, from the top down. When `f` gives a replacement
+    /// for a node, the nodes under it aren't visited. Nodes with nothing replaced under them are
+    /// shared with the original rather than copied.
+    fn rewrite(
+        &mut self,
+        root: NodeId,
+        f: &mut dyn FnMut(&mut Scene, NodeId) -> Option<NodeId>,
+    ) -> NodeId {
+        fn visit(
+            scene: &mut Scene,
+            id: NodeId,
+            f: &mut dyn FnMut(&mut Scene, NodeId) -> Option<NodeId>,
+            done: &mut BTreeMap<NodeId, NodeId>,
+        ) -> NodeId {
+            if let Some(&new) = done.get(&id) {
+                return new;
+            }
+            if let Some(new) = f(scene, id) {
+                done.insert(id, new);
+                return new;
+            }
+
+            let children = scene.node(id).children();
+            let replaced: Vec<_> = children
+                .iter()
+                .map(|&child| visit(scene, child, f, done))
+                .collect();
+
+            let new = if replaced == children {
+                id
+            } else if let Node::Group { union, .. } = *scene.node(id) {
+                scene.add_bvh(union, replaced)
+            } else {
+                let node = match scene.node(id) {
+                    Node::Intersect { .. } => Node::Intersect { nodes: replaced },
+                    Node::Subtract { .. } => Node::Subtract {
+                        left: replaced[0],
+                        right: replaced[1],
+                    },
+                    Node::SmoothUnion { k, .. } => Node::SmoothUnion {
+                        k: *k,
+                        left: replaced[0],
+                        right: replaced[1],
+                    },
+                    Node::Invert { .. } => Node::Invert { node: replaced[0] },
+                    Node::Transform { transform, .. } => Node::Transform {
+                        transform: transform.clone(),
+                        node: replaced[0],
+                    },
+                    Node::Material { material, .. } => Node::Material {
+                        material: *material,
+                        node: replaced[0],
+                    },
+                    Node::Volume { volume, .. } => Node::Volume {
+                        volume: volume.clone(),
+                        node: replaced[0],
+                    },
+                    Node::Displace { displacement, .. } => Node::Displace {
+                        displacement: displacement.clone(),
+                        node: replaced[0],
+                    },
+                    Node::Group { .. }
+                    | Node::Prim { .. }
+                    | Node::Grid { .. }
+                    | Node::Heightfield { .. } => {
+                        unreachable!("groups are rebuilt above, and leaves have no children")
+                    }
+                };
+                scene.add_node(node)
+            };
+            done.insert(id, new);
+            new
+        }
+
+        visit(self, root, f, &mut BTreeMap::new())
+    }
+
+    pub fn volume(&mut self, volume: Volume, node: NodeId) -> NodeId {
+        self.add_node(Node::Volume { volume, node })
+    }
+
+    pub fn displace(&mut self, displacement: Displacement, node: NodeId) -> NodeId {
+        self.add_node(Node::Displace { displacement, node })
+    }
+
+    /// Bake the distances to `node` into a grid with `resolution` cells along its longest side,
+    /// returning a node that samples the grid in its place. Materials under `node` are lost.
+    pub fn bake(&mut self, node: NodeId, resolution: usize) -> Result<NodeId> {
+        let grid = DistanceGrid::bake(self, node, resolution)?;
+        Ok(self.grid(grid))
+    }
+
+    pub fn grid(&mut self, grid: DistanceGrid) -> NodeId {
+        self.add_node(Node::Grid { grid })
+    }
+
+    pub fn heightfield(&mut self, field: Heightfield) -> NodeId {
+        self.add_node(Node::Heightfield { field })
+    }
+
     #[inline]
     fn add_material(&mut self, material: Material) -> MaterialId {
         let id = MaterialId(self.materials.len() as u32);
@@ -288,8 +1096,160 @@ impl Scene {
         &self.materials[id as usize]
     }
 
-    pub fn phong(
-        &mut self,
+    /// Find the surfaces under `root` that are painted with an emissive material, through groups
+    /// and transforms, and sample points on them. Unbounded surfaces like planes can't be sampled,
+    /// so they only show their own color.
+    pub fn emitters(&self, root: NodeId) -> Vec<Emitter> {
+        let mut emitters = Vec::new();
+        self.find_emitters(root, &Transform::new(), None, &mut emitters);
+        emitters
+    }
+
+    fn find_emitters(
+        &self,
+        id: NodeId,
+        transform: &Transform,
+        paint: Option<PatternId>,
+        emitters: &mut Vec<Emitter>,
+    ) {
+        match self.node(id) {
+            // Each member of a group is sampled on its own, as the hierarchy only gives distances
+            // to the members along a ray.
+            Node::Group { nodes, .. } => {
+                for &node in nodes.iter() {
+                    self.find_emitters(node, transform, paint, emitters);
+                }
+            }
+
+            Node::Transform {
+                transform: inner,
+                node,
+            } => self.find_emitters(*node, &(transform * inner), paint, emitters),
+
+            // The innermost paint wins, so each material replaces the one around it.
+            Node::Material { material, node } => {
+                let paint = match self.material(*material) {
+                    Material::Emissive { pattern, .. } => Some(*pattern),
+                    Material::Phong { .. } | Material::Toon { .. } | Material::Matcap { .. } => {
+                        None
+                    }
+                };
+                self.find_emitters(*node, transform, paint, emitters)
+            }
+
+            Node::Volume { .. } => {}
+
+            _ => {
+                if let Some(pattern) = paint {
+                    emitters.extend(self.sample_emitter(id, transform, pattern));
+                }
+            }
+        }
+    }
+
+    /// Sample the surface of an emissive node by drawing points from its bounds, keeping those in
+    /// a thin shell around the surface and projecting them onto it. The fraction of the points
+    /// that land in the shell also gives the area of the surface.
+    fn sample_emitter(
+        &self,
+        id: NodeId,
+        transform: &Transform,
+        pattern: PatternId,
+    ) -> Option<Emitter> {
+        let BoundingBox::Bounds { min, max } = self.bounding_box(id).apply(transform) else {
+            return None;
+        };
+
+        // The bounds are padded by the shell, which would otherwise be cut off where the surface
+        // touches them.
+        let shell = 0.05 * (max - min).min();
+        if !(shell > 0. && shell.is_finite()) {
+            return None;
+        }
+        let min = min - Vector3::repeat(shell);
+        let size = max - min + Vector3::repeat(shell);
+
+        // Seeded by the node, so that every thread and worker lights the scene the same way.
+        let mut rng = StdRng::seed_from_u64(id.0 as u64);
+        let node = self.node(id);
+        let sdf = |position: Point3<Float>| {
+            let mut res = node.sdf(
+                self,
+                id,
+                &Ray::new(position, Vector3::y_axis()).invert(transform),
+            );
+            res.distance.0 *= transform.scale_factor();
+            res.normal = transform.apply_normal(&res.normal);
+            res
+        };
+
+        // Drawing a point from each cell of a grid spreads them more evenly than drawing them
+        // from the whole of the bounds, which the area and the light are both sensitive to.
+        let cell = size / EMITTER_GRID as Float;
+        let mut hits = Vec::new();
+        for i in 0..EMITTER_GRID.pow(3) {
+            let index = Vector3::new(
+                i % EMITTER_GRID,
+                i / EMITTER_GRID % EMITTER_GRID,
+                i / (EMITTER_GRID * EMITTER_GRID),
+            );
+            let jitter = Vector3::from_fn(|_, _| rng.gen::<Float>());
+            let point = min + (index.cast::<Float>() + jitter).component_mul(&cell);
+            let res = sdf(point);
+            if res.distance.0.abs() < shell {
+                hits.push(point - res.normal.scale(res.distance.0));
+            }
+        }
+
+        // When there are too many points, every few are kept, which stay spread over the surface.
+        let stride = hits.len().div_ceil(EMITTER_SAMPLES).max(1);
+        let samples: Vec<EmitterSample> = hits
+            .iter()
+            .step_by(stride)
+            .map(|&position| {
+                let res = sdf(position);
+                let radiance = self.pattern(pattern).color_at(
+                    self,
+                    &SurfacePoint {
+                        object: res.object,
+                        world: position,
+                        uv: res.uv(self),
+                        normal: res.normal,
+                        object_normal: res.object_normal,
+                        eye: res.normal,
+                        sdf: &|point| sdf(*point).distance.0,
+                    },
+                );
+                EmitterSample {
+                    position,
+                    normal: res.normal,
+                    radiance,
+                }
+            })
+            .collect();
+
+        let cdf: Vec<f32> = samples
+            .iter()
+            .scan(0., |sum, sample| {
+                *sum += sample.radiance.luminance();
+                Some(*sum)
+            })
+            .collect();
+        if cdf.last().is_none_or(|&total| total <= 0.) {
+            return None;
+        }
+
+        let volume = size.x * size.y * size.z;
+        let area = hits.len() as Float / EMITTER_GRID.pow(3) as Float * volume / (2. * shell);
+        Some(Emitter {
+            area: area / samples.len() as Float,
+            samples,
+            cdf,
+        })
+    }
+
+    pub fn phong(
+        &mut self,
         pattern: PatternId,
         ambient: f32,
         diffuse: f32,
@@ -297,7 +1257,7 @@ impl Scene {
         shininess: f32,
         reflective: f32,
         transparent: f32,
-        refractive_index: f32,
+        refractive_index: Float,
     ) -> MaterialId {
         self.add_material(Material::Phong {
             pattern,
@@ -306,13 +1266,173 @@ impl Scene {
             specular,
             shininess,
             reflective,
+            roughness: 0.,
+            gloss_samples: 8,
+            fresnel: true,
             transparent,
             refractive_index,
+            dispersion: 0.,
+            absorption: Color::black(),
+            priority: 0,
+            scatter: Color::black(),
+            scatter_depth: 1.,
+            film_thickness: 0.,
+            film_index: 1.33,
+            backface: Backface::default(),
+            bias: Bias::default(),
+        })
+    }
+
+    pub fn toon(&mut self, pattern: PatternId, ambient: f32, bands: u32) -> MaterialId {
+        self.add_material(Material::Toon {
+            pattern,
+            ambient,
+            bands,
+            rim: Color::black(),
+            rim_width: 0.3,
+            outline: None,
+            outline_width: 0.2,
+            backface: Backface::default(),
+            bias: Bias::default(),
+        })
+    }
+
+    pub fn matcap(&mut self, matcap: Matcap) -> MaterialId {
+        self.add_material(Material::Matcap {
+            matcap,
+            backface: Backface::default(),
+            bias: Bias::default(),
         })
     }
 
     pub fn emissive(&mut self, pattern: PatternId) -> MaterialId {
-        self.add_material(Material::Emissive { pattern })
+        self.add_material(Material::Emissive {
+            pattern,
+            backface: Backface::default(),
+            bias: Bias::default(),
+        })
+    }
+
+    /// Change how `material` shades the far side of its surface.
+    pub fn set_backface(&mut self, material: MaterialId, backface: Backface) {
+        match &mut self.materials[material.index()] {
+            Material::Phong { backface: b, .. }
+            | Material::Toon { backface: b, .. }
+            | Material::Matcap { backface: b, .. }
+            | Material::Emissive { backface: b, .. } => *b = backface,
+        }
+    }
+
+    /// Change how blurred the reflections of `material` are, and how many rays are averaged to
+    /// blur them. Only Phong materials reflect.
+    pub fn set_gloss(&mut self, material: MaterialId, roughness: f32, samples: u32) {
+        if let Material::Phong {
+            roughness: r,
+            gloss_samples: s,
+            ..
+        } = &mut self.materials[material.index()]
+        {
+            *r = roughness;
+            *s = samples;
+        }
+    }
+
+    /// Change whether opaque surfaces of `material` reflect more at grazing angles. Only Phong
+    /// materials reflect.
+    pub fn set_fresnel(&mut self, material: MaterialId, fresnel: bool) {
+        if let Material::Phong { fresnel: f, .. } = &mut self.materials[material.index()] {
+            *f = fresnel;
+        }
+    }
+
+    /// Change the color of the light that scatters through thin parts of objects made of
+    /// `material`, and how deep into them it reaches. Only Phong materials scatter light.
+    pub fn set_scatter(&mut self, material: MaterialId, scatter: Color, depth: Float) {
+        if let Material::Phong {
+            scatter: s,
+            scatter_depth: d,
+            ..
+        } = &mut self.materials[material.index()]
+        {
+            *s = scatter;
+            *d = depth;
+        }
+    }
+
+    /// Coat `material` in a thin film `thickness` nanometers thick, whose refractive index is
+    /// `index`. Only Phong materials reflect.
+    pub fn set_film(&mut self, material: MaterialId, thickness: Float, index: Float) {
+        if let Material::Phong {
+            film_thickness: t,
+            film_index: i,
+            ..
+        } = &mut self.materials[material.index()]
+        {
+            *t = thickness;
+            *i = index;
+        }
+    }
+
+    /// Change how much `material` disperses the light that refracts through it. Only Phong
+    /// materials refract light.
+    pub fn set_dispersion(&mut self, material: MaterialId, dispersion: Float) {
+        if let Material::Phong { dispersion: d, .. } = &mut self.materials[material.index()] {
+            *d = dispersion;
+        }
+    }
+
+    /// Add `rim` to the surfaces of `material` where they turn away from the eye, reaching `width`
+    /// from the silhouette. Only toon materials have rims.
+    pub fn set_rim(&mut self, material: MaterialId, rim: Color, width: f32) {
+        if let Material::Toon {
+            rim: r,
+            rim_width: w,
+            ..
+        } = &mut self.materials[material.index()]
+        {
+            *r = rim;
+            *w = width;
+        }
+    }
+
+    /// Draw a line of the `outline` color along the silhouettes of `material`, reaching `width`
+    /// from them, or no line when it's `None`. Only toon materials have outlines.
+    pub fn set_outline(&mut self, material: MaterialId, outline: Option<Color>, width: f32) {
+        if let Material::Toon {
+            outline: o,
+            outline_width: w,
+            ..
+        } = &mut self.materials[material.index()]
+        {
+            *o = outline;
+            *w = width;
+        }
+    }
+
+    /// Change how much of each channel `material` absorbs per unit of distance traveled inside
+    /// it. Only Phong materials refract light.
+    pub fn set_absorption(&mut self, material: MaterialId, absorption: Color) {
+        if let Material::Phong { absorption: a, .. } = &mut self.materials[material.index()] {
+            *a = absorption;
+        }
+    }
+
+    /// Change the priority of `material` where transparent objects overlap, which are filled by
+    /// the medium with the highest priority. Only Phong materials refract light.
+    pub fn set_priority(&mut self, material: MaterialId, priority: u32) {
+        if let Material::Phong { priority: p, .. } = &mut self.materials[material.index()] {
+            *p = priority;
+        }
+    }
+
+    /// Change how far rays leaving the surfaces of `material` start from them.
+    pub fn set_bias(&mut self, material: MaterialId, bias: Bias) {
+        match &mut self.materials[material.index()] {
+            Material::Phong { bias: b, .. }
+            | Material::Toon { bias: b, .. }
+            | Material::Matcap { bias: b, .. }
+            | Material::Emissive { bias: b, .. } => *b = bias,
+        }
     }
 
     #[inline]
@@ -322,12 +1442,91 @@ impl Scene {
         id
     }
 
-    pub fn point_light(&mut self, position: Point3<f32>, color: Color) -> LightId {
-        self.add_light(Light::Point { position, color })
+    pub fn point_light(
+        &mut self,
+        position: Point3<Float>,
+        color: Color,
+        intensity: f32,
+        falloff: Falloff,
+    ) -> LightId {
+        self.add_light(Light::Point {
+            position,
+            color,
+            intensity,
+            falloff,
+            links: LightLinks::default(),
+        })
+    }
+
+    pub fn diffuse_light(&mut self, color: Color, horizon: Color, intensity: f32) -> LightId {
+        self.add_light(Light::Diffuse {
+            color,
+            horizon,
+            intensity,
+            links: LightLinks::default(),
+        })
+    }
+
+    /// Limit the light to the surfaces reached through the nodes in `include`, when it's not
+    /// empty, and keep it off the surfaces reached through those in `exclude`.
+    pub fn link_light(
+        &mut self,
+        LightId(id): LightId,
+        include: &[NodeId],
+        exclude: &[NodeId],
+    ) -> Result<()> {
+        let links = LightLinks {
+            include: if include.is_empty() {
+                None
+            } else {
+                Some(self.link_mask(include)?)
+            },
+            exclude: self.link_mask(exclude)?,
+        };
+
+        match &mut self.lights[id as usize] {
+            Light::Diffuse { links: old, .. } | Light::Point { links: old, .. } => *old = links,
+        }
+        Ok(())
+    }
+
+    /// The bits of `nodes` in [`SDFResult::links`], giving each node a bit the first time that
+    /// it's linked.
+    fn link_mask(&mut self, nodes: &[NodeId]) -> Result<u64> {
+        let mut mask = 0;
+        for &NodeId(node) in nodes {
+            let node = node as usize;
+            if self.linked.len() <= node {
+                self.linked.resize(node + 1, 0);
+            }
+            if self.linked[node] == 0 {
+                let used = self.linked.iter().filter(|bit| **bit != 0).count();
+                if used == MAX_LINKED_NODES {
+                    bail!("Lights can be linked to at most {} nodes", MAX_LINKED_NODES);
+                }
+                self.linked[node] = 1 << used;
+            }
+            mask |= self.linked[node];
+        }
+        Ok(mask)
     }
 
-    pub fn diffuse_light(&mut self, color: Color) -> LightId {
-        self.add_light(Light::Diffuse { color })
+    /// The bit that `id` sets in [`SDFResult::links`], if it's named by a light's links.
+    #[inline]
+    fn link_bit(&self, NodeId(id): NodeId) -> u64 {
+        self.linked.get(id as usize).copied().unwrap_or(0)
+    }
+
+    /// The nodes under any of `roots`, including the roots themselves.
+    fn descendants(&self, roots: &[NodeId]) -> BTreeSet<NodeId> {
+        let mut found = BTreeSet::new();
+        let mut stack = roots.to_vec();
+        while let Some(id) = stack.pop() {
+            if found.insert(id) {
+                stack.extend(self.node(id).children());
+            }
+        }
+        found
     }
 
     #[inline]
@@ -363,7 +1562,51 @@ impl Scene {
     }
 
     pub fn transform_pat(&mut self, transform: Transform, pattern: PatternId) -> PatternId {
-        self.add_pattern(Pattern::Transform { transform, pattern })
+        self.add_pattern(Pattern::Transform {
+            transform: Box::new(transform),
+            pattern,
+        })
+    }
+
+    pub fn ramp(&mut self, axis: Vector3<Float>, stops: Vec<(Float, Color)>) -> PatternId {
+        self.add_pattern(Pattern::Ramp { axis, stops })
+    }
+
+    pub fn facing(&mut self, facing: PatternId, edge: PatternId, power: Float) -> PatternId {
+        self.add_pattern(Pattern::Facing {
+            facing,
+            edge,
+            power,
+        })
+    }
+
+    pub fn triplanar(&mut self, scale: Float, pattern: PatternId) -> PatternId {
+        self.add_pattern(Pattern::Triplanar { scale, pattern })
+    }
+
+    pub fn contours(
+        &mut self,
+        node: NodeId,
+        spacing: Float,
+        width: Float,
+        first: PatternId,
+        line: PatternId,
+    ) -> PatternId {
+        self.add_pattern(Pattern::Contours {
+            node,
+            spacing,
+            width,
+            first,
+            line,
+        })
+    }
+
+    pub fn curvature(&mut self, radius: Float, pattern: PatternId) -> PatternId {
+        self.add_pattern(Pattern::Curvature { radius, pattern })
+    }
+
+    pub fn uv(&mut self, pattern: PatternId) -> PatternId {
+        self.add_pattern(Pattern::Uv { pattern })
     }
 }
 
@@ -396,13 +1639,51 @@ impl Prim {
             }
 
             &Prim::Triangle { a, b, c, .. } => BoundingBox::new(a, b).union_point(&c),
+
+            &Prim::Capsule { a, b, radius } => {
+                let r = Vector3::repeat(radius);
+                BoundingBox::new(a.inf(&b) - r, a.sup(&b) + r)
+            }
+
+            // A twisted profile sweeps out the disc that contains it.
+            Prim::Extrude {
+                profile,
+                depth,
+                twist,
+            } => {
+                let (min, max) = if *twist == 0. {
+                    profile.bounds()
+                } else {
+                    let r = profile.radius();
+                    (Point2::new(-r, -r), Point2::new(r, r))
+                };
+                BoundingBox::new(
+                    Point3::new(min.x, min.y, -depth / 2.),
+                    Point3::new(max.x, max.y, depth / 2.),
+                )
+            }
+
+            Prim::Revolve { profile, offset } => {
+                let (min, max) = profile.bounds();
+                let rad = (min.x + offset).abs().max((max.x + offset).abs());
+                BoundingBox::new(Point3::new(-rad, min.y, -rad), Point3::new(rad, max.y, rad))
+            }
+
+            Prim::Metaballs { metaballs } => metaballs.bounding_box(),
+
+            Prim::Fractal { fractal } => {
+                let r = Vector3::repeat(fractal.radius());
+                BoundingBox::new(Point3::from(-r), Point3::from(r))
+            }
+
+            Prim::Custom { sdf, .. } => sdf.bounding_box(),
         }
     }
 
     /// Compute the distance from the current position of the ray to the primitive object. As
     /// primitives are all centered at the origin, there is no need to return more information than
     /// the distance.
-    pub fn sdf(&self, p: &Point3<f32>) -> Distance {
+    pub fn sdf(&self, p: &Point3<Float>) -> Distance {
         let pv = Vector3::new(p.x, p.y, p.z);
         match self {
             Prim::Plane { normal } => Distance(pv.dot(normal)),
@@ -425,7 +1706,7 @@ impl Prim {
                 return Distance(q.norm() - radius);
             }
 
-            Prim::Triangle { a, b, c, n } => {
+            Prim::Triangle { a, b, c, n, .. } => {
                 let ba = b - a;
                 let cb = c - b;
                 let ac = a - c;
@@ -439,21 +1720,67 @@ impl Prim {
                     + ac.cross(&n).dot(&pc).signum()
                     < 2.0
                 {
-                    let x = ba * f32::clamp(ba.dot(&pa) / ba.dot(&ba), 0.0, 1.0) - pa;
-                    let y = cb * f32::clamp(cb.dot(&pb) / cb.dot(&cb), 0.0, 0.0) - pb;
-                    let z = ac * f32::clamp(ac.dot(&pc) / ac.dot(&ac), 0.0, 0.0) - pc;
+                    let x = ba * Float::clamp(ba.dot(&pa) / ba.dot(&ba), 0.0, 1.0) - pa;
+                    let y = cb * Float::clamp(cb.dot(&pb) / cb.dot(&cb), 0.0, 0.0) - pb;
+                    let z = ac * Float::clamp(ac.dot(&pc) / ac.dot(&ac), 0.0, 0.0) - pc;
                     x.dot(&x).min(y.dot(&y)).min(z.dot(&z))
                 } else {
                     n.dot(&pa).powi(2) / n.dot(&n)
                 };
 
-                Distance(f32::sqrt(v))
+                Distance(Float::sqrt(v))
+            }
+
+            Prim::Capsule { a, b, radius } => {
+                let (_, closest) = segment_closest(a, b, p);
+                Distance((p - closest).norm() - radius)
+            }
+
+            Prim::Extrude {
+                profile,
+                depth,
+                twist,
+            } => {
+                // Turning the point back by the twist at its depth stretches distances across the
+                // profile, by at most how fast its furthest point moves along z.
+                let rate = twist / depth;
+                let (sin, cos) = (-rate * p.z).sin_cos();
+                let q = Point2::new(cos * p.x - sin * p.y, sin * p.x + cos * p.y);
+                let w = Vector2::new(profile.sdf(&q), p.z.abs() - depth / 2.);
+                let stretch = Float::hypot(1., rate * profile.radius());
+                Distance((w.max().min(0.) + w.map(|c| c.max(0.)).norm()) / stretch)
+            }
+
+            Prim::Revolve { profile, offset } => {
+                Distance(profile.sdf(&Point2::new(pv.xz().norm() - offset, p.y)))
             }
+
+            Prim::Metaballs { metaballs } => Distance(metaballs.sdf(p)),
+
+            Prim::Fractal { fractal } => Distance(fractal.sdf(p)),
+
+            Prim::Custom { sdf, .. } => Distance(sdf.sdf(p)),
         }
     }
 
     /// Compute the normal for the primitive when possible.
-    pub fn normal(&self, p: &Point3<f32>) -> Option<Unit<Vector3<f32>>> {
+    /// The normal to shade the surface at `p` with, when it isn't the geometric normal.
+    pub fn shading_normal(&self, p: &Point3<Float>) -> Option<Unit<Vector3<Float>>> {
+        match self {
+            Prim::Triangle {
+                normals: Some([na, nb, nc]),
+                ..
+            } => {
+                let uv = self.uv(p);
+                let blend =
+                    na.as_ref() * (1. - uv.x - uv.y) + nb.as_ref() * uv.x + nc.as_ref() * uv.y;
+                Unit::try_new(blend, Float::default_epsilon())
+            }
+            _ => None,
+        }
+    }
+
+    pub fn normal(&self, p: &Point3<Float>) -> Option<Unit<Vector3<Float>>> {
         match self {
             // The plane knows its normal already
             Prim::Plane { normal } => Some(normal.clone()),
@@ -463,23 +1790,184 @@ impl Prim {
 
             Prim::Triangle { n, .. } => Some(n.clone()),
 
-            _ => None,
+            // The gradient points away from the closest point on the segment, which is undefined on
+            // the segment itself.
+            Prim::Capsule { a, b, .. } => {
+                let (_, closest) = segment_closest(a, b, p);
+                Unit::try_new(p - closest, 0.)
+            }
+
+            Prim::Extrude { .. } | Prim::Revolve { .. } | Prim::Fractal { .. } => None,
+
+            Prim::Metaballs { metaballs } => metaballs.normal(p),
+
+            Prim::Custom { sdf, .. } => sdf.normal(p),
+
+            // Outside the box the gradient points away from the closest point on its surface, and
+            // inside it's the axis of the closest face.
+            &Prim::Box {
+                width,
+                height,
+                depth,
+            } => {
+                let q = p.coords.abs() - Vector3::new(width, height, depth);
+                let dir = if q.max() > 0. {
+                    q.map(|c| c.max(0.))
+                } else if q.x >= q.y && q.x >= q.z {
+                    Vector3::x()
+                } else if q.y >= q.z {
+                    Vector3::y()
+                } else {
+                    Vector3::z()
+                };
+                Unit::try_new(dir.component_mul(&p.coords.map(Float::signum)), 0.)
+            }
+
+            // The gradient points away from the closest point on the ring through the middle of
+            // the torus, which is undefined on its axis.
+            &Prim::Torus { hole, .. } => {
+                let ring = p.xz().coords.try_normalize(0.)? * hole;
+                Unit::try_new(p.coords - Vector3::new(ring.x, 0., ring.y), 0.)
+            }
+        }
+    }
+
+    /// The texture coordinates of the point on the surface closest to `p`. They cover `[0, 1]`
+    /// around spheres and tori, and across each face of a box, while planes repeat every unit.
+    pub fn uv(&self, p: &Point3<Float>) -> Point2<Float> {
+        // The fraction of a turn from the x axis towards the z axis.
+        let turn = |x: Float, z: Float| 0.5 + Float::atan2(z, x) / (2. * consts::PI);
+
+        match *self {
+            Prim::Plane { normal } => {
+                let tangent = if normal.x.abs() > 0.9 {
+                    Vector3::y()
+                } else {
+                    Vector3::x()
+                };
+                let tangent = (tangent - normal.as_ref() * tangent.dot(&normal)).normalize();
+                let bitangent = tangent.cross(&normal);
+                Point2::new(p.coords.dot(&tangent), p.coords.dot(&bitangent))
+            }
+
+            Prim::Sphere { .. } => {
+                let dir = p.coords.try_normalize(0.).unwrap_or_else(Vector3::y);
+                Point2::new(
+                    turn(dir.x, dir.z),
+                    0.5 + dir.y.clamp(-1., 1.).asin() / consts::PI,
+                )
+            }
+
+            Prim::Box {
+                width,
+                height,
+                depth,
+            } => {
+                // Each face is mapped from the two axes that run across it.
+                let span = |x: Float, size: Float| 0.5 + 0.5 * x / size;
+                let q = p
+                    .coords
+                    .abs()
+                    .component_div(&Vector3::new(width, height, depth));
+                if q.x >= q.y && q.x >= q.z {
+                    Point2::new(span(p.z, depth), span(p.y, height))
+                } else if q.y >= q.z {
+                    Point2::new(span(p.x, width), span(p.z, depth))
+                } else {
+                    Point2::new(span(p.x, width), span(p.y, height))
+                }
+            }
+
+            Prim::Torus { hole, .. } => {
+                Point2::new(turn(p.x, p.z), turn(p.xz().coords.norm() - hole, p.y))
+            }
+
+            // Along the segment, and around it from a direction across it.
+            Prim::Capsule { ref a, ref b, .. } => {
+                let (t, closest) = segment_closest(a, b, p);
+                let axis = (b - a).try_normalize(0.).unwrap_or_else(Vector3::y);
+                let across = if axis.x.abs() > 0.9 {
+                    Vector3::y()
+                } else {
+                    Vector3::x()
+                };
+                let across = (across - axis * across.dot(&axis)).normalize();
+                let around = p - closest;
+                Point2::new(
+                    t,
+                    turn(around.dot(&across), around.dot(&axis.cross(&across))),
+                )
+            }
+
+            // Barycentric coordinates, which are the weights of `b` and `c`.
+            Prim::Triangle { a, b, c, .. } => {
+                let ab = b - a;
+                let ac = c - a;
+                let ap = p - a;
+                let (d00, d01, d11) = (ab.dot(&ab), ab.dot(&ac), ac.dot(&ac));
+                let (d20, d21) = (ap.dot(&ab), ap.dot(&ac));
+                let denom = d00 * d11 - d01 * d01;
+                if denom == 0. {
+                    return Point2::origin();
+                }
+                Point2::new(
+                    (d11 * d20 - d01 * d21) / denom,
+                    (d00 * d21 - d01 * d20) / denom,
+                )
+            }
+
+            Prim::Extrude { .. } | Prim::Metaballs { .. } => p.xy(),
+
+            Prim::Fractal { ref fractal } => fractal.uv(p),
+
+            Prim::Custom { ref sdf, .. } => sdf.uv(p),
+
+            Prim::Revolve { .. } => Point2::new(turn(p.x, p.z), p.y),
         }
     }
 }
 
+/// The closest point to `p` on the segment from `a` to `b`, and how far along the segment it is.
+fn segment_closest(
+    a: &Point3<Float>,
+    b: &Point3<Float>,
+    p: &Point3<Float>,
+) -> (Float, Point3<Float>) {
+    let ab = b - a;
+    let t = ((p - a).dot(&ab) / ab.norm_squared()).clamp(0., 1.);
+    let t = if t.is_nan() { 0. } else { t };
+    (t, a + ab * t)
+}
+
 /// Returns the difference between the right and left distances, `h` which is the linear
 /// interpolation value between the two distances, and the composite distance.
-fn smooth_union_parts(k: f32, left: Distance, right: Distance) -> (f32, f32, Distance) {
+fn smooth_union_parts(k: Float, left: Distance, right: Distance) -> (Float, Float, Distance) {
     let diff = right.0 - left.0;
 
     let h = (0.5 + 0.5 * diff / k).clamp(0., 1.);
     let factor = k * h * (1.0 - h);
 
-    (diff, h, Distance(f32::mix(right.0, left.0, h) - factor))
+    (diff, h, Distance(Float::mix(right.0, left.0, h) - factor))
+}
+
+/// The bounds of the smooth union of nodes with bounds `left` and `right`. Blending pulls the
+/// surface out by at most `k / 4`, and only where the distances to the two nodes are within `k`
+/// of each other, so the fillet stays near the gap between the nodes rather than growing the
+/// whole box.
+fn smooth_union_bounds(k: Float, left: &BoundingBox, right: &BoundingBox) -> BoundingBox {
+    let union = left.union(right);
+    let reach = 1.25 * k;
+    let fillet = left
+        .grow(reach)
+        .intersect(&right.grow(reach))
+        .intersect(&union.grow(0.25 * k));
+    union.union(&fillet)
 }
 
 impl Node {
+    /// Bounds that contain every point where the node's distance is negative. Nodes that change
+    /// the distances of their children pad or move their children's bounds to match, as groups
+    /// skip the children whose bounds a ray misses.
     pub fn bounding_box(&self, scene: &Scene) -> BoundingBox {
         match self {
             Node::Prim { prim } => prim.bounding_box(),
@@ -490,8 +1978,8 @@ impl Node {
 
             Node::Subtract { left, .. } => scene.bounding_box(*left).clone(),
 
-            Node::SmoothUnion { left, right, .. } => {
-                scene.bounding_box(*left).union(scene.bounding_box(*right))
+            Node::SmoothUnion { k, left, right } => {
+                smooth_union_bounds(*k, scene.bounding_box(*left), scene.bounding_box(*right))
             }
 
             Node::Intersect { nodes } => {
@@ -503,21 +1991,37 @@ impl Node {
             Node::Transform { transform, node } => scene.bounding_box(*node).apply(transform),
 
             Node::Material { node, .. } => scene.bounding_box(*node).clone(),
+
+            Node::Volume { node, .. } => scene.bounding_box(*node).clone(),
+
+            Node::Displace { displacement, node } => {
+                scene.bounding_box(*node).grow(displacement.amount.max(0.))
+            }
+
+            Node::Grid { grid } => grid.bounding_box(),
+
+            Node::Heightfield { field } => field.bounding_box(),
         }
     }
 
     pub fn sdf(&self, scene: &Scene, id: NodeId, ray: &Ray) -> SDFResult {
-        match self {
+        let mut res = match self {
             Node::Prim { prim } => {
                 let distance = prim.sdf(&ray.position);
+                let normal = prim
+                    .normal(&ray.position)
+                    .unwrap_or_else(|| self.normal_sdf(scene, id, ray.clone()));
                 SDFResult {
                     id,
                     material: None,
+                    links: 0,
                     object: ray.position,
-                    normal: prim
-                        .normal(&ray.position)
-                        .unwrap_or_else(|| self.normal_sdf(scene, ray.clone(), distance)),
+                    normal,
+                    object_normal: normal,
+                    shading: prim.shading_normal(&ray.position),
                     distance,
+                    prim: id,
+                    local: ray.position,
                 }
             }
 
@@ -526,6 +2030,8 @@ impl Node {
 
                 res.distance.0 = -res.distance.0;
                 res.normal = -res.normal;
+                res.object_normal = -res.object_normal;
+                res.shading = res.shading.map(|normal| -normal);
 
                 res
             }
@@ -544,6 +2050,7 @@ impl Node {
                 if *union {
                     res.id = id;
                     res.object = ray.position;
+                    res.object_normal = res.normal;
                 }
 
                 res
@@ -558,10 +2065,13 @@ impl Node {
                 if left.distance < right.distance {
                     right.object = ray.position;
                     right.normal = -right.normal;
+                    right.object_normal = right.normal;
+                    right.shading = right.shading.map(|normal| -normal);
                     right.material = right.material.or_else(|| left.material);
                     right
                 } else {
                     left.object = ray.position;
+                    left.object_normal = left.normal;
                     left
                 }
             }
@@ -585,15 +2095,19 @@ impl Node {
                 if h < 1. {
                     if h == 0. {
                         left.normal = right.normal;
+                        left.shading = right.shading;
                     } else {
+                        // The blend is shaded by its own shape.
+                        left.shading = None;
                         left.normal = right
                             .normal
-                            .try_slerp(&left.normal, h, f32::default_epsilon())
-                            .unwrap_or_else(|| self.normal_sdf(scene, ray.clone(), left.distance));
+                            .try_slerp(&left.normal, h, Float::default_epsilon())
+                            .unwrap_or_else(|| self.normal_sdf(scene, id, ray.clone()));
                     }
                 }
 
                 left.object = ray.position;
+                left.object_normal = left.normal;
 
                 left
             }
@@ -607,71 +2121,235 @@ impl Node {
                     .unwrap();
 
                 res.object = ray.position;
+                res.object_normal = res.normal;
 
                 res
             }
 
             Node::Transform { transform, node } => {
                 let mut res = scene.node(*node).sdf(scene, *node, &ray.invert(transform));
-                res.normal = res.normal.apply(transform);
+                res.normal = transform.apply_normal(&res.normal);
+                res.shading = res.shading.map(|normal| transform.apply_normal(&normal));
                 res.distance.0 *= transform.scale_factor();
                 res
             }
 
             Node::Material { material, node } => {
                 let mut res = scene.node(*node).sdf(scene, *node, ray);
-                res.material = Some(*material);
+                res.material = res.material.or(Some(*material));
                 res
             }
-        }
-    }
-
-    /// Compute the normal by using the SDF. Useful as an intermediate for combination nodes that
-    /// don't have a closed form normal computation.
-    fn normal_sdf(&self, scene: &Scene, mut ray: Ray, dist: Distance) -> Unit<Vector3<f32>> {
-        let p = ray.position;
-        let offset = Vector3::new(0.00001, 0.0, 0.0);
 
-        ray.position = p - offset.xyy();
-        let px = self.fast_sdf(scene, &ray).distance;
+            Node::Volume { .. } => SDFResult::new(id, ray.position),
 
-        ray.position = p - offset.yxy();
-        let py = self.fast_sdf(scene, &ray).distance;
+            Node::Displace { displacement, node } => {
+                let mut res = scene.node(*node).sdf(scene, *node, ray);
+                if let Some(distance) = displacement.far(res.distance.0) {
+                    res.distance.0 = distance;
+                } else {
+                    res.distance.0 = displacement.near(scene, *node, ray, &res);
+                    res.normal = self.normal_sdf(scene, id, ray.clone());
+                    res.shading = None;
+                }
+                res
+            }
 
-        ray.position = p - offset.yyx();
-        let pz = self.fast_sdf(scene, &ray).distance;
-        let (res, norm) =
-            Unit::new_and_get(Vector3::new(dist.0 - px.0, dist.0 - py.0, dist.0 - pz.0));
+            Node::Grid { grid } => {
+                let normal = grid.normal(&ray.position);
+                SDFResult {
+                    id,
+                    material: None,
+                    links: 0,
+                    object: ray.position,
+                    normal,
+                    object_normal: normal,
+                    shading: None,
+                    distance: Distance(grid.sdf(&ray.position)),
+                    prim: id,
+                    local: ray.position,
+                }
+            }
 
-        // This is a really unfortunate bug: occassionally the normal produced will be [0, 0, 0]
-        // using the sdf approach, which will normalize to a vector of NaN. That will in turn mess
-        // everything up down the line, producing weird white artifacts where this problem occurs.
-        // As a gross hacky workaround, detect this case and return [0,1,0] when it happens.
-        if norm == 0.0 {
-            Unit::new_unchecked(Vector3::new(0.0, 1.0, 0.0))
-        } else {
-            res
+            Node::Heightfield { field } => {
+                let normal = self.normal_sdf(scene, id, ray.clone());
+                SDFResult {
+                    id,
+                    material: None,
+                    links: 0,
+                    object: ray.position,
+                    normal,
+                    object_normal: normal,
+                    shading: None,
+                    distance: Distance(field.sdf(&ray.position)),
+                    prim: id,
+                    local: ray.position,
+                }
+            }
+        };
+
+        if scene.finite_check != FiniteCheck::Off {
+            // Combinations of nodes use an infinite distance when there's nothing nearby, but it
+            // never makes sense for a primitive.
+            let valid = match self {
+                Node::Prim { .. } => res.distance.0.is_finite(),
+                _ => !res.distance.0.is_nan(),
+            };
+
+            if !valid {
+                scene.non_finite.record(id, &ray.position);
+            }
         }
+
+        res.links |= scene.link_bit(id);
+        res
     }
 
-    // A version of `sdf` that only computes the distance and material information. Useful for
-    // things like lighting calculations.
-    pub fn fast_sdf(&self, scene: &Scene, ray: &Ray) -> FastSDFResult {
+    /// The name of the node's form in the scene description language.
+    pub fn name(&self) -> &str {
         match self {
-            Node::Prim { prim } => FastSDFResult {
-                distance: prim.sdf(&ray.position),
-                material: None,
+            Node::Prim { prim } => match prim {
+                Prim::Plane { .. } => "plane",
+                Prim::Sphere { .. } => "sphere",
+                Prim::Box { .. } => "box",
+                Prim::Torus { .. } => "torus",
+                Prim::Triangle { .. } => "triangle",
+                Prim::Capsule { .. } => "capsule",
+                Prim::Extrude { .. } => "extrude",
+                Prim::Revolve { .. } => "revolve",
+                Prim::Metaballs { .. } => "metaballs",
+                Prim::Fractal { .. } => "fractal",
+                Prim::Custom { name, .. } => name,
             },
+            Node::Invert { .. } => "invert",
+            Node::Group { union: false, .. } => "group",
+            Node::Group { union: true, .. } => "union",
+            Node::Subtract { .. } => "subtract",
+            Node::SmoothUnion { .. } => "smooth-union",
+            Node::Intersect { .. } => "intersect",
+            Node::Transform { .. } => "transform",
+            Node::Material { .. } => "paint",
+            Node::Volume { .. } => "volume",
+            Node::Displace { .. } => "displace",
+            Node::Grid { .. } => "grid",
+            Node::Heightfield { .. } => "heightfield",
+        }
+    }
 
-            Node::Invert { node } => {
-                let mut res = scene.node(*node).fast_sdf(scene, ray);
-                res.distance.0 = -res.distance.0;
-                res
+    /// The nodes directly under this one.
+    pub fn children(&self) -> Vec<NodeId> {
+        match self {
+            Node::Prim { .. } | Node::Grid { .. } | Node::Heightfield { .. } => Vec::new(),
+            Node::Group { nodes, .. } => nodes.iter().copied().collect(),
+            Node::Intersect { nodes } => nodes.clone(),
+            Node::Subtract { left, right } | Node::SmoothUnion { left, right, .. } => {
+                vec![*left, *right]
+            }
+            Node::Invert { node }
+            | Node::Transform { node, .. }
+            | Node::Material { node, .. }
+            | Node::Volume { node, .. }
+            | Node::Displace { node, .. } => vec![*node],
+        }
+    }
+
+    /// A version of `sdf` for rays that are traveling through transparent objects. The members of
+    /// groups are treated as separate boundaries, so the distance is to the closest surface of any
+    /// of them, and is negative when that surface is one the ray is inside of.
+    pub fn boundary_sdf(&self, scene: &Scene, id: NodeId, ray: &Ray) -> SDFResult {
+        let mut res = match self {
+            Node::Group {
+                union: false,
+                nodes,
+            } => nodes.fold_intersections(ray, SDFResult::new(id, ray.position), |acc, &id| {
+                let res = scene.node(id).boundary_sdf(scene, id, ray);
+                if res.distance.0.abs() < acc.distance.0.abs() {
+                    res
+                } else {
+                    acc
+                }
+            }),
+
+            Node::Transform { transform, node } => {
+                let mut res = scene
+                    .node(*node)
+                    .boundary_sdf(scene, *node, &ray.invert(transform));
+                res.normal = transform.apply_normal(&res.normal);
+                res.shading = res.shading.map(|normal| transform.apply_normal(&normal));
+                res.distance.0 *= transform.scale_factor();
+                res
+            }
+
+            Node::Material { material, node } => {
+                let mut res = scene.node(*node).boundary_sdf(scene, *node, ray);
+                res.material = res.material.or(Some(*material));
+                res
+            }
+
+            _ => self.sdf(scene, id, ray),
+        };
+
+        res.links |= scene.link_bit(id);
+        res
+    }
+
+    /// Compute the normal by using the SDF. Useful as an intermediate for combination nodes that
+    /// don't have a closed form normal computation. The gradient is estimated by sampling the
+    /// corners of a tetrahedron around the point, which is centered unlike one-sided differences and
+    /// needs one fewer sample than central differences.
+    fn normal_sdf(&self, scene: &Scene, id: NodeId, mut ray: Ray) -> Unit<Vector3<Float>> {
+        let p = ray.position;
+
+        // Scale the offset with the distance from the origin, as smaller offsets get lost in the
+        // precision of the position, and with the size of the node, so that the tetrahedron
+        // doesn't step over the details of small nodes or into the rounding of large ones.
+        let size = scene_scale(scene.bounding_box(id)).unwrap_or(1.);
+        let eps = NORMAL_EPSILON * p.coords.amax().max(size);
+
+        let mut n = Vector3::zeros();
+        for k in [
+            Vector3::new(1., -1., -1.),
+            Vector3::new(-1., -1., 1.),
+            Vector3::new(-1., 1., -1.),
+            Vector3::new(1., 1., 1.),
+        ] {
+            ray.position = p + k * eps;
+            n += k * self.fast_sdf(scene, &ray, Float::NEG_INFINITY).distance.0;
+        }
+
+        // The samples can all be equal, for example when the point is far from anything and the
+        // distance is infinite, and normalizing would produce a vector of NaN that turns into white
+        // artifacts down the line. Fall back on [0,1,0] when that happens.
+        Unit::try_new(n, 0.)
+            .filter(|n| n.x.is_finite())
+            .unwrap_or_else(|| Unit::new_unchecked(Vector3::new(0.0, 1.0, 0.0)))
+    }
+
+    // A version of `sdf` that only computes the distance and material information. Useful for
+    // things like lighting calculations.
+    //
+    // Once any surface is found closer than `threshold`, the result may be that surface rather
+    // than the closest one, which is all that a ray checking for a hit needs. Pass
+    // `Float::NEG_INFINITY` for the exact distance.
+    pub fn fast_sdf(&self, scene: &Scene, ray: &Ray, threshold: Float) -> FastSDFResult {
+        match self {
+            Node::Prim { prim } => FastSDFResult {
+                distance: prim.sdf(&ray.position),
+                material: None,
+            },
+
+            Node::Invert { node } => {
+                let mut res = scene.node(*node).fast_sdf(scene, ray, Float::NEG_INFINITY);
+                res.distance.0 = -res.distance.0;
+                res
             }
 
             Node::Group { nodes, .. } => {
                 nodes.fold_intersections(ray, FastSDFResult::new(), |acc, &id| {
-                    let res = scene.node(id).fast_sdf(scene, ray);
+                    if acc.distance.0 < threshold {
+                        return acc;
+                    }
+
+                    let res = scene.node(id).fast_sdf(scene, ray, threshold);
                     if res.distance < acc.distance {
                         res
                     } else {
@@ -681,8 +2359,8 @@ impl Node {
             }
 
             Node::Subtract { left, right } => {
-                let left = scene.node(*left).fast_sdf(scene, ray);
-                let mut right = scene.node(*right).fast_sdf(scene, ray);
+                let left = scene.node(*left).fast_sdf(scene, ray, Float::NEG_INFINITY);
+                let mut right = scene.node(*right).fast_sdf(scene, ray, Float::NEG_INFINITY);
 
                 right.distance.0 = -right.distance.0;
                 if left.distance < right.distance {
@@ -692,9 +2370,10 @@ impl Node {
                 }
             }
 
+            // Blending only brings the surface closer, so the children may stop early too.
             Node::SmoothUnion { k, left, right } => {
-                let mut left = scene.node(*left).fast_sdf(scene, ray);
-                let right = scene.node(*right).fast_sdf(scene, ray);
+                let mut left = scene.node(*left).fast_sdf(scene, ray, threshold);
+                let right = scene.node(*right).fast_sdf(scene, ray, threshold);
 
                 let (diff, _, dist) = smooth_union_parts(*k, left.distance, right.distance);
 
@@ -710,17 +2389,92 @@ impl Node {
             Node::Intersect { nodes } => nodes
                 .iter()
                 .copied()
-                .map(|id| scene.node(id).fast_sdf(scene, ray))
+                .map(|id| scene.node(id).fast_sdf(scene, ray, Float::NEG_INFINITY))
                 .max_by_key(|res| res.distance)
                 .unwrap(),
 
             Node::Transform { transform, node } => {
-                let mut res = scene.node(*node).fast_sdf(scene, &ray.invert(transform));
+                let scale = transform.scale_factor();
+                let mut res =
+                    scene
+                        .node(*node)
+                        .fast_sdf(scene, &ray.invert(transform), threshold / scale);
+                res.distance.0 *= scale;
+                res
+            }
+
+            Node::Material { node, .. } => scene.node(*node).fast_sdf(scene, ray, threshold),
+
+            Node::Volume { .. } => FastSDFResult::new(),
+
+            Node::Displace { displacement, node } => {
+                let child = scene.node(*node);
+                let mut res = child.fast_sdf(scene, ray, Float::NEG_INFINITY);
+                res.distance.0 = match displacement.far(res.distance.0) {
+                    Some(distance) => distance,
+                    None => displacement.near(scene, *node, ray, &child.sdf(scene, *node, ray)),
+                };
+                res
+            }
+
+            Node::Grid { grid } => FastSDFResult {
+                distance: Distance(grid.sdf(&ray.position)),
+                material: None,
+            },
+
+            Node::Heightfield { field } => FastSDFResult {
+                distance: Distance(field.sdf(&ray.position)),
+                material: None,
+            },
+        }
+    }
+
+    /// The distance to the closest volume, ignoring all surfaces. Volumes that appear in
+    /// combinations with other nodes are unioned together.
+    pub fn volume_sdf<'a>(&'a self, scene: &'a Scene, ray: &Ray) -> VolumeSDFResult<'a> {
+        let closest = |acc: VolumeSDFResult<'a>, id: NodeId| {
+            let res = scene.node(id).volume_sdf(scene, ray);
+            if res.distance < acc.distance {
+                res
+            } else {
+                acc
+            }
+        };
+
+        match self {
+            Node::Prim { .. } | Node::Grid { .. } | Node::Heightfield { .. } => {
+                VolumeSDFResult::new()
+            }
+
+            Node::Invert { node } | Node::Material { node, .. } | Node::Displace { node, .. } => {
+                scene.node(*node).volume_sdf(scene, ray)
+            }
+
+            Node::Group { nodes, .. } => {
+                nodes.fold_intersections(ray, VolumeSDFResult::new(), |acc, &id| closest(acc, id))
+            }
+
+            Node::Subtract { left, right } | Node::SmoothUnion { left, right, .. } => {
+                closest(closest(VolumeSDFResult::new(), *left), *right)
+            }
+
+            Node::Intersect { nodes } => {
+                nodes.iter().copied().fold(VolumeSDFResult::new(), closest)
+            }
+
+            Node::Transform { transform, node } => {
+                let mut res = scene.node(*node).volume_sdf(scene, &ray.invert(transform));
                 res.distance.0 *= transform.scale_factor();
                 res
             }
 
-            Node::Material { node, .. } => scene.node(*node).fast_sdf(scene, ray),
+            Node::Volume { volume, node } => VolumeSDFResult {
+                distance: scene
+                    .node(*node)
+                    .fast_sdf(scene, ray, Float::NEG_INFINITY)
+                    .distance,
+                volume: Some(volume),
+            },
         }
     }
 }
@@ -729,8 +2483,8 @@ impl Mix for Distance {
     type Output = Distance;
 
     #[inline]
-    fn mix(self, b: Distance, t: f32) -> Self::Output {
-        Distance(f32::mix(self.0, b.0, t))
+    fn mix(self, b: Distance, t: Float) -> Self::Output {
+        Distance(Float::mix(self.0, b.0, t))
     }
 }
 
@@ -762,30 +2516,126 @@ impl Ord for Distance {
 
 #[derive(Debug)]
 pub enum Light {
-    /// A diffuse light, for rays that escape the scene.
-    Diffuse { color: Color },
+    /// A diffuse light, for rays that escape the scene. Rays escaping straight up see `color`,
+    /// which fades to `horizon` as their direction approaches the horizon. Both are scaled by
+    /// `intensity`.
+    Diffuse {
+        color: Color,
+        horizon: Color,
+        intensity: f32,
+        links: LightLinks,
+    },
+
+    /// A point light, positioned according to the given transform. Its color is scaled by
+    /// `intensity`, and by the falloff at the distance to the point being lit. The falloff doesn't
+    /// dim the light it adds to the ambient term of surfaces.
+    Point {
+        position: Point3<Float>,
+        color: Color,
+        intensity: f32,
+        falloff: Falloff,
+        links: LightLinks,
+    },
+}
+
+/// The surfaces that a light affects. Lights affect every surface unless they're linked to
+/// specific nodes. The nodes are kept as their bits in [`SDFResult::links`].
+#[derive(Debug, Default)]
+pub struct LightLinks {
+    /// When present, the only nodes whose surfaces are lit.
+    include: Option<u64>,
+
+    /// Nodes whose surfaces are never lit.
+    exclude: u64,
+}
+
+impl LightLinks {
+    /// True when the light affects every surface.
+    pub fn is_empty(&self) -> bool {
+        self.include.is_none() && self.exclude == 0
+    }
+
+    /// True when the light affects a surface reached through the linked nodes in `links`.
+    pub fn affects(&self, links: u64) -> bool {
+        self.include.is_none_or(|include| links & include != 0) && links & self.exclude == 0
+    }
+}
+
+/// How the light from a point light dims with distance.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Falloff {
+    /// The light is as bright at any distance.
+    None,
+
+    /// The light dims with the square of the distance, as it does physically.
+    InverseSquare,
 
-    /// A point light, positioned according to the given transform.
-    Point { position: Point3<f32>, color: Color },
+    /// The light dims with the distance raised to the exponent.
+    Exponent(Float),
+}
+
+impl Falloff {
+    /// The fraction of the light that remains at `distance` from the light.
+    pub fn attenuation(&self, distance: Float) -> f32 {
+        match self {
+            Falloff::None => 1.,
+            Falloff::InverseSquare => (1. / (distance * distance)) as f32,
+            Falloff::Exponent(exponent) => distance.powf(-exponent) as f32,
+        }
+    }
 }
 
 impl Light {
-    /// The light contribution for rays that escape the scene.
-    pub fn light_escape(&self) -> Color {
+    /// The light contribution for rays that escape the scene traveling in `direction`.
+    pub fn light_escape(&self, direction: &Vector3<Float>) -> Color {
         match self {
-            Light::Diffuse { color } => color.clone(),
+            Light::Diffuse {
+                color,
+                horizon,
+                intensity,
+                ..
+            } => horizon.mix(color, direction.y.max(0.) as f32) * *intensity,
             Light::Point { .. } => Color::black(),
         }
     }
 
-    pub fn intensity(&self) -> &Color {
+    /// The light that the light adds to the ambient term of the surfaces it affects, which is the
+    /// same everywhere.
+    pub fn ambient(&self) -> Color {
         match self {
-            Light::Diffuse { color } => color,
-            Light::Point { color, .. } => color,
+            Light::Diffuse {
+                color, intensity, ..
+            }
+            | Light::Point {
+                color, intensity, ..
+            } => color * *intensity,
         }
     }
 
-    pub fn position(&self) -> Option<Point3<f32>> {
+    /// The light arriving at `point`, from the light's direction.
+    pub fn intensity_at(&self, point: &Point3<Float>) -> Color {
+        match self {
+            Light::Diffuse {
+                color, intensity, ..
+            } => color * *intensity,
+            Light::Point {
+                position,
+                color,
+                intensity,
+                falloff,
+                ..
+            } => color * (intensity * falloff.attenuation((position - point).norm())),
+        }
+    }
+
+    pub fn links(&self) -> &LightLinks {
+        match self {
+            Light::Diffuse { links, .. } => links,
+            Light::Point { links, .. } => links,
+        }
+    }
+
+    pub fn position(&self) -> Option<Point3<Float>> {
         match self {
             Light::Diffuse { .. } => None,
             Light::Point { position, .. } => Some(position.clone()),
@@ -793,6 +2643,59 @@ impl Light {
     }
 }
 
+/// A node painted with an emissive material, which lights the rest of the scene. It's represented
+/// by points spread evenly over its surface, which are picked in proportion to their brightness.
+#[derive(Debug)]
+pub struct Emitter {
+    samples: Vec<EmitterSample>,
+
+    /// The running total of the luminance of the samples.
+    cdf: Vec<f32>,
+
+    /// The area of the surface around each sample.
+    area: Float,
+}
+
+/// A point on the surface of an [`Emitter`], in world space.
+#[derive(Debug)]
+pub struct EmitterSample {
+    pub position: Point3<Float>,
+    pub normal: Unit<Vector3<Float>>,
+    pub radiance: Color,
+}
+
+impl Emitter {
+    /// Pick a point on the emitter, along with the area it stands in for divided by the
+    /// probability of picking it.
+    pub fn sample(&self, rng: &mut impl Rng) -> (&EmitterSample, Float) {
+        let total = *self.cdf.last().unwrap();
+        let target = rng.gen::<f32>() * total;
+        let index = self
+            .cdf
+            .partition_point(|&sum| sum <= target)
+            .min(self.samples.len() - 1);
+        let sample = &self.samples[index];
+        let pdf = sample.radiance.luminance() / total;
+        (sample, self.area / pdf as Float)
+    }
+}
+
+/// A homogeneous participating medium. Light traveling through it is absorbed, and replaced by
+/// light scattered towards the viewer, which is assumed to be `color` everywhere.
+#[derive(Debug, Clone)]
+pub struct Fog {
+    pub color: Color,
+    pub density: f32,
+}
+
+impl Fog {
+    /// The color seen after light of `color` travels `distance` through the fog.
+    pub fn apply(&self, color: Color, distance: Float) -> Color {
+        let transmittance = f32::exp(-self.density * distance as f32);
+        color * transmittance + &self.color * (1. - transmittance)
+    }
+}
+
 /// Materials using the Phong reflection model.
 #[derive(Debug)]
 pub enum Material {
@@ -815,19 +2718,162 @@ pub enum Material {
         /// How reflective the surface is.
         reflective: f32,
 
+        /// How blurred reflections are, from `0` for a mirror to `1` for reflected rays spread
+        /// over the whole of the sky above the surface.
+        roughness: f32,
+
+        /// The number of reflected rays that are averaged for a rough surface.
+        gloss_samples: u32,
+
+        /// Whether opaque surfaces reflect more at grazing angles, rising from `reflective` when
+        /// seen head on to a mirror at the horizon. Transparent surfaces always do.
+        fresnel: bool,
+
         /// How transparent the object is.
         transparent: f32,
 
         /// The refractive index of the object.
-        refractive_index: f32,
+        refractive_index: Float,
+
+        /// How much the refractive index spreads between blue and red light, which splits white
+        /// light into its colors. The index of green light is `refractive_index`.
+        dispersion: Float,
+
+        /// How much of each channel is absorbed per unit of distance traveled inside the object.
+        absorption: Color,
+
+        /// Where transparent objects overlap, the medium with the highest priority is used.
+        priority: u32,
+
+        /// The color of the light that scatters beneath the surface and out of it again, which
+        /// lights thin parts of the object from behind. Black for no scattering.
+        scatter: Color,
+
+        /// How far light scatters into the object before it's mostly absorbed.
+        scatter_depth: Float,
+
+        /// The thickness in nanometers of a film coating the surface, like soap or oil, which
+        /// reflects colors that shift with the angle it's seen from. Zero for no film.
+        film_thickness: Float,
+
+        /// The refractive index of the film.
+        film_index: Float,
+
+        /// How the far side of the surface is shaded.
+        backface: Backface,
+
+        /// How far rays leaving the surface start from it.
+        bias: Bias,
+    },
+
+    /// Cel shading, which lights the surface in a few flat bands rather than a smooth gradient.
+    Toon {
+        /// The pattern of the surface.
+        pattern: PatternId,
+
+        /// The ambient reflection of this surface.
+        ambient: f32,
+
+        /// The number of bands that the diffuse light is rounded up to.
+        bands: u32,
+
+        /// The color added where the surface turns away from the eye.
+        rim: Color,
+
+        /// How far from the silhouette the rim reaches, as the cosine of the angle between the
+        /// normal and the eye.
+        rim_width: f32,
+
+        /// The color of the line drawn along the silhouette, if any.
+        outline: Option<Color>,
+
+        /// How far from the silhouette the outline reaches, as for the rim.
+        outline_width: f32,
+
+        /// How the far side of the surface is shaded.
+        backface: Backface,
+
+        /// How far rays leaving the surface start from it.
+        bias: Bias,
+    },
+
+    /// Colors looked up from a material capture by the direction of the normal, ignoring the
+    /// lights in the scene.
+    Matcap {
+        matcap: Matcap,
+
+        /// How the far side of the surface is shaded.
+        backface: Backface,
+
+        /// How far rays leaving the surface start from it.
+        bias: Bias,
     },
 
     Emissive {
         /// The emissive pattern.
         pattern: PatternId,
+
+        /// How the far side of the surface is shaded.
+        backface: Backface,
+
+        /// How far rays leaving the surface start from it.
+        bias: Bias,
     },
 }
 
+impl Material {
+    pub fn backface(&self) -> Backface {
+        match *self {
+            Material::Phong { backface, .. }
+            | Material::Toon { backface, .. }
+            | Material::Matcap { backface, .. }
+            | Material::Emissive { backface, .. } => backface,
+        }
+    }
+
+    pub fn bias(&self) -> Bias {
+        match *self {
+            Material::Phong { bias, .. }
+            | Material::Toon { bias, .. }
+            | Material::Matcap { bias, .. }
+            | Material::Emissive { bias, .. } => bias,
+        }
+    }
+}
+
+/// How a material shades the far side of its surface, which rays only reach from inside the object,
+/// such as when the camera is inside it or it's inverted.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Backface {
+    /// Shade it with the normal facing away from the viewer, so that only ambient light reaches it.
+    #[default]
+    OneSided,
+
+    /// Shade it like the near side, with the normal turned towards the viewer.
+    TwoSided,
+
+    /// Turn the normal around on both sides, for surfaces that face the wrong way.
+    Flip,
+
+    /// Leave it out, so that rays continue out of the object.
+    Cull,
+}
+
+/// How far rays that leave a surface start from it, in scene units. Starting too close finds the
+/// surface that the ray left, speckling it with shadow acne, while starting too far leaves a gap
+/// where shadows meet the objects that cast them. Thin shells and displaced surfaces may need
+/// other distances than the march uses everywhere else.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Bias {
+    /// The distance along the normal to start shadow, reflected and refracted rays from, or the
+    /// march's `min_dist` when `None`.
+    pub normal: Option<Float>,
+
+    /// The distance along shadow rays that's skipped before looking for anything in the way of
+    /// the light.
+    pub shadow: Float,
+}
+
 /// Patterns for texturing a surface with.
 #[derive(Debug)]
 pub enum Pattern {
@@ -837,6 +2883,13 @@ pub enum Pattern {
     /// A gradient based on the object's x value.
     Gradiant { first: PatternId, second: PatternId },
 
+    /// Colors interpolated along an axis, between stops that are sorted by their position. The
+    /// position of a point is its projection onto the axis, so longer axes make for shorter ramps.
+    Ramp {
+        axis: Vector3<Float>,
+        stops: Vec<(Float, Color)>,
+    },
+
     /// Stripes of two different patterns.
     Stripes { first: PatternId, second: PatternId },
 
@@ -848,64 +2901,1084 @@ pub enum Pattern {
 
     /// Transform the point before rendering the pattern.
     Transform {
-        transform: Transform,
+        transform: Box<Transform>,
         pattern: PatternId,
     },
+
+    /// Blend from `facing` where the surface faces the viewer to `edge` where it's seen edge-on, by
+    /// one minus the facing ratio raised to `power`. Higher powers keep `edge` to a thinner rim.
+    Facing {
+        facing: PatternId,
+        edge: PatternId,
+        power: Float,
+    },
+
+    /// Render the pattern on the planes facing each axis, as the point `(u, v, 0)` scaled by
+    /// `scale`, and blend the three by how closely the normal lines up with each axis.
+    Triplanar { scale: Float, pattern: PatternId },
+
+    /// Render the pattern at the surface's texture coordinates, as the point `(u, v, 0)`, so that
+    /// it follows the surface rather than cutting through the object.
+    Uv { pattern: PatternId },
+
+    /// Lines of `line` over `first` wherever the signed distance from the world space point to
+    /// `node` is within half of `width` of a multiple of `spacing`, like the contours of a map.
+    Contours {
+        node: NodeId,
+        spacing: Float,
+        width: Float,
+        first: PatternId,
+        line: PatternId,
+    },
+
+    /// Render the pattern at the point `(k, 0, 0)`, where `k` is the curvature of the surface
+    /// measured over `radius`, so that a ramp along x can darken crevices and brighten edges.
+    Curvature { radius: Float, pattern: PatternId },
+}
+
+/// The point of a surface that a pattern colors.
+#[derive(Clone, Copy)]
+pub struct SurfacePoint<'a> {
+    /// The point in object space.
+    pub object: Point3<Float>,
+
+    /// The same point in world space.
+    pub world: Point3<Float>,
+
+    /// The texture coordinates of the surface at the point.
+    pub uv: Point2<Float>,
+
+    /// The normal of the surface, in world space.
+    pub normal: Unit<Vector3<Float>>,
+
+    /// The geometric normal of the surface, in object space.
+    pub object_normal: Unit<Vector3<Float>>,
+
+    /// The direction back towards the viewer.
+    pub eye: Unit<Vector3<Float>>,
+
+    /// The signed distance from points in world space to everything that the surface belongs to,
+    /// for patterns that measure the shape around the point.
+    pub sdf: &'a dyn Fn(&Point3<Float>) -> Float,
+}
+
+impl SurfacePoint<'_> {
+    /// The same surface, with the object space point moved to `object`.
+    fn at(&self, object: Point3<Float>) -> Self {
+        Self { object, ..*self }
+    }
+
+    /// The sum of the principal curvatures of the surface, estimated from the second differences
+    /// of the distance at `radius` around the world space point. Convex surfaces are positive and
+    /// concave ones negative, and a sphere's is two over its radius.
+    pub fn curvature(&self, radius: Float) -> Float {
+        let center = (self.sdf)(&self.world);
+        let mut sum = -6. * center;
+        for axis in [Vector3::x(), Vector3::y(), Vector3::z()] {
+            let offset = axis * radius;
+            sum += (self.sdf)(&(self.world + offset)) + (self.sdf)(&(self.world - offset));
+        }
+        sum / (radius * radius)
+    }
 }
 
 impl Pattern {
-    /// Generate the color for a point in object space, along with its world normal.
-    pub fn color_at(
-        &self,
-        scene: &Scene,
-        point: &Point3<f32>,
-        normal: &Unit<Vector3<f32>>,
-    ) -> Color {
+    /// Generate the color for a point on a surface.
+    pub fn color_at(&self, scene: &Scene, surface: &SurfacePoint) -> Color {
+        let point = &surface.object;
         match self {
             Pattern::Solid { color } => color.clone(),
 
             Pattern::Gradiant { first, second } => {
                 if point.x < 0. {
-                    scene.pattern(*first).color_at(scene, point, normal)
+                    scene.pattern(*first).color_at(scene, surface)
                 } else if point.x > 1. {
-                    scene.pattern(*second).color_at(scene, point, normal)
+                    scene.pattern(*second).color_at(scene, surface)
                 } else {
-                    let first = scene.pattern(*first).color_at(scene, point, normal);
-                    let second = scene.pattern(*second).color_at(scene, point, normal);
-                    first.mix(&second, point.x)
+                    let first = scene.pattern(*first).color_at(scene, surface);
+                    let second = scene.pattern(*second).color_at(scene, surface);
+                    first.mix(&second, point.x as f32)
+                }
+            }
+
+            Pattern::Ramp { axis, stops } => {
+                let t = point.coords.dot(axis);
+
+                // The first stop that's at or after the point.
+                let next = stops.partition_point(|(at, _)| *at < t);
+                if next == 0 {
+                    stops[0].1.clone()
+                } else if next == stops.len() {
+                    stops[next - 1].1.clone()
+                } else {
+                    let (start, first) = &stops[next - 1];
+                    let (end, second) = &stops[next];
+                    first.mix(second, ((t - start) / (end - start)) as f32)
                 }
             }
 
             Pattern::Stripes { first, second } => {
                 if point.x.floor() % 2. == 0. {
-                    scene.pattern(*first).color_at(scene, point, normal)
+                    scene.pattern(*first).color_at(scene, surface)
                 } else {
-                    scene.pattern(*second).color_at(scene, point, normal)
+                    scene.pattern(*second).color_at(scene, surface)
                 }
             }
 
             Pattern::Checkers { first, second } => {
                 let val = point.x.floor() + point.y.floor() + point.z.floor();
                 if val % 2. == 0. {
-                    scene.pattern(*first).color_at(scene, point, normal)
+                    scene.pattern(*first).color_at(scene, surface)
                 } else {
-                    scene.pattern(*second).color_at(scene, point, normal)
+                    scene.pattern(*second).color_at(scene, surface)
                 }
             }
 
             Pattern::Shells { first, second } => {
                 let val = Vector3::new(point.x, point.y, point.z).norm().floor();
                 if val % 2. == 0. {
-                    scene.pattern(*first).color_at(scene, point, normal)
+                    scene.pattern(*first).color_at(scene, surface)
                 } else {
-                    scene.pattern(*second).color_at(scene, point, normal)
+                    scene.pattern(*second).color_at(scene, surface)
                 }
             }
 
             Pattern::Transform { transform, pattern } => {
                 let point = point.invert(transform);
-                scene.pattern(*pattern).color_at(scene, &point, normal)
+                scene.pattern(*pattern).color_at(scene, &surface.at(point))
+            }
+
+            Pattern::Facing {
+                facing,
+                edge,
+                power,
+            } => {
+                // Surfaces seen from inside face away from the viewer, so ignore the side.
+                let ratio = surface.normal.dot(&surface.eye).abs().min(1.);
+                let facing = scene.pattern(*facing).color_at(scene, surface);
+                let edge = scene.pattern(*edge).color_at(scene, surface);
+                facing.mix(&edge, (1. - ratio).powf(*power) as f32)
+            }
+
+            Pattern::Triplanar { scale, pattern } => {
+                // The squared components of the normal sum to one, and fall off quickly enough
+                // that each projection fades out before it starts to stretch. The point is in
+                // object space, so the normal that picks its projections has to be too.
+                let normal = &surface.object_normal;
+                let weights = normal.component_mul(normal);
+                let projections = [
+                    (weights.x, Point3::new(point.z, point.y, 0.)),
+                    (weights.y, Point3::new(point.x, point.z, 0.)),
+                    (weights.z, Point3::new(point.x, point.y, 0.)),
+                ];
+
+                let pattern = scene.pattern(*pattern);
+                let mut color = Color::black();
+                for (weight, point) in projections {
+                    if weight > 0. {
+                        let sample = pattern.color_at(scene, &surface.at(point * *scale));
+                        color += sample * weight as f32;
+                    }
+                }
+                color
+            }
+
+            Pattern::Uv { pattern } => {
+                let point = Point3::new(surface.uv.x, surface.uv.y, 0.);
+                scene.pattern(*pattern).color_at(scene, &surface.at(point))
+            }
+
+            &Pattern::Contours {
+                node,
+                spacing,
+                width,
+                first,
+                line,
+            } => {
+                let ray = Ray::new(surface.world, surface.eye);
+                let distance = scene
+                    .node(node)
+                    .fast_sdf(scene, &ray, Float::NEG_INFINITY)
+                    .distance
+                    .0;
+
+                // The distance to the closest contour, on either side.
+                let offset = (distance / spacing).round() * spacing - distance;
+                let pattern = if offset.abs() <= width / 2. {
+                    line
+                } else {
+                    first
+                };
+                scene.pattern(pattern).color_at(scene, surface)
+            }
+
+            &Pattern::Curvature { radius, pattern } => {
+                let point = Point3::new(surface.curvature(radius), 0., 0.);
+                scene.pattern(pattern).color_at(scene, &surface.at(point))
             }
         }
     }
 }
+
+#[test]
+fn test_ring() {
+    let mut scene = Scene::default();
+    let sphere = scene.sphere(1.).unwrap();
+    let ring = scene.ring(4, 3., sphere).unwrap();
+
+    let Node::Group { nodes, .. } = scene.node(ring) else {
+        panic!("ring didn't produce a group");
+    };
+
+    let bounds = nodes.bounding_box();
+    assert!(bounds.contains(&Point3::new(3.9, 0., 0.)));
+    assert!(bounds.contains(&Point3::new(0., 0., -3.9)));
+    assert!(!bounds.contains(&Point3::new(4.1, 0., 0.)));
+}
+
+#[test]
+fn test_profiles() {
+    // A circle revolved away from the axis is a torus.
+    let torus = Prim::Torus {
+        hole: 2.,
+        radius: 0.5,
+    };
+    let revolved = Prim::Revolve {
+        profile: Profile::Circle { radius: 0.5 },
+        offset: 2.,
+    };
+    for p in [
+        Point3::new(0., 0., 0.),
+        Point3::new(2., 0.25, 0.),
+        Point3::new(-1., 3., 1.),
+    ] {
+        approx::assert_relative_eq!(torus.sdf(&p).0, revolved.sdf(&p).0, epsilon = 1e-5);
+    }
+    let bounds = revolved.bounding_box();
+    assert!(bounds.contains(&Point3::new(0., 0., -2.4)));
+    assert!(!bounds.contains(&Point3::new(2.6, 0., 0.)));
+
+    // A square turned a quarter of the way around has its corners on the axes at either end.
+    let twisted = Prim::Extrude {
+        profile: Profile::Rect {
+            width: 1.,
+            height: 1.,
+        },
+        depth: 2.,
+        twist: consts::PI / 2.,
+    };
+    assert!(twisted.sdf(&Point3::new(1.2, 0., 0.95)).0 < 0.);
+    assert!(twisted.sdf(&Point3::new(1.2, 0., -0.95)).0 < 0.);
+    assert!(twisted.sdf(&Point3::new(1.2, 0., 0.)).0 > 0.);
+    assert!(twisted.bounding_box().contains(&Point3::new(1.4, 0., 0.)));
+}
+
+#[test]
+fn test_clip() {
+    let mut scene = Scene::default();
+    let sphere = scene.sphere(1.).unwrap();
+    let red = scene.solid(Color::new(1., 0., 0.));
+    let cap = scene.emissive(red);
+    let clipped = scene
+        .clip(
+            sphere,
+            &[(Point3::new(0., 0., -0.5), -Vector3::z_axis())],
+            Some(cap),
+        )
+        .unwrap();
+
+    let at = |z: Float| {
+        let ray = Ray::new(Point3::new(0., 0., z), Vector3::z_axis());
+        scene.node(clipped).sdf(&scene, clipped, &ray)
+    };
+
+    // The front of the sphere is cut off, and the cut face is capped.
+    let cut = at(-0.75);
+    assert_eq!(0.25, cut.distance.0);
+    assert_eq!(Some(cap), cut.material);
+    assert_eq!(-0.5, at(0.).distance.0);
+    assert_eq!(None, at(0.9).material);
+
+    // The sphere is still whole for anything else that uses it.
+    let ray = Ray::new(Point3::new(0., 0., -0.75), Vector3::z_axis());
+    assert_eq!(
+        -0.25,
+        scene.node(sphere).sdf(&scene, sphere, &ray).distance.0
+    );
+}
+
+#[test]
+fn test_light_escape_gradient() {
+    let light = Light::Diffuse {
+        color: Color::new(0., 0., 1.),
+        horizon: Color::white(),
+        intensity: 1.,
+        links: LightLinks::default(),
+    };
+
+    let up = light.light_escape(&Vector3::new(0., 1., 0.));
+    assert_eq!((0., 0., 1.), (up.r, up.g, up.b));
+
+    let across = light.light_escape(&Vector3::new(1., 0., 0.));
+    assert_eq!((1., 1., 1.), (across.r, across.g, across.b));
+
+    // Below the horizon stays at the horizon color.
+    let down = light.light_escape(&Vector3::new(0., -1., 0.));
+    assert_eq!((1., 1., 1.), (down.r, down.g, down.b));
+}
+
+#[test]
+fn test_check_graph() {
+    let mut scene = Scene::default();
+    let sphere = scene.sphere(1.).unwrap();
+    let mut root = sphere;
+    for _ in 1..scene.max_depth {
+        root = scene.subtract(root, sphere);
+    }
+    scene.check_graph(root).unwrap();
+
+    // Marching the deepest graph allowed fits in the stack of a thread with the default size.
+    let ray = Ray::new(
+        Point3::new(0., 0., -5.),
+        Unit::new_normalize(Vector3::new(0., 0., 1.)),
+    );
+    std::thread::scope(|s| {
+        std::thread::Builder::new()
+            .stack_size(2 << 20)
+            .spawn_scoped(s, || {
+                scene.node(root).sdf(&scene, root, &ray);
+                scene.node(root).fast_sdf(&scene, &ray, 0.);
+            })
+            .unwrap()
+            .join()
+            .unwrap();
+    });
+
+    let deeper = scene.invert(root);
+    let err = scene.check_graph(deeper).unwrap_err();
+    assert!(err.to_string().contains("nested 257 deep"), "{}", err);
+    scene.max_depth += 1;
+    scene.check_graph(deeper).unwrap();
+
+    // Nodes edited in place may refer to themselves.
+    let inner = scene.invert(sphere);
+    let outer = scene.invert(inner);
+    scene.nodes[inner.index()].1 = Node::Invert { node: outer };
+    let err = scene.check_graph(outer).unwrap_err();
+    assert!(err.to_string().contains("contains itself"), "{}", err);
+
+    // Adding a node that refers to one that isn't in the scene yet would let it contain itself.
+    let next = NodeId(scene.nodes.len() as u32);
+    let added = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        scene.invert(next);
+    }));
+    assert!(added.is_err());
+
+    // The limit can only be raised as far as the rendering threads' stack fits.
+    let mut scene = Scene::default();
+    let mut root = scene.sphere(1.).unwrap();
+    let other = scene.sphere(0.5).unwrap();
+    for i in 1..MAX_DEPTH_LIMIT {
+        root = if i % 2 == 0 {
+            scene.group(vec![root, other]).unwrap()
+        } else {
+            scene.smooth_union(0.1, &[root, other]).unwrap()
+        };
+    }
+    scene.max_depth = MAX_DEPTH_LIMIT;
+    scene.check_graph(root).unwrap();
+    std::thread::scope(|s| {
+        std::thread::Builder::new()
+            .stack_size(STACK_SIZE)
+            .spawn_scoped(s, || {
+                scene.node(root).sdf(&scene, root, &ray);
+                scene.node(root).fast_sdf(&scene, &ray, 0.);
+            })
+            .unwrap()
+            .join()
+            .unwrap();
+    });
+    scene.max_depth += 1;
+    let err = scene.check_graph(root).unwrap_err();
+    assert!(err.to_string().contains("at most 1024 deep"), "{}", err);
+}
+
+#[test]
+fn test_degenerate_nodes() {
+    let mut scene = Scene::default();
+    let err = |res: Result<NodeId>| res.unwrap_err().to_string();
+
+    assert_eq!(
+        "The radius of a sphere must be positive, not NaN",
+        err(scene.sphere(Float::NAN))
+    );
+    assert!(scene.sphere(-1.).is_err());
+    assert!(scene.rect(1., 1., Float::INFINITY).is_err());
+    assert!(scene.rect(0., 0., 0.).is_err());
+    assert!(scene.torus(-1., 1.).is_err());
+    assert!(scene
+        .capsule(Point3::origin(), Point3::new(Float::NAN, 0., 0.), 1.)
+        .is_err());
+
+    // Flat boxes and tori without a hole are fine.
+    let card = scene.rect(1., 1., 0.).unwrap();
+    scene.torus(0., 1.).unwrap();
+
+    assert_eq!("A group needs at least one node", err(scene.group(vec![])));
+    assert!(scene.union(vec![]).is_err());
+    assert!(scene.intersect(vec![]).is_err());
+    assert!(scene.smooth_union(0.5, &[]).is_err());
+    assert!(scene.smooth_union(0., &[card, card]).is_err());
+    assert!(scene.ring(0, 1., card).is_err());
+    assert!(scene.ring(4, Float::NAN, card).is_err());
+    assert_eq!(card, scene.smooth_union(0.5, &[card]).unwrap());
+}
+
+#[test]
+fn test_non_finite() {
+    let mut scene = Scene {
+        finite_check: FiniteCheck::Report,
+        ..Scene::default()
+    };
+
+    let good = scene.sphere(1.).unwrap();
+    let bad = scene.plane(Unit::new_normalize(Vector3::zeros()));
+    let bad = scene.transform(Transform::new().translate(&Vector3::new(2., 0., 0.)), bad);
+    let root = scene.group(vec![good, bad]).unwrap();
+
+    // The group hides the NaN, but the plane that produced it is still recorded.
+    let ray = Ray::new(
+        Point3::new(0., 0., -5.),
+        Unit::new_normalize(Vector3::new(0., 0., 1.)),
+    );
+    let res = scene.node(root).sdf(&scene, root, &ray);
+    assert_eq!(4., res.distance.0);
+
+    let report = scene.non_finite.take(&scene).expect("non-finite distance");
+    assert!(report.starts_with("node 1 (plane)"), "{}", report);
+    assert!(scene.non_finite.take(&scene).is_none());
+
+    scene.finite_check = FiniteCheck::Off;
+    scene.node(root).sdf(&scene, root, &ray);
+    assert!(scene.non_finite.is_empty());
+}
+
+#[test]
+fn test_normals() {
+    let mut scene = Scene::default();
+    let cube = scene.rect(1., 2., 3.).unwrap();
+    let torus = scene.torus(2., 0.5).unwrap();
+    let scaled = scene.transform(Transform::new().scale(&Vector3::new(2., 1., 1.)), cube);
+
+    let points = [
+        Point3::new(1.5, 0.5, -0.5),
+        Point3::new(-0.2, 2.1, 2.5),
+        Point3::new(0.5, -1., 3.5),
+        Point3::new(2., 0.5, 0.),
+        Point3::new(-1., 0.3, 1.5),
+    ];
+
+    // The analytic normals agree with the gradient of the sdf.
+    let capsule = scene
+        .capsule(Point3::new(0., -1., 1.), Point3::new(1., 2., 0.), 0.5)
+        .unwrap();
+    for id in [cube, torus, scaled, capsule] {
+        for p in points {
+            let ray = Ray::new(p, Unit::new_normalize(Vector3::new(0., 0., 1.)));
+            let node = scene.node(id);
+            let analytic = node.sdf(&scene, id, &ray).normal;
+            let estimate = node.normal_sdf(&scene, id, ray);
+            assert!(
+                analytic.dot(&estimate) > 0.999,
+                "{} at {}: {:?} vs {:?}",
+                node.name(),
+                p,
+                analytic,
+                estimate
+            );
+        }
+    }
+
+    // Far from the origin the offsets grow with the position, rather than vanishing into it.
+    let sphere = scene.sphere(1.).unwrap();
+    let far = scene.transform(
+        Transform::new().translate(&Vector3::new(1e4, 0., 0.)),
+        sphere,
+    );
+    let blend = scene.smooth_union(0.5, &[far, sphere]).unwrap();
+    let ray = Ray::new(
+        Point3::new(1e4 + 1., 0., 0.),
+        Unit::new_normalize(Vector3::new(-1., 0., 0.)),
+    );
+    let n = scene.node(blend).normal_sdf(&scene, blend, ray);
+    assert!(n.x > 0.999, "{:?}", n);
+}
+
+#[test]
+fn test_uv() {
+    let uv = |prim: &Prim, x, y, z| {
+        let uv = prim.uv(&Point3::new(x, y, z));
+        (uv.x, uv.y)
+    };
+
+    let sphere = Prim::Sphere { radius: 2. };
+    assert_eq!((0.5, 0.5), uv(&sphere, 2., 0., 0.));
+    assert_eq!((0.75, 0.5), uv(&sphere, 0., 0., 2.));
+    assert_eq!(1., uv(&sphere, 0., 2., 0.).1);
+
+    let cube = Prim::Box {
+        width: 1.,
+        height: 2.,
+        depth: 3.,
+    };
+    assert_eq!((0.5, 0.5), uv(&cube, 1., 0., 0.));
+    assert_eq!((0.75, 0.), uv(&cube, 0.5, 2., -3.));
+    assert_eq!((0., 1.), uv(&cube, -1., 2., 3.5));
+
+    let plane = Prim::Plane {
+        normal: Unit::new_normalize(Vector3::new(0., 1., 0.)),
+    };
+    assert_eq!((2., 3.), uv(&plane, 2., 0., 3.));
+
+    let torus = Prim::Torus {
+        hole: 2.,
+        radius: 0.5,
+    };
+    assert_eq!((0.5, 0.5), uv(&torus, 2.5, 0., 0.));
+    assert_eq!((0.5, 0.75), uv(&torus, 2., 0.5, 0.));
+
+    let triangle = Prim::Triangle {
+        a: Point3::origin(),
+        b: Point3::new(1., 0., 0.),
+        c: Point3::new(0., 1., 0.),
+        n: Unit::new_normalize(Vector3::new(0., 0., 1.)),
+        normals: None,
+    };
+    assert_eq!((0.25, 0.5), uv(&triangle, 0.25, 0.5, 0.));
+
+    // The coordinates come from the primitive that was hit, in its own space.
+    let mut scene = Scene::default();
+    let sphere = scene.sphere(1.).unwrap();
+    let moved = scene.transform(
+        Transform::new().translate(&Vector3::new(5., 0., 0.)),
+        sphere,
+    );
+    let cube = scene.rect(1., 1., 1.).unwrap();
+    let root = scene.union(vec![moved, cube]).unwrap();
+    let ray = Ray::new(
+        Point3::new(5., 0., -1.),
+        Unit::new_normalize(Vector3::new(0., 0., 1.)),
+    );
+    let uv = scene.node(root).sdf(&scene, root, &ray).uv(&scene);
+    assert_eq!((0.25, 0.5), (uv.x, uv.y));
+}
+
+#[test]
+fn test_smooth_union_bounds() {
+    let mut scene = Scene::default();
+    let slab = scene.rect(0.9, 1., 1.).unwrap();
+    let left = scene.transform(Transform::new().translate(&Vector3::new(-1., 0., 0.)), slab);
+    let right = scene.transform(Transform::new().translate(&Vector3::new(1., 0., 0.)), slab);
+
+    // The fillet across the gap between the slabs bulges past their tops.
+    let blend = scene.smooth_union(1., &[left, right]).unwrap();
+    let bulge = Point3::new(0., 1.05, 0.);
+    let ray = Ray::new(bulge, Vector3::y_axis());
+    assert!(scene.node(blend).sdf(&scene, blend, &ray).distance.0 < 0.);
+    assert!(scene.bounding_box(blend).contains(&bulge));
+    assert!(!scene
+        .bounding_box(blend)
+        .contains(&Point3::new(2.1, 0., 0.)));
+
+    // Nodes too far apart to blend keep the bounds of their union.
+    let far = scene.transform(Transform::new().translate(&Vector3::new(5., 0., 0.)), slab);
+    let apart = scene.smooth_union(1., &[left, far]).unwrap();
+    assert_eq!(
+        scene.bounding_box(left).union(scene.bounding_box(far)),
+        *scene.bounding_box(apart)
+    );
+
+    // The fillet is found from inside a group, which skips children whose bounds a ray misses.
+    let group = scene.group(vec![blend]).unwrap();
+    let ray = Ray::new(Point3::new(0., 1.05, -3.), Vector3::z_axis());
+    let hit = crate::integrator::Hit::march(&MarchConfig::default(), &scene, group, ray, false)
+        .expect("fillet");
+    assert!(hit.ray.position.z < 0.);
+}
+
+#[test]
+fn test_displacement() {
+    let mut scene = Scene::default();
+    let sphere = scene.sphere(1.).unwrap();
+    let white = scene.solid(Color::white());
+    let bumps = scene.ramp(
+        Vector3::x(),
+        vec![(-1., Color::black()), (1., Color::white())],
+    );
+    let displace = |scene: &mut Scene, pattern, amount| {
+        let displacement = Displacement {
+            pattern,
+            amount,
+            slope: 1.,
+        };
+        scene.displace(displacement, sphere)
+    };
+    let raised = displace(&mut scene, white, 0.2);
+    let sunk = displace(&mut scene, white, -0.2);
+    let ramped = displace(&mut scene, bumps, 0.2);
+
+    let distance = |scene: &Scene, id: NodeId, x: Float| {
+        let ray = Ray::new(Point3::new(x, 0., 0.), -Vector3::x_axis());
+        let res = scene.node(id).sdf(scene, id, &ray);
+        assert_eq!(
+            res.distance.0,
+            scene.node(id).fast_sdf(scene, &ray, 0.).distance.0
+        );
+        res.distance.0
+    };
+
+    // Far away only the reach of the pattern matters, while close by the pattern is looked up.
+    assert!((distance(&scene, raised, 3.) - 1.8).abs() < 1e-6);
+    assert!((distance(&scene, sunk, 3.) - 2.).abs() < 1e-6);
+    assert!(distance(&scene, raised, 1.15) < 0.);
+    assert!(distance(&scene, sunk, 0.9) > 0.);
+
+    // The ramp raises the side facing positive x, and leaves the other side alone.
+    assert!(distance(&scene, ramped, 1.15) < 0.);
+    assert!(distance(&scene, ramped, -1.15) > 0.);
+
+    assert!(scene
+        .bounding_box(raised)
+        .contains(&Point3::new(1.15, 0., 0.)));
+    assert!(!scene
+        .bounding_box(sunk)
+        .contains(&Point3::new(1.15, 0., 0.)));
+}
+
+#[test]
+fn test_non_uniform_scale() {
+    let mut scene = Scene::default();
+    let sphere = scene.sphere(1.).unwrap();
+    let stretched = scene.transform(Transform::new().scale(&Vector3::new(1., 10., 1.)), sphere);
+
+    // The side of the stretched sphere is still one away from here, even though the sphere is ten
+    // times longer, so the distance mustn't be scaled past that.
+    let ray = Ray::new(Point3::new(2., 0., 0.), Vector3::y_axis());
+    let distance = scene
+        .node(stretched)
+        .sdf(&scene, stretched, &ray)
+        .distance
+        .0;
+    assert!(distance <= 1. && distance > 0.5, "{}", distance);
+}
+
+#[test]
+fn test_shading_normals() {
+    let mut scene = Scene::default();
+    let z = Vector3::z_axis();
+    let triangle = scene.smooth_triangle(
+        Point3::origin(),
+        Point3::new(1., 0., 0.),
+        Point3::new(0., 1., 0.),
+        z,
+        [
+            z,
+            Unit::new_normalize(Vector3::new(1., 0., 1.)),
+            Unit::new_normalize(Vector3::new(0., 1., 1.)),
+        ],
+    );
+    let ray = |x: Float, y: Float| Ray::new(Point3::new(x, y, 1.), -Vector3::z_axis());
+
+    // The corner normals are blended for shading, while the geometric normal stays flat.
+    let result = scene.node(triangle).sdf(&scene, triangle, &ray(0., 0.));
+    assert_eq!(z, result.normal);
+    assert!((result.shading.unwrap().z - 1.).abs() < 1e-6);
+    let result = scene.node(triangle).sdf(&scene, triangle, &ray(0.5, 0.));
+    let shading = result.shading.unwrap();
+    assert_eq!(z, result.normal);
+    assert!(shading.x > 0.3 && shading.y.abs() < 1e-6, "{:?}", shading);
+
+    // Both normals follow the triangle when it's moved and flipped over.
+    let flipped = scene.transform(
+        Transform::new().rotate(&Vector3::new(consts::PI, 0., 0.)),
+        triangle,
+    );
+    let result = scene.node(flipped).sdf(&scene, flipped, &ray(0.5, 0.));
+    assert!((result.normal.z + 1.).abs() < 1e-6);
+    assert!(result.shading.unwrap().z < 0.);
+    let inverted = scene.invert(triangle);
+    let result = scene.node(inverted).sdf(&scene, inverted, &ray(0.5, 0.));
+    assert!(result.shading.unwrap().x < -0.3);
+
+    // Flat triangles leave shading to the geometric normal.
+    let flat = scene.triangle(
+        Point3::origin(),
+        Point3::new(1., 0., 0.),
+        Point3::new(0., 1., 0.),
+        z,
+    );
+    assert!(scene
+        .node(flat)
+        .sdf(&scene, flat, &ray(0.5, 0.))
+        .shading
+        .is_none());
+}
+
+#[test]
+fn test_ramp() {
+    let mut scene = Scene::default();
+    let ramp = scene.ramp(
+        Vector3::new(0., 2., 0.),
+        vec![
+            (0., Color::new(1., 0., 0.)),
+            (0.5, Color::new(0., 1., 0.)),
+            (0.5, Color::new(0., 0., 1.)),
+            (1., Color::new(1., 1., 1.)),
+        ],
+    );
+
+    let color = |y| {
+        let normal = Unit::new_normalize(Vector3::new(0., 1., 0.));
+        let c = scene.pattern(ramp).color_at(
+            &scene,
+            &SurfacePoint {
+                object: Point3::new(5., y, 0.),
+                world: Point3::new(5., y, 0.),
+                uv: Point2::origin(),
+                normal,
+                object_normal: normal,
+                eye: normal,
+                sdf: &|_| 0.,
+            },
+        );
+        (c.r, c.g, c.b)
+    };
+
+    // Points past the ends take the color of the closest stop.
+    assert_eq!((1., 0., 0.), color(-1.));
+    assert_eq!((1., 1., 1.), color(1.));
+
+    // The axis is twice as long as a unit, so the stops are half as far apart.
+    assert_eq!((0.5, 0.5, 0.), color(0.125));
+
+    // Stops at the same position make a hard edge.
+    assert_eq!((0., 1., 0.), color(0.25));
+    let (r, g, b) = color(0.26);
+    assert!(r < 0.1 && g < 0.1 && b == 1., "{:?}", (r, g, b));
+}
+
+#[test]
+fn test_facing() {
+    let mut scene = Scene::default();
+    let white = scene.solid(Color::white());
+    let black = scene.solid(Color::black());
+    let linear = scene.facing(white, black, 1.);
+    let rim = scene.facing(white, black, 4.);
+
+    let normal = Unit::new_normalize(Vector3::new(0., 0., -1.));
+    let color = |pattern, x: Float, z: Float| {
+        let eye = Unit::new_normalize(Vector3::new(x, 0., z));
+        let c = scene.pattern(pattern).color_at(
+            &scene,
+            &SurfacePoint {
+                object: Point3::origin(),
+                world: Point3::origin(),
+                uv: Point2::origin(),
+                normal,
+                object_normal: normal,
+                eye,
+                sdf: &|_| 0.,
+            },
+        );
+        c.r
+    };
+
+    // Head on, and from inside the surface, only the facing pattern shows.
+    assert_eq!(1., color(linear, 0., -1.));
+    assert_eq!(1., color(linear, 0., 1.));
+
+    // Edge on, only the edge pattern shows.
+    assert_eq!(0., color(linear, 1., 0.));
+
+    // At 60 degrees the facing ratio is a half, and a higher power keeps more of the facing
+    // pattern.
+    let (x, z) = (Float::sqrt(3.) / 2., -0.5);
+    assert!((color(linear, x, z) - 0.5).abs() < 1e-4);
+    assert!((color(rim, x, z) - 0.9375).abs() < 1e-4);
+}
+
+#[test]
+fn test_contours() {
+    let mut scene = Scene::default();
+    let white = scene.solid(Color::white());
+    let black = scene.solid(Color::black());
+    let sphere = scene.sphere(1.).unwrap();
+    let contours = scene.contours(sphere, 0.5, 0.1, white, black);
+
+    let color = |x: Float| {
+        let point = Point3::new(x, 0., 0.);
+        let normal = Unit::new_normalize(Vector3::new(0., 1., 0.));
+        let c = scene.pattern(contours).color_at(
+            &scene,
+            &SurfacePoint {
+                object: Point3::origin(),
+                world: point,
+                uv: Point2::origin(),
+                normal,
+                object_normal: normal,
+                eye: normal,
+                sdf: &|_| 0.,
+            },
+        );
+        c.r
+    };
+
+    // Lines follow the distance to the sphere, inside and out, rather than the object space point.
+    assert_eq!(0., color(1.));
+    assert_eq!(0., color(1.52));
+    assert_eq!(0., color(0.48));
+    assert_eq!(1., color(1.25));
+    assert_eq!(1., color(0.7));
+}
+
+#[test]
+fn test_curvature() {
+    let mut scene = Scene::default();
+    let ramp = scene.ramp(
+        Vector3::x(),
+        vec![(-4., Color::black()), (4., Color::white())],
+    );
+    let curvature = scene.curvature(0.01, ramp);
+
+    fn at(sdf: &dyn Fn(&Point3<Float>) -> Float) -> SurfacePoint<'_> {
+        let normal = Unit::new_normalize(Vector3::new(0., 1., 0.));
+        SurfacePoint {
+            object: Point3::origin(),
+            world: Point3::new(0., 0.5, 0.),
+            uv: Point2::origin(),
+            normal,
+            object_normal: normal,
+            eye: normal,
+            sdf,
+        }
+    }
+
+    // A ball of radius a half curves twice as much as a unit ball, and a hollow of the same size
+    // curves the other way.
+    let ball = |p: &Point3<Float>| p.coords.norm() - 0.5;
+    let hollow = |p: &Point3<Float>| 0.5 - p.coords.norm();
+    let floor = |p: &Point3<Float>| p.y - 0.5;
+    assert!((at(&ball).curvature(0.01) - 4.).abs() < 0.01);
+    assert!((at(&hollow).curvature(0.01) + 4.).abs() < 0.01);
+    assert!(at(&floor).curvature(0.01).abs() < 1e-3);
+
+    let color = |sdf: &dyn Fn(&Point3<Float>) -> Float| {
+        scene.pattern(curvature).color_at(&scene, &at(sdf)).r
+    };
+    assert!(color(&ball) > 0.99);
+    assert!(color(&hollow) < 0.01);
+    assert!((color(&floor) - 0.5).abs() < 0.01);
+}
+
+#[test]
+fn test_triplanar() {
+    let mut scene = Scene::default();
+    let white = scene.solid(Color::white());
+    let black = scene.solid(Color::black());
+    let stripes = scene.stripes(white, black);
+    let triplanar = scene.triplanar(2., stripes);
+
+    let color = |point: Point3<Float>, normal: Vector3<Float>| {
+        let normal = Unit::new_normalize(normal);
+        let c = scene.pattern(triplanar).color_at(
+            &scene,
+            &SurfacePoint {
+                object: point,
+                world: point,
+                uv: Point2::origin(),
+                normal,
+                object_normal: normal,
+                eye: normal,
+                sdf: &|_| 0.,
+            },
+        );
+        c.r
+    };
+
+    // Facing down an axis, only the projection along that axis shows, and the scale doubles the
+    // number of stripes.
+    assert_eq!(
+        1.,
+        color(Point3::new(0.25, 0., 0.), Vector3::new(0., 0., -1.))
+    );
+    assert_eq!(
+        0.,
+        color(Point3::new(0.75, 0., 0.), Vector3::new(0., 0., -1.))
+    );
+    assert_eq!(
+        0.,
+        color(Point3::new(0., 0., 0.75), Vector3::new(1., 0., 0.))
+    );
+
+    // Halfway between two axes, both projections contribute equally.
+    let c = color(Point3::new(0.25, 0., 0.75), Vector3::new(1., 0., 1.));
+    assert!((c - 0.5).abs() < 1e-4);
+
+    // The projections follow a rotated object rather than the world. The top of the turned box is
+    // its side in object space, which is striped along z.
+    let cube = scene.rect(1., 1., 1.).unwrap();
+    let rotated = scene.transform(
+        Transform::new().rotate(&Vector3::new(0., 0., consts::FRAC_PI_2)),
+        cube,
+    );
+    let ray = Ray::new(Point3::new(-0.2, 1., 0.75), -Vector3::y_axis());
+    let res = scene.node(rotated).sdf(&scene, rotated, &ray);
+    assert!(res.normal.y > 0.99);
+    assert!(res.object_normal.x > 0.99);
+    let c = scene.pattern(triplanar).color_at(
+        &scene,
+        &SurfacePoint {
+            object: res.object,
+            world: ray.position,
+            uv: Point2::origin(),
+            normal: res.normal,
+            object_normal: res.object_normal,
+            eye: res.normal,
+            sdf: &|_| 0.,
+        },
+    );
+    assert_eq!(0., c.r);
+}
+
+#[test]
+fn test_emitters() {
+    let mut scene = Scene::default();
+    let white = scene.solid(Color::white());
+    let glow = scene.emissive(white);
+    let matte = scene.phong(white, 0.1, 0.9, 0.9, 200., 0., 0., 1.);
+
+    let sphere = scene.sphere(1.).unwrap();
+    let lamp = scene.paint(glow, sphere);
+    let center = Vector3::new(0., 3., 0.);
+    let lamp = scene.transform(Transform::new().translate(&center), lamp);
+
+    let sphere = scene.sphere(1.).unwrap();
+    let ball = scene.paint(matte, sphere);
+    let plane = scene.plane(Unit::new_normalize(Vector3::new(0., 1., 0.)));
+    let floor = scene.paint(glow, plane);
+    let root = scene.group(vec![lamp, ball, floor]).unwrap();
+
+    // Only the bounded emissive node lights the scene.
+    let emitters = scene.emitters(root);
+    assert_eq!(1, emitters.len());
+
+    let emitter = &emitters[0];
+    assert!(!emitter.samples.is_empty() && emitter.samples.len() <= EMITTER_SAMPLES);
+    for sample in &emitter.samples {
+        let offset = sample.position - Point3::from(center);
+        assert!((offset.norm() - 1.).abs() < 1e-3);
+        assert!(sample.normal.dot(&offset.normalize()) > 0.999);
+    }
+
+    let area = emitter.area * emitter.samples.len() as Float;
+    assert!((area / (4. * consts::PI) - 1.).abs() < 0.05, "{}", area);
+}
+
+#[test]
+fn test_material_inheritance() {
+    let mut scene = Scene::default();
+    let white = scene.solid(Color::white());
+    let glow = scene.emissive(white);
+    let matte = scene.phong(white, 0.1, 0.9, 0.9, 200., 0., 0., 1.);
+
+    let sphere = scene.sphere(1.).unwrap();
+    let bulb = scene.paint(glow, sphere);
+    let bulb = scene.transform(Transform::new().translate(&Vector3::new(0., 3., 0.)), bulb);
+    let base = scene.rect(1., 1., 1.).unwrap();
+    let lamp = scene.group(vec![bulb, base]).unwrap();
+    let root = scene.paint(matte, lamp);
+
+    let material = |scene: &Scene, root: NodeId, y: Float| {
+        let ray = Ray::new(Point3::new(0., y, 0.), Vector3::y_axis());
+        scene.node(root).sdf(scene, root, &ray).material
+    };
+
+    // Paint on the group is a default that the bulb overrides.
+    assert_eq!(Some(glow), material(&scene, root, 3.));
+    assert_eq!(Some(matte), material(&scene, root, 0.));
+    assert_eq!(1, scene.emitters(root).len());
+
+    // Overriding the bulb replaces its material, and leaves the rest of the lamp shared.
+    let dark = scene.override_material(root, &[bulb], matte).unwrap();
+    assert_eq!(Some(matte), material(&scene, dark, 3.));
+    assert_eq!(Some(matte), material(&scene, dark, 0.));
+    assert!(scene.emitters(dark).is_empty());
+    assert!(scene.descendants(&[dark]).contains(&base));
+    assert_eq!(Some(glow), material(&scene, root, 3.));
+
+    assert_eq!(None, scene.override_material(bulb, &[base], matte));
+}
+
+#[test]
+fn test_falloff() {
+    let light = |intensity, falloff| Light::Point {
+        position: Point3::origin(),
+        color: Color::new(0.5, 0.5, 0.5),
+        intensity,
+        falloff,
+        links: LightLinks::default(),
+    };
+    let brightness = |light: Light, distance| light.intensity_at(&Point3::new(0., distance, 0.)).r;
+
+    assert_eq!(0.5, brightness(light(1., Falloff::None), 4.));
+    assert_eq!(2., brightness(light(4., Falloff::None), 4.));
+    assert_eq!(0.125, brightness(light(4., Falloff::InverseSquare), 4.));
+    assert_eq!(0.5, brightness(light(4., Falloff::Exponent(1.)), 4.));
+
+    // Closer than a unit away, the light is brighter than its intensity.
+    assert_eq!(8., brightness(light(4., Falloff::InverseSquare), 0.5));
+
+    // The ambient light is the same at any distance.
+    assert_eq!(2., light(4., Falloff::InverseSquare).ambient().r);
+}
+
+#[test]
+fn test_light_links() {
+    let mut scene = Scene::default();
+    let white = scene.solid(Color::white());
+    let glow = scene.emissive(white);
+    let sphere = scene.sphere(1.).unwrap();
+    let ball = scene.paint(glow, sphere);
+    let ball = scene.transform(Transform::new().uniform_scale(2.), ball);
+    let plane = scene.plane(Unit::new_normalize(Vector3::new(0., 1., 0.)));
+    let floor = scene.paint(glow, plane);
+
+    // The same sphere is also used without the ball around it.
+    let copy = scene.transform(
+        Transform::new().translate(&Vector3::new(0., 0., 10.)),
+        sphere,
+    );
+    let root = scene.group(vec![ball, floor, copy]).unwrap();
+
+    let key = scene.point_light(Point3::origin(), Color::white(), 1., Falloff::None);
+    let fill = scene.point_light(Point3::origin(), Color::white(), 1., Falloff::None);
+    let rim = scene.point_light(Point3::origin(), Color::white(), 1., Falloff::None);
+    scene.link_light(key, &[ball], &[]).unwrap();
+    scene.link_light(fill, &[], &[ball]).unwrap();
+    scene.link_light(rim, &[ball, floor], &[floor]).unwrap();
+
+    // Hits are marked with the linked nodes on the way to them, looking down from above.
+    let hit = |x, y, z| {
+        let ray = Ray::new(Point3::new(x, y, z), -Vector3::y_axis());
+        scene.node(root).sdf(&scene, root, &ray).links
+    };
+    let (on_ball, on_floor, on_copy) = (hit(0., 3., 0.), hit(10., 0.5, 0.), hit(0., 2., 10.));
+
+    let links = |LightId(id): LightId| scene.lights[id as usize].links();
+    assert!(links(key).affects(on_ball) && !links(key).affects(on_floor));
+    assert!(!links(fill).affects(on_ball) && links(fill).affects(on_floor));
+    assert!(links(rim).affects(on_ball) && !links(rim).affects(on_floor));
+
+    // The sphere isn't part of the ball when it's reached some other way.
+    assert!(!links(key).affects(on_copy) && links(fill).affects(on_copy));
+}
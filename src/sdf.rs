@@ -0,0 +1,28 @@
+//! Shapes defined outside of the crate, which are marched like any of the built in primitives.
+
+use nalgebra::{Point2, Point3, Unit, Vector3};
+
+use crate::{bvh::BoundingBox, math::Float};
+
+/// A custom primitive, centered at the origin like the built in ones. Add one to a scene with
+/// [`crate::Scene::custom`], or from a scene description with
+/// [`crate::parser::Plugins::with_primitive`].
+pub trait Sdf: Send + Sync + std::fmt::Debug {
+    /// The signed distance from `p` to the surface, which is negative inside of the shape. It must
+    /// never be more than the true distance, or rays will step through the surface.
+    fn sdf(&self, p: &Point3<Float>) -> Float;
+
+    /// A box around the whole shape, or [`BoundingBox::max`] for shapes without an end.
+    fn bounding_box(&self) -> BoundingBox;
+
+    /// The normal of the surface at `p`, when it's known exactly. Otherwise it's estimated from
+    /// the distances around `p`.
+    fn normal(&self, _p: &Point3<Float>) -> Option<Unit<Vector3<Float>>> {
+        None
+    }
+
+    /// The texture coordinates of the surface at `p`, which are its x and y by default.
+    fn uv(&self, p: &Point3<Float>) -> Point2<Float> {
+        p.xy()
+    }
+}
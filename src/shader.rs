@@ -0,0 +1,840 @@
+//! Export the signed distance function of a scene as shader code, so that models built with rendrs
+//! can be used in realtime shader projects. The generated code is self-contained: it declares a
+//! `SceneSdf` struct, a `scene_sdf` function that returns the distance to the scene along with the
+//! material of the closest surface, and a `scene_color` function that evaluates the pattern of a
+//! material. Lighting and marching are left to the project the code is dropped into.
+//!
+//! The gpu backend compiles its shader with the same [`Compiler`], adding its own shading and
+//! marching around the distance functions.
+
+use anyhow::{anyhow, bail, Error};
+use nalgebra::Matrix4;
+use std::collections::BTreeSet;
+use std::fmt::Write;
+
+use crate::{
+    canvas::Color,
+    math::Float,
+    parser::{self, Overrides},
+    scene::{Material, MaterialId, Node, NodeId, Pattern, PatternId, Prim, Scene},
+};
+
+type Result<T> = std::result::Result<T, Error>;
+
+/// The shading language to export to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Language {
+    /// GLSL, in the subset accepted by Shadertoy and WebGL 2.
+    Glsl,
+
+    /// WGSL, for WebGPU and wgpu.
+    Wgsl,
+}
+
+/// Parse a scene description and export the node graph of one of its renders: the one named
+/// `render`, or the first if no name is given.
+pub fn export(
+    input: &str,
+    flags: &[String],
+    render: Option<&str>,
+    language: Language,
+) -> Result<String> {
+    let (scene, renders) = parser::parse_with(input, flags, &Overrides::default())?;
+    let root = match render {
+        Some(name) => {
+            renders
+                .iter()
+                .find(|render| render.name.as_deref() == Some(name))
+                .ok_or_else(|| anyhow!("There's no render named `{}`", name))?
+                .root
+        }
+        None => match renders.first() {
+            Some(render) => render.root,
+            None => bail!("The scene has no renders to export"),
+        },
+    };
+    compile(&scene, root, language)
+}
+
+/// Compile the nodes reachable from `root`, and the materials they use.
+fn compile(scene: &Scene, root: NodeId, language: Language) -> Result<String> {
+    let mut compiler = Compiler::new(scene, language, "can't be exported to shaders");
+
+    compiler
+        .out
+        .push_str("// Generated by rendrs. `scene_sdf` gives the distance to the scene from a point, and\n// the material of the closest surface, which `scene_color` gives the color of at the\n// point `object`. Surfaces with no material have a material of -1.\n\n");
+    compiler.prelude();
+    compiler.node(root)?;
+    for id in compiler.materials.clone() {
+        match *scene.material(id) {
+            Material::Phong { pattern, .. }
+            | Material::Toon { pattern, .. }
+            | Material::Emissive { pattern, .. } => compiler.pattern(pattern)?,
+            Material::Matcap { .. } => bail!("Matcap materials can't be exported to shaders"),
+        }
+    }
+    compiler.colors()?;
+    compiler.sdf(root);
+
+    Ok(compiler.out)
+}
+
+impl Language {
+    fn float(self) -> &'static str {
+        match self {
+            Language::Glsl => "float",
+            Language::Wgsl => "f32",
+        }
+    }
+
+    fn vec2(self) -> &'static str {
+        match self {
+            Language::Glsl => "vec2",
+            Language::Wgsl => "vec2<f32>",
+        }
+    }
+
+    fn vec3(self) -> &'static str {
+        match self {
+            Language::Glsl => "vec3",
+            Language::Wgsl => "vec3<f32>",
+        }
+    }
+
+    fn int(self) -> &'static str {
+        match self {
+            Language::Glsl => "int",
+            Language::Wgsl => "i32",
+        }
+    }
+
+    /// Declare a local of type `ty` with the value `value`. Immutable locals are `let` bindings in
+    /// WGSL, and plain variables in GLSL.
+    fn local(self, mutable: bool, name: &str, ty: &str, value: &str) -> String {
+        match self {
+            Language::Glsl => format!("{} {} = {};", ty, name, value),
+            Language::Wgsl if mutable => format!("var {} = {};", name, value),
+            Language::Wgsl => format!("let {} = {};", name, value),
+        }
+    }
+
+    fn function(self, name: &str, params: &[(&str, &str)], ret: &str, body: &str) -> String {
+        match self {
+            Language::Glsl => {
+                let params: Vec<_> = params
+                    .iter()
+                    .map(|(name, ty)| format!("{} {}", ty, name))
+                    .collect();
+                format!("{} {}({}) {{\n{}}}\n\n", ret, name, params.join(", "), body)
+            }
+            Language::Wgsl => {
+                let params: Vec<_> = params
+                    .iter()
+                    .map(|(name, ty)| format!("{}: {}", name, ty))
+                    .collect();
+                format!(
+                    "fn {}({}) -> {} {{\n{}}}\n\n",
+                    name,
+                    params.join(", "),
+                    ret,
+                    body
+                )
+            }
+        }
+    }
+
+    /// The remainder of `a` divided by `b`, for floats.
+    fn modulo(self, a: &str, b: &str) -> String {
+        match self {
+            Language::Glsl => format!("mod({}, {})", a, b),
+            Language::Wgsl => format!("(({}) % {})", a, b),
+        }
+    }
+
+    pub(crate) fn vec3_literal(
+        self,
+        x: impl Into<f64>,
+        y: impl Into<f64>,
+        z: impl Into<f64>,
+    ) -> String {
+        format!("{}({}, {}, {})", self.vec3(), float(x), float(y), float(z))
+    }
+
+    pub(crate) fn color(self, c: &Color) -> String {
+        self.vec3_literal(c.r, c.g, c.b)
+    }
+
+    fn mat4(self, m: &Matrix4<Float>) -> String {
+        let elems: Vec<_> = m.as_slice().iter().copied().map(float).collect();
+        let ty = match self {
+            Language::Glsl => "mat4",
+            Language::Wgsl => "mat4x4<f32>",
+        };
+        format!("{}({})", ty, elems.join(", "))
+    }
+}
+
+/// Format a float as a literal that both languages accept. Shaders compute in `f32`, whatever the
+/// precision of the scene.
+pub(crate) fn float(x: impl Into<f64>) -> String {
+    let x = x.into() as f32;
+    if x.is_nan() {
+        String::from("0.0")
+    } else if x.is_infinite() {
+        format!("{:?}", f32::MAX.copysign(x))
+    } else {
+        format!("{:?}", x)
+    }
+}
+
+/// Compiles the distance functions of nodes and the color functions of patterns. Each node and
+/// pattern is compiled once, into a function named after its index, along with everything that it
+/// depends on.
+pub(crate) struct Compiler<'a> {
+    scene: &'a Scene,
+    language: Language,
+
+    /// How the parts of a scene that can't be compiled are described in errors, following their
+    /// name.
+    unsupported: &'static str,
+
+    /// The code compiled so far.
+    pub(crate) out: String,
+
+    nodes: BTreeSet<NodeId>,
+    patterns: BTreeSet<PatternId>,
+
+    /// The materials of the nodes compiled so far, which are left to the caller to compile.
+    pub(crate) materials: BTreeSet<MaterialId>,
+}
+
+impl<'a> Compiler<'a> {
+    pub(crate) fn new(scene: &'a Scene, language: Language, unsupported: &'static str) -> Self {
+        Self {
+            scene,
+            language,
+            unsupported,
+            out: String::new(),
+            nodes: BTreeSet::new(),
+            patterns: BTreeSet::new(),
+            materials: BTreeSet::new(),
+        }
+    }
+
+    /// Declare the `SceneSdf` struct that the distance functions return.
+    pub(crate) fn prelude(&mut self) {
+        match self.language {
+            Language::Glsl => self.out.push_str(
+                "struct SceneSdf {
+    float dist;
+    int material;
+    vec3 object;
+};
+
+",
+            ),
+            Language::Wgsl => self.out.push_str(
+                "struct SceneSdf {
+    dist: f32,
+    material: i32,
+    object: vec3<f32>,
+}
+
+",
+            ),
+        }
+    }
+
+    /// Compile the `scene_node_` function of `id`.
+    pub(crate) fn node(&mut self, id: NodeId) -> Result<()> {
+        if !self.nodes.insert(id) {
+            return Ok(());
+        }
+
+        let lang = self.language;
+        let (float_ty, vec3) = (lang.float(), lang.vec3());
+        let mut body = String::new();
+
+        match self.scene.node(id) {
+            Node::Prim { prim } => match prim {
+                Prim::Plane { normal } => writeln!(
+                    body,
+                    "    return SceneSdf(dot(p, {}), -1, p);",
+                    lang.vec3_literal(normal.x, normal.y, normal.z)
+                )
+                .unwrap(),
+
+                Prim::Sphere { radius } => writeln!(
+                    body,
+                    "    return SceneSdf(length(p) - {}, -1, p);",
+                    float(*radius)
+                )
+                .unwrap(),
+
+                Prim::Box {
+                    width,
+                    height,
+                    depth,
+                } => write!(
+                    body,
+                    "    {}
+    {}
+    return SceneSdf(d, -1, p);
+",
+                    lang.local(
+                        false,
+                        "q",
+                        vec3,
+                        &format!("abs(p) - {}", lang.vec3_literal(*width, *height, *depth))
+                    ),
+                    lang.local(
+                        false,
+                        "d",
+                        float_ty,
+                        &format!(
+                            "length(max(q, {}(0.0))) + min(max(q.x, max(q.y, q.z)), 0.0)",
+                            vec3
+                        )
+                    ),
+                )
+                .unwrap(),
+
+                Prim::Torus { hole, radius } => write!(
+                    body,
+                    "    {}
+    return SceneSdf(length(q) - {}, -1, p);
+",
+                    lang.local(
+                        false,
+                        "q",
+                        lang.vec2(),
+                        &format!("{}(length(p.xz) - {}, p.y)", lang.vec2(), float(*hole))
+                    ),
+                    float(*radius)
+                )
+                .unwrap(),
+
+                Prim::Triangle { a, b, c, n, .. } => {
+                    let locals = [
+                        ("a", lang.vec3_literal(a.x, a.y, a.z)),
+                        ("b", lang.vec3_literal(b.x, b.y, b.z)),
+                        ("c", lang.vec3_literal(c.x, c.y, c.z)),
+                        ("n", lang.vec3_literal(n.x, n.y, n.z)),
+                        ("ba", String::from("b - a")),
+                        ("cb", String::from("c - b")),
+                        ("ac", String::from("a - c")),
+                        ("pa", String::from("p - a")),
+                        ("pb", String::from("p - b")),
+                        ("pc", String::from("p - c")),
+                    ];
+                    for (name, value) in locals {
+                        writeln!(body, "    {}", lang.local(false, name, vec3, &value)).unwrap();
+                    }
+                    write!(
+                        body,
+                        "    {}
+    if (sign(dot(cross(ba, n), pa)) + sign(dot(cross(cb, n), pb)) + sign(dot(cross(ac, n), pc)) < 2.0) {{
+        {}
+        {}
+        {}
+        v = min(min(dot(x, x), dot(y, y)), dot(z, z));
+    }} else {{
+        v = dot(n, pa) * dot(n, pa) / dot(n, n);
+    }}
+    return SceneSdf(sqrt(v), -1, p);
+",
+                        lang.local(true, "v", float_ty, "0.0"),
+                        lang.local(
+                            false,
+                            "x",
+                            vec3,
+                            "ba * clamp(dot(ba, pa) / dot(ba, ba), 0.0, 1.0) - pa"
+                        ),
+                        lang.local(
+                            false,
+                            "y",
+                            vec3,
+                            "cb * clamp(dot(cb, pb) / dot(cb, cb), 0.0, 1.0) - pb"
+                        ),
+                        lang.local(
+                            false,
+                            "z",
+                            vec3,
+                            "ac * clamp(dot(ac, pc) / dot(ac, ac), 0.0, 1.0) - pc"
+                        ),
+                    )
+                    .unwrap();
+                }
+
+                Prim::Capsule { a, b, radius } => write!(
+                    body,
+                    "    {}
+    {}
+    {}
+    return SceneSdf(length(pa - ba * h) - {}, -1, p);
+",
+                    lang.local(
+                        false,
+                        "pa",
+                        vec3,
+                        &format!("p - {}", lang.vec3_literal(a.x, a.y, a.z))
+                    ),
+                    lang.local(
+                        false,
+                        "ba",
+                        vec3,
+                        &format!(
+                            "{} - {}",
+                            lang.vec3_literal(b.x, b.y, b.z),
+                            lang.vec3_literal(a.x, a.y, a.z)
+                        )
+                    ),
+                    lang.local(
+                        false,
+                        "h",
+                        float_ty,
+                        "clamp(dot(pa, ba) / max(dot(ba, ba), 1e-12), 0.0, 1.0)"
+                    ),
+                    float(*radius)
+                )
+                .unwrap(),
+
+                Prim::Extrude { .. } => bail!("Extrusions {}", self.unsupported),
+
+                Prim::Revolve { .. } => bail!("Revolved profiles {}", self.unsupported),
+
+                Prim::Metaballs { .. } => bail!("Metaballs {}", self.unsupported),
+
+                Prim::Fractal { .. } => bail!("Fractals {}", self.unsupported),
+
+                Prim::Custom { name, .. } => bail!("`{}` {}", name, self.unsupported),
+            },
+
+            &Node::Invert { node } => {
+                self.node(node)?;
+                write!(
+                    body,
+                    "    {}
+    r.dist = -r.dist;
+    return r;
+",
+                    lang.local(
+                        true,
+                        "r",
+                        "SceneSdf",
+                        &format!("scene_node_{}(p)", node.index())
+                    )
+                )
+                .unwrap();
+            }
+
+            Node::Group { union, nodes } => {
+                let init = format!("SceneSdf({}, -1, p)", float(f32::INFINITY));
+                writeln!(body, "    {}", lang.local(true, "r", "SceneSdf", &init)).unwrap();
+                for &node in nodes.iter() {
+                    self.node(node)?;
+                    self.closest(&mut body, node, "c.dist < r.dist");
+                }
+                if *union {
+                    writeln!(body, "    r.object = p;").unwrap();
+                }
+                writeln!(body, "    return r;").unwrap();
+            }
+
+            &Node::Subtract { left, right } => {
+                self.node(left)?;
+                self.node(right)?;
+                write!(
+                    body,
+                    "    {}
+    {}
+    r.dist = -r.dist;
+    if (l.dist < r.dist) {{
+        r.object = p;
+        if (r.material < 0) {{
+            r.material = l.material;
+        }}
+        return r;
+    }}
+    l.object = p;
+    return l;
+",
+                    lang.local(
+                        true,
+                        "l",
+                        "SceneSdf",
+                        &format!("scene_node_{}(p)", left.index())
+                    ),
+                    lang.local(
+                        true,
+                        "r",
+                        "SceneSdf",
+                        &format!("scene_node_{}(p)", right.index())
+                    ),
+                )
+                .unwrap();
+            }
+
+            &Node::SmoothUnion { k, left, right } => {
+                self.node(left)?;
+                self.node(right)?;
+                write!(
+                    body,
+                    "    {}
+    {}
+    {}
+    {}
+    {}
+    if (diff < 0.0) {{
+        l.material = r.material;
+    }}
+    l.dist = mix(r.dist, l.dist, h) - k * h * (1.0 - h);
+    l.object = p;
+    return l;
+",
+                    lang.local(false, "k", float_ty, &float(k)),
+                    lang.local(
+                        true,
+                        "l",
+                        "SceneSdf",
+                        &format!("scene_node_{}(p)", left.index())
+                    ),
+                    lang.local(
+                        false,
+                        "r",
+                        "SceneSdf",
+                        &format!("scene_node_{}(p)", right.index())
+                    ),
+                    lang.local(false, "diff", float_ty, "r.dist - l.dist"),
+                    lang.local(
+                        false,
+                        "h",
+                        float_ty,
+                        "clamp(0.5 + 0.5 * diff / k, 0.0, 1.0)"
+                    ),
+                )
+                .unwrap();
+            }
+
+            Node::Intersect { nodes } => {
+                let init = format!("SceneSdf({}, -1, p)", float(-f32::INFINITY));
+                writeln!(body, "    {}", lang.local(true, "r", "SceneSdf", &init)).unwrap();
+                for &node in nodes {
+                    self.node(node)?;
+                    self.closest(&mut body, node, "c.dist >= r.dist");
+                }
+                writeln!(body, "    r.object = p;\n    return r;").unwrap();
+            }
+
+            Node::Transform { transform, node } => {
+                self.node(*node)?;
+                let value = format!(
+                    "scene_node_{}(({} * vec4(p, 1.0)).xyz)",
+                    node.index(),
+                    lang.mat4(transform.inverse_matrix())
+                );
+                write!(
+                    body,
+                    "    {}
+    r.dist = r.dist * {};
+    return r;
+",
+                    lang.local(true, "r", "SceneSdf", &value),
+                    float(transform.scale_factor())
+                )
+                .unwrap();
+            }
+
+            &Node::Material { material, node } => {
+                self.node(node)?;
+                self.materials.insert(material);
+                write!(
+                    body,
+                    "    {}
+    if (r.material < 0) {{
+        r.material = {};
+    }}
+    return r;
+",
+                    lang.local(
+                        true,
+                        "r",
+                        "SceneSdf",
+                        &format!("scene_node_{}(p)", node.index())
+                    ),
+                    material.index()
+                )
+                .unwrap();
+            }
+
+            Node::Volume { .. } => bail!("Volumes {}", self.unsupported),
+            Node::Displace { .. } => bail!("Displacement {}", self.unsupported),
+
+            Node::Grid { .. } => bail!("Baked grids {}", self.unsupported),
+
+            Node::Heightfield { .. } => bail!("Heightfields {}", self.unsupported),
+        }
+
+        let name = format!("scene_node_{}", id.index());
+        let function = lang.function(&name, &[("p", vec3)], "SceneSdf", &body);
+        self.out.push_str(&function);
+
+        Ok(())
+    }
+
+    /// Emit a block that replaces `r` with the result of `node` when `test` holds of it.
+    fn closest(&self, body: &mut String, node: NodeId, test: &str) {
+        write!(
+            body,
+            "    {{
+        {}
+        if ({}) {{
+            r = c;
+        }}
+    }}
+",
+            self.language.local(
+                false,
+                "c",
+                "SceneSdf",
+                &format!("scene_node_{}(p)", node.index())
+            ),
+            test
+        )
+        .unwrap();
+    }
+
+    /// Compile the `scene_pattern_` function of `id`.
+    pub(crate) fn pattern(&mut self, id: PatternId) -> Result<()> {
+        if !self.patterns.insert(id) {
+            return Ok(());
+        }
+
+        let lang = self.language;
+
+        // Emit a test that selects between two sub-patterns.
+        let choose = |test: &str, first: PatternId, second: PatternId| {
+            format!(
+                "    if ({}) {{
+        return scene_pattern_{}(p);
+    }}
+    return scene_pattern_{}(p);
+",
+                test,
+                first.index(),
+                second.index()
+            )
+        };
+
+        let body = match self.scene.pattern(id) {
+            Pattern::Solid { color } => format!("    return {};\n", lang.color(color)),
+
+            &Pattern::Gradiant { first, second } => {
+                self.pattern(first)?;
+                self.pattern(second)?;
+                format!(
+                    "    if (p.x < 0.0) {{
+        return scene_pattern_{0}(p);
+    }} else if (p.x > 1.0) {{
+        return scene_pattern_{1}(p);
+    }}
+    return mix(scene_pattern_{0}(p), scene_pattern_{1}(p), p.x);
+",
+                    first.index(),
+                    second.index()
+                )
+            }
+
+            Pattern::Ramp { axis, stops } => {
+                let t = format!("dot(p, {})", lang.vec3_literal(axis.x, axis.y, axis.z));
+                let mut body = format!("    {}\n", lang.local(false, "t", lang.float(), &t));
+                let (first_at, first) = &stops[0];
+                writeln!(
+                    body,
+                    "    if (t <= {}) {{\n        return {};\n    }}",
+                    float(*first_at),
+                    lang.color(first)
+                )
+                .unwrap();
+                for pair in stops.windows(2) {
+                    let [(start, first), (end, second)] = pair else {
+                        unreachable!()
+                    };
+                    writeln!(
+                        body,
+                        "    if (t <= {}) {{\n        return mix({}, {}, (t - {}) / {});\n    }}",
+                        float(*end),
+                        lang.color(first),
+                        lang.color(second),
+                        float(*start),
+                        float(end - start)
+                    )
+                    .unwrap();
+                }
+                let (_, last) = stops.last().unwrap();
+                writeln!(body, "    return {};", lang.color(last)).unwrap();
+                body
+            }
+
+            &Pattern::Stripes { first, second } => {
+                self.pattern(first)?;
+                self.pattern(second)?;
+                let test = format!("{} == 0.0", lang.modulo("floor(p.x)", "2.0"));
+                choose(&test, first, second)
+            }
+
+            &Pattern::Checkers { first, second } => {
+                self.pattern(first)?;
+                self.pattern(second)?;
+                let test = format!(
+                    "{} == 0.0",
+                    lang.modulo("floor(p.x) + floor(p.y) + floor(p.z)", "2.0")
+                );
+                choose(&test, first, second)
+            }
+
+            &Pattern::Shells { first, second } => {
+                self.pattern(first)?;
+                self.pattern(second)?;
+                let test = format!("{} == 0.0", lang.modulo("floor(length(p))", "2.0"));
+                choose(&test, first, second)
+            }
+
+            Pattern::Transform { transform, pattern } => {
+                self.pattern(*pattern)?;
+                format!(
+                    "    return scene_pattern_{}(({} * vec4(p, 1.0)).xyz);\n",
+                    pattern.index(),
+                    lang.mat4(transform.inverse_matrix())
+                )
+            }
+
+            Pattern::Facing { .. } => bail!("Facing ratio patterns {}", self.unsupported),
+
+            Pattern::Triplanar { .. } => bail!("Triplanar patterns {}", self.unsupported),
+
+            Pattern::Uv { .. } => bail!("Texture coordinates {}", self.unsupported),
+            Pattern::Contours { .. } => bail!("Contour patterns {}", self.unsupported),
+            Pattern::Curvature { .. } => bail!("Curvature patterns {}", self.unsupported),
+        };
+
+        let name = format!("scene_pattern_{}", id.index());
+        let function = lang.function(&name, &[("p", lang.vec3())], lang.vec3(), &body);
+        self.out.push_str(&function);
+
+        Ok(())
+    }
+
+    /// Emit the `scene_sdf` function, which gives the distance to `root`.
+    pub(crate) fn sdf(&mut self, root: NodeId) {
+        let lang = self.language;
+        let function = lang.function(
+            "scene_sdf",
+            &[("p", lang.vec3())],
+            "SceneSdf",
+            &format!("    return scene_node_{}(p);\n", root.index()),
+        );
+        self.out.push_str(&function);
+    }
+
+    /// Emit the function that selects the pattern of a material.
+    fn colors(&mut self) -> Result<()> {
+        let lang = self.language;
+        let mut body = String::new();
+        for &id in self.materials.iter() {
+            let pattern = match *self.scene.material(id) {
+                Material::Phong { pattern, .. }
+                | Material::Toon { pattern, .. }
+                | Material::Emissive { pattern, .. } => pattern,
+                Material::Matcap { .. } => unreachable!("matcap materials are rejected up front"),
+            };
+            writeln!(
+                body,
+                "    if (material == {}) {{\n        return scene_pattern_{}(object);\n    }}",
+                id.index(),
+                pattern.index()
+            )
+            .unwrap();
+        }
+
+        // Magenta for surfaces with no material, as in the renderer.
+        writeln!(body, "    return {};", lang.color(&Color::new(1., 0., 1.))).unwrap();
+
+        let function = lang.function(
+            "scene_color",
+            &[("material", lang.int()), ("object", lang.vec3())],
+            lang.vec3(),
+            &body,
+        );
+        self.out.push_str(&function);
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SCENE: &str = r#"
+        (pattern tiles (checkers (solid #ffffff) (transform (scale (2 2 2)) (stripes (solid #ff0000) (solid #0000ff)))))
+        (material tiled (phong :pattern tiles))
+        (node piece
+          (smooth-union 0.2
+            (subtract (intersect (sphere 0.6) (box 0.45 0.45 0.45)) (sphere 0.5))
+            (transform (translate 0 0.5 0) (torus 0.3 0.08))))
+        (render (ascii "out") (whitted (uniform 1) (pinhole 8 8 (translate 0 0 -4) (degrees 60)))
+          (group
+            (paint tiled piece)
+            (invert (triangle (0 0 0) (1 0 0) (0 1 0)))
+            (plane (0 1 0))))
+        "#;
+
+    #[test]
+    fn test_export() {
+        let glsl = export(SCENE, &[], None, Language::Glsl).unwrap();
+        assert!(glsl.contains("SceneSdf scene_sdf(vec3 p) {"), "{}", glsl);
+        assert!(
+            glsl.contains("vec3 scene_color(int material, vec3 object) {"),
+            "{}",
+            glsl
+        );
+        assert!(glsl.contains("mod(floor(p.x), 2.0)"), "{}", glsl);
+        assert!(
+            !glsl.contains("let ") && !glsl.contains("<f32>"),
+            "{}",
+            glsl
+        );
+
+        let wgsl = export(SCENE, &[], None, Language::Wgsl).unwrap();
+        assert!(
+            wgsl.contains("fn scene_sdf(p: vec3<f32>) -> SceneSdf {"),
+            "{}",
+            wgsl
+        );
+        assert!(!wgsl.contains("mod("), "{}", wgsl);
+        assert!(
+            wgsl.contains("((floor(p.x) + floor(p.y) + floor(p.z)) % 2.0)"),
+            "{}",
+            wgsl
+        );
+
+        assert!(export(SCENE, &[], Some("missing"), Language::Glsl).is_err());
+        assert!(export("(node ball (sphere 1))", &[], None, Language::Glsl).is_err());
+    }
+
+    #[cfg(feature = "gpu")]
+    #[test]
+    fn test_export_validates() {
+        use wgpu::naga;
+
+        let wgsl = export(SCENE, &[], None, Language::Wgsl).unwrap();
+        let module = naga::front::wgsl::parse_str(&wgsl).unwrap();
+        naga::valid::Validator::new(
+            naga::valid::ValidationFlags::all(),
+            naga::valid::Capabilities::empty(),
+        )
+        .validate(&module)
+        .unwrap();
+    }
+}
@@ -0,0 +1,157 @@
+//! Lay out a string in a TrueType or OpenType font, producing the outline of each glyph as a
+//! polygon that can be extruded into a solid.
+
+use anyhow::{bail, Error};
+use nalgebra::{Point2, Vector2};
+
+use crate::{math::Float, polygon::Polygon};
+
+type Result<T> = std::result::Result<T, Error>;
+
+/// The number of straight edges that each curve in a glyph's outline is flattened into.
+const CURVE_SEGMENTS: usize = 8;
+
+/// The outlines of the glyphs in `text`, in the font whose file contents are `font`. The text
+/// starts at the origin on the baseline, with an em of `size`, and each line after a newline is a
+/// line height below the one before it. Glyphs without outlines, like spaces, only move the glyphs
+/// after them along.
+pub fn layout(font: &[u8], text: &str, size: Float) -> Result<Vec<Polygon>> {
+    let face = match ttf_parser::Face::parse(font, 0) {
+        Ok(face) => face,
+        Err(err) => bail!("Failed to read the font: {}", err),
+    };
+
+    let scale = size / face.units_per_em() as Float;
+    let line_height = (face.height() + face.line_gap()) as Float * scale;
+
+    let mut glyphs = Vec::new();
+    for (line, chars) in text.lines().enumerate() {
+        let mut pen = Vector2::new(0., -(line as Float) * line_height);
+        for c in chars.chars() {
+            let id = face.glyph_index(c).unwrap_or(ttf_parser::GlyphId(0));
+
+            let mut outline = Outline {
+                scale,
+                offset: pen,
+                contours: Vec::new(),
+            };
+            face.outline_glyph(id, &mut outline);
+            let polygon = Polygon::new(outline.contours);
+            if !polygon.is_empty() {
+                glyphs.push(polygon);
+            }
+
+            pen.x += face.glyph_hor_advance(id).unwrap_or(0) as Float * scale;
+        }
+    }
+
+    Ok(glyphs)
+}
+
+/// Collects the contours of a glyph, flattening its curves.
+struct Outline {
+    scale: Float,
+    offset: Vector2<Float>,
+    contours: Vec<Vec<Point2<Float>>>,
+}
+
+impl Outline {
+    fn point(&self, x: f32, y: f32) -> Point2<Float> {
+        Point2::new(x as Float, y as Float) * self.scale + self.offset
+    }
+
+    fn last(&self) -> Point2<Float> {
+        self.contours
+            .last()
+            .and_then(|contour| contour.last())
+            .copied()
+            .unwrap_or_else(|| Point2::from(self.offset))
+    }
+
+    fn push(&mut self, p: Point2<Float>) {
+        if self.contours.is_empty() {
+            self.contours.push(Vec::new());
+        }
+        self.contours.last_mut().unwrap().push(p);
+    }
+}
+
+impl ttf_parser::OutlineBuilder for Outline {
+    fn move_to(&mut self, x: f32, y: f32) {
+        self.contours.push(vec![self.point(x, y)]);
+    }
+
+    fn line_to(&mut self, x: f32, y: f32) {
+        let p = self.point(x, y);
+        self.push(p);
+    }
+
+    fn quad_to(&mut self, x1: f32, y1: f32, x: f32, y: f32) {
+        let (p0, p1, p2) = (self.last(), self.point(x1, y1), self.point(x, y));
+        for i in 1..=CURVE_SEGMENTS {
+            let t = i as Float / CURVE_SEGMENTS as Float;
+            let u = 1. - t;
+            let p = p0.coords * (u * u) + p1.coords * (2. * u * t) + p2.coords * (t * t);
+            self.push(p.into());
+        }
+    }
+
+    fn curve_to(&mut self, x1: f32, y1: f32, x2: f32, y2: f32, x: f32, y: f32) {
+        let (p0, p1, p2, p3) = (
+            self.last(),
+            self.point(x1, y1),
+            self.point(x2, y2),
+            self.point(x, y),
+        );
+        for i in 1..=CURVE_SEGMENTS {
+            let t = i as Float / CURVE_SEGMENTS as Float;
+            let u = 1. - t;
+            let p = p0.coords * (u * u * u)
+                + p1.coords * (3. * u * u * t)
+                + p2.coords * (3. * u * t * t)
+                + p3.coords * (t * t * t);
+            self.push(p.into());
+        }
+    }
+
+    // Contours are always closed by the polygon, and the last point repeats the first.
+    fn close(&mut self) {
+        if let Some(contour) = self.contours.last_mut() {
+            if contour.len() > 1 && contour.first() == contour.last() {
+                contour.pop();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ttf_parser::OutlineBuilder;
+
+    #[test]
+    fn test_outline() {
+        let mut outline = Outline {
+            scale: 0.5,
+            offset: Vector2::new(10., 0.),
+            contours: Vec::new(),
+        };
+        outline.move_to(0., 0.);
+        outline.line_to(2., 0.);
+        outline.quad_to(2., 2., 0., 2.);
+        outline.line_to(0., 0.);
+        outline.close();
+
+        let contour = &outline.contours[0];
+        assert_eq!(2 + CURVE_SEGMENTS, contour.len());
+        assert_eq!(Point2::new(10., 0.), contour[0]);
+        assert_eq!(Point2::new(11., 0.), contour[1]);
+        assert_eq!(Point2::new(10., 1.), *contour.last().unwrap());
+
+        let polygon = Polygon::new(outline.contours);
+        assert!(polygon.sdf(&Point2::new(10.5, 0.5)) < 0.);
+        assert!(polygon.sdf(&Point2::new(9.5, 0.5)) > 0.);
+
+        assert!(layout(b"not a font", "hello", 1.).is_err());
+    }
+}
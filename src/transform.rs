@@ -1,11 +1,34 @@
-use nalgebra::{Matrix4, Normed, Point3, Unit, Vector3};
+use nalgebra::{Matrix4, Normed, Point3, Unit, UnitQuaternion, Vector3};
 use std::ops::Neg;
 
+use crate::math::Float;
+
+/// The least that `matrix` stretches any direction by, which is its smallest singular value.
+fn min_stretch(matrix: &Matrix4<Float>) -> Float {
+    matrix
+        .fixed_view::<3, 3>(0, 0)
+        .into_owned()
+        .singular_values()
+        .min()
+}
+
+/// A transform and its inverse. The builders come in two kinds: `then_*` builders apply after the
+/// transform so far, which reads in the order the steps happen to a node, while [`translate`],
+/// [`scale`] and [`uniform_scale`] apply before it, and [`rotate`] applies after it.
+///
+/// [`translate`]: Transform::translate
+/// [`scale`]: Transform::scale
+/// [`uniform_scale`]: Transform::uniform_scale
+/// [`rotate`]: Transform::rotate
 #[derive(Debug, Clone)]
 pub struct Transform {
-    matrix: Matrix4<f32>,
-    inverse: Matrix4<f32>,
-    scale_factor: f32,
+    matrix: Matrix4<Float>,
+    inverse: Matrix4<Float>,
+
+    /// The least that the transform stretches any direction by. Distances measured under the
+    /// transform are scaled by this, which can only underestimate them when the scaling isn't
+    /// uniform, so marching never steps through a surface.
+    scale_factor: Float,
 }
 
 impl Transform {
@@ -17,8 +40,21 @@ impl Transform {
         }
     }
 
+    /// Construct the transform that scales, then rotates, and then translates, which is the usual
+    /// way to place a node.
+    pub fn from_trs(
+        translation: &Vector3<Float>,
+        rotation: &UnitQuaternion<Float>,
+        scale: &Vector3<Float>,
+    ) -> Self {
+        Self::new()
+            .then_scale(scale)
+            .then_rotation(rotation)
+            .then_translate(translation)
+    }
+
     /// Construct the lhs look-at transform.
-    pub fn look_at(eye: &Point3<f32>, target: &Point3<f32>, up: &Vector3<f32>) -> Self {
+    pub fn look_at(eye: &Point3<Float>, target: &Point3<Float>, up: &Vector3<Float>) -> Self {
         let matrix = Matrix4::look_at_lh(eye, target, up);
         let inverse = matrix.try_inverse().unwrap();
         Self {
@@ -29,7 +65,7 @@ impl Transform {
     }
 
     /// Construct a perspective transform.
-    pub fn perspective(aspect: f32, fov: f32, znear: f32, zfar: f32) -> Self {
+    pub fn perspective(aspect: Float, fov: Float, znear: Float, zfar: Float) -> Self {
         let matrix = Matrix4::new_perspective(aspect, fov, znear, zfar);
         let inverse = matrix.try_inverse().unwrap();
         Self {
@@ -43,45 +79,112 @@ impl Transform {
         Self {
             matrix: self.inverse,
             inverse: self.matrix,
-            scale_factor: 1.0 / self.scale_factor,
+            scale_factor: min_stretch(&self.inverse),
         }
     }
 
-    pub fn scale_factor(&self) -> f32 {
+    /// The matrix that undoes this transform.
+    pub fn inverse_matrix(&self) -> &Matrix4<Float> {
+        &self.inverse
+    }
+
+    /// False when the transform can't be undone, such as when it scales by zero, which leaves
+    /// distances under it infinite or NaN.
+    pub fn is_invertible(&self) -> bool {
+        self.matrix
+            .iter()
+            .chain(self.inverse.iter())
+            .all(|x| x.is_finite())
+    }
+
+    pub fn scale_factor(&self) -> Float {
         self.scale_factor
     }
 
-    /// Compose a translation with this transform.
-    pub fn translate(mut self, vec: &Vector3<f32>) -> Self {
+    /// Transform a surface normal. Normals transform by the inverse transpose, which keeps them
+    /// perpendicular to the surface under non-uniform scaling.
+    pub fn apply_normal(&self, normal: &Unit<Vector3<Float>>) -> Unit<Vector3<Float>> {
+        Unit::new_normalize(self.inverse.fixed_view::<3, 3>(0, 0).tr_mul(normal))
+    }
+
+    /// Compose a translation before this transform, so that it moves points before the rest of
+    /// the transform applies to them.
+    pub fn translate(mut self, vec: &Vector3<Float>) -> Self {
         self.matrix.prepend_translation_mut(vec);
         self.inverse.append_translation_mut(&vec.neg());
         self
     }
 
-    /// Compose a uniform scaling with this transform.
-    pub fn uniform_scale(mut self, amount: f32) -> Self {
+    /// Compose a uniform scaling before this transform.
+    pub fn uniform_scale(mut self, amount: Float) -> Self {
         self.matrix.prepend_scaling_mut(amount);
         self.inverse.append_scaling_mut(1.0 / amount);
-        self.scale_factor *= amount;
+        self.scale_factor *= amount.abs();
         self
     }
 
-    /// Compose a non-uniform scaling with this transform.
-    pub fn scale(mut self, vec: &Vector3<f32>) -> Self {
+    /// Compose a non-uniform scaling before this transform. The distance to a surface under the
+    /// scaling is only known to be at least the distance before it times the smallest factor, so
+    /// marching takes shorter steps through strongly stretched nodes.
+    pub fn scale(mut self, vec: &Vector3<Float>) -> Self {
         self.matrix.prepend_nonuniform_scaling_mut(vec);
 
         let inv = Vector3::new(1. / vec.x, 1. / vec.y, 1. / vec.z);
         self.inverse.append_nonuniform_scaling_mut(&inv);
 
-        // hmmm
-        self.scale_factor *= vec.x.max(vec.y).max(vec.z);
+        self.scale_factor = min_stretch(&self.matrix);
+        self
+    }
+
+    /// Compose an axis-angle rotation after this transform, the same as
+    /// [`then_rotate`](Transform::then_rotate).
+    pub fn rotate(self, axisangle: &Vector3<Float>) -> Self {
+        self.then_rotate(axisangle)
+    }
+
+    /// Apply `other` after this transform.
+    pub fn then(&self, other: &Transform) -> Self {
+        other * self
+    }
+
+    /// Compose a translation after this transform.
+    pub fn then_translate(mut self, vec: &Vector3<Float>) -> Self {
+        self.matrix.append_translation_mut(vec);
+        self.inverse.prepend_translation_mut(&vec.neg());
+        self
+    }
+
+    /// Compose an axis-angle rotation after this transform. The direction of the vector is the
+    /// axis, and its length is the angle in radians.
+    pub fn then_rotate(mut self, axisangle: &Vector3<Float>) -> Self {
+        self.matrix = Matrix4::new_rotation(*axisangle) * self.matrix;
+        self.inverse *= Matrix4::new_rotation(axisangle.neg());
         self
     }
 
-    /// Compose an axis-angle rotation to the transform.
-    pub fn rotate(mut self, axisangle: &Vector3<f32>) -> Self {
-        self.matrix = Matrix4::new_rotation(axisangle.clone()) * self.matrix;
-        self.inverse = self.inverse * Matrix4::new_rotation(axisangle.neg());
+    /// Compose a rotation after this transform.
+    pub fn then_rotation(mut self, rotation: &UnitQuaternion<Float>) -> Self {
+        self.matrix = rotation.to_homogeneous() * self.matrix;
+        self.inverse *= rotation.inverse().to_homogeneous();
+        self
+    }
+
+    /// Compose a uniform scaling after this transform.
+    pub fn then_uniform_scale(mut self, amount: Float) -> Self {
+        self.matrix.append_scaling_mut(amount);
+        self.inverse.prepend_scaling_mut(1.0 / amount);
+        self.scale_factor *= amount.abs();
+        self
+    }
+
+    /// Compose a non-uniform scaling after this transform.
+    pub fn then_scale(mut self, vec: &Vector3<Float>) -> Self {
+        self.matrix.append_nonuniform_scaling_mut(vec);
+
+        let inv = Vector3::new(1. / vec.x, 1. / vec.y, 1. / vec.z);
+        self.inverse.prepend_nonuniform_scaling_mut(&inv);
+
+        self.scale_factor = min_stretch(&self.matrix);
         self
     }
 }
@@ -90,10 +193,11 @@ impl std::ops::Mul for &Transform {
     type Output = Transform;
 
     fn mul(self, other: Self) -> Self::Output {
+        let matrix = self.matrix * other.matrix;
         Self::Output {
-            matrix: self.matrix * other.matrix,
+            matrix,
             inverse: other.inverse * self.inverse,
-            scale_factor: self.scale_factor * other.scale_factor,
+            scale_factor: min_stretch(&matrix),
         }
     }
 }
@@ -102,16 +206,17 @@ impl std::ops::Mul<&Transform> for Transform {
     type Output = Transform;
 
     fn mul(self, other: &Self) -> Self::Output {
+        let matrix = self.matrix * other.matrix;
         Self::Output {
-            matrix: self.matrix * other.matrix,
+            matrix,
             inverse: other.inverse * self.inverse,
-            scale_factor: self.scale_factor * other.scale_factor,
+            scale_factor: min_stretch(&matrix),
         }
     }
 }
 
 pub trait ApplyTransform: Sized {
-    fn transform(&self, m: &Matrix4<f32>) -> Self;
+    fn transform(&self, m: &Matrix4<Float>) -> Self;
 
     #[inline]
     fn apply(&self, t: &Transform) -> Self {
@@ -124,23 +229,23 @@ pub trait ApplyTransform: Sized {
     }
 }
 
-impl ApplyTransform for Point3<f32> {
+impl ApplyTransform for Point3<Float> {
     #[inline]
-    fn transform(&self, m: &Matrix4<f32>) -> Self {
+    fn transform(&self, m: &Matrix4<Float>) -> Self {
         m.transform_point(self)
     }
 }
 
-impl ApplyTransform for Vector3<f32> {
+impl ApplyTransform for Vector3<Float> {
     #[inline]
-    fn transform(&self, m: &Matrix4<f32>) -> Self {
+    fn transform(&self, m: &Matrix4<Float>) -> Self {
         m.transform_vector(self)
     }
 }
 
 impl<T: Normed + ApplyTransform> ApplyTransform for Unit<T> {
     #[inline]
-    fn transform(&self, m: &Matrix4<f32>) -> Self {
+    fn transform(&self, m: &Matrix4<Float>) -> Self {
         Unit::new_normalize(self.as_ref().transform(m))
     }
 }
@@ -162,7 +267,7 @@ fn test_scaling() {
 
 #[test]
 fn test_rotation() {
-    let t = Transform::new().rotate(&Vector3::new(std::f32::consts::PI, 0., 0.));
+    let t = Transform::new().rotate(&Vector3::new(crate::math::consts::PI, 0., 0.));
     let p = Point3::new(0., 1., 0.);
     assert_eq!(p, p.apply(&t).invert(&t));
 }
@@ -171,10 +276,157 @@ fn test_rotation() {
 fn test_composition() {
     let t = Transform::new()
         .translate(&Vector3::new(1., 0., 0.))
-        .rotate(&Vector3::new(0., 0., std::f32::consts::FRAC_PI_2));
+        .rotate(&Vector3::new(0., 0., crate::math::consts::FRAC_PI_2));
     let p = Point3::new(0., 0., 0.);
     assert_eq!(1., p.apply(&t).y);
 
     let p = Point3::new(1., 0., 3.);
     assert_eq!(p, p.apply(&t).invert(&t));
 }
+
+#[test]
+fn test_non_uniform_scaling() {
+    // Stretching a unit sphere along x leaves points on the y axis as close as they were, so the
+    // distance can't be scaled up by the stretch.
+    let close = |expected: Float, t: &Transform| {
+        assert!(
+            (t.scale_factor() - expected).abs() < 1e-4,
+            "{}",
+            t.scale_factor()
+        )
+    };
+    close(1., &Transform::new().scale(&Vector3::new(4., 1., 1.)));
+    let t = Transform::new().scale(&Vector3::new(4., 0.5, 2.));
+    close(0.5, &t);
+    close(0.25, &t.inverse());
+
+    // A rotation between two scalings can turn the direction that one leaves alone into the one
+    // that the other stretches, so the composition is measured as a whole.
+    let t = &Transform::new().scale(&Vector3::new(4., 1., 4.))
+        * &Transform::new()
+            .rotate(&Vector3::new(0., 0., crate::math::consts::FRAC_PI_2))
+            .scale(&Vector3::new(4., 1., 4.));
+    close(4., &t);
+    close(2., &Transform::new().uniform_scale(-2.));
+}
+
+#[test]
+fn test_ordering() {
+    let p = Point3::new(1., 0., 0.);
+    let offset = Vector3::new(1., 0., 0.);
+    let quarter = Vector3::new(0., 0., crate::math::consts::FRAC_PI_2);
+    let close = |a: Point3<Float>, b: Point3<Float>| (a - b).norm() < 1e-6;
+
+    // `then_*` builders apply in the order they're written.
+    let t = Transform::new()
+        .then_translate(&offset)
+        .then_rotate(&quarter);
+    assert!(close(Point3::new(0., 2., 0.), p.apply(&t)));
+    let t = Transform::new()
+        .then_rotate(&quarter)
+        .then_translate(&offset);
+    assert!(close(Point3::new(1., 1., 0.), p.apply(&t)));
+    let t = Transform::new()
+        .then_scale(&Vector3::new(2., 1., 1.))
+        .then_translate(&offset);
+    assert!(close(Point3::new(3., 0., 0.), p.apply(&t)));
+
+    // Translations and scalings without `then_` apply before the transform so far.
+    let t = Transform::new().rotate(&quarter).translate(&offset);
+    assert!(close(Point3::new(0., 2., 0.), p.apply(&t)));
+    let t = Transform::new().translate(&offset).uniform_scale(2.);
+    assert!(close(Point3::new(3., 0., 0.), p.apply(&t)));
+
+    // `then` applies its argument second, as does `from_trs` with its parts in reverse.
+    let a = Transform::new().then_translate(&offset);
+    let b = Transform::new().then_rotate(&quarter);
+    assert!(close(Point3::new(0., 2., 0.), p.apply(&a.then(&b))));
+    let rotation = UnitQuaternion::from_scaled_axis(quarter);
+    let t = Transform::from_trs(&offset, &rotation, &Vector3::new(2., 2., 2.));
+    assert!(close(Point3::new(1., 2., 0.), p.apply(&t)));
+    assert!((t.scale_factor() - 2.).abs() < 1e-4);
+}
+
+/// One step of a chain of transforms, with translations, rotations and scales that stay near
+/// unit size so that a long chain stays well conditioned.
+#[cfg(test)]
+#[derive(Debug, Clone)]
+enum Step {
+    Translate(Vector3<Float>),
+    ThenTranslate(Vector3<Float>),
+    Rotate(Vector3<Float>),
+    Scale(Vector3<Float>),
+    ThenScale(Vector3<Float>),
+    UniformScale(Float),
+    ThenUniformScale(Float),
+    ThenTrs(Vector3<Float>, Vector3<Float>, Vector3<Float>),
+}
+
+#[cfg(test)]
+impl Step {
+    fn apply(&self, t: Transform) -> Transform {
+        match self {
+            Step::Translate(v) => t.translate(v),
+            Step::ThenTranslate(v) => t.then_translate(v),
+            Step::Rotate(v) => t.rotate(v),
+            Step::Scale(v) => t.scale(v),
+            Step::ThenScale(v) => t.then_scale(v),
+            Step::UniformScale(s) => t.uniform_scale(*s),
+            Step::ThenUniformScale(s) => t.then_uniform_scale(*s),
+            Step::ThenTrs(o, a, s) => t.then(&Transform::from_trs(
+                o,
+                &UnitQuaternion::from_scaled_axis(*a),
+                s,
+            )),
+        }
+    }
+
+    fn strategy() -> impl proptest::strategy::Strategy<Value = Step> {
+        use proptest::prelude::*;
+
+        let vector = |range: std::ops::Range<Float>| {
+            (range.clone(), range.clone(), range).prop_map(|(x, y, z)| Vector3::new(x, y, z))
+        };
+        let offset = vector(-1.0..1.0);
+        let angle = vector(-3.0..3.0);
+        let stretch = vector(0.8..1.25);
+        let amount = 0.8..1.25 as Float;
+
+        prop_oneof![
+            offset.clone().prop_map(Step::Translate),
+            offset.clone().prop_map(Step::ThenTranslate),
+            angle.clone().prop_map(Step::Rotate),
+            stretch.clone().prop_map(Step::Scale),
+            stretch.clone().prop_map(Step::ThenScale),
+            amount.clone().prop_map(Step::UniformScale),
+            amount.prop_map(Step::ThenUniformScale),
+            (offset, angle, stretch).prop_map(|(o, a, s)| Step::ThenTrs(o, a, s)),
+        ]
+    }
+}
+
+#[cfg(test)]
+proptest::proptest! {
+    #[test]
+    fn test_inverse_chain(steps in proptest::collection::vec(Step::strategy(), 1..32)) {
+        let t = steps.iter().fold(Transform::new(), |t, step| step.apply(t));
+
+        let error = (t.matrix * t.inverse - Matrix4::identity()).amax();
+        proptest::prop_assert!(error < 1e-3, "{} off the identity: {:?}", error, t);
+
+        let p = Point3::new(0.5, -0.25, 1.);
+        proptest::prop_assert!((p.apply(&t).invert(&t) - p).norm() < 1e-3);
+        proptest::prop_assert!(t.scale_factor() > 0.);
+    }
+}
+
+#[test]
+fn test_apply_normal() {
+    let t = Transform::new().scale(&Vector3::new(2., 1., 1.));
+    let n = Unit::new_normalize(Vector3::new(1., 1., 0.));
+    let n = t.apply_normal(&n);
+
+    // The tangent of the surface is stretched along x, and the normal must remain perpendicular.
+    let tangent = Vector3::new(1., -1., 0.).apply(&t);
+    assert!(n.dot(&tangent).abs() < 1e-6);
+}
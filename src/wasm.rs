@@ -0,0 +1,106 @@
+//! Bindings for rendering small previews in the browser, built with `wasm-pack build --features
+//! wasm --no-default-features`. There are no threads or files there, so renders run a tile at a
+//! time on the calling thread and come back as RGBA pixels rather than being written out.
+
+use wasm_bindgen::prelude::*;
+
+use crate::{
+    canvas::{BitDepth, Channels},
+    integrator,
+    math::Float,
+    parser::{self, Overrides, Render},
+    scene::Scene,
+};
+
+fn js_error(err: anyhow::Error) -> JsError {
+    JsError::new(&format!("{:#}", err))
+}
+
+/// A parsed scene description, whose renders can be drawn one at a time.
+#[wasm_bindgen]
+pub struct Preview {
+    scene: Scene,
+    renders: Vec<Render>,
+}
+
+#[wasm_bindgen]
+impl Preview {
+    /// Parse `source`, enabling the `if-flag` conditionals named by `flags`. The resolution of
+    /// every camera is multiplied by `scale`, and `samples` replaces the samples taken for each
+    /// pixel, when they're given.
+    #[wasm_bindgen(constructor)]
+    pub fn new(
+        source: &str,
+        flags: Vec<String>,
+        scale: Option<f64>,
+        samples: Option<u32>,
+    ) -> Result<Preview, JsError> {
+        let overrides = Overrides {
+            scale: scale.map(|scale| scale as Float),
+            samples,
+            ..Overrides::default()
+        };
+        let (scene, renders) = parser::parse_with(source, &flags, &overrides).map_err(js_error)?;
+        Ok(Preview { scene, renders })
+    }
+
+    /// The number of renders in the scene.
+    #[wasm_bindgen(getter)]
+    pub fn length(&self) -> usize {
+        self.renders.len()
+    }
+
+    /// The name given to the render at `index`.
+    pub fn name(&self, index: usize) -> Option<String> {
+        self.renders.get(index)?.name.clone()
+    }
+
+    pub fn width(&self, index: usize) -> Option<u32> {
+        Some(self.renders.get(index)?.canvas_info.width)
+    }
+
+    pub fn height(&self, index: usize) -> Option<u32> {
+        Some(self.renders.get(index)?.canvas_info.height)
+    }
+
+    /// Render the render at `index`, returning its pixels as RGBA bytes from the top row down,
+    /// ready for an `ImageData`. `progress` is called with the number of tiles finished and the
+    /// total after each tile. The lens effects, bloom and exposure are applied, but denoising is
+    /// skipped.
+    pub fn render(
+        &self,
+        index: usize,
+        progress: Option<js_sys::Function>,
+    ) -> Result<Vec<u8>, JsError> {
+        let Some(render) = self.renders.get(index) else {
+            return Err(JsError::new(&format!("There is no render {}", index)));
+        };
+
+        let total = integrator::tile_count(&render.canvas_info);
+        let mut done = 0;
+        let mut canvas = integrator::render_serial(
+            render.canvas_info.clone(),
+            &self.scene,
+            render.root,
+            render.sampler.clone_sampler(),
+            &render.builder,
+            &mut |_, _| {
+                done += 1;
+                if let Some(progress) = &progress {
+                    let _ = progress.call2(
+                        &JsValue::NULL,
+                        &JsValue::from(done as f64),
+                        &JsValue::from(total as f64),
+                    );
+                }
+            },
+        );
+
+        render.apply_effects(&mut canvas);
+
+        Ok(canvas
+            .to_image(Channels::Rgba, BitDepth::Eight)
+            .into_rgba8()
+            .into_raw())
+    }
+}
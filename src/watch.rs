@@ -0,0 +1,152 @@
+use anyhow::Error;
+use crossbeam::channel::{self, Receiver, RecvTimeoutError};
+use notify::event::ModifyKind;
+use notify::{Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// How long the files must go without changing before an edit is considered finished.
+const DEBOUNCE: Duration = Duration::from_millis(1000);
+
+/// The extensions of the files that are treated as scene descriptions in a directory.
+const SCENE_EXTENSIONS: &[&str] = &["scene", "rendrs"];
+
+/// True when `path` has the extension of a scene description.
+pub fn is_scene(path: &Path) -> bool {
+    path.extension()
+        .and_then(|ext| ext.to_str())
+        .is_some_and(|ext| SCENE_EXTENSIONS.contains(&ext))
+}
+
+/// Find the scene descriptions in `dir` and its subdirectories, sorted by their path.
+pub fn find_scenes(dir: &Path) -> Result<Vec<PathBuf>, Error> {
+    let mut scenes = Vec::new();
+    let mut dirs = vec![dir.to_path_buf()];
+    while let Some(dir) = dirs.pop() {
+        for entry in std::fs::read_dir(dir)? {
+            let path = entry?.path();
+            if path.is_dir() {
+                dirs.push(path);
+            } else if is_scene(&path) {
+                scenes.push(path);
+            }
+        }
+    }
+    scenes.sort();
+    Ok(scenes)
+}
+
+/// Watches the files that a scene is built from.
+pub struct SceneWatcher {
+    watcher: Mutex<RecommendedWatcher>,
+    recv: Receiver<()>,
+
+    /// The files being watched, and the directories watched for them.
+    files: Arc<Mutex<HashSet<PathBuf>>>,
+    dirs: Mutex<HashSet<PathBuf>>,
+}
+
+impl SceneWatcher {
+    /// Watch `paths` for changes. The directories containing them are watched, rather than the
+    /// files themselves.
+    pub fn new(paths: &[PathBuf]) -> Result<Self, Error> {
+        let watcher = Self::start(false)?;
+        for path in paths {
+            watcher.add(&path.canonicalize()?)?;
+        }
+        Ok(watcher)
+    }
+
+    /// Watch `dir` and its subdirectories for scene descriptions that are changed, added or
+    /// removed.
+    pub fn directory(dir: &Path) -> Result<Self, Error> {
+        let watcher = Self::start(true)?;
+        watcher
+            .watcher
+            .lock()
+            .unwrap()
+            .watch(dir, RecursiveMode::Recursive)?;
+        Ok(watcher)
+    }
+
+    /// Also watch `paths`, such as the files that a scene read while it was rendered. Paths that
+    /// don't exist are skipped, as the scene fails to render without them anyway.
+    pub fn watch(&self, paths: &[PathBuf]) -> Result<(), Error> {
+        for path in paths {
+            if let Ok(path) = path.canonicalize() {
+                self.add(&path)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Start watching nothing, or every scene description in the directories that are watched
+    /// when `scenes` is set.
+    fn start(scenes: bool) -> Result<Self, Error> {
+        let (send, recv) = channel::bounded(1);
+        let files = Arc::new(Mutex::new(HashSet::new()));
+
+        let watched = files.clone();
+        let watcher = notify::recommended_watcher(move |event| {
+            if let Ok(Event { kind, paths, .. }) = event {
+                let changed = match kind {
+                    EventKind::Modify(ModifyKind::Data(_)) => true,
+                    EventKind::Create(_)
+                    | EventKind::Remove(_)
+                    | EventKind::Modify(ModifyKind::Name(_)) => scenes,
+                    _ => false,
+                };
+                let watched = watched.lock().unwrap();
+                if changed
+                    && paths
+                        .iter()
+                        .any(|path| (scenes && is_scene(path)) || watched.contains(path))
+                {
+                    let _ = send.try_send(());
+                }
+            }
+        })?;
+
+        Ok(Self {
+            watcher: Mutex::new(watcher),
+            recv,
+            files,
+            dirs: Mutex::new(HashSet::new()),
+        })
+    }
+
+    /// Watch the file at the canonical `path`.
+    fn add(&self, path: &Path) -> Result<(), Error> {
+        if !self.files.lock().unwrap().insert(path.to_path_buf()) {
+            return Ok(());
+        }
+
+        if let Some(dir) = path.parent() {
+            if self.dirs.lock().unwrap().insert(dir.to_path_buf()) {
+                self.watcher
+                    .lock()
+                    .unwrap()
+                    .watch(dir, RecursiveMode::NonRecursive)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Block until the files change and then stop changing, so that a burst of writes from an
+    /// editor only triggers one render. Returns `false` if the watcher has stopped.
+    pub fn wait(&self) -> bool {
+        if self.recv.recv().is_err() {
+            return false;
+        }
+
+        loop {
+            match self.recv.recv_timeout(DEBOUNCE) {
+                Ok(_) => continue,
+                Err(RecvTimeoutError::Timeout) => return true,
+                Err(RecvTimeoutError::Disconnected) => return false,
+            }
+        }
+    }
+}
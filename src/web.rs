@@ -2,102 +2,264 @@ use actix::prelude::*;
 use actix_files as fs;
 use actix_web::{web, App, HttpRequest, HttpResponse, HttpServer, Responder};
 use actix_web_actors::ws;
-use anyhow::Error;
-use crossbeam::channel::{self, RecvTimeoutError};
+use anyhow::{anyhow, bail, Error};
 use fs::NamedFile;
-use notify::event::ModifyKind;
-use notify::{Event, EventKind, RecursiveMode, Watcher};
 use rand::{rngs::ThreadRng, Rng};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, VecDeque};
 use std::fmt::Write;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
 use std::time::{Duration, Instant};
 
-use crate::render;
+use crate::camera::View;
+use crate::canvas::{AutoExposure, HistogramSnapshot};
+use crate::math::{self, Float};
+use crate::watch::{self, SceneWatcher};
+use crate::{parser, render};
 
+/// How often histogram updates are sent to clients while a render is in progress.
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(100);
+
+/// The samples taken for each pixel while the camera is being moved from the web ui.
+const PREVIEW_SAMPLES: u32 = 1;
+
+/// The number of renders submitted with `wait=false` that may wait for the one before them, and
+/// that are kept for polling once they're done.
+const MAX_JOBS: usize = 16;
+
+/// Serve the web ui for `scene` on `port`, logging through whatever subscriber the caller has
+/// installed.
 #[actix_web::main]
-pub async fn serve(port: u16, threads: usize, scene: String) -> Result<(), Error> {
-    env_logger::init_from_env(env_logger::Env::new().default_filter_or("info"));
+pub async fn serve(
+    port: u16,
+    threads: usize,
+    flags: Vec<String>,
+    strict: bool,
+    history: usize,
+    scene: String,
+) -> Result<(), Error> {
+    let (requests, recv) = crossbeam::channel::unbounded();
+    let render_server = RenderServer::new(history, requests.clone()).start();
 
-    let render_server = RenderServer::new().start();
+    let project = Project::open(&scene)?;
+    let watcher = Arc::new(project.watcher()?);
 
-    let scene_path = PathBuf::from(scene).canonicalize()?;
-    let scene_dir = scene_path.parent().unwrap().to_path_buf();
+    let settings = render::Settings {
+        threads,
+        backend: render::Backend::Cpu,
+        strict,
+        coordinator: None,
+        in_memory: false,
+        concurrent: false,
 
-    let mut watcher = {
+        // Edits usually touch a few of the renders, and the others are reused.
+        cache: Some(Arc::new(render::RenderCache::default())),
+    };
+
+    // Scenes from the editor and from `/render` are kept in memory, rather than overwriting the
+    // outputs of the scene file.
+    let editor_settings = render::Settings {
+        in_memory: true,
+        concurrent: false,
+        ..settings.clone()
+    };
+
+    let (queue, queued) = crossbeam::channel::bounded(MAX_JOBS);
+    let service = web::Data::new(RenderService {
+        settings: editor_settings.clone(),
+        flags: flags.clone(),
+        jobs: Mutex::new(Jobs::default()),
+        queue,
+    });
+    {
+        let service = service.clone();
+        std::thread::spawn(move || service.work(queued));
+    }
+
+    {
         let render_server = render_server.clone();
 
-        let (send, recv) = channel::bounded(1);
+        let progress: Arc<render::Progress> = {
+            let render_server = render_server.clone();
+            let last = Mutex::new(Instant::now());
+            Arc::new(move |name, histogram, status| {
+                let histogram = histogram.snapshot();
 
-        let watcher_path = scene_path.clone();
-        let watcher = notify::recommended_watcher(move |event| match event {
-            Ok(Event {
-                kind: EventKind::Modify(ModifyKind::Data(_)),
-                paths,
-                ..
-            }) if paths.contains(&watcher_path) => send.send(()).unwrap(),
-            _ => (),
-        })?;
+                // Always send the final update, but otherwise limit how often they're sent.
+                let mut last = last.lock().unwrap();
+                if histogram.pixels < histogram.total && last.elapsed() < PROGRESS_INTERVAL {
+                    return;
+                }
+                *last = Instant::now();
+
+                render_server.do_send(RenderProgress {
+                    name: name.to_string(),
+                    histogram,
+                    status: status.clone(),
+                });
+            })
+        };
+
+        // Edits to the scene files are queued alongside the descriptions sent from the editor, so
+        // that a single thread renders both in the order they arrive.
+        {
+            let requests = requests.clone();
+            let watcher = watcher.clone();
+            std::thread::spawn(move || {
+                while watcher.wait() {
+                    if requests.send(Request::File).is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+
+        requests.send(Request::File)?;
 
         std::thread::spawn(move || {
-            'outer: loop {
-                log::info!("rendering {:?}", scene_path);
+            let mut scenes = Vec::new();
+            let mut selected: Option<String> = None;
 
-                // render the scene
-                match render::render_scene(threads, &scene_path) {
-                    Ok(outputs) => {
-                        let outputs = outputs
-                            .map(|output| match output {
-                                render::Output::File { path } => Output::File {
-                                    name: String::from(
-                                        path.file_name().and_then(|os| os.to_str()).unwrap(),
-                                    ),
-                                },
-                                render::Output::Ascii { name, chars } => Output::Ascii {
-                                    name,
-                                    content: chars,
-                                },
-                            })
-                            .collect();
-
-                        log::info!("render done");
-
-                        let scene = String::from(
-                            scene_path.file_name().and_then(|os| os.to_str()).unwrap(),
-                        );
-                        render_server.do_send(RenderResult { scene, outputs });
+            // The description sent from the editor, which is rendered in place of the scene file
+            // until the file changes.
+            let mut edited = None;
+            let mut view = View::default();
+
+            for request in recv.iter() {
+                // Handle everything that was queued while the last render was running at once, so
+                // that camera moves are combined into a single preview.
+                let mut preview = true;
+                let mut refresh = false;
+                for request in std::iter::once(request).chain(recv.try_iter()) {
+                    match request {
+                        Request::File => {
+                            edited = None;
+                            preview = false;
+                            refresh = true;
+                        }
+                        Request::Select(scene) => {
+                            if selected.as_ref() != Some(&scene) {
+                                selected = Some(scene);
+                                edited = None;
+                                view = View::default();
+                            }
+                            preview = false;
+                            refresh = true;
+                        }
+                        Request::Source(source) => {
+                            edited = Some(source);
+                            preview = false;
+                        }
+                        Request::Camera(camera) => camera.apply(&mut view),
                     }
+                }
 
-                    Err(err) => log::error!("error: {}", err),
+                // Scenes may have been added or removed, so list them again and tell the clients.
+                if refresh {
+                    match project.scenes() {
+                        Ok(found) => scenes = found,
+                        Err(err) => tracing::error!("failed to find scenes: {}", err),
+                    }
+                    if !selected
+                        .as_ref()
+                        .is_some_and(|scene| scenes.contains(scene))
+                    {
+                        selected = scenes.first().cloned();
+                        edited = None;
+                        view = View::default();
+                    }
+                    render_server.do_send(SceneList {
+                        scenes: scenes.clone(),
+                        selected: selected.clone(),
+                    });
                 }
 
-                // wait for the next edit
-                if recv.recv().is_err() {
-                    break 'outer;
+                let Some(name) = &selected else {
+                    continue;
+                };
+
+                let overrides = parser::Overrides {
+                    samples: preview.then_some(PREVIEW_SAMPLES),
+                    view: (view != View::default()).then(|| view.clone()),
+                    ..parser::Overrides::default()
+                };
+
+                // Only the scene file, as written, replaces the files that its renders produce.
+                let in_memory = preview || edited.is_some() || overrides.view.is_some();
+                let settings = if in_memory {
+                    &editor_settings
+                } else {
+                    &settings
+                };
+
+                let mut scene = name.clone();
+                let source = match &edited {
+                    Some(source) => {
+                        scene.push_str(" (edited)");
+                        Ok(source.clone())
+                    }
+                    None => std::fs::read_to_string(project.path(name)).map_err(Error::from),
+                };
+                if overrides.view.is_some() {
+                    scene.push_str(" (moved)");
+                }
+                if preview {
+                    scene.push_str(" (preview)");
                 }
 
-                // debounce edits
-                loop {
-                    let res = recv.recv_timeout(Duration::from_millis(1000));
-                    match res {
-                        Ok(_) => continue,
-                        Err(RecvTimeoutError::Timeout) => break,
-                        Err(_) => break 'outer,
+                tracing::info!("rendering {}", scene);
+                let outputs = source.and_then(|source| {
+                    if edited.is_none() && !preview {
+                        render_server.do_send(SceneSource {
+                            source: source.clone(),
+                        });
+                    }
+                    let outputs = render::render_source(
+                        settings,
+                        source,
+                        &flags,
+                        &overrides,
+                        Some(progress.clone()),
+                    )?;
+
+                    // The files that the scene read are watched along with it.
+                    if let Err(err) = watcher.watch(&outputs.files) {
+                        tracing::warn!("failed to watch the files of the scene: {}", err);
+                    }
+                    keep_outputs(outputs)
+                });
+
+                match outputs {
+                    Ok(outputs) => {
+                        tracing::info!("render done");
+                        render_server.do_send(RenderResult {
+                            id: 0,
+                            oldest: 0,
+                            scene,
+                            outputs,
+                        });
+                    }
+
+                    Err(err) => {
+                        tracing::error!("error: {:#}", err);
+                        render_server.do_send(RenderError {
+                            message: format!("{:#}", err),
+                        });
                     }
                 }
             }
         });
-
-        watcher
-    };
-
-    watcher.watch(&scene_dir, RecursiveMode::NonRecursive)?;
+    }
 
     let server = HttpServer::new(move || {
         App::new()
             .app_data(web::Data::new(render_server.clone()))
+            .app_data(service.clone())
             .service(web::resource("/").to(index))
             .route("/ws", web::get().to(client_route))
+            .route("/history/{id}/{name:.*}", web::get().to(history_route))
+            .route("/render", web::post().to(submit_render))
+            .route("/render/{id}", web::get().to(poll_render))
             .service(fs::Files::new("/output", "."))
             .service(fs::Files::new("/static", "web").index_file("index.html"))
     })
@@ -107,8 +269,8 @@ pub async fn serve(port: u16, threads: usize, scene: String) -> Result<(), Error
 
     let url = format!("http://127.0.0.1:{}/", port);
     if open::that(&url).is_err() {
-        log::warn!("Failed to open browser");
-        log::info!("Rendering available at {url}");
+        tracing::warn!("Failed to open browser");
+        tracing::info!("Rendering available at {url}");
     }
 
     server.await?;
@@ -116,6 +278,59 @@ pub async fn serve(port: u16, threads: usize, scene: String) -> Result<(), Error
     Ok(())
 }
 
+/// The scene files being served, which are either a single file or every scene description in a
+/// directory.
+struct Project {
+    /// The directory that the names of scenes are relative to.
+    dir: PathBuf,
+
+    /// The file that was given in place of a directory.
+    file: Option<PathBuf>,
+}
+
+impl Project {
+    fn open(path: &str) -> Result<Self, Error> {
+        let path = PathBuf::from(path).canonicalize()?;
+        if path.is_dir() {
+            Ok(Self {
+                dir: path,
+                file: None,
+            })
+        } else {
+            Ok(Self {
+                dir: path.parent().unwrap().to_path_buf(),
+                file: Some(path),
+            })
+        }
+    }
+
+    fn watcher(&self) -> Result<SceneWatcher, Error> {
+        match &self.file {
+            Some(file) => SceneWatcher::new(std::slice::from_ref(file)),
+            None => SceneWatcher::directory(&self.dir),
+        }
+    }
+
+    /// The names of the scenes, relative to the directory.
+    fn scenes(&self) -> Result<Vec<String>, Error> {
+        let paths = match &self.file {
+            Some(file) => vec![file.clone()],
+            None => watch::find_scenes(&self.dir)?,
+        };
+        Ok(paths
+            .iter()
+            .map(|path| {
+                let name = path.strip_prefix(&self.dir).unwrap_or(path);
+                name.to_string_lossy().into_owned()
+            })
+            .collect())
+    }
+
+    fn path(&self, scene: &str) -> PathBuf {
+        self.dir.join(scene)
+    }
+}
+
 async fn index() -> impl Responder {
     NamedFile::open_async("./web/index.html").await.unwrap()
 }
@@ -136,23 +351,382 @@ async fn client_route(
     )
 }
 
+/// Serve an image from a render in the history, which may have since been overwritten on disk.
+async fn history_route(
+    path: web::Path<(usize, String)>,
+    srv: web::Data<Addr<RenderServer>>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let (id, name) = path.into_inner();
+    let ext = name.rsplit('.').next().unwrap_or_default();
+    let content_type = fs::file_extension_to_mime(ext);
+    match srv.send(GetImage { id, name }).await {
+        Ok(Some(data)) => Ok(HttpResponse::Ok()
+            .content_type(content_type)
+            .body(data.as_ref().clone())),
+        _ => Ok(HttpResponse::NotFound().finish()),
+    }
+}
+
+/// Keep a copy of each image from a render, so that it can be served from the history.
+fn keep_outputs(outputs: render::Outputs) -> Result<Vec<Output>, Error> {
+    outputs
+        .map(|output| {
+            Ok(match output? {
+                // The file is overwritten by the next render, so read it back now.
+                render::Output::File { path, preview } => Output::File {
+                    data: Arc::new(std::fs::read(&path)?),
+                    name: String::from(path.to_str().unwrap()),
+                    preview: match preview {
+                        Some(preview) => Some((
+                            String::from(preview.to_str().unwrap()),
+                            Arc::new(std::fs::read(&preview)?),
+                        )),
+                        None => None,
+                    },
+                },
+                render::Output::Image {
+                    path,
+                    image,
+                    preview,
+                } => Output::File {
+                    data: encode(&path, &image)?,
+                    name: String::from(path.to_str().unwrap()),
+                    preview: match preview {
+                        Some((preview, image)) => Some((
+                            String::from(preview.to_str().unwrap()),
+                            encode(&preview, &image)?,
+                        )),
+                        None => None,
+                    },
+                },
+                render::Output::Ascii { name, chars } => Output::Ascii {
+                    name,
+                    content: chars,
+                },
+            })
+        })
+        .collect()
+}
+
+/// Encode `image` in the format that the extension of `path` names.
+fn encode(path: &Path, image: &image::DynamicImage) -> Result<Arc<Vec<u8>>, Error> {
+    let mut data = std::io::Cursor::new(Vec::new());
+    image.write_to(&mut data, image::ImageFormat::from_path(path)?)?;
+    Ok(Arc::new(data.into_inner()))
+}
+
+/// Renders scene descriptions posted to `/render`, so that other tools can use the server as a
+/// render service. File targets are kept in memory rather than written out.
+struct RenderService {
+    settings: render::Settings,
+    flags: Vec<String>,
+    jobs: Mutex<Jobs>,
+
+    /// The renders started with `wait=false`, which are rendered one at a time.
+    queue: crossbeam::channel::Sender<Job>,
+}
+
+/// A render started with `wait=false`, waiting for its turn.
+struct Job {
+    id: usize,
+    source: String,
+    flags: Vec<String>,
+    name: Option<String>,
+}
+
+/// Renders started with `wait=false`, which are polled for their results.
+#[derive(Default)]
+struct Jobs {
+    next_id: usize,
+    states: BTreeMap<usize, Option<JobResult>>,
+}
+
+/// A rendered output, or the error that stopped the render.
+type JobResult = std::result::Result<(&'static str, Vec<u8>), String>;
+
+impl RenderService {
+    /// Render `source`, returning the output of the render named `name`, or of the first render.
+    fn render(&self, source: String, flags: &[String], name: Option<String>) -> JobResult {
+        let overrides = parser::Overrides {
+            only: name.into_iter().collect(),
+            ..parser::Overrides::default()
+        };
+        let output = render::render_source(&self.settings, source, flags, &overrides, None)
+            .and_then(|mut outputs| outputs.next().ok_or_else(|| anyhow!("Nothing to render"))?)
+            .map_err(|err| format!("{:#}", err))?;
+
+        match output {
+            render::Output::Image { image, .. } => {
+                let mut png = std::io::Cursor::new(Vec::new());
+                image
+                    .write_to(&mut png, image::ImageFormat::Png)
+                    .map_err(|err| err.to_string())?;
+                Ok(("image/png", png.into_inner()))
+            }
+            render::Output::Ascii { chars, .. } => Ok(("text/plain", chars.into_bytes())),
+            render::Output::File { .. } => unreachable!(),
+        }
+    }
+
+    /// Queue `source` to be rendered in the background, returning the id to poll for the result.
+    /// Returns `None` when too many renders are already waiting.
+    fn start(&self, source: String, flags: Vec<String>, name: Option<String>) -> Option<usize> {
+        let mut jobs = self.jobs.lock().unwrap();
+        let id = jobs.next_id;
+        let job = Job {
+            id,
+            source,
+            flags,
+            name,
+        };
+        self.queue.try_send(job).ok()?;
+        jobs.next_id += 1;
+        jobs.states.insert(id, None);
+
+        // Forget the oldest finished jobs.
+        while jobs.states.len() > MAX_JOBS {
+            let Some(oldest) = jobs.states.iter().find(|(_, state)| state.is_some()) else {
+                break;
+            };
+            let oldest = *oldest.0;
+            jobs.states.remove(&oldest);
+        }
+
+        Some(id)
+    }
+
+    /// Render the queued jobs in the order they were started, until the server stops.
+    fn work(&self, queued: crossbeam::channel::Receiver<Job>) {
+        for job in queued {
+            let result = self.render(job.source, &job.flags, job.name);
+            self.jobs
+                .lock()
+                .unwrap()
+                .states
+                .insert(job.id, Some(result));
+        }
+    }
+}
+
+fn job_response(result: &JobResult) -> HttpResponse {
+    match result {
+        Ok((content_type, body)) => HttpResponse::Ok()
+            .content_type(*content_type)
+            .body(body.clone()),
+        Err(err) => HttpResponse::UnprocessableEntity()
+            .content_type("text/plain")
+            .body(err.clone()),
+    }
+}
+
+/// Render the scene description in the body of the request. The response is the output of the
+/// first render, or of the render named by the `name` parameter, as a png image or text. Each
+/// `flag` parameter enables the matching `if-flag` forms, along with the flags the server was
+/// started with. With `wait=false`, the response is a job id to poll at `/render/{id}` instead.
+async fn submit_render(
+    query: web::Query<Vec<(String, String)>>,
+    body: String,
+    service: web::Data<RenderService>,
+) -> HttpResponse {
+    let mut flags = service.flags.clone();
+    let mut name = None;
+    let mut wait = true;
+    for (key, value) in query.into_inner() {
+        match key.as_str() {
+            "flag" => flags.push(value),
+            "name" => name = Some(value),
+            "wait" => wait = value != "false",
+            key => return HttpResponse::BadRequest().body(format!("Unknown parameter `{}`", key)),
+        }
+    }
+
+    let service = service.into_inner();
+    if wait {
+        match web::block(move || service.render(body, &flags, name)).await {
+            Ok(result) => job_response(&result),
+            Err(err) => HttpResponse::InternalServerError().body(err.to_string()),
+        }
+    } else {
+        match service.start(body, flags, name) {
+            Some(id) => HttpResponse::Accepted()
+                .content_type("application/json")
+                .body(format!("{{ \"id\": {} }}", id)),
+            None => HttpResponse::ServiceUnavailable()
+                .content_type("text/plain")
+                .body("Too many renders are waiting, try again later"),
+        }
+    }
+}
+
+/// The result of a render started with `wait=false`, which is `202 Accepted` while it's running.
+async fn poll_render(path: web::Path<usize>, service: web::Data<RenderService>) -> HttpResponse {
+    let id = path.into_inner();
+    match service.jobs.lock().unwrap().states.get(&id) {
+        Some(Some(result)) => job_response(result),
+        Some(None) => HttpResponse::Accepted()
+            .content_type("application/json")
+            .body(format!("{{ \"id\": {}, \"status\": \"running\" }}", id)),
+        None => HttpResponse::NotFound().finish(),
+    }
+}
+
 #[derive(Message, Clone)]
 #[rtype(result = "()")]
 struct RenderResult {
+    /// Identifies the render in the history, and is assigned by the [`RenderServer`].
+    id: usize,
+
+    /// The id of the oldest render still in the history, so that clients can drop the rest.
+    oldest: usize,
+
     scene: String,
     outputs: Vec<Output>,
 }
 
+/// Fetch the image written by a file target of a render in the history.
+#[derive(Message)]
+#[rtype(result = "Option<Arc<Vec<u8>>>")]
+struct GetImage {
+    id: usize,
+    name: String,
+}
+
+/// The luminance histogram and status of a render target that's in progress.
+#[derive(Message, Clone)]
+#[rtype(result = "()")]
+struct RenderProgress {
+    name: String,
+    histogram: HistogramSnapshot,
+    status: render::Status,
+}
+
+/// The latest contents of the scene file, which clients start editing from.
+#[derive(Message, Clone)]
+#[rtype(result = "()")]
+struct SceneSource {
+    source: String,
+}
+
+/// Why the scene couldn't be parsed or rendered.
+#[derive(Message, Clone)]
+#[rtype(result = "()")]
+struct RenderError {
+    message: String,
+}
+
+/// A scene description sent from a client's editor.
+#[derive(Message)]
+#[rtype(result = "()")]
+struct SubmitScene {
+    source: String,
+}
+
+/// The scenes that can be picked from, and the one being rendered.
+#[derive(Message, Clone)]
+#[rtype(result = "()")]
+struct SceneList {
+    scenes: Vec<String>,
+    selected: Option<String>,
+}
+
+/// Switch to rendering another scene, from a client's scene picker.
+#[derive(Message)]
+#[rtype(result = "()")]
+struct SelectScene {
+    scene: String,
+}
+
+/// A camera move from a client's camera controls.
+#[derive(Message)]
+#[rtype(result = "()")]
+struct MoveCamera {
+    camera: CameraMove,
+}
+
+/// What the render thread is asked to render next.
+enum Request {
+    /// The scene file, which has changed on disk.
+    File,
+
+    /// A scene description from the editor, which leaves the scene file alone.
+    Source(String),
+
+    /// Render another of the scenes, by its name.
+    Select(String),
+
+    /// Move the camera, and preview the result.
+    Camera(CameraMove),
+}
+
+/// How the camera controls change the [`View`] of the scene.
+enum CameraMove {
+    /// Orbit around the target, by angles in radians.
+    Orbit(Float, Float),
+
+    /// Move the target across the view, by multiples of its distance from the camera.
+    Pan(Float, Float),
+
+    /// Scale the distance to the target.
+    Dolly(Float),
+
+    /// Go back to the cameras in the scene.
+    Reset,
+}
+
+impl CameraMove {
+    /// Parse a camera command from a client, whose arguments are numbers separated by spaces.
+    fn parse(command: &str, args: &str) -> Result<Self, Error> {
+        let args = args
+            .split_whitespace()
+            .map(|arg| arg.parse())
+            .collect::<Result<Vec<Float>, _>>()?;
+        Ok(match (command, args.as_slice()) {
+            ("orbit", &[yaw, pitch]) => CameraMove::Orbit(yaw, pitch),
+            ("pan", &[x, y]) => CameraMove::Pan(x, y),
+            ("dolly", &[amount]) if amount > 0. => CameraMove::Dolly(amount),
+            ("reset-view", &[]) => CameraMove::Reset,
+            _ => bail!("Invalid command `{}` with arguments {:?}", command, args),
+        })
+    }
+
+    fn apply(&self, view: &mut View) {
+        // Stop short of looking straight down, where the camera's up axis would flip over.
+        let max_pitch = math::consts::FRAC_PI_2 - 0.01;
+        match *self {
+            CameraMove::Orbit(yaw, pitch) => {
+                view.yaw += yaw;
+                view.pitch = (view.pitch + pitch).clamp(-max_pitch, max_pitch);
+            }
+            CameraMove::Pan(x, y) => {
+                // Pan by the same amount on screen however far the camera has been dollied.
+                view.pan_x += x * view.dolly;
+                view.pan_y += y * view.dolly;
+            }
+            CameraMove::Dolly(amount) => view.dolly *= amount,
+            CameraMove::Reset => *view = View::default(),
+        }
+    }
+}
+
 #[derive(Clone)]
 enum Output {
-    File { name: String },
-    Ascii { name: String, content: String },
+    File {
+        name: String,
+        data: Arc<Vec<u8>>,
+
+        /// The name and contents of the preview written alongside the file.
+        preview: Option<(String, Arc<Vec<u8>>)>,
+    },
+    Ascii {
+        name: String,
+        content: String,
+    },
 }
 
 #[derive(Message)]
 #[rtype(usize)]
 struct Connect {
-    addr: Recipient<RenderResult>,
+    addr: Addr<RenderClient>,
 }
 
 #[derive(Message)]
@@ -162,9 +736,23 @@ struct Disconnect {
 }
 
 struct RenderServer {
-    clients: HashMap<usize, Recipient<RenderResult>>,
+    clients: HashMap<usize, Addr<RenderClient>>,
     rng: ThreadRng,
-    last_result: Option<RenderResult>,
+
+    /// The most recent renders, oldest first, so that clients can flip between and compare them.
+    history: VecDeque<RenderResult>,
+
+    /// The number of renders kept in the history.
+    history_len: usize,
+
+    next_id: usize,
+
+    /// The contents of the scene file when it was last rendered.
+    source: Option<String>,
+
+    scenes: Option<SceneList>,
+
+    requests: crossbeam::channel::Sender<Request>,
 }
 
 impl Actor for RenderServer {
@@ -172,11 +760,16 @@ impl Actor for RenderServer {
 }
 
 impl RenderServer {
-    fn new() -> Self {
+    fn new(history_len: usize, requests: crossbeam::channel::Sender<Request>) -> Self {
         RenderServer {
             clients: HashMap::new(),
             rng: rand::thread_rng(),
-            last_result: None,
+            history: VecDeque::new(),
+            history_len: history_len.max(1),
+            next_id: 0,
+            source: None,
+            scenes: None,
+            requests,
         }
     }
 }
@@ -184,16 +777,111 @@ impl RenderServer {
 impl Handler<RenderResult> for RenderServer {
     type Result = ();
 
-    fn handle(&mut self, msg: RenderResult, _: &mut Context<Self>) -> Self::Result {
-        self.last_result = Some(msg.clone());
+    fn handle(&mut self, mut msg: RenderResult, _: &mut Context<Self>) -> Self::Result {
+        msg.id = self.next_id;
+        self.next_id += 1;
+
+        self.history.push_back(msg);
+        while self.history.len() > self.history_len {
+            self.history.pop_front();
+        }
+
+        let mut msg = self.history.back().unwrap().clone();
+        msg.oldest = self.history.front().unwrap().id;
+        for client in self.clients.values() {
+            client.do_send(msg.clone())
+        }
+    }
+}
+
+impl Handler<GetImage> for RenderServer {
+    type Result = Option<Arc<Vec<u8>>>;
+
+    fn handle(&mut self, msg: GetImage, _: &mut Context<Self>) -> Self::Result {
+        let result = self.history.iter().find(|result| result.id == msg.id)?;
+        result.outputs.iter().find_map(|output| match output {
+            Output::File { name, data, .. } if *name == msg.name => Some(data.clone()),
+            Output::File {
+                preview: Some((name, data)),
+                ..
+            } if *name == msg.name => Some(data.clone()),
+            _ => None,
+        })
+    }
+}
+
+impl Handler<RenderProgress> for RenderServer {
+    type Result = ();
+
+    fn handle(&mut self, msg: RenderProgress, _: &mut Context<Self>) -> Self::Result {
+        for client in self.clients.values() {
+            client.do_send(msg.clone())
+        }
+    }
+}
+
+impl Handler<SceneSource> for RenderServer {
+    type Result = ();
+
+    fn handle(&mut self, msg: SceneSource, _: &mut Context<Self>) -> Self::Result {
+        self.source = Some(msg.source.clone());
+        for client in self.clients.values() {
+            client.do_send(msg.clone())
+        }
+    }
+}
+
+impl Handler<RenderError> for RenderServer {
+    type Result = ();
+
+    fn handle(&mut self, msg: RenderError, _: &mut Context<Self>) -> Self::Result {
+        for client in self.clients.values() {
+            client.do_send(msg.clone())
+        }
+    }
+}
+
+impl Handler<SubmitScene> for RenderServer {
+    type Result = ();
+
+    fn handle(&mut self, msg: SubmitScene, _: &mut Context<Self>) -> Self::Result {
+        if self.requests.send(Request::Source(msg.source)).is_err() {
+            tracing::error!("the render thread has stopped");
+        }
+    }
+}
+
+impl Handler<SceneList> for RenderServer {
+    type Result = ();
 
-        // TODO: buffer the last render result in the server, and send it on new client connections
+    fn handle(&mut self, msg: SceneList, _: &mut Context<Self>) -> Self::Result {
+        self.scenes = Some(msg.clone());
         for client in self.clients.values() {
             client.do_send(msg.clone())
         }
     }
 }
 
+impl Handler<SelectScene> for RenderServer {
+    type Result = ();
+
+    fn handle(&mut self, msg: SelectScene, _: &mut Context<Self>) -> Self::Result {
+        if self.requests.send(Request::Select(msg.scene)).is_err() {
+            tracing::error!("the render thread has stopped");
+        }
+    }
+}
+
+impl Handler<MoveCamera> for RenderServer {
+    type Result = ();
+
+    fn handle(&mut self, msg: MoveCamera, _: &mut Context<Self>) -> Self::Result {
+        if self.requests.send(Request::Camera(msg.camera)).is_err() {
+            tracing::error!("the render thread has stopped");
+        }
+    }
+}
+
 impl Handler<Connect> for RenderServer {
     type Result = usize;
 
@@ -202,8 +890,22 @@ impl Handler<Connect> for RenderServer {
 
         self.clients.insert(id, msg.addr.clone());
 
-        if let Some(outputs) = &self.last_result {
-            msg.addr.do_send(outputs.clone());
+        if let Some(scenes) = &self.scenes {
+            msg.addr.do_send(scenes.clone());
+        }
+
+        if let Some(source) = &self.source {
+            msg.addr.do_send(SceneSource {
+                source: source.clone(),
+            });
+        }
+
+        let oldest = self.history.front().map_or(0, |result| result.id);
+        for result in self.history.iter() {
+            msg.addr.do_send(RenderResult {
+                oldest,
+                ..result.clone()
+            });
         }
 
         id
@@ -231,13 +933,13 @@ impl RenderClient {
     fn hb(&self, ctx: &mut ws::WebsocketContext<Self>) {
         ctx.run_interval(HEARTBEAT_INTERVAL, |act, ctx| {
             if Instant::now().duration_since(act.hb) > CLIENT_TIMEOUT {
-                log::trace!("Heartbeat failed, disconnecting");
+                tracing::trace!("Heartbeat failed, disconnecting");
                 act.addr.do_send(Disconnect { id: act.id });
                 ctx.stop();
                 return;
             }
 
-            log::trace!("sending a ping request");
+            tracing::trace!("sending a ping request");
             ctx.ping(b"");
         });
     }
@@ -249,15 +951,13 @@ impl Actor for RenderClient {
     fn started(&mut self, ctx: &mut Self::Context) {
         let addr = ctx.address();
         self.addr
-            .send(Connect {
-                addr: addr.recipient(),
-            })
+            .send(Connect { addr })
             .into_actor(self)
             .then(|res, act, ctx| {
                 match res {
                     Ok(res) => {
                         act.id = res;
-                        log::info!("started client {}", act.id);
+                        tracing::info!("started client {}", act.id);
                     }
                     _ => ctx.stop(),
                 }
@@ -268,7 +968,7 @@ impl Actor for RenderClient {
     }
 
     fn stopping(&mut self, _: &mut Self::Context) -> Running {
-        log::info!("stopping client {}", self.id);
+        tracing::info!("stopping client {}", self.id);
         self.addr.do_send(Disconnect { id: self.id });
         Running::Stop
     }
@@ -288,9 +988,24 @@ impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for RenderClient {
         match msg {
             ws::Message::Ping(msg) => ctx.pong(&msg),
             ws::Message::Pong(_) => {
-                log::trace!("ping response");
+                tracing::trace!("ping response");
                 self.hb = Instant::now()
             }
+
+            // Commands from the client are its name on the first line, followed by its argument.
+            ws::Message::Text(text) => match text.split_once('\n').unwrap_or((&text, "")) {
+                ("scene", source) => self.addr.do_send(SubmitScene {
+                    source: source.to_string(),
+                }),
+                ("select", scene) => self.addr.do_send(SelectScene {
+                    scene: scene.to_string(),
+                }),
+                (command, args) => match CameraMove::parse(command, args) {
+                    Ok(camera) => self.addr.do_send(MoveCamera { camera }),
+                    Err(err) => tracing::warn!("bad command from client {}: {}", self.id, err),
+                },
+            },
+
             _ => (),
         }
     }
@@ -303,26 +1018,34 @@ impl Handler<RenderResult> for RenderClient {
         let mut buf = String::new();
         let mut sep = "";
 
-        write!(&mut buf, "{{ \"scene\": \"{}\", \"outputs\": [", msg.scene).unwrap();
+        write!(
+            &mut buf,
+            "{{ \"type\": \"render\", \"id\": {}, \"oldest\": {}, \"scene\": {}, \
+             \"outputs\": [",
+            msg.id,
+            msg.oldest,
+            json_string(&msg.scene)
+        )
+        .unwrap();
 
         for output in msg.outputs {
             write!(&mut buf, "{}", sep).unwrap();
             match output {
-                Output::File { name } => {
-                    write!(&mut buf, "{{ \"type\": \"file\", \"name\": \"{}\" }}", name).unwrap()
+                Output::File { name, preview, .. } => {
+                    write!(&mut buf, "{{ \"type\": \"file\", \"name\": \"{}\"", name).unwrap();
+                    if let Some((preview, _)) = preview {
+                        write!(&mut buf, ", \"preview\": \"{}\"", preview).unwrap();
+                    }
+                    write!(&mut buf, " }}").unwrap()
                 }
 
-                Output::Ascii { name, content } => {
-                    let content = content.replace("\\", "\\\\");
-                    let content = content.replace("\n", "\\n");
-                    write!(
-                        &mut buf,
-                        "{{ \"type\": \"ascii\", \"name\": \"{}\", \"content\": \"{}\" }}",
-                        name,
-                        content.replace("\"", "\\\"")
-                    )
-                    .unwrap();
-                }
+                Output::Ascii { name, content } => write!(
+                    &mut buf,
+                    "{{ \"type\": \"ascii\", \"name\": \"{}\", \"content\": {} }}",
+                    name,
+                    json_string(&content)
+                )
+                .unwrap(),
             }
 
             sep = ", ";
@@ -333,3 +1056,98 @@ impl Handler<RenderResult> for RenderClient {
         ctx.text(buf);
     }
 }
+
+impl Handler<RenderProgress> for RenderClient {
+    type Result = ();
+
+    fn handle(&mut self, msg: RenderProgress, ctx: &mut Self::Context) {
+        let histogram = msg.histogram;
+        let bins: Vec<_> = histogram.bins.iter().map(|bin| bin.to_string()).collect();
+        let exposure = AutoExposure::default().scale_for(histogram.log_average);
+
+        ctx.text(format!(
+            "{{ \"type\": \"histogram\", \"name\": \"{}\", \"bins\": [{}], \"pixels\": {}, \
+             \"total\": {}, \"log_average\": {}, \"exposure\": {} }}",
+            msg.name,
+            bins.join(", "),
+            histogram.pixels,
+            histogram.total,
+            histogram.log_average,
+            exposure,
+        ));
+
+        let status = msg.status;
+        let eta = match status.eta() {
+            Some(eta) => eta.as_secs_f64().to_string(),
+            None => String::from("null"),
+        };
+        ctx.text(format!(
+            "{{ \"type\": \"progress\", \"name\": \"{}\", \"tiles\": {}, \"total\": {}, \
+             \"rays\": {}, \"elapsed\": {}, \"eta\": {}, \"rays_per_sec\": {} }}",
+            msg.name,
+            status.tiles,
+            status.total,
+            status.rays,
+            status.elapsed.as_secs_f64(),
+            eta,
+            status.rays_per_sec(),
+        ));
+    }
+}
+
+impl Handler<SceneSource> for RenderClient {
+    type Result = ();
+
+    fn handle(&mut self, msg: SceneSource, ctx: &mut Self::Context) {
+        ctx.text(format!(
+            "{{ \"type\": \"source\", \"source\": {} }}",
+            json_string(&msg.source)
+        ));
+    }
+}
+
+impl Handler<SceneList> for RenderClient {
+    type Result = ();
+
+    fn handle(&mut self, msg: SceneList, ctx: &mut Self::Context) {
+        let scenes: Vec<_> = msg.scenes.iter().map(|scene| json_string(scene)).collect();
+        let selected = match &msg.selected {
+            Some(scene) => json_string(scene),
+            None => String::from("null"),
+        };
+        ctx.text(format!(
+            "{{ \"type\": \"scenes\", \"scenes\": [{}], \"selected\": {} }}",
+            scenes.join(", "),
+            selected
+        ));
+    }
+}
+
+impl Handler<RenderError> for RenderClient {
+    type Result = ();
+
+    fn handle(&mut self, msg: RenderError, ctx: &mut Self::Context) {
+        ctx.text(format!(
+            "{{ \"type\": \"error\", \"message\": {} }}",
+            json_string(&msg.message)
+        ));
+    }
+}
+
+/// Quote `s` as a json string.
+fn json_string(s: &str) -> String {
+    let mut buf = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => buf.push_str("\\\""),
+            '\\' => buf.push_str("\\\\"),
+            '\n' => buf.push_str("\\n"),
+            '\r' => buf.push_str("\\r"),
+            '\t' => buf.push_str("\\t"),
+            c if c.is_control() => write!(buf, "\\u{:04x}", c as u32).unwrap(),
+            c => buf.push(c),
+        }
+    }
+    buf.push('"');
+    buf
+}
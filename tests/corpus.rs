@@ -0,0 +1,52 @@
+//! Run the parser over a corpus of scene descriptions. Everything in `scenes` and
+//! `tests/corpus/valid` must parse, and must describe the same scene once expanded, while
+//! everything in `tests/corpus/invalid` must be rejected with an error rather than a panic. The
+//! same files seed the parser's fuzz target.
+
+use std::path::{Path, PathBuf};
+
+use rendrs::parser::{expand, parse};
+
+fn corpus(dir: &str) -> Vec<PathBuf> {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join(dir);
+    let mut paths: Vec<_> = std::fs::read_dir(&dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "scene"))
+        .collect();
+    paths.sort();
+    assert!(!paths.is_empty(), "no scenes found in {}", dir.display());
+    paths
+}
+
+fn renders(input: &str, flags: &[String]) -> usize {
+    parse(input, flags).unwrap().1.len()
+}
+
+#[test]
+fn test_valid_corpus() {
+    let flags = [String::from("final")];
+    for path in corpus("scenes")
+        .into_iter()
+        .chain(corpus("tests/corpus/valid"))
+    {
+        let input = std::fs::read_to_string(&path).unwrap();
+        for flags in [&[][..], &flags[..]] {
+            if let Err(err) = parse(&input, flags) {
+                panic!("{}: {}", path.display(), err);
+            }
+
+            let expanded = expand(&input, flags).unwrap();
+            assert_eq!(renders(&input, flags), renders(&expanded, &[]));
+            assert_eq!(expanded, expand(&expanded, &[]).unwrap());
+        }
+    }
+}
+
+#[test]
+fn test_invalid_corpus() {
+    for path in corpus("tests/corpus/invalid") {
+        let input = std::fs::read_to_string(&path).unwrap();
+        assert!(parse(&input, &[]).is_err(), "{} parsed", path.display());
+    }
+}
@@ -0,0 +1,177 @@
+//! Render the small scenes in `tests/golden` and compare them against the reference images next
+//! to them, so that changes to the integrator or the scene code that change what's rendered are
+//! caught. Pixels may differ a little, so that rounding differences between platforms don't fail
+//! the test. When a scene doesn't match, its render and an image of the differences are written
+//! under the cargo target directory.
+//!
+//! After a change that's meant to alter the renders, run the test with `RENDRS_BLESS=1` to replace
+//! the reference images with the new renders, and check the differences before committing them.
+
+use image::{Rgb, RgbImage};
+use std::path::{Path, PathBuf};
+
+use rendrs::{parser::Overrides, render};
+
+/// How far apart two colors may be, in the units of [`distance`], before their pixels are counted
+/// as different. This is around the smallest difference that's easy to see.
+const THRESHOLD: f64 = 12.;
+
+/// The fraction of pixels that may differ, which allows for a few pixels on the edges of shapes
+/// to land on the other side of a surface.
+const MAX_DIFFERENT: f64 = 0.005;
+
+/// The distance between two 8-bit colors, weighting the channels by how sensitive the eye is to
+/// them with the "redmean" approximation. This gives roughly the euclidean distance of the
+/// channels, but grows faster for green, and for red in bright colors.
+fn distance(a: &Rgb<u8>, b: &Rgb<u8>) -> f64 {
+    let mean = (a[0] as f64 + b[0] as f64) / 2.;
+    let [dr, dg, db] = [0, 1, 2].map(|i| a[i] as f64 - b[i] as f64);
+    ((2. + mean / 256.) * dr * dr + 4. * dg * dg + (2. + (255. - mean) / 256.) * db * db).sqrt()
+        / 3.
+}
+
+/// Compare `actual` with `expected`, returning an image with the differing pixels in red over a
+/// faded copy of the reference, when too many of them differ.
+fn compare(expected: &RgbImage, actual: &RgbImage) -> Result<(), (String, RgbImage)> {
+    if expected.dimensions() != actual.dimensions() {
+        return Err((
+            format!(
+                "the render is {:?}, but the reference is {:?}",
+                actual.dimensions(),
+                expected.dimensions()
+            ),
+            actual.clone(),
+        ));
+    }
+
+    let mut diff = RgbImage::new(expected.width(), expected.height());
+    let mut different = 0;
+    let mut worst: f64 = 0.;
+    for ((e, a), d) in expected
+        .pixels()
+        .zip(actual.pixels())
+        .zip(diff.pixels_mut())
+    {
+        let dist = distance(e, a);
+        worst = worst.max(dist);
+        *d = if dist > THRESHOLD {
+            different += 1;
+            Rgb([255, 0, 0])
+        } else {
+            Rgb(e.0.map(|c| c / 4))
+        };
+    }
+
+    let fraction = different as f64 / (expected.width() * expected.height()) as f64;
+    if fraction > MAX_DIFFERENT {
+        return Err((
+            format!(
+                "{} pixels differ ({:.2}%), by up to {:.1}",
+                different,
+                fraction * 100.,
+                worst
+            ),
+            diff,
+        ));
+    }
+
+    Ok(())
+}
+
+/// Render the scene at `path`, which must have a single render with a file target.
+fn render(path: &Path) -> RgbImage {
+    let settings = render::Settings {
+        threads: 2,
+        backend: render::Backend::Cpu,
+        strict: true,
+        coordinator: None,
+        in_memory: true,
+        concurrent: false,
+        cache: None,
+    };
+    let input = std::fs::read_to_string(path).unwrap();
+    let mut outputs: Vec<_> =
+        render::render_source(&settings, input, &[], &Overrides::default(), None)
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+    assert_eq!(
+        1,
+        outputs.len(),
+        "{} has more than one render",
+        path.display()
+    );
+    match outputs.pop().unwrap() {
+        render::Output::Image { image, .. } => image.into_rgb8(),
+        _ => panic!("{} doesn't render to a file", path.display()),
+    }
+}
+
+#[test]
+fn test_golden_images() {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/golden");
+    let mut scenes: Vec<PathBuf> = std::fs::read_dir(&dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "scene"))
+        .collect();
+    scenes.sort();
+    assert!(!scenes.is_empty(), "no scenes found in {}", dir.display());
+
+    let bless = std::env::var_os("RENDRS_BLESS").is_some();
+    let out = Path::new(env!("CARGO_TARGET_TMPDIR")).join("golden");
+    let mut failures = Vec::new();
+    for scene in scenes {
+        let name = scene.file_stem().unwrap().to_string_lossy().into_owned();
+        let reference = scene.with_extension("png");
+        let actual = render(&scene);
+
+        if bless {
+            actual.save(&reference).unwrap();
+            continue;
+        }
+
+        let expected = match image::open(&reference) {
+            Ok(expected) => expected.into_rgb8(),
+            Err(err) => {
+                failures.push(format!("{}: no reference image: {}", name, err));
+                continue;
+            }
+        };
+
+        if let Err((message, diff)) = compare(&expected, &actual) {
+            std::fs::create_dir_all(&out).unwrap();
+            let actual_path = out.join(format!("{}.png", name));
+            let diff_path = out.join(format!("{}.diff.png", name));
+            actual.save(&actual_path).unwrap();
+            diff.save(&diff_path).unwrap();
+            failures.push(format!(
+                "{}: {}, see {} and {}",
+                name,
+                message,
+                actual_path.display(),
+                diff_path.display()
+            ));
+        }
+    }
+
+    assert!(failures.is_empty(), "{}", failures.join("\n"));
+}
+
+#[test]
+fn test_compare() {
+    let gray = RgbImage::from_pixel(10, 20, Rgb([128, 128, 128]));
+    assert!(compare(&gray, &gray).is_ok());
+
+    // Slight differences everywhere are tolerated, as is a large difference in a single pixel.
+    let mut close = RgbImage::from_pixel(10, 20, Rgb([130, 127, 129]));
+    close.put_pixel(3, 4, Rgb([255, 0, 0]));
+    assert!(compare(&gray, &close).is_ok());
+
+    close.put_pixel(5, 6, Rgb([0, 0, 0]));
+    let (_, diff) = compare(&gray, &close).unwrap_err();
+    assert_eq!(Rgb([255, 0, 0]), *diff.get_pixel(5, 6));
+    assert_eq!(Rgb([32, 32, 32]), *diff.get_pixel(0, 0));
+
+    assert!(compare(&gray, &RgbImage::new(20, 10)).is_err());
+}
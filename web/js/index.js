@@ -23,69 +23,447 @@ class OutputManager {
 
 const mgr = new OutputManager();
 
+// The renders received from the server, oldest first. The server only keeps a
+// limited history, and tells us the oldest render that it still has.
+const history = [];
+
+// The renders being shown. `a` follows the latest render when it's null, and
+// `b` is only set when comparing two renders side by side.
+const selected = { a: null, b: null };
+
+// The scene file as the server last rendered it, and whether the editor has
+// been changed since, in which case edits to the file don't replace it.
+const editor = { source: null, edited: false };
+
 const con = new WebSocket(`ws://${window.location.host}/ws`);
 
 con.onmessage = event => {
   const message = JSON.parse(event.data);
-  document.title = message.scene;
 
-  const outputs = document.getElementById('outputs');
+  if (message.type == "histogram") {
+    updateHistogram(message);
+    return;
+  }
+
+  if (message.type == "progress") {
+    updateProgress(message);
+    return;
+  }
+
+  if (message.type == "source") {
+    editor.source = message.source;
+    if (!editor.edited) {
+      revertSource();
+    }
+    return;
+  }
+
+  if (message.type == "scenes") {
+    updateScenes(message);
+    return;
+  }
+
+  if (message.type == "error") {
+    showError(message.message);
+    return;
+  }
+
+  showError(null);
+
+  history.push(message);
+  while (history.length > 0 && history[0].id < message.oldest) {
+    history.shift();
+  }
+  for (const key of ['a', 'b']) {
+    if (selected[key] != null && findRender(selected[key]) == null) {
+      selected[key] = null;
+    }
+  }
+
+  updateSelects();
+  showOutputs();
+};
+
+window.addEventListener('DOMContentLoaded', () => {
+  for (const key of ['a', 'b']) {
+    document.getElementById(`render-${key}`).addEventListener('change', event => {
+      const value = event.target.value;
+      selected[key] = value == '' ? null : Number(value);
+      showOutputs();
+    });
+  }
+
+  document.getElementById('swap').addEventListener('click', () => {
+    if (selected.b == null) {
+      return;
+    }
+    const a = selected.a ?? history[history.length - 1].id;
+    selected.a = selected.b;
+    selected.b = a;
+    updateSelects();
+    showOutputs();
+  });
+
+  const source = document.getElementById('source');
+  source.addEventListener('input', () => {
+    editor.edited = true;
+  });
+  source.addEventListener('keydown', event => {
+    if (event.key == 'Enter' && (event.ctrlKey || event.metaKey)) {
+      event.preventDefault();
+      submitSource();
+    }
+  });
+  document.getElementById('submit').addEventListener('click', submitSource);
+  document.getElementById('scene').addEventListener('change', event => {
+    con.send(`select\n${event.target.value}`);
+  });
+  document.getElementById('reset-view').addEventListener('click', () => {
+    con.send('reset-view');
+  });
+  addCameraControls(document.getElementById('outputs'));
+  document.getElementById('revert').addEventListener('click', revertSource);
+  revertSource();
+});
+
+// Send the contents of the editor to the server to be rendered. Commands are
+// their name on the first line, followed by their argument.
+function submitSource() {
+  con.send(`scene\n${document.getElementById('source').value}`);
+}
+
+// How far the camera moves for each pixel that the mouse is dragged. Orbiting
+// is in radians, and panning in multiples of the distance to the target.
+const ORBIT_SPEED = 0.01;
+const PAN_SPEED = 0.002;
+
+// Dragging over the outputs orbits the camera around its target, or pans it
+// with shift held, and scrolling dollies it in and out. The server renders a
+// quick preview after each move.
+function addCameraControls(outputs) {
+  let last = null;
+
+  outputs.addEventListener('mousedown', event => {
+    if (event.button != 0 || event.target.closest('div.pane') == null) {
+      return;
+    }
+    event.preventDefault();
+    last = { x: event.clientX, y: event.clientY };
+  });
+
+  window.addEventListener('mousemove', event => {
+    if (last == null) {
+      return;
+    }
+    const dx = event.clientX - last.x;
+    const dy = event.clientY - last.y;
+    last = { x: event.clientX, y: event.clientY };
+    if (dx == 0 && dy == 0) {
+      return;
+    }
+
+    if (event.shiftKey) {
+      con.send(`pan\n${-dx * PAN_SPEED} ${dy * PAN_SPEED}`);
+    } else {
+      con.send(`orbit\n${-dx * ORBIT_SPEED} ${dy * ORBIT_SPEED}`);
+    }
+  });
+
+  window.addEventListener('mouseup', () => {
+    last = null;
+  });
+
+  outputs.addEventListener('wheel', event => {
+    if (event.target.closest('div.pane') == null) {
+      return;
+    }
+    event.preventDefault();
+    con.send(`dolly\n${Math.pow(1.1, event.deltaY / 100)}`);
+  }, { passive: false });
+}
+
+// Replace the contents of the editor with the scene file.
+function revertSource() {
+  const source = document.getElementById('source');
+  if (source == null || editor.source == null) {
+    return;
+  }
+  source.value = editor.source;
+  editor.edited = false;
+}
+
+// Show why the last render failed, or clear the message when `message` is null.
+function showError(message) {
+  const error = document.getElementById('error');
+  if (error == null) {
+    return;
+  }
+  error.textContent = message ?? '';
+  error.hidden = message == null;
+}
+
+// Rebuild the scene picker from the scenes that the server has found.
+function updateScenes(message) {
+  const picker = document.getElementById('scene');
+  if (picker == null) {
+    return;
+  }
+  picker.replaceChildren(...message.scenes.map(scene => new Option(scene, scene)));
+  picker.value = message.selected ?? '';
+  picker.parentElement.hidden = message.scenes.length < 2;
+}
+
+function findRender(id) {
+  return history.find(render => render.id == id) ?? null;
+}
+
+// Rebuild the options for choosing renders from the history.
+function updateSelects() {
+  const options = (empty) => {
+    const opts = [new Option(empty, '')];
+    for (const render of [...history].reverse()) {
+      opts.push(new Option(`render #${render.id}: ${render.scene}`, render.id));
+    }
+    return opts;
+  };
+
+  const a = document.getElementById('render-a');
+  a.replaceChildren(...options('latest'));
+  a.value = selected.a ?? '';
+
+  const b = document.getElementById('render-b');
+  b.replaceChildren(...options('nothing'));
+  b.value = selected.b ?? '';
+}
+
+// Show the selected renders, side by side when two are selected.
+function showOutputs() {
+  if (history.length == 0) {
+    return;
+  }
+
+  const a = findRender(selected.a) ?? history[history.length - 1];
+  const b = findRender(selected.b);
+  document.title = a.scene;
 
   // TODO: keep outputs sorted according to the order in the message from the
   // server
-  // TODO: remove outputs that don't occur in the message from the server
-  message.outputs.forEach(output => {
-    const node = mgr.hasOutput(output.name);
-    if (node != null) {
-      console.log(`updating ${output.name}`);
-      updateOutput(node, output);
-    } else {
-      console.log(`creating ${output.name}`);
-      const node = makeOutput(output);
-      mgr.addOutput(output.name, node);
-      outputs.appendChild(node);
+  const names = new Set(a.outputs.map(output => output.name));
+  if (b != null) {
+    b.outputs.forEach(output => names.add(output.name));
+  }
+
+  // Hide the outputs of other renders, such as those of another scene.
+  for (const output of mgr.known_outputs) {
+    output.node.hidden = !names.has(output.name);
+  }
+
+  names.forEach(name => {
+    const container = findOrMakeOutput(name);
+    const panes = container.getElementsByClassName('panes')[0];
+    panes.replaceChildren(makePane(a, name, b != null));
+    if (b != null) {
+      panes.appendChild(makePane(b, name, true));
     }
   });
-};
+}
 
-function makeOutput(output) {
-  const container = document.createElement('div');
-  container.classList.add('container');
+// Make the element showing the named output of a render.
+function makePane(render, name, caption) {
+  const pane = document.createElement('div');
+  pane.classList.add('pane');
 
-  const title = document.createElement('div');
-  title.innerText = output.name;
-  title.classList.add('title');
-  container.appendChild(title);
+  if (caption) {
+    const title = document.createElement('div');
+    title.classList.add('caption');
+    title.innerText = `render #${render.id}`;
+    pane.appendChild(title);
+  }
+
+  const output = render.outputs.find(output => output.name == name);
+  if (output == null) {
+    const missing = document.createElement('div');
+    missing.innerText = 'not rendered';
+    pane.appendChild(missing);
+    return pane;
+  }
 
   switch (output.type) {
     case "ascii":
-      container.classList.add('ascii');
+      pane.classList.add('ascii');
       const pre = document.createElement('pre');
-      pre.innerText = output.content;
-      container.appendChild(pre);
+      fillAscii(pre, output.content);
+      pane.appendChild(pre);
       break;
 
     case "file":
-      container.classList.add('image');
+      pane.classList.add('image');
       const image = document.createElement('img');
-      image.src = `/output/${output.name}?t=${Date.now()}`;
-      container.appendChild(image);
+      const src = `/history/${render.id}/${output.name}`;
+      if (output.preview) {
+        // Show the preview until the full image has loaded.
+        image.src = `/history/${render.id}/${output.preview}`;
+        const full = new Image();
+        full.onload = () => image.src = src;
+        full.src = src;
+      } else {
+        image.src = src;
+      }
+      pane.appendChild(image);
       break;
   }
 
+  return pane;
+}
+
+// Find the container for the named output, creating an empty one if it hasn't
+// been seen before. Histogram updates can arrive before the first render of an
+// output has finished.
+function findOrMakeOutput(name) {
+  const existing = mgr.hasOutput(name);
+  if (existing != null) {
+    existing.hidden = false;
+    return existing;
+  }
+
+  const container = document.createElement('div');
+  container.classList.add('container');
+
+  const title = document.createElement('div');
+  title.innerText = name;
+  title.classList.add('title');
+  container.appendChild(title);
+
+  const stats = document.createElement('div');
+  stats.classList.add('stats');
+
+  const histogram = document.createElement('canvas');
+  histogram.classList.add('histogram');
+  histogram.width = 256;
+  histogram.height = 96;
+  stats.appendChild(histogram);
+
+  const readout = document.createElement('div');
+  readout.classList.add('readout');
+  stats.appendChild(readout);
+
+  const progress = document.createElement('div');
+  progress.classList.add('progress');
+  progress.appendChild(document.createElement('progress'));
+  const timing = document.createElement('div');
+  timing.classList.add('timing');
+  progress.appendChild(timing);
+  stats.appendChild(progress);
+
+  container.appendChild(stats);
+
+  const panes = document.createElement('div');
+  panes.classList.add('panes');
+  container.appendChild(panes);
+
+  mgr.addOutput(name, container);
+  document.getElementById('outputs').appendChild(container);
+
   return container;
 }
 
-function updateOutput(node, output) {
-  switch (output.type) {
-    case "ascii":
-      const pre = node.getElementsByTagName('pre')[0];
-      pre.innerText = output.content;
-      break;
+// Fill a pre element with ascii output, turning the 24-bit ANSI color escapes used by the color
+// modes into styled spans.
+function fillAscii(pre, content) {
+  pre.replaceChildren();
 
-    case "file":
-      const image = node.getElementsByTagName('img')[0];
-      image.src = `/output/${output.name}?t=${Date.now()}`;
-      break;
+  let fg = '';
+  let bg = '';
+  const parts = content.split(/\x1b\[([0-9;]*)m/);
+  parts.forEach((part, i) => {
+    if (i % 2 == 0) {
+      if (part.length == 0) {
+        return;
+      }
+      const span = document.createElement('span');
+      span.style.color = fg;
+      span.style.backgroundColor = bg;
+      span.innerText = part;
+      pre.appendChild(span);
+      return;
+    }
+
+    const codes = part.split(';');
+    for (let j = 0; j < codes.length; j++) {
+      switch (codes[j]) {
+        case '0':
+        case '':
+          fg = '';
+          bg = '';
+          break;
+        case '38':
+          fg = `rgb(${codes.slice(j + 2, j + 5).join(',')})`;
+          j += 4;
+          break;
+        case '48':
+          bg = `rgb(${codes.slice(j + 2, j + 5).join(',')})`;
+          j += 4;
+          break;
+        case '49':
+          bg = '';
+          break;
+      }
+    }
+  });
+}
+
+function updateHistogram(message) {
+  const container = findOrMakeOutput(message.name);
+  const canvas = container.getElementsByClassName('histogram')[0];
+  const ctx = canvas.getContext('2d');
+
+  ctx.clearRect(0, 0, canvas.width, canvas.height);
+
+  const max = Math.max(1, ...message.bins);
+  const width = canvas.width / message.bins.length;
+  ctx.fillStyle = '#404040';
+  message.bins.forEach((count, i) => {
+    const height = canvas.height * count / max;
+    ctx.fillRect(i * width, canvas.height - height, width - 1, height);
+  });
+
+  const done = Math.round(100 * message.pixels / Math.max(1, message.total));
+  const readout = container.getElementsByClassName('readout')[0];
+  readout.innerText =
+    `${done}% rendered\n` +
+    `log-average luminance: ${message.log_average.toFixed(4)}\n` +
+    `auto exposure: x${message.exposure.toFixed(2)}`;
+}
+
+function updateProgress(message) {
+  const container = findOrMakeOutput(message.name);
+  const bar = container.getElementsByTagName('progress')[0];
+  bar.max = message.total;
+  bar.value = message.tiles;
+
+  const eta = message.eta == null ? 'unknown' : formatSeconds(message.eta);
+  const timing = container.getElementsByClassName('timing')[0];
+  timing.innerText =
+    `${message.tiles} / ${message.total} tiles\n` +
+    `elapsed: ${formatSeconds(message.elapsed)}\n` +
+    (message.tiles < message.total ? `remaining: ${eta}\n` : '') +
+    `${formatCount(message.rays_per_sec)} rays/s`;
+}
+
+function formatSeconds(seconds) {
+  if (seconds < 60) {
+    return `${seconds.toFixed(1)}s`;
+  }
+  const minutes = Math.floor(seconds / 60);
+  return `${minutes}m ${Math.round(seconds - minutes * 60)}s`;
+}
+
+function formatCount(count) {
+  if (count >= 1e6) {
+    return `${(count / 1e6).toFixed(2)}M`;
+  }
+  if (count >= 1e3) {
+    return `${(count / 1e3).toFixed(1)}k`;
   }
+  return count.toFixed(0);
 }